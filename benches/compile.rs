@@ -0,0 +1,32 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use doublejit_vm::middleend::address_map::AddressMap;
+use doublejit_vm::middleend::emit_wasm::{emit_block_module, indirect_exit};
+use doublejit_vm::runtime::config::{Config, OptLevel};
+use doublejit_vm::runtime::dispatch::{BlockSlots, InlineCaches};
+use doublejit_vm::wasm::wasm_builder::WasmBuilder;
+
+fn compile(c: &mut Criterion) {
+    let config = Config::new().strict_memory(true);
+    let mut body = String::new();
+    for reg in 1..32 {
+        body.push_str(&format!(
+            "(global.set $x{0} (call $load_i64 (i64.add (global.get $x{0}) (i64.const 8))))\n",
+            reg
+        ));
+    }
+    body.push_str(&indirect_exit(0x10000, "(global.get $x1)", &InlineCaches::new(), &mut BlockSlots::new()));
+    let mut wat = String::new();
+    emit_block_module(&mut wat, &AddressMap::new(1 << 20), &config, &body);
+    let wasm = WasmBuilder::assemble(&wat).unwrap();
+
+    let mut group = c.benchmark_group("backend");
+    group.bench_function("wat2wasm", |b| b.iter(|| WasmBuilder::assemble(black_box(&wat)).unwrap()));
+    for (name, opt_level) in [("singlepass", OptLevel::Baseline), ("cranelift", OptLevel::Optimized)] {
+        let builder = WasmBuilder::with_opt_level(opt_level);
+        group.bench_function(name, |b| b.iter(|| builder.compile(black_box(&wasm)).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compile);
+criterion_main!(benches);
@@ -0,0 +1,101 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use doublejit_vm::frontend::binary::get_memory_initializers;
+use doublejit_vm::frontend::elf::ElfFile;
+use doublejit_vm::middleend::address_map::{AddressMap, GuestMemory, Perm};
+use doublejit_vm::middleend::emit_buf::{Arg, EmitBuffer, ALU_IMM, ALU_REG, LOAD};
+use doublejit_vm::middleend::emit_wasm::{emit_memory_helpers, emit_parallel};
+use doublejit_vm::runtime::config::Config;
+use doublejit_vm::runtime::interp::{Interpreter, Stop};
+use doublejit_vm::runtime::regs::Registers;
+use doublejit_vm::runtime::syscall::SyscallEnv;
+
+/// Sums 1..=100000 and exits with the sum, 300k instructions in one hot loop
+const LOOP: [u32; 8] = [
+    0x00000513, // addi a0, zero, 0
+    0x000182b7, // lui t0, 0x18
+    0x6a028293, // addi t0, t0, 0x6a0
+    0x00550533, // loop: add a0, a0, t0
+    0xfff28293, // addi t0, t0, -1
+    0xfe029ce3, // bnez t0, loop
+    0x05d00893, // addi a7, zero, 93
+    0x00000073, // ecall
+];
+
+fn decode(c: &mut Criterion) {
+    // read rather than included, the ELF parser needs the bytes aligned
+    let gzip = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test_binaries/gzip_test/gzip")).unwrap();
+    let mut group = c.benchmark_group("frontend");
+    group.throughput(Throughput::Bytes(gzip.len() as u64));
+    group.bench_function("load gzip", |b| {
+        b.iter(|| {
+            let elf = ElfFile::new(black_box(&gzip)).unwrap();
+            get_memory_initializers(&elf, 0).unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn emit(c: &mut Criterion) {
+    let mut map = AddressMap::new(64 << 20);
+    map.map(0x10000..0x100000, Perm::RWX).unwrap();
+    let strict = Config::new().strict_memory(true);
+    let blocks: Vec<u64> = (0..4096).collect();
+    let mut group = c.benchmark_group("middleend");
+    group.bench_function("memory helpers", |b| {
+        b.iter(|| {
+            let mut out = String::new();
+            emit_memory_helpers(&mut out, &map, &strict);
+            out
+        })
+    });
+    group.throughput(Throughput::Elements(blocks.len() as u64));
+    group.bench_function("emit blocks", |b| {
+        b.iter(|| {
+            let mut out = String::new();
+            emit_parallel(
+                &mut out,
+                &blocks,
+                |&block, buf: &mut EmitBuffer| {
+                    for reg in 1..8 {
+                        ALU_IMM.render(buf, &[Arg::Dec(reg), Arg::Str("add"), Arg::Dec(2), Arg::Dec(block as i64)]);
+                        ALU_REG.render(buf, &[Arg::Dec(reg), Arg::Str("xor"), Arg::Dec(reg), Arg::Dec(10)]);
+                        LOAD.render(buf, &[Arg::Dec(reg), Arg::Str("i64"), Arg::Dec(2), Arg::Dec(-8)]);
+                    }
+                },
+                None,
+            );
+            out
+        })
+    });
+    group.finish();
+}
+
+fn execute(c: &mut Criterion) {
+    let code: Vec<u8> = LOOP.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+    let mut map = AddressMap::new(1 << 20);
+    map.map(0x10000..0x11000, Perm::RX).unwrap();
+    let mut linear = vec![0u8; 1 << 20];
+    let mut memory = GuestMemory::new(&mut map, &mut linear);
+    memory.init(0x10000, &code).unwrap();
+    let config = Config::new();
+    let mut group = c.benchmark_group("runtime");
+    group.throughput(Throughput::Elements(3 + 3 * 100_000 + 2));
+    group.bench_function("interpret loop", |b| {
+        b.iter(|| {
+            let mut env = SyscallEnv::new(&config);
+            let mut regs = Registers::new(0x10000, 0);
+            let mut interp = Interpreter::new(&config);
+            let stop = loop {
+                match interp.run(&mut env, &mut memory, &mut regs, u64::MAX) {
+                    Stop::Yield | Stop::Hot(_) => continue,
+                    stop => break stop,
+                }
+            };
+            assert!(matches!(stop, Stop::Exit(_)), "{:?}", stop);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode, emit, execute);
+criterion_main!(benches);
@@ -1,12 +1,306 @@
-use doublejit_vm::frontend::binary::Binary;
-
-fn main(){
-    let path = std::env::args().nth(1).expect("no path given");
-    let bin =  Binary::parse(include_bytes!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        path
-    )))
-    .unwrap();
-    // middle end, invoke native and have lock to prevent execution
-    // let mut middleend = MiddleEnd::new();
-}
\ No newline at end of file
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use doublejit_vm::codegen::optimizer::Optimizer;
+use doublejit_vm::error::VmError;
+use doublejit_vm::frontend::binary::get_memory_initializers;
+use doublejit_vm::frontend::elf::ElfFile;
+use doublejit_vm::middleend::address_map::{AddressMap, GuestMemory};
+use doublejit_vm::middleend::standalone::emit_standalone;
+use doublejit_vm::runtime::config::{Config, OptLevel};
+use doublejit_vm::runtime::execution::GuestExit;
+use doublejit_vm::runtime::interp::Interpreter;
+use doublejit_vm::runtime::layout::MemoryLayout;
+use doublejit_vm::runtime::pgo::BlockProfile;
+use doublejit_vm::runtime::process::{self, ProcessError, ProcessImage};
+use doublejit_vm::runtime::syscall::SyscallEnv;
+use doublejit_vm::tools::artifacts::Artifacts;
+use doublejit_vm::tools::backtrace::Unwinder;
+use doublejit_vm::tools::debugger::Debugger;
+use doublejit_vm::tools::inspect::Inspector;
+use doublejit_vm::tools::perf::{Profiler, BACKEND_COMPILE};
+use doublejit_vm::tools::trace::Tracer;
+use doublejit_vm::wasm::disk_cache::DiskCache;
+use doublejit_vm::wasm::error::BackendError;
+use doublejit_vm::wasm::wasm_builder::WasmBuilder;
+use tracing_subscriber::EnvFilter;
+
+const USAGE: &str = "usage:
+  doublejit-runner translate [-o DIR] [--strict-memory] <elf>
+  doublejit-runner standalone [--code FILE] [--strict-memory] [-o FILE] <elf>
+  doublejit-runner compile [-O baseline|optimized] [--opt-wat FILE] [--wasm FILE] [--no-cache] [--profile] <module.wat>
+  doublejit-runner [run] [--seed N] [--debug-runtime] [--debug] [--profile] [--profile-json FILE]
+      [--record-blocks FILE] [--block-profile FILE] [--no-cache] [--trace FILE] [--trace-events blocks,instructions,syscalls] [--env KEY=VALUE] <elf> [args...]
+FILE is - for stdout, --code replaces the guest translated from the elf with code defining $guest_entry, --trace writes JSON Lines of the blocks and syscalls unless --trace-events picks others,
+--record-blocks writes the blocks the run ran for a later run to compile with --block-profile";
+
+fn main() -> Result<(), VmError> {
+    // RUST_LOG picks the diagnostics, e.g. RUST_LOG=doublejit::syscall=trace
+    // with --debug-runtime in a build with the debug-runtime feature
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("translate") => translate(args.skip(1)),
+        Some("standalone") => standalone(args.skip(1)),
+        Some("compile") => compile(args.skip(1)),
+        Some("run") => run(args.skip(1)),
+        Some("-h" | "--help") | None => {
+            eprintln!("{}", USAGE);
+            Ok(())
+        }
+        // a binary without a subcommand runs, as it always did
+        Some(_) => run(args),
+    }
+}
+
+/// Write `bytes` to `path`, or to stdout for `-`
+fn emit(path: &str, bytes: &[u8]) -> Result<(), VmError> {
+    if path == "-" {
+        std::io::stdout().write_all(bytes)?;
+    } else {
+        std::fs::write(path, bytes)?;
+    }
+    Ok(())
+}
+
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> String {
+    args.next().unwrap_or_else(|| panic!("{} needs a value\n{}", flag, USAGE))
+}
+
+/// Translate the basic blocks of the executable segments into one module
+/// and write every stage into the output directory: the module the middle
+/// end emits, the optimized module, its .wasm and the list of the blocks
+fn translate(mut args: impl Iterator<Item = String>) -> Result<(), VmError> {
+    let mut config = Config::new();
+    let (mut out, mut path) = (String::from("."), None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = value(&mut args, "-o"),
+            "--strict-memory" => config = config.strict_memory(true),
+            _ => path = Some(arg),
+        }
+    }
+    let data = std::fs::read(path.expect(USAGE))?;
+    Artifacts::translate(&data, &config)?.write(out.as_ref())?;
+    Ok(())
+}
+
+/// Translate the executable into a self-contained module holding its image
+/// and importing only WASI, and write it out as .wasm for any WASM runtime
+/// to run. `--code` gives guest code defining `$guest_entry` to wrap instead.
+fn standalone(mut args: impl Iterator<Item = String>) -> Result<(), VmError> {
+    let mut config = Config::new();
+    let (mut code, mut out, mut path) = (None, String::from("-"), None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--code" => code = Some(value(&mut args, "--code")),
+            "--strict-memory" => config = config.strict_memory(true),
+            "-o" => out = value(&mut args, "-o"),
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.expect(USAGE);
+    let data = std::fs::read(&path)?;
+    let Some(code) = code else {
+        let wat = Artifacts::standalone(&data, &config, &[path])?;
+        return emit(&out, &WasmBuilder::assemble(&wat)?);
+    };
+    let code = std::fs::read_to_string(code)?;
+    let elf = ElfFile::new(&data)?;
+    let image = ProcessImage::new(&elf, &MemoryLayout::default())?;
+    let mut map = AddressMap::new(AddressMap::DEFAULT_SIZE);
+    image.map(&mut map).map_err(ProcessError::from)?;
+    let segments = get_memory_initializers(&elf, image.load_bias)?;
+    let wat = emit_standalone(&map, &config, &segments, &code)?;
+    emit(&out, &WasmBuilder::assemble(&wat)?)
+}
+
+/// Optimize a module the middle end emitted, assemble it and compile it
+/// with the backend, writing out each stage asked for. The compiled module
+/// is cached in the user's cache unless asked not to, and compiling the
+/// same module again loads it from there.
+fn compile(mut args: impl Iterator<Item = String>) -> Result<(), VmError> {
+    let mut level = OptLevel::Optimized;
+    let (mut opt_wat, mut wasm, mut profile, mut path) = (None, None, false, None);
+    let mut cache = true;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O" => {
+                level = match value(&mut args, "-O").as_str() {
+                    "baseline" => OptLevel::Baseline,
+                    "optimized" => OptLevel::Optimized,
+                    other => panic!("unknown level {}\n{}", other, USAGE),
+                }
+            }
+            "--opt-wat" => opt_wat = Some(value(&mut args, "--opt-wat")),
+            "--wasm" => wasm = Some(value(&mut args, "--wasm")),
+            "--no-cache" => cache = false,
+            "--profile" => profile = true,
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+    let wat = std::fs::read_to_string(path.expect(USAGE))?;
+    let profiler = Profiler::new();
+    let builder = WasmBuilder::with_opt_level(level);
+    let build = || {
+        let optimized = Optimizer::new(level).run(&wat, Some(&profiler)).map_err(BackendError::Optimize)?;
+        if let Some(path) = &opt_wat {
+            emit(path, optimized.as_bytes())?;
+        }
+        let binary = WasmBuilder::assemble(&optimized)?;
+        if let Some(path) = &wasm {
+            emit(path, &binary)?;
+        }
+        Ok::<_, VmError>(profiler.time(BACKEND_COMPILE, || builder.compile(&binary))?)
+    };
+    // a cached module has no stages left to write out
+    match DiskCache::user().filter(|_| cache && opt_wat.is_none() && wasm.is_none()) {
+        Some(cache) => {
+            let key = DiskCache::key(wat.as_bytes(), &Config::new().opt_level(level), 0);
+            cache.get_or_compile(&builder, &key, build)?;
+        }
+        None => {
+            build()?;
+        }
+    }
+    if profile {
+        eprint!("{}", profiler.report());
+    }
+    Ok(())
+}
+
+fn run(mut args: impl Iterator<Item = String>) -> Result<(), VmError> {
+    let mut config = Config::new();
+    let mut path = None;
+    let mut envs = Vec::new();
+    let mut debug = false;
+    let (mut profile, mut profile_json) = (false, None);
+    let mut record_blocks = None;
+    let mut cache = true;
+    let (mut trace, mut trace_events) = (None, String::from("blocks,syscalls"));
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let seed = value(&mut args, "--seed");
+                config = config.seed(seed.parse().expect("invalid seed"));
+            }
+            "--debug-runtime" => config = config.debug_runtime(true),
+            "--debug" => debug = true,
+            "--profile" => profile = true,
+            "--profile-json" => profile_json = Some(value(&mut args, "--profile-json")),
+            "--record-blocks" => {
+                let recorded = Arc::new(Mutex::new(BlockProfile::new()));
+                config = config.record_blocks(recorded.clone());
+                record_blocks = Some((value(&mut args, "--record-blocks"), recorded));
+            }
+            "--no-cache" => cache = false,
+            "--block-profile" => {
+                let file = std::fs::File::open(value(&mut args, "--block-profile"))?;
+                config = config.block_profile(BlockProfile::load(std::io::BufReader::new(file))?);
+            }
+            "--trace" => trace = Some(value(&mut args, "--trace")),
+            "--trace-events" => trace_events = value(&mut args, "--trace-events"),
+            "--env" => {
+                let env = args.next().expect("--env needs KEY=VALUE");
+                let (key, value) = env.split_once('=').expect("--env needs KEY=VALUE");
+                envs.push((key.to_string(), value.to_string()));
+            }
+            _ => {
+                path = Some(arg);
+                // everything after the binary is passed to the guest
+                break;
+            }
+        }
+    }
+    let path = path.expect("no path given");
+    let mut config = config
+        .args(std::iter::once(path.clone()).chain(args))
+        .envs(envs);
+    if profile || profile_json.is_some() {
+        config = config.profiler(Profiler::new());
+    }
+    if let Some(trace) = trace {
+        config = config.trace(tracer(&trace, &trace_events)?);
+    }
+    if let Some(cache) = DiskCache::user().filter(|_| cache) {
+        config = config.cache_dir(cache.dir());
+    }
+    let data = std::fs::read(&path)?;
+    let result = match debug {
+        true => debug_guest(&data, &config),
+        false => run_guest(&data, &config),
+    };
+    // the report covers a run that failed too, up to where it failed
+    if let Some(profiler) = &config.profiler {
+        if profile {
+            eprint!("{}", profiler.report());
+        }
+        if let Some(path) = profile_json {
+            emit(&path, profiler.export_json().as_bytes())?;
+        }
+    }
+    if let Some(tracer) = &config.trace {
+        tracer.flush()?;
+    }
+    if let Some((path, recorded)) = record_blocks {
+        let mut out = Vec::new();
+        recorded.lock().unwrap().save(&mut out)?;
+        emit(&path, &out)?;
+    }
+    // the runner exits with the guest's status, as a shell would
+    match result? {
+        0 => Ok(()),
+        code => std::process::exit(code),
+    }
+}
+
+/// A tracer into `path`, or stdout for `-`, recording the comma separated
+/// kinds of `events`
+fn tracer(path: &str, events: &str) -> Result<Tracer, VmError> {
+    let mut tracer = match path {
+        "-" => Tracer::new(std::io::stdout()),
+        path => Tracer::create(path)?,
+    };
+    for event in events.split(',') {
+        tracer = match event {
+            "blocks" => tracer.blocks(true),
+            "instructions" => tracer.instructions(true),
+            "syscalls" => tracer.syscalls(true),
+            other => panic!("unknown trace event {}\n{}", other, USAGE),
+        };
+    }
+    Ok(tracer)
+}
+
+/// Load the executable as linux would and run it in the interpreter until
+/// the process ends, giving the status a shell would report
+fn run_guest(data: &[u8], config: &Config) -> Result<i32, VmError> {
+    Ok(process::execute(data, config)?.exit_code())
+}
+
+/// Load the executable as linux would and run it in the interpreter under
+/// the debugger, reading commands from stdin
+fn debug_guest(data: &[u8], config: &Config) -> Result<i32, VmError> {
+    let mut map = AddressMap::new(AddressMap::DEFAULT_SIZE);
+    let mut linear = vec![0u8; AddressMap::DEFAULT_SIZE];
+    let mut env = SyscallEnv::new(config);
+    let (image, mut regs) = process::load(data, config, &env, &mut map, &mut linear)?;
+    let mut memory = GuestMemory::new(&mut map, &mut linear);
+    let mut interp = Interpreter::new(config);
+    let inspector = Inspector::from_elf(data, image.load_bias)?.segment(image.stack.clone(), "[stack]");
+    let exit = Debugger::new(&mut interp, &mut env, &mut memory, &mut regs)
+        .unwinder(Unwinder::from_elf(data)?)
+        .inspector(inspector)
+        .run(std::io::stdin().lock(), std::io::stdout())?;
+    if let Some(exit) = &exit {
+        eprintln!("{}", exit);
+    }
+    Ok(match exit {
+        Some(GuestExit::Process(result)) => result.exit_code(),
+        _ => 0,
+    })
+}
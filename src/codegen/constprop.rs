@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use crate::codegen::fold::eval;
+use crate::codegen::ir::{Func, Node, Op, ValType};
+use crate::codegen::passes::Fuel;
+
+/// The variables holding a known constant at a point, as `l$name` and
+/// `g$name`, `None` where the code cannot be reached
+type Consts = Option<HashMap<String, (ValType, i64)>>;
+
+/// What two paths joining agree on
+fn meet(a: Consts, b: Consts) -> Consts {
+    match (a, b) {
+        (None, consts) | (consts, None) => consts,
+        (Some(a), Some(b)) => Some(a.into_iter().filter(|(var, value)| b.get(var) == Some(value)).collect()),
+    }
+}
+
+/// The variable a `local.*` or `global.*` instruction reads or writes
+fn var(op: &Op) -> Option<String> {
+    let scope = match op.name.split_once('.')? {
+        ("local", _) => "l",
+        ("global", _) => "g",
+        _ => return None,
+    };
+    Some(format!("{}{}", scope, op.imms.first()?))
+}
+
+/// The value of `node` if it is constant where `consts` hold
+fn constant(node: &Node, consts: &HashMap<String, (ValType, i64)>) -> Option<(ValType, i64)> {
+    let op = node.as_op()?;
+    match op.name.as_str() {
+        "i32.const" | "i64.const" => node.as_const(),
+        "local.get" | "global.get" => consts.get(&var(op)?).copied(),
+        name => {
+            let args = op.args.iter().map(|arg| constant(arg, consts).map(|c| c.1)).collect::<Option<Vec<_>>>()?;
+            eval(name, &args).filter(|_| !args.is_empty())
+        }
+    }
+}
+
+struct Propagation<'a> {
+    /// The enclosing labels, innermost last, with what the branches to
+    /// each carry: to the end of a block, or the head of a loop
+    labels: Vec<(Option<String>, Consts)>,
+    rewrite: bool,
+    fuel: &'a mut Fuel,
+}
+
+impl Propagation<'_> {
+    /// Join `consts` into what reaches `label`, a name or a depth
+    fn branch(&mut self, label: &str, consts: &Consts) {
+        let index = match label.parse::<usize>() {
+            Ok(depth) => self.labels.len().checked_sub(depth + 1),
+            Err(_) => self.labels.iter().rposition(|(name, _)| name.as_deref() == Some(label)),
+        };
+        // a branch to the function body returns
+        if let Some(index) = index {
+            let reached = self.labels[index].1.take();
+            self.labels[index].1 = meet(reached, consts.clone());
+        }
+    }
+
+    fn body(&mut self, nodes: &mut [Node], mut consts: Consts) -> Consts {
+        for node in nodes {
+            consts = self.node(node, consts);
+        }
+        consts
+    }
+
+    /// Run `body` under `label`, and get what reaches the label and what
+    /// falls through
+    fn labeled(&mut self, label: &Option<String>, body: &mut [Node], consts: Consts) -> (Consts, Consts) {
+        self.labels.push((label.clone(), None));
+        let out = self.body(body, consts);
+        (self.labels.pop().unwrap().1, out)
+    }
+
+    fn node(&mut self, node: &mut Node, consts: Consts) -> Consts {
+        let known = consts?;
+        match node {
+            Node::Op(op) => {
+                let mut consts = Some(known);
+                for arg in &mut op.args {
+                    consts = self.node(arg, consts);
+                }
+                let mut known = consts?;
+                let name = op.name.as_str();
+                match name {
+                    "local.get" | "global.get" => {
+                        let value = var(op).and_then(|var| known.get(&var).copied());
+                        if let Some((ty, value)) = value.filter(|_| self.rewrite && self.fuel.spend()) {
+                            *node = Node::constant(ty, value);
+                        }
+                    }
+                    "local.set" | "local.tee" | "global.set" => {
+                        let var = var(op).unwrap_or_default();
+                        match op.args.first().and_then(|value| constant(value, &known)) {
+                            Some(value) => known.insert(var, value),
+                            None => known.remove(&var),
+                        };
+                    }
+                    "br" => {
+                        self.branch(&op.imms[0], &Some(known));
+                        return None;
+                    }
+                    "br_if" => self.branch(&op.imms[0], &Some(known.clone())),
+                    "br_table" => {
+                        let consts = Some(known);
+                        op.imms.iter().for_each(|label| self.branch(label, &consts));
+                        return None;
+                    }
+                    "return" | "unreachable" => return None,
+                    // the host may change any register
+                    _ if name.starts_with("call") => known.retain(|var, _| !var.starts_with('g')),
+                    _ => {}
+                }
+                Some(known)
+            }
+            Node::Block { looping: false, label, body, .. } => {
+                let (reached, out) = self.labeled(label, body, Some(known));
+                meet(reached, out)
+            }
+            Node::Block { looping: true, label, body, .. } => {
+                // the head gets what enters the loop and what the back-edges
+                // bring back, which shrinks until it settles
+                let rewrite = std::mem::replace(&mut self.rewrite, false);
+                let mut head = Some(known.clone());
+                loop {
+                    let (back, _) = self.labeled(label, body, head.clone());
+                    let next = meet(Some(known.clone()), back);
+                    if next == head {
+                        break;
+                    }
+                    head = next;
+                }
+                self.rewrite = rewrite;
+                self.labeled(label, body, head).1
+            }
+            Node::If { label, cond, then, otherwise, .. } => {
+                let consts = self.node(cond, Some(known));
+                let (reached, then) = self.labeled(label, then, consts.clone());
+                let (reached_else, otherwise) = self.labeled(label, otherwise, consts);
+                meet(meet(reached, reached_else), meet(then, otherwise))
+            }
+            Node::Comment(_) => Some(known),
+        }
+    }
+}
+
+/// Constant propagation over the control flow: a register or local holding
+/// a constant is read as the constant where every path to the read agrees
+/// on it. At the end of an if or a block the paths meet and keep only what
+/// they all know, and a loop keeps only what holds on every back-edge as
+/// well as on entry.
+pub fn propagate_constants(func: &mut Func, fuel: &mut Fuel) {
+    let mut propagation = Propagation { labels: Vec::new(), rewrite: true, fuel };
+    propagation.body(&mut func.body, Some(HashMap::new()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::ir::Module;
+
+    #[test]
+    fn test_propagate_constants() {
+        let wat = "(module (func $f (result i64)
+            (global.set $x5 (i64.const 1))
+            (global.set $x13 (i64.shl (global.get $x5) (i64.const 3)))
+            (if (i64.eqz (global.get $x9))
+                (then (global.set $x6 (i64.const 2)) (global.set $x7 (i64.const 3)))
+                (else (global.set $x6 (i64.const 2))))
+            (global.set $x8 (i64.add (global.get $x6) (global.get $x7)))
+            (loop $next
+                (global.set $x10 (i64.add (global.get $x5) (global.get $x13)))
+                (global.set $x5 (i64.add (global.get $x5) (i64.const 1)))
+                (br_if $next (i64.lt_u (global.get $x5) (i64.const 8))))
+            (block $skip
+                (br_if $skip (i64.eqz (global.get $x9)))
+                (global.set $x11 (i64.const 4)))
+            (global.set $x12 (global.get $x11))
+            (i64.const 0)))";
+        let mut module = Module::parse(wat).unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        propagate_constants(func, &mut Fuel::unlimited());
+        let body: Vec<String> = func.body.iter().map(|node| node.to_string()).collect();
+        assert_eq!(body[1], "(global.set $x13 (i64.shl (i64.const 1) (i64.const 3)))");
+        // both arms set $x6 to 2, only one sets $x7
+        assert_eq!(body[3], "(global.set $x8 (i64.add (i64.const 2) (global.get $x7)))");
+        // $x5 changes on the back-edge, $x13 does not
+        assert!(body[4].contains("(global.set $x10 (i64.add (global.get $x5) (i64.const 8)))"));
+        // the branch skips the set
+        assert_eq!(body[6], "(global.set $x12 (global.get $x11))");
+    }
+}
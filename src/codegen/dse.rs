@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+
+use crate::codegen::ir::{Func, Node};
+use crate::codegen::passes::Fuel;
+
+/// Variables live at a point, as `l$name` and `g$name`
+type Live = HashSet<String>;
+
+/// Whether the instruction may trap or hand control to the host, which
+/// then sees every register in its global
+fn leaves(name: &str) -> bool {
+    name.starts_with("call")
+        || name == "unreachable"
+        || ["load", "store", "div", "rem", "trunc", "atomic", "memory."].iter().any(|op| name.contains(op))
+}
+
+fn nop() -> Node {
+    Node::op("nop", Vec::new(), Vec::new())
+}
+
+struct Liveness<'a> {
+    /// Every global the function touches, all live where the guest leaves
+    /// the function
+    globals: Live,
+    /// What is live where each enclosing label branches to, innermost last
+    labels: Vec<(Option<String>, Live)>,
+    fuel: &'a mut Fuel,
+}
+
+impl Liveness<'_> {
+    /// Live at the target of a branch to `label`, a name or a depth
+    fn target(&self, label: &str) -> Live {
+        let index = match label.parse::<usize>() {
+            Ok(depth) => self.labels.len().checked_sub(depth + 1),
+            Err(_) => self.labels.iter().rposition(|(name, _)| name.as_deref() == Some(label)),
+        };
+        // a branch to the function body leaves it
+        index.map_or_else(|| self.globals.clone(), |index| self.labels[index].1.clone())
+    }
+
+    /// Live before `nodes` when `live` is live after them. With `rewrite`
+    /// the writes nothing reads are removed on the way.
+    fn body(&mut self, nodes: &mut Vec<Node>, mut live: Live, rewrite: bool) -> Live {
+        for node in nodes.iter_mut().rev() {
+            live = self.node(node, live, rewrite);
+        }
+        if rewrite {
+            nodes.retain(|node| *node != nop());
+        }
+        live
+    }
+
+    fn node(&mut self, node: &mut Node, mut live: Live, rewrite: bool) -> Live {
+        match node {
+            Node::Op(op) => {
+                match op.name.as_str() {
+                    "local.set" | "local.tee" | "global.set" => {
+                        let var = format!("{}{}", &op.name[..1], op.imms[0]);
+                        if !live.remove(&var) && rewrite && self.fuel.spend() {
+                            let value = op.args.pop().unwrap_or_else(nop);
+                            *node = match op.name.as_str() {
+                                "local.tee" => value,
+                                _ if value.has_effects() => Node::op("drop", Vec::new(), vec![value]),
+                                _ => nop(),
+                            };
+                            return self.node(node, live, rewrite);
+                        }
+                    }
+                    "local.get" | "global.get" => {
+                        live.insert(format!("{}{}", &op.name[..1], op.imms[0]));
+                    }
+                    "br" => live = self.target(&op.imms[0]),
+                    "br_if" => live.extend(self.target(&op.imms[0])),
+                    "br_table" => live = op.imms.iter().flat_map(|label| self.target(label)).collect(),
+                    "return" => live = self.globals.clone(),
+                    name if leaves(name) => live.extend(self.globals.iter().cloned()),
+                    _ => {}
+                }
+                for arg in op.args.iter_mut().rev() {
+                    live = self.node(arg, live, rewrite);
+                }
+                live
+            }
+            Node::Block { looping: false, label, body, .. } => {
+                self.labels.push((label.clone(), live.clone()));
+                let live = self.body(body, live, rewrite);
+                self.labels.pop();
+                live
+            }
+            Node::Block { looping: true, label, body, .. } => {
+                // what is live at the head flows around the back-edges until
+                // it settles, only then is anything removed
+                let mut head = Live::new();
+                loop {
+                    self.labels.push((label.clone(), head.clone()));
+                    let before = self.body(body, live.clone(), false);
+                    self.labels.pop();
+                    if before == head {
+                        break;
+                    }
+                    head = before;
+                }
+                self.labels.push((label.clone(), head));
+                let live = self.body(body, live, rewrite);
+                self.labels.pop();
+                live
+            }
+            Node::If { label, cond, then, otherwise, .. } => {
+                self.labels.push((label.clone(), live.clone()));
+                let mut before = self.body(then, live.clone(), rewrite);
+                before.extend(self.body(otherwise, live, rewrite));
+                self.labels.pop();
+                self.node(cond, before, rewrite)
+            }
+            Node::Comment(_) => live,
+        }
+    }
+}
+
+/// Remove writes to registers and locals that are written again before
+/// anything reads them, by liveness analysis over the whole function. The
+/// registers are all live wherever the guest may leave the function: at its
+/// end, at a call, which may hand them to the host, and at anything that
+/// may trap. A value with effects of its own is still computed and dropped.
+pub fn eliminate_dead_stores(func: &mut Func, fuel: &mut Fuel) {
+    let mut globals = Live::new();
+    for node in &func.body {
+        node.walk(&mut |node| {
+            if let Some(global) = node.var("global.get").or(node.var("global.set")) {
+                globals.insert(format!("g{}", global));
+            }
+        });
+    }
+    let mut liveness = Liveness { globals: globals.clone(), labels: Vec::new(), fuel };
+    liveness.body(&mut func.body, globals, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::ir::Module;
+
+    #[test]
+    fn test_dead_stores() {
+        let wat = "(module (func $f (result i64) (local $r6 i64)
+            (global.set $x5 (i64.const 1))
+            (global.set $x5 (i64.const 2))
+            (local.set $r6 (global.get $x5))
+            (local.set $r6 (i64.const 7))
+            (global.set $x7 (local.get $r6))
+            (global.set $x8 (call $load_64 (i64.const 0)))
+            (global.set $x8 (i64.const 3))
+            (if (i32.eqz (global.get $x9)) (then (global.set $x9 (i64.const 1)) (global.set $x10 (i64.const 1))) (else (br 1)))
+            (global.set $x9 (i64.const 2))
+            (block $exit (loop $next
+                (global.set $x11 (i64.add (global.get $x11) (i64.const 1)))
+                (br_if $next (i64.lt_u (global.get $x11) (i64.const 8)))
+                (global.set $x12 (i64.const 0))
+                (br $exit)))
+            (global.set $x12 (i64.const 1))
+            (i64.const 0)))";
+        let mut module = Module::parse(wat).unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        eliminate_dead_stores(func, &mut Fuel::unlimited());
+        let body: Vec<String> = func.body.iter().map(|node| node.to_string()).collect();
+        assert_eq!(body[0], "(global.set $x5 (i64.const 2))");
+        assert_eq!(body[1], "(local.set $r6 (i64.const 7))");
+        assert_eq!(body[3], "(drop (call $load_64 (i64.const 0)))");
+        // the then arm falls through to $x9 being written again, while
+        // $x10 is read by whoever runs next
+        assert!(!body[5].contains("(global.set $x9 (i64.const 1))"));
+        assert!(body[5].contains("(global.set $x10 (i64.const 1))"));
+        // the loop increments $x11 for its next run, and $x12 is written
+        // again after the loop
+        assert!(body[7].contains("(global.set $x11"));
+        assert!(!body[7].contains("$x12"));
+        assert_eq!(body[8], "(global.set $x12 (i64.const 1))");
+    }
+}
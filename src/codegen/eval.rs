@@ -0,0 +1,501 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+use crate::codegen::fold::eval;
+use crate::codegen::ir::{parse_int, Func, IrError, Item, Module, Node};
+use crate::runtime::rng::Rng;
+
+/// Instructions a run may take before it is given up on, the loops need not
+/// end from a random state
+const MAX_STEPS: usize = 20_000;
+
+/// Runs of each function from a different random state
+const TRIALS: u64 = 8;
+
+/// The most bytes a `memory.copy` or `memory.fill` may touch
+const MAX_BULK: u64 = 1 << 16;
+
+/// Why a run did not return
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Stop {
+    /// The code trapped, an outcome like a return
+    Trap,
+    /// The evaluator cannot tell what the code does: it runs too long or
+    /// uses what the evaluator does not model
+    Unknown,
+}
+
+/// Where control goes after a node
+enum Flow {
+    Next,
+    /// To the label this many blocks out
+    Branch(usize),
+    Return,
+}
+
+/// A value derived from the seed of a run and `key`, the same in the runs
+/// of both versions of the code
+fn noise(seed: u64, key: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    Rng::new(seed ^ hasher.finish()).next_u64()
+}
+
+/// The number of parameters and results in a signature, e.g. of
+/// `(param $addr i64) (param $len i64) (result i32)`
+fn arity<'a>(items: impl Iterator<Item = &'a str>) -> (usize, usize) {
+    let (mut params, mut results) = (0, 0);
+    for item in items {
+        let mut words = item.split(|c: char| c.is_whitespace() || c == '(' || c == ')').filter(|word| !word.is_empty());
+        let count = match words.next() {
+            Some("param") => &mut params,
+            Some("result") => &mut results,
+            _ => continue,
+        };
+        *count += words.filter(|word| !word.starts_with('$')).count();
+    }
+    (params, results)
+}
+
+/// The parameter and result counts of a function import, from its text
+fn import(text: &str) -> Option<(String, (usize, usize))> {
+    let func = &text[text.find("(func ")? + 6..];
+    let name = func.split_whitespace().next()?.to_string();
+    let sig = func.split("(").skip(1).collect::<Vec<_>>();
+    Some((name, arity(sig.into_iter())))
+}
+
+struct Frame {
+    locals: Vec<i64>,
+    names: HashMap<String, usize>,
+    stack: Vec<i64>,
+    labels: Vec<Option<String>>,
+}
+
+impl Frame {
+    fn local(&self, name: &str) -> Result<usize, Stop> {
+        let index = self.names.get(name).copied().or_else(|| name.parse().ok());
+        index.filter(|&index| index < self.locals.len()).ok_or(Stop::Unknown)
+    }
+
+    fn pop(&mut self) -> Result<i64, Stop> {
+        self.stack.pop().ok_or(Stop::Unknown)
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<i64>, Stop> {
+        let at = self.stack.len().checked_sub(n).ok_or(Stop::Unknown)?;
+        Ok(self.stack.split_off(at))
+    }
+
+    /// How many blocks out `label`, a name or a depth, is
+    fn depth(&self, label: &str) -> Result<usize, Stop> {
+        match label.parse() {
+            Ok(depth) => Ok(depth),
+            Err(_) => self.labels.iter().rev().position(|name| name.as_deref() == Some(label)).ok_or(Stop::Unknown),
+        }
+    }
+}
+
+/// Reference evaluator of the IR, on a random initial state. Registers and
+/// memory not written yet read as noise derived from the seed, and host
+/// functions return noise derived from their arguments.
+struct Machine<'a> {
+    funcs: HashMap<&'a str, &'a Func>,
+    imports: HashMap<String, (usize, usize)>,
+    seed: u64,
+    /// Registers start out small on some runs, to land in guest memory
+    small: bool,
+    memory_size: u64,
+    /// Registers read or written
+    globals: BTreeMap<String, i64>,
+    /// Bytes of memory written, by address
+    memory: BTreeMap<u64, u8>,
+    /// Host functions called, with their arguments
+    calls: Vec<(String, Vec<i64>)>,
+    steps: usize,
+}
+
+impl<'a> Machine<'a> {
+    fn new(module: &'a Module, seed: u64, small: bool) -> Self {
+        let mut funcs = HashMap::new();
+        let mut imports = HashMap::new();
+        let mut memory_size = 0;
+        for item in &module.items {
+            match item {
+                Item::Func(func) => {
+                    if let Some(name) = &func.name {
+                        funcs.insert(name.as_str(), func);
+                    }
+                }
+                Item::Other(text) => {
+                    if let Some(memory) = text.find("(memory ").map(|at| &text[at + 8..]) {
+                        let pages = memory.split_whitespace().next().and_then(parse_int).unwrap_or_default();
+                        memory_size = pages as u64 * (1 << 16);
+                    }
+                    imports.extend(import(text));
+                }
+            }
+        }
+        Self {
+            funcs,
+            imports,
+            seed,
+            small,
+            memory_size,
+            globals: BTreeMap::new(),
+            memory: BTreeMap::new(),
+            calls: Vec::new(),
+            steps: 0,
+        }
+    }
+
+    /// The value of `name`, a register or argument, before the run
+    fn initial(&self, name: &str) -> i64 {
+        match self.small {
+            true => (noise(self.seed, name) % self.memory_size.max(1)) as i64,
+            false => noise(self.seed, name) as i64,
+        }
+    }
+
+    fn global(&mut self, name: &str) -> i64 {
+        let value = self.initial(name);
+        *self.globals.entry(name.to_string()).or_insert(value)
+    }
+
+    fn byte(&self, addr: u64) -> u8 {
+        self.memory.get(&addr).copied().unwrap_or_else(|| noise(self.seed, addr) as u8)
+    }
+
+    /// The address of `width` bytes at `addr + offset`, trapping outside of
+    /// memory
+    fn address(&self, addr: i64, offset: u64, width: u64) -> Result<u64, Stop> {
+        let addr = addr as u32 as u64 + offset;
+        match addr + width <= self.memory_size {
+            true => Ok(addr),
+            false => Err(Stop::Trap),
+        }
+    }
+
+    fn call(&mut self, name: &str, args: Vec<i64>) -> Result<Vec<i64>, Stop> {
+        if let Some(&func) = self.funcs.get(name) {
+            return self.invoke(func, args);
+        }
+        let (_, results) = self.imports.get(name).copied().ok_or(Stop::Unknown)?;
+        let values = (0..results).map(|n| noise(self.seed, (name, &args, n)) as i64).collect();
+        self.calls.push((name.to_string(), args));
+        Ok(values)
+    }
+
+    fn invoke(&mut self, func: &Func, args: Vec<i64>) -> Result<Vec<i64>, Stop> {
+        let (params, results) = arity(func.sig.iter().map(String::as_str));
+        if args.len() != params {
+            return Err(Stop::Unknown);
+        }
+        let mut names = HashMap::new();
+        let named = func.sig.iter().filter(|item| item.starts_with("(param"));
+        for (index, item) in named.enumerate() {
+            if let Some(name) = item.split_whitespace().nth(1).filter(|name| name.starts_with('$')) {
+                names.insert(name.to_string(), index);
+            }
+        }
+        for (index, local) in func.locals.iter().enumerate() {
+            if let Some(name) = &local.name {
+                names.insert(name.clone(), params + index);
+            }
+        }
+        let mut locals = args;
+        locals.resize(params + func.locals.len(), 0);
+        let mut frame = Frame { locals, names, stack: Vec::new(), labels: Vec::new() };
+        self.body(&mut frame, &func.body)?;
+        frame.pop_n(results)
+    }
+
+    fn body(&mut self, frame: &mut Frame, body: &[Node]) -> Result<Flow, Stop> {
+        for node in body {
+            match self.node(frame, node)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Run `body` as a block, which a branch to leaves or, `looping`,
+    /// starts over
+    fn block(&mut self, frame: &mut Frame, label: &Option<String>, result: &Option<String>, looping: bool, body: &[Node]) -> Result<Flow, Stop> {
+        let height = frame.stack.len();
+        frame.labels.push(label.clone());
+        let flow = loop {
+            match self.body(frame, body)? {
+                Flow::Branch(0) if looping => frame.stack.truncate(height),
+                Flow::Branch(0) => {
+                    let values = frame.pop_n(result.is_some() as usize)?;
+                    frame.stack.truncate(height);
+                    frame.stack.extend(values);
+                    break Flow::Next;
+                }
+                Flow::Branch(depth) => break Flow::Branch(depth - 1),
+                flow => break flow,
+            }
+        };
+        frame.labels.pop();
+        Ok(flow)
+    }
+
+    fn node(&mut self, frame: &mut Frame, node: &Node) -> Result<Flow, Stop> {
+        let op = match node {
+            Node::Op(op) => op,
+            Node::Block { looping, label, result, body } => return self.block(frame, label, result, *looping, body),
+            Node::If { label, result, cond, then, otherwise } => {
+                if let flow @ (Flow::Branch(_) | Flow::Return) = self.node(frame, cond)? {
+                    return Ok(flow);
+                }
+                let arm = if frame.pop()? as i32 != 0 { then } else { otherwise };
+                return self.block(frame, label, result, false, arm);
+            }
+            Node::Comment(_) => return Ok(Flow::Next),
+        };
+        for arg in &op.args {
+            if let flow @ (Flow::Branch(_) | Flow::Return) = self.node(frame, arg)? {
+                return Ok(flow);
+            }
+        }
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(Stop::Unknown);
+        }
+        let imm = |index: usize| op.imms.get(index).map(String::as_str).ok_or(Stop::Unknown);
+        let offset = op.imms.iter().find_map(|imm| imm.strip_prefix("offset=")).and_then(parse_int).unwrap_or_default() as u64;
+        let name = op.name.as_str();
+        match name {
+            "nop" => {}
+            "unreachable" => return Err(Stop::Trap),
+            "drop" => {
+                frame.pop()?;
+            }
+            "i32.const" | "i64.const" => frame.stack.push(node.as_const().ok_or(Stop::Unknown)?.1),
+            "local.get" => frame.stack.push(frame.locals[frame.local(imm(0)?)?]),
+            "local.set" | "local.tee" => {
+                let (index, value) = (frame.local(imm(0)?)?, frame.pop()?);
+                frame.locals[index] = value;
+                if name == "local.tee" {
+                    frame.stack.push(value);
+                }
+            }
+            "global.get" => {
+                let value = self.global(imm(0)?);
+                frame.stack.push(value);
+            }
+            "global.set" => {
+                let value = frame.pop()?;
+                self.globals.insert(imm(0)?.to_string(), value);
+            }
+            // the host grows the tables, never the code
+            "table.size" => {
+                let size = self.initial(&format!("table.size {}", imm(0)?)) as u16;
+                frame.stack.push(size as i64);
+            }
+            "select" => {
+                let [a, b, cond] = <[i64; 3]>::try_from(frame.pop_n(3)?).unwrap();
+                frame.stack.push(if cond as i32 != 0 { a } else { b });
+            }
+            "br" => return Ok(Flow::Branch(frame.depth(imm(0)?)?)),
+            "br_if" => {
+                if frame.pop()? as i32 != 0 {
+                    return Ok(Flow::Branch(frame.depth(imm(0)?)?));
+                }
+            }
+            "br_table" => {
+                let index = (frame.pop()? as u32 as usize).min(op.imms.len().saturating_sub(1));
+                return Ok(Flow::Branch(frame.depth(imm(index)?)?));
+            }
+            "return" => return Ok(Flow::Return),
+            "call" => {
+                let callee = imm(0)?;
+                let params = match self.funcs.get(callee) {
+                    Some(func) => arity(func.sig.iter().map(String::as_str)).0,
+                    None => self.imports.get(callee).ok_or(Stop::Unknown)?.0,
+                };
+                let args = frame.pop_n(params)?;
+                let results = self.call(callee, args)?;
+                frame.stack.extend(results);
+            }
+            "memory.fill" | "memory.copy" => {
+                let [dst, src, len] = <[i64; 3]>::try_from(frame.pop_n(3)?).unwrap();
+                let len = len as u32 as u64;
+                if len > MAX_BULK {
+                    return Err(Stop::Unknown);
+                }
+                let dst = self.address(dst, 0, len)?;
+                let bytes: Vec<u8> = match name {
+                    "memory.fill" => vec![src as u8; len as usize],
+                    _ => {
+                        let src = self.address(src, 0, len)?;
+                        (src..src + len).map(|addr| self.byte(addr)).collect()
+                    }
+                };
+                self.memory.extend((dst..).zip(bytes));
+            }
+            _ if name.contains(".load") && !name.contains("atomic") => {
+                let (ty, load) = name.split_once(".load").unwrap();
+                let width = match load.trim_end_matches(['_', 's', 'u']) {
+                    "8" => 1,
+                    "16" => 2,
+                    "32" => 4,
+                    "" if ty == "i32" => 4,
+                    "" if ty == "i64" => 8,
+                    _ => return Err(Stop::Unknown),
+                };
+                let addr = self.address(frame.pop()?, offset, width)?;
+                let mut value = (0..width).rev().fold(0u64, |value, n| value << 8 | self.byte(addr + n) as u64);
+                if load.ends_with("_s") || load.is_empty() && ty == "i32" {
+                    let shift = 64 - 8 * width;
+                    value = ((value << shift) as i64 >> shift) as u64;
+                }
+                frame.stack.push(value as i64);
+            }
+            _ if name.contains(".store") && !name.contains("atomic") => {
+                let (ty, store) = name.split_once(".store").unwrap();
+                let width = match store {
+                    "8" => 1,
+                    "16" => 2,
+                    "32" => 4,
+                    "" if ty == "i32" => 4,
+                    "" if ty == "i64" => 8,
+                    _ => return Err(Stop::Unknown),
+                };
+                let value = frame.pop()?;
+                let addr = self.address(frame.pop()?, offset, width)?;
+                self.memory.extend((0..width).map(|n| (addr + n, (value >> (8 * n)) as u8)));
+            }
+            _ => {
+                let unary = ["eqz", "clz", "ctz", "popcnt", "extend", "wrap"].iter().any(|op| name.contains(op));
+                let args = frame.pop_n(if unary { 1 } else { 2 })?;
+                match eval(name, &args) {
+                    Some((_, value)) => frame.stack.push(value),
+                    // the evaluation only fails for the traps of a division
+                    None if name.contains(".div_") || name.contains(".rem_") => return Err(Stop::Trap),
+                    None => return Err(Stop::Unknown),
+                }
+            }
+        }
+        Ok(Flow::Next)
+    }
+}
+
+/// Run `func` of `module` from the random state of `seed`, and get how it
+/// ended with the machine for the state it left
+fn run<'a>(module: &'a Module, func: &Func, seed: u64) -> (Result<Vec<i64>, Stop>, Machine<'a>) {
+    let mut machine = Machine::new(module, seed, seed.is_multiple_of(2));
+    let (params, _) = arity(func.sig.iter().map(String::as_str));
+    let args = (0..params).map(|n| machine.initial(&format!("param {}", n))).collect();
+    (machine.invoke(func, args), machine)
+}
+
+/// The first difference between the states two runs left
+fn difference(before: &Machine, after: &Machine) -> Option<String> {
+    if before.calls != after.calls {
+        return Some(format!("it called {:?} before and {:?} after", before.calls, after.calls));
+    }
+    let globals: BTreeSet<&String> = before.globals.keys().chain(after.globals.keys()).collect();
+    for global in globals {
+        let value = |machine: &Machine| machine.globals.get(global).copied().unwrap_or_else(|| machine.initial(global));
+        if value(before) != value(after) {
+            return Some(format!("{} is {:#x} before and {:#x} after", global, value(before), value(after)));
+        }
+    }
+    let addrs: BTreeSet<&u64> = before.memory.keys().chain(after.memory.keys()).collect();
+    for &addr in addrs {
+        if before.byte(addr) != after.byte(addr) {
+            return Some(format!("the byte at {:#x} is {:#x} before and {:#x} after", addr, before.byte(addr), after.byte(addr)));
+        }
+    }
+    None
+}
+
+/// Check that the functions of `after`, optimized from those of `before`,
+/// do what they did: run both versions of each from the same random
+/// registers, memory and arguments on the reference evaluator, and compare
+/// how they end, the registers and memory they leave and the host
+/// functions they call. Runs the evaluator cannot follow to the end are
+/// not compared.
+pub fn verify(before: &Module, after: &Module, seed: u64) -> Result<(), IrError> {
+    for (index, (old, new)) in before.funcs().zip(after.funcs()).enumerate() {
+        for trial in 0..TRIALS {
+            let seed = seed.wrapping_add(trial);
+            let ((a, old_state), (b, new_state)) = (run(before, old, seed), run(after, new, seed));
+            if a == Err(Stop::Unknown) || b == Err(Stop::Unknown) {
+                continue;
+            }
+            let difference = match a == b {
+                true => difference(&old_state, &new_state),
+                false => Some(format!("it ended in {:?} before and {:?} after", a, b)),
+            };
+            if let Some(difference) = difference {
+                let func = old.name.clone().unwrap_or_else(|| format!("{}", index));
+                return Err(IrError::Diverged { func, seed, difference });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run `func` of `module` on `args` with the bytes `memory` written, giving
+/// its results and the memory it left, to check the helpers the middle end
+/// emits
+#[cfg(test)]
+pub fn call(module: &Module, func: &str, args: Vec<i64>, memory: &[(u64, u8)]) -> Option<(Vec<i64>, BTreeMap<u64, u8>)> {
+    let mut machine = Machine::new(module, 0, false);
+    machine.memory.extend(memory.iter().copied());
+    let results = machine.call(func, args).ok()?;
+    Some((results, machine.memory))
+}
+
+/// `call` with the registers and other globals set to `globals` first,
+/// giving the globals it left as well, to check translated guest code
+/// against the interpreter
+#[cfg(test)]
+pub fn call_with(
+    module: &Module,
+    func: &str,
+    args: Vec<i64>,
+    globals: &[(String, i64)],
+    memory: &[(u64, u8)],
+) -> Option<(Vec<i64>, BTreeMap<String, i64>, BTreeMap<u64, u8>)> {
+    let mut machine = Machine::new(module, 0, false);
+    machine.globals.extend(globals.iter().cloned());
+    machine.memory.extend(memory.iter().copied());
+    let results = machine.call(func, args).ok()?;
+    Some((results, machine.globals, machine.memory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let wat = "(module (import \"env\" \"memory\" (memory 1 1)) (import \"env\" \"trace\" (func $trace (param i64)))
+            (func $f (param $n i64) (result i64) (local $i i64)
+                (block $exit (loop $next
+                    (br_if $exit (i64.ge_u (local.get $i) (i64.and (local.get $n) (i64.const 7))))
+                    (i64.store offset=8 (i32.wrap_i64 (i64.and (global.get $x10) (i64.const 0xff))) (local.get $i))
+                    (global.set $x11 (i64.add (global.get $x11) (local.get $i)))
+                    (local.set $i (i64.add (local.get $i) (i64.const 1)))
+                    (br $next)))
+                (call $trace (global.get $x11))
+                (i64.div_u (global.get $x12) (global.get $x13))))";
+        let before = Module::parse(wat).unwrap();
+        assert_eq!(verify(&before, &before, 1), Ok(()));
+        for broken in [
+            wat.replace("(i64.const 7)", "(i64.const 6)"),
+            wat.replace("offset=8", "offset=16"),
+            wat.replace("(global.get $x11))\n", "(i64.const 0))\n"),
+            wat.replace("div_u", "rem_u"),
+        ] {
+            let after = Module::parse(&broken).unwrap();
+            assert!(matches!(verify(&before, &after, 1), Err(IrError::Diverged { .. })), "{}", broken);
+        }
+    }
+}
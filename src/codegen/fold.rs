@@ -0,0 +1,197 @@
+use crate::codegen::ir::{Func, Node, Op, ValType};
+use crate::codegen::passes::Fuel;
+use crate::codegen::peephole::rewrite;
+
+const ZERO: &str = "$x0";
+
+fn eval32(name: &str, args: &[i32]) -> Option<i64> {
+    let bool = |b: bool| Some(b as i64);
+    let value = match (name, args) {
+        ("eqz", &[a]) => return bool(a == 0),
+        ("clz", &[a]) => a.leading_zeros() as i32,
+        ("ctz", &[a]) => a.trailing_zeros() as i32,
+        ("popcnt", &[a]) => a.count_ones() as i32,
+        ("extend8_s", &[a]) => a as i8 as i32,
+        ("extend16_s", &[a]) => a as i16 as i32,
+        ("add", &[a, b]) => a.wrapping_add(b),
+        ("sub", &[a, b]) => a.wrapping_sub(b),
+        ("mul", &[a, b]) => a.wrapping_mul(b),
+        ("div_s", &[a, b]) => a.checked_div(b)?,
+        ("div_u", &[a, b]) => (a as u32).checked_div(b as u32)? as i32,
+        ("rem_s", &[a, b]) if b != 0 => a.wrapping_rem(b),
+        ("rem_u", &[a, b]) => (a as u32).checked_rem(b as u32)? as i32,
+        ("and", &[a, b]) => a & b,
+        ("or", &[a, b]) => a | b,
+        ("xor", &[a, b]) => a ^ b,
+        ("shl", &[a, b]) => a.wrapping_shl(b as u32),
+        ("shr_s", &[a, b]) => a.wrapping_shr(b as u32),
+        ("shr_u", &[a, b]) => (a as u32).wrapping_shr(b as u32) as i32,
+        ("rotl", &[a, b]) => a.rotate_left(b as u32 % 32),
+        ("rotr", &[a, b]) => a.rotate_right(b as u32 % 32),
+        ("eq", &[a, b]) => return bool(a == b),
+        ("ne", &[a, b]) => return bool(a != b),
+        ("lt_s", &[a, b]) => return bool(a < b),
+        ("lt_u", &[a, b]) => return bool((a as u32) < b as u32),
+        ("gt_s", &[a, b]) => return bool(a > b),
+        ("gt_u", &[a, b]) => return bool(a as u32 > b as u32),
+        ("le_s", &[a, b]) => return bool(a <= b),
+        ("le_u", &[a, b]) => return bool(a as u32 <= b as u32),
+        ("ge_s", &[a, b]) => return bool(a >= b),
+        ("ge_u", &[a, b]) => return bool(a as u32 >= b as u32),
+        _ => return None,
+    };
+    Some(value as i64)
+}
+
+fn eval64(name: &str, args: &[i64]) -> Option<i64> {
+    let bool = |b: bool| Some(b as i64);
+    let value = match (name, args) {
+        ("eqz", &[a]) => return bool(a == 0),
+        ("clz", &[a]) => a.leading_zeros() as i64,
+        ("ctz", &[a]) => a.trailing_zeros() as i64,
+        ("popcnt", &[a]) => a.count_ones() as i64,
+        ("extend8_s", &[a]) => a as i8 as i64,
+        ("extend16_s", &[a]) => a as i16 as i64,
+        ("extend32_s", &[a]) => a as i32 as i64,
+        ("extend_i32_s", &[a]) => a as i32 as i64,
+        ("extend_i32_u", &[a]) => a as u32 as i64,
+        ("add", &[a, b]) => a.wrapping_add(b),
+        ("sub", &[a, b]) => a.wrapping_sub(b),
+        ("mul", &[a, b]) => a.wrapping_mul(b),
+        ("div_s", &[a, b]) => a.checked_div(b)?,
+        ("div_u", &[a, b]) => (a as u64).checked_div(b as u64)? as i64,
+        ("rem_s", &[a, b]) if b != 0 => a.wrapping_rem(b),
+        ("rem_u", &[a, b]) => (a as u64).checked_rem(b as u64)? as i64,
+        ("and", &[a, b]) => a & b,
+        ("or", &[a, b]) => a | b,
+        ("xor", &[a, b]) => a ^ b,
+        ("shl", &[a, b]) => a.wrapping_shl(b as u32),
+        ("shr_s", &[a, b]) => a.wrapping_shr(b as u32),
+        ("shr_u", &[a, b]) => (a as u64).wrapping_shr(b as u32) as i64,
+        ("rotl", &[a, b]) => a.rotate_left((b % 64) as u32),
+        ("rotr", &[a, b]) => a.rotate_right((b % 64) as u32),
+        ("eq", &[a, b]) => return bool(a == b),
+        ("ne", &[a, b]) => return bool(a != b),
+        ("lt_s", &[a, b]) => return bool(a < b),
+        ("lt_u", &[a, b]) => return bool((a as u64) < b as u64),
+        ("gt_s", &[a, b]) => return bool(a > b),
+        ("gt_u", &[a, b]) => return bool(a as u64 > b as u64),
+        ("le_s", &[a, b]) => return bool(a <= b),
+        ("le_u", &[a, b]) => return bool(a as u64 <= b as u64),
+        ("ge_s", &[a, b]) => return bool(a >= b),
+        ("ge_u", &[a, b]) => return bool(a as u64 >= b as u64),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The type of the result of an integer instruction
+fn result_type(name: &str) -> Option<ValType> {
+    let (ty, op) = name.split_once('.')?;
+    let compare = matches!(op, "eqz" | "eq" | "ne")
+        || ["lt_", "gt_", "le_", "ge_"].iter().any(|prefix| op.starts_with(prefix));
+    match ty {
+        _ if compare || op == "wrap_i64" => Some(ValType::I32),
+        "i32" => Some(ValType::I32),
+        "i64" => Some(ValType::I64),
+        _ => None,
+    }
+}
+
+/// Evaluate the integer instruction `name` on constant operands, an i32 as
+/// its value sign extended. `None` for anything else and for operations
+/// that would trap, like a division by zero, which are left to run.
+pub fn eval(name: &str, args: &[i64]) -> Option<(ValType, i64)> {
+    let ty = result_type(name)?;
+    let value = match name.split_once('.')? {
+        ("i32", "wrap_i64") => args.first().map(|&a| a as i32 as i64),
+        ("i32", op) => eval32(op, &args.iter().map(|&a| a as i32).collect::<Vec<_>>()),
+        ("i64", op) => eval64(op, args),
+        _ => None,
+    }?;
+    Some((ty, value))
+}
+
+/// x0 reads as zero whatever is written to it, its reads are constants
+/// and its writes only evaluate the value. The emitter gives it no global,
+/// but expansions and other passes may still name it.
+fn zero_register(op: &mut Op) -> Option<Node> {
+    match op.name.as_str() {
+        "global.get" if op.imms[0] == ZERO => Some(Node::constant(ValType::I64, 0)),
+        "global.set" if op.imms[0] == ZERO => match op.args.pop() {
+            Some(value) if value.has_effects() => Some(Node::op("drop", Vec::new(), vec![value])),
+            // removed with the other nops by dead store elimination
+            _ => Some(Node::op("nop", Vec::new(), Vec::new())),
+        },
+        _ => None,
+    }
+}
+
+/// Replace integer operations on constants with their result, and drop
+/// the operations the identities make redundant, like `x - 0`, `x & -1` or
+/// `x ^ x`, by the patterns of the peephole table. Reads of x0 are folded
+/// as the zero they are.
+pub fn fold_constants(func: &mut Func, fuel: &mut Fuel) {
+    for node in &mut func.body {
+        node.walk_mut(&mut |node| {
+            let Node::Op(op) = node else { return };
+            if !fuel.left() {
+                return;
+            }
+            let folded = zero_register(op).or_else(|| {
+                let consts = op.args.iter().map(|arg| arg.as_const().map(|c| c.1)).collect::<Option<Vec<_>>>();
+                let (ty, value) = eval(&op.name, &consts.filter(|consts| !consts.is_empty())?)?;
+                Some(Node::constant(ty, value))
+            });
+            let folded = folded.or_else(|| rewrite(node));
+            if let Some(folded) = folded {
+                fuel.spend();
+                *node = folded;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::ir::Module;
+
+    #[test]
+    fn test_fold_constants() {
+        assert_eq!(eval("i64.sub", &[3, 5]), Some((ValType::I64, -2)));
+        assert_eq!(eval("i32.shr_u", &[-1, 28]), Some((ValType::I32, 0xf)));
+        assert_eq!(eval("i64.lt_u", &[-1, 1]), Some((ValType::I32, 0)));
+        assert_eq!(eval("i64.extend_i32_u", &[-1]), Some((ValType::I64, 0xffff_ffff)));
+        assert_eq!(eval("i32.wrap_i64", &[0x1_8000_0000]), Some((ValType::I32, i32::MIN as i64)));
+        assert_eq!(eval("i32.mul", &[0x10000, 0x10000]), Some((ValType::I32, 0)));
+        assert_eq!(eval("i64.rotl", &[1, 65]), Some((ValType::I64, 2)));
+        assert_eq!(eval("i64.div_s", &[i64::MIN, -1]), None);
+        assert_eq!(eval("i32.rem_u", &[1, 0]), None);
+
+        let wat = "(module (func $f (result i64)
+            (global.set $x1 (i64.add (global.get $x1) (i64.mul (i64.const 4) (i64.sub (i64.const 0x10) (i64.const 1)))))
+            (global.set $x2 (i64.and (i64.or (global.get $x2) (i64.const 0)) (i64.const -1)))
+            (global.set $x3 (i64.xor (global.get $x3) (global.get $x3)))
+            (global.set $x4 (i64.mul (call $load_64 (i64.const 0)) (i64.const 0)))
+            (drop (i32.eq (i32.wrap_i64 (global.get $x5)) (i32.wrap_i64 (global.get $x5))))
+            (global.set $x0 (global.get $x7))
+            (global.set $x0 (call $load_64 (i64.const 0)))
+            (global.set $x8 (i64.or (global.get $x8) (i64.add (global.get $x0) (global.get $x0))))
+            (i64.shl (global.get $x6) (i64.const 64))))";
+        let mut module = Module::parse(wat).unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        fold_constants(func, &mut Fuel::unlimited());
+        let body: Vec<String> = func.body.iter().map(|node| node.to_string()).collect();
+        assert_eq!(body[0], "(global.set $x1 (i64.add (global.get $x1) (i64.const 60)))");
+        assert_eq!(body[1], "(global.set $x2 (global.get $x2))");
+        assert_eq!(body[2], "(global.set $x3 (i64.const 0))");
+        // the load still has to run
+        assert_eq!(body[3], "(global.set $x4 (i64.mul (call $load_64 (i64.const 0)) (i64.const 0)))");
+        assert_eq!(body[4], "(drop (i32.const 1))");
+        assert_eq!(body[5], "(nop)");
+        assert_eq!(body[6], "(drop (call $load_64 (i64.const 0)))");
+        assert_eq!(body[7], "(global.set $x8 (global.get $x8))");
+        assert_eq!(body[8], "(global.get $x6)");
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::codegen::ir::{Func, Node, ValType};
+use crate::codegen::passes::Fuel;
+
+/// Whether the instruction computes its value from its operands alone
+fn is_pure(name: &str) -> bool {
+    (name.starts_with("i32.") || name.starts_with("i64.")) && !name.contains("load") && !name.contains("store")
+        || name == "select"
+}
+
+fn is_register(global: &str) -> bool {
+    global == "$pc" || global.strip_prefix("$x").or(global.strip_prefix("$f")).is_some_and(|n| n.parse::<u8>().is_ok())
+}
+
+/// The type of the value `node` computes, if the pass can tell
+pub fn value_type(node: &Node) -> Option<ValType> {
+    let op = node.as_op()?;
+    if op.name == "global.get" {
+        return is_register(op.imms.first()?).then_some(ValType::I64);
+    }
+    let (ty, name) = op.name.split_once('.')?;
+    let compare = matches!(name, "eqz" | "eq" | "ne") || name.starts_with("lt_") || name.starts_with("gt_") || name.starts_with("le_") || name.starts_with("ge_");
+    match ty {
+        _ if compare => Some(ValType::I32),
+        "i32" => Some(ValType::I32),
+        "i64" => Some(ValType::I64),
+        _ => None,
+    }
+}
+
+/// The variables a node writes, as `l$name` and `g$name`, and whether it
+/// calls out, after which any global may have changed
+pub fn writes(node: &Node, vars: &mut HashSet<String>, calls: &mut bool) {
+    node.walk(&mut |node| match node.as_op() {
+        Some(op) if matches!(op.name.as_str(), "local.set" | "local.tee") => {
+            vars.insert(format!("l{}", op.imms[0]));
+        }
+        Some(op) if op.name == "global.set" => {
+            vars.insert(format!("g{}", op.imms[0]));
+        }
+        Some(op) if op.name.starts_with("call") => *calls = true,
+        _ => {}
+    });
+}
+
+#[derive(Default)]
+struct Numbering {
+    /// Version of each variable, a new one for every write
+    versions: HashMap<String, u32>,
+    last_version: u32,
+    /// Bumped by every call, which may change any global
+    epoch: u32,
+    /// The value a variable was set to, while it holds it, by key and the
+    /// node itself when reading the node again costs no more than the
+    /// variable
+    values: HashMap<String, (String, Option<Node>)>,
+    /// Values computed so far where the code being visited runs, by the
+    /// site computing them first and the local holding them once rewritten
+    available: HashMap<String, (usize, Option<String>)>,
+    log: Vec<String>,
+    /// How often the value of each site is computed, from counting
+    counts: Vec<usize>,
+    sites: usize,
+    rewrite: bool,
+}
+
+impl Numbering {
+    fn var_key(&self, var: String) -> String {
+        let version = self.versions.get(&var).copied().unwrap_or_default();
+        match var.starts_with('g') {
+            true => format!("{}@{}.{}", var, self.epoch, version),
+            false => format!("{}@{}", var, version),
+        }
+    }
+
+    /// The value number of a pure expression: equal keys, equal values
+    fn key(&self, node: &Node) -> Option<String> {
+        let op = node.as_op()?;
+        let var = match op.name.as_str() {
+            "i32.const" | "i64.const" => return Some(node.to_string()),
+            "local.get" => format!("l{}", op.imms.first()?),
+            "global.get" => format!("g{}", op.imms.first()?),
+            name if is_pure(name) => {
+                let args = op.args.iter().map(|arg| self.key(arg)).collect::<Option<Vec<_>>>()?;
+                return Some(format!("({} {} {})", op.name, op.imms.join(" "), args.join(" ")));
+            }
+            _ => return None,
+        };
+        match self.values.get(&var) {
+            Some((key, _)) => Some(key.clone()),
+            None => Some(self.var_key(var)),
+        }
+    }
+
+    fn bump(&mut self, var: String) {
+        self.values.remove(&var);
+        self.last_version += 1;
+        self.versions.insert(var, self.last_version);
+    }
+
+    fn bump_all(&mut self, vars: HashSet<String>, calls: bool) {
+        if calls {
+            self.epoch += 1;
+            self.values.retain(|var, _| !var.starts_with('g'));
+        }
+        vars.into_iter().for_each(|var| self.bump(var));
+    }
+
+    /// Visit the nodes of a region the code after it may not have run
+    fn region(&mut self, func: &mut Func, fuel: &mut Fuel, body: &mut [Node]) {
+        let mark = self.log.len();
+        body.iter_mut().for_each(|node| self.visit(func, fuel, node));
+        for key in self.log.drain(mark..) {
+            self.available.remove(&key);
+        }
+    }
+
+    /// What a register read is replaced with while the register holds a
+    /// constant or a local
+    fn forwarded(&self, node: &Node) -> Option<Node> {
+        let (key, value) = self.values.get(&format!("g{}", node.var("global.get")?))?;
+        let value = value.as_ref()?;
+        (self.key(value).as_ref() == Some(key)).then(|| value.clone())
+    }
+
+    fn visit(&mut self, func: &mut Func, fuel: &mut Fuel, node: &mut Node) {
+        if let Some(value) = self.forwarded(node) {
+            if self.rewrite && fuel.spend() {
+                *node = value;
+            }
+            return;
+        }
+        let candidate = match node.as_op() {
+            Some(op) if op.name == "global.get" => true,
+            Some(op) => is_pure(&op.name) && !op.args.is_empty(),
+            None => false,
+        };
+        if let (true, Some(ty), Some(key)) = (candidate, value_type(node), self.key(node)) {
+            if let Some((site, local)) = self.available.get(&key) {
+                match local {
+                    Some(local) if fuel.spend() => *node = Node::op("local.get", vec![local.clone()], Vec::new()),
+                    Some(_) => {}
+                    None if !self.rewrite => self.counts[*site] += 1,
+                    None => {}
+                }
+                return;
+            }
+            if let Node::Op(op) = node {
+                op.args.iter_mut().for_each(|arg| self.visit(func, fuel, arg));
+            }
+            let site = self.sites;
+            self.sites += 1;
+            let mut local = None;
+            if !self.rewrite {
+                self.counts.push(1);
+            } else if self.counts[site] > 1 && fuel.spend() {
+                let name = func.add_local("gvn", ty);
+                let value = std::mem::replace(node, Node::Comment(String::new()));
+                *node = Node::op("local.tee", vec![name.clone()], vec![value]);
+                local = Some(name);
+            }
+            self.available.insert(key.clone(), (site, local));
+            self.log.push(key);
+            return;
+        }
+        match node {
+            Node::Op(op) => {
+                // the value set, numbered before its operands are rewritten
+                let value = op.args.first().and_then(|value| {
+                    let key = self.key(value)?;
+                    let cheap = value.as_const().is_some() || value.var("local.get").is_some();
+                    Some((key, self.forwarded(value).or_else(|| cheap.then(|| value.clone()))))
+                });
+                op.args.iter_mut().for_each(|arg| self.visit(func, fuel, arg));
+                let scope = match op.name.as_str() {
+                    "local.set" | "local.tee" => "l",
+                    "global.set" => "g",
+                    name if name.starts_with("call") => {
+                        self.bump_all(HashSet::new(), true);
+                        return;
+                    }
+                    _ => return,
+                };
+                let var = format!("{}{}", scope, op.imms[0]);
+                self.bump(var.clone());
+                if let Some(value) = value {
+                    self.values.insert(var, value);
+                }
+            }
+            Node::Block { looping, body, .. } => {
+                let (mut vars, mut calls) = (HashSet::new(), false);
+                body.iter().for_each(|node| writes(node, &mut vars, &mut calls));
+                if *looping {
+                    // the body starts over with what its last run left
+                    self.bump_all(vars.clone(), calls);
+                }
+                self.region(func, fuel, body);
+                self.bump_all(vars, calls);
+            }
+            Node::If { cond, then, otherwise, .. } => {
+                self.visit(func, fuel, cond);
+                let (mut vars, mut calls) = (HashSet::new(), false);
+                then.iter().chain(otherwise.iter()).for_each(|node| writes(node, &mut vars, &mut calls));
+                // each arm sees what came before the if, but not the other arm
+                let before = (self.versions.clone(), self.values.clone(), self.epoch);
+                self.region(func, fuel, then);
+                (self.versions, self.values, self.epoch) = before;
+                self.region(func, fuel, otherwise);
+                self.bump_all(vars, calls);
+            }
+            Node::Comment(_) => {}
+        }
+    }
+}
+
+/// Global value numbering: values computed more than once, like an address
+/// or a register reloaded from its global, are kept in a local the first
+/// time and read from there after. Every write gives a variable a new
+/// version, in the manner of SSA, so only computations from the same
+/// versions are taken for the same value, and a register read back after
+/// being set is the value it was set to. Values computed in a block or an
+/// arm are not reused after it, which the code there may not have run.
+pub fn number_values(func: &mut Func, fuel: &mut Fuel) {
+    let mut body = std::mem::take(&mut func.body);
+    let mut numbering = Numbering::default();
+    // counting changes nothing, and costs no fuel
+    numbering.region(func, &mut Fuel::unlimited(), &mut body);
+    let mut numbering = Numbering { counts: numbering.counts, rewrite: true, ..Numbering::default() };
+    numbering.region(func, fuel, &mut body);
+    func.body = body;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::ir::Module;
+
+    #[test]
+    fn test_number_values() {
+        let wat = "(module (func $f (result i64)
+            (global.set $x5 (i64.add (global.get $x10) (i64.const 8)))
+            (global.set $x6 (i64.load (i32.wrap_i64 (i64.add (global.get $x10) (i64.const 8)))))
+            (global.set $x7 (i64.mul (global.get $x5) (global.get $x5)))
+            (global.set $x8 (i64.const 3))
+            (if (i64.eqz (global.get $x8)) (then (global.set $x10 (global.get $x8)) (global.set $x11 (global.get $x10))))
+            (global.set $x12 (global.get $x10))
+            (global.set $x13 (global.get $x10))
+            (i64.const 0)))";
+        let mut module = Module::parse(wat).unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        number_values(func, &mut Fuel::unlimited());
+        let body: Vec<String> = func.body.iter().map(|node| node.to_string()).collect();
+        assert_eq!(body[0], "(global.set $x5 (local.tee $gvn0 (i64.add (global.get $x10) (i64.const 8))))");
+        assert_eq!(body[1], "(global.set $x6 (i64.load (i32.wrap_i64 (local.get $gvn0))))");
+        assert_eq!(body[2], "(global.set $x7 (i64.mul (local.get $gvn0) (local.get $gvn0)))");
+        assert!(body[4].starts_with("(if (i64.eqz (i64.const 3))"));
+        assert!(body[4].contains("(global.set $x11 (i64.const 3))"));
+        // $x10 may or may not have been set by the if
+        assert_eq!(body[5], "(global.set $x12 (local.tee $gvn1 (global.get $x10)))");
+        assert_eq!(body[6], "(global.set $x13 (local.get $gvn1))");
+    }
+}
@@ -0,0 +1,233 @@
+use crate::codegen::regmap::RegMap;
+use crate::frontend::instruction::{btype_immediate, funct3, itype_immediate, rd, rs1, rs2, stype_immediate};
+use crate::middleend::emit_wasm::{block_exit, INSTR_COUNT_GLOBAL};
+use crate::runtime::dispatch::BlockSlots;
+
+/// Locals the fast path of an idiom uses, declared by the function it is
+/// emitted into
+pub const IDIOM_LOCALS: &str = "(local $bulk_len i64) (local $bulk_dst i32) (local $bulk_src i32)";
+
+/// How a byte loop knows when to stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopEnd {
+    /// A counter decremented down to zero, `bnez count`
+    Count(usize),
+    /// The pointer `ptr` compared to the register `end`, `bne ptr, end`
+    Bound { ptr: usize, end: usize },
+}
+
+/// A loop copying or filling guest memory one byte at a time, as compilers
+/// and hand written libc routines emit for memcpy and memset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idiom {
+    /// `lb(u) tmp, 0(src)` `sb tmp, 0(dst)`, both pointers stepping by one
+    Memcpy {
+        dst: usize,
+        src: usize,
+        tmp: usize,
+        signed: bool,
+        end: LoopEnd,
+    },
+    /// `sb value, 0(dst)`, the pointer stepping by one
+    Memset { dst: usize, value: usize, end: LoopEnd },
+}
+
+/// `addi reg, reg, imm` as `(reg, imm)`
+fn step(insn: u32) -> Option<(usize, i32)> {
+    (insn & 0x7f == 0x13 && funct3(insn) == 0 && rd(insn) == rs1(insn) && rd(insn) != 0)
+        .then(|| (rd(insn) as usize, itype_immediate(insn) as i32))
+}
+
+/// `lb` or `lbu` with a zero offset as `(rd, rs1, signed)`
+fn load_byte(insn: u32) -> Option<(usize, usize, bool)> {
+    let signed = match funct3(insn) {
+        0 => true,
+        4 => false,
+        _ => return None,
+    };
+    (insn & 0x7f == 0x03 && itype_immediate(insn) == 0).then(|| (rd(insn) as usize, rs1(insn) as usize, signed))
+}
+
+/// `sb` with a zero offset as `(rs2, rs1)`
+fn store_byte(insn: u32) -> Option<(usize, usize)> {
+    (insn & 0x7f == 0x23 && funct3(insn) == 0 && stype_immediate(insn) == 0)
+        .then(|| (rs2(insn) as usize, rs1(insn) as usize))
+}
+
+impl Idiom {
+    /// Recognize the loop made of the 4 byte instructions `insns`, starting
+    /// at the loop header and ending with the `bne` branching back to it.
+    /// The steps may come in any order after the memory accesses.
+    pub fn recognize(insns: &[u32]) -> Option<Self> {
+        let (&branch, body) = insns.split_last()?;
+        if branch & 0x7f != 0x63 || funct3(branch) != 1 || btype_immediate(branch) as i32 != -4 * body.len() as i32 {
+            return None;
+        }
+        let (idiom, steps) = match body {
+            [load, store, steps @ ..] if load_byte(*load).is_some() => {
+                let (tmp, src, signed) = load_byte(*load)?;
+                let (value, dst) = store_byte(*store)?;
+                if value != tmp {
+                    return None;
+                }
+                (Self::Memcpy { dst, src, tmp, signed, end: LoopEnd::Count(0) }, steps)
+            }
+            [store, steps @ ..] => {
+                let (value, dst) = store_byte(*store)?;
+                (Self::Memset { dst, value, end: LoopEnd::Count(0) }, steps)
+            }
+            [] => return None,
+        };
+        let pointers = match idiom {
+            Self::Memcpy { dst, src, .. } => vec![dst, src],
+            Self::Memset { dst, .. } => vec![dst],
+        };
+        let end = match (rs1(branch) as usize, rs2(branch) as usize) {
+            (count, 0) => LoopEnd::Count(count),
+            (ptr, end) if pointers.contains(&ptr) => LoopEnd::Bound { ptr, end },
+            (end, ptr) if pointers.contains(&ptr) => LoopEnd::Bound { ptr, end },
+            _ => return None,
+        };
+
+        // the steps are exactly the pointers going up and the count down
+        let mut expected: Vec<(usize, i32)> = pointers.iter().map(|&reg| (reg, 1)).collect();
+        let mut regs = match idiom {
+            Self::Memcpy { dst, src, tmp, .. } => vec![dst, src, tmp],
+            Self::Memset { dst, value, .. } => vec![dst, value],
+        };
+        match end {
+            LoopEnd::Count(count) => {
+                expected.push((count, -1));
+                regs.push(count);
+            }
+            LoopEnd::Bound { end, .. } => regs.push(end),
+        }
+        let mut steps = steps.iter().map(|&insn| step(insn)).collect::<Option<Vec<_>>>()?;
+        steps.sort_unstable();
+        expected.sort_unstable();
+        if steps != expected {
+            return None;
+        }
+        // no register does two jobs, a memset may store x0 though
+        let distinct = regs
+            .iter()
+            .enumerate()
+            .all(|(i, &reg)| !regs[..i].contains(&reg) && (reg != 0 || matches!(idiom, Self::Memset { value: 0, .. })));
+        distinct.then_some(match idiom {
+            Self::Memcpy { dst, src, tmp, signed, .. } => Self::Memcpy { dst, src, tmp, signed, end },
+            Self::Memset { dst, value, .. } => Self::Memset { dst, value, end },
+        })
+    }
+
+    /// Emit the fast path placed at the header of the `len` instruction loop
+    /// at `header`: when the whole range lies in one piece of linear memory
+    /// it does the work of every iteration with one `memory.copy` or
+    /// `memory.fill`, leaves the registers as the last iteration would, and
+    /// exits past the loop. Otherwise, e.g. for an overlapping copy, it falls
+    /// through to the loop. Only for memory `bulk_memory` allows, as the
+    /// accesses are neither checked nor split. With `counted` the iterations
+    /// are added to the retired instructions, the budget is checked by the
+    /// block exited to.
+    pub fn fast_path(&self, regs: &RegMap, header: u64, len: usize, slots: &mut BlockSlots, counted: bool) -> String {
+        let (dst, end) = match *self {
+            Self::Memcpy { dst, end, .. } | Self::Memset { dst, end, .. } => (dst, end),
+        };
+        let count = match end {
+            LoopEnd::Count(count) => regs.read(count),
+            LoopEnd::Bound { ptr, end } => format!("(i64.sub {} {})", regs.read(end), regs.read(ptr)),
+        };
+        let mut out = format!(
+            "(local.set $bulk_len {})\n(local.set $bulk_dst (call $linear_range {} (local.get $bulk_len)))\n",
+            count,
+            regs.read(dst)
+        );
+        let mut guard = "(i32.and (i64.ne (local.get $bulk_len) (i64.const 0)) (i32.ne (local.get $bulk_dst) (i32.const -1)))".to_string();
+        let mut then = String::new();
+        let mut pointers = vec![dst];
+        match *self {
+            Self::Memcpy { src, tmp, signed, .. } => {
+                out.push_str(&format!(
+                    "(local.set $bulk_src (call $linear_range {} (local.get $bulk_len)))\n",
+                    regs.read(src)
+                ));
+                // a forward byte copy only matches memory.copy if the
+                // destination does not start within the source
+                guard = format!(
+                    "(i32.and {} (i32.and (i32.ne (local.get $bulk_src) (i32.const -1)) (i64.ge_u (i64.sub {} {}) (local.get $bulk_len))))",
+                    guard,
+                    regs.read(dst),
+                    regs.read(src)
+                );
+                then.push_str("(memory.copy (local.get $bulk_dst) (local.get $bulk_src) (i32.wrap_i64 (local.get $bulk_len))) ");
+                let last = format!(
+                    "(i64.load8_{} (i32.add (local.get $bulk_src) (i32.wrap_i64 (i64.sub (local.get $bulk_len) (i64.const 1)))))",
+                    if signed { "s" } else { "u" }
+                );
+                then.push_str(&regs.write(tmp, &last));
+                then.push(' ');
+                pointers.push(src);
+            }
+            Self::Memset { value, .. } => {
+                then.push_str(&format!(
+                    "(memory.fill (local.get $bulk_dst) (i32.wrap_i64 {}) (i32.wrap_i64 (local.get $bulk_len))) ",
+                    regs.read(value)
+                ));
+            }
+        }
+        for reg in pointers {
+            then.push_str(&regs.write(reg, &format!("(i64.add {} (local.get $bulk_len))", regs.read(reg))));
+            then.push(' ');
+        }
+        if let LoopEnd::Count(count) = end {
+            then.push_str(&regs.write(count, "(i64.const 0)"));
+            then.push(' ');
+        }
+        if counted {
+            then.push_str(&format!(
+                "(global.set ${0} (i64.add (global.get ${0}) (i64.mul (local.get $bulk_len) (i64.const {1})))) ",
+                INSTR_COUNT_GLOBAL, len
+            ));
+        }
+        then.push_str(&regs.sync());
+        out.push_str(&format!(
+            "(if {}\n  (then {} (return {})))\n",
+            guard,
+            then.trim_end(),
+            block_exit(header + 4 * len as u64, slots)
+        ));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::regs::{A0, A1, A2, T0};
+
+    #[test]
+    fn test_recognize_idioms() {
+        // lbu t0, 0(a1) / sb t0, 0(a0) / addi a0, a0, 1 / addi a1, a1, 1 / addi a2, a2, -1 / bnez a2, -20
+        let memcpy = [0x0005c283, 0x00550023, 0x00150513, 0x00158593, 0xfff60613, 0xfe0616e3];
+        let idiom = Idiom::recognize(&memcpy).unwrap();
+        assert_eq!(idiom, Idiom::Memcpy { dst: A0, src: A1, tmp: T0, signed: false, end: LoopEnd::Count(A2) });
+        // the steps in another order are the same loop, a branch elsewhere is not
+        let swapped = [memcpy[0], memcpy[1], memcpy[4], memcpy[3], memcpy[2], memcpy[5]];
+        assert_eq!(Idiom::recognize(&swapped), Some(idiom));
+        assert_eq!(Idiom::recognize(&[memcpy[0], memcpy[1], memcpy[2], memcpy[3], memcpy[4], 0xfe0618e3]), None);
+        // the count stepping by two is not a copy
+        assert_eq!(Idiom::recognize(&[memcpy[0], memcpy[1], memcpy[2], memcpy[3], 0xffe60613, memcpy[5]]), None);
+
+        // sb zero, 0(a0) / addi a0, a0, 1 / bne a0, a2, -8
+        let memset = [0x00050023, 0x00150513, 0xfec51ce3];
+        let idiom = Idiom::recognize(&memset).unwrap();
+        assert_eq!(idiom, Idiom::Memset { dst: A0, value: 0, end: LoopEnd::Bound { ptr: A0, end: A2 } });
+
+        let mut slots = BlockSlots::new();
+        let fast = idiom.fast_path(&RegMap::new(&[A0]), 0x10000, memset.len(), &mut slots, true);
+        assert!(fast.starts_with("(local.set $bulk_len (i64.sub (global.get $x12) (local.get $r10)))"));
+        assert!(fast.contains("(memory.fill (local.get $bulk_dst) (i32.wrap_i64 (i64.const 0))"));
+        assert!(fast.contains("(local.set $r10 (i64.add (local.get $r10) (local.get $bulk_len)))"));
+        assert!(fast.contains("(i64.const 0x1000c)"));
+        assert_eq!(fast.matches('(').count(), fast.matches(')').count());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::codegen::ir::{Func, Node};
+use crate::codegen::passes::Fuel;
+
+/// The most nodes a value may have to be computed whichever way the
+/// branch goes
+const MAX_SIZE: usize = 8;
+
+fn size(node: &Node) -> usize {
+    let mut size = 0;
+    node.walk(&mut |_| size += 1);
+    size
+}
+
+/// A value cheap enough to compute on both paths
+fn cheap(node: &Node) -> bool {
+    !node.has_effects() && size(node) <= MAX_SIZE
+}
+
+/// `(local.set $v value)` or `(global.set $v value)` as (op, var, value)
+fn as_set(node: &Node) -> Option<(&str, &str, &Node)> {
+    let op = node.as_op().filter(|op| matches!(op.name.as_str(), "local.set" | "global.set"))?;
+    Some((&op.name, op.imms.first()?, op.args.first()?))
+}
+
+/// What an arm does: nothing, `Some(None)`, or only set a variable
+fn arm(arm: &[Node]) -> Option<Option<(&str, &str, &Node)>> {
+    match arm {
+        [] => Some(None),
+        [set] => as_set(set).map(Some),
+        _ => None,
+    }
+}
+
+/// The set of the same variable `(if cond (then ...) (else ...))` comes to
+/// when both arms only set it, or one does and the other is empty
+fn convert(node: &Node) -> Option<Node> {
+    let Node::If { label: None, result: None, cond, then, otherwise } = node else { return None };
+    // an arm that does not set the variable leaves it as it is
+    let get = |name: &str, var: &str| Node::op(&name.replace("set", "get"), vec![var.to_string()], Vec::new());
+    let (name, var, chosen, other) = match (arm(then)?, arm(otherwise)?) {
+        (Some((name, var, a)), Some(b)) if (name, var) == (b.0, b.1) => (name, var, a.clone(), b.2.clone()),
+        (Some((name, var, a)), None) => (name, var, a.clone(), get(name, var)),
+        (None, Some((name, var, b))) => (name, var, get(name, var), b.clone()),
+        _ => return None,
+    };
+    // select evaluates all three, and the condition last
+    if !cheap(&chosen) || !cheap(&other) || cond.has_effects() {
+        return None;
+    }
+    let select = Node::op("select", Vec::new(), vec![chosen, other, (**cond).clone()]);
+    Some(Node::op(name, vec![var.to_string()], vec![select]))
+}
+
+fn convert_in(nodes: &mut [Node], fuel: &mut Fuel) {
+    for node in nodes {
+        match node {
+            Node::Block { body, .. } => convert_in(body, fuel),
+            Node::If { then, otherwise, .. } => {
+                convert_in(then, fuel);
+                convert_in(otherwise, fuel);
+            }
+            _ => {}
+        }
+        if let Some(converted) = convert(node).filter(|_| fuel.spend()) {
+            *node = converted;
+        }
+    }
+}
+
+/// If-conversion: an if that only picks which of two cheap values a
+/// register or local is set to, or whether to set it at all, becomes a set
+/// of a `select`, which Singlepass compiles without a branch
+pub fn convert_branches(func: &mut Func, fuel: &mut Fuel) {
+    convert_in(&mut func.body, fuel);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::ir::Module;
+
+    #[test]
+    fn test_convert_branches() {
+        let wat = "(module (func $f (result i64) (local $r5 i64)
+            (if (i64.lt_s (global.get $x10) (global.get $x11))
+                (then (global.set $x12 (global.get $x10)))
+                (else (global.set $x12 (global.get $x11))))
+            (if (i64.eqz (global.get $x10)) (then (local.set $r5 (i64.const 1))))
+            (if (i64.eqz (global.get $x10)) (then (global.set $x12 (i64.const 1))) (else (global.set $x13 (i64.const 1))))
+            (if (i64.eqz (global.get $x10)) (then (global.set $x12 (call $load_64 (i64.const 0)))))
+            (i64.const 0)))";
+        let mut module = Module::parse(wat).unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        convert_branches(func, &mut Fuel::unlimited());
+        let body: Vec<String> = func.body.iter().map(|node| node.to_string()).collect();
+        assert_eq!(
+            body[0],
+            "(global.set $x12 (select (global.get $x10) (global.get $x11) (i64.lt_s (global.get $x10) (global.get $x11))))"
+        );
+        assert_eq!(body[1], "(local.set $r5 (select (i64.const 1) (local.get $r5) (i64.eqz (global.get $x10))))");
+        // different registers, and a load that may only run on one path
+        assert!(body[2].starts_with("(if"));
+        assert!(body[3].starts_with("(if"));
+    }
+}
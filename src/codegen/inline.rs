@@ -0,0 +1,213 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::codegen::ir::{Func, Module, Node, ValType};
+use crate::codegen::passes::Fuel;
+
+/// Name of inlining in the profile and the fuel log
+pub const INLINE: &str = "inline";
+
+/// The most nodes a function may have for its calls to be inlined
+pub const INLINE_THRESHOLD: usize = 32;
+
+/// How deep calls in inlined code are inlined in turn
+const MAX_DEPTH: usize = 2;
+
+fn val_type(ty: &str) -> Option<ValType> {
+    match ty {
+        "i32" => Some(ValType::I32),
+        "i64" => Some(ValType::I64),
+        _ => None,
+    }
+}
+
+/// A function whose calls may be inlined
+#[derive(Debug, Clone)]
+struct Callee {
+    params: Vec<(String, ValType)>,
+    locals: Vec<(String, ValType)>,
+    result: Option<String>,
+    body: Vec<Node>,
+}
+
+impl Callee {
+    /// `func` if it is no larger than `threshold` and plain enough to
+    /// inline: named integer parameters and locals, at most one result, and
+    /// no calls to itself
+    fn of(func: &Func, threshold: usize) -> Option<Self> {
+        let (mut params, mut result) = (Vec::new(), None);
+        for item in &func.sig {
+            let words: Vec<&str> = item.trim_matches(['(', ')']).split_whitespace().collect();
+            match words[..] {
+                ["param", name, ty] if name.starts_with('$') => params.push((name.to_string(), val_type(ty)?)),
+                ["result", ty] if result.is_none() => {
+                    val_type(ty)?;
+                    result = Some(ty.to_string());
+                }
+                ["export", ..] => {}
+                _ => return None,
+            }
+        }
+        let locals = func
+            .locals
+            .iter()
+            .map(|local| Some((local.name.clone()?, val_type(&local.ty)?)))
+            .collect::<Option<Vec<_>>>()?;
+        let (mut size, mut plain) = (0, true);
+        for node in &func.body {
+            node.walk(&mut |node| {
+                size += 1;
+                plain &= match node.as_op() {
+                    Some(op) if op.name.starts_with("local.") => op.imms[0].starts_with('$'),
+                    Some(op) if op.name == "call" => func.name.as_ref() != op.imms.first(),
+                    Some(op) => !op.name.starts_with("return_call"),
+                    None => true,
+                };
+            });
+        }
+        (plain && size <= threshold).then(|| Self { params, locals, result, body: func.body.clone() })
+    }
+
+    /// The block computing `(call $callee args)` in `func`, its locals
+    /// renamed to new locals of `func` and its returns branches out of it,
+    /// or the body itself when it is a single expression
+    fn inline(&self, func: &mut Func, label: &str, args: Vec<Node>) -> Node {
+        let mut renamed = HashMap::new();
+        let mut body = Vec::new();
+        let mut returns = false;
+        for ((name, ty), arg) in self.params.iter().zip(args) {
+            let local = func.add_local(&name[1..], *ty);
+            body.push(Node::op("local.set", vec![local.clone()], vec![arg]));
+            renamed.insert(name.clone(), local);
+        }
+        for (name, ty) in &self.locals {
+            let local = func.add_local(&name[1..], *ty);
+            // each call starts with its locals at zero
+            body.push(Node::op("local.set", vec![local.clone()], vec![Node::constant(*ty, 0)]));
+            renamed.insert(name.clone(), local);
+        }
+        for node in &self.body {
+            let mut node = node.clone();
+            node.walk_mut(&mut |node| {
+                let Node::Op(op) = node else { return };
+                match op.name.as_str() {
+                    name if name.starts_with("local.") => op.imms[0] = renamed[&op.imms[0]].clone(),
+                    "return" => {
+                        op.name = "br".to_string();
+                        op.imms = vec![label.to_string()];
+                        returns = true;
+                    }
+                    _ => {}
+                }
+            });
+            body.push(node);
+        }
+        if let ([node], false) = (&mut body[..], returns) {
+            return std::mem::replace(node, Node::Comment(String::new()));
+        }
+        Node::Block { looping: false, label: Some(label.to_string()), result: self.result.clone(), body }
+    }
+}
+
+/// The labels of structured control in `nodes`
+fn labels(nodes: &[Node], labels: &mut HashSet<String>) {
+    for node in nodes {
+        node.walk(&mut |node| {
+            if let Node::Block { label: Some(label), .. } | Node::If { label: Some(label), .. } = node {
+                labels.insert(label.clone());
+            }
+        });
+    }
+}
+
+/// Inlining of calls to small functions, like the address translation
+/// and memory access helpers hot code calls on every access: the call
+/// becomes a block running the body of the function on the arguments, open
+/// to folding with the code around it
+#[derive(Debug, Clone)]
+pub struct Inliner {
+    callees: HashMap<String, Callee>,
+}
+
+impl Inliner {
+    /// Inline the functions of `module` of at most `threshold` nodes
+    pub fn new(module: &Module, threshold: usize) -> Self {
+        let callees = module
+            .funcs()
+            .filter_map(|func| Some((func.name.clone()?, Callee::of(func, threshold)?)))
+            .collect();
+        Self { callees }
+    }
+
+    /// Leave the calls to the functions `keep` picks as they are
+    pub fn keep(mut self, keep: impl Fn(&str) -> bool) -> Self {
+        self.callees.retain(|name, _| !keep(name));
+        self
+    }
+
+    /// Inline the calls of `func`, and the calls in what is inlined down
+    /// to a small depth
+    pub fn inline_calls(&self, func: &mut Func, fuel: &mut Fuel) {
+        let mut taken = HashSet::new();
+        labels(&func.body, &mut taken);
+        for _ in 0..MAX_DEPTH {
+            let mut body = std::mem::take(&mut func.body);
+            let mut inlined = false;
+            for node in &mut body {
+                node.walk_mut(&mut |node| {
+                    let Some(op) = node.as_op().filter(|op| op.name == "call") else { return };
+                    let name = &op.imms[0];
+                    let Some(callee) = self.callees.get(name).filter(|_| fuel.spend()) else { return };
+                    labels(&callee.body, &mut taken);
+                    let label = (0..).map(|n| format!("$inline{}", n)).find(|label| !taken.contains(label)).unwrap();
+                    taken.insert(label.clone());
+                    let Node::Op(op) = std::mem::replace(node, Node::Comment(String::new())) else { unreachable!() };
+                    *node = callee.inline(func, &label, op.args);
+                    inlined = true;
+                });
+            }
+            func.body = body;
+            if !inlined {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_calls() {
+        let wat = "(module
+            (func $offset (param $addr i64) (result i32) (local $base i64)
+                (local.set $base (i64.const 0x1000))
+                (if (i64.lt_u (local.get $addr) (local.get $base)) (then (return (i32.const -1))))
+                (i32.wrap_i64 (i64.sub (local.get $addr) (local.get $base))))
+            (func $load (param $vaddr i64) (result i64)
+                (i64.load (call $offset (local.get $vaddr))))
+            (func $loop (result i32) (call $loop))
+            (func $f (param $addr i64) (result i64)
+                (drop (call $loop))
+                (i64.add (call $load (local.get $addr)) (call $load (i64.const 8)))))";
+        let module = Module::parse(wat).unwrap();
+        let inliner = Inliner::new(&module, INLINE_THRESHOLD);
+        let mut funcs: Vec<Func> = module.funcs().cloned().collect();
+        let mut fuel = Fuel::unlimited();
+        inliner.inline_calls(&mut funcs[3], &mut fuel);
+        let body = funcs[3].to_string();
+        // both loads and the offsets they compute are inlined, with a local
+        // for each of theirs
+        assert_eq!(fuel.spent(), 4);
+        assert!(!body.contains("(call $load") && !body.contains("(call $offset"));
+        assert!(body.contains("(local.set $vaddr1 (i64.const 8))"));
+        assert!(body.contains("(local.set $base0 (i64.const 0))"));
+        assert!(body.contains("(br $inline2 (i32.const -1))"));
+        // nor is a function calling itself
+        assert!(body.contains("(drop (call $loop))"));
+
+        let inliner = Inliner::new(&module, INLINE_THRESHOLD).keep(|name| name == "$offset");
+        inliner.inline_calls(&mut funcs[1], &mut Fuel::unlimited());
+        assert!(funcs[1].to_string().contains("(call $offset"));
+    }
+}
@@ -0,0 +1,634 @@
+use std::fmt;
+
+/// Why emitted code could not be read into the IR
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum IrError {
+    #[error("unexpected end of the code")]
+    UnexpectedEnd,
+    #[error("unexpected `{0}` in the code")]
+    Unexpected(String),
+    #[error("{0} is not supported by the optimizer")]
+    Unsupported(&'static str),
+    /// The optimized code did not do what the code it was optimized from
+    /// did, run from the same state
+    #[error("optimized function {func} diverged on seed {seed}: {difference}")]
+    Diverged { func: String, seed: u64, difference: String },
+}
+
+/// Value types of the integer code the optimizer works on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+        }
+    }
+}
+
+/// A plain instruction with its immediates and its operands folded in, e.g.
+/// `(i64.load8_u offset=4 (local.get $addr))`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Op {
+    pub name: String,
+    pub imms: Vec<String>,
+    pub args: Vec<Node>,
+}
+
+/// One node of a function body. Structured control keeps its nesting, so
+/// passes see where values flow between blocks instead of guessing it from
+/// labels in flat text.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Node {
+    Op(Op),
+    /// `block` or, when `looping`, `loop`
+    Block {
+        looping: bool,
+        label: Option<String>,
+        result: Option<String>,
+        body: Vec<Node>,
+    },
+    If {
+        label: Option<String>,
+        result: Option<String>,
+        cond: Box<Node>,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    /// A `;;` comment on a line of its own, e.g. the pc markers of the
+    /// debug runtime
+    Comment(String),
+}
+
+impl Node {
+    pub fn op(name: &str, imms: Vec<String>, args: Vec<Node>) -> Self {
+        Node::Op(Op { name: name.to_string(), imms, args })
+    }
+
+    pub fn constant(ty: ValType, value: i64) -> Self {
+        let value = match ty {
+            ValType::I32 => value as i32 as i64,
+            ValType::I64 => value,
+        };
+        Node::op(&format!("{}.const", ty.name()), vec![value.to_string()], Vec::new())
+    }
+
+    /// Read one folded instruction, e.g. `(i64.add (local.get $a) (i64.const 1))`
+    pub fn parse(text: &str) -> Result<Self, IrError> {
+        let mut tokens = Tokens::new(text);
+        let list = match tokens.sexp()? {
+            Some(Sexp::List(list)) => list,
+            Some(token) => return Err(IrError::Unexpected(token.to_string())),
+            None => return Err(IrError::UnexpectedEnd),
+        };
+        if let Some(token) = tokens.sexp()? {
+            return Err(IrError::Unexpected(token.to_string()));
+        }
+        node(list)
+    }
+
+    pub fn as_op(&self) -> Option<&Op> {
+        match self {
+            Node::Op(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    /// The value of an `i32.const` or `i64.const`, an i32 sign extended
+    pub fn as_const(&self) -> Option<(ValType, i64)> {
+        let op = self.as_op()?;
+        let ty = match op.name.as_str() {
+            "i32.const" => ValType::I32,
+            "i64.const" => ValType::I64,
+            _ => return None,
+        };
+        let value = parse_int(op.imms.first()?)?;
+        Some((ty, if ty == ValType::I32 { value as i32 as i64 } else { value }))
+    }
+
+    /// The name of the variable an `(op $name)` reads or writes, e.g. of
+    /// `global.get $x1` when `op` is `global.get`
+    pub fn var(&self, op: &str) -> Option<&str> {
+        match self {
+            Node::Op(node) if node.name == op => node.imms.first().map(String::as_str),
+            _ => None,
+        }
+    }
+
+    /// Whether evaluating the node does more than compute its value: write
+    /// a variable or memory, call out, branch or trap
+    pub fn has_effects(&self) -> bool {
+        let Node::Op(op) = self else { return true };
+        let pure = match op.name.as_str() {
+            "local.get" | "global.get" | "select" => true,
+            name => {
+                (name.starts_with("i32.") || name.starts_with("i64."))
+                    && !["load", "store", "div", "rem", "trunc", "atomic"].iter().any(|op| name.contains(op))
+            }
+        };
+        !pure || op.args.iter().any(Node::has_effects)
+    }
+
+    /// `walk`, handing out the nodes mutably
+    pub fn walk_mut(&mut self, f: &mut impl FnMut(&mut Node)) {
+        match self {
+            Node::Op(op) => op.args.iter_mut().for_each(|arg| arg.walk_mut(f)),
+            Node::Block { body, .. } => body.iter_mut().for_each(|node| node.walk_mut(f)),
+            Node::If { cond, then, otherwise, .. } => {
+                cond.walk_mut(f);
+                then.iter_mut().chain(otherwise.iter_mut()).for_each(|node| node.walk_mut(f));
+            }
+            Node::Comment(_) => {}
+        }
+        f(self)
+    }
+
+    /// Visit every node of the tree, operands before the node using them
+    pub fn walk(&self, f: &mut impl FnMut(&Node)) {
+        match self {
+            Node::Op(op) => op.args.iter().for_each(|arg| arg.walk(f)),
+            Node::Block { body, .. } => body.iter().for_each(|node| node.walk(f)),
+            Node::If { cond, then, otherwise, .. } => {
+                cond.walk(f);
+                then.iter().chain(otherwise.iter()).for_each(|node| node.walk(f));
+            }
+            Node::Comment(_) => {}
+        }
+        f(self)
+    }
+
+    fn print(&self, out: &mut String, indent: usize) {
+        let pad = "  ".repeat(indent);
+        match self {
+            Node::Op(_) => {
+                out.push_str(&pad);
+                out.push_str(&self.to_string());
+            }
+            Node::Block { looping, label, result, body } => {
+                out.push_str(&format!("{}({}", pad, if *looping { "loop" } else { "block" }));
+                print_label(out, label, result);
+                print_body(out, body, indent + 1);
+                out.push(')');
+            }
+            Node::If { label, result, cond, then, otherwise } => {
+                out.push_str(&format!("{}(if", pad));
+                print_label(out, label, result);
+                out.push_str(&format!(" {}\n{}  (then", cond, pad));
+                print_body(out, then, indent + 2);
+                out.push(')');
+                if !otherwise.is_empty() {
+                    out.push_str(&format!("\n{}  (else", pad));
+                    print_body(out, otherwise, indent + 2);
+                    out.push(')');
+                }
+                out.push(')');
+            }
+            Node::Comment(text) => out.push_str(&format!("{};;{}", pad, text)),
+        }
+    }
+}
+
+fn print_label(out: &mut String, label: &Option<String>, result: &Option<String>) {
+    if let Some(label) = label {
+        out.push_str(&format!(" {}", label));
+    }
+    if let Some(result) = result {
+        out.push_str(&format!(" (result {})", result));
+    }
+}
+
+fn print_body(out: &mut String, body: &[Node], indent: usize) {
+    for node in body {
+        out.push('\n');
+        node.print(out, indent);
+    }
+    // a comment runs to the end of its line
+    if matches!(body.last(), Some(Node::Comment(_))) {
+        out.push('\n');
+    }
+}
+
+/// Prints operands folded, and structured control on lines of its own
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Op(op) => {
+                write!(f, "({}", op.name)?;
+                for imm in &op.imms {
+                    write!(f, " {}", imm)?;
+                }
+                for arg in &op.args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+            _ => {
+                let mut out = String::new();
+                self.print(&mut out, 0);
+                f.write_str(&out)
+            }
+        }
+    }
+}
+
+/// A local of a function, named unless it is only reached by its index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Local {
+    pub name: Option<String>,
+    pub ty: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Func {
+    pub name: Option<String>,
+    /// The export, parameters and result, as written
+    pub sig: Vec<String>,
+    pub locals: Vec<Local>,
+    pub body: Vec<Node>,
+}
+
+impl Func {
+    /// Add a local named after `hint` not taken yet, and get its name
+    pub fn add_local(&mut self, hint: &str, ty: ValType) -> String {
+        let taken = |name: &str| {
+            self.locals.iter().any(|local| local.name.as_deref() == Some(name))
+                || self.sig.iter().any(|item| item.contains(&format!("{} ", name)))
+        };
+        let name = (0..).map(|n| format!("${}{}", hint, n)).find(|name| !taken(name)).unwrap();
+        self.locals.push(Local { name: Some(name.clone()), ty: ty.name().to_string() });
+        name
+    }
+}
+
+impl fmt::Display for Func {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::from("(func");
+        if let Some(name) = &self.name {
+            out.push_str(&format!(" {}", name));
+        }
+        for item in &self.sig {
+            out.push_str(&format!(" {}", item));
+        }
+        for local in &self.locals {
+            match &local.name {
+                Some(name) => out.push_str(&format!(" (local {} {})", name, local.ty)),
+                None => out.push_str(&format!(" (local {})", local.ty)),
+            }
+        }
+        print_body(&mut out, &self.body, 1);
+        out.push(')');
+        f.write_str(&out)
+    }
+}
+
+/// A module item, functions are read into the IR and the rest kept as text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Func(Func),
+    Other(String),
+}
+
+/// The emitted module with its functions in the IR
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub items: Vec<Item>,
+}
+
+impl Module {
+    pub fn parse(text: &str) -> Result<Self, IrError> {
+        let mut tokens = Tokens::new(text);
+        let module = match tokens.sexp()? {
+            Some(Sexp::List(items)) => items,
+            _ => return Err(IrError::Unsupported("code outside of a module")),
+        };
+        if let Some(token) = tokens.sexp()? {
+            return Err(IrError::Unexpected(token.to_string()));
+        }
+        let mut items = module.into_iter().filter(|item| !matches!(item, Sexp::Comment(_)));
+        if items.next() != Some(Sexp::Atom("module".into())) {
+            return Err(IrError::Unsupported("code outside of a module"));
+        }
+        let items = items
+            .map(|item| match item {
+                Sexp::List(list) if list.first() == Some(&Sexp::Atom("func".into())) => Ok(Item::Func(func(list)?)),
+                item => Ok(Item::Other(item.to_string())),
+            })
+            .collect::<Result<_, IrError>>()?;
+        Ok(Self { items })
+    }
+
+    pub fn funcs(&self) -> impl Iterator<Item = &Func> {
+        self.items.iter().filter_map(|item| match item {
+            Item::Func(func) => Some(func),
+            Item::Other(_) => None,
+        })
+    }
+
+    pub fn funcs_mut(&mut self) -> impl Iterator<Item = &mut Func> {
+        self.items.iter_mut().filter_map(|item| match item {
+            Item::Func(func) => Some(func),
+            Item::Other(_) => None,
+        })
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "(module")?;
+        for item in &self.items {
+            match item {
+                Item::Func(func) => writeln!(f, "{}", func)?,
+                Item::Other(text) => writeln!(f, "{}", text)?,
+            }
+        }
+        writeln!(f, ")")
+    }
+}
+
+/// An integer immediate as written in WAT: decimal or `0x` hex, signed,
+/// with `_` separators
+pub fn parse_int(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(&text)),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?,
+    };
+    Some(if negative { (value as i64).wrapping_neg() } else { value as i64 })
+}
+
+/// The text as read, before it is told apart into instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+    Comment(String),
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sexp::Atom(atom) => f.write_str(atom),
+            Sexp::List(items) => {
+                write!(f, "(")?;
+                let mut first = true;
+                for item in items {
+                    if matches!(item, Sexp::Comment(_)) {
+                        continue;
+                    }
+                    if !first {
+                        write!(f, " ")?;
+                    }
+                    first = false;
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+            Sexp::Comment(text) => writeln!(f, ";;{}", text),
+        }
+    }
+}
+
+struct Tokens<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(text: &'a str) -> Self {
+        Self { text, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    /// Skip whitespace and block comments
+    fn skip(&mut self) -> Result<(), IrError> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if !trimmed.starts_with("(;") {
+                return Ok(());
+            }
+            let end = trimmed.find(";)").ok_or(IrError::UnexpectedEnd)?;
+            self.pos += end + 2;
+        }
+    }
+
+    /// The next expression, `None` at the end of the text or of a list
+    fn sexp(&mut self) -> Result<Option<Sexp>, IrError> {
+        self.skip()?;
+        let rest = self.rest();
+        if let Some(comment) = rest.strip_prefix(";;") {
+            let end = comment.find('\n').unwrap_or(comment.len());
+            self.pos += 2 + end;
+            return Ok(Some(Sexp::Comment(comment[..end].to_string())));
+        }
+        match rest.chars().next() {
+            None | Some(')') => Ok(None),
+            Some('(') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                while let Some(item) = self.sexp()? {
+                    items.push(item);
+                }
+                if !self.rest().starts_with(')') {
+                    return Err(IrError::UnexpectedEnd);
+                }
+                self.pos += 1;
+                Ok(Some(Sexp::List(items)))
+            }
+            Some('"') => {
+                let mut escaped = false;
+                let end = rest[1..]
+                    .char_indices()
+                    .find(|&(_, c)| {
+                        let end = c == '"' && !escaped;
+                        escaped = c == '\\' && !escaped;
+                        end
+                    })
+                    .ok_or(IrError::UnexpectedEnd)?
+                    .0;
+                self.pos += end + 2;
+                Ok(Some(Sexp::Atom(rest[..end + 2].to_string())))
+            }
+            Some(_) => {
+                let end = rest.find(|c: char| c.is_whitespace() || c == '(' || c == ')').unwrap_or(rest.len());
+                self.pos += end;
+                Ok(Some(Sexp::Atom(rest[..end].to_string())))
+            }
+        }
+    }
+}
+
+/// Whether a bare atom is an immediate rather than an instruction
+fn is_immediate(atom: &str) -> bool {
+    atom.starts_with(['$', '"', '-', '+']) || atom.starts_with(|c: char| c.is_ascii_digit()) || atom.contains('=')
+}
+
+fn head(list: &[Sexp]) -> Option<&str> {
+    match list.first() {
+        Some(Sexp::Atom(atom)) => Some(atom),
+        _ => None,
+    }
+}
+
+fn func(list: Vec<Sexp>) -> Result<Func, IrError> {
+    let mut items = list.into_iter().skip(1).peekable();
+    let name = match items.peek() {
+        Some(Sexp::Atom(atom)) if atom.starts_with('$') => Some(atom.clone()),
+        _ => None,
+    };
+    if name.is_some() {
+        items.next();
+    }
+    let mut sig = Vec::new();
+    let mut locals = Vec::new();
+    let mut body = Vec::new();
+    for item in items {
+        match &item {
+            Sexp::List(list) if body.is_empty() && matches!(head(list), Some("export" | "param" | "result" | "type")) => {
+                sig.push(item.to_string())
+            }
+            Sexp::List(list) if body.is_empty() && head(list) == Some("local") => match &list[1..] {
+                [Sexp::Atom(name), Sexp::Atom(ty)] if name.starts_with('$') => {
+                    locals.push(Local { name: Some(name.clone()), ty: ty.clone() })
+                }
+                types => {
+                    for ty in types {
+                        locals.push(Local { name: None, ty: ty.to_string() });
+                    }
+                }
+            },
+            _ => body.push(item),
+        }
+    }
+    Ok(Func { name, sig, locals, body: statements(body)? })
+}
+
+/// Read a sequence of statements, where bare instructions take the atoms
+/// after them as immediates
+fn statements(items: Vec<Sexp>) -> Result<Vec<Node>, IrError> {
+    let mut nodes = Vec::new();
+    for item in items {
+        match item {
+            Sexp::Atom(atom) if is_immediate(&atom) => match nodes.last_mut() {
+                Some(Node::Op(op)) if op.args.is_empty() => op.imms.push(atom),
+                _ => return Err(IrError::Unexpected(atom)),
+            },
+            Sexp::Atom(atom) => nodes.push(Node::op(&atom, Vec::new(), Vec::new())),
+            Sexp::Comment(text) => nodes.push(Node::Comment(text)),
+            Sexp::List(list) => nodes.push(node(list)?),
+        }
+    }
+    Ok(nodes)
+}
+
+/// The optional label and `(result t)` of structured control, taken off the
+/// front of `items`
+fn label_and_result(items: &mut Vec<Sexp>) -> Result<(Option<String>, Option<String>), IrError> {
+    let label = match items.first() {
+        Some(Sexp::Atom(atom)) if atom.starts_with('$') => Some(atom.clone()),
+        _ => None,
+    };
+    if label.is_some() {
+        items.remove(0);
+    }
+    let result = match items.first() {
+        Some(Sexp::List(list)) if head(list) == Some("result") => match &list[1..] {
+            [Sexp::Atom(ty)] => Some(ty.clone()),
+            _ => return Err(IrError::Unsupported("a block with several results")),
+        },
+        Some(Sexp::List(list)) if matches!(head(list), Some("param" | "type")) => {
+            return Err(IrError::Unsupported("a block with a type"))
+        }
+        _ => None,
+    };
+    if result.is_some() {
+        items.remove(0);
+    }
+    Ok((label, result))
+}
+
+fn node(list: Vec<Sexp>) -> Result<Node, IrError> {
+    let name = head(&list).ok_or(IrError::Unsupported("a list without an instruction"))?.to_string();
+    let mut items: Vec<Sexp> = list.into_iter().skip(1).collect();
+    match name.as_str() {
+        "block" | "loop" => {
+            let (label, result) = label_and_result(&mut items)?;
+            Ok(Node::Block { looping: name == "loop", label, result, body: statements(items)? })
+        }
+        "if" => {
+            let (label, result) = label_and_result(&mut items)?;
+            items.retain(|item| !matches!(item, Sexp::Comment(_)));
+            let mut arms = [Vec::new(), Vec::new()];
+            while let Some(Sexp::List(list)) = items.last() {
+                let arm = match head(list) {
+                    Some("else") => 1,
+                    Some("then") => 0,
+                    _ => break,
+                };
+                let Some(Sexp::List(list)) = items.pop() else { unreachable!() };
+                arms[arm] = statements(list.into_iter().skip(1).collect())?;
+            }
+            let cond = match <[Sexp; 1]>::try_from(items) {
+                Ok([Sexp::List(cond)]) => node(cond)?,
+                _ => return Err(IrError::Unsupported("an if without a folded condition")),
+            };
+            let [then, otherwise] = arms;
+            Ok(Node::If { label, result, cond: Box::new(cond), then, otherwise })
+        }
+        _ => {
+            let mut imms = Vec::new();
+            let mut args = Vec::new();
+            for item in items {
+                match item {
+                    Sexp::Atom(atom) => imms.push(atom),
+                    Sexp::List(list) if matches!(head(&list), Some("type" | "param" | "result")) => {
+                        imms.push(Sexp::List(list).to_string())
+                    }
+                    Sexp::List(list) => args.push(node(list)?),
+                    Sexp::Comment(_) => {}
+                }
+            }
+            Ok(Node::Op(Op { name, imms, args }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let text = "(module\n(import \"env\" \"x1\" (global $x1 (mut i64)))\n(func (export \"run\") (result i64) (local $target i64)\n;; 0x10000\n(global.set $x1 (i64.add (global.get $x1) (i64.const 0x10)))\n(if (result i64) (i64.eqz (global.get $x1)) (then (global.set $next_slot (i32.const 0)) (i64.const 0x10004)) (else (call_indirect $blocks (type $block) (i32.const 1)))))\n)\n";
+        let module = Module::parse(text).unwrap();
+        assert_eq!(module.items.len(), 2);
+        let printed = module.to_string();
+        assert_eq!(Module::parse(&printed).unwrap(), module);
+        assert!(printed.contains("\n  ;; 0x10000\n  (global.set $x1 (i64.add (global.get $x1) (i64.const 0x10)))"));
+        assert!(printed.contains("(call_indirect $blocks (type $block) (i32.const 1))"));
+
+        let Item::Func(func) = &module.items[1] else { panic!() };
+        assert_eq!(func.sig, ["(export \"run\")", "(result i64)"]);
+        let Node::If { cond, then, otherwise, .. } = &func.body[2] else { panic!() };
+        assert_eq!(cond.as_op().unwrap().name, "i64.eqz");
+        assert_eq!(then[1].as_const(), Some((ValType::I64, 0x10004)));
+        assert_eq!(otherwise.len(), 1);
+
+        assert_eq!(parse_int("-0x10"), Some(-16));
+        assert_eq!(parse_int("18446744073709551615"), Some(-1));
+        assert_eq!(Module::parse("(module (func (i64.const 1)"), Err(IrError::UnexpectedEnd));
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+
+use crate::codegen::gvn::{value_type, writes};
+use crate::codegen::ir::{Func, Node};
+use crate::codegen::passes::Fuel;
+
+/// What a loop changes: the variables it writes, as `l$name` and `g$name`,
+/// and whether it calls out, which may change any global
+struct Writes {
+    vars: HashSet<String>,
+    calls: bool,
+}
+
+impl Writes {
+    fn of(body: &[Node]) -> Self {
+        let (mut vars, mut calls) = (HashSet::new(), false);
+        body.iter().for_each(|node| writes(node, &mut vars, &mut calls));
+        Self { vars, calls }
+    }
+
+    /// Whether `node` computes the same value on every run of the loop, and
+    /// may as well be computed once before it
+    fn invariant(&self, node: &Node) -> bool {
+        if node.has_effects() {
+            return false;
+        }
+        let mut invariant = true;
+        node.walk(&mut |node| {
+            if let Some(local) = node.var("local.get") {
+                invariant &= !self.vars.contains(&format!("l{}", local));
+            }
+            if let Some(global) = node.var("global.get") {
+                invariant &= !self.calls && !self.vars.contains(&format!("g{}", global));
+            }
+        });
+        invariant
+    }
+}
+
+/// Replace the largest invariant computations in `node` with locals, set
+/// in `hoisted` before the loop
+fn hoist_from(func: &mut Func, fuel: &mut Fuel, node: &mut Node, writes: &Writes, hoisted: &mut Vec<(String, Node)>) {
+    if let Node::Op(op) = node {
+        let computes = !op.args.is_empty() && !op.name.ends_with(".get");
+        if computes && writes.invariant(node) {
+            if let Some(ty) = value_type(node).filter(|_| fuel.spend()) {
+                let local = match hoisted.iter().find(|(_, value)| value == node) {
+                    Some((local, _)) => local.clone(),
+                    None => {
+                        let local = func.add_local("licm", ty);
+                        hoisted.push((local.clone(), node.clone()));
+                        local
+                    }
+                };
+                *node = Node::op("local.get", vec![local], Vec::new());
+                return;
+            }
+        }
+    }
+    match node {
+        Node::Op(op) => op.args.iter_mut().for_each(|arg| hoist_from(func, fuel, arg, writes, hoisted)),
+        Node::Block { body, .. } => body.iter_mut().for_each(|node| hoist_from(func, fuel, node, writes, hoisted)),
+        Node::If { cond, then, otherwise, .. } => {
+            hoist_from(func, fuel, cond, writes, hoisted);
+            then.iter_mut().chain(otherwise.iter_mut()).for_each(|node| hoist_from(func, fuel, node, writes, hoisted));
+        }
+        Node::Comment(_) => {}
+    }
+}
+
+fn hoist_in(func: &mut Func, fuel: &mut Fuel, nodes: &mut Vec<Node>) {
+    let mut index = 0;
+    while index < nodes.len() {
+        // inner loops first, what they hoist may be invariant in the outer
+        // loop too
+        match &mut nodes[index] {
+            Node::Block { body, .. } => hoist_in(func, fuel, body),
+            Node::If { then, otherwise, .. } => {
+                hoist_in(func, fuel, then);
+                hoist_in(func, fuel, otherwise);
+            }
+            _ => {}
+        }
+        if let Node::Block { looping: true, body, .. } = &mut nodes[index] {
+            let writes = Writes::of(body);
+            let mut hoisted = Vec::new();
+            body.iter_mut().for_each(|node| hoist_from(func, fuel, node, &writes, &mut hoisted));
+            let count = hoisted.len();
+            let sets = hoisted.into_iter().map(|(local, value)| Node::op("local.set", vec![local], vec![value]));
+            nodes.splice(index..index, sets);
+            index += count;
+        }
+        index += 1;
+    }
+}
+
+/// Loop-invariant code motion: computations in a loop whose operands the
+/// loop never changes, such as the address of a field off a base register,
+/// are computed once into a local before the loop. Only computations that
+/// cannot trap are moved, as they run even when the loop would not have
+/// reached them.
+pub fn hoist_invariants(func: &mut Func, fuel: &mut Fuel) {
+    let mut body = std::mem::take(&mut func.body);
+    hoist_in(func, fuel, &mut body);
+    func.body = body;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::ir::Module;
+
+    #[test]
+    fn test_hoist_invariants() {
+        let wat = "(module (func $f (result i64)
+            (loop $next
+                (global.set $x5 (i64.load (i32.wrap_i64 (i64.add (global.get $x10) (i64.const 16)))))
+                (global.set $x6 (i64.add (global.get $x6) (i64.const 1)))
+                (br_if $next (i64.lt_u (global.get $x6) (i64.mul (global.get $x11) (i64.const 8))))
+                (global.set $x7 (i64.div_u (global.get $x11) (global.get $x12))))
+            (loop $again (drop (call $load_64 (i64.add (global.get $x10) (i64.const 16)))))
+            (i64.const 0)))";
+        let mut module = Module::parse(wat).unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        hoist_invariants(func, &mut Fuel::unlimited());
+        let body: Vec<String> = func.body.iter().map(|node| node.to_string()).collect();
+        assert_eq!(body[0], "(local.set $licm0 (i32.wrap_i64 (i64.add (global.get $x10) (i64.const 16))))");
+        assert_eq!(body[1], "(local.set $licm1 (i64.mul (global.get $x11) (i64.const 8)))");
+        assert!(body[2].contains("(i64.load (local.get $licm0))"));
+        assert!(body[2].contains("(global.get $x6) (local.get $licm1)"));
+        // a division may trap, and the call may change any register
+        assert!(body[2].contains("(i64.div_u (global.get $x11) (global.get $x12))"));
+        assert!(body[3].contains("(i64.add (global.get $x10) (i64.const 16))"));
+        assert_eq!(func.locals.len(), 2);
+    }
+}
@@ -1,2 +1,15 @@
-mod optimizer;
-mod regmap;
+pub mod constprop;
+pub mod dse;
+pub mod eval;
+pub mod fold;
+pub mod gvn;
+pub mod idiom;
+pub mod ifconv;
+pub mod inline;
+pub mod ir;
+pub mod licm;
+pub mod optimizer;
+pub mod passes;
+pub mod peephole;
+pub mod regmap;
+pub mod translation;
@@ -1 +1,140 @@
-// passes
\ No newline at end of file
+use std::time::Instant;
+
+use crate::codegen::eval::verify;
+use crate::codegen::inline::{Inliner, INLINE, INLINE_THRESHOLD};
+use crate::codegen::ir::{Func, IrError, Module};
+use crate::codegen::passes::{Fuel, PassManager, TRANSLATION};
+use crate::codegen::translation::{is_access_helper, LINEAR_RANGE};
+use crate::runtime::config::OptLevel;
+use crate::runtime::rng::Rng;
+use crate::tools::perf::{Profiler, MIDDLEEND_OPTIMIZE};
+
+/// Runs the pipeline of passes over the functions of emitted modules, on
+/// the IR rather than on lines of text
+#[derive(Debug, Clone)]
+pub struct Optimizer {
+    passes: PassManager,
+    /// The largest function inlined, in nodes, 0 to inline nothing
+    inline: usize,
+    verify: bool,
+    /// Shared by every module optimized, so a limit counts the changes of
+    /// the whole run
+    fuel: Fuel,
+}
+
+impl Optimizer {
+    /// The optimizer with the pipeline of `level`
+    pub fn new(level: OptLevel) -> Self {
+        Self { passes: PassManager::for_level(level), inline: INLINE_THRESHOLD, verify: false, fuel: Fuel::unlimited() }
+    }
+
+    /// Run `passes` instead of the pipeline of the opt level
+    pub fn passes(mut self, passes: PassManager) -> Self {
+        self.passes = passes;
+        self
+    }
+
+    /// Inline the calls to functions of at most `threshold` nodes before
+    /// the passes run, none with 0
+    pub fn inline_threshold(mut self, threshold: usize) -> Self {
+        self.inline = threshold;
+        self
+    }
+
+    /// Check each optimized module against the module it was optimized
+    /// from on the reference evaluator, and fail on the first difference
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Stop making changes after the first `limit`, to bisect a miscompile
+    pub fn fuel(mut self, limit: u64) -> Self {
+        self.fuel = Fuel::new(limit);
+        self
+    }
+
+    /// The changes made so far
+    pub fn fuel_spent(&self) -> u64 {
+        self.fuel.spent()
+    }
+
+    pub fn optimize(&mut self, func: &mut Func, profiler: Option<&Profiler>) {
+        self.passes.run(func, &mut self.fuel, profiler);
+    }
+
+    /// Optimize every function of the module `wat`, first inlining the
+    /// small functions it calls. The profiler gets the whole under
+    /// `middleend.optimize` and inlining and each pass on their own.
+    pub fn run(&mut self, wat: &str, profiler: Option<&Profiler>) -> Result<String, IrError> {
+        if self.passes.is_empty() {
+            return Ok(wat.to_string());
+        }
+        let start = Instant::now();
+        let mut module = Module::parse(wat)?;
+        let before = self.verify.then(|| module.clone());
+        // accesses go to linear memory unchecked where ranges resolve
+        let linear = module.funcs().any(|func| func.name.as_deref() == Some(LINEAR_RANGE));
+        let passes = match linear {
+            true => self.passes.clone(),
+            false => self.passes.clone().enable(TRANSLATION, false),
+        };
+        // the accesses grouped to share their translation keep their calls
+        let inliner = Inliner::new(&module, self.inline).keep(|name| linear && is_access_helper(name));
+        for func in module.funcs_mut() {
+            let start = Instant::now();
+            self.fuel.enter(INLINE);
+            inliner.inline_calls(func, &mut self.fuel);
+            if let Some(profiler) = profiler {
+                profiler.record(&format!("{}.{}", MIDDLEEND_OPTIMIZE, INLINE), start.elapsed());
+            }
+            passes.run(func, &mut self.fuel, profiler);
+        }
+        if let Some(before) = before {
+            verify(&before, &module, Rng::from_entropy().next_u64())?;
+        }
+        if let Some(profiler) = profiler {
+            profiler.record(MIDDLEEND_OPTIMIZE, start.elapsed());
+        }
+        Ok(module.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_optimizer() {
+        let wat = "(module (func $f (result i64) (global.set $x1 (i64.add (global.get $x1) (i64.mul (i64.const 4) (i64.add (i64.const 0x10) (i64.const -1))))) (i64.const 0)))";
+        let profiler = Profiler::new();
+        let optimized = Optimizer::new(OptLevel::Optimized).run(wat, Some(&profiler)).unwrap();
+        assert!(optimized.contains("(global.set $x1 (i64.add (global.get $x1) (i64.const 60)))"));
+        assert_eq!(profiler.timer(MIDDLEEND_OPTIMIZE).unwrap().count, 1);
+        assert_eq!(Optimizer::new(OptLevel::Baseline).run(wat, None).unwrap(), wat);
+        let folding = PassManager::new().register("fold", crate::codegen::fold::fold_constants);
+        let folded = Optimizer::new(OptLevel::Baseline).passes(folding).run(wat, None).unwrap();
+        assert!(folded.contains("(i64.const 60)"));
+        let mut optimizer = Optimizer::new(OptLevel::Optimized).fuel(1);
+        let partly = optimizer.run(wat, None).unwrap();
+        assert!(partly.contains("(i64.const 15)") && !partly.contains("(i64.const 60)"));
+        assert_eq!(optimizer.run(wat, None).unwrap(), Module::parse(wat).unwrap().to_string());
+        assert_eq!(optimizer.fuel_spent(), 1);
+
+        // the calls are inlined, and what they compute folded
+        let wat = "(module (func $two (result i64) (i64.const 2))
+            (func $inc (param $v i64) (result i64) (i64.add (local.get $v) (i64.const 1)))
+            (func $f (result i64) (i64.add (call $two) (call $inc (i64.const 2)))))";
+        let profiler = Profiler::new();
+        let inlined = Optimizer::new(OptLevel::Optimized).run(wat, Some(&profiler)).unwrap();
+        assert!(inlined.contains("(i64.add (i64.const 2) (block $inline1 (result i64)\n  (i64.const 3))))"));
+        assert!(profiler.timer("middleend.optimize.inline").is_some());
+        let called = Optimizer::new(OptLevel::Optimized).inline_threshold(0).run(wat, None).unwrap();
+        assert!(called.contains("(call $inc (i64.const 2))"));
+
+        let mut module = Module::parse("(module (func (drop (i32.mul (i32.const 0x10000) (i32.const 0x10000)))))").unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        Optimizer::new(OptLevel::Optimized).optimize(func, None);
+        assert_eq!(func.body[0].to_string(), "(drop (i32.const 0))");
+    }
+}
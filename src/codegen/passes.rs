@@ -0,0 +1,210 @@
+use std::time::Instant;
+
+use crate::codegen::constprop::propagate_constants;
+use crate::codegen::dse::eliminate_dead_stores;
+use crate::codegen::fold::fold_constants;
+use crate::codegen::gvn::number_values;
+use crate::codegen::ifconv::convert_branches;
+use crate::codegen::ir::Func;
+use crate::codegen::licm::hoist_invariants;
+use crate::codegen::translation::share_translations;
+use crate::runtime::config::OptLevel;
+use crate::tools::perf::{Profiler, MIDDLEEND_OPTIMIZE};
+
+/// A transformation of one function, each change it makes paid for with
+/// a unit of fuel
+pub type Pass = fn(&mut Func, &mut Fuel);
+
+/// How many more changes the passes may make. With a limit a guest the
+/// optimizer breaks can be bisected to the one change breaking it: the
+/// smallest limit it fails with names the change in the log.
+#[derive(Debug, Clone, Default)]
+pub struct Fuel {
+    limit: Option<u64>,
+    spent: u64,
+    /// The pass running, named when the fuel runs out
+    pass: &'static str,
+}
+
+impl Fuel {
+    /// As much fuel as the passes want
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    pub fn new(limit: u64) -> Self {
+        Self { limit: Some(limit), ..Self::default() }
+    }
+
+    /// Name `pass` as the one making the changes from now on
+    pub fn enter(&mut self, pass: &'static str) {
+        self.pass = pass;
+    }
+
+    /// Whether a change may be made
+    pub fn left(&self) -> bool {
+        self.limit.is_none_or(|limit| self.spent < limit)
+    }
+
+    /// Pay for a change, false if there is no fuel left for it, and it
+    /// must not be made
+    pub fn spend(&mut self) -> bool {
+        if !self.left() {
+            return false;
+        }
+        self.spent += 1;
+        if self.limit == Some(self.spent) {
+            tracing::info!(target: "doublejit::optimizer", pass = self.pass, change = self.spent, "optimizer fuel ran out");
+        }
+        true
+    }
+
+    /// The changes made so far
+    pub fn spent(&self) -> u64 {
+        self.spent
+    }
+}
+
+/// Name of the pass sharing address translations, which only applies to
+/// modules with `$linear_range`
+pub const TRANSLATION: &str = "translation";
+
+#[derive(Debug, Clone)]
+struct Entry {
+    name: &'static str,
+    pass: Pass,
+    enabled: bool,
+}
+
+/// The passes of the optimizer in the order they run. The pipeline is run
+/// again while a run still changes the function, up to `rounds` times, as
+/// one pass often opens up work for another: a register forwarded by value
+/// numbering may fold, a folded condition may turn an if into a select.
+#[derive(Debug, Clone)]
+pub struct PassManager {
+    passes: Vec<Entry>,
+    rounds: usize,
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self { passes: Vec::new(), rounds: 1 }
+    }
+
+    /// The pipeline of `level`
+    pub fn for_level(level: OptLevel) -> Self {
+        match level {
+            // singlepass compiles the code as it comes, as fast as it can
+            OptLevel::Baseline => Self::new(),
+            OptLevel::Optimized | OptLevel::Tiered { .. } => Self::new()
+                .register(TRANSLATION, share_translations)
+                .register("constprop", propagate_constants)
+                .register("fold", fold_constants)
+                .register("ifconv", convert_branches)
+                .register("gvn", number_values)
+                .register("licm", hoist_invariants)
+                .register("dse", eliminate_dead_stores)
+                .rounds(4),
+        }
+    }
+
+    /// Add `pass` at the end of the pipeline
+    pub fn register(mut self, name: &'static str, pass: Pass) -> Self {
+        self.passes.push(Entry { name, pass, enabled: true });
+        self
+    }
+
+    /// Add `pass` right before the pass `before`, or at the end without it
+    pub fn register_before(mut self, before: &str, name: &'static str, pass: Pass) -> Self {
+        let index = self.passes.iter().position(|entry| entry.name == before).unwrap_or(self.passes.len());
+        self.passes.insert(index, Entry { name, pass, enabled: true });
+        self
+    }
+
+    /// Turn the pass `name` on or off
+    pub fn enable(mut self, name: &str, enabled: bool) -> Self {
+        for entry in self.passes.iter_mut().filter(|entry| entry.name == name) {
+            entry.enabled = enabled;
+        }
+        self
+    }
+
+    /// Run the pipeline at most `rounds` times
+    pub fn rounds(mut self, rounds: usize) -> Self {
+        self.rounds = rounds.max(1);
+        self
+    }
+
+    /// The passes in order, with whether they are on
+    pub fn passes(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        self.passes.iter().map(|entry| (entry.name, entry.enabled))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.passes.iter().any(|entry| entry.enabled)
+    }
+
+    /// Run the pipeline over `func` until it settles or the fuel runs out.
+    /// The profiler gets the time of each pass under
+    /// `middleend.optimize.<pass>`.
+    pub fn run(&self, func: &mut Func, fuel: &mut Fuel, profiler: Option<&Profiler>) {
+        for _ in 0..self.rounds {
+            let before = func.clone();
+            for entry in self.passes.iter().filter(|entry| entry.enabled) {
+                let start = Instant::now();
+                fuel.enter(entry.name);
+                (entry.pass)(func, fuel);
+                if let Some(profiler) = profiler {
+                    profiler.record(&format!("{}.{}", MIDDLEEND_OPTIMIZE, entry.name), start.elapsed());
+                }
+            }
+            if *func == before {
+                break;
+            }
+        }
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::ir::Module;
+
+    #[test]
+    fn test_pass_manager() {
+        let passes = PassManager::for_level(OptLevel::Optimized).enable("licm", false);
+        let names: Vec<_> = passes.passes().collect();
+        assert_eq!(names[0], (TRANSLATION, true));
+        assert!(names.contains(&("licm", false)));
+        assert!(PassManager::for_level(OptLevel::Baseline).is_empty());
+
+        // the pipeline runs until a round changes nothing
+        let wat = "(module (func $f (result i64)
+            (global.set $x5 (i64.const 3))
+            (global.set $x6 (i64.mul (global.get $x5) (i64.const 4)))
+            (i64.const 0)))";
+        let mut module = Module::parse(wat).unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        let profiler = Profiler::new();
+        let mut unoptimized = func.clone();
+        passes.run(func, &mut Fuel::unlimited(), Some(&profiler));
+        assert_eq!(func.body[1].to_string(), "(global.set $x6 (i64.const 12))");
+        let fold = profiler.timer("middleend.optimize.fold").unwrap();
+        assert!(fold.count >= 2);
+        assert!(profiler.timer("middleend.optimize.licm").is_none());
+
+        // propagating $x5 is the first change, folding the product the
+        // second
+        let mut fuel = Fuel::new(1);
+        passes.run(&mut unoptimized, &mut fuel, None);
+        assert_eq!(unoptimized.body[1].to_string(), "(global.set $x6 (i64.mul (i64.const 3) (i64.const 4)))");
+        assert_eq!(fuel.spent(), 1);
+        assert!(!fuel.spend());
+    }
+}
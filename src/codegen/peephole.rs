@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::codegen::ir::{parse_int, Node, ValType};
+
+/// Rewrites of an instruction into a simpler one, as folded WAT, where
+/// - `T.` stands for either integer type, the same one throughout a rewrite
+/// - `(?x)` stands for any operand, the same one wherever it appears
+/// - `W` stands for the width of `T` in bits
+///
+/// The first rewrite matching an instruction applies. An operand the
+/// rewrite drops, reads twice or moves may have no effects.
+pub const PATTERNS: &[(&str, &str)] = &[
+    // identities
+    ("(T.add (?x) (T.const 0))", "(?x)"),
+    ("(T.add (T.const 0) (?x))", "(?x)"),
+    ("(T.sub (?x) (T.const 0))", "(?x)"),
+    ("(T.or (?x) (T.const 0))", "(?x)"),
+    ("(T.or (T.const 0) (?x))", "(?x)"),
+    ("(T.xor (?x) (T.const 0))", "(?x)"),
+    ("(T.xor (T.const 0) (?x))", "(?x)"),
+    ("(T.mul (?x) (T.const 1))", "(?x)"),
+    ("(T.mul (T.const 1) (?x))", "(?x)"),
+    ("(T.div_s (?x) (T.const 1))", "(?x)"),
+    ("(T.div_u (?x) (T.const 1))", "(?x)"),
+    ("(T.and (?x) (T.const -1))", "(?x)"),
+    ("(T.and (T.const -1) (?x))", "(?x)"),
+    // shifts by zero, or by the width, which wasm takes modulo the width
+    ("(T.shl (?x) (T.const 0))", "(?x)"),
+    ("(T.shr_s (?x) (T.const 0))", "(?x)"),
+    ("(T.shr_u (?x) (T.const 0))", "(?x)"),
+    ("(T.rotl (?x) (T.const 0))", "(?x)"),
+    ("(T.rotr (?x) (T.const 0))", "(?x)"),
+    ("(T.shl (?x) (T.const W))", "(?x)"),
+    ("(T.shr_s (?x) (T.const W))", "(?x)"),
+    ("(T.shr_u (?x) (T.const W))", "(?x)"),
+    ("(T.rotl (?x) (T.const W))", "(?x)"),
+    ("(T.rotr (?x) (T.const W))", "(?x)"),
+    // absorbing operands
+    ("(T.mul (?x) (T.const 0))", "(T.const 0)"),
+    ("(T.mul (T.const 0) (?x))", "(T.const 0)"),
+    ("(T.and (?x) (T.const 0))", "(T.const 0)"),
+    ("(T.and (T.const 0) (?x))", "(T.const 0)"),
+    ("(T.or (?x) (T.const -1))", "(T.const -1)"),
+    ("(T.or (T.const -1) (?x))", "(T.const -1)"),
+    // the same operand twice
+    ("(T.and (?x) (?x))", "(?x)"),
+    ("(T.or (?x) (?x))", "(?x)"),
+    ("(T.sub (?x) (?x))", "(T.const 0)"),
+    ("(T.xor (?x) (?x))", "(T.const 0)"),
+    ("(T.eq (?x) (?x))", "(i32.const 1)"),
+    ("(T.le_s (?x) (?x))", "(i32.const 1)"),
+    ("(T.le_u (?x) (?x))", "(i32.const 1)"),
+    ("(T.ge_s (?x) (?x))", "(i32.const 1)"),
+    ("(T.ge_u (?x) (?x))", "(i32.const 1)"),
+    ("(T.ne (?x) (?x))", "(i32.const 0)"),
+    ("(T.lt_s (?x) (?x))", "(i32.const 0)"),
+    ("(T.lt_u (?x) (?x))", "(i32.const 0)"),
+    ("(T.gt_s (?x) (?x))", "(i32.const 0)"),
+    ("(T.gt_u (?x) (?x))", "(i32.const 0)"),
+    // double negation
+    ("(T.sub (T.const 0) (T.sub (T.const 0) (?x)))", "(?x)"),
+    ("(T.xor (T.xor (?x) (T.const -1)) (T.const -1))", "(?x)"),
+    ("(T.xor (T.xor (?x) (?y)) (?y))", "(?x)"),
+    ("(i32.eqz (i32.eqz (T.eqz (?x))))", "(T.eqz (?x))"),
+    // a negated comparison is the opposite comparison
+    ("(i32.eqz (T.eq (?x) (?y)))", "(T.ne (?x) (?y))"),
+    ("(i32.eqz (T.ne (?x) (?y)))", "(T.eq (?x) (?y))"),
+    ("(i32.eqz (T.lt_s (?x) (?y)))", "(T.ge_s (?x) (?y))"),
+    ("(i32.eqz (T.lt_u (?x) (?y)))", "(T.ge_u (?x) (?y))"),
+    ("(i32.eqz (T.gt_s (?x) (?y)))", "(T.le_s (?x) (?y))"),
+    ("(i32.eqz (T.gt_u (?x) (?y)))", "(T.le_u (?x) (?y))"),
+    ("(i32.eqz (T.le_s (?x) (?y)))", "(T.gt_s (?x) (?y))"),
+    ("(i32.eqz (T.le_u (?x) (?y)))", "(T.gt_u (?x) (?y))"),
+    ("(i32.eqz (T.ge_s (?x) (?y)))", "(T.lt_s (?x) (?y))"),
+    ("(i32.eqz (T.ge_u (?x) (?y)))", "(T.lt_u (?x) (?y))"),
+    // round trips through the other width
+    ("(i32.wrap_i64 (i64.extend_i32_s (?x)))", "(?x)"),
+    ("(i32.wrap_i64 (i64.extend_i32_u (?x)))", "(?x)"),
+    ("(i64.extend32_s (i64.extend_i32_s (?x)))", "(i64.extend_i32_s (?x))"),
+];
+
+/// A pattern read into the IR
+struct Rule {
+    pattern: Node,
+    replacement: Node,
+    /// The operands the rewrite keeps once and in order, which may have
+    /// effects
+    kept: Vec<String>,
+}
+
+impl Rule {
+    fn new(pattern: &str, replacement: &str) -> Self {
+        let pattern = Node::parse(pattern).unwrap_or_else(|err| panic!("bad pattern {}: {}", pattern, err));
+        let replacement = Node::parse(replacement).unwrap_or_else(|err| panic!("bad pattern {}: {}", replacement, err));
+        let (from, to) = (vars(&pattern), vars(&replacement));
+        let once = |vars: &[String], var: &String| vars.iter().filter(|v| *v == var).count() == 1;
+        let mut kept: Vec<String> = from.iter().filter(|var| once(&from, var) && once(&to, var)).cloned().collect();
+        if to.iter().filter(|var| kept.contains(var)).ne(kept.iter()) {
+            kept.clear();
+        }
+        Self { pattern, replacement, kept }
+    }
+}
+
+/// The operand variables of a pattern, in the order they are evaluated
+fn vars(pattern: &Node) -> Vec<String> {
+    let mut vars = Vec::new();
+    pattern.walk(&mut |node| vars.extend(node.as_op().filter(|op| op.name.starts_with('?')).map(|op| op.name.clone())));
+    vars
+}
+
+fn rules() -> &'static [Rule] {
+    static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+    RULES.get_or_init(|| PATTERNS.iter().map(|(pattern, replacement)| Rule::new(pattern, replacement)).collect())
+}
+
+/// What the pattern variables stand for in a match
+#[derive(Default)]
+struct Bindings<'a> {
+    ty: Option<ValType>,
+    vars: HashMap<String, &'a Node>,
+}
+
+impl<'a> Bindings<'a> {
+    /// Whether the instruction `name` is the one `pattern` names
+    fn name(&mut self, pattern: &str, name: &str) -> bool {
+        let Some(op) = pattern.strip_prefix("T.") else { return pattern == name };
+        let ty = match name.split_once('.') {
+            Some(("i32", rest)) if rest == op => ValType::I32,
+            Some(("i64", rest)) if rest == op => ValType::I64,
+            _ => return false,
+        };
+        *self.ty.get_or_insert(ty) == ty
+    }
+
+    fn bits(&self) -> i64 {
+        match self.ty {
+            Some(ValType::I32) => 32,
+            _ => 64,
+        }
+    }
+
+    /// The value of a constant in a pattern
+    fn value(&self, imm: &str) -> Option<i64> {
+        match imm {
+            "W" => Some(self.bits()),
+            imm => parse_int(imm),
+        }
+    }
+
+    fn matches(&mut self, pattern: &Node, node: &'a Node) -> bool {
+        let Some(p) = pattern.as_op() else { return false };
+        if p.name.starts_with('?') {
+            return match self.vars.get(&p.name) {
+                Some(&bound) => bound == node,
+                None => {
+                    self.vars.insert(p.name.clone(), node);
+                    true
+                }
+            };
+        }
+        let Some(op) = node.as_op() else { return false };
+        if !self.name(&p.name, &op.name) || p.args.len() != op.args.len() {
+            return false;
+        }
+        let imms = match node.as_const() {
+            Some(constant) if p.name.ends_with(".const") => {
+                // an i32 matches however it is written, signed or not
+                let value = p.imms.first().and_then(|imm| self.value(imm));
+                value.and_then(|value| Node::constant(constant.0, value).as_const()) == Some(constant)
+            }
+            _ => p.imms == op.imms,
+        };
+        imms && p.args.iter().zip(&op.args).all(|(p, arg)| self.matches(p, arg))
+    }
+
+    fn build(&self, replacement: &Node) -> Node {
+        let Some(op) = replacement.as_op() else { return replacement.clone() };
+        if let Some(&bound) = self.vars.get(&op.name) {
+            return bound.clone();
+        }
+        let name = match (op.name.strip_prefix("T."), self.ty) {
+            (Some(rest), Some(ty)) => format!("{}.{}", ty.name(), rest),
+            _ => op.name.clone(),
+        };
+        let ty = match name.split_once('.') {
+            Some(("i32", "const")) => Some(ValType::I32),
+            Some(("i64", "const")) => Some(ValType::I64),
+            _ => None,
+        };
+        match ty.zip(op.imms.first().and_then(|imm| self.value(imm))) {
+            Some((ty, value)) => Node::constant(ty, value),
+            None => Node::op(&name, op.imms.clone(), op.args.iter().map(|arg| self.build(arg)).collect()),
+        }
+    }
+}
+
+/// The simpler instruction the first pattern matching `node` rewrites it
+/// to
+pub fn rewrite(node: &Node) -> Option<Node> {
+    rules().iter().find_map(|rule| {
+        let mut bindings = Bindings::default();
+        if !bindings.matches(&rule.pattern, node) {
+            return None;
+        }
+        // only what is kept as it was may have effects
+        let pure = bindings.vars.iter().all(|(var, bound)| rule.kept.contains(var) || !bound.has_effects());
+        pure.then(|| bindings.build(&rule.replacement))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite() {
+        // every pattern rewrites what it describes, in both types
+        for (pattern, replacement) in PATTERNS {
+            for (ty, bits) in [("i32", "32"), ("i64", "64")] {
+                let instantiate = |text: &str| {
+                    let text = text.replace("T.", &format!("{}.", ty)).replace(" W)", &format!(" {})", bits));
+                    let text = text.replace("(?x)", "(local.get $a)").replace("(?y)", "(local.get $b)");
+                    Node::parse(&text).unwrap()
+                };
+                let rewritten = rewrite(&instantiate(pattern));
+                assert_eq!(rewritten, Some(instantiate(replacement)), "{} in {}", pattern, ty);
+            }
+        }
+
+        let rewrite = |text: &str| rewrite(&Node::parse(text).unwrap()).map(|node| node.to_string());
+        assert_eq!(rewrite("(i64.add (call $f) (i64.const 0))").as_deref(), Some("(call $f)"));
+        assert_eq!(rewrite("(i32.shl (local.get $a) (i32.const 0x20))").as_deref(), Some("(local.get $a)"));
+        assert_eq!(rewrite("(i32.and (local.get $a) (i32.const 0xffffffff))").as_deref(), Some("(local.get $a)"));
+        // the call has to run, and would run twice
+        assert_eq!(rewrite("(i64.mul (call $f) (i64.const 0))"), None);
+        assert_eq!(rewrite("(i64.sub (call $f) (call $f))"), None);
+        // a shift by 32 is not one by the width of an i64
+        assert_eq!(rewrite("(i64.shl (local.get $a) (i64.const 32))"), None);
+        assert_eq!(rewrite("(i64.add (local.get $a) (i32.const 0))"), None);
+    }
+}
@@ -1 +1,140 @@
+use crate::frontend::instruction::{rd, rs1, rs2};
+use crate::middleend::emit_wasm::x_global;
+use crate::runtime::regs::{A0, A1, A2, RA, SP, T0, T1};
 
+/// Guest registers kept in wasm locals at most
+pub const MAX_LOCALS: usize = 8;
+/// The registers kept in locals before any were counted
+pub const DEFAULT_HOT: [usize; 7] = [SP, RA, A0, A1, A2, T0, T1];
+
+/// Count the integer registers `insn` reads and writes into `uses`
+pub fn count_uses(uses: &mut [u64; 32], insn: u32) {
+    let (writes_rd, reads_rs1, reads_rs2) = match insn & 0x7f {
+        // lui, auipc, jal
+        0x37 | 0x17 | 0x6f => (true, false, false),
+        // op-imm, loads, jalr, system
+        0x13 | 0x1b | 0x03 | 0x67 | 0x73 => (true, true, false),
+        // stores, branches
+        0x23 | 0x63 => (false, true, true),
+        // op, amo
+        0x33 | 0x3b | 0x2f => (true, true, true),
+        // fp loads and stores take their address from an integer register
+        0x07 | 0x27 => (false, true, false),
+        _ => (false, false, false),
+    };
+    for (used, reg) in [(writes_rd, rd(insn)), (reads_rs1, rs1(insn)), (reads_rs2, rs2(insn))] {
+        if used && reg != 0 {
+            uses[reg as usize] += 1;
+        }
+    }
+}
+
+/// Where translated code keeps each guest register within one function:
+/// the hot ones in locals, which the blocks of a trace or loop pass on to
+/// each other, and the rest in their globals. The locals are loaded from the
+/// globals on entry and written back before every exit and host call, so
+/// the dispatcher and the host always find the registers in the globals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegMap {
+    /// Registers in locals, in the order they were picked
+    locals: Vec<usize>,
+}
+
+impl RegMap {
+    pub fn new(hot: &[usize]) -> Self {
+        let mut locals = Vec::new();
+        for &reg in hot {
+            if reg != 0 && !locals.contains(&reg) && locals.len() < MAX_LOCALS {
+                locals.push(reg);
+            }
+        }
+        Self { locals }
+    }
+
+    /// The registers used most according to `uses`, registers never used
+    /// are left in their globals
+    pub fn from_uses(uses: &[u64; 32]) -> Self {
+        let mut regs: Vec<usize> = (1..32).filter(|&reg| uses[reg] > 0).collect();
+        regs.sort_by_key(|&reg| (std::cmp::Reverse(uses[reg]), reg));
+        Self::new(&regs)
+    }
+
+    pub fn is_local(&self, reg: usize) -> bool {
+        self.locals.contains(&reg)
+    }
+
+    fn local(reg: usize) -> String {
+        format!("$r{}", reg)
+    }
+
+    /// The declarations of the locals, at the start of the function
+    pub fn declare(&self) -> String {
+        self.locals.iter().map(|&reg| format!("(local {} i64)", Self::local(reg))).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Load the locals from the globals, on entry to the function
+    pub fn load(&self) -> String {
+        self.locals
+            .iter()
+            .map(|&reg| format!("(local.set {} (global.get ${}))", Self::local(reg), x_global(reg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Write the locals back to the globals, before an exit or host call
+    pub fn sync(&self) -> String {
+        self.locals
+            .iter()
+            .map(|&reg| format!("(global.set ${} (local.get {}))", x_global(reg), Self::local(reg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The value of `reg`
+    pub fn read(&self, reg: usize) -> String {
+        match reg {
+            0 => "(i64.const 0)".to_string(),
+            reg if self.is_local(reg) => format!("(local.get {})", Self::local(reg)),
+            reg => format!("(global.get ${})", x_global(reg)),
+        }
+    }
+
+    /// Set `reg` to `value`. Writes to x0 only evaluate the value.
+    pub fn write(&self, reg: usize, value: &str) -> String {
+        match reg {
+            0 => format!("(drop {})", value),
+            reg if self.is_local(reg) => format!("(local.set {} {})", Self::local(reg), value),
+            reg => format!("(global.set ${} {})", x_global(reg), value),
+        }
+    }
+}
+
+impl Default for RegMap {
+    fn default() -> Self {
+        Self::new(&DEFAULT_HOT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_registers() {
+        let mut uses = [0; 32];
+        // addi sp, sp, -16 / sd ra, 8(sp) / add a0, a0, t0 / lui t0, 0x18
+        for insn in [0xff010113, 0x00113423, 0x00550533, 0x000182b7] {
+            count_uses(&mut uses, insn);
+        }
+        assert_eq!((uses[SP], uses[RA], uses[A0], uses[T0]), (3, 1, 2, 2));
+        let map = RegMap::from_uses(&uses);
+        assert_eq!(map.locals, [SP, T0, A0, RA]);
+
+        assert_eq!(map.read(SP), "(local.get $r2)");
+        assert_eq!(map.read(0), "(i64.const 0)");
+        assert_eq!(map.write(A1, "(i64.const 1)"), "(global.set $x11 (i64.const 1))");
+        assert_eq!(map.declare(), "(local $r2 i64) (local $r5 i64) (local $r10 i64) (local $r1 i64)");
+        assert!(map.sync().starts_with("(global.set $x2 (local.get $r2))"));
+        assert_eq!(RegMap::default().locals.len(), DEFAULT_HOT.len());
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::codegen::ir::{Func, Node, ValType};
+use crate::codegen::passes::Fuel;
+use crate::middleend::emit_wasm::{LOADS, STORES};
+
+/// The helper resolving a range of guest addresses to linear memory, -1
+/// when the range is not in one piece of it
+pub const LINEAR_RANGE: &str = "$linear_range";
+
+/// How far apart, in bytes, the accesses sharing a translation may be
+const MAX_SPAN: i64 = 256;
+
+/// A guest memory access through a `$load_*` or `$store_*` helper, at a
+/// constant offset from a register
+struct Access {
+    /// The register read, `(global.get $x10)` or `(local.get $r10)`
+    base: Node,
+    imm: i64,
+    width: i64,
+    /// The instruction accessing linear memory directly
+    op: &'static str,
+}
+
+impl Access {
+    fn of(node: &Node) -> Option<Self> {
+        let op = node.as_op().filter(|op| op.name == "call")?;
+        let callee = op.imms.first()?;
+        let (width, wasm) = match (callee.strip_prefix("$load_"), callee.strip_prefix("$store_")) {
+            (Some(suffix), _) => LOADS.iter().find(|load| load.0 == suffix).map(|load| (load.1, load.2))?,
+            (_, Some(suffix)) => STORES.iter().find(|store| store.0 == suffix).map(|store| (store.1, store.2))?,
+            _ => return None,
+        };
+        // the operands are evaluated on either path, so may have no effects
+        if op.args.iter().any(Node::has_effects) {
+            return None;
+        }
+        let is_register = |node: &Node| node.var("global.get").or(node.var("local.get")).is_some();
+        let (base, imm) = match op.args.first()?.as_op()? {
+            _ if is_register(&op.args[0]) => (op.args[0].clone(), 0),
+            add if add.name == "i64.add" => match &add.args[..] {
+                [base, imm] | [imm, base] if is_register(base) => (base.clone(), imm.as_const()?.1),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(Self { base, imm, width: width as i64, op: wasm })
+    }
+
+    /// The variable the base register is read from, as `l$name` or `g$name`
+    fn var(&self) -> String {
+        let op = self.base.as_op().unwrap();
+        format!("{}{}", &op.name[..1], op.imms[0])
+    }
+}
+
+/// Whether `name` is a `$load_*` or `$store_*` helper, whose calls this
+/// pass rewrites
+pub fn is_access_helper(name: &str) -> bool {
+    match (name.strip_prefix("$load_"), name.strip_prefix("$store_")) {
+        (Some(suffix), _) => LOADS.iter().any(|load| load.0 == suffix),
+        (_, Some(suffix)) => STORES.iter().any(|store| store.0 == suffix),
+        _ => false,
+    }
+}
+
+/// Accesses off one register, by the statements they are in
+struct Group {
+    base: Node,
+    first: usize,
+    statements: HashSet<usize>,
+    accesses: usize,
+    lo: i64,
+    hi: i64,
+}
+
+/// The variables a statement writes before the value it sets is written,
+/// and whether it calls anything but the memory helpers, which may change
+/// any global
+fn writes(node: &Node, vars: &mut HashSet<String>, calls: &mut bool) {
+    node.walk(&mut |node| match node.as_op() {
+        Some(op) if matches!(op.name.as_str(), "local.set" | "local.tee" | "global.set") => {
+            vars.insert(format!("{}{}", &op.name[..1], op.imms[0]));
+        }
+        Some(op) if op.name.starts_with("call") => *calls |= Access::of(node).is_none(),
+        _ => {}
+    });
+}
+
+/// Rewrite `node`, an access of `group`, to go to linear memory at the
+/// offset in `local` unless the range did not resolve
+fn fast_path(node: &mut Node, group: &Group, local: &str) {
+    let within = |access: &Access| group.lo <= access.imm && access.imm + access.width <= group.hi;
+    let Some(access) = Access::of(node).filter(|access| access.base == group.base && within(access)) else { return };
+    let Node::Op(op) = node else { return };
+    let offset = Node::op("local.get", vec![local.to_string()], Vec::new());
+    let mut args = vec![offset.clone()];
+    args.extend(op.args.iter().skip(1).cloned());
+    let direct = Node::op(access.op, vec![format!("offset={}", access.imm - group.lo)], args);
+    let resolved = Node::op("i32.ne", Vec::new(), vec![offset, Node::constant(ValType::I32, -1)]);
+    let slow = std::mem::replace(node, Node::Comment(String::new()));
+    *node = Node::If {
+        label: None,
+        result: access.op.starts_with("i64.load").then(|| "i64".to_string()),
+        cond: Box::new(resolved),
+        then: vec![direct],
+        otherwise: vec![slow],
+    };
+}
+
+fn share_in(func: &mut Func, fuel: &mut Fuel, nodes: &mut Vec<Node>) {
+    for node in nodes.iter_mut() {
+        match node {
+            Node::Block { body, .. } => share_in(func, fuel, body),
+            Node::If { then, otherwise, .. } => {
+                share_in(func, fuel, then);
+                share_in(func, fuel, otherwise);
+            }
+            _ => {}
+        }
+    }
+    let mut open: HashMap<String, Group> = HashMap::new();
+    let mut done = Vec::new();
+    let close = |open: &mut HashMap<String, Group>, done: &mut Vec<Group>, vars: &HashSet<String>, calls: bool| {
+        let closed: Vec<String> =
+            open.keys().filter(|var| vars.contains(*var) || (calls && var.starts_with('g'))).cloned().collect();
+        done.extend(closed.iter().filter_map(|var| open.remove(var)));
+    };
+    for (index, node) in nodes.iter().enumerate() {
+        let mut control = false;
+        node.walk(&mut |node| control |= matches!(node, Node::Block { .. } | Node::If { .. }));
+        if control {
+            done.extend(open.drain().map(|(_, group)| group));
+            continue;
+        }
+        let Node::Op(op) = node else { continue };
+        // what the statement writes while its accesses are evaluated ends
+        // the groups first, what it sets last after them
+        let set = matches!(op.name.as_str(), "local.set" | "global.set");
+        let (mut vars, mut calls) = (HashSet::new(), false);
+        match set {
+            true => op.args.iter().for_each(|arg| writes(arg, &mut vars, &mut calls)),
+            false => writes(node, &mut vars, &mut calls),
+        }
+        close(&mut open, &mut done, &vars, calls);
+        let mut accesses = Vec::new();
+        node.walk(&mut |node| accesses.extend(Access::of(node)));
+        for access in accesses {
+            let var = access.var();
+            let (lo, hi) = (access.imm, access.imm + access.width);
+            // too far from the others, left to its helper
+            if open.get(&var).is_some_and(|group| group.hi.max(hi) - group.lo.min(lo) > MAX_SPAN) {
+                continue;
+            }
+            let group = open.entry(var).or_insert_with(|| Group {
+                base: access.base.clone(),
+                first: index,
+                statements: HashSet::new(),
+                accesses: 0,
+                lo,
+                hi,
+            });
+            group.statements.insert(index);
+            group.accesses += 1;
+            (group.lo, group.hi) = (group.lo.min(lo), group.hi.max(hi));
+        }
+        if set {
+            close(&mut open, &mut done, &HashSet::from([format!("{}{}", &op.name[..1], op.imms[0])]), false);
+        }
+    }
+    done.extend(open.into_values());
+    done.retain(|group| group.accesses > 1);
+    done.sort_by_key(|group| (group.first, group.base.to_string()));
+    done.retain(|_| fuel.spend());
+    let mut ranges = Vec::new();
+    for group in done {
+        let local = func.add_local("xlat", ValType::I32);
+        for &index in &group.statements {
+            nodes[index].walk_mut(&mut |node| fast_path(node, &group, &local));
+        }
+        let lo = Node::op("i64.add", Vec::new(), vec![group.base.clone(), Node::constant(ValType::I64, group.lo)]);
+        let span = Node::constant(ValType::I64, group.hi - group.lo);
+        let range = Node::op("call", vec![LINEAR_RANGE.to_string()], vec![lo, span]);
+        ranges.push((group.first, Node::op("local.set", vec![local], vec![range])));
+    }
+    // from the last, so the statements before stay where they are
+    for (index, set) in ranges.into_iter().rev() {
+        nodes.insert(index, set);
+    }
+}
+
+/// Share the translation of guest addresses between the accesses near one
+/// register: the loads and stores at constant offsets from it, while it is
+/// unchanged, resolve the range they span with `$linear_range` once and
+/// access linear memory at offsets into it. Each keeps its helper for when
+/// the range does not resolve, so that it faults the way it did. Only for
+/// modules with `$linear_range`, where every access goes to linear memory
+/// unchecked.
+pub fn share_translations(func: &mut Func, fuel: &mut Fuel) {
+    let mut body = std::mem::take(&mut func.body);
+    share_in(func, fuel, &mut body);
+    func.body = body;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::ir::Module;
+
+    #[test]
+    fn test_share_translations() {
+        let wat = "(module (func $f (result i64)
+            (global.set $x5 (call $load_64 (i64.add (global.get $x10) (i64.const 8))))
+            (call $store_32 (global.get $x10) (global.get $x5))
+            (global.set $x10 (call $load_u16 (i64.add (global.get $x10) (i64.const -2))))
+            (global.set $x6 (call $load_64 (global.get $x10)))
+            (global.set $x7 (call $load_64 (i64.add (global.get $x11) (i64.const 0))))
+            (i64.const 0)))";
+        let mut module = Module::parse(wat).unwrap();
+        let func = module.funcs_mut().next().unwrap();
+        share_translations(func, &mut Fuel::unlimited());
+        let body: Vec<String> = func.body.iter().map(|node| node.to_string()).collect();
+        assert_eq!(body[0], "(local.set $xlat0 (call $linear_range (i64.add (global.get $x10) (i64.const -2)) (i64.const 18)))");
+        assert!(body[1].contains("(then\n    (i64.load offset=10 (local.get $xlat0)))"));
+        assert!(body[1].contains("(else\n    (call $load_64 (i64.add (global.get $x10) (i64.const 8)))))"));
+        assert!(body[2].contains("(i64.store32 offset=2 (local.get $xlat0) (global.get $x5))"));
+        assert!(body[3].contains("(i64.load16_u offset=0 (local.get $xlat0))"));
+        // $x10 has changed, and $x11 is accessed once
+        assert_eq!(body[4], "(global.set $x6 (call $load_64 (global.get $x10)))");
+        assert_eq!(body[5], "(global.set $x7 (call $load_64 (i64.add (global.get $x11) (i64.const 0))))");
+        assert_eq!(func.locals.len(), 1);
+    }
+}
@@ -0,0 +1,28 @@
+use std::io;
+
+use crate::frontend::elf::ElfError;
+use crate::middleend::standalone::StandaloneError;
+use crate::runtime::error::RuntimeError;
+use crate::runtime::process::ProcessError;
+use crate::runtime::limits::Limit;
+use crate::wasm::error::BackendError;
+
+/// Everything that can go wrong between reading a guest binary and running
+/// it to completion
+#[derive(Debug, thiserror::Error)]
+pub enum VmError {
+    #[error("cannot load the guest: {0}")]
+    Elf(#[from] ElfError),
+    #[error(transparent)]
+    Standalone(#[from] StandaloneError),
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    #[error(transparent)]
+    Runtime(#[from] RuntimeError),
+    #[error(transparent)]
+    Process(#[from] ProcessError),
+    #[error("guest exceeded its {0}")]
+    LimitExceeded(#[from] Limit),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
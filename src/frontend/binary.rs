@@ -1,8 +1,10 @@
 use crate::frontend::elf::{Class, Machine, ProgramHeaderType};
 use crate::frontend::BIT_LENGTH;
+use crate::middleend::address_map::Perm;
 
 use super::elf::{Data, ElfError, ElfFile, ParseResult, Type};
 use super::page::Page;
+use std::collections::BTreeMap;
 use std::ops::Range;
 
 /// From ELF Byte to Page
@@ -21,7 +23,7 @@ macro_rules! parse_not_meet{
 
 impl<'a> Binary<'a> {
     pub fn parse(bytes: &'a [u8]) -> ParseResult<Self> {
-        let mut elf = ElfFile::new(bytes).unwrap();
+        let mut elf = ElfFile::new(bytes)?;
         let pages = Vec::new();
         // jitedly translate instruction to flatmap, and map memory to linear memory
         unsafe {
@@ -55,7 +57,7 @@ impl<'a> Binary<'a> {
         let program_iter = elf.program_iter();
         // iterate the program and iterate the bytes
         for ph in program_iter {
-            dbg!(ph);
+            tracing::debug!(target: "doublejit::frontend", ?ph, "program header");
             if ph.get_type() != ProgramHeaderType::Load {
                 continue;
             }
@@ -129,19 +131,108 @@ impl<'a> Binary<'a> {
             }
         }
         let mut section_iter = elf.section_iter();
-        dbg!(
-            "Section header size: {}",
+        tracing::debug!(
+            target: "doublejit::frontend",
+            "section header size: {}",
             elf.header_part2.get_sh_count() - 1
         );
         section_iter.next();
         for sh in section_iter {
-            dbg!(" {},", sh.get_data(&elf));
+            tracing::trace!(target: "doublejit::frontend", data = ?sh.get_data(&elf), "section");
         }
         
         Ok(Self { pages })
     }
 }
 
+/// Page aligned bytes to place at a guest address before execution starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryInitializer {
+    pub vaddr: u64,
+    pub perm: Perm,
+    pub data: Vec<u8>,
+}
+
+/// Build the initial guest image from the PT_LOAD segments, the way the
+/// kernel maps them: every segment covers whole pages, the bytes in front of
+/// an unaligned segment come from the file (this is how the ELF header ends up
+/// mapped), `file_size..mem_size` is zero filled, and a later segment wins
+/// where segments overlap. The result is sorted, non overlapping, and merged
+/// into one initializer per run of contiguous pages with equal permissions.
+pub fn get_memory_initializers(
+    elf: &ElfFile,
+    load_bias: u64,
+) -> ParseResult<Vec<MemoryInitializer>> {
+    let page_size = Page::SIZE as u64;
+    let mut pages: BTreeMap<u64, (Perm, Box<[u8; Page::SIZE]>)> = BTreeMap::new();
+    for ph in elf.program_iter() {
+        if ph.get_type() != ProgramHeaderType::Load || ph.get_mem_size() == 0 {
+            continue;
+        }
+        let file_size = ph.get_file_size();
+        let offset = ph.get_offset();
+        if file_size > ph.get_mem_size() {
+            return Err(ElfError::Malformed(String::from(
+                "PT_LOAD file size exceeds its memory size",
+            )));
+        }
+        let vaddr = ph.get_virtual_addr().wrapping_add(load_bias);
+        let end = vaddr
+            .checked_add(ph.get_mem_size())
+            .filter(|&end| end <= Page::ADDRESS_LIMIT)
+            .ok_or_else(|| ElfError::AddressError(vaddr, String::from("PT_LOAD out of range")))?;
+        let padding = vaddr % page_size;
+        if offset % page_size != padding {
+            return Err(ElfError::Malformed(String::from(
+                "PT_LOAD offset and address are not congruent",
+            )));
+        }
+        let file = elf
+            .input
+            .get((offset - padding) as usize..(offset + file_size) as usize)
+            .ok_or_else(|| ElfError::Malformed(String::from("PT_LOAD beyond end of file")))?;
+        let file_start = vaddr - padding;
+        let file_end = vaddr + file_size;
+        let perm = Perm::from_elf_flags(ph.get_flags());
+
+        let mut page = file_start;
+        while page < end {
+            let fresh = !pages.contains_key(&page);
+            let (page_perm, data) = pages
+                .entry(page)
+                .or_insert_with(|| (Perm::NONE, Box::new([0; Page::SIZE])));
+            *page_perm = *page_perm | perm;
+            // padding is only an artifact of mapping whole pages, it must not
+            // clobber what an earlier segment put there
+            let from = if fresh { page } else { page.max(vaddr) };
+            let to = (page + page_size).min(end);
+            for addr in from..to {
+                data[(addr - page) as usize] = if addr < file_end {
+                    file[(addr - file_start) as usize]
+                } else {
+                    0
+                };
+            }
+            page += page_size;
+        }
+    }
+
+    let mut initializers: Vec<MemoryInitializer> = Vec::new();
+    for (vaddr, (perm, data)) in pages {
+        match initializers.last_mut() {
+            Some(last) if last.perm == perm && last.vaddr + last.data.len() as u64 == vaddr => {
+                last.data.extend_from_slice(&data[..]);
+            }
+            _ => initializers.push(MemoryInitializer {
+                vaddr,
+                perm,
+                data: data.to_vec(),
+            }),
+        }
+    }
+    Ok(initializers)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -154,4 +245,26 @@ mod test {
         .unwrap();
         unsafe { assert_eq!(BIT_LENGTH, 1) }
     }
+
+    #[test]
+    fn test_memory_initializers() {
+        let bytes =
+            std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test_binaries/test1")).unwrap();
+        let elf = ElfFile::new(&bytes).unwrap();
+        let initializers = get_memory_initializers(&elf, 0).unwrap();
+        assert_eq!(initializers.len(), 2);
+        let (text, data) = (&initializers[0], &initializers[1]);
+        assert_eq!((text.vaddr, text.perm, text.data.len()), (0x10000, Perm::RX, 0x1000));
+        assert_eq!(&text.data[..4], b"\x7fELF");
+        // the tail of the text page beyond the segment stays zero
+        assert!(text.data[0x514..].iter().all(|&b| b == 0));
+        assert_eq!((data.vaddr, data.perm, data.data.len()), (0x11000, Perm::RW, 0x2000));
+        assert_eq!(&data.data[0xe18..0x1038], &bytes[0xe18..0x1038]);
+        // .bss is zero filled
+        assert!(data.data[0x1038..].iter().all(|&b| b == 0));
+
+        let biased = get_memory_initializers(&elf, 0x1000_0000).unwrap();
+        assert_eq!(biased[0].vaddr, 0x1001_0000);
+        assert_eq!(biased[0].data, text.data);
+    }
 }
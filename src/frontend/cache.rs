@@ -1,21 +1,53 @@
 use crate::frontend::instruction::Instruction;
 use std::collections::HashMap;
+use std::ops::Range;
 
+#[derive(Debug, Default)]
 pub struct CodeCache {
     cache: HashMap<u64, Instruction>,
+    /// Bumped by every invalidation, so code compiled from the cache can
+    /// tell it is stale
+    generation: u64,
+    /// Lookups of instructions the cache did not have
+    misses: u64,
 }
 
 impl CodeCache {
     pub fn new() -> CodeCache {
         CodeCache {
             cache: HashMap::new(),
+            generation: 0,
+            misses: 0,
         }
     }
 
     pub fn get(&mut self, addr: u64) -> Option<&Instruction> {
-        self.cache.get(&addr)
+        let instruction = self.cache.get(&addr);
+        self.misses += instruction.is_none() as u64;
+        instruction
     }
     pub fn set(&mut self, addr: u64, instruction: Instruction) {
         self.cache.insert(addr, instruction);
     } // cache for function and cache for instruction
+
+    /// Drop the instructions decoded from `range`, after the guest rewrote
+    /// its code there
+    pub fn invalidate(&mut self, range: Range<u64>) {
+        // an instruction of up to 4 bytes may start before the range
+        self.cache.retain(|&addr, _| addr.saturating_add(4) <= range.start || addr >= range.end);
+        self.generation += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.generation += 1;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
 }
@@ -13,18 +13,23 @@ pub const SHT_HIPROC: u32 = 0x7fffffff;
 pub const SHT_LOUSER: u32 = 0x80000000;
 pub const SHT_HIUSER: u32 = 0xffffffff;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum ElfError {
     /// The Binary is Malformed SomeWhere
+    #[error("malformed ELF: {0}")]
     Malformed(String),
     /// The Binary does not meet requirement
+    #[error("unsupported ELF: {0}")]
     NotMeet(String),
     /// The Magic is Unknown
+    #[error("bad ELF magic {0:#x}")]
     BadMagic(u64),
     /// An IO based error
     #[cfg(feature = "std")]
-    IO(io::Error),
+    #[error("reading the ELF failed: {0}")]
+    IO(#[from] io::Error),
     /// Possible Out of User Space Bound Mapping
+    #[error("bad address {0:#x}: {1}")]
     AddressError(u64, String),
 }
 
@@ -69,6 +74,28 @@ impl<'a> ElfFile<'a> {
         }
     }
 
+    /// The functions of the symbol table as name, address and size, the
+    /// size 0 where the toolchain left it out
+    pub fn functions(&self) -> Vec<(&'a str, u64, u64)> {
+        let mut functions = Vec::new();
+        for header in self.section_iter() {
+            // get_data turns every section into Empty, so the table is read here
+            let symbols = matches!(header.get_type(), Ok(SectionHeaderType::SymbolTable));
+            if !symbols || self.header_part1.get_class() != Class::SixtyFour {
+                continue;
+            }
+            let entries: &[Entry64] = read_array(header.raw_data(self));
+            let Ok(strings) = self.parse_section_header(self.input, header.get_link() as u16) else { continue };
+            let strings = strings.raw_data(self);
+            for entry in entries.iter().filter(|entry| entry.is_function() && entry.get_value() != 0) {
+                if let Some(name) = strings.get(entry.get_name_index() as usize..) {
+                    functions.push((read_str(name), entry.get_value(), entry.get_size()));
+                }
+            }
+        }
+        functions
+    }
+
     pub fn program_iter(&self) -> impl Iterator<Item = ProgramHeader<'_>> {
         ProgramIter {
             file: self,
@@ -84,7 +111,7 @@ impl<'a> ElfFile<'a> {
         /* From index 0 (SHN_UNDEF) is an error */
         let start = (index as u64 * self.header_part2.get_sh_entry_size() as u64
             + self.header_part2.get_sh_offset() as u64) as usize;
-        dbg!(start);
+        tracing::trace!(target: "doublejit::frontend", start, "section header");
         let end = start + self.header_part2.get_sh_entry_size() as usize;
         Ok(match self.header_part1.get_class() {
             Class::ThirtyTwo => {
@@ -114,6 +141,13 @@ impl<'a> ElfFile<'a> {
         let start = self.header_part2.get_ph_offset() as usize
             + index as usize * self.header_part2.get_ph_entry_size() as usize;
         let end = start + self.header_part2.get_ph_entry_size() as usize;
+        let size = match self.header_part1.get_class() {
+            Class::ThirtyTwo => mem::size_of::<ProgramHeader32>(),
+            _ => mem::size_of::<ProgramHeader64>(),
+        };
+        if end > input.len() || end - start < size {
+            return Err(ElfError::Malformed(String::from("Program header past the end of the file.")));
+        }
         match self.header_part1.get_class() {
             Class::ThirtyTwo => Ok(ProgramHeader::ProgramHeader32(read(&input[start..end]))),
             Class::SixtyFour => Ok(ProgramHeader::ProgramHeader64(read(&input[start..end]))),
@@ -128,6 +162,18 @@ impl<'a> ElfFile<'a> {
             )));
         }
         let header_part1: &'a HeaderPt1 = read(&input[..size_part1]);
+        if header_part1.magic != *b"\x7fELF" {
+            return Err(ElfError::BadMagic(u32::from_le_bytes(header_part1.magic) as u64));
+        }
+        let size_part2 = match header_part1.get_class() {
+            Class::ThirtyTwo => mem::size_of::<HeaderPt2_<u32>>(),
+            _ => mem::size_of::<HeaderPt2_<u64>>(),
+        };
+        if input.len() < size_part1 + size_part2 {
+            return Err(ElfError::Malformed(String::from(
+                "File is shorter than the ELF header",
+            )));
+        }
         let header_part2 = match header_part1.get_class() {
             Class::ThirtyTwo => HeaderPt2::Header32(read(
                 &input[size_part1..size_part1 + mem::size_of::<HeaderPt2_<u32>>()],
@@ -752,7 +798,7 @@ impl<'a> SectionHeader<'a> {
     pub fn get_type(&self) -> ParseResult<SectionHeaderType> {
         self.get_section_type()
     }
-    fn get_flags(&self) -> u64 {
+    pub fn get_flags(&self) -> u64 {
         match *self {
             SectionHeader::SectionHeader32(h) => h.flags as u64,
             SectionHeader::SectionHeader64(h) => h.flags,
@@ -764,7 +810,7 @@ impl<'a> SectionHeader<'a> {
             SectionHeader::SectionHeader64(h) => h.name,
         }
     }
-    fn get_address(&self) -> u64 {
+    pub fn get_address(&self) -> u64 {
         match *self {
             SectionHeader::SectionHeader32(h) => h.address as u64,
             SectionHeader::SectionHeader64(h) => h.address,
@@ -788,7 +834,7 @@ impl<'a> SectionHeader<'a> {
             SectionHeader::SectionHeader64(h) => h.offset,
         }
     }
-    fn get_size(&self) -> u64 {
+    pub fn get_size(&self) -> u64 {
         match *self {
             SectionHeader::SectionHeader32(h) => h.size as u64,
             SectionHeader::SectionHeader64(h) => h.size,
@@ -1087,6 +1133,25 @@ pub struct Entry64(Entry64_);
 #[repr(C)]
 pub struct Entry128(Entry128_);
 
+impl Entry64 {
+    pub fn get_name_index(&self) -> u32 {
+        self.0.name
+    }
+
+    pub fn get_value(&self) -> u64 {
+        self.0.value
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.0.size
+    }
+
+    /// Whether the symbol is of type STT_FUNC
+    pub fn is_function(&self) -> bool {
+        self.0.info & 0xf == 2
+    }
+}
+
 unsafe impl Pod for Entry32 {}
 unsafe impl Pod for Entry64 {}
 unsafe impl Pod for Entry128 {}
@@ -825,21 +825,858 @@ pub enum Instr {
     NOP,
 }
 
+/// ABI names of the integer and floating point registers
+pub const X_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2",
+    "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+const F_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6",
+    "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// An operand the way assembly writes it
+trait Operand {
+    /// `None` for one assembly leaves out, like the dynamic rounding mode
+    fn asm(&self) -> Option<String>;
+}
+
+impl Operand for Reg {
+    fn asm(&self) -> Option<String> {
+        Some(match *self {
+            Reg::X(Xx(n)) if n < 32 => X_NAMES[n as usize].to_string(),
+            Reg::F(Xx(n)) if n < 32 => F_NAMES[n as usize].to_string(),
+            Reg::X(Xx(n)) => format!("x{}", n),
+            Reg::F(Xx(n)) => format!("f{}", n),
+            Reg::V(Xx(n)) => format!("v{}", n),
+            Reg::PC => "pc".to_string(),
+            Reg::FCSR => "fcsr".to_string(),
+        })
+    }
+}
+
+macro_rules! register_operand {
+    ($($ty:ident),*) => {
+        $(impl Operand for $ty {
+            fn asm(&self) -> Option<String> {
+                self.0.asm()
+            }
+        })*
+    };
+}
+register_operand!(Rd, Rs, Rs1, Rs2, Rs3);
+
+impl<const HIGH_BIT: usize, const LOW_BIT: usize> Operand for Imm32<HIGH_BIT, LOW_BIT> {
+    fn asm(&self) -> Option<String> {
+        Some((self.0 as i32).to_string())
+    }
+}
+
+impl Operand for Shamt {
+    fn asm(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+impl Operand for RoundingMode {
+    fn asm(&self) -> Option<String> {
+        let mode = match self {
+            RoundingMode::RNE => "rne",
+            RoundingMode::RTZ => "rtz",
+            RoundingMode::RDN => "rdn",
+            RoundingMode::RUP => "rup",
+            RoundingMode::RMM => "rmm",
+            RoundingMode::DYN => return None,
+        };
+        Some(mode.to_string())
+    }
+}
+
+impl Operand for VM {
+    fn asm(&self) -> Option<String> {
+        (!self.0).then(|| "v0.t".to_string())
+    }
+}
+
+impl Operand for CSRAddr {
+    fn asm(&self) -> Option<String> {
+        Some(format!("{:#x}", self.value()))
+    }
+}
+
+impl Operand for UImm {
+    fn asm(&self) -> Option<String> {
+        Some(self.value().to_string())
+    }
+}
+
+/// The accesses a fence orders, e.g. `rw`
+fn fence_set(bits: Xx<16>) -> String {
+    "iorw".chars().enumerate().filter(|(i, _)| bits.0 & 8 >> i != 0).map(|(_, c)| c).collect()
+}
+
+/// Write the `mnemonic` followed by the operands assembly does not leave
+/// out
+fn write_asm(f: &mut fmt::Formatter, mnemonic: &str, operands: &[Option<String>]) -> fmt::Result {
+    let operands: Vec<&str> = operands.iter().flatten().map(String::as_str).collect();
+    if operands.is_empty() {
+        write!(f, "{}", mnemonic)
+    } else {
+        write!(f, "{} {}", mnemonic, operands.join(","))
+    }
+}
+
+/// The forms of the operands of the variants `asm!` lists: in the order of
+/// the fields, none, the upper immediate of lui and auipc, memory as
+/// `offset(base)` for loads and stores, atomics with their ordering as a
+/// suffix, the sets of a fence and the CSR before the source
+macro_rules! plain {
+    ($f:ident, $mnemonic:literal $(, $operand:ident)*) => {
+        write_asm($f, $mnemonic, &[$($operand.asm()),*])
+    };
+}
+macro_rules! bare {
+    ($f:ident, $mnemonic:literal $(, $operand:ident)*) => {{
+        let _ = ($($operand,)*);
+        write_asm($f, $mnemonic, &[])
+    }};
+}
+macro_rules! upper {
+    ($f:ident, $mnemonic:literal, $rd:ident, $imm:ident) => {
+        write_asm($f, $mnemonic, &[$rd.asm(), Some(format!("{:#x}", $imm.0 >> 12))])
+    };
+}
+macro_rules! load {
+    ($f:ident, $mnemonic:literal, $rd:ident, $rs1:ident, $imm:ident) => {
+        write_asm($f, $mnemonic, &[$rd.asm(), Some(format!("{}({})", $imm.0 as i32, $rs1.asm().unwrap_or_default()))])
+    };
+}
+macro_rules! store {
+    ($f:ident, $mnemonic:literal, $rs1:ident, $rs2:ident, $imm:ident) => {
+        write_asm($f, $mnemonic, &[$rs2.asm(), Some(format!("{}({})", $imm.0 as i32, $rs1.asm().unwrap_or_default()))])
+    };
+}
+macro_rules! lr {
+    ($f:ident, $mnemonic:literal, $rd:ident, $rs1:ident, $aq:ident, $rl:ident) => {
+        write_asm(
+            $f,
+            &format!("{}{}{}", $mnemonic, if $aq.0 { ".aq" } else { "" }, if $rl.0 { ".rl" } else { "" }),
+            &[$rd.asm(), Some(format!("({})", $rs1.asm().unwrap_or_default()))],
+        )
+    };
+}
+macro_rules! amo {
+    ($f:ident, $mnemonic:literal, $rd:ident, $rs1:ident, $rs2:ident, $aq:ident, $rl:ident) => {
+        write_asm(
+            $f,
+            &format!("{}{}{}", $mnemonic, if $aq.0 { ".aq" } else { "" }, if $rl.0 { ".rl" } else { "" }),
+            &[$rd.asm(), $rs2.asm(), Some(format!("({})", $rs1.asm().unwrap_or_default()))],
+        )
+    };
+}
+macro_rules! fence {
+    ($f:ident, $mnemonic:literal, $rd:ident, $rs1:ident, $succ:ident, $pred:ident, $fm:ident) => {{
+        let _ = ($rd, $rs1, $fm);
+        write_asm($f, $mnemonic, &[Some(fence_set($pred.0)), Some(fence_set($succ.0))])
+    }};
+}
+macro_rules! csr {
+    ($f:ident, $mnemonic:literal, $rd:ident, $source:ident, $csr:ident) => {
+        write_asm($f, $mnemonic, &[$rd.asm(), $csr.asm(), $source.asm()])
+    };
+}
+
+/// Implement `Display` in assembly syntax for the instructions of each
+/// extension: every variant, with the form of its operands and mnemonic
+macro_rules! asm {
+    ($($ty:ident { $($variant:ident $(($($field:ident),+))? => $form:ident $mnemonic:literal,)* })*) => {
+        $(impl fmt::Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    $($ty::$variant $(($($field),+))? => $form!(f, $mnemonic $($(, $field)+)?),)*
+                }
+            }
+        })*
+    };
+}
+
+asm! {
+    RV32I {
+        LUI(rd, imm) => upper "lui",
+        AUIPC(rd, imm) => upper "auipc",
+        JAL(rd, imm) => plain "jal",
+        JALR(rd, rs1, imm) => load "jalr",
+        BEQ(rs1, rs2, imm) => plain "beq",
+        BNE(rs1, rs2, imm) => plain "bne",
+        BLT(rs1, rs2, imm) => plain "blt",
+        BGE(rs1, rs2, imm) => plain "bge",
+        BLTU(rs1, rs2, imm) => plain "bltu",
+        BGEU(rs1, rs2, imm) => plain "bgeu",
+        LB(rd, rs1, imm) => load "lb",
+        LH(rd, rs1, imm) => load "lh",
+        LW(rd, rs1, imm) => load "lw",
+        LBU(rd, rs1, imm) => load "lbu",
+        LHU(rd, rs1, imm) => load "lhu",
+        SB(rs1, rs2, imm) => store "sb",
+        SH(rs1, rs2, imm) => store "sh",
+        SW(rs1, rs2, imm) => store "sw",
+        ADDI(rd, rs1, imm) => plain "addi",
+        SLTI(rd, rs1, imm) => plain "slti",
+        SLTIU(rd, rs1, imm) => plain "sltiu",
+        XORI(rd, rs1, imm) => plain "xori",
+        ORI(rd, rs1, imm) => plain "ori",
+        ANDI(rd, rs1, imm) => plain "andi",
+        SLLI(rd, rs1, shamt) => plain "slli",
+        SRLI(rd, rs1, shamt) => plain "srli",
+        SRAI(rd, rs1, shamt) => plain "srai",
+        ADD(rd, rs1, rs2) => plain "add",
+        SUB(rd, rs1, rs2) => plain "sub",
+        SLL(rd, rs1, rs2) => plain "sll",
+        SLT(rd, rs1, rs2) => plain "slt",
+        SLTU(rd, rs1, rs2) => plain "sltu",
+        XOR(rd, rs1, rs2) => plain "xor",
+        SRL(rd, rs1, rs2) => plain "srl",
+        SRA(rd, rs1, rs2) => plain "sra",
+        OR(rd, rs1, rs2) => plain "or",
+        AND(rd, rs1, rs2) => plain "and",
+        FENCE(rd, rs1, succ, pred, fm) => fence "fence",
+        FENCE_TSO => plain "fence.tso",
+        PAUSE => plain "pause",
+        ECALL => plain "ecall",
+        EBREAK => plain "ebreak",
+    }
+    RV32M {
+        MUL(rd, rs1, rs2) => plain "mul",
+        MULH(rd, rs1, rs2) => plain "mulh",
+        MULHSU(rd, rs1, rs2) => plain "mulhsu",
+        MULHU(rd, rs1, rs2) => plain "mulhu",
+        DIV(rd, rs1, rs2) => plain "div",
+        DIVU(rd, rs1, rs2) => plain "divu",
+        REM(rd, rs1, rs2) => plain "rem",
+        REMU(rd, rs1, rs2) => plain "remu",
+    }
+    RV32A {
+        LR_W(rd, rs1, aq, rl) => lr "lr.w",
+        SC_W(rd, rs1, rs2, aq, rl) => amo "sc.w",
+        AMOSWAP_W(rd, rs1, rs2, aq, rl) => amo "amoswap.w",
+        AMOADD_W(rd, rs1, rs2, aq, rl) => amo "amoadd.w",
+        AMOXOR_W(rd, rs1, rs2, aq, rl) => amo "amoxor.w",
+        AMOAND_W(rd, rs1, rs2, aq, rl) => amo "amoand.w",
+        AMOOR_W(rd, rs1, rs2, aq, rl) => amo "amoor.w",
+        AMOMIN_W(rd, rs1, rs2, aq, rl) => amo "amomin.w",
+        AMOMAX_W(rd, rs1, rs2, aq, rl) => amo "amomax.w",
+        AMOMINU_W(rd, rs1, rs2, aq, rl) => amo "amominu.w",
+        AMOMAXU_W(rd, rs1, rs2, aq, rl) => amo "amomaxu.w",
+    }
+    RV32F {
+        FLW(rd, rs1, imm) => load "flw",
+        FSW(rs1, rs2, imm) => store "fsw",
+        FMADD_S(rd, rs1, rs2, rs3, rm) => plain "fmadd.s",
+        FMSUB_S(rd, rs1, rs2, rs3, rm) => plain "fmsub.s",
+        FNMSUB_S(rd, rs1, rs2, rs3, rm) => plain "fnmsub.s",
+        FNMADD_S(rd, rs1, rs2, rs3, rm) => plain "fnmadd.s",
+        FADD_S(rd, rs1, rs2, rm) => plain "fadd.s",
+        FSUB_S(rd, rs1, rs2, rm) => plain "fsub.s",
+        FMUL_S(rd, rs1, rs2, rm) => plain "fmul.s",
+        FDIV_S(rd, rs1, rs2, rm) => plain "fdiv.s",
+        FSQRT_S(rd, rs1, rm) => plain "fsqrt.s",
+        FSGNJ_S(rd, rs1, rs2) => plain "fsgnj.s",
+        FSGNJN_S(rd, rs1, rs2) => plain "fsgnjn.s",
+        FSGNJX_S(rd, rs1, rs2) => plain "fsgnjx.s",
+        FMIN_S(rd, rs1, rs2) => plain "fmin.s",
+        FMAX_S(rd, rs1, rs2) => plain "fmax.s",
+        FCVT_W_S(rd, rs1, rm) => plain "fcvt.w.s",
+        FCVT_WU_S(rd, rs1, rm) => plain "fcvt.wu.s",
+        FMV_X_W(rd, rs1) => plain "fmv.x.w",
+        FEQ_S(rd, rs1, rs2) => plain "feq.s",
+        FLT_S(rd, rs1, rs2) => plain "flt.s",
+        FLE_S(rd, rs1, rs2) => plain "fle.s",
+        FCLASS_S(rd, rs1) => plain "fclass.s",
+        FCVT_S_W(rd, rs1, rm) => plain "fcvt.s.w",
+        FCVT_S_WU(rd, rs1, rm) => plain "fcvt.s.wu",
+        FMV_W_X(rd, rs1) => plain "fmv.w.x",
+    }
+    RV32E {
+        LUI(rd, imm) => upper "lui",
+        AUIPC(rd, imm) => upper "auipc",
+        JAL(rd, imm) => plain "jal",
+        JALR(rd, rs1, imm) => load "jalr",
+        BEQ(rs1, rs2, imm) => plain "beq",
+        BNE(rs1, rs2, imm) => plain "bne",
+        BLT(rs1, rs2, imm) => plain "blt",
+        BGE(rs1, rs2, imm) => plain "bge",
+        BLTU(rs1, rs2, imm) => plain "bltu",
+        BGEU(rs1, rs2, imm) => plain "bgeu",
+        LB(rd, rs1, imm) => load "lb",
+        LH(rd, rs1, imm) => load "lh",
+        LW(rd, rs1, imm) => load "lw",
+        LBU(rd, rs1, imm) => load "lbu",
+        LHU(rd, rs1, imm) => load "lhu",
+        SB(rs1, rs2, imm) => store "sb",
+        SH(rs1, rs2, imm) => store "sh",
+        SW(rs1, rs2, imm) => store "sw",
+        ADDI(rd, rs1, imm) => plain "addi",
+        SLTI(rd, rs1, imm) => plain "slti",
+        SLTIU(rd, rs1, imm) => plain "sltiu",
+        XORI(rd, rs1, imm) => plain "xori",
+        ORI(rd, rs1, imm) => plain "ori",
+        ANDI(rd, rs1, imm) => plain "andi",
+        SLLI(rd, rs1, shamt) => plain "slli",
+        SRLI(rd, rs1, shamt) => plain "srli",
+        SRAI(rd, rs1, shamt) => plain "srai",
+        ADD(rd, rs1, rs2) => plain "add",
+        SUB(rd, rs1, rs2) => plain "sub",
+        SLL(rd, rs1, rs2) => plain "sll",
+        SLT(rd, rs1, rs2) => plain "slt",
+        SLTU(rd, rs1, rs2) => plain "sltu",
+        XOR(rd, rs1, rs2) => plain "xor",
+        SRL(rd, rs1, rs2) => plain "srl",
+        SRA(rd, rs1, rs2) => plain "sra",
+        OR(rd, rs1, rs2) => plain "or",
+        AND(rd, rs1, rs2) => plain "and",
+        FENCE(rd, rs1, succ, pred, fm) => fence "fence",
+        FENCE_TSO => plain "fence.tso",
+        PAUSE => plain "pause",
+        ECALL => plain "ecall",
+        EBREAK => plain "ebreak",
+    }
+    RV32D {
+        FLD(rd, rs1, imm) => load "fld",
+        FSD(rs1, rs2, imm) => store "fsd",
+        FMADD_D(rd, rs1, rs2, rs3, rm) => plain "fmadd.d",
+        FMSUB_D(rd, rs1, rs2, rs3, rm) => plain "fmsub.d",
+        FNMSUB_D(rd, rs1, rs2, rs3, rm) => plain "fnmsub.d",
+        FNMADD_D(rd, rs1, rs2, rs3, rm) => plain "fnmadd.d",
+        FADD_D(rd, rs1, rs2, rm) => plain "fadd.d",
+        FSUB_D(rd, rs1, rs2, rm) => plain "fsub.d",
+        FMUL_D(rd, rs1, rs2, rm) => plain "fmul.d",
+        FDIV_D(rd, rs1, rs2, rm) => plain "fdiv.d",
+        FSQRT_D(rd, rs1, rm) => plain "fsqrt.d",
+        FSGNJ_D(rd, rs1, rs2) => plain "fsgnj.d",
+        FSGNJN_D(rd, rs1, rs2) => plain "fsgnjn.d",
+        FSGNJX_D(rd, rs1, rs2) => plain "fsgnjx.d",
+        FMIN_D(rd, rs1, rs2) => plain "fmin.d",
+        FMAX_D(rd, rs1, rs2) => plain "fmax.d",
+        FCVT_S_D(rd, rs1, rm) => plain "fcvt.s.d",
+        FCVT_D_S(rd, rs1, rm) => plain "fcvt.d.s",
+        FEQ_D(rd, rs1, rs2) => plain "feq.d",
+        FLT_D(rd, rs1, rs2) => plain "flt.d",
+        FLE_D(rd, rs1, rs2) => plain "fle.d",
+        FCLASS_D(rd, rs1) => plain "fclass.d",
+        FCVT_W_D(rd, rs1, rm) => plain "fcvt.w.d",
+        FCVT_WU_D(rd, rs1, rm) => plain "fcvt.wu.d",
+        FCVT_D_W(rd, rs1, rm) => plain "fcvt.d.w",
+        FCVT_D_WU(rd, rs1, rm) => plain "fcvt.d.wu",
+    }
+    RVV {
+        VSETIVLI(rd, rs1, imm) => plain "vsetivli",
+        VSETVLI(rd, rs1, imm) => plain "vsetvli",
+        VSETVL(rd, rs1, rs2) => plain "vsetvl",
+        VLM_V(rd, rs1) => plain "vlm.v",
+        VLE8_V(rd, rs1, vm) => plain "vle8.v",
+        VLE16_V(rd, rs1, vm) => plain "vle16.v",
+        VLE32_V(rd, rs1, vm) => plain "vle32.v",
+        VLE64_V(rd, rs1, vm) => plain "vle64.v",
+        VLE128_V(rd, rs1, vm) => plain "vle128.v",
+        VLE256_V(rd, rs1, vm) => plain "vle256.v",
+        VLE512_V(rd, rs1, vm) => plain "vle512.v",
+        VLE1024_V(rd, rs1, vm) => plain "vle1024.v",
+        VSM_V(rs3, rs1) => plain "vsm.v",
+        VSE8_V(rs3, rs1, vm) => plain "vse8.v",
+        VSE16_V(rs3, rs1, vm) => plain "vse16.v",
+        VSE32_V(rs3, rs1, vm) => plain "vse32.v",
+        VSE64_V(rd, rs1) => plain "vse64.v",
+        VSE128_V(rd, rs1) => plain "vse128.v",
+        VSE256_V(rd, rs1) => plain "vse256.v",
+        VSE512_V(rd, rs1) => plain "vse512.v",
+        VSE1024_V(rd, rs1) => plain "vse1024.v",
+        VADD_VV(rd, rs1) => plain "vadd.vv",
+        VADD_VX(rd, rs1) => plain "vadd.vx",
+        VADD_VI(rd, rs1) => plain "vadd.vi",
+        VSUB_VV(rd, rs1) => plain "vsub.vv",
+        VSUB_VX(rd, rs1) => plain "vsub.vx",
+        VRSUB_VX(rd, rs1) => plain "vrsub.vx",
+        VRSUB_VI(rd, rs1) => plain "vrsub.vi",
+        VMUL_VV(rd, rs1) => plain "vmul.vv",
+        VMUL_VX(rd, rs1) => plain "vmul.vx",
+        VDIV_VV(rd, rs1) => plain "vdiv.vv",
+        VDIV_VX(rd, rs1) => plain "vdiv.vx",
+        VDIVU_VV(rd, rs1) => plain "vdivu.vv",
+        VDIVU_VX(rd, rs1) => plain "vdivu.vx",
+        VREM_VV(rd, rs1) => plain "vrem.vv",
+        VREM_VX(rd, rs1) => plain "vrem.vx",
+        VREMU_VV(rd, rs1) => plain "vremu.vv",
+        VREMU_VX(rd, rs1) => plain "vremu.vx",
+        VSLL_VV(rd, rs1) => plain "vsll.vv",
+        VSLL_VX(rd, rs1) => plain "vsll.vx",
+        VSLL_VI(rd, rs1) => plain "vsll.vi",
+        VSRL_VV(rd, rs1) => plain "vsrl.vv",
+        VSRL_VX(rd, rs1) => plain "vsrl.vx",
+        VSRL_VI(rd, rs1) => plain "vsrl.vi",
+        VSRA_VV(rd, rs1) => plain "vsra.vv",
+        VSRA_VX(rd, rs1) => plain "vsra.vx",
+        VSRA_VI(rd, rs1) => plain "vsra.vi",
+        VMSEQ_VV(rd, rs1) => plain "vmseq.vv",
+        VMSEQ_VX(rd, rs1) => plain "vmseq.vx",
+        VMSEQ_VI(rd, rs1) => plain "vmseq.vi",
+        VMSNE_VV(rd, rs1) => plain "vmsne.vv",
+        VMSNE_VX(rd, rs1) => plain "vmsne.vx",
+        VMSNE_VI(rd, rs1) => plain "vmsne.vi",
+        VMSLTU_VV(rd, rs1) => plain "vmsltu.vv",
+        VMSLTU_VX(rd, rs1) => plain "vmsltu.vx",
+        VMSLT_VV(rd, rs1) => plain "vmslt.vv",
+        VMSLT_VX(rd, rs1) => plain "vmslt.vx",
+        VMSLEU_VV(rd, rs1) => plain "vmsleu.vv",
+        VMSLEU_VX(rd, rs1) => plain "vmsleu.vx",
+        VMSLEU_VI(rd, rs1) => plain "vmsleu.vi",
+        VMSLE_VV(rd, rs1) => plain "vmsle.vv",
+        VMSLE_VX(rd, rs1) => plain "vmsle.vx",
+        VMSLE_VI(rd, rs1) => plain "vmsle.vi",
+        VMSGTU_VX(rd, rs1) => plain "vmsgtu.vx",
+        VMSGTU_VI(rd, rs1) => plain "vmsgtu.vi",
+        VMSGT_VX(rd, rs1) => plain "vmsgt.vx",
+        VMSGT_VI(rd, rs1) => plain "vmsgt.vi",
+        VMINU_VV(rd, rs1) => plain "vminu.vv",
+        VMINU_VX(rd, rs1) => plain "vminu.vx",
+        VMIN_VV(rd, rs1) => plain "vmin.vv",
+        VMIN_VX(rd, rs1) => plain "vmin.vx",
+        VMAXU_VV(rd, rs1) => plain "vmaxu.vv",
+        VMAXU_VX(rd, rs1) => plain "vmaxu.vx",
+        VMAX_VV(rd, rs1) => plain "vmax.vv",
+        VMAX_VX(rd, rs1) => plain "vmax.vx",
+        VWADDU_VV(rd, rs1) => plain "vwaddu.vv",
+        VWADDU_VX(rd, rs1) => plain "vwaddu.vx",
+        VWSUBU_VV(rd, rs1) => plain "vwsubu.vv",
+        VWSUBU_VX(rd, rs1) => plain "vwsubu.vx",
+        VWADD_VV(rd, rs1) => plain "vwadd.vv",
+        VWADD_VX(rd, rs1) => plain "vwadd.vx",
+        VWSUB_VV(rd, rs1) => plain "vwsub.vv",
+        VWSUB_VX(rd, rs1) => plain "vwsub.vx",
+        VWADDU_WV(rd, rs1) => plain "vwaddu.wv",
+        VWADDU_WX(rd, rs1) => plain "vwaddu.wx",
+        VWSUBU_WV(rd, rs1) => plain "vwsubu.wv",
+        VWSUBU_WX(rd, rs1) => plain "vwsubu.wx",
+        VWADD_WV(rd, rs1) => plain "vwadd.wv",
+        VWADD_WX(rd, rs1) => plain "vwadd.wx",
+        VWSUB_WV(rd, rs1) => plain "vwsub.wv",
+        VWSUB_WX(rd, rs1) => plain "vwsub.wx",
+        VZEXT_VF8(rd, rs1) => plain "vzext.vf8",
+        VSEXT_VF8(rd, rs1) => plain "vsext.vf8",
+        VZEXT_VF4(rd, rs1) => plain "vzext.vf4",
+        VSEXT_VF4(rd, rs1) => plain "vsext.vf4",
+        VZEXT_VF2(rd, rs1) => plain "vzext.vf2",
+        VSEXT_VF2(rd, rs1) => plain "vsext.vf2",
+        VADC_VVM(rd, rs1) => plain "vadc.vvm",
+        VADC_VXM(rd, rs1) => plain "vadc.vxm",
+        VADC_VIM(rd, rs1) => plain "vadc.vim",
+        VMADC_VVM(rd, rs1) => plain "vmadc.vvm",
+        VMADC_VXM(rd, rs1) => plain "vmadc.vxm",
+        VMADC_VIM(rd, rs1) => plain "vmadc.vim",
+        VMADC_VV(rd, rs1) => plain "vmadc.vv",
+        VMADC_VX(rd, rs1) => plain "vmadc.vx",
+        VMADC_VI(rd, rs1) => plain "vmadc.vi",
+        VSBC_VVM(rd, rs1) => plain "vsbc.vvm",
+        VSBC_VXM(rd, rs1) => plain "vsbc.vxm",
+        VMSBC_VVM(rd, rs1) => plain "vmsbc.vvm",
+        VMSBC_VXM(rd, rs1) => plain "vmsbc.vxm",
+        VMSBC_VV(rd, rs1) => plain "vmsbc.vv",
+        VMSBC_VX(rd, rs1) => plain "vmsbc.vx",
+        VAND_VV(rd, rs1) => plain "vand.vv",
+        VAND_VI(rd, rs1) => plain "vand.vi",
+        VAND_VX(rd, rs1) => plain "vand.vx",
+        VOR_VV(rd, rs1) => plain "vor.vv",
+        VOR_VX(rd, rs1) => plain "vor.vx",
+        VOR_VI(rd, rs1) => plain "vor.vi",
+        VXOR_VV(rd, rs1) => plain "vxor.vv",
+        VXOR_VX(rd, rs1) => plain "vxor.vx",
+        VXOR_VI(rd, rs1) => plain "vxor.vi",
+        VNSRL_WV(rd, rs1) => plain "vnsrl.wv",
+        VNSRL_WX(rd, rs1) => plain "vnsrl.wx",
+        VNSRL_WI(rd, rs1) => plain "vnsrl.wi",
+        VNSRA_WV(rd, rs1) => plain "vnsra.wv",
+        VNSRA_WX(rd, rs1) => plain "vnsra.wx",
+        VNSRA_WI(rd, rs1) => plain "vnsra.wi",
+        VMULH_VV(rd, rs1) => plain "vmulh.vv",
+        VMULH_VX(rd, rs1) => plain "vmulh.vx",
+        VMULHU_VV(rd, rs1) => plain "vmulhu.vv",
+        VMULHU_VX(rd, rs1) => plain "vmulhu.vx",
+        VMULHSU_VV(rd, rs1) => plain "vmulhsu.vv",
+        VMULHSU_VX(rd, rs1) => plain "vmulhsu.vx",
+        VWMULU_VV(rd, rs1) => plain "vwmulu.vv",
+        VWMULU_VX(rd, rs1) => plain "vwmulu.vx",
+        VWMULSU_VV(rd, rs1) => plain "vwmulsu.vv",
+        VWMULSU_VX(rd, rs1) => plain "vwmulsu.vx",
+        VWMUL_VV(rd, rs1) => plain "vwmul.vv",
+        VWMUL_VX(rd, rs1) => plain "vwmul.vx",
+        VMV_V_V(rd, rs1) => plain "vmv.v.v",
+        VMV_V_X(rd, rs1) => plain "vmv.v.x",
+        VMV_V_I(rd, rs1) => plain "vmv.v.i",
+        VSADDU_VV(rd, rs1) => plain "vsaddu.vv",
+        VSADDU_VX(rd, rs1) => plain "vsaddu.vx",
+        VSADDU_VI(rd, rs1) => plain "vsaddu.vi",
+        VSADD_VV(rd, rs1) => plain "vsadd.vv",
+        VSADD_VX(rd, rs1) => plain "vsadd.vx",
+        VSADD_VI(rd, rs1) => plain "vsadd.vi",
+        VSSUBU_VV(rd, rs1) => plain "vssubu.vv",
+        VSSUBU_VX(rd, rs1) => plain "vssubu.vx",
+        VSSUB_VV(rd, rs1) => plain "vssub.vv",
+        VSSUB_VX(rd, rs1) => plain "vssub.vx",
+        VAADDU_VV(rd, rs1) => plain "vaaddu.vv",
+        VAADDU_VX(rd, rs1) => plain "vaaddu.vx",
+        VAADD_VV(rd, rs1) => plain "vaadd.vv",
+        VAADD_VX(rd, rs1) => plain "vaadd.vx",
+        VASUBU_VV(rd, rs1) => plain "vasubu.vv",
+        VASUBU_VX(rd, rs1) => plain "vasubu.vx",
+        VASUB_VV(rd, rs1) => plain "vasub.vv",
+        VASUB_VX(rd, rs1) => plain "vasub.vx",
+        VMV1R_V(rd, rs1) => plain "vmv1r.v",
+        VMV2R_V(rd, rs1) => plain "vmv2r.v",
+        VMV4R_V(rd, rs1) => plain "vmv4r.v",
+        VMV8R_V(rd, rs1) => plain "vmv8r.v",
+        VFIRST_M(rd, rs1) => plain "vfirst.m",
+        VMAND_MM(rd, rs1) => plain "vmand.mm",
+        VMNAND_MM(rd, rs1) => plain "vmnand.mm",
+        VMANDNOT_MM(rd, rs1) => plain "vmandnot.mm",
+        VMXOR_MM(rd, rs1) => plain "vmxor.mm",
+        VMOR_MM(rd, rs1) => plain "vmor.mm",
+        VMNOR_MM(rd, rs1) => plain "vmnor.mm",
+        VMORNOT_MM(rd, rs1) => plain "vmornot.mm",
+        VMXNOR_MM(rd, rs1) => plain "vmxnor.mm",
+        VLSE8_V(rd, rs1) => plain "vlse8.v",
+        VLSE16_V(rd, rs1) => plain "vlse16.v",
+        VLSE32_V(rd, rs1) => plain "vlse32.v",
+        VLSE64_V(rd, rs1) => plain "vlse64.v",
+        VLSE128_V(rd, rs1) => plain "vlse128.v",
+        VLSE256_V(rd, rs1) => plain "vlse256.v",
+        VLSE512_V(rd, rs1) => plain "vlse512.v",
+        VLSE1024_V(rd, rs1) => plain "vlse1024.v",
+        VSSE8_V(rd, rs1) => plain "vsse8.v",
+        VSSE16_V(rd, rs1) => plain "vsse16.v",
+        VSSE32_V(rd, rs1) => plain "vsse32.v",
+        VSSE64_V(rd, rs1) => plain "vsse64.v",
+        VSSE128_V(rd, rs1) => plain "vsse128.v",
+        VSSE256_V(rd, rs1) => plain "vsse256.v",
+        VSSE512_V(rd, rs1) => plain "vsse512.v",
+        VSSE1024_V(rd, rs1) => plain "vsse1024.v",
+        VLUXEI8_V(rd, rs1) => plain "vluxei8.v",
+        VLUXEI16_V(rd, rs1) => plain "vluxei16.v",
+        VLUXEI32_V(rd, rs1) => plain "vluxei32.v",
+        VLUXEI64_V(rd, rs1) => plain "vluxei64.v",
+        VLUXEI128_V(rd, rs1) => plain "vluxei128.v",
+        VLUXEI256_V(rd, rs1) => plain "vluxei256.v",
+        VLUXEI512_V(rd, rs1) => plain "vluxei512.v",
+        VLUXEI1024_V(rd, rs1) => plain "vluxei1024.v",
+        VLOXEI8_V(rd, rs1) => plain "vloxei8.v",
+        VLOXEI16_V(rd, rs1) => plain "vloxei16.v",
+        VLOXEI32_V(rd, rs1) => plain "vloxei32.v",
+        VLOXEI64_V(rd, rs1) => plain "vloxei64.v",
+        VLOXEI128_V(rd, rs1) => plain "vloxei128.v",
+        VLOXEI256_V(rd, rs1) => plain "vloxei256.v",
+        VLOXEI512_V(rd, rs1) => plain "vloxei512.v",
+        VLOXEI1024_V(rd, rs1) => plain "vloxei1024.v",
+        VSUXEI8_V(rd, rs1) => plain "vsuxei8.v",
+        VSUXEI16_V(rd, rs1) => plain "vsuxei16.v",
+        VSUXEI32_V(rd, rs1) => plain "vsuxei32.v",
+        VSUXEI64_V(rd, rs1) => plain "vsuxei64.v",
+        VSUXEI128_V(rd, rs1) => plain "vsuxei128.v",
+        VSUXEI256_V(rd, rs1) => plain "vsuxei256.v",
+        VSUXEI512_V(rd, rs1) => plain "vsuxei512.v",
+        VSUXEI1024_V(rd, rs1) => plain "vsuxei1024.v",
+        VSOXEI8_V(rd, rs1) => plain "vsoxei8.v",
+        VSOXEI16_V(rd, rs1) => plain "vsoxei16.v",
+        VSOXEI32_V(rd, rs1) => plain "vsoxei32.v",
+        VSOXEI64_V(rd, rs1) => plain "vsoxei64.v",
+        VSOXEI128_V(rd, rs1) => plain "vsoxei128.v",
+        VSOXEI256_V(rd, rs1) => plain "vsoxei256.v",
+        VSOXEI512_V(rd, rs1) => plain "vsoxei512.v",
+        VSOXEI1024_V(rd, rs1) => plain "vsoxei1024.v",
+        VL1RE8_V(rd, rs1) => plain "vl1re8.v",
+        VL1RE16_V(rd, rs1) => plain "vl1re16.v",
+        VL1RE32_V(rd, rs1) => plain "vl1re32.v",
+        VL1RE64_V(rd, rs1) => plain "vl1re64.v",
+        VL2RE8_V(rd, rs1) => plain "vl2re8.v",
+        VL2RE16_V(rd, rs1) => plain "vl2re16.v",
+        VL2RE32_V(rd, rs1) => plain "vl2re32.v",
+        VL2RE64_V(rd, rs1) => plain "vl2re64.v",
+        VL4RE8_V(rd, rs1) => plain "vl4re8.v",
+        VL4RE16_V(rd, rs1) => plain "vl4re16.v",
+        VL4RE32_V(rd, rs1) => plain "vl4re32.v",
+        VL4RE64_V(rd, rs1) => plain "vl4re64.v",
+        VL8RE8_V(rd, rs1) => plain "vl8re8.v",
+        VL8RE16_V(rd, rs1) => plain "vl8re16.v",
+        VL8RE32_V(rd, rs1) => plain "vl8re32.v",
+        VL8RE64_V(rd, rs1) => plain "vl8re64.v",
+        VS1R_V(rd, rs1) => plain "vs1r.v",
+        VS2R_V(rd, rs1) => plain "vs2r.v",
+        VS4R_V(rd, rs1) => plain "vs4r.v",
+        VS8R_V(rd, rs1) => plain "vs8r.v",
+        VMACC_VV(rd, rs1) => plain "vmacc.vv",
+        VMACC_VX(rd, rs1) => plain "vmacc.vx",
+        VNMSAC_VV(rd, rs1) => plain "vnmsac.vv",
+        VNMSAC_VX(rd, rs1) => plain "vnmsac.vx",
+        VMADD_VV(rd, rs1) => plain "vmadd.vv",
+        VMADD_VX(rd, rs1) => plain "vmadd.vx",
+        VNMSUB_VV(rd, rs1) => plain "vnmsub.vv",
+        VNMSUB_VX(rd, rs1) => plain "vnmsub.vx",
+        VSSRL_VV(rd, rs1) => plain "vssrl.vv",
+        VSSRL_VX(rd, rs1) => plain "vssrl.vx",
+        VSSRL_VI(rd, rs1) => plain "vssrl.vi",
+        VSSRA_VV(rd, rs1) => plain "vssra.vv",
+        VSSRA_VX(rd, rs1) => plain "vssra.vx",
+        VSSRA_VI(rd, rs1) => plain "vssra.vi",
+        VSMUL_VV(rd, rs1) => plain "vsmul.vv",
+        VSMUL_VX(rd, rs1) => plain "vsmul.vx",
+        VWMACCU_VV(rd, rs1) => plain "vwmaccu.vv",
+        VWMACCU_VX(rd, rs1) => plain "vwmaccu.vx",
+        VWMACC_VV(rd, rs1) => plain "vwmacc.vv",
+        VWMACC_VX(rd, rs1) => plain "vwmacc.vx",
+        VWMACCSU_VV(rd, rs1) => plain "vwmaccsu.vv",
+        VWMACCSU_VX(rd, rs1) => plain "vwmaccsu.vx",
+        VWMACCUS_VX(rd, rs1) => plain "vwmaccus.vx",
+        VMERGE_VVM(rd, rs1) => plain "vmerge.vvm",
+        VMERGE_VXM(rd, rs1) => plain "vmerge.vxm",
+        VMERGE_VIM(rd, rs1) => plain "vmerge.vim",
+        VNCLIPU_WV(rd, rs1) => plain "vnclipu.wv",
+        VNCLIPU_WX(rd, rs1) => plain "vnclipu.wx",
+        VNCLIPU_WI(rd, rs1) => plain "vnclipu.wi",
+        VNCLIP_WV(rd, rs1) => plain "vnclip.wv",
+        VNCLIP_WX(rd, rs1) => plain "vnclip.wx",
+        VNCLIP_WI(rd, rs1) => plain "vnclip.wi",
+        VREDSUM_VS(rd, rs1) => plain "vredsum.vs",
+        VREDAND_VS(rd, rs1) => plain "vredand.vs",
+        VREDOR_VS(rd, rs1) => plain "vredor.vs",
+        VREDXOR_VS(rd, rs1) => plain "vredxor.vs",
+        VREDMINU_VS(rd, rs1) => plain "vredminu.vs",
+        VREDMIN_VS(rd, rs1) => plain "vredmin.vs",
+        VREDMAXU_VS(rd, rs1) => plain "vredmaxu.vs",
+        VREDMAX_VS(rd, rs1) => plain "vredmax.vs",
+        VWREDSUMU_VS(rd, rs1) => plain "vwredsumu.vs",
+        VWREDSUM_VS(rd, rs1) => plain "vwredsum.vs",
+        VCPOP_M(rd, rs1) => plain "vcpop.m",
+        VMSBF_M(rd, rs1) => plain "vmsbf.m",
+        VMSOF_M(rd, rs1) => plain "vmsof.m",
+        VMSIF_M(rd, rs1) => plain "vmsif.m",
+        VIOTA_M(rd, rs1) => plain "viota.m",
+        VID_V(rd, rs1) => plain "vid.v",
+        VMV_X_S(rd, rs1) => plain "vmv.x.s",
+        VMV_S_X(rd, rs1) => plain "vmv.s.x",
+        VCOMPRESS_VM(rd, rs1) => plain "vcompress.vm",
+        VSLIDE1UP_VX(rd, rs1) => plain "vslide1up.vx",
+        VSLIDEUP_VX(rd, rs1) => plain "vslideup.vx",
+        VSLIDEUP_VI(rd, rs1) => plain "vslideup.vi",
+        VSLIDE1DOWN_VX(rd, rs1) => plain "vslide1down.vx",
+        VSLIDEDOWN_VX(rd, rs1) => plain "vslidedown.vx",
+        VSLIDEDOWN_VI(rd, rs1) => plain "vslidedown.vi",
+        VRGATHER_VX(rd, rs1) => plain "vrgather.vx",
+        VRGATHER_VV(rd, rs1) => plain "vrgather.vv",
+        VRGATHEREI16_VV(rd, rs1) => plain "vrgatherei16.vv",
+        VRGATHER_VI(rd, rs1) => plain "vrgather.vi",
+    }
+    RV64I {
+        LWU(rd, rs1, imm) => load "lwu",
+        LD(rd, rs1, imm) => load "ld",
+        SD(rs1, rs2, imm) => store "sd",
+        SLLI(rd, rs1, shamt) => plain "slli",
+        SRLI(rd, rs1, shamt) => plain "srli",
+        SRAI(rd, rs1, shamt) => plain "srai",
+        ADDIW(rd, rs1, imm) => plain "addiw",
+        SLLIW(rd, rs1, shamt) => plain "slliw",
+        SRLIW(rd, rs1, shamt) => plain "srliw",
+        SRAIW(rd, rs1, shamt) => plain "sraiw",
+        ADDW(rd, rs1, rs2) => plain "addw",
+        SUBW(rd, rs1, rs2) => plain "subw",
+        SLLW(rd, rs1, rs2) => plain "sllw",
+        SRLW(rd, rs1, rs2) => plain "srlw",
+        SRAW(rd, rs1, rs2) => plain "sraw",
+    }
+    RV64M {
+        MULW(rd, rs1, rs2) => plain "mulw",
+        DIVW(rd, rs1, rs2) => plain "divw",
+        DIVUW(rd, rs1, rs2) => plain "divuw",
+        REMW(rd, rs1, rs2) => plain "remw",
+        REMUW(rd, rs1, rs2) => plain "remuw",
+    }
+    RV64A {
+        LR_D(rd, rs1, aq, rl) => lr "lr.d",
+        SC_D(rd, rs1, rs2, aq, rl) => amo "sc.d",
+        AMOSWAP_D(rd, rs1, rs2, aq, rl) => amo "amoswap.d",
+        AMOADD_D(rd, rs1, rs2, aq, rl) => amo "amoadd.d",
+        AMOXOR_D(rd, rs1, rs2, aq, rl) => amo "amoxor.d",
+        AMOAND_D(rd, rs1, rs2, aq, rl) => amo "amoand.d",
+        AMOOR_D(rd, rs1, rs2, aq, rl) => amo "amoor.d",
+        AMOMIN_D(rd, rs1, rs2, aq, rl) => amo "amomin.d",
+        AMOMAX_D(rd, rs1, rs2, aq, rl) => amo "amomax.d",
+        AMOMINU_D(rd, rs1, rs2, aq, rl) => amo "amominu.d",
+        AMOMAXU_D(rd, rs1, rs2, aq, rl) => amo "amomaxu.d",
+    }
+    RV64F {
+        FCVT_L_S(rd, rs1, rm) => plain "fcvt.l.s",
+        FCVT_LU_S(rd, rs1, rm) => plain "fcvt.lu.s",
+        FCVT_S_L(rd, rs1, rm) => plain "fcvt.s.l",
+        FCVT_S_LU(rd, rs1, rm) => plain "fcvt.s.lu",
+    }
+    RV64E {
+        LWU(rd, rs1, imm) => load "lwu",
+        LD(rd, rs1, imm) => load "ld",
+        SD(rs1, rs2, imm) => store "sd",
+        SLLI(rd, rs1, shamt) => plain "slli",
+        SRLI(rd, rs1, shamt) => plain "srli",
+        SRAI(rd, rs1, shamt) => plain "srai",
+        ADDIW(rd, rs1, imm) => plain "addiw",
+        SLLIW(rd, rs1, shamt) => plain "slliw",
+        SRLIW(rd, rs1, shamt) => plain "srliw",
+        SRAIW(rd, rs1, shamt) => plain "sraiw",
+        ADDW(rd, rs1, rs2) => plain "addw",
+        SUBW(rd, rs1, rs2) => plain "subw",
+        SLLW(rd, rs1, rs2) => plain "sllw",
+        SRLW(rd, rs1, rs2) => plain "srlw",
+        SRAW(rd, rs1, rs2) => plain "sraw",
+    }
+    RV64D {
+        FCVT_L_D(rd, rs1, rm) => plain "fcvt.l.d",
+        FCVT_LU_D(rd, rs1, rm) => plain "fcvt.lu.d",
+        FMV_X_D(rd, rs1) => plain "fmv.x.d",
+        FCVT_D_L(rd, rs1, rm) => plain "fcvt.d.l",
+        FCVT_D_LU(rd, rs1, rm) => plain "fcvt.d.lu",
+        FMV_D_X(rd, rs1) => plain "fmv.d.x",
+    }
+    RV128I {
+        LDU(rd, rs1, imm) => plain "ldu",
+        LD(rd, rs1, imm) => load "ld",
+        SD(rs1, rs2, imm) => store "sd",
+        SLLI(rd, rs1, shamt) => plain "slli",
+        SRLI(rd, rs1, shamt) => plain "srli",
+        SRAI(rd, rs1, shamt) => plain "srai",
+        ADDID(rd, rs1, imm) => plain "addid",
+        SLLID(rd, rs1, shamt) => plain "sllid",
+        SRLID(rd, rs1, shamt) => plain "srlid",
+        SRAID(rd, rs1, shamt) => plain "sraid",
+        ADDD(rd, rs1, rs2) => plain "addd",
+        SUBD(rd, rs1, rs2) => plain "subd",
+        SLLD(rd, rs1, rs2) => plain "slld",
+        SRLD(rd, rs1, rs2) => plain "srld",
+        SRAD(rd, rs1, rs2) => plain "srad",
+    }
+    RVPreviledge {
+        SRET => plain "sret",
+        MRET => plain "mret",
+        WFI => plain "wfi",
+        SFENCE_VMA(rs1, rs2) => plain "sfence.vma",
+        SINVAL_VMA(rs1, rs2) => plain "sinval.vma",
+        SFENCE_W_INVAL => plain "sfence.w.inval",
+        SFENCE_INVAL_IR => plain "sfence.inval.ir",
+    }
+    RVB {
+        ADDUW(rd, rs1, rs2) => plain "adduw",
+        ANDN(rd, rs1, rs2) => plain "andn",
+        BCLR(rd, rs1, rs2) => plain "bclr",
+        BCLRI(rd, rs1, imm) => plain "bclri",
+        BEXT(rd, rs1, rs2) => plain "bext",
+        BEXTI(rd, rs1, imm) => plain "bexti",
+        BINV(rd, rs1, rs2) => plain "binv",
+        BINVI(rd, rs1, imm) => plain "binvi",
+        BSET(rd, rs1, rs2) => plain "bset",
+        BSETI(rd, rs1, imm) => plain "bseti",
+        CLMUL(rd, rs1, rs2) => plain "clmul",
+        CLMULH(rd, rs1, rs2) => plain "clmulh",
+        CLMULR(rd, rs1, rs2) => plain "clmulr",
+        CLZ(rd, rs) => plain "clz",
+        CLZW(rd, rs) => plain "clzw",
+        CPOP(rd, rs) => plain "cpop",
+        CPOPW(rd, rs) => plain "cpopw",
+        CTZ(rd, rs) => plain "ctz",
+        CTZW(rd, rs) => plain "ctzw",
+        MAX(rd, rs1, rs2) => plain "max",
+        MAXU(rd, rs1, rs2) => plain "maxu",
+        MIN(rd, rs1, rs2) => plain "min",
+        MINU(rd, rs1, rs2) => plain "minu",
+        ORCB(rd, rs1, rs2) => plain "orcb",
+        ORN(rd, rs1, rs2) => plain "orn",
+        REV8(rd, rs) => plain "rev8",
+        ROL(rd, rs1, rs2) => plain "rol",
+        ROLW(rd, rs1, rs2) => plain "rolw",
+        ROR(rd, rs1, rs2) => plain "ror",
+        RORI(rd, rs1, shamt) => plain "rori",
+        RORIW(rd, rs1, shamt) => plain "roriw",
+        RORW(rd, rs1, rs2) => plain "rorw",
+        SEXTB(rd, rs) => plain "sextb",
+        SEXTH(rd, rs) => plain "sexth",
+        SH1ADD(rd, rs1, rs2) => plain "sh1add",
+        SH1ADDUW(rd, rs1, rs2) => plain "sh1adduw",
+        SH2ADD(rd, rs1, rs2) => plain "sh2add",
+        SH2ADDUW(rd, rs1, rs2) => plain "sh2adduw",
+        SH3ADD(rd, rs1, rs2) => plain "sh3add",
+        SH3ADDUW(rd, rs1, rs2) => plain "sh3adduw",
+        SLLIUW(rd, rs1, rs2) => plain "slliuw",
+        XNOR(rd, rs1, rs2) => plain "xnor",
+        ZEXTH(rd, rs) => plain "zexth",
+    }
+    RVZcsr {
+        CSRRW(rd, rs1, csr) => csr "csrrw",
+        CSRRS(rd, rs1, csr) => csr "csrrs",
+        CSRRC(rd, rs1, csr) => csr "csrrc",
+        CSRRWI(rd, uimm, csr) => csr "csrrwi",
+        CSRRSI(rd, uimm, csr) => csr "csrrsi",
+        CSRRCI(rd, uimm, csr) => csr "csrrci",
+    }
+    RVZifencei {
+        FENCE_I(rd, rs1, imm) => bare "fence.i",
+    }
+}
+
 impl fmt::Display for RV32Instr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Ok(())
+        match self {
+            RV32Instr::RV32I(inst) => inst.fmt(f),
+            RV32Instr::RV32M(inst) => inst.fmt(f),
+            RV32Instr::RV32A(inst) => inst.fmt(f),
+            RV32Instr::RV32F(inst) => inst.fmt(f),
+            RV32Instr::RV32E(inst) => inst.fmt(f),
+            RV32Instr::RV32D(inst) => inst.fmt(f),
+            RV32Instr::RVB(inst) => inst.fmt(f),
+            RV32Instr::RVV(inst) => inst.fmt(f),
+            RV32Instr::RVZifencei(inst) => inst.fmt(f),
+            RV32Instr::RVZcsr(inst) => inst.fmt(f),
+        }
     }
-} // TODO: implement the rv32
+}
 impl fmt::Display for RV64Instr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Ok(())
+        match self {
+            RV64Instr::RV64I(inst) => inst.fmt(f),
+            RV64Instr::RV64M(inst) => inst.fmt(f),
+            RV64Instr::RV64A(inst) => inst.fmt(f),
+            RV64Instr::RV64F(inst) => inst.fmt(f),
+            RV64Instr::RV64E(inst) => inst.fmt(f),
+            RV64Instr::RV64D(inst) => inst.fmt(f),
+            RV64Instr::RVB(inst) => inst.fmt(f),
+            RV64Instr::RV64V(inst) => inst.fmt(f),
+            RV64Instr::RVZifencei(inst) => inst.fmt(f),
+            RV64Instr::RVZcsr(inst) => inst.fmt(f),
+            RV64Instr::RVPreviledge(inst) => inst.fmt(f),
+        }
     }
-} // TODO: implement the rv64
+}
 impl fmt::Display for RV128Instr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Ok(())
+        match self {
+            RV128Instr::RV128I(inst) => inst.fmt(f),
+            RV128Instr::RVV(inst) => inst.fmt(f),
+            RV128Instr::RVZifencei(inst) => inst.fmt(f),
+            RV128Instr::RVZcsr(inst) => inst.fmt(f),
+        }
     }
-} // TODO: implement the rv128
+}
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::NOP => write!(f, "nop"),
+            Instr::RV32(inst) => inst.fmt(f),
+            Instr::RV64(inst) => inst.fmt(f),
+            Instr::RV128(inst) => inst.fmt(f),
+        }
+    }
+}
 
 impl Instr {
     pub fn get_arch(self) -> u32 {
@@ -1708,7 +2545,7 @@ macro_rules! rrm {
             0b011 => RoundingMode::RUP,
             0b100 => RoundingMode::RMM,
             0b111 => RoundingMode::DYN,
-            _ => panic!("wrong RoundingMode"),
+            _ => return None,
         };
         $type!($opcode1, $opcode2, rd, rs1, rs2, rm)
     }};
@@ -1725,7 +2562,7 @@ macro_rules! rrm_no_rs2 {
             0b011 => RoundingMode::RUP,
             0b100 => RoundingMode::RMM,
             0b111 => RoundingMode::DYN,
-            _ => panic!("wrong RoundingMode"),
+            _ => return None,
         };
         $type!($opcode1, $opcode2, rd, rs1, rm)
     }};
@@ -1744,7 +2581,7 @@ macro_rules! r4 {
             0b011 => RoundingMode::RUP,
             0b100 => RoundingMode::RMM,
             0b111 => RoundingMode::DYN,
-            _ => panic!("wrong RoundingMode"),
+            _ => return None,
         };
         $type!($opcode1, $opcode2, rd, rs1, rs2, rs3, rm)
     }};
@@ -1776,9 +2613,25 @@ macro_rules! rvv {
     ($ident1:ident,$ident2:ident, $($t:expr),*) => { Instr::RV32(RV32Instr::$ident1($ident1::$ident2($( $t, )*))) };
 }
 impl Instruction {
+    /// Decode the instruction at the start of `bytes`, giving its length,
+    /// or `None` for an encoding the decoder does not know or one cut off
+    pub fn decode(bytes: &[u8]) -> Option<(Instruction, usize)> {
+        let len = if bytes.first()? & 0b11 == 0b11 { 4 } else { 2 };
+        let mut bit = [0; 4];
+        bit[..len].copy_from_slice(bytes.get(..len)?);
+        let instruction = if len == 2 { try_from_compressed(&bit)? } else { Self::try_parse(&bit)? };
+        Some((instruction, len))
+    }
+
     fn parse(bit: &[u8]) -> Instruction {
+        Self::try_parse(bit).unwrap()
+    }
+
+    /// The instruction `bit` encodes, `None` for an encoding the decoder
+    /// does not know
+    fn try_parse(bit: &[u8]) -> Option<Instruction> {
         if let Some(instr) = try_from_compressed(bit) {
-            instr
+            Some(instr)
         } else {
             let bit_u32 = u32::from_le_bytes(bit.split_at(4).0.try_into().unwrap());
 
@@ -2658,11 +3511,10 @@ impl Instruction {
                 //     _ => None,
                 // },
                 _ => None,
-            }
-            .unwrap();
+            }?;
 
-            dbg!(opcode);
-            Self { instr }
+            tracing::trace!(target: "doublejit::frontend", opcode, "decoded");
+            Some(Self { instr })
         }
     }
 }
@@ -2843,4 +3695,17 @@ mod tests {
         //     )))
         // );
     }
+    #[test]
+    fn test_display() {
+        for (instr_asm, asm) in [
+            (0xff010113u32, "addi sp,sp,-16"),
+            (0x00113423, "sd ra,8(sp)"),
+            (0x04b6252f, "amoadd.w.aq a0,a1,(a2)"),
+            (0x30051073, "csrrw zero,0x300,a0"),
+            (0x0330000f, "fence rw,rw"),
+            (0x00000073, "ecall"),
+        ] {
+            assert_eq!(Instruction::parse(&instr_asm.to_le_bytes()).instr.to_string(), asm);
+        }
+    }
 }
@@ -10,3 +10,14 @@ static mut BIT_LENGTH: i8 = 0;
 static mut IS_E: bool = false;
 pub const VLEN: i32 = 2048;
 pub const ELEN: i32 = 2048;
+
+/// Decode the instructions of the base ISA of `class` from now on, as
+/// `Binary::parse` does for the binary it loads
+pub fn set_class(class: elf::Class) {
+    let bit_length = match class {
+        elf::Class::ThirtyTwo => 0,
+        elf::Class::OneTwentyEight => 2,
+        _ => 1,
+    };
+    unsafe { BIT_LENGTH = bit_length }
+}
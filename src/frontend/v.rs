@@ -32,29 +32,37 @@ impl V {
         r.set_vl(0, 0, 0, u64::MAX);
         r
     }
-    pub fn set_vl(&mut self, rd: usize, rs1: usize, avl: u64, new_type: u64) {
+    /// Apply a vsetvli or vsetvl of `new_type` asking for `avl` elements,
+    /// returning the new `vl`, which is what the instruction writes to rd.
+    /// A `vtype` the hart does not support, with reserved bits, a reserved
+    /// SEW or LMUL, or a SEW wider than ELEN times a fractional LMUL, sets
+    /// `vill` and clears `vl`.
+    pub fn set_vl(&mut self, rd: usize, rs1: usize, avl: u64, new_type: u64) -> u64 {
         if self.vtype != new_type {
-            self.vtype = new_type;
-            self.vsew = 1 << (((new_type >> 3) & 0x7) + 3);
-            self.vlmul = match new_type & 0x7 {
-                0b000 => 1.0,
-                0b001 => 2.0,
-                0b010 => 4.0,
-                0b011 => 8.0,
-                0b111 => 0.5,
-                0b110 => 0.25,
-                0b101 => 0.125,
-                _ => 0.0625,
-            };
-            self.vlmax = ((VLEN as u64 / self.vsew) as f64 * self.vlmul) as u64;
-            self.vta = ((new_type >> 6) & 0x1) != 0;
-            self.vma = ((new_type >> 7) & 0x1) != 0;
-            self.vill = self.vlmul == 0.0625
-                || (new_type >> 8) != 0
-                || self.vsew as f64 > if self.vlmul > 1.0 { 1.0 } else { self.vlmul } * ELEN as f64;
+            let sew = (new_type >> 3) & 0x7;
+            let lmul = new_type & 0x7;
+            // LMUL as a power of two, negative for the fractional ones
+            let lmul_log2 = if lmul < 4 { lmul as i32 } else { lmul as i32 - 8 };
+            let sew_log2 = sew as i32 + 3;
+            self.vill = (new_type >> 8) != 0
+                || sew > 0b011
+                || lmul == 0b100
+                || sew_log2 > ELEN.ilog2() as i32 + lmul_log2.min(0);
             if self.vill {
-                self.vlmax = 0;
                 self.vtype = 1 << 63;
+                self.vsew = 0;
+                self.vlmul = 0.0;
+                self.vta = false;
+                self.vma = false;
+                self.vlmax = 0;
+            } else {
+                self.vtype = new_type;
+                self.vsew = 1 << sew_log2;
+                self.vlmul = 2f64.powi(lmul_log2);
+                self.vta = ((new_type >> 6) & 0x1) != 0;
+                self.vma = ((new_type >> 7) & 0x1) != 0;
+                let elements = VLEN as u64 >> sew_log2;
+                self.vlmax = if lmul_log2 >= 0 { elements << lmul_log2 } else { elements >> -lmul_log2 };
             }
         }
         if self.vlmax == 0 {
@@ -67,6 +75,15 @@ impl V {
             self.vl = std::cmp::min(avl, self.vlmax);
         }
         self.vstart = 0;
+        self.vl
+    }
+
+    pub fn vtype(&self) -> u64 {
+        self.vtype
+    }
+
+    pub fn vstart(&self) -> u64 {
+        self.vstart
     }
 
     pub fn vl(&self) -> u64 {
@@ -100,4 +117,27 @@ impl V {
     pub fn vlenb(&self) -> u64 {
         self.vlenb
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_vl() {
+        let mut v = V::new();
+        assert!(v.vill());
+        // e32, m1, ta, ma
+        assert_eq!(v.set_vl(1, 2, 10, 0b1101_0000), 10);
+        assert_eq!((v.vlmax(), v.vsew(), v.vta(), v.vma()), (64, 32, true, true));
+        assert_eq!(v.set_vl(1, 0, 0, 0b1101_0000), 64);
+        // a fractional LMUL divides VLMAX
+        assert_eq!(v.set_vl(1, 2, 100, 0b011_101), VLEN as u64 / 64 / 8);
+        assert_eq!(v.vlmul(), 0.125);
+        // reserved SEW, LMUL and high bits all set vill
+        for vtype in [0b100_000, 0b000_100, 1 << 8] {
+            assert_eq!(v.set_vl(1, 2, 10, vtype), 0);
+            assert_eq!((v.vill(), v.vtype(), v.vsew()), (true, 1 << 63, 0));
+        }
+    }
+}
@@ -6,9 +6,10 @@ extern crate alloc;
 #[cfg(any(test, feature = "std"))]
 extern crate std;
 
-mod codegen;
-mod frontend;
-mod middleend;
-mod runtime;
-mod tools;
-mod wasm;
+pub mod codegen;
+pub mod error;
+pub mod frontend;
+pub mod middleend;
+pub mod runtime;
+pub mod tools;
+pub mod wasm;
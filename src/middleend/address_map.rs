@@ -1,3 +1,1087 @@
+use crate::frontend::page::Page;
+use crate::runtime::mmu::Mmu;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{BitOr, Range};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Byte storage behind guest memory, addressed by linear memory offsets
 pub trait LinearMemory {
-    
-}
\ No newline at end of file
+    fn size(&self) -> usize;
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), MemoryError>;
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), MemoryError>;
+
+    fn read_u64(&self, offset: usize) -> Result<u64, MemoryError> {
+        let mut bytes = [0; 8];
+        self.read(offset, &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write_u64(&mut self, offset: usize, value: u64) -> Result<(), MemoryError> {
+        self.write(offset, &value.to_le_bytes())
+    }
+
+    /// Store `new` in the `len` bytes at `offset` if they still hold
+    /// `current`, telling whether they did. Only the low `len` bytes of the
+    /// values count. A memory shared by threads does it atomically.
+    fn compare_exchange(&mut self, offset: usize, len: usize, current: u64, new: u64) -> Result<bool, MemoryError> {
+        let mut bytes = [0; 8];
+        self.read(offset, &mut bytes[..len])?;
+        if u64::from_le_bytes(bytes) != current & (u64::MAX >> (64 - len * 8)) {
+            return Ok(false);
+        }
+        self.write(offset, &new.to_le_bytes()[..len])?;
+        Ok(true)
+    }
+
+    /// Record the contents of every page, copying only the pages that are
+    /// not all zeros
+    fn snapshot(&self) -> Snapshot {
+        let mut page = [0; Page::SIZE];
+        let pages = (0..self.size() / Page::SIZE)
+            .map(|index| {
+                self.read(index * Page::SIZE, &mut page).unwrap();
+                page.iter().any(|&byte| byte != 0).then(|| Box::new(page))
+            })
+            .collect();
+        Snapshot {
+            size: self.size(),
+            pages,
+        }
+    }
+
+    /// Page aligned, coalesced offset ranges changed since the snapshot was
+    /// taken, memory grown since then counts as dirty
+    fn diff(&self, snapshot: &Snapshot) -> Vec<Range<usize>> {
+        let mut page = [0; Page::SIZE];
+        let mut dirty: Vec<Range<usize>> = Vec::new();
+        for index in 0..self.size() / Page::SIZE {
+            self.read(index * Page::SIZE, &mut page).unwrap();
+            if snapshot.page(index).is_some_and(|old| *old == page) {
+                continue;
+            }
+            let start = index * Page::SIZE;
+            match dirty.last_mut() {
+                Some(last) if last.end == start => last.end += Page::SIZE,
+                _ => dirty.push(start..start + Page::SIZE),
+            }
+        }
+        dirty
+    }
+
+    /// Copy the pages changed since `base`, e.g. `Snapshot::zeroed` for
+    /// everything but the untouched zero pages
+    fn capture(&self, base: &Snapshot) -> MemoryDelta {
+        let pages = self
+            .diff(base)
+            .into_iter()
+            .map(|range| {
+                let mut data = vec![0; range.len()];
+                self.read(range.start, &mut data).unwrap();
+                (range.start, data)
+            })
+            .collect();
+        MemoryDelta {
+            size: self.size(),
+            pages,
+        }
+    }
+
+    /// Write the captured pages back, onto a memory in the state of the
+    /// base they were captured against
+    fn restore(&mut self, delta: &MemoryDelta) -> Result<(), MemoryError> {
+        if delta.size > self.size() {
+            return Err(MemoryError::OutOfBounds(delta.size));
+        }
+        for (offset, data) in &delta.pages {
+            self.write(*offset, data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Guest pointer accessor, reading and writing guest virtual addresses
+/// through the address map the way the kernel would on behalf of a syscall
+pub struct GuestMemory<'a, M: LinearMemory + ?Sized> {
+    map: &'a mut AddressMap,
+    memory: &'a mut M,
+}
+
+impl<'a, M: LinearMemory + ?Sized> GuestMemory<'a, M> {
+    pub fn new(map: &'a mut AddressMap, memory: &'a mut M) -> Self {
+        Self { map, memory }
+    }
+
+    pub fn map(&self) -> &AddressMap {
+        self.map
+    }
+
+    /// The guest physical address of `vaddr` under the page table of `mmu`
+    pub fn translate(&mut self, mmu: &mut Mmu, vaddr: u64, access: Access) -> Result<u64, GuestFault> {
+        mmu.translate(vaddr, access, self.map, self.memory)
+    }
+
+    /// The guest physical address of `vaddr` under the page table of `mmu`,
+    /// looked up without side effects on the guest
+    pub fn lookup(&self, mmu: &Mmu, vaddr: u64) -> Option<u64> {
+        mmu.peek(vaddr, self.map, &*self.memory)
+    }
+
+    /// Split the access into per page pieces of (linear offset, range in
+    /// buffer), checking the page permissions unless `access` is `None`
+    fn pieces(
+        &self,
+        vaddr: u64,
+        len: usize,
+        access: Option<Access>,
+    ) -> Result<Vec<(usize, Range<usize>)>, MemoryError> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let addr = vaddr
+                .checked_add(done as u64)
+                .ok_or(MemoryError::Unmapped(vaddr))?;
+            let offset = match access {
+                Some(access) => self.map.check(addr, access).ok(),
+                None => self.map.vaddr_to_offset(addr),
+            };
+            let offset = offset.ok_or(MemoryError::Unmapped(addr))?;
+            let page_left = Page::SIZE - (addr as usize % Page::SIZE);
+            let n = page_left.min(len - done);
+            pieces.push((offset, done..done + n));
+            done += n;
+        }
+        Ok(pieces)
+    }
+
+    pub fn read(&self, vaddr: u64, buf: &mut [u8]) -> Result<(), MemoryError> {
+        for (offset, range) in self.pieces(vaddr, buf.len(), Some(Access::Read))? {
+            self.memory.read(offset, &mut buf[range])?;
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, vaddr: u64, data: &[u8]) -> Result<(), MemoryError> {
+        for (offset, range) in self.pieces(vaddr, data.len(), Some(Access::Write))? {
+            self.memory.write(offset, &data[range])?;
+        }
+        Ok(())
+    }
+
+    /// Write regardless of the page permissions, as the loader and mmap do
+    /// to fill pages the guest may not write itself
+    pub fn init(&mut self, vaddr: u64, data: &[u8]) -> Result<(), MemoryError> {
+        for (offset, range) in self.pieces(vaddr, data.len(), None)? {
+            self.memory.write(offset, &data[range])?;
+        }
+        Ok(())
+    }
+
+    /// Read regardless of the page permissions, as mremap does to move pages
+    /// the guest may not read itself
+    pub fn peek(&self, vaddr: u64, buf: &mut [u8]) -> Result<(), MemoryError> {
+        for (offset, range) in self.pieces(vaddr, buf.len(), None)? {
+            self.memory.read(offset, &mut buf[range])?;
+        }
+        Ok(())
+    }
+
+    /// Store `new` at `vaddr`, aligned to `len`, if the bytes there still
+    /// hold `current`, regardless of the page permissions like `init`.
+    /// Tells whether it stored.
+    pub fn compare_exchange(&mut self, vaddr: u64, len: usize, current: u64, new: u64) -> Result<bool, MemoryError> {
+        let offset = self.map.vaddr_to_offset(vaddr).ok_or(MemoryError::Unmapped(vaddr))?;
+        self.memory.compare_exchange(offset, len, current, new)
+    }
+
+    /// Write the permissions of the map into the table in linear memory,
+    /// once the loader mapped the image
+    pub fn write_perm_table(&mut self) -> Result<(), MemoryError> {
+        self.memory.write(self.map.perm_table_offset(), &self.map.perm_table())
+    }
+
+    /// Take the permissions from the table in linear memory, which another
+    /// thread changed through its own map of the same memory
+    pub fn read_perm_table(&mut self) -> Result<(), MemoryError> {
+        let mut table = vec![0; self.map.page_perms.len()];
+        self.memory.read(self.map.perm_table_offset(), &mut table)?;
+        self.map.page_perms = table.into_iter().map(Perm).collect();
+        Ok(())
+    }
+
+    /// Change the permission of the pages in the range, keeping the
+    /// permission table in linear memory in sync with the map
+    pub fn protect(&mut self, vaddr: Range<u64>, perm: Perm) -> Result<(), MemoryError> {
+        self.map.protect(vaddr.clone(), perm)?;
+        let table = self.map.perm_table_offset();
+        let mut page = vaddr.start & !(Page::SIZE as u64 - 1);
+        while page < vaddr.end {
+            let offset = self.map.vaddr_to_offset(page).unwrap();
+            self.memory.write(table + offset / Page::SIZE, &[perm.0])?;
+            page += Page::SIZE as u64;
+        }
+        Ok(())
+    }
+
+    pub fn read_vec(&self, vaddr: u64, len: usize) -> Result<Vec<u8>, MemoryError> {
+        let mut buf = vec![0; len];
+        self.read(vaddr, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read a NUL terminated string of at most `max` bytes, without the NUL
+    pub fn read_cstr(&self, vaddr: u64, max: usize) -> Result<Vec<u8>, MemoryError> {
+        let mut bytes = Vec::new();
+        let mut byte = [0];
+        while bytes.len() < max {
+            self.read(vaddr + bytes.len() as u64, &mut byte)?;
+            if byte[0] == 0 {
+                return Ok(bytes);
+            }
+            bytes.push(byte[0]);
+        }
+        Err(MemoryError::OutOfBounds(max))
+    }
+
+    pub fn read_u32(&self, vaddr: u64) -> Result<u32, MemoryError> {
+        let mut bytes = [0; 4];
+        self.read(vaddr, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&self, vaddr: u64) -> Result<u64, MemoryError> {
+        let mut bytes = [0; 8];
+        self.read(vaddr, &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn write_u32(&mut self, vaddr: u64, value: u32) -> Result<(), MemoryError> {
+        self.write(vaddr, &value.to_le_bytes())
+    }
+
+    pub fn write_u64(&mut self, vaddr: u64, value: u64) -> Result<(), MemoryError> {
+        self.write(vaddr, &value.to_le_bytes())
+    }
+
+    /// Run `f` on this memory, returning its result along with the writes
+    /// it made as linear offset and bytes
+    pub fn journaled<R>(
+        &mut self,
+        f: impl FnOnce(&mut GuestMemory<WriteJournal<M>>) -> R,
+    ) -> (R, Writes) {
+        let mut journal = WriteJournal {
+            memory: &mut *self.memory,
+            writes: Vec::new(),
+        };
+        let result = f(&mut GuestMemory::new(&mut *self.map, &mut journal));
+        (result, journal.writes)
+    }
+
+    /// Make the writes `journaled` returned again, at the same linear offsets
+    pub fn replay_writes(&mut self, writes: &[(usize, Vec<u8>)]) -> Result<(), MemoryError> {
+        for (offset, data) in writes {
+            self.memory.write(*offset, data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes to linear memory as offset and bytes, in the order made
+pub type Writes = Vec<(usize, Vec<u8>)>;
+
+/// Linear memory logging every write made to it
+pub struct WriteJournal<'a, M: LinearMemory + ?Sized> {
+    memory: &'a mut M,
+    writes: Writes,
+}
+
+impl<M: LinearMemory + ?Sized> LinearMemory for WriteJournal<'_, M> {
+    fn size(&self) -> usize {
+        self.memory.size()
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+        self.memory.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), MemoryError> {
+        self.memory.write(offset, data)?;
+        self.writes.push((offset, data.to_vec()));
+        Ok(())
+    }
+
+    fn compare_exchange(&mut self, offset: usize, len: usize, current: u64, new: u64) -> Result<bool, MemoryError> {
+        let exchanged = self.memory.compare_exchange(offset, len, current, new)?;
+        if exchanged {
+            self.writes.push((offset, new.to_le_bytes()[..len].to_vec()));
+        }
+        Ok(exchanged)
+    }
+}
+
+/// Linear memory of a guest process whose threads run on host threads of
+/// their own, each with a clone of it. The bytes live in atomic words, so
+/// every thread sees the stores of the others and `compare_exchange` is
+/// atomic.
+#[derive(Clone)]
+pub struct SharedMemory {
+    words: Arc<[AtomicU64]>,
+}
+
+impl SharedMemory {
+    /// A zeroed memory of `size` bytes, whose pages the host only
+    /// allocates once they are written
+    pub fn new(size: usize) -> Self {
+        let words = Arc::<[AtomicU64]>::new_zeroed_slice(size.div_ceil(8));
+        // SAFETY: an all zero AtomicU64 is a valid one
+        Self {
+            words: unsafe { words.assume_init() },
+        }
+    }
+
+    /// A memory of its own with the same contents, as a forked child gets
+    pub fn copy(&self) -> Self {
+        let copy = Self::new(self.size());
+        for (word, source) in copy.words.iter().zip(self.words.iter()) {
+            // the zero words are there already, and stay unallocated
+            let value = source.load(Ordering::Acquire);
+            if value != 0 {
+                word.store(value, Ordering::Release);
+            }
+        }
+        copy
+    }
+
+    /// The first byte, for compiled code to access the memory in place.
+    /// The words are little endian, so the bytes are in the order of the
+    /// offsets.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.words.as_ptr() as *mut u8
+    }
+}
+
+impl fmt::Debug for SharedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMemory").field("size", &self.size()).finish_non_exhaustive()
+    }
+}
+
+impl LinearMemory for SharedMemory {
+    fn size(&self) -> usize {
+        self.words.len() * 8
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+        if offset.checked_add(buf.len()).is_none_or(|end| end > self.size()) {
+            return Err(MemoryError::OutOfBounds(offset));
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let (index, shift) = ((offset + done) / 8, (offset + done) % 8);
+            let n = (8 - shift).min(buf.len() - done);
+            let word = self.words[index].load(Ordering::Acquire).to_le_bytes();
+            buf[done..done + n].copy_from_slice(&word[shift..shift + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), MemoryError> {
+        if offset.checked_add(data.len()).is_none_or(|end| end > self.size()) {
+            return Err(MemoryError::OutOfBounds(offset));
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let (index, shift) = ((offset + done) / 8, (offset + done) % 8);
+            let n = (8 - shift).min(data.len() - done);
+            let bytes = &data[done..done + n];
+            if n == 8 {
+                self.words[index].store(u64::from_le_bytes(bytes.try_into().unwrap()), Ordering::Release);
+            } else {
+                // the other bytes of the word may be another thread's
+                let _ = self.words[index].fetch_update(Ordering::AcqRel, Ordering::Acquire, |word| {
+                    let mut word = word.to_le_bytes();
+                    word[shift..shift + n].copy_from_slice(bytes);
+                    Some(u64::from_le_bytes(word))
+                });
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn compare_exchange(&mut self, offset: usize, len: usize, current: u64, new: u64) -> Result<bool, MemoryError> {
+        let (index, shift) = (offset / 8, offset % 8 * 8);
+        if shift / 8 + len > 8 || index >= self.words.len() {
+            return Err(MemoryError::OutOfBounds(offset));
+        }
+        let mask = (u64::MAX >> (64 - len * 8)) << shift;
+        let exchanged = self.words[index].fetch_update(Ordering::AcqRel, Ordering::Acquire, |word| {
+            (word & mask == (current << shift) & mask).then_some(word & !mask | (new << shift) & mask)
+        });
+        Ok(exchanged.is_ok())
+    }
+}
+
+/// The pages of a linear memory at one point in time, zero pages kept as
+/// `None`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    size: usize,
+    pages: Vec<Option<Box<[u8; Page::SIZE]>>>,
+}
+
+impl Snapshot {
+    /// The pages of a freshly created memory of `size` bytes
+    pub fn zeroed(size: usize) -> Self {
+        Self {
+            size,
+            pages: vec![None; size / Page::SIZE],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Contents of the page at `index`, `None` past the end of the memory
+    fn page(&self, index: usize) -> Option<&[u8; Page::SIZE]> {
+        const ZERO: [u8; Page::SIZE] = [0; Page::SIZE];
+        Some(self.pages.get(index)?.as_deref().unwrap_or(&ZERO))
+    }
+}
+
+/// The pages of a linear memory that differ from a snapshot, with their
+/// contents
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryDelta {
+    size: usize,
+    pages: Vec<(usize, Vec<u8>)>,
+}
+
+impl MemoryDelta {
+    /// Size of the memory the pages were captured from
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Bytes of page contents held
+    pub fn len(&self) -> usize {
+        self.pages.iter().map(|(_, data)| data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+}
+
+impl LinearMemory for Vec<u8> {
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let src = self
+            .get(offset..offset + buf.len())
+            .ok_or(MemoryError::OutOfBounds(offset))?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), MemoryError> {
+        self.get_mut(offset..offset + data.len())
+            .ok_or(MemoryError::OutOfBounds(offset))?
+            .copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemoryError {
+    /// The guest address is not covered by any region
+    #[error("guest address {0:#x} is not mapped")]
+    Unmapped(u64),
+    /// The new region intersects an existing one
+    #[error("region at {0:#x} overlaps an existing one")]
+    Overlap(u64),
+    /// Linear memory has no room left for the requested size
+    #[error("linear memory has no room for {0} bytes")]
+    OutOfMemory(usize),
+    /// A shared mapping was requested on a map without a shared arena
+    #[error("shared mapping on a map without a shared arena")]
+    NotShared,
+    /// The linear memory offset lies past the end of the memory
+    #[error("linear memory offset {0:#x} out of bounds")]
+    OutOfBounds(usize),
+}
+
+/// Page permission bits, one byte per linear memory page in the permission table
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+pub struct Perm(pub u8);
+
+impl Perm {
+    pub const NONE: Perm = Perm(0);
+    pub const READ: Perm = Perm(1);
+    pub const WRITE: Perm = Perm(2);
+    pub const EXEC: Perm = Perm(4);
+    pub const RW: Perm = Perm(3);
+    pub const RX: Perm = Perm(5);
+    pub const RWX: Perm = Perm(7);
+
+    /// From the `p_flags` of a program header, where X = 1, W = 2 and R = 4
+    pub fn from_elf_flags(flags: u32) -> Self {
+        let mut perm = Perm::NONE;
+        if flags & 4 != 0 {
+            perm = perm | Perm::READ;
+        }
+        if flags & 2 != 0 {
+            perm = perm | Perm::WRITE;
+        }
+        if flags & 1 != 0 {
+            perm = perm | Perm::EXEC;
+        }
+        perm
+    }
+
+    pub fn contains(self, other: Perm) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Perm {
+    type Output = Perm;
+
+    fn bitor(self, rhs: Perm) -> Perm {
+        Perm(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Debug for Perm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |perm, c| if self.contains(perm) { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(Perm::READ, 'r'),
+            flag(Perm::WRITE, 'w'),
+            flag(Perm::EXEC, 'x')
+        )
+    }
+}
+
+/// Kind of guest memory access
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Access {
+    Read = 0,
+    Write = 1,
+    Exec = 2,
+}
+
+impl Access {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Access::Read),
+            1 => Some(Access::Write),
+            2 => Some(Access::Exec),
+            _ => None,
+        }
+    }
+
+    pub fn required_perm(self) -> Perm {
+        match self {
+            Access::Read => Perm::READ,
+            Access::Write => Perm::WRITE,
+            Access::Exec => Perm::EXEC,
+        }
+    }
+}
+
+/// A memory access the guest was not allowed to perform
+#[derive(Copy, Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum GuestFault {
+    /// Access to a hole between regions
+    #[error("{access:?} of unmapped address {vaddr:#x}")]
+    Unmapped { vaddr: u64, access: Access },
+    /// Access to a mapped page lacking the permission, e.g. a W^X violation
+    #[error("{access:?} of {vaddr:#x} denied by page permission {perm:?}")]
+    Protection { vaddr: u64, access: Access, perm: Perm },
+    /// The guest page table has no valid mapping allowing the access
+    #[error("page fault on {access:?} of {vaddr:#x}")]
+    PageFault { vaddr: u64, access: Access },
+    /// An atomic access not aligned to its width
+    #[error("misaligned {access:?} of {vaddr:#x}")]
+    Misaligned { vaddr: u64, access: Access },
+}
+
+/// Device model behind an MMIO region, offsets are relative to the region
+pub trait MmioDevice: Send {
+    fn read(&mut self, offset: u64, width: u8) -> u64;
+    fn write(&mut self, offset: u64, width: u8, value: u64);
+}
+
+/// A range of guest addresses whose accesses go to a host device
+#[derive(Clone)]
+pub struct MmioRegion {
+    pub vaddr: Range<u64>,
+    pub device: Arc<Mutex<dyn MmioDevice>>,
+}
+
+impl fmt::Debug for MmioRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MmioRegion({:#x}..{:#x})", self.vaddr.start, self.vaddr.end)
+    }
+}
+
+/// A contiguous range of guest addresses backed by linear memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub vaddr: Range<u64>,
+    pub offset: usize,
+}
+
+#[derive(Debug)]
+struct ArenaState {
+    size: usize,
+    next_offset: usize,
+    /// Shared regions by key, as (offset, length)
+    named: HashMap<u64, (usize, usize)>,
+}
+
+/// Allocator for one linear memory shared by several address maps, e.g. two
+/// guests emulating processes with a common shared mapping. Private regions of
+/// every map get disjoint offsets, a shared region gets the same offset in
+/// every map that maps its key.
+#[derive(Clone, Debug)]
+pub struct SharedArena {
+    state: Arc<Mutex<ArenaState>>,
+}
+
+impl SharedArena {
+    pub fn new(size: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ArenaState {
+                size,
+                next_offset: Page::SIZE,
+                named: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    fn alloc(&self, len: usize) -> Result<usize, MemoryError> {
+        self.state.lock().unwrap().alloc(len)
+    }
+
+    /// The offset of the region `key`, allocated by the first map asking for
+    /// it. The lookup and the allocation happen under one lock, so two maps
+    /// racing for the same key get the same offset.
+    fn alloc_named(&self, key: u64, len: usize) -> Result<usize, MemoryError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(&(offset, old_len)) = state.named.get(&key) {
+            return if len <= old_len {
+                Ok(offset)
+            } else {
+                Err(MemoryError::OutOfMemory(len))
+            };
+        }
+        let offset = state.alloc(len)?;
+        state.named.insert(key, (offset, len));
+        Ok(offset)
+    }
+}
+
+impl ArenaState {
+    fn alloc(&mut self, len: usize) -> Result<usize, MemoryError> {
+        let offset = self.next_offset;
+        if offset + len > self.size {
+            return Err(MemoryError::OutOfMemory(len));
+        }
+        self.next_offset += len;
+        Ok(offset)
+    }
+}
+
+/// Mapping from guest virtual addresses to offsets in the WASM linear memory
+#[derive(Clone, Debug)]
+pub struct AddressMap {
+    regions: Vec<Region>,
+    /// Permission of every linear memory page, mirrored into the guest memory
+    page_perms: Vec<Perm>,
+    size: usize,
+    next_offset: usize,
+    perm_table_offset: usize,
+    arena: Option<SharedArena>,
+    mmio: Vec<MmioRegion>,
+}
+
+impl AddressMap {
+    pub const DEFAULT_SIZE: usize = 192 << 20;
+
+    pub fn new(size: usize) -> Self {
+        let pages = size / Page::SIZE;
+        let mut map = Self {
+            regions: Vec::new(),
+            page_perms: vec![Perm::NONE; pages],
+            size,
+            // offset 0 stays unmapped so that a null offset never aliases guest data
+            next_offset: Page::SIZE,
+            perm_table_offset: 0,
+            arena: None,
+            mmio: Vec::new(),
+        };
+        map.perm_table_offset = size - map.perm_table_len();
+        map
+    }
+
+    /// Address map allocating from a linear memory shared with other maps
+    pub fn shared(arena: &SharedArena) -> Result<Self, MemoryError> {
+        let mut map = Self::new(arena.size());
+        map.perm_table_offset = arena.alloc(map.perm_table_len())?;
+        map.arena = Some(arena.clone());
+        Ok(map)
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.arena.is_some()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Where the permission table lives inside linear memory, at its very end
+    pub fn perm_table_offset(&self) -> usize {
+        self.perm_table_offset
+    }
+
+    fn perm_table_len(&self) -> usize {
+        (self.page_perms.len() + Page::SIZE - 1) & !(Page::SIZE - 1)
+    }
+
+    /// Map the page aligned guest range and return its linear memory offset
+    pub fn map(&mut self, vaddr: Range<u64>, perm: Perm) -> Result<usize, MemoryError> {
+        self.map_with(vaddr, perm, None)
+    }
+
+    /// Map the guest range onto the region of the shared arena named by `key`,
+    /// so every map sharing the arena sees the same bytes there
+    pub fn map_shared(
+        &mut self,
+        vaddr: Range<u64>,
+        perm: Perm,
+        key: u64,
+    ) -> Result<usize, MemoryError> {
+        self.map_with(vaddr, perm, Some(key))
+    }
+
+    fn map_with(
+        &mut self,
+        vaddr: Range<u64>,
+        perm: Perm,
+        key: Option<u64>,
+    ) -> Result<usize, MemoryError> {
+        let mask = Page::SIZE as u64 - 1;
+        let vaddr = (vaddr.start & !mask)..((vaddr.end + mask) & !mask);
+        self.check_overlap(&vaddr)?;
+        let len = (vaddr.end - vaddr.start) as usize;
+        let offset = match (&self.arena, key) {
+            (Some(arena), Some(key)) => arena.alloc_named(key, len)?,
+            (Some(arena), None) => arena.alloc(len)?,
+            (None, Some(_)) => return Err(MemoryError::NotShared),
+            (None, None) => {
+                let offset = self.next_offset;
+                if offset + len > self.perm_table_offset {
+                    return Err(MemoryError::OutOfMemory(len));
+                }
+                self.next_offset += len;
+                offset
+            }
+        };
+        let index = self
+            .regions
+            .partition_point(|r| r.vaddr.start < vaddr.start);
+        self.regions.insert(index, Region { vaddr, offset });
+        self.page_perms[offset / Page::SIZE..(offset + len) / Page::SIZE].fill(perm);
+        Ok(offset)
+    }
+
+    fn check_overlap(&self, vaddr: &Range<u64>) -> Result<(), MemoryError> {
+        let ranges = self.regions.iter().map(|r| &r.vaddr);
+        let mut ranges = ranges.chain(self.mmio.iter().map(|r| &r.vaddr));
+        match ranges.find(|r| r.start < vaddr.end && vaddr.start < r.end) {
+            Some(range) => Err(MemoryError::Overlap(range.start.max(vaddr.start))),
+            None => Ok(()),
+        }
+    }
+
+    /// Route loads and stores of the guest range to the device
+    pub fn map_mmio(
+        &mut self,
+        vaddr: Range<u64>,
+        device: Arc<Mutex<dyn MmioDevice>>,
+    ) -> Result<(), MemoryError> {
+        self.check_overlap(&vaddr)?;
+        self.mmio.push(MmioRegion { vaddr, device });
+        Ok(())
+    }
+
+    pub fn mmio_regions(&self) -> &[MmioRegion] {
+        &self.mmio
+    }
+
+    pub fn mmio_region(&self, vaddr: u64) -> Option<&MmioRegion> {
+        self.mmio.iter().find(|r| r.vaddr.contains(&vaddr))
+    }
+
+    /// Perform a device load, as the `mmio_read` import does
+    pub fn mmio_read(&self, vaddr: u64, width: u8) -> Result<u64, MemoryError> {
+        let region = self.mmio_region(vaddr).ok_or(MemoryError::Unmapped(vaddr))?;
+        let value = region
+            .device
+            .lock()
+            .unwrap()
+            .read(vaddr - region.vaddr.start, width);
+        Ok(match width {
+            8 => value,
+            width => value & ((1 << (width as u32 * 8)) - 1),
+        })
+    }
+
+    /// Perform a device store, as the `mmio_write` import does
+    pub fn mmio_write(&self, vaddr: u64, width: u8, value: u64) -> Result<(), MemoryError> {
+        let region = self.mmio_region(vaddr).ok_or(MemoryError::Unmapped(vaddr))?;
+        region
+            .device
+            .lock()
+            .unwrap()
+            .write(vaddr - region.vaddr.start, width, value);
+        Ok(())
+    }
+
+    pub fn region(&self, vaddr: u64) -> Option<&Region> {
+        let index = self.regions.partition_point(|r| r.vaddr.end <= vaddr);
+        self.regions
+            .get(index)
+            .filter(|r| r.vaddr.contains(&vaddr))
+    }
+
+    pub fn vaddr_to_offset(&self, vaddr: u64) -> Option<usize> {
+        self.region(vaddr)
+            .map(|r| r.offset + (vaddr - r.vaddr.start) as usize)
+    }
+
+    pub fn permission(&self, vaddr: u64) -> Option<Perm> {
+        self.vaddr_to_offset(vaddr)
+            .map(|offset| self.page_perms[offset / Page::SIZE])
+    }
+
+    /// Change the permission of every mapped page in the range
+    pub fn protect(&mut self, vaddr: Range<u64>, perm: Perm) -> Result<(), MemoryError> {
+        let mut page = vaddr.start & !(Page::SIZE as u64 - 1);
+        while page < vaddr.end {
+            let offset = self
+                .vaddr_to_offset(page)
+                .ok_or(MemoryError::Unmapped(page))?;
+            self.page_perms[offset / Page::SIZE] = perm;
+            page += Page::SIZE as u64;
+        }
+        Ok(())
+    }
+
+    /// Check an access the way the strict memory helpers do
+    pub fn check(&self, vaddr: u64, access: Access) -> Result<usize, GuestFault> {
+        let offset = self
+            .vaddr_to_offset(vaddr)
+            .ok_or(GuestFault::Unmapped { vaddr, access })?;
+        let perm = self.page_perms[offset / Page::SIZE];
+        if perm.contains(access.required_perm()) {
+            Ok(offset)
+        } else {
+            Err(GuestFault::Protection {
+                vaddr,
+                access,
+                perm,
+            })
+        }
+    }
+
+    /// Content of the permission table to be copied to `perm_table_offset`
+    pub fn perm_table(&self) -> Vec<u8> {
+        let mut table: Vec<u8> = self.page_perms.iter().map(|p| p.0).collect();
+        table.resize(self.perm_table_len(), 0);
+        table
+    }
+}
+
+impl Default for AddressMap {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_and_translate() {
+        let mut map = AddressMap::new(1 << 20);
+        let text = map.map(0x10000..0x10800, Perm::RX).unwrap();
+        let data = map.map(0x20000..0x22000, Perm::RW).unwrap();
+        assert_eq!(map.vaddr_to_offset(0x10004), Some(text + 4));
+        assert_eq!(map.vaddr_to_offset(0x21000), Some(data + 0x1000));
+        assert_eq!(map.vaddr_to_offset(0x18000), None);
+        assert!(matches!(
+            map.map(0x21000..0x23000, Perm::RW),
+            Err(MemoryError::Overlap(0x21000))
+        ));
+    }
+
+    #[test]
+    fn test_check_permissions() {
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        assert!(map.check(0x10000, Access::Exec).is_ok());
+        assert_eq!(
+            map.check(0x10010, Access::Write),
+            Err(GuestFault::Protection {
+                vaddr: 0x10010,
+                access: Access::Write,
+                perm: Perm::RX
+            })
+        );
+        assert_eq!(
+            map.check(0x30000, Access::Read),
+            Err(GuestFault::Unmapped {
+                vaddr: 0x30000,
+                access: Access::Read
+            })
+        );
+        map.protect(0x10000..0x11000, Perm::RW).unwrap();
+        assert!(map.check(0x10000, Access::Exec).is_err());
+        assert!(map.check(0x10000, Access::Write).is_ok());
+    }
+
+    struct Uart {
+        output: Vec<u8>,
+    }
+
+    impl MmioDevice for Uart {
+        fn read(&mut self, offset: u64, _width: u8) -> u64 {
+            // line status register reports the transmitter as always ready
+            if offset == 5 {
+                0x60
+            } else {
+                0xffff_ffff_ffff_ffff
+            }
+        }
+
+        fn write(&mut self, offset: u64, _width: u8, value: u64) {
+            if offset == 0 {
+                self.output.push(value as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mmio_routing() {
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RW).unwrap();
+        let uart = Arc::new(Mutex::new(Uart { output: Vec::new() }));
+        map.map_mmio(0x1000_0000..0x1000_0100, uart.clone()).unwrap();
+        assert!(matches!(
+            map.map(0x1000_0000..0x1000_1000, Perm::RW),
+            Err(MemoryError::Overlap(0x1000_0000))
+        ));
+        assert_eq!(map.vaddr_to_offset(0x1000_0000), None);
+        assert_eq!(map.mmio_read(0x1000_0005, 1).unwrap(), 0x60);
+        assert_eq!(map.mmio_read(0x1000_0010, 2).unwrap(), 0xffff);
+        map.mmio_write(0x1000_0000, 1, b'A' as u64).unwrap();
+        assert_eq!(uart.lock().unwrap().output, b"A");
+        assert!(map.mmio_read(0x2000_0000, 1).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let mut memory = vec![0u8; 16 * Page::SIZE];
+        let snapshot = memory.snapshot();
+        assert!(memory.diff(&snapshot).is_empty());
+        memory.write(Page::SIZE + 5, &[1]).unwrap();
+        memory.write(2 * Page::SIZE, &[2]).unwrap();
+        memory.write(9 * Page::SIZE - 1, &[3]).unwrap();
+        assert_eq!(
+            memory.diff(&snapshot),
+            vec![Page::SIZE..3 * Page::SIZE, 8 * Page::SIZE..9 * Page::SIZE]
+        );
+        // writing the old content back makes the page clean again
+        memory.write(9 * Page::SIZE - 1, &[0]).unwrap();
+        assert_eq!(memory.diff(&snapshot), vec![Page::SIZE..3 * Page::SIZE]);
+        memory.resize(17 * Page::SIZE, 0);
+        assert_eq!(memory.diff(&snapshot).last(), Some(&(16 * Page::SIZE..17 * Page::SIZE)));
+        // words with the FNV-1a hash of a zero page are still a change
+        let (basis, prime) = (0xcbf2_9ce4_8422_2325u64, 0x100_0000_01b3u64);
+        let collision = basis.wrapping_mul(prime) ^ (basis ^ 1).wrapping_mul(prime);
+        memory.write(4 * Page::SIZE, &1u64.to_le_bytes()).unwrap();
+        memory.write(4 * Page::SIZE + 8, &collision.to_le_bytes()).unwrap();
+        assert_eq!(memory.diff(&Snapshot::zeroed(16 * Page::SIZE))[1], 4 * Page::SIZE..5 * Page::SIZE);
+    }
+
+    #[test]
+    fn test_shared_arena() {
+        let arena = SharedArena::new(1 << 20);
+        let mut a = AddressMap::shared(&arena).unwrap();
+        let mut b = AddressMap::shared(&arena).unwrap();
+        assert_ne!(a.perm_table_offset(), b.perm_table_offset());
+        let private_a = a.map(0x10000..0x11000, Perm::RX).unwrap();
+        let private_b = b.map(0x10000..0x11000, Perm::RX).unwrap();
+        assert_ne!(private_a, private_b);
+        let shared_a = a.map_shared(0x40000..0x42000, Perm::RW, 7).unwrap();
+        let shared_b = b.map_shared(0x80000..0x82000, Perm::READ, 7).unwrap();
+        assert_eq!(shared_a, shared_b);
+        assert_eq!(a.vaddr_to_offset(0x41000), b.vaddr_to_offset(0x81000));
+        // maps racing for a new key all get the one region
+        let offsets: Vec<_> = (0..8)
+            .map(|_| {
+                let arena = arena.clone();
+                std::thread::spawn(move || arena.alloc_named(8, Page::SIZE).unwrap())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert!(offsets.iter().all(|&offset| offset == offsets[0]));
+        assert!(matches!(
+            AddressMap::new(1 << 20).map_shared(0x0..0x1000, Perm::RW, 7),
+            Err(MemoryError::NotShared)
+        ));
+    }
+
+    #[test]
+    fn test_shared_memory() {
+        let mut memory = SharedMemory::new(1 << 12);
+        let mut other = memory.clone();
+        // a write across words, seen through the clone
+        memory.write(6, &[1, 2, 3, 4]).unwrap();
+        let mut bytes = [0; 6];
+        other.read(5, &mut bytes).unwrap();
+        assert_eq!(bytes, [0, 1, 2, 3, 4, 0]);
+        // only the low bytes count, and the rest of the word stays
+        assert!(!other.compare_exchange(8, 4, 0x0404, 9).unwrap());
+        assert!(other.compare_exchange(8, 4, 0xffff_ffff_0000_0403, 9).unwrap());
+        assert_eq!(memory.read_u64(8).unwrap(), 9);
+        assert_eq!(memory.read_u64(0).unwrap(), 0x0201 << 48);
+        let copy = memory.copy();
+        memory.write_u64(0, 0).unwrap();
+        assert_eq!(copy.read_u64(0).unwrap(), 0x0201 << 48);
+        assert!(matches!(memory.write(4094, &[0; 4]), Err(MemoryError::OutOfBounds(4094))));
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt;
+use std::sync::Mutex;
+
+/// The bytes of emitted WAT. Text is copied in as is and numbers are
+/// rendered by hand, which spares the formatting machinery of `format!` on
+/// the hot path of emission.
+#[derive(Debug, Default)]
+pub struct EmitBuffer {
+    bytes: Vec<u8>,
+}
+
+impl EmitBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            bytes: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn push_str(&mut self, text: &str) {
+        self.bytes.extend_from_slice(text.as_bytes());
+    }
+
+    pub fn push_dec(&mut self, value: i64) {
+        if value < 0 {
+            self.bytes.push(b'-');
+        }
+        let mut digits = [0; 20];
+        let mut value = value.unsigned_abs();
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.bytes.extend_from_slice(&digits[start..]);
+    }
+
+    /// Push `value` the way `{:#x}` formats it
+    pub fn push_hex(&mut self, value: u64) {
+        self.bytes.extend_from_slice(b"0x");
+        let nibbles = (64 - value.leading_zeros()).div_ceil(4).max(1);
+        for nibble in (0..nibbles).rev() {
+            self.bytes.push(b"0123456789abcdef"[(value >> (nibble * 4)) as usize & 0xf]);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: only whole strs and ASCII digits are ever pushed
+        unsafe { std::str::from_utf8_unchecked(&self.bytes) }
+    }
+}
+
+impl fmt::Write for EmitBuffer {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.push_str(text);
+        Ok(())
+    }
+}
+
+/// One value filling a hole of a template
+#[derive(Debug, Clone, Copy)]
+pub enum Arg<'a> {
+    Str(&'a str),
+    Dec(i64),
+    Hex(u64),
+}
+
+/// A common shape of emitted code, pre-split around its holes so that
+/// rendering it is copying the pieces and the values in between
+#[derive(Debug, Clone, Copy)]
+pub struct Template(pub &'static [&'static str]);
+
+/// `rd = rs1 op rs2`, with the op, rd, rs1 and rs2 as holes. x0 has no
+/// global: reads of it are `(i64.const 0)` and writes are dropped.
+pub const ALU_REG: Template = Template(&["(global.set $x", " (i64.", " (global.get $x", ") (global.get $x", ")))\n"]);
+/// `rd = rs1 op imm`
+pub const ALU_IMM: Template = Template(&["(global.set $x", " (i64.", " (global.get $x", ") (i64.const ", ")))\n"]);
+/// `rd = load(rs1 + imm)`, with the suffix of the `$load_*` helper
+pub const LOAD: Template = Template(&["(global.set $x", " (call $load_", " (i64.add (global.get $x", ") (i64.const ", "))))\n"]);
+/// `store(rs1 + imm, rs2)`, with the suffix of the `$store_*` helper
+pub const STORE: Template = Template(&["(call $store_", " (i64.add (global.get $x", ") (i64.const ", ")) (global.get $x", "))\n"]);
+/// The exit of a block to a fixed pc, with the slot and the pc
+pub const BLOCK_EXIT: Template = Template(&["(global.set $next_slot (i32.const ", ")) (i64.const ", ")"]);
+
+impl Template {
+    pub fn holes(self) -> usize {
+        self.0.len() - 1
+    }
+
+    pub fn render(self, out: &mut EmitBuffer, args: &[Arg]) {
+        assert_eq!(args.len(), self.holes(), "wrong number of template arguments");
+        out.push_str(self.0[0]);
+        for (arg, part) in args.iter().zip(&self.0[1..]) {
+            match *arg {
+                Arg::Str(text) => out.push_str(text),
+                Arg::Dec(value) => out.push_dec(value),
+                Arg::Hex(value) => out.push_hex(value),
+            }
+            out.push_str(part);
+        }
+    }
+}
+
+/// Buffers given back after use, so emitting a block reuses the memory an
+/// earlier one grew instead of reallocating from scratch
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    free: Mutex<Vec<EmitBuffer>>,
+}
+
+impl BufferPool {
+    pub const fn new() -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// An empty buffer, recycled if there is one
+    pub fn take(&self) -> EmitBuffer {
+        self.free.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    pub fn give(&self, mut buffer: EmitBuffer) {
+        buffer.clear();
+        self.free.lock().unwrap().push(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates() {
+        let mut out = EmitBuffer::new();
+        for value in [0, 7, -1, i64::MIN, i64::MAX] {
+            out.clear();
+            out.push_dec(value);
+            assert_eq!(out.as_str(), value.to_string());
+        }
+        for value in [0, 0xf, 0x10004, u64::MAX] {
+            out.clear();
+            out.push_hex(value);
+            assert_eq!(out.as_str(), format!("{:#x}", value));
+        }
+
+        out.clear();
+        ALU_IMM.render(&mut out, &[Arg::Dec(10), Arg::Str("add"), Arg::Dec(2), Arg::Dec(-16)]);
+        assert_eq!(out.as_str(), "(global.set $x10 (i64.add (global.get $x2) (i64.const -16)))\n");
+        for template in [ALU_REG, ALU_IMM, LOAD, STORE, BLOCK_EXIT] {
+            let text = template.0.concat();
+            assert_eq!(text.matches('(').count(), text.matches(')').count());
+        }
+
+        let pool = BufferPool::new();
+        let mut buffer = pool.take();
+        buffer.push_str("(nop)");
+        let capacity = buffer.bytes.capacity();
+        pool.give(buffer);
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.bytes.capacity(), capacity);
+    }
+}
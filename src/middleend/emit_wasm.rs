@@ -0,0 +1,870 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::codegen::regmap::RegMap;
+use crate::frontend::page::Page;
+use crate::middleend::address_map::{Access, AddressMap};
+use crate::middleend::emit_buf::{Arg, BufferPool, EmitBuffer, BLOCK_EXIT};
+use crate::runtime::config::Config;
+use crate::runtime::csr::CsrOp;
+use crate::runtime::dispatch::{BlockSlots, InlineCaches};
+use crate::runtime::pgo::BlockProfile;
+use crate::tools::perf::{Profiler, MIDDLEEND_EMIT, MIDDLEEND_EMIT_BLOCKS};
+
+const WASM_PAGE_SIZE: usize = 1 << 16;
+
+/// Host function the strict memory helpers report faults to, `(kind, vaddr)`
+pub const GUEST_FAULT_IMPORT: &str =
+    "(import \"env\" \"guest_fault\" (func $guest_fault (param i32 i64)))\n";
+
+/// Host page table walker used in MMU mode, `(vaddr, access) -> paddr`
+pub const MMU_TRANSLATE_IMPORT: &str =
+    "(import \"env\" \"mmu_translate\" (func $mmu_translate (param i64 i32) (result i64)))\n";
+
+/// (helper suffix, width in bytes, wasm load instruction)
+pub const LOADS: [(&str, u64, &str); 7] = [
+    ("i8", 1, "i64.load8_s"),
+    ("u8", 1, "i64.load8_u"),
+    ("i16", 2, "i64.load16_s"),
+    ("u16", 2, "i64.load16_u"),
+    ("i32", 4, "i64.load32_s"),
+    ("u32", 4, "i64.load32_u"),
+    ("64", 8, "i64.load"),
+];
+/// (helper suffix, width in bytes, wasm store instruction)
+pub const STORES: [(&str, u64, &str); 4] = [
+    ("8", 1, "i64.store8"),
+    ("16", 2, "i64.store16"),
+    ("32", 4, "i64.store32"),
+    ("64", 8, "i64.store"),
+];
+
+/// Export name of the global holding integer register `x{index}`, `pc`,
+/// floating point register `f{index}` or `fcsr`
+pub fn x_global(index: usize) -> String {
+    format!("x{}", index)
+}
+
+pub fn f_global(index: usize) -> String {
+    format!("f{}", index)
+}
+
+pub const PC_GLOBAL: &str = "pc";
+pub const FCSR_GLOBAL: &str = "fcsr";
+
+/// The rounding mode in `frm`, for floating point instructions with the
+/// dynamic rounding mode
+pub const FRM_VALUE: &str = "(i32.and (i32.shr_u (global.get $fcsr) (i32.const 5)) (i32.const 7))";
+
+/// Raise the `fflags` exception flags an i32 `flags` computes, as
+/// `CsrState::accrue` does
+pub fn accrue_fflags(flags: &str) -> String {
+    format!("(global.set $fcsr (i32.or (global.get $fcsr) (i32.and {} (i32.const 0x1f))))", flags)
+}
+
+/// Host functions running the CSR instructions, `(csr, source) -> old` for
+/// csrrw and `(csr, source, write) -> old` for csrrs and csrrc, where
+/// `write` is 0 for a source of x0 or a zero immediate
+pub const CSR_IMPORTS: &str = "(import \"env\" \"csr_read_write\" (func $csr_read_write (param i32 i64) (result i64)))\n(import \"env\" \"csr_read_set\" (func $csr_read_set (param i32 i64 i32) (result i64)))\n(import \"env\" \"csr_read_clear\" (func $csr_read_clear (param i32 i64 i32) (result i64)))\n";
+
+/// The call running a CSR instruction on `csr` with the i64 `source`,
+/// which gives the old value for rd
+pub fn csr_call(op: CsrOp, csr: u32, source: &str, write: bool) -> String {
+    match op {
+        CsrOp::Write => format!("(call $csr_read_write (i32.const {:#x}) {})", csr, source),
+        CsrOp::Set => format!("(call $csr_read_set (i32.const {:#x}) {} (i32.const {}))", csr, source, write as u8),
+        CsrOp::Clear => format!("(call $csr_read_clear (i32.const {:#x}) {} (i32.const {}))", csr, source, write as u8),
+    }
+}
+
+/// Emit the mutable globals translated code keeps the guest registers in,
+/// exported so the embedder can load them before a run and read them back
+/// after it. x0 is always zero and has no global.
+pub fn emit_register_globals(out: &mut String) {
+    for (name, ty) in register_globals() {
+        out.push_str(&format!(
+            "(global ${0} (export \"{0}\") (mut {1}) ({1}.const 0))\n",
+            name, ty
+        ));
+    }
+}
+
+/// Emit imports of the register globals instead, for the modules of single
+/// blocks, which all work on the registers of one guest
+pub fn emit_register_imports(out: &mut String) {
+    for (name, ty) in register_globals() {
+        out.push_str(&format!(
+            "(import \"env\" \"{0}\" (global ${0} (mut {1})))\n",
+            name, ty
+        ));
+    }
+}
+
+/// (name, wasm type) of every register global
+fn register_globals() -> Vec<(String, &'static str)> {
+    let mut globals = vec![(PC_GLOBAL.to_string(), "i64")];
+    globals.extend((1..32).map(x_global).chain((0..32).map(f_global)).map(|name| (name, "i64")));
+    globals.push((FCSR_GLOBAL.to_string(), "i32"));
+    globals
+}
+
+/// Export of a block module running the block, which returns the pc
+/// execution continues at
+pub const BLOCK_EXPORT: &str = "run";
+
+/// The funcref table compiled blocks are linked through, and the global a
+/// block exit leaves the slot of its target in, -1 when it is not known
+pub const BLOCK_TABLE: &str = "blocks";
+pub const NEXT_SLOT_GLOBAL: &str = "next_slot";
+/// The pc of the block that last took a computed jump, which the
+/// dispatcher records the target for
+pub const EXIT_SITE_GLOBAL: &str = "exit_site";
+/// Export of the chain module, which runs linked blocks from a pc
+pub const CHAIN_EXPORT: &str = "chain";
+
+const BLOCK_LINK_IMPORTS: &str = "(import \"env\" \"blocks\" (table $blocks 0 funcref))\n(import \"env\" \"next_slot\" (global $next_slot (mut i32)))\n(import \"env\" \"exit_site\" (global $exit_site (mut i64)))\n";
+
+/// The exit of a block to the fixed pc `target`, which goes straight to
+/// the target block once it is linked
+pub fn block_exit(target: u64, slots: &mut BlockSlots) -> String {
+    let mut out = EmitBuffer::with_capacity(64);
+    BLOCK_EXIT.render(&mut out, &[Arg::Dec(slots.slot(target) as i64), Arg::Hex(target)]);
+    out.as_str().to_string()
+}
+
+/// The exit of the block at `site` to the pc `target` computes, e.g. of
+/// jalr. The targets in the inline cache of the site are compared first and
+/// exited to directly, any other goes through the dispatcher.
+pub fn indirect_exit(site: u64, target: &str, caches: &InlineCaches, slots: &mut BlockSlots) -> String {
+    let mut exit = format!(
+        "(global.set $exit_site (i64.const {:#x})) (global.set $next_slot (i32.const -1)) (local.get $target)",
+        site
+    );
+    for &cached in caches.targets(site).iter().rev() {
+        exit = format!(
+            "(if (result i64) (i64.eq (local.get $target) (i64.const {:#x})) (then {}) (else {}))",
+            cached,
+            block_exit(cached, slots),
+            exit
+        );
+    }
+    format!("(local.set $target {}) {}", target, exit)
+}
+
+/// Emit the module running linked blocks: it calls the block in the slot
+/// the last exit named for as long as that slot is filled, and returns the
+/// pc execution continues at when it is not.
+pub fn emit_chain_module(out: &mut String) {
+    out.push_str("(module\n");
+    out.push_str(BLOCK_LINK_IMPORTS);
+    out.push_str("(type $block (func (result i64)))\n");
+    out.push_str(&format!(
+        "(func (export \"{}\") (param $pc i64) (result i64)\n  (local $slot i32)\n  (block $exit\n    (loop $next\n      (local.set $slot (global.get $next_slot))\n      (br_if $exit (i32.ge_u (local.get $slot) (table.size $blocks)))\n      (br_if $exit (ref.is_null (table.get $blocks (local.get $slot))))\n      (local.set $pc (call_indirect $blocks (type $block) (local.get $slot)))\n      (br $next)))\n  (local.get $pc))\n",
+        CHAIN_EXPORT
+    ));
+    out.push_str(")\n");
+}
+
+/// Emit the exit of a block ending in a conditional branch on the i32
+/// `cond`, which leaves the next pc on the stack. With a profile the
+/// successor taken more often goes in the `then` arm, which the compilers
+/// lay out as the fallthrough.
+pub fn emit_branch(
+    out: &mut String,
+    cond: &str,
+    pc: u64,
+    (taken, fallthrough): (u64, u64),
+    slots: &mut BlockSlots,
+    profile: Option<&BlockProfile>,
+) {
+    let hot_fallthrough = profile.is_some_and(|p| p.edge(pc, fallthrough) > p.edge(pc, taken));
+    let (cond, then, other) = if hot_fallthrough {
+        (format!("(i32.eqz {})", cond), fallthrough, taken)
+    } else {
+        (cond.to_string(), taken, fallthrough)
+    };
+    out.push_str(&format!(
+        "(if (result i64) {} (then {}) (else {}))\n",
+        cond,
+        block_exit(then, slots),
+        block_exit(other, slots)
+    ));
+}
+
+/// Emit the conditional branch ending a block in the middle of a trace, which
+/// carries on at `on_trace`: the other successor becomes a side exit out of
+/// the trace, while the trace goes on straight into the code of the next
+/// block without leaving a pc behind. The side exit writes the registers
+/// the trace keeps in locals back to their globals.
+pub fn emit_side_exit(
+    out: &mut String,
+    cond: &str,
+    (taken, fallthrough): (u64, u64),
+    on_trace: u64,
+    regs: &RegMap,
+    slots: &mut BlockSlots,
+) {
+    debug_assert!(on_trace == taken || on_trace == fallthrough, "trace leaves the block at {:#x}", on_trace);
+    let (cond, exit) = if on_trace == fallthrough {
+        (cond.to_string(), taken)
+    } else {
+        (format!("(i32.eqz {})", cond), fallthrough)
+    };
+    out.push_str(&format!("(if {} (then {} (return {})))\n", cond, regs.sync(), block_exit(exit, slots)));
+}
+
+/// The hash identifying emitted code by its text
+pub fn content_hash(text: &str) -> [u8; 32] {
+    Sha256::digest(text.as_bytes()).into()
+}
+
+/// Emit the body of every block as a function, but each distinct body only
+/// once: padding, thunks and the like often translate to the same code.
+/// Returns the name of the function running each block, by pc.
+pub fn emit_deduplicated(out: &mut String, blocks: &[(u64, String)]) -> Vec<(u64, String)> {
+    let mut functions: HashMap<[u8; 32], String> = HashMap::new();
+    let mut names = Vec::with_capacity(blocks.len());
+    for (pc, body) in blocks {
+        let name = functions.entry(content_hash(body)).or_insert_with_key(|hash| {
+            let name: String = hash[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+            let name = format!("$block_{}", name);
+            out.push_str(&format!("(func {} (result i64)\n(local $target i64)\n{})\n", name, body));
+            name
+        });
+        names.push((*pc, name.clone()));
+    }
+    names
+}
+
+/// Emit the bodies of translated blocks, keyed by their pc, as functions in
+/// the order of the profile so hot paths sit together and blocks that never
+/// ran come last. Blocks with the same body share one function, see
+/// `emit_deduplicated`, whose names this returns.
+pub fn emit_blocks(out: &mut String, blocks: &[(u64, String)], profile: Option<&BlockProfile>) -> Vec<(u64, String)> {
+    let code: HashMap<u64, &String> = blocks.iter().map(|(pc, code)| (*pc, code)).collect();
+    let pcs: Vec<u64> = blocks.iter().map(|(pc, _)| *pc).collect();
+    let order = match profile {
+        Some(profile) => profile.layout(&pcs),
+        None => pcs,
+    };
+    let ordered: Vec<(u64, String)> = order.into_iter().map(|pc| (pc, code[&pc].clone())).collect();
+    emit_deduplicated(out, &ordered)
+}
+
+/// Buffers of `emit_parallel`, kept across calls
+static BUFFERS: BufferPool = BufferPool::new();
+
+/// Emit `blocks` in parallel, each with `emit`, and append them to `out` in
+/// their order: the text of one block does not depend on any other. The
+/// profiler gets the wall time under `middleend.emit` and the time of the
+/// blocks summed up under `middleend.emit.blocks`.
+pub fn emit_parallel<B: Sync>(
+    out: &mut String,
+    blocks: &[B],
+    emit: impl Fn(&B, &mut EmitBuffer) + Sync,
+    profiler: Option<&Profiler>,
+) {
+    let start = Instant::now();
+    let emitted: Vec<(EmitBuffer, _)> = blocks
+        .par_iter()
+        .map(|block| {
+            let start = Instant::now();
+            let mut text = BUFFERS.take();
+            emit(block, &mut text);
+            (text, start.elapsed())
+        })
+        .collect();
+    out.reserve(emitted.iter().map(|(text, _)| text.len()).sum());
+    for (text, _) in &emitted {
+        out.push_str(text.as_str());
+    }
+    let blocks_time = emitted.iter().map(|(_, elapsed)| *elapsed).sum();
+    for (text, _) in emitted {
+        BUFFERS.give(text);
+    }
+    if let Some(profiler) = profiler {
+        profiler.record(MIDDLEEND_EMIT, start.elapsed());
+        profiler.record(MIDDLEEND_EMIT_BLOCKS, blocks_time);
+    }
+}
+
+/// Emit the module of one basic block, compiled the first time its pc is
+/// reached. It imports the memory and the registers of the guest, and
+/// `body` ends in one of `block_exit`, `indirect_exit` or `emit_branch`.
+pub fn emit_block_module(out: &mut String, map: &AddressMap, config: &Config, body: &str) {
+    emit_block_imports(out, map, config, body);
+    out.push_str(&format!(
+        "(func (export \"{}\") (result i64)\n(local $target i64)\n{})\n)\n",
+        BLOCK_EXPORT, body
+    ));
+}
+
+/// The export of the block at `pc` in a module of `emit_blocks_module`
+pub fn block_export(pc: u64) -> String {
+    format!("block_{:#x}", pc)
+}
+
+/// Emit the module of many blocks compiled together, e.g. those an earlier
+/// run ran, with the code of each block, keyed by its pc, exported under
+/// `block_export`. The blocks are laid out in the order of `profile`,
+/// blocks translating to the same code sharing it, see `emit_blocks`.
+pub fn emit_blocks_module(out: &mut String, map: &AddressMap, config: &Config, blocks: &[(u64, String)], profile: Option<&BlockProfile>) {
+    let bodies: String = blocks.iter().map(|(_, body)| body.as_str()).collect();
+    emit_block_imports(out, map, config, &bodies);
+    for (pc, name) in emit_blocks(out, blocks, profile) {
+        out.push_str(&format!("(export \"{}\" (func {}))\n", block_export(pc), name));
+    }
+    out.push_str(")\n");
+}
+
+/// Emit the start of a module of block code, up to its functions: the
+/// imports and helpers the code `body` uses
+fn emit_block_imports(out: &mut String, map: &AddressMap, config: &Config, body: &str) {
+    let pages = map.size() / WASM_PAGE_SIZE;
+    out.push_str("(module\n");
+    out.push_str(&format!(
+        "(import \"env\" \"memory\" (memory {} {}{}))\n",
+        pages,
+        pages,
+        if map.is_shared() { " shared" } else { "" }
+    ));
+    out.push_str(BLOCK_LINK_IMPORTS);
+    emit_register_imports(out);
+    if config.strict_memory {
+        out.push_str(GUEST_FAULT_IMPORT);
+    }
+    if config.mmu {
+        out.push_str(MMU_TRANSLATE_IMPORT);
+    }
+    if !map.mmio_regions().is_empty() {
+        out.push_str(MMIO_IMPORTS);
+    }
+    if body.contains("(call $csr_") {
+        out.push_str(CSR_IMPORTS);
+    }
+    if body.contains("$instr_") {
+        out.push_str(COUNTER_IMPORTS);
+    }
+    if body.contains("(call $budget_exhausted)") {
+        out.push_str(BUDGET_IMPORT);
+    }
+    if body.contains("(call $osr_exit") {
+        out.push_str(OSR_IMPORTS);
+    }
+    emit_memory_helpers(out, map, config);
+    emit_atomic_helpers(out, map, config);
+    if body.contains("(call $mulh") {
+        emit_mulh_helpers(out);
+    }
+}
+
+/// The loop header the engine has optimized code ready for, 0 while there
+/// is none
+pub const OSR_GLOBAL: &str = "osr_target";
+
+/// The global naming the loop to leave, and the host function a loop calls
+/// with its header to unwind the guest, which the engine resumes in the
+/// optimized code of the loop
+pub const OSR_IMPORTS: &str =
+    "(import \"env\" \"osr_target\" (global $osr_target (mut i64)))\n(import \"env\" \"osr_exit\" (func $osr_exit (param i64)))\n";
+
+/// The code placed at the back-edge of the loop at `header`: once the
+/// optimized loop is ready the guest leaves at the header, with all of its
+/// state in the register globals, instead of finishing the loop first
+pub fn osr_check(header: u64) -> String {
+    format!(
+        "(if (i64.eq (global.get ${0}) (i64.const {1:#x})) (then (global.set ${2} (i64.const {1:#x})) (call $osr_exit (i64.const {1:#x}))))\n",
+        OSR_GLOBAL, header, PC_GLOBAL
+    )
+}
+
+/// Debug instrumentation in front of the instruction at `pc`: a comment with
+/// the pc, and the instruction count bumped so a trap shows exactly how far
+/// the guest got. Empty unless the config is instrumented.
+pub fn instruction_marker(config: &Config, pc: u64) -> String {
+    if !config.instrumented() {
+        return String::new();
+    }
+    format!(
+        ";; {:#x}\n(global.set ${1} (i64.add (global.get ${1}) (i64.const 1)))\n",
+        pc, INSTR_COUNT_GLOBAL
+    )
+}
+
+/// Instructions compiled code retired so far and the count at which it
+/// hands the guest back to the engine, which checks the execution limits
+pub const INSTR_COUNT_GLOBAL: &str = "instr_count";
+pub const INSTR_BUDGET_GLOBAL: &str = "instr_budget";
+
+/// Host function called when the instruction count reaches the budget. It
+/// unwinds the guest, for the engine to check the limits and hand it the
+/// next slice.
+pub const BUDGET_IMPORT: &str = "(import \"env\" \"budget_exhausted\" (func $budget_exhausted))\n";
+
+/// Imports of the counters, for block modules, whose counts add up in the
+/// globals of the engine
+pub const COUNTER_IMPORTS: &str = "(import \"env\" \"instr_count\" (global $instr_count (mut i64)))\n(import \"env\" \"instr_budget\" (global $instr_budget (mut i64)))\n";
+
+/// The code placed at the entry of the block at `pc` and at loop headers,
+/// so a guest loop cannot spin forever without the limits being checked:
+/// once the count reached the budget the engine gets the guest at `pc`,
+/// with the registers `sync` writes back, then the `count` instructions
+/// about to run are retired. Instrumented code counts every instruction by
+/// itself and passes 0.
+pub fn budget_check(pc: u64, count: u64, sync: &str) -> String {
+    let mut check = format!(
+        "(if (i64.ge_u (global.get ${0}) (global.get ${1})) (then (global.set ${2} (i64.const {3:#x})) {4} (call $budget_exhausted)))\n",
+        INSTR_COUNT_GLOBAL, INSTR_BUDGET_GLOBAL, PC_GLOBAL, pc, sync
+    );
+    if count > 0 {
+        check.push_str(&format!("(global.set ${0} (i64.add (global.get ${0}) (i64.const {1})))\n", INSTR_COUNT_GLOBAL, count));
+    }
+    check
+}
+
+/// Emit `$mulhu`, `$mulh` and `$mulhsu`, the high 64 bits of the 128 bit
+/// products of the M extension, from the 32 bit halves of the operands
+pub fn emit_mulh_helpers(out: &mut String) {
+    out.push_str(concat!(
+        "(func $mulhu (param $a i64) (param $b i64) (result i64)\n",
+        "  (local $a_hi i64) (local $b_hi i64) (local $lo_lo i64) (local $hi_lo i64) (local $lo_hi i64)\n",
+        "  (local.set $a_hi (i64.shr_u (local.get $a) (i64.const 32)))\n",
+        "  (local.set $b_hi (i64.shr_u (local.get $b) (i64.const 32)))\n",
+        "  (local.set $a (i64.and (local.get $a) (i64.const 0xffffffff)))\n",
+        "  (local.set $b (i64.and (local.get $b) (i64.const 0xffffffff)))\n",
+        "  (local.set $lo_lo (i64.mul (local.get $a) (local.get $b)))\n",
+        "  (local.set $hi_lo (i64.mul (local.get $a_hi) (local.get $b)))\n",
+        "  (local.set $lo_hi (i64.add (i64.add (i64.shr_u (local.get $lo_lo) (i64.const 32)) (i64.and (local.get $hi_lo) (i64.const 0xffffffff))) (i64.mul (local.get $a) (local.get $b_hi))))\n",
+        "  (i64.add (i64.add (i64.mul (local.get $a_hi) (local.get $b_hi)) (i64.shr_u (local.get $hi_lo) (i64.const 32))) (i64.shr_u (local.get $lo_hi) (i64.const 32))))\n",
+        "(func $mulh (param $a i64) (param $b i64) (result i64)\n",
+        "  (i64.sub (i64.sub (call $mulhu (local.get $a) (local.get $b))\n",
+        "    (select (local.get $b) (i64.const 0) (i64.lt_s (local.get $a) (i64.const 0))))\n",
+        "    (select (local.get $a) (i64.const 0) (i64.lt_s (local.get $b) (i64.const 0)))))\n",
+        "(func $mulhsu (param $a i64) (param $b i64) (result i64)\n",
+        "  (i64.sub (call $mulhu (local.get $a) (local.get $b))\n",
+        "    (select (local.get $b) (i64.const 0) (i64.lt_s (local.get $a) (i64.const 0)))))\n",
+    ));
+}
+
+/// Emit the linear memory declaration. A shared address map imports the
+/// memory so the embedder can hand the same shared memory to every guest.
+pub fn emit_memory(out: &mut String, map: &AddressMap) {
+    let pages = map.size() / WASM_PAGE_SIZE;
+    if map.is_shared() {
+        out.push_str(&format!(
+            "(import \"env\" \"memory\" (memory {} {} shared))\n",
+            pages, pages
+        ));
+    } else {
+        out.push_str(&format!("(memory (export \"memory\") {})\n", pages));
+    }
+}
+
+/// Host callbacks of MMIO regions, `(addr, width) -> value` and `(addr, width, value)`
+pub const MMIO_IMPORTS: &str = "(import \"env\" \"mmio_read\" (func $mmio_read (param i64 i32) (result i64)))\n(import \"env\" \"mmio_write\" (func $mmio_write (param i64 i32 i64)))\n";
+
+/// Condition testing whether `$addr` lies in `start..end`
+fn range_check(start: u64, end: u64) -> String {
+    format!(
+        "(i32.and (i64.ge_u (local.get $addr) (i64.const {})) (i64.lt_u (local.get $addr) (i64.const {})))",
+        start, end
+    )
+}
+
+/// Emit `$vaddr_to_offset`, one range check per region, -1 for holes. In MMU
+/// mode the regions describe guest physical memory, so the chain becomes
+/// `$paddr_to_offset` and `$vaddr_to_offset` walks the page table first.
+pub fn emit_vaddr_to_offset(out: &mut String, map: &AddressMap, mmu: bool) {
+    if mmu {
+        out.push_str("(func $vaddr_to_offset (param $vaddr i64) (result i32)\n  (call $paddr_to_offset (call $mmu_translate (local.get $vaddr) (i32.const 0))))\n");
+    }
+    out.push_str(&format!(
+        "(func ${}_to_offset (param $addr i64) (result i32)\n",
+        if mmu { "paddr" } else { "vaddr" }
+    ));
+    for region in map.regions() {
+        out.push_str(&format!(
+            "  (if {}\n    (then (return (i32.wrap_i64 (i64.add (i64.sub (local.get $addr) (i64.const {})) (i64.const {}))))))\n",
+            range_check(region.vaddr.start, region.vaddr.end),
+            region.vaddr.start,
+            region.offset
+        ));
+    }
+    out.push_str("  (i32.const -1))\n");
+}
+
+/// Whether translated code may access guest memory in bulk, with
+/// `memory.copy` and `memory.fill`: only when every access of a range goes
+/// to linear memory unchecked, so the range need not be split up
+pub fn bulk_memory(map: &AddressMap, config: &Config) -> bool {
+    !config.strict_memory && !config.mmu && map.mmio_regions().is_empty()
+}
+
+/// Emit `$linear_range`, the linear offset of the `$len` bytes at `$addr`
+/// if they lie in a single region, and so in one piece of linear memory,
+/// -1 otherwise
+fn emit_linear_range(out: &mut String, map: &AddressMap) {
+    out.push_str("(func $linear_range (param $addr i64) (param $len i64) (result i32)\n");
+    for region in map.regions() {
+        out.push_str(&format!(
+            "  (if {}\n    (then (return (select (i32.wrap_i64 (i64.add (i64.sub (local.get $addr) (i64.const {start})) (i64.const {offset}))) (i32.const -1) (i64.le_u (local.get $len) (i64.sub (i64.const {end}) (local.get $addr)))))))\n",
+            range_check(region.vaddr.start, region.vaddr.end),
+            start = region.vaddr.start,
+            offset = region.offset,
+            end = region.vaddr.end,
+        ));
+    }
+    out.push_str("  (i32.const -1))\n");
+}
+
+/// Emit `$is_mmio`, telling whether an address belongs to a device
+fn emit_is_mmio(out: &mut String, map: &AddressMap) {
+    out.push_str("(func $is_mmio (param $addr i64) (result i32)\n");
+    for region in map.mmio_regions() {
+        out.push_str(&format!(
+            "  (if {} (then (return (i32.const 1))))\n",
+            range_check(region.vaddr.start, region.vaddr.end)
+        ));
+    }
+    out.push_str("  (i32.const 0))\n");
+}
+
+/// Emit `$translate_read/write/exec`, which consult the permission table and
+/// report a guest fault instead of returning when the access is not allowed.
+/// They take the flat, or in MMU mode physical, address of the access.
+fn emit_translate(out: &mut String, map: &AddressMap, access: Access, mmu: bool) {
+    let name = match access {
+        Access::Read => "read",
+        Access::Write => "write",
+        Access::Exec => "exec",
+    };
+    out.push_str(&format!(
+        "(func $translate_{} (param $addr i64) (result i32)\n  (local $offset i32)\n  (local.set $offset (call ${}_to_offset (local.get $addr)))\n",
+        name,
+        if mmu { "paddr" } else { "vaddr" }
+    ));
+    out.push_str(&format!(
+        "  (if (i32.eq (local.get $offset) (i32.const -1))\n    (then (call $guest_fault (i32.const {kind}) (local.get $addr)) (unreachable)))\n  (if (i32.eqz (i32.and (i32.load8_u offset={table} (i32.shr_u (local.get $offset) (i32.const {shift}))) (i32.const {perm})))\n    (then (call $guest_fault (i32.const {kind}) (local.get $addr)) (unreachable)))\n  (local.get $offset))\n",
+        kind = access as i32,
+        table = map.perm_table_offset(),
+        shift = Page::SIZE.trailing_zeros(),
+        perm = access.required_perm().0,
+    ));
+}
+
+/// Emit the `$load_*` / `$store_*` helpers used by translated memory
+/// instructions. Each resolves the guest address once into `$addr` (walking
+/// the page table in MMU mode), hands it to the device callbacks if it falls
+/// in an MMIO region, and otherwise accesses linear memory. With strict
+/// memory every access is checked against the page permission table, and a
+/// wide access checks its last byte as well so that straddling into an
+/// unmapped or protected page is caught too. Where memory may be accessed
+/// in bulk `$linear_range` resolves whole ranges for it.
+pub fn emit_memory_helpers(out: &mut String, map: &AddressMap, config: &Config) {
+    let strict = config.strict_memory;
+    let mmio = !map.mmio_regions().is_empty();
+    emit_vaddr_to_offset(out, map, config.mmu);
+    if bulk_memory(map, config) {
+        emit_linear_range(out, map);
+    }
+    if strict {
+        emit_translate(out, map, Access::Read, config.mmu);
+        emit_translate(out, map, Access::Write, config.mmu);
+        emit_translate(out, map, Access::Exec, config.mmu);
+    }
+    if mmio {
+        emit_is_mmio(out, map);
+    }
+    let prologue = |out: &mut String, access: Access| {
+        let addr = if config.mmu {
+            format!(
+                "(call $mmu_translate (local.get $vaddr) (i32.const {}))",
+                access as i32
+            )
+        } else {
+            "(local.get $vaddr)".to_string()
+        };
+        out.push_str(&format!("  (local $addr i64)\n  (local.set $addr {})\n", addr));
+    };
+    let offset = |out: &mut String, access: Access, width: u64| {
+        let name = if access == Access::Read { "read" } else { "write" };
+        if !strict {
+            let space = if config.mmu { "paddr" } else { "vaddr" };
+            return format!("(call ${}_to_offset (local.get $addr))", space);
+        }
+        if width > 1 {
+            out.push_str(&format!(
+                "  (drop (call $translate_{} (i64.add (local.get $addr) (i64.const {}))))\n",
+                name,
+                width - 1
+            ));
+        }
+        format!("(call $translate_{} (local.get $addr))", name)
+    };
+    for (suffix, width, op) in LOADS {
+        out.push_str(&format!(
+            "(func $load_{} (param $vaddr i64) (result i64)\n",
+            suffix
+        ));
+        prologue(out, Access::Read);
+        if mmio {
+            let value = format!("(call $mmio_read (local.get $addr) (i32.const {}))", width);
+            let value = match suffix {
+                "i8" => format!("(i64.extend8_s {})", value),
+                "i16" => format!("(i64.extend16_s {})", value),
+                "i32" => format!("(i64.extend32_s {})", value),
+                _ => value,
+            };
+            out.push_str(&format!(
+                "  (if (call $is_mmio (local.get $addr)) (then (return {})))\n",
+                value
+            ));
+        }
+        let offset = offset(out, Access::Read, width);
+        out.push_str(&format!("  ({} {}))\n", op, offset));
+    }
+    for (suffix, width, op) in STORES {
+        out.push_str(&format!(
+            "(func $store_{} (param $vaddr i64) (param $value i64)\n",
+            suffix
+        ));
+        prologue(out, Access::Write);
+        if mmio {
+            out.push_str(&format!(
+                "  (if (call $is_mmio (local.get $addr)) (then (return (call $mmio_write (local.get $addr) (i32.const {}) (local.get $value)))))\n",
+                width
+            ));
+        }
+        let offset = offset(out, Access::Write, width);
+        out.push_str(&format!("  ({} {} (local.get $value)))\n", op, offset));
+    }
+}
+
+/// (helper name, read-modify-write op) of the AMO instructions
+const AMOS: [(&str, &str); 5] = [
+    ("swap", "xchg"),
+    ("add", "add"),
+    ("and", "and"),
+    ("or", "or"),
+    ("xor", "xor"),
+];
+
+/// Emit `$amo_*_{32,64}` and `$cas_{32,64}`, each returning the old value.
+/// On a shared memory these are real WASM atomics so AMOs and LR/SC (as a
+/// compare-and-swap against the reserved value) stay atomic between guests;
+/// a private memory only ever has one writer and uses plain accesses. The
+/// word helpers compare the low 32 bits only and return the old word sign
+/// extended, as AMO*.W and SC.W write it to rd.
+pub fn emit_atomic_helpers(out: &mut String, map: &AddressMap, config: &Config) {
+    let shared = map.is_shared();
+    let offset = if config.mmu {
+        "(call $paddr_to_offset (call $mmu_translate (local.get $vaddr) (i32.const 1)))"
+    } else {
+        "(call $vaddr_to_offset (local.get $vaddr))"
+    };
+    for bits in [32, 64] {
+        let word = bits == 32;
+        // the atomics zero extend a word, the old value is sign extended
+        let old = |value: String| if word { format!("(i64.extend32_s {})", value) } else { value };
+        let (load, store) = if word { ("i64.load32_s", "i64.store32") } else { ("i64.load", "i64.store") };
+        for (name, op) in AMOS {
+            out.push_str(&format!(
+                "(func $amo_{}_{} (param $vaddr i64) (param $value i64) (result i64)\n",
+                name, bits
+            ));
+            if shared {
+                let op = if word {
+                    format!("i64.atomic.rmw32.{}_u", op)
+                } else {
+                    format!("i64.atomic.rmw.{}", op)
+                };
+                out.push_str(&format!("  {})\n", old(format!("({} {} (local.get $value))", op, offset))));
+            } else {
+                let new = match name {
+                    "swap" => "(local.get $value)".to_string(),
+                    _ => format!("(i64.{} (local.get $old) (local.get $value))", name),
+                };
+                out.push_str(&format!(
+                    "  (local $offset i32) (local $old i64)\n  (local.set $offset {})\n  (local.set $old ({} (local.get $offset)))\n  ({} (local.get $offset) {})\n  (local.get $old))\n",
+                    offset, load, store, new
+                ));
+            }
+        }
+        out.push_str(&format!(
+            "(func $cas_{} (param $vaddr i64) (param $expected i64) (param $value i64) (result i64)\n",
+            bits
+        ));
+        if shared {
+            let cmpxchg = if word {
+                // the word in memory is compared zero extended
+                format!(
+                    "(i64.atomic.rmw32.cmpxchg_u {} (i64.extend_i32_u (i32.wrap_i64 (local.get $expected))) (local.get $value))",
+                    offset
+                )
+            } else {
+                format!("(i64.atomic.rmw.cmpxchg {} (local.get $expected) (local.get $value))", offset)
+            };
+            out.push_str(&format!("  {})\n", old(cmpxchg)));
+        } else {
+            let equal = if word {
+                "(i32.eq (i32.wrap_i64 (local.get $old)) (i32.wrap_i64 (local.get $expected)))"
+            } else {
+                "(i64.eq (local.get $old) (local.get $expected))"
+            };
+            out.push_str(&format!(
+                "  (local $offset i32) (local $old i64)\n  (local.set $offset {})\n  (local.set $old ({} (local.get $offset)))\n  (if {}\n    (then ({} (local.get $offset) (local.get $value))))\n  (local.get $old))\n",
+                offset, load, equal, store
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::eval::call;
+    use crate::codegen::ir::Module;
+    use crate::middleend::address_map::{Perm, SharedArena};
+
+    #[test]
+    fn test_word_atomics_sign_extend() {
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RW).unwrap();
+        let mut helpers = String::new();
+        emit_atomic_helpers(&mut helpers, &map, &Config::new());
+        let wat = format!(
+            "(module (memory 1 1)\n(func $vaddr_to_offset (param $vaddr i64) (result i32) (i32.wrap_i64 (local.get $vaddr)))\n{})",
+            helpers
+        );
+        let module = Module::parse(&wat).unwrap();
+        // -2 as a word at 0x100
+        let memory: Vec<_> = (0x100..).zip((-2i32).to_le_bytes()).collect();
+        let word = |memory: &std::collections::BTreeMap<u64, u8>| {
+            i32::from_le_bytes([memory[&0x100], memory[&0x101], memory[&0x102], memory[&0x103]])
+        };
+
+        // LR.W gives the word sign extended, which SC.W then compares equal
+        let (old, memory_after) = call(&module, "$cas_32", vec![0x100, -2, 5], &memory).unwrap();
+        assert_eq!((old, word(&memory_after)), (vec![-2], 5));
+        let (old, memory_after) = call(&module, "$cas_32", vec![0x100, -1, 5], &memory).unwrap();
+        assert_eq!((old, word(&memory_after)), (vec![-2], -2));
+        let (old, memory_after) = call(&module, "$amo_add_32", vec![0x100, 1], &memory).unwrap();
+        assert_eq!((old, word(&memory_after)), (vec![-2], -1));
+
+        let arena = SharedArena::new(1 << 20);
+        let mut shared = String::new();
+        emit_atomic_helpers(&mut shared, &AddressMap::shared(&arena).unwrap(), &Config::new());
+        assert!(shared.contains("(i64.extend32_s (i64.atomic.rmw32.add_u"));
+        assert!(shared.contains("(i64.extend32_s (i64.atomic.rmw32.cmpxchg_u"));
+        assert!(shared.contains("(i64.extend_i32_u (i32.wrap_i64 (local.get $expected)))"));
+    }
+
+    #[test]
+    fn test_strict_helpers_check_permissions() {
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        let mut relaxed = String::new();
+        emit_memory_helpers(&mut relaxed, &map, &Config::new());
+        assert!(!relaxed.contains("$guest_fault"));
+        assert!(relaxed.contains("(func $load_u16"));
+        assert!(relaxed.contains("(func $linear_range"));
+
+        let mut strict = String::new();
+        emit_memory_helpers(&mut strict, &map, &Config::new().strict_memory(true));
+        assert!(strict.contains("(func $translate_exec"));
+        assert!(!strict.contains("$linear_range"));
+        assert!(strict.contains(&format!("offset={}", map.perm_table_offset())));
+        assert_eq!(strict.matches("(drop (call $translate_write").count(), 3);
+        assert_eq!(strict.matches('(').count(), strict.matches(')').count());
+    }
+
+    #[test]
+    fn test_register_globals() {
+        let mut out = String::new();
+        emit_register_globals(&mut out);
+        assert_eq!(out.lines().count(), 1 + 31 + 32 + 1);
+        assert!(!out.contains("\"x0\""));
+        assert!(out.contains("(global $x31 (export \"x31\") (mut i64)"));
+        assert!(out.contains("(global $fcsr (export \"fcsr\") (mut i32)"));
+
+        let mut slots = BlockSlots::new();
+        let mut caches = InlineCaches::new();
+        caches.record(0x10000, 0x10200);
+        let exit = indirect_exit(0x10000, "(global.get $x1)", &caches, &mut slots);
+        assert!(exit.starts_with("(local.set $target (global.get $x1)) (if (result i64) (i64.eq (local.get $target) (i64.const 0x10200)) (then (global.set $next_slot (i32.const 0))"));
+        let mut block = String::new();
+        emit_block_module(&mut block, &AddressMap::new(1 << 20), &Config::new(), &exit);
+        assert!(block.contains("(import \"env\" \"x31\" (global $x31 (mut i64)))"));
+        assert!(block.contains("(import \"env\" \"memory\" (memory 16 16))"));
+        assert_eq!(block.matches('(').count(), block.matches(')').count());
+        assert!(!block.contains("$csr_"));
+        // frflags, whose block imports the CSR host functions
+        let frflags = csr_call(CsrOp::Set, 0x001, "(i64.const 0)", false);
+        assert_eq!(frflags, "(call $csr_read_set (i32.const 0x1) (i64.const 0) (i32.const 0))");
+        let mut block = String::new();
+        emit_block_module(&mut block, &AddressMap::new(1 << 20), &Config::new(), &format!("(global.set $x10 {})\n{}", frflags, exit));
+        assert!(block.contains("(import \"env\" \"csr_read_clear\""));
+
+        let mut profile = BlockProfile::new();
+        profile.record(0x10000, 0x10004);
+        let mut exit = String::new();
+        emit_branch(&mut exit, "(local.get $c)", 0x10000, (0x10100, 0x10004), &mut slots, Some(&profile));
+        assert_eq!(
+            exit,
+            "(if (result i64) (i32.eqz (local.get $c)) (then (global.set $next_slot (i32.const 1)) (i64.const 0x10004)) (else (global.set $next_slot (i32.const 2)) (i64.const 0x10100)))\n"
+        );
+        let mut side = String::new();
+        emit_side_exit(&mut side, "(local.get $c)", (0x10100, 0x10004), 0x10100, &RegMap::new(&[2]), &mut slots);
+        assert_eq!(
+            side,
+            "(if (i32.eqz (local.get $c)) (then (global.set $x2 (local.get $r2)) (return (global.set $next_slot (i32.const 1)) (i64.const 0x10004))))\n"
+        );
+        let mut chain = String::new();
+        emit_chain_module(&mut chain);
+        assert!(chain.contains("(call_indirect $blocks (type $block) (local.get $slot))"));
+        assert_eq!(chain.matches('(').count(), chain.matches(')').count());
+        let profiler = Profiler::new();
+        let mut parallel = String::new();
+        let pcs: Vec<u64> = (0..64).map(|block| 0x20000 + block * 4).collect();
+        emit_parallel(&mut parallel, &pcs, |pc, out| out.push_str(&indirect_exit(*pc, "(i64.const 0)", &caches, &mut BlockSlots::new())), Some(&profiler));
+        assert_eq!(parallel.matches("(global.set $exit_site").count(), 64);
+        assert!(parallel.starts_with("(local.set $target (i64.const 0)) (global.set $exit_site (i64.const 0x20000))"));
+        assert_eq!(profiler.timer(MIDDLEEND_EMIT_BLOCKS).unwrap().count, 1);
+
+        let mut functions = String::new();
+        let thunk = block_exit(0x10000, &mut slots);
+        let names = emit_deduplicated(&mut functions, &[(0x20000, thunk.clone()), (0x20004, exit.clone()), (0x20008, thunk)]);
+        assert_eq!(functions.matches("(func $block_").count(), 2);
+        assert_eq!(names[0].1, names[2].1);
+        assert_ne!(names[0].1, names[1].1);
+
+        let mut blocks = String::new();
+        let cold = "(i64.const 0x10100)".to_string();
+        let names = emit_blocks(&mut blocks, &[(0x10100, cold.clone()), (0x10000, "(i64.const 0x10004)".into()), (0x10200, cold)], Some(&profile));
+        assert!(blocks.find("0x10004").unwrap() < blocks.find("0x10100").unwrap());
+        assert_eq!(blocks.matches("(func $block_").count(), 2);
+        assert_eq!(names.iter().map(|(pc, _)| *pc).collect::<Vec<_>>(), [0x10000, 0x10100, 0x10200]);
+        assert_eq!(names[1].1, names[2].1);
+        let mut module = String::new();
+        let blocks = [(0x10100, block_exit(0x10000, &mut slots)), (0x10000, exit.clone())];
+        emit_blocks_module(&mut module, &AddressMap::new(1 << 20), &Config::new(), &blocks, Some(&profile));
+        assert!(module.find(&block_export(0x10000)).unwrap() < module.find(&block_export(0x10100)).unwrap());
+        assert_eq!(module.matches('(').count(), module.matches(')').count());
+
+        let check = osr_check(0x10040);
+        assert!(check.starts_with("(if (i64.eq (global.get $osr_target) (i64.const 0x10040))"));
+        assert_eq!(check.matches('(').count(), check.matches(')').count());
+
+        assert_eq!(instruction_marker(&Config::new(), 0x10000), "");
+        let marker = instruction_marker(&Config::new().debug_runtime(true), 0x10000);
+        assert_eq!(marker.starts_with(";; 0x10000\n"), cfg!(feature = "debug-runtime"));
+
+        let check = budget_check(0x10000, 12, "");
+        assert!(check.contains("(i64.const 12)"));
+        assert!(check.contains("(then (global.set $pc (i64.const 0x10000))  (call $budget_exhausted))"));
+        assert_eq!(check.matches('(').count(), check.matches(')').count());
+    }
+}
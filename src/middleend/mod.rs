@@ -1,2 +1,6 @@
-mod address_map;
-mod wasm_module;
\ No newline at end of file
+pub mod address_map;
+pub mod emit_buf;
+pub mod emit_wasm;
+pub mod standalone;
+pub mod translate;
+mod wasm_module;
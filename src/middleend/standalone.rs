@@ -0,0 +1,261 @@
+use std::fmt::Write;
+
+use crate::frontend::binary::MemoryInitializer;
+use crate::middleend::address_map::{Access, AddressMap, MemoryError};
+use crate::middleend::emit_wasm::{
+    block_exit, emit_atomic_helpers, emit_deduplicated, emit_memory_helpers, emit_mulh_helpers, emit_register_globals,
+    x_global, INSTR_BUDGET_GLOBAL, INSTR_COUNT_GLOBAL,
+};
+use crate::runtime::config::Config;
+use crate::runtime::dispatch::BlockSlots;
+use crate::runtime::regs::Registers;
+
+const WASM_PAGE_SIZE: usize = 1 << 16;
+
+/// The WASI functions a standalone module uses in place of the embedder's
+/// host functions
+pub const WASI_IMPORTS: &str = "(import \"wasi_snapshot_preview1\" \"fd_write\" (func $fd_write (param i32 i32 i32 i32) (result i32)))\n(import \"wasi_snapshot_preview1\" \"fd_read\" (func $fd_read (param i32 i32 i32 i32) (result i32)))\n(import \"wasi_snapshot_preview1\" \"random_get\" (func $random_get (param i32 i32) (result i32)))\n(import \"wasi_snapshot_preview1\" \"proc_exit\" (func $proc_exit (param i32)))\n";
+
+/// Why a guest cannot be turned into a standalone module
+#[derive(Debug, thiserror::Error)]
+pub enum StandaloneError {
+    /// The guest needs a host callback WASI has no counterpart for
+    #[error("standalone modules do not support {0}")]
+    Unsupported(&'static str),
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+}
+
+/// The export of `emit_image_loader`
+pub const IMAGE_LOADER_EXPORT: &str = "load_image";
+
+/// Length of `data` without the zeros at its end
+fn trimmed_len(data: &[u8]) -> usize {
+    data.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1)
+}
+
+fn emit_bytes(out: &mut String, data: &[u8]) {
+    out.push('"');
+    for byte in data {
+        write!(out, "\\{:02x}", byte).unwrap();
+    }
+    out.push('"');
+}
+
+/// Emit a data segment placing `data` at linear memory `offset`. Zeros at
+/// the end are left out as the memory starts zeroed.
+fn emit_data(out: &mut String, offset: usize, data: &[u8]) {
+    let len = trimmed_len(data);
+    if len == 0 {
+        return;
+    }
+    write!(out, "(data (i32.const {}) ", offset).unwrap();
+    emit_bytes(out, &data[..len]);
+    out.push_str(")\n");
+}
+
+/// Emit one data segment per initializer, at the linear offset its address
+/// is mapped to
+pub fn emit_data_segments(
+    out: &mut String,
+    map: &AddressMap,
+    image: &[MemoryInitializer],
+) -> Result<(), MemoryError> {
+    for init in image {
+        let offset = map.vaddr_to_offset(init.vaddr).ok_or(MemoryError::Unmapped(init.vaddr))?;
+        emit_data(out, offset, &init.data);
+    }
+    Ok(())
+}
+
+/// Emit the guest image as passive data segments, and the exported
+/// `load_image` function copying them into linear memory with
+/// `memory.init`, zeroing the rest of each initializer with `memory.fill`.
+/// The embedder calls it once after instantiating instead of writing the
+/// image byte by byte, and the segments are dropped after the copy. As the
+/// zeros are filled in too, it also reloads a memory the guest has used.
+pub fn emit_image_loader(
+    out: &mut String,
+    map: &AddressMap,
+    image: &[MemoryInitializer],
+) -> Result<(), MemoryError> {
+    let mut body = String::new();
+    for (index, init) in image.iter().enumerate() {
+        let offset = map.vaddr_to_offset(init.vaddr).ok_or(MemoryError::Unmapped(init.vaddr))?;
+        let len = trimmed_len(&init.data);
+        if len > 0 {
+            write!(out, "(data $image_{} ", index).unwrap();
+            emit_bytes(out, &init.data[..len]);
+            out.push_str(")\n");
+            writeln!(
+                body,
+                "  (memory.init $image_{0} (i32.const {1}) (i32.const 0) (i32.const {2}))\n  (data.drop $image_{0})",
+                index, offset, len
+            )
+            .unwrap();
+        }
+        if len < init.data.len() {
+            writeln!(
+                body,
+                "  (memory.fill (i32.const {}) (i32.const 0) (i32.const {}))",
+                offset + len,
+                init.data.len() - len
+            )
+            .unwrap();
+        }
+    }
+    writeln!(out, "(func (export \"{}\")\n{})", IMAGE_LOADER_EXPORT, body.trim_end()).unwrap();
+    Ok(())
+}
+
+/// Emit `$ecall`, the handful of linux syscalls a standalone guest gets,
+/// implemented with WASI: read, write, exit, exit_group and getrandom. The
+/// others fail with ENOSYS. `scratch` is linear memory for the iovec.
+fn emit_ecall(out: &mut String, scratch: usize) {
+    out.push_str("(func $ecall (param $nr i64) (param $a0 i64) (param $a1 i64) (param $a2 i64) (result i64)\n");
+    for (nr, func) in [(63, "$fd_read"), (64, "$fd_write")] {
+        write!(
+            out,
+            "  (if (i64.eq (local.get $nr) (i64.const {nr}))\n    (then\n      (i32.store (i32.const {iov}) (call $vaddr_to_offset (local.get $a1)))\n      (i32.store (i32.const {len}) (i32.wrap_i64 (local.get $a2)))\n      (if (call {func} (i32.wrap_i64 (local.get $a0)) (i32.const {iov}) (i32.const 1) (i32.const {done}))\n        (then (return (i64.const -5))))\n      (return (i64.load32_u (i32.const {done})))))\n",
+            iov = scratch,
+            len = scratch + 4,
+            done = scratch + 8,
+        )
+        .unwrap();
+    }
+    out.push_str("  (if (i32.or (i64.eq (local.get $nr) (i64.const 93)) (i64.eq (local.get $nr) (i64.const 94)))\n    (then (call $proc_exit (i32.wrap_i64 (local.get $a0))) (unreachable)))\n");
+    out.push_str("  (if (i64.eq (local.get $nr) (i64.const 278))\n    (then\n      (if (call $random_get (call $vaddr_to_offset (local.get $a0)) (i32.wrap_i64 (local.get $a1)))\n        (then (return (i64.const -5))))\n      (return (local.get $a1))))\n");
+    out.push_str("  (i64.const -38))\n");
+}
+
+/// The block of the ecall at `pc`, which the blocks of the interpreter's
+/// tiers stop in front of: it makes the syscall through `$ecall` and goes on
+/// after the instruction
+pub fn ecall_block(pc: u64, slots: &mut BlockSlots) -> String {
+    format!(
+        "(global.set ${a0} (call $ecall (global.get ${nr}) (global.get ${a0}) (global.get ${a1}) (global.get ${a2})))\n{exit}\n",
+        nr = x_global(17),
+        a0 = x_global(10),
+        a1 = x_global(11),
+        a2 = x_global(12),
+        exit = block_exit(pc.wrapping_add(4), slots)
+    )
+}
+
+/// Emit the guest code of a standalone module out of the translated
+/// `blocks`, keyed by pc, whose exits name slots of `slots`: the registers,
+/// counters without a budget, the table the blocks are linked through and
+/// `$guest_entry`, which starts the guest with `regs` and runs block after
+/// block, looking up the block of a computed jump by its pc. A pc without a
+/// block faults the guest.
+pub fn emit_guest(out: &mut String, blocks: &[(u64, String)], slots: &mut BlockSlots, regs: &Registers) {
+    emit_register_globals(out);
+    writeln!(out, "(global ${} (mut i64) (i64.const 0))", INSTR_COUNT_GLOBAL).unwrap();
+    writeln!(out, "(global ${} (mut i64) (i64.const -1))", INSTR_BUDGET_GLOBAL).unwrap();
+    out.push_str("(func $budget_exhausted)\n");
+    if blocks.iter().any(|(_, body)| body.contains("(call $mulh")) {
+        emit_mulh_helpers(out);
+    }
+    let names = emit_deduplicated(out, blocks);
+    let linked: Vec<(u32, u64, String)> = names.into_iter().map(|(pc, name)| (slots.slot(pc), pc, name)).collect();
+    writeln!(out, "(type $block (func (result i64)))\n(table $blocks {} funcref)", slots.len()).unwrap();
+    for (slot, _, name) in &linked {
+        writeln!(out, "(elem (i32.const {}) func {})", slot, name).unwrap();
+    }
+    out.push_str("(global $next_slot (mut i32) (i32.const -1))\n(global $exit_site (mut i64) (i64.const 0))\n");
+    out.push_str("(func $slot_of (param $pc i64) (result i32)\n");
+    for (slot, pc, _) in &linked {
+        writeln!(out, "  (if (i64.eq (local.get $pc) (i64.const {:#x})) (then (return (i32.const {}))))", pc, slot).unwrap();
+    }
+    out.push_str("  (i32.const -1))\n");
+    out.push_str("(func $guest_entry\n  (local $pc i64) (local $slot i32)\n");
+    for (index, &value) in regs.x.iter().enumerate().skip(1).filter(|(_, &value)| value != 0) {
+        writeln!(out, "  (global.set ${} (i64.const {:#x}))", x_global(index), value).unwrap();
+    }
+    write!(
+        out,
+        "  (local.set $pc (i64.const {pc:#x}))\n  (loop $next\n    (local.set $slot (global.get $next_slot))\n    (if (i32.lt_s (local.get $slot) (i32.const 0))\n      (then (local.set $slot (call $slot_of (local.get $pc)))))\n    (if (i32.ge_u (local.get $slot) (i32.const {len}))\n      (then (call $guest_fault (i32.const {exec}) (local.get $pc)) (unreachable)))\n    (if (ref.is_null (table.get $blocks (local.get $slot)))\n      (then (call $guest_fault (i32.const {exec}) (local.get $pc)) (unreachable)))\n    (local.set $pc (call_indirect $blocks (type $block) (local.get $slot)))\n    (br $next)))\n",
+        pc = regs.pc,
+        len = slots.len(),
+        exec = Access::Exec as i32,
+    )
+    .unwrap();
+}
+
+/// Build a self-contained module out of the translated guest `code`, which
+/// defines `$guest_entry` and reaches the kernel through `$ecall`. The guest
+/// image is placed in data segments, and the module imports nothing but
+/// WASI, so any WASM runtime or a browser can run it without the embedder.
+pub fn emit_standalone(
+    map: &AddressMap,
+    config: &Config,
+    image: &[MemoryInitializer],
+    code: &str,
+) -> Result<String, StandaloneError> {
+    if config.mmu {
+        return Err(StandaloneError::Unsupported("the MMU"));
+    }
+    if map.is_shared() {
+        return Err(StandaloneError::Unsupported("shared memory"));
+    }
+    if !map.mmio_regions().is_empty() {
+        return Err(StandaloneError::Unsupported("MMIO regions"));
+    }
+    let mut out = String::from("(module\n");
+    out.push_str(WASI_IMPORTS);
+    // one page past the guest's memory holds the scratch of $ecall
+    let scratch = map.size();
+    writeln!(out, "(memory (export \"memory\") {})", scratch / WASM_PAGE_SIZE + 1).unwrap();
+    // a fault ends the guest the way SIGSEGV would
+    out.push_str("(func $guest_fault (param i32 i64) (call $proc_exit (i32.const 139)))\n");
+    emit_memory_helpers(&mut out, map, config);
+    emit_atomic_helpers(&mut out, map, config);
+    emit_ecall(&mut out, scratch);
+    out.push_str(code);
+    out.push_str("(func (export \"_start\") (call $guest_entry) (call $proc_exit (i32.const 0)))\n");
+    emit_data_segments(&mut out, map, image)?;
+    if config.strict_memory {
+        emit_data(&mut out, map.perm_table_offset(), &map.perm_table());
+    }
+    out.push_str(")\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+
+    #[test]
+    fn test_standalone_module() {
+        let mut map = AddressMap::new(1 << 20);
+        let offset = map.map(0x10000..0x12000, Perm::RX).unwrap();
+        let mut data = vec![0; 0x2000];
+        data[..4].copy_from_slice(b"\x7fELF");
+        let image = [MemoryInitializer { vaddr: 0x10000, perm: Perm::RX, data }];
+        let code = "(func $guest_entry (drop (call $ecall (i64.const 93) (i64.const 0) (i64.const 0) (i64.const 0))))\n";
+
+        let wat = emit_standalone(&map, &Config::new().strict_memory(true), &image, code).unwrap();
+        assert!(!wat.contains("(import \"env\""));
+        assert!(wat.contains("(memory (export \"memory\") 17)"));
+        assert!(wat.contains(&format!("(data (i32.const {}) \"\\7f\\45\\4c\\46\")", offset)));
+        assert!(wat.contains(&format!("(data (i32.const {})", map.perm_table_offset())));
+        assert_eq!(wat.matches('(').count(), wat.matches(')').count());
+
+        let mut loader = String::new();
+        emit_image_loader(&mut loader, &map, &image).unwrap();
+        assert!(loader.starts_with("(data $image_0 \"\\7f\\45\\4c\\46\")\n"));
+        assert!(loader.contains(&format!("(memory.init $image_0 (i32.const {}) (i32.const 0) (i32.const 4))", offset)));
+        assert!(loader.contains(&format!("(memory.fill (i32.const {}) (i32.const 0) (i32.const {}))", offset + 4, 0x2000 - 4)));
+
+        let unmapped = [MemoryInitializer { vaddr: 0x20000, perm: Perm::RW, data: vec![1] }];
+        assert!(matches!(
+            emit_standalone(&map, &Config::new(), &unmapped, code),
+            Err(StandaloneError::Memory(MemoryError::Unmapped(0x20000)))
+        ));
+        assert!(matches!(
+            emit_standalone(&map, &Config::new().mmu(true), &image, code),
+            Err(StandaloneError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,841 @@
+use crate::codegen::idiom::{Idiom, IDIOM_LOCALS};
+use crate::codegen::regmap::RegMap;
+use crate::frontend::page::Page;
+use crate::middleend::address_map::{Access, AddressMap, GuestMemory, LinearMemory};
+use crate::middleend::emit_wasm::{
+    block_exit, budget_check, bulk_memory, emit_branch, emit_side_exit, indirect_exit, instruction_marker, osr_check, x_global, PC_GLOBAL,
+};
+use crate::runtime::config::{Config, OptLevel};
+use crate::runtime::dispatch::{BlockSlots, InlineCaches};
+use crate::runtime::interp::{ends_block, imm_b, imm_i, imm_j, imm_s, imm_u, insn_at};
+use crate::runtime::pgo::BlockProfile;
+
+/// Instructions translated into one block at most, a longer run goes on in
+/// the block after
+pub const MAX_BLOCK_LEN: usize = 64;
+
+/// (helper suffix) of the loads by funct3
+const LOAD_HELPERS: [&str; 7] = ["i8", "i16", "i32", "64", "u8", "u16", "u32"];
+const STORE_HELPERS: [&str; 4] = ["8", "16", "32", "64"];
+
+/// Whether compiled code runs `insn`: RV64I and M. The rest ends the
+/// block before it for the interpreter to run, ecall and the CSRs, the
+/// atomics, which the interpreter runs atomically between threads, floating
+/// point, fence.i, and invalid encodings, which the interpreter raises.
+pub fn translatable(insn: u32) -> bool {
+    let (funct3, funct7) = ((insn >> 12) & 7, insn >> 25);
+    match insn & 0x7f {
+        0x37 | 0x17 | 0x6f => true,
+        0x67 => funct3 == 0,
+        0x63 => !matches!(funct3, 2 | 3),
+        0x03 => funct3 != 7,
+        0x23 => funct3 <= 3,
+        0x13 => match funct3 {
+            1 => insn >> 26 == 0,
+            5 => matches!(insn >> 26, 0 | 0x10),
+            _ => true,
+        },
+        0x1b => matches!((funct3, funct7), (0, _) | (1, 0) | (5, 0) | (5, 0x20)),
+        0x33 => matches!(funct7, 0 | 1) || funct7 == 0x20 && matches!(funct3, 0 | 5),
+        0x3b => matches!((funct7, funct3), (0 | 0x20, 0) | (0, 1) | (0 | 0x20, 5) | (1, 0) | (1, 4..=7)),
+        // the one thread running the code needs no fence
+        0x0f => funct3 == 0,
+        _ => false,
+    }
+}
+
+/// The instructions of a guest block compiled code runs: from its start up
+/// to the jump or branch ending it, or up to the first instruction left to
+/// the interpreter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestBlock {
+    pub start: u64,
+    /// (pc, instruction, length), a compressed one expanded
+    pub insns: Vec<(u64, u32, u64)>,
+}
+
+impl GuestBlock {
+    /// Decode the block at the start of `code`, loaded at `start`. `None`
+    /// when the first instruction is left to the interpreter.
+    pub fn decode(code: &[u8], start: u64) -> Option<Self> {
+        let mut insns = Vec::new();
+        let mut offset = 0;
+        while let Some((insn, len)) = insn_at(code, offset) {
+            if !translatable(insn) || insns.len() == MAX_BLOCK_LEN {
+                break;
+            }
+            insns.push((start.wrapping_add(offset as u64), insn, len as u64));
+            offset += len;
+            if ends_block(insn) {
+                break;
+            }
+        }
+        (!insns.is_empty()).then_some(Self { start, insns })
+    }
+
+    /// Decode the block at `pc` from guest memory. A block ends at the end
+    /// of its page, the next one may not be mapped, and with `strict` a
+    /// page the guest may not execute has no block.
+    pub fn read<M: LinearMemory + ?Sized>(memory: &GuestMemory<M>, strict: bool, pc: u64) -> Option<Self> {
+        if strict && memory.map().check(pc, Access::Exec).is_err() {
+            return None;
+        }
+        let page = Page::SIZE as u64;
+        let len = (page - pc % page).min(4 * MAX_BLOCK_LEN as u64);
+        let mut code = vec![0; len as usize];
+        memory.peek(pc, &mut code).ok()?;
+        Self::decode(&code, pc)
+    }
+
+    /// The pc after the last instruction
+    pub fn end(&self) -> u64 {
+        let &(pc, _, len) = self.insns.last().unwrap();
+        pc.wrapping_add(len)
+    }
+
+    /// The raw instructions, for `count_uses`
+    pub fn words(&self) -> impl Iterator<Item = u32> + '_ {
+        self.insns.iter().map(|&(_, insn, _)| insn)
+    }
+}
+
+fn konst(value: u64) -> String {
+    format!("(i64.const {})", value as i64)
+}
+
+fn op(name: &str, a: &str, b: &str) -> String {
+    format!("({} {} {})", name, a, b)
+}
+
+/// An i32 comparison as the 0 or 1 of rd
+fn flag(name: &str, a: &str, b: &str) -> String {
+    format!("(i64.extend_i32_u ({} {} {}))", name, a, b)
+}
+
+/// The register-register operations of I and M on the i64 values `a` and
+/// `b`, with the results of a division by zero and of an overflowing one
+/// RISC-V gives instead of the trap of wasm
+fn alu(funct3: u32, funct7: u32, a: &str, b: &str) -> String {
+    match (funct7, funct3) {
+        (0, 0) => op("i64.add", a, b),
+        (0x20, 0) => op("i64.sub", a, b),
+        (0, 1) => op("i64.shl", a, b),
+        (0, 2) => flag("i64.lt_s", a, b),
+        (0, 3) => flag("i64.lt_u", a, b),
+        (0, 4) => op("i64.xor", a, b),
+        (0, 5) => op("i64.shr_u", a, b),
+        (0x20, 5) => op("i64.shr_s", a, b),
+        (0, 6) => op("i64.or", a, b),
+        (0, 7) => op("i64.and", a, b),
+        (1, 0) => op("i64.mul", a, b),
+        (1, 1) => format!("(call $mulh {} {})", a, b),
+        (1, 2) => format!("(call $mulhsu {} {})", a, b),
+        (1, 3) => format!("(call $mulhu {} {})", a, b),
+        (1, 4) => format!(
+            "(if (result i64) (i64.eqz {b}) (then (i64.const -1)) (else (if (result i64) (i32.and (i64.eq {a} (i64.const {min})) (i64.eq {b} (i64.const -1))) (then {a}) (else (i64.div_s {a} {b})))))",
+            a = a,
+            b = b,
+            min = i64::MIN
+        ),
+        (1, 5) => format!("(if (result i64) (i64.eqz {b}) (then (i64.const -1)) (else (i64.div_u {a} {b})))", a = a, b = b),
+        // wasm gives the 0 RISC-V does for the overflowing remainder
+        (1, 6) => format!("(if (result i64) (i64.eqz {b}) (then {a}) (else (i64.rem_s {a} {b})))", a = a, b = b),
+        (1, 7) => format!("(if (result i64) (i64.eqz {b}) (then {a}) (else (i64.rem_u {a} {b})))", a = a, b = b),
+        _ => unreachable!("untranslatable op {:#x} {:#x}", funct7, funct3),
+    }
+}
+
+/// The 32 bit operations of RV64I and RV64M on the i32 values `a` and `b`,
+/// giving the i32 to sign extend into rd
+fn alu_w(funct3: u32, funct7: u32, a: &str, b: &str) -> String {
+    match (funct7, funct3) {
+        (0, 0) => op("i32.add", a, b),
+        (0x20, 0) => op("i32.sub", a, b),
+        (0, 1) => op("i32.shl", a, b),
+        (0, 5) => op("i32.shr_u", a, b),
+        (0x20, 5) => op("i32.shr_s", a, b),
+        (1, 0) => op("i32.mul", a, b),
+        (1, 4) => format!(
+            "(if (result i32) (i32.eqz {b}) (then (i32.const -1)) (else (if (result i32) (i32.and (i32.eq {a} (i32.const {min})) (i32.eq {b} (i32.const -1))) (then {a}) (else (i32.div_s {a} {b})))))",
+            a = a,
+            b = b,
+            min = i32::MIN
+        ),
+        (1, 5) => format!("(if (result i32) (i32.eqz {b}) (then (i32.const -1)) (else (i32.div_u {a} {b})))", a = a, b = b),
+        (1, 6) => format!("(if (result i32) (i32.eqz {b}) (then {a}) (else (i32.rem_s {a} {b})))", a = a, b = b),
+        (1, 7) => format!("(if (result i32) (i32.eqz {b}) (then {a}) (else (i32.rem_u {a} {b})))", a = a, b = b),
+        _ => unreachable!("untranslatable op-32 {:#x} {:#x}", funct7, funct3),
+    }
+}
+
+/// The condition of a branch, an i32
+fn condition(funct3: u32, a: &str, b: &str) -> String {
+    let name = match funct3 {
+        0 => "i64.eq",
+        1 => "i64.ne",
+        4 => "i64.lt_s",
+        5 => "i64.ge_s",
+        6 => "i64.lt_u",
+        _ => "i64.ge_u",
+    };
+    op(name, a, b)
+}
+
+/// Emits the instructions of one function, with the registers where `regs`
+/// keeps them. Registers held in locals are written back to their globals
+/// before every memory access, and the pc set, so a trapping access leaves
+/// the state of the instruction it trapped in for the interpreter to
+/// raise the trap from.
+pub struct BlockWriter<'a> {
+    pub out: String,
+    regs: &'a RegMap,
+    config: &'a Config,
+    /// Registers in locals written since their globals last were
+    dirty: Vec<usize>,
+    /// The pc of the loop the code is in, the label `$header` branches
+    /// back to
+    header: Option<u64>,
+    /// The header of the loop whose back-edge leaves for its optimized
+    /// code once that is ready
+    osr: Option<u64>,
+}
+
+impl<'a> BlockWriter<'a> {
+    pub fn new(regs: &'a RegMap, config: &'a Config) -> Self {
+        Self { out: String::new(), regs, config, dirty: Vec::new(), header: None, osr: None }
+    }
+
+    /// Write the code of a loop at `header`, to which jumps and branches
+    /// go back with a `br` to the label `$header` rather than leaving
+    pub fn header(mut self, header: u64) -> Self {
+        self.header = Some(header);
+        self
+    }
+
+    /// Have the jump or branch back to `header` check for the optimized
+    /// code of the loop and leave for it first, see `osr_check`
+    pub fn osr(mut self, header: u64) -> Self {
+        self.osr = Some(header);
+        self
+    }
+
+    fn write(&mut self, reg: usize, value: &str) {
+        self.out.push_str(&self.regs.write(reg, value));
+        self.out.push('\n');
+        if self.regs.is_local(reg) && !self.dirty.contains(&reg) {
+            self.dirty.push(reg);
+        }
+    }
+
+    /// The code writing the registers changed in locals back, which stay
+    /// changed, for a way out of the code not taken every time
+    fn written(&self) -> String {
+        self.dirty.iter().map(|&reg| format!("(global.set ${} {})", x_global(reg), self.regs.read(reg))).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Write the registers changed in locals back to their globals
+    pub fn sync(&mut self) -> String {
+        let code = self.written();
+        self.dirty.clear();
+        code
+    }
+
+    /// The budget check on entry to the code of `block`, which counts its
+    /// instructions, with the registers changed in locals written back
+    /// before yielding. At the header of a loop any of them may have
+    /// changed in an earlier iteration.
+    pub fn enter(&mut self, block: &GuestBlock) {
+        let count = if self.config.instrumented() { 0 } else { block.insns.len() as u64 };
+        if self.header == Some(block.start) {
+            self.dirty = (1..32).filter(|&reg| self.regs.is_local(reg)).collect();
+        }
+        self.out.push_str(&budget_check(block.start, count, &self.written()));
+    }
+
+    /// The end of `block` in the middle of a trace going on at `on_trace`:
+    /// a jump there carries straight on, and a branch elsewhere leaves the
+    /// trace through a side exit
+    pub fn pass(&mut self, block: &GuestBlock, on_trace: u64, slots: &mut BlockSlots) {
+        let &(pc, insn, len) = block.insns.last().unwrap();
+        match insn & 0x7f {
+            0x6f => self.write(((insn >> 7) & 31) as usize, &konst(pc.wrapping_add(len))),
+            0x63 => {
+                let a = self.regs.read(((insn >> 15) & 31) as usize);
+                let b = self.regs.read(((insn >> 20) & 31) as usize);
+                let cond = condition((insn >> 12) & 7, &a, &b);
+                let targets = (pc.wrapping_add(imm_b(insn)), pc.wrapping_add(len));
+                emit_side_exit(&mut self.out, &cond, targets, on_trace, self.regs, slots);
+            }
+            _ => {}
+        }
+    }
+
+    /// The exit to `target`, a branch back to the loop at its header, and
+    /// otherwise the registers written back and the block left
+    fn goto(&mut self, target: u64, slots: &mut BlockSlots) -> String {
+        match self.header == Some(target) {
+            true => "(br $header)".to_string(),
+            false => format!("{} {}", self.sync(), self.leave(target, slots)),
+        }
+    }
+
+    /// The block exit to `target`, which first leaves for the optimized
+    /// loop when it is the back-edge `osr` watches
+    fn leave(&self, target: u64, slots: &mut BlockSlots) -> String {
+        match self.osr == Some(target) {
+            true => format!("{}{}", osr_check(target), block_exit(target, slots)),
+            false => block_exit(target, slots),
+        }
+    }
+
+    /// Emit every instruction of `block` but the jump or branch ending it,
+    /// which `block_exit` emits
+    pub fn body(&mut self, block: &GuestBlock) {
+        for &(pc, insn, _) in &block.insns {
+            if ends_block(insn) {
+                break;
+            }
+            self.insn(pc, insn);
+        }
+    }
+
+    fn insn(&mut self, pc: u64, insn: u32) {
+        self.out.push_str(&instruction_marker(self.config, pc));
+        let rd = ((insn >> 7) & 31) as usize;
+        let (funct3, funct7) = ((insn >> 12) & 7, insn >> 25);
+        let a = self.regs.read(((insn >> 15) & 31) as usize);
+        let b = self.regs.read(((insn >> 20) & 31) as usize);
+        let imm = konst(imm_i(insn));
+        let value = match insn & 0x7f {
+            0x37 => konst(imm_u(insn)),
+            0x17 => konst(pc.wrapping_add(imm_u(insn))),
+            0x13 => {
+                let shamt = konst(((insn >> 20) & 0x3f) as u64);
+                match funct3 {
+                    0 => op("i64.add", &a, &imm),
+                    1 => op("i64.shl", &a, &shamt),
+                    2 => flag("i64.lt_s", &a, &imm),
+                    3 => flag("i64.lt_u", &a, &imm),
+                    4 => op("i64.xor", &a, &imm),
+                    5 if insn >> 26 == 0 => op("i64.shr_u", &a, &shamt),
+                    5 => op("i64.shr_s", &a, &shamt),
+                    6 => op("i64.or", &a, &imm),
+                    _ => op("i64.and", &a, &imm),
+                }
+            }
+            0x1b => {
+                let a = format!("(i32.wrap_i64 {})", a);
+                let b = match funct3 {
+                    0 => format!("(i32.const {})", imm_i(insn) as i32),
+                    _ => format!("(i32.const {})", (insn >> 20) & 0x1f),
+                };
+                let funct7 = if funct3 == 0 { 0 } else { funct7 };
+                format!("(i64.extend_i32_s {})", alu_w(funct3, funct7, &a, &b))
+            }
+            0x33 => alu(funct3, funct7, &a, &b),
+            0x3b => {
+                let (a, b) = (format!("(i32.wrap_i64 {})", a), format!("(i32.wrap_i64 {})", b));
+                format!("(i64.extend_i32_s {})", alu_w(funct3, funct7, &a, &b))
+            }
+            0x03 => {
+                self.access(pc);
+                format!("(call $load_{} {})", LOAD_HELPERS[funct3 as usize], op("i64.add", &a, &imm))
+            }
+            0x23 => {
+                self.access(pc);
+                let addr = op("i64.add", &a, &konst(imm_s(insn)));
+                self.out.push_str(&format!("(call $store_{} {} {})\n", STORE_HELPERS[funct3 as usize], addr, b));
+                return;
+            }
+            _ => return,
+        };
+        self.write(rd, &value);
+    }
+
+    /// Leave the state a trap in the access of the instruction at `pc`
+    /// would be raised from
+    fn access(&mut self, pc: u64) {
+        let sync = self.sync();
+        self.out.push_str(&format!("(global.set ${} (i64.const {:#x})) {}\n", PC_GLOBAL, pc, sync));
+    }
+
+    /// Emit the exit of `block`, the value of the function: the jump or
+    /// branch ending it, or the fall through to the pc after it when it
+    /// stops before an instruction left to the interpreter
+    pub fn exit(&mut self, block: &GuestBlock, slots: &mut BlockSlots, caches: &InlineCaches, profile: Option<&BlockProfile>) {
+        let &(pc, insn, len) = block.insns.last().unwrap();
+        let next = pc.wrapping_add(len);
+        let rd = ((insn >> 7) & 31) as usize;
+        match insn & 0x7f {
+            0x6f => {
+                self.write(rd, &konst(next));
+                let exit = self.goto(pc.wrapping_add(imm_j(insn)), slots);
+                self.out.push_str(&exit);
+                self.out.push('\n');
+            }
+            0x67 => {
+                let base = self.regs.read(((insn >> 15) & 31) as usize);
+                let target = op("i64.and", &op("i64.add", &base, &konst(imm_i(insn))), "(i64.const -2)");
+                self.out.push_str(&format!("(local.set $target {})\n", target));
+                self.write(rd, &konst(next));
+                let sync = self.sync();
+                self.out.push_str(&format!("{} {}\n", sync, indirect_exit(pc, "(local.get $target)", caches, slots)));
+            }
+            0x63 => {
+                let a = self.regs.read(((insn >> 15) & 31) as usize);
+                let b = self.regs.read(((insn >> 20) & 31) as usize);
+                let cond = condition((insn >> 12) & 7, &a, &b);
+                let taken = pc.wrapping_add(imm_b(insn));
+                match self.header {
+                    // the loop goes around without writing anything back
+                    Some(header) if header == taken || header == next => {
+                        let (cond, other) = match header == taken {
+                            true => (cond, next),
+                            false => (format!("(i32.eqz {})", cond), taken),
+                        };
+                        let exit = self.goto(other, slots);
+                        self.out.push_str(&format!("(if (result i64) {} (then (br $header)) (else {}))\n", cond, exit));
+                    }
+                    _ => {
+                        let sync = self.sync();
+                        self.out.push_str(&sync);
+                        self.out.push('\n');
+                        match self.osr {
+                            Some(header) if header == taken || header == next => {
+                                let (taken, next) = (self.leave(taken, slots), self.leave(next, slots));
+                                self.out.push_str(&format!("(if (result i64) {} (then {}) (else {}))\n", cond, taken, next));
+                            }
+                            _ => emit_branch(&mut self.out, &cond, pc, (taken, next), slots, profile),
+                        }
+                    }
+                }
+            }
+            _ => {
+                let exit = self.goto(next, slots);
+                self.out.push_str(&exit);
+                self.out.push('\n');
+            }
+        }
+    }
+}
+
+/// The fast path of the byte copy or fill loop `block` makes of its own,
+/// see `Idiom::fast_path`, in the module of `map`. It goes before the loop,
+/// with `IDIOM_LOCALS` declared and the registers loaded.
+fn bulk_path(block: &GuestBlock, map: &AddressMap, config: &Config, regs: &RegMap, slots: &mut BlockSlots) -> Option<String> {
+    if !bulk_memory(map, config) || block.insns.iter().any(|&(_, _, len)| len != 4) {
+        return None;
+    }
+    let idiom = Idiom::recognize(&block.words().collect::<Vec<_>>())?;
+    Some(idiom.fast_path(regs, block.start, block.insns.len(), slots, true))
+}
+
+/// Declare the locals of `regs` and load them, with the fast path `bulk`
+/// after when there is one
+fn prologue(out: &mut String, regs: &RegMap, bulk: Option<String>) {
+    out.push_str(&regs.declare());
+    out.push('\n');
+    if bulk.is_some() {
+        out.push_str(IDIOM_LOCALS);
+        out.push('\n');
+    }
+    out.push_str(&regs.load());
+    out.push('\n');
+    out.push_str(&bulk.unwrap_or_default());
+}
+
+/// Translate `block` into the body of its module for `emit_block_module`,
+/// with the registers `regs` maps to locals loaded on entry. When tiering, a
+/// block looping on itself leaves at its back-edge for the optimized code of
+/// the loop, once there is some. A byte copy or fill loop first tries its
+/// bulk memory fast path, see `bulk_path`.
+pub fn translate_block(
+    block: &GuestBlock,
+    map: &AddressMap,
+    config: &Config,
+    regs: &RegMap,
+    slots: &mut BlockSlots,
+    caches: &InlineCaches,
+    profile: Option<&BlockProfile>,
+) -> String {
+    let mut writer = BlockWriter::new(regs, config);
+    if matches!(config.opt_level, OptLevel::Tiered { .. }) && is_loop(block) {
+        writer = writer.osr(block.start);
+    }
+    let bulk = bulk_path(block, map, config, regs, slots);
+    prologue(&mut writer.out, regs, bulk);
+    writer.enter(block);
+    writer.body(block);
+    writer.exit(block, slots, caches, profile);
+    writer.out
+}
+
+/// Translate the blocks of a trace, see `read_trace`, into the body of one
+/// module for `emit_block_module`: each block goes straight on into the
+/// next, the registers `regs` maps to locals staying in them, and a branch
+/// off the trace becomes a side exit. The last block ends as on its own.
+pub fn translate_trace(
+    blocks: &[GuestBlock],
+    config: &Config,
+    regs: &RegMap,
+    slots: &mut BlockSlots,
+    caches: &InlineCaches,
+    profile: Option<&BlockProfile>,
+) -> String {
+    let mut writer = BlockWriter::new(regs, config);
+    writer.out.push_str(&regs.declare());
+    writer.out.push('\n');
+    for (index, block) in blocks.iter().enumerate() {
+        writer.enter(block);
+        if index == 0 {
+            writer.out.push_str(&regs.load());
+            writer.out.push('\n');
+        }
+        writer.body(block);
+        match blocks.get(index + 1) {
+            Some(next) => writer.pass(block, next.start, slots),
+            None => writer.exit(block, slots, caches, profile),
+        }
+    }
+    writer.out
+}
+
+/// Read the blocks of the trace `profile` has from `start`, see
+/// `BlockProfile::trace`. The trace is cut after a block that cannot go on
+/// into the next one without a computed jump, and after one the guest
+/// changed since the profile was recorded.
+pub fn read_trace<M: LinearMemory + ?Sized>(memory: &GuestMemory<M>, strict: bool, profile: &BlockProfile, start: u64) -> Vec<GuestBlock> {
+    let mut blocks: Vec<GuestBlock> = Vec::new();
+    for pc in profile.trace(start) {
+        if blocks.last().is_some_and(|block| !continues_at(block, pc)) {
+            break;
+        }
+        match GuestBlock::read(memory, strict, pc) {
+            Some(block) => blocks.push(block),
+            None => break,
+        }
+    }
+    blocks
+}
+
+/// Whether `block` may go on at `pc` without a computed jump
+fn continues_at(block: &GuestBlock, pc: u64) -> bool {
+    let &(last, insn, len) = block.insns.last().unwrap();
+    match insn & 0x7f {
+        0x6f => last.wrapping_add(imm_j(insn)) == pc,
+        0x63 => last.wrapping_add(imm_b(insn)) == pc || last.wrapping_add(len) == pc,
+        0x67 => false,
+        _ => last.wrapping_add(len) == pc,
+    }
+}
+
+/// Whether the jump or branch ending `block` may go back to its start, so
+/// that it makes a loop of its own
+pub fn is_loop(block: &GuestBlock) -> bool {
+    let &(pc, insn, _) = block.insns.last().unwrap();
+    match insn & 0x7f {
+        0x6f => pc.wrapping_add(imm_j(insn)) == block.start,
+        0x63 => pc.wrapping_add(imm_b(insn)) == block.start,
+        _ => false,
+    }
+}
+
+/// Translate the loop `block` makes of its own, see `is_loop`, into the
+/// body of its module for `emit_block_module`. Going around stays within
+/// the function as a branch back to a wasm loop, the registers `regs` maps
+/// to locals staying in them, and only leaving the loop returns a pc. A byte
+/// copy or fill loop first tries its bulk memory fast path, see `bulk_path`.
+pub fn translate_loop(
+    block: &GuestBlock,
+    map: &AddressMap,
+    config: &Config,
+    regs: &RegMap,
+    slots: &mut BlockSlots,
+    caches: &InlineCaches,
+) -> String {
+    let mut writer = BlockWriter::new(regs, config).header(block.start);
+    let bulk = bulk_path(block, map, config, regs, slots);
+    prologue(&mut writer.out, regs, bulk);
+    writer.out.push_str("(loop $header (result i64)\n");
+    writer.enter(block);
+    writer.body(block);
+    writer.exit(block, slots, caches, None);
+    writer.out.push_str(")\n");
+    writer.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::eval::call_with;
+    use crate::codegen::ir::Module;
+    use crate::codegen::optimizer::Optimizer;
+    use crate::codegen::regmap::count_uses;
+    use crate::middleend::address_map::{AddressMap, Perm};
+    use crate::middleend::emit_wasm::{emit_block_module, OSR_IMPORTS};
+    use crate::runtime::interp::Interpreter;
+    use crate::runtime::regs::{Registers, A0, RA};
+    use crate::runtime::syscall::SyscallEnv;
+
+    /// Run the block at 0x10000 of `code` in the interpreter and, translated,
+    /// on the reference evaluator from the same registers and memory, and
+    /// compare the registers, memory and next pc they leave
+    fn compare(code: &[u32], regs: &RegMap) {
+        let config = Config::new();
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        map.map(0x20000..0x21000, Perm::RW).unwrap();
+        let mut linear = vec![0u8; 1 << 20];
+        let bytes: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let data: Vec<u8> = (0..64u32).map(|byte| (byte * 37 + 11) as u8).collect();
+        let mut memory = GuestMemory::new(&mut map, &mut linear);
+        memory.init(0x10000, &bytes).unwrap();
+        memory.init(0x20000, &data).unwrap();
+
+        let mut start = Registers::new(0x10000, 0x20020);
+        for (reg, value) in start.x.iter_mut().enumerate().skip(3) {
+            *value = (reg as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (reg % 7 * 9);
+        }
+        start.x[10] = 0x7fff_ffff;
+        start.x[11] = 0;
+        let block = GuestBlock::read(&memory, false, 0x10000).unwrap();
+        let mut slots = BlockSlots::new();
+        let body = translate_block(&block, memory.map(), &config, regs, &mut slots, &InlineCaches::new(), None);
+        let mut wat = String::new();
+        emit_block_module(&mut wat, memory.map(), &config, &body);
+        let module = Module::parse(&wat.replace("(func (export", "(func $run (export")).unwrap();
+
+        let mut globals: Vec<(String, i64)> = (1..32).map(|reg| (format!("${}", x_global(reg)), start.x[reg] as i64)).collect();
+        globals.extend([("$instr_count".to_string(), 0), ("$instr_budget".to_string(), i64::MAX)]);
+        let offset = |vaddr: u64| memory.map().vaddr_to_offset(vaddr).unwrap() as u64;
+        let initial: Vec<(u64, u8)> = (0..64).map(|n| (offset(0x20000 + n), data[n as usize])).collect();
+        let (next, globals, written) = call_with(&module, "$run", vec![], &globals, &initial).unwrap();
+
+        let mut regs = start.clone();
+        let mut env = SyscallEnv::new(&config);
+        let mut interp = Interpreter::new(&config);
+        for _ in &block.insns {
+            interp.step_instruction(&mut env, &mut memory, &mut regs).unwrap();
+        }
+        assert_eq!(next, vec![regs.pc as i64]);
+        for reg in 1..32 {
+            let value = globals.get(&format!("${}", x_global(reg))).copied().unwrap_or(start.x[reg] as i64);
+            assert_eq!(value as u64, regs.x[reg], "x{}", reg);
+        }
+        for (addr, byte) in written {
+            assert_eq!(byte, linear[addr as usize], "byte at offset {:#x}", addr);
+        }
+        assert_eq!(globals["$instr_count"], block.insns.len() as i64);
+    }
+
+    #[test]
+    fn test_block_matches_interpreter() {
+        let code = [
+            0x0055_0513u32, // addi a0, a0, 5
+            0x0035_1593,    // slli a1, a0, 3
+            0x02a5_8633,    // mul a2, a1, a0
+            0x02c5_96b3,    // mulh a3, a1, a2
+            0x02d6_3733,    // mulhu a4, a2, a3
+            0x02c6_a2b3,    // mulhsu t0, a3, a2
+            0x40b6_d7b3,    // sra a5, a3, a1
+            0x00c1_3423,    // sd a2, 8(sp)
+            0x0081_2803,    // lw a6, 8(sp)
+            0xff81_4883,    // lbu a7, -8(sp)
+            0x0205_d933,    // divu s2, a1, zero
+            0x0335_69b3,    // rem s3, a0, s3
+            0xfff5_0a1b,    // addiw s4, a0, -1
+            0x0315_4abb,    // divw s5, a0, a7
+            0x4076_dc1b,    // sraiw s8, a3, 7
+            0x00b5_4b33,    // xor s6, a0, a1
+            0x0165_3bb3,    // sltu s7, a0, s6
+            0xfab5_1ee3,    // bne a0, a1, -68
+        ];
+        compare(&code, &RegMap::new(&[]));
+        // the registers it uses most in locals are back in their globals at
+        // every exit, and around every access that may trap
+        let mut uses = [0; 32];
+        for &insn in &code {
+            count_uses(&mut uses, insn);
+        }
+        compare(&code, &RegMap::from_uses(&uses));
+        compare(&[0x0040_8093, 0x0080_00ef], &RegMap::new(&[RA])); // addi ra, ra, 4; jal ra, 8
+        compare(&[0x0055_0513, 0x0005_0067], &RegMap::new(&[A0])); // addi a0, a0, 5; jr a0
+        // a block stops before an ecall, which the interpreter runs
+        compare(&[0x0015_0513, 0x0000_0073], &RegMap::new(&[]));
+        assert!(GuestBlock::decode(&0x0000_0073u32.to_le_bytes(), 0x10000).is_none());
+
+        let block = GuestBlock::decode(&[0x05, 0x05, 0x67, 0x80, 0x00, 0x00], 0x10000).unwrap();
+        assert_eq!(block.insns, [(0x10000, 0x0015_0513, 2), (0x10002, 0x0000_8067, 4)]);
+        let mut caches = InlineCaches::new();
+        caches.record(0x10002, 0x10400);
+        let map = AddressMap::new(1 << 20);
+        let body = translate_block(&block, &map, &Config::new(), &RegMap::new(&[]), &mut BlockSlots::new(), &caches, None);
+        assert!(body.contains("(local.set $target (i64.and (i64.add (global.get $x1) (i64.const 0)) (i64.const -2)))"));
+        assert!(body.contains("(i64.const 0x10400)"));
+    }
+
+    #[test]
+    fn test_loop_stays_in_function() {
+        let code = [
+            0x0035_0513u32, // loop: addi a0, a0, 3
+            0xfff2_8293,    // addi t0, t0, -1
+            0xfe02_9ce3,    // bnez t0, loop
+        ];
+        let config = Config::new();
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        let mut linear = vec![0u8; 1 << 20];
+        let bytes: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut memory = GuestMemory::new(&mut map, &mut linear);
+        memory.init(0x10000, &bytes).unwrap();
+        let block = GuestBlock::read(&memory, false, 0x10000).unwrap();
+        assert!(is_loop(&block));
+
+        let mut uses = [0; 32];
+        for &(_, insn, _) in &block.insns {
+            count_uses(&mut uses, insn);
+        }
+        let body = translate_loop(&block, memory.map(), &config, &RegMap::from_uses(&uses), &mut BlockSlots::new(), &InlineCaches::new());
+        assert!(body.contains("(br $header)"));
+        let mut wat = String::new();
+        emit_block_module(&mut wat, memory.map(), &config, &body);
+        let wat = Optimizer::new(OptLevel::Optimized).verify(true).run(&wat, None).unwrap();
+        let module = Module::parse(&wat.replace("(func (export", "(func $run (export")).unwrap();
+        let globals = [
+            ("$x5".to_string(), 10),
+            ("$x10".to_string(), 7),
+            ("$instr_count".to_string(), 0),
+            ("$instr_budget".to_string(), i64::MAX),
+        ];
+        let (next, globals, _) = call_with(&module, "$run", vec![], &globals, &[]).unwrap();
+        // ten iterations, with the locals back in the globals on leaving
+        assert_eq!(next, vec![0x1000c]);
+        assert_eq!((globals["$x5"], globals["$x10"]), (0, 37));
+        assert_eq!(globals["$instr_count"], 30);
+
+        // as a block of the first tier it leaves at its back-edge once the
+        // loop is optimized
+        let tiered = Config::new().opt_level(OptLevel::Tiered { threshold: 10 });
+        let body = translate_block(&block, memory.map(), &tiered, &RegMap::new(&[]), &mut BlockSlots::new(), &InlineCaches::new(), None);
+        assert!(body.contains(&format!("(then {}", osr_check(0x10000))));
+        let mut wat = String::new();
+        emit_block_module(&mut wat, memory.map(), &tiered, &body);
+        assert!(wat.contains(OSR_IMPORTS));
+        Module::parse(&wat).unwrap();
+    }
+
+    #[test]
+    fn test_copy_loop_bulk_path() {
+        let code = [
+            0x0005_c283u32, // loop: lbu t0, 0(a1)
+            0x0055_0023,    // sb t0, 0(a0)
+            0x0015_0513,    // addi a0, a0, 1
+            0x0015_8593,    // addi a1, a1, 1
+            0xfff6_0613,    // addi a2, a2, -1
+            0xfe06_16e3,    // bnez a2, loop
+        ];
+        let config = Config::new();
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        map.map(0x20000..0x21000, Perm::RW).unwrap();
+        let mut linear = vec![0u8; 1 << 20];
+        let bytes: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut memory = GuestMemory::new(&mut map, &mut linear);
+        memory.init(0x10000, &bytes).unwrap();
+        let block = GuestBlock::read(&memory, false, 0x10000).unwrap();
+
+        let mut uses = [0; 32];
+        for insn in block.words() {
+            count_uses(&mut uses, insn);
+        }
+        let regs = RegMap::from_uses(&uses);
+        let body = translate_loop(&block, memory.map(), &config, &regs, &mut BlockSlots::new(), &InlineCaches::new());
+        assert!(body.contains("(memory.copy (local.get $bulk_dst) (local.get $bulk_src)"));
+        let mut wat = String::new();
+        emit_block_module(&mut wat, memory.map(), &config, &body);
+        assert!(wat.contains("(func $linear_range"));
+        let module = Module::parse(&wat.replace("(func (export", "(func $run (export")).unwrap();
+        let offset = |vaddr: u64| memory.map().vaddr_to_offset(vaddr).unwrap() as u64;
+        let data: Vec<(u64, u8)> = (0..16).map(|n| (offset(0x20000 + n), n as u8 + 1)).collect();
+        let run = |dst: i64| {
+            let globals = [
+                ("$x10".to_string(), dst),
+                ("$x11".to_string(), 0x20000),
+                ("$x12".to_string(), 16),
+                ("$instr_count".to_string(), 0),
+                ("$instr_budget".to_string(), i64::MAX),
+            ];
+            call_with(&module, "$run", vec![], &globals, &data).unwrap()
+        };
+        // one memory.copy leaves what sixteen iterations would
+        let (next, globals, written) = run(0x20100);
+        assert_eq!(next, vec![0x10018]);
+        assert_eq!((globals["$x10"], globals["$x11"], globals["$x12"], globals["$x5"]), (0x20110, 0x20010, 0, 16));
+        assert_eq!(globals["$instr_count"], 16 * 6);
+        assert!((0..16).all(|n| written[&offset(0x20100 + n)] == n as u8 + 1));
+        // a copy into its own source goes round the loop instead
+        let (next, globals, written) = run(0x20001);
+        assert_eq!(next, vec![0x10018]);
+        assert_eq!((globals["$x10"], globals["$x12"], globals["$instr_count"]), (0x20011, 0, 16 * 6));
+        assert!((1..17).all(|n| written[&offset(0x20000 + n)] == 1));
+
+        // every block of the loop gets the fast path, where memory is not checked
+        let body = translate_block(&block, memory.map(), &config, &regs, &mut BlockSlots::new(), &InlineCaches::new(), None);
+        assert!(body.contains(IDIOM_LOCALS) && body.contains("(memory.copy"));
+        let strict = Config::new().strict_memory(true);
+        let body = translate_block(&block, memory.map(), &strict, &regs, &mut BlockSlots::new(), &InlineCaches::new(), None);
+        assert!(!body.contains("$bulk_"));
+    }
+
+    #[test]
+    fn test_trace_side_exit() {
+        let code = [
+            0x0035_0513u32, // loop: addi a0, a0, 3
+            0xfff2_8293,    // addi t0, t0, -1
+            0xfe02_9ce3,    // bnez t0, loop
+            0x0015_0593,    // addi a1, a0, 1
+            0x0100_006f,    // j 0x10020
+        ];
+        let config = Config::new();
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        let mut linear = vec![0u8; 1 << 20];
+        let bytes: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut memory = GuestMemory::new(&mut map, &mut linear);
+        memory.init(0x10000, &bytes).unwrap();
+        // the profile saw the loop left, and nothing after the jump
+        let mut profile = BlockProfile::new();
+        profile.record(0x10000, 0x1000c);
+        profile.record(0x1000c, 0x10020);
+        profile.record(0x1000c, 0x10040);
+        let blocks = read_trace(&memory, false, &profile, 0x10000);
+        assert_eq!(blocks.iter().map(|block| block.start).collect::<Vec<_>>(), [0x10000, 0x1000c]);
+
+        let mut uses = [0; 32];
+        for insn in blocks.iter().flat_map(GuestBlock::words) {
+            count_uses(&mut uses, insn);
+        }
+        let body = translate_trace(&blocks, &config, &RegMap::from_uses(&uses), &mut BlockSlots::new(), &InlineCaches::new(), None);
+        let mut wat = String::new();
+        emit_block_module(&mut wat, memory.map(), &config, &body);
+        let module = Module::parse(&wat.replace("(func (export", "(func $run (export")).unwrap();
+        let run = |count: i64| {
+            let globals = [
+                ("$x5".to_string(), count),
+                ("$x10".to_string(), 7),
+                ("$instr_count".to_string(), 0),
+                ("$instr_budget".to_string(), i64::MAX),
+            ];
+            let (next, globals, _) = call_with(&module, "$run", vec![], &globals, &[]).unwrap();
+            (next, globals)
+        };
+        // going round the loop again leaves the trace back at its start
+        let (next, globals) = run(3);
+        assert_eq!(next, vec![0x10000]);
+        assert_eq!((globals["$x5"], globals["$x10"], globals["$instr_count"]), (2, 10, 3));
+        // leaving it goes on along the trace
+        let (next, globals) = run(1);
+        assert_eq!(next, vec![0x10020]);
+        assert_eq!((globals["$x5"], globals["$x10"], globals["$x11"], globals["$instr_count"]), (0, 10, 11, 5));
+    }
+}
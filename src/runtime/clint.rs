@@ -0,0 +1,136 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::middleend::address_map::MmioDevice;
+use crate::runtime::clock::{Clock, TIMEBASE_FREQ};
+use crate::runtime::csr::{InterruptSource, MIP_MSIP, MIP_MTIP};
+
+/// Where the CLINT sits on the qemu virt board
+pub const CLINT_BASE: u64 = 0x200_0000;
+pub const CLINT_SIZE: u64 = 0x1_0000;
+
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+/// The core local interruptor of a single hart: its machine timer, whose
+/// `mtime` ticks with the `time` CSR of the guest's clock, and its software
+/// interrupt
+#[derive(Debug)]
+pub struct Clint {
+    clock: Arc<Clock>,
+    msip: bool,
+    mtimecmp: u64,
+    /// What the guest wrote to `mtime` less the clock's time then
+    offset: u64,
+}
+
+impl Clint {
+    /// A CLINT whose timer does not fire until the guest sets `mtimecmp`
+    pub fn new(clock: Arc<Clock>) -> Self {
+        Self { clock, msip: false, mtimecmp: u64::MAX, offset: 0 }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.clock.rdtime().wrapping_add(self.offset)
+    }
+
+    pub fn mtimecmp(&self) -> u64 {
+        self.mtimecmp
+    }
+
+    /// The interrupts pending, as bits of `mip`
+    pub fn pending(&self) -> u64 {
+        let timer = if self.mtime() >= self.mtimecmp { MIP_MTIP } else { 0 };
+        let software = if self.msip { MIP_MSIP } else { 0 };
+        timer | software
+    }
+
+    /// When `mtime` reaches `mtimecmp`, none while the timer is off
+    pub fn deadline(&self) -> Option<Duration> {
+        if self.mtimecmp == u64::MAX {
+            return None;
+        }
+        let ticks = self.mtimecmp.saturating_sub(self.mtime()) as u128;
+        let nanos = (ticks * 1_000_000_000 / TIMEBASE_FREQ as u128).min(u64::MAX as u128) as u64;
+        Some(self.clock.monotonic().saturating_add(Duration::from_nanos(nanos)))
+    }
+
+    /// The register at `offset` and the offset of the access into it
+    fn register(&self, offset: u64) -> Option<(u64, u64)> {
+        match offset & !7 {
+            MSIP => Some((self.msip as u64, offset & 7)),
+            MTIMECMP => Some((self.mtimecmp, offset & 7)),
+            MTIME => Some((self.mtime(), offset & 7)),
+            _ => None,
+        }
+    }
+}
+
+/// The bytes of a `width` byte access at byte `shift` of a register
+fn mask(width: u8, shift: u64) -> u64 {
+    let bits = width as u64 * 8;
+    let mask = if bits >= 64 { u64::MAX } else { (1 << bits) - 1 };
+    mask << (shift * 8)
+}
+
+impl MmioDevice for Clint {
+    fn read(&mut self, offset: u64, width: u8) -> u64 {
+        match self.register(offset) {
+            Some((value, shift)) => (value & mask(width, shift)) >> (shift * 8),
+            None => 0,
+        }
+    }
+
+    /// Writes of 4 bytes, as RV32 kernels do, update half of a 64 bit
+    /// register
+    fn write(&mut self, offset: u64, width: u8, value: u64) {
+        let Some((old, shift)) = self.register(offset) else { return };
+        let mask = mask(width, shift);
+        let new = (old & !mask) | ((value << (shift * 8)) & mask);
+        match offset & !7 {
+            MSIP => self.msip = new & 1 != 0,
+            MTIMECMP => self.mtimecmp = new,
+            _ => self.offset = new.wrapping_sub(self.clock.rdtime()),
+        }
+    }
+}
+
+impl InterruptSource for Mutex<Clint> {
+    fn pending(&self) -> u64 {
+        self.lock().unwrap().pending()
+    }
+
+    fn deadline(&self) -> Option<Duration> {
+        self.lock().unwrap().deadline()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::clock::ClockMode;
+
+    #[test]
+    fn test_timer() {
+        let clock = Arc::new(Clock::new(ClockMode::Virtual { instructions_per_sec: 10_000_000 }));
+        let mut clint = Clint::new(clock.clone());
+        assert_eq!((clint.pending(), clint.deadline()), (0, None));
+
+        // the timer fires once mtime passes mtimecmp, written in halves
+        clint.write(MTIMECMP, 4, 100);
+        clint.write(MTIMECMP + 4, 4, 0);
+        assert_eq!(clint.read(MTIMECMP, 8), 100);
+        clock.retire(99);
+        assert_eq!((clint.read(MTIME, 8), clint.pending()), (99, 0));
+        assert_eq!(clint.deadline(), Some(Duration::from_nanos(100 * 100)));
+        clock.retire(1);
+        assert_eq!(clint.pending(), MIP_MTIP);
+        // setting mtime back clears it
+        clint.write(MTIME, 8, 0);
+        assert_eq!(clint.read(MTIME + 4, 4), 0);
+        assert_eq!(clint.pending(), 0);
+        clint.write(MSIP, 4, 1);
+        assert_eq!(clint.pending(), MIP_MSIP);
+    }
+}
@@ -0,0 +1,186 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::runtime::replay::Replay;
+
+/// Frequency of the `time` CSR, the 10 MHz timebase of the qemu virt board
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
+/// Wall clock time a virtual clock starts at, 2000-01-01T00:00:00Z
+pub const VIRTUAL_EPOCH: Duration = Duration::from_secs(946_684_800);
+
+/// Where guest time comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClockMode {
+    /// Host monotonic and wall clock time
+    #[default]
+    Host,
+    /// Time advanced only by retired guest instructions at the given rate,
+    /// so runs are reproducible regardless of host speed
+    Virtual { instructions_per_sec: u64 },
+}
+
+/// Time source of a guest, backing the clock syscalls as well as the
+/// `cycle`, `time` and `instret` counters
+#[derive(Debug)]
+pub struct Clock {
+    mode: ClockMode,
+    start: Instant,
+    /// Host wall clock at `start`
+    wall_start: Duration,
+    instret: AtomicU64,
+    /// Nanoseconds a virtual clock jumped ahead for sleeping guests
+    skipped: AtomicU64,
+    /// Where reads of the host clock are recorded or replayed from
+    replay: Option<Arc<Replay>>,
+}
+
+impl Clock {
+    pub fn new(mode: ClockMode) -> Self {
+        Self {
+            mode,
+            start: Instant::now(),
+            wall_start: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            instret: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            replay: None,
+        }
+    }
+
+    /// Record the host time the guest sees into `replay`, or replay it
+    pub fn replay(mut self, replay: Arc<Replay>) -> Self {
+        self.wall_start = replay.wall_start(self.wall_start);
+        self.replay = Some(replay);
+        self
+    }
+
+    /// Host time since the start
+    fn elapsed(&self) -> Duration {
+        match &self.replay {
+            Some(replay) => replay.time(|| self.start.elapsed()),
+            None => self.start.elapsed(),
+        }
+    }
+
+    pub fn mode(&self) -> ClockMode {
+        self.mode
+    }
+
+    /// Account `count` retired instructions, reported by the engine
+    pub fn retire(&self, count: u64) {
+        self.instret.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn instret(&self) -> u64 {
+        self.instret.load(Ordering::Relaxed)
+    }
+
+    /// Time since the guest started, its `CLOCK_MONOTONIC`
+    pub fn monotonic(&self) -> Duration {
+        match self.mode {
+            ClockMode::Host => self.elapsed(),
+            ClockMode::Virtual { .. } => self.executed() + Duration::from_nanos(self.skipped.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Virtual time spent executing instructions
+    fn executed(&self) -> Duration {
+        let ClockMode::Virtual { instructions_per_sec } = self.mode else {
+            return Duration::ZERO;
+        };
+        let nanos = self.instret() as u128 * 1_000_000_000 / instructions_per_sec.max(1) as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+
+    /// Processor time the guest used: the time spent executing instructions
+    /// for a virtual clock, and for the host clock the time since the start,
+    /// as the engine does not tell running from blocked guests apart
+    pub fn cpu_time(&self) -> Duration {
+        match self.mode {
+            ClockMode::Host => self.elapsed(),
+            ClockMode::Virtual { .. } => self.executed(),
+        }
+    }
+
+    /// Block until `monotonic()` reaches `deadline`. A virtual clock does not
+    /// wait, it jumps ahead to the deadline, so sleeping guests make progress
+    /// at once and runs stay reproducible.
+    pub fn sleep_until(&self, deadline: Duration) {
+        match self.mode {
+            ClockMode::Host => {
+                let now = self.monotonic();
+                if deadline > now {
+                    thread::sleep(deadline - now);
+                }
+            }
+            ClockMode::Virtual { .. } => {
+                let needed = deadline.saturating_sub(self.executed()).as_nanos() as u64;
+                self.skipped.fetch_max(needed, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Time since the unix epoch, the guest's `CLOCK_REALTIME`
+    pub fn realtime(&self) -> Duration {
+        match self.mode {
+            ClockMode::Host => self.wall_start + self.elapsed(),
+            ClockMode::Virtual { .. } => VIRTUAL_EPOCH + self.monotonic(),
+        }
+    }
+
+    /// Value of the `time` CSR, ticking at `TIMEBASE_FREQ`
+    pub fn rdtime(&self) -> u64 {
+        (self.monotonic().as_nanos() * TIMEBASE_FREQ as u128 / 1_000_000_000) as u64
+    }
+
+    /// Value of the `cycle` CSR, one cycle per instruction for a virtual
+    /// clock and per nanosecond, a nominal 1 GHz hart, for the host clock
+    pub fn rdcycle(&self) -> u64 {
+        match self.mode {
+            ClockMode::Host => self.elapsed().as_nanos() as u64,
+            ClockMode::Virtual { .. } => self.instret(),
+        }
+    }
+
+    pub fn rdinstret(&self) -> u64 {
+        self.instret()
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(ClockMode::Host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = Clock::new(ClockMode::Virtual {
+            instructions_per_sec: 1_000_000,
+        });
+        assert_eq!(clock.monotonic(), Duration::ZERO);
+        assert_eq!(clock.realtime(), VIRTUAL_EPOCH);
+        clock.retire(2_500_000);
+        assert_eq!(clock.monotonic(), Duration::from_millis(2500));
+        assert_eq!(clock.rdtime(), 25_000_000);
+        assert_eq!(clock.rdcycle(), 2_500_000);
+        assert_eq!(clock.rdinstret(), 2_500_000);
+        // sleeping jumps ahead without touching the counters
+        clock.sleep_until(Duration::from_secs(10));
+        assert_eq!(clock.monotonic(), Duration::from_secs(10));
+        assert_eq!(clock.rdinstret(), 2_500_000);
+        assert_eq!(clock.cpu_time(), Duration::from_millis(2500));
+        clock.sleep_until(Duration::from_secs(1));
+        assert_eq!(clock.monotonic(), Duration::from_secs(10));
+
+        let host = Clock::default();
+        let before = host.monotonic();
+        assert!(host.monotonic() >= before);
+        assert!(host.realtime() > VIRTUAL_EPOCH);
+    }
+}
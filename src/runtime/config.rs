@@ -0,0 +1,333 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::runtime::clock::ClockMode;
+use crate::runtime::pgo::BlockProfile;
+use crate::runtime::replay::Replay;
+use crate::runtime::syscall::net::{NetPolicy, NetRule};
+use crate::runtime::syscall::policy::SyscallPolicy;
+use crate::runtime::syscall::Preopen;
+use crate::tools::backtrace::Unwinder;
+use crate::tools::perf::Profiler;
+use crate::tools::trace::Tracer;
+
+/// Address space layout randomization mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Aslr {
+    /// Fixed, linux-like default placement
+    #[default]
+    Disabled,
+    /// Fresh randomization from host entropy on every run
+    Random,
+    /// Randomized but reproducible placement derived from the seed
+    Seeded(u64),
+}
+
+/// Compiler tier the backend turns the translated module into native code
+/// with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    /// Singlepass for the whole module: quick to compile, slow to run
+    Baseline,
+    /// Optimizing Cranelift for the whole module
+    #[default]
+    Optimized,
+    /// Singlepass first, then functions that ran `threshold` times are
+    /// recompiled with Cranelift
+    Tiered { threshold: u64 },
+}
+
+impl OptLevel {
+    /// Whether a function that ran `count` times is due for the optimizing
+    /// tier
+    pub fn is_hot(self, count: u64) -> bool {
+        matches!(self, OptLevel::Tiered { threshold } if count >= threshold)
+    }
+}
+
+/// Per-run configuration of the guest
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub aslr: Aslr,
+    /// Check every guest access against the page permissions and report
+    /// violations as guest faults instead of silently allowing them
+    pub strict_memory: bool,
+    /// Route guest accesses through the Sv39 page table walker so guests can
+    /// run with their own paging instead of the flat mapping
+    pub mmu: bool,
+    /// Host directories the guest may access, nothing else of the host
+    /// filesystem is visible to it
+    pub preopens: Vec<Preopen>,
+    /// In-memory files placed in the guest's VFS, visible even when no
+    /// host directory is preopened
+    pub files: Vec<(PathBuf, Vec<u8>)>,
+    /// Host addresses guest sockets may use, nothing is reachable by default
+    pub net: NetPolicy,
+    /// Seed of the guest's randomness, `None` for the host random source.
+    /// A seeded run sees the same getrandom and /dev/urandom bytes each time.
+    pub seed: Option<u64>,
+    /// Host time or a virtual clock driven by retired instructions
+    pub clock: ClockMode,
+    /// Cycles the `cycle` counter charges each retired instruction, `None`
+    /// for the clock's own cycles
+    pub cycles_per_instruction: Option<f64>,
+    /// Read CSRs the VM does not implement as zero and drop writes to them
+    /// and to read only CSRs, like earlier versions, instead of raising an
+    /// illegal instruction exception
+    pub permissive_csrs: bool,
+    /// The guest's argv, starting with the program name
+    pub args: Vec<String>,
+    /// The guest's environment, empty unless set
+    pub envs: Vec<(String, String)>,
+    /// Syscalls the guest may make, all of them by default
+    pub syscalls: SyscallPolicy,
+    /// Log the number of every syscall the guest makes into
+    /// `SyscallEnv::syscall_log`, to compare runs against other emulators
+    pub record_syscalls: bool,
+    /// Count the calls and host time of each syscall into
+    /// `SyscallEnv::syscall_stats`
+    pub syscall_stats: bool,
+    /// Record the nondeterministic inputs of the run, or replay them
+    pub replay: Option<Arc<Replay>>,
+    /// Log a backtrace of the guest when it traps with no handler of its
+    /// own or makes a syscall the VM does not know
+    pub backtrace: Option<Arc<Unwinder>>,
+    /// Time the stages of the run, from decoding to the syscalls, into
+    /// the profiler
+    pub profiler: Option<Profiler>,
+    /// Write the blocks, instructions and syscalls of the guest out as
+    /// JSON Lines
+    pub trace: Option<Tracer>,
+    /// Present the standard streams as an 80x24 terminal, otherwise
+    /// terminal ioctls on them fail with ENOTTY like on a pipe
+    pub tty: bool,
+    /// Compiler tier of the translated code
+    pub opt_level: OptLevel,
+    /// Stop the guest after this many instructions
+    pub max_instructions: Option<u64>,
+    /// Stop the guest once it ran this long on the host
+    pub max_wall_time: Option<Duration>,
+    /// The blocks an earlier run of the guest ran, compiled together
+    /// before they run, laid out by how often they ran, and along whose
+    /// traces the blocks are compiled, see `BlockProfile::trace`
+    pub block_profile: Option<Arc<BlockProfile>>,
+    /// Record the blocks compiled code runs into the profile, for later
+    /// runs to compile with. Blocks are not linked while recording, so each
+    /// one is seen.
+    pub record_blocks: Option<Arc<Mutex<BlockProfile>>>,
+    /// Directory the compiled modules of guest binaries are cached in
+    /// across runs, none to compile every time
+    pub cache_dir: Option<PathBuf>,
+    /// Trace every instruction the interpreter runs, every block compiled
+    /// code runs, which are then not linked, and every syscall, at the trace
+    /// level, and count the instructions of translated code one by one.
+    /// Without the `debug-runtime` feature there is no instrumentation to
+    /// turn on.
+    pub debug_runtime: bool,
+    /// Check the optimizer output of every hot loop against its input on
+    /// a reference evaluator, and fail on any difference
+    pub verify_optimizer: bool,
+    /// Let the optimizer make only this many changes, to bisect a guest it
+    /// breaks to the change breaking it
+    pub optimizer_fuel: Option<u64>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn aslr(mut self, aslr: Aslr) -> Self {
+        self.aslr = aslr;
+        self
+    }
+
+    pub fn strict_memory(mut self, strict_memory: bool) -> Self {
+        self.strict_memory = strict_memory;
+        self
+    }
+
+    pub fn mmu(mut self, mmu: bool) -> Self {
+        self.mmu = mmu;
+        self
+    }
+
+    /// Make the host directory `host` visible to the guest as `guest`
+    pub fn preopen(mut self, guest: impl Into<PathBuf>, host: impl Into<PathBuf>) -> Self {
+        self.preopens.push(Preopen {
+            guest: guest.into(),
+            host: host.into(),
+        });
+        self
+    }
+
+    /// Provide an in-memory file at `guest` with the initial `data`
+    pub fn file(mut self, guest: impl Into<PathBuf>, data: impl Into<Vec<u8>>) -> Self {
+        self.files.push((guest.into(), data.into()));
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn clock(mut self, clock: ClockMode) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn cycles_per_instruction(mut self, cpi: f64) -> Self {
+        self.cycles_per_instruction = Some(cpi);
+        self
+    }
+
+    pub fn permissive_csrs(mut self, permissive: bool) -> Self {
+        self.permissive_csrs = permissive;
+        self
+    }
+
+    pub fn tty(mut self, tty: bool) -> Self {
+        self.tty = tty;
+        self
+    }
+
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    pub fn max_instructions(mut self, max: u64) -> Self {
+        self.max_instructions = Some(max);
+        self
+    }
+
+    pub fn max_wall_time(mut self, max: Duration) -> Self {
+        self.max_wall_time = Some(max);
+        self
+    }
+
+    pub fn block_profile(mut self, profile: BlockProfile) -> Self {
+        self.block_profile = Some(Arc::new(profile));
+        self
+    }
+
+    pub fn record_blocks(mut self, profile: Arc<Mutex<BlockProfile>>) -> Self {
+        self.record_blocks = Some(profile);
+        self
+    }
+
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    pub fn debug_runtime(mut self, debug_runtime: bool) -> Self {
+        self.debug_runtime = debug_runtime;
+        self
+    }
+
+    pub fn verify_optimizer(mut self, verify: bool) -> Self {
+        self.verify_optimizer = verify;
+        self
+    }
+
+    pub fn optimizer_fuel(mut self, fuel: u64) -> Self {
+        self.optimizer_fuel = Some(fuel);
+        self
+    }
+
+    /// Whether to instrument the run, always false in builds without the
+    /// `debug-runtime` feature so the checks fold away
+    pub fn instrumented(&self) -> bool {
+        cfg!(feature = "debug-runtime") && self.debug_runtime
+    }
+
+    pub fn record_syscalls(mut self, record: bool) -> Self {
+        self.record_syscalls = record;
+        self
+    }
+
+    pub fn syscall_stats(mut self, stats: bool) -> Self {
+        self.syscall_stats = stats;
+        self
+    }
+
+    /// Record the run into `replay`, or replay it from there
+    pub fn replay(mut self, replay: Arc<Replay>) -> Self {
+        self.replay = Some(replay);
+        self
+    }
+
+    /// Unwind the guest stack with `unwinder` when the guest crashes
+    pub fn backtrace(mut self, unwinder: Arc<Unwinder>) -> Self {
+        self.backtrace = Some(unwinder);
+        self
+    }
+
+    /// Report the time of each stage into `profiler`, which the threads
+    /// and forks of the guest share
+    pub fn profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = Some(profiler);
+        self
+    }
+
+    /// Trace the guest into `tracer`, the events of each kind it records
+    pub fn trace(mut self, tracer: Tracer) -> Self {
+        self.trace = Some(tracer);
+        self
+    }
+
+    /// Confine the guest to the syscalls `policy` allows
+    pub fn syscall_policy(mut self, policy: SyscallPolicy) -> Self {
+        self.syscalls = policy;
+        self
+    }
+
+    /// Set the guest's argv, the first argument being the program name
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Add environment variables of the guest
+    pub fn envs<I, K, V>(mut self, envs: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.envs.extend(envs.into_iter().map(|(key, value)| (key.into(), value.into())));
+        self
+    }
+
+    /// Let guest sockets connect or send to addresses matching `rule`
+    pub fn allow_connect(mut self, rule: NetRule) -> Self {
+        self.net.connect.push(rule);
+        self
+    }
+
+    /// Let guest sockets bind to addresses matching `rule`
+    pub fn allow_bind(mut self, rule: NetRule) -> Self {
+        self.net.bind.push(rule);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiering() {
+        assert!(!OptLevel::Optimized.is_hot(u64::MAX));
+        let tiered = Config::new().opt_level(OptLevel::Tiered { threshold: 1000 }).opt_level;
+        assert!(!tiered.is_hot(999));
+        assert!(tiered.is_hot(1000));
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+/// Bytes of guest code one bitmap page covers
+const PAGE: u64 = 4096;
+/// A bit per halfword, where compressed instructions may start
+const WORDS: usize = (PAGE / 2 / 64) as usize;
+
+/// The guest instructions that ran, as a bitmap keyed by pc. Pages of the
+/// bitmap are only allocated for code that ran, so sparse address spaces
+/// cost little. Merging the coverage of each input tells a fuzzer driving
+/// guests through the VM which inputs reached new code.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pages: BTreeMap<u64, Box<[u64; WORDS]>>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The instruction at `pc` ran
+    pub fn record(&mut self, pc: u64) {
+        let (page, word, bit) = locate(pc);
+        self.pages.entry(page).or_insert_with(|| Box::new([0; WORDS]))[word] |= bit;
+    }
+
+    pub fn contains(&self, pc: u64) -> bool {
+        let (page, word, bit) = locate(pc);
+        self.pages.get(&page).is_some_and(|bits| bits[word] & bit != 0)
+    }
+
+    /// How many instructions ran
+    pub fn len(&self) -> usize {
+        self.pages.values().flat_map(|bits| bits.iter()).map(|word| word.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add the instructions `other` covers, returning how many of them are
+    /// new
+    pub fn merge(&mut self, other: &Coverage) -> usize {
+        let mut new = 0;
+        for (&page, other) in &other.pages {
+            let bits = self.pages.entry(page).or_insert_with(|| Box::new([0; WORDS]));
+            for (word, &other) in bits.iter_mut().zip(other.iter()) {
+                new += (other & !*word).count_ones() as usize;
+                *word |= other;
+            }
+        }
+        new
+    }
+
+    /// The pcs of the instructions that ran, in ascending order
+    pub fn pcs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.pages.iter().flat_map(|(&page, bits)| {
+            bits.iter().enumerate().flat_map(move |(word, &value)| {
+                (0..64).filter(move |bit| value & 1 << bit != 0).map(move |bit| page + (word as u64 * 64 + bit) * 2)
+            })
+        })
+    }
+
+    /// One pc per line in hex, the input `addr2line -e <elf>` maps to
+    /// source lines
+    pub fn write_addresses(&self, mut out: impl Write) -> io::Result<()> {
+        for pc in self.pcs() {
+            writeln!(out, "{:#x}", pc)?;
+        }
+        Ok(())
+    }
+
+    /// Write the coverage as an lcov tracefile, with `resolve` mapping a pc
+    /// to its source file and line, as the DWARF line table of the guest
+    /// binary does. Only the lines that ran are known, so every line listed
+    /// is hit.
+    pub fn write_lcov(&self, mut out: impl Write, mut resolve: impl FnMut(u64) -> Option<(String, u32)>) -> io::Result<()> {
+        let mut files: BTreeMap<String, BTreeSet<u32>> = BTreeMap::new();
+        for (file, line) in self.pcs().filter_map(&mut resolve) {
+            files.entry(file).or_default().insert(line);
+        }
+        writeln!(out, "TN:")?;
+        for (file, lines) in &files {
+            writeln!(out, "SF:{}", file)?;
+            for line in lines {
+                writeln!(out, "DA:{},1", line)?;
+            }
+            writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines.len(), lines.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// The page of the bitmap holding `pc`, and its word and bit in it
+fn locate(pc: u64) -> (u64, usize, u64) {
+    let halfword = (pc % PAGE) / 2;
+    (pc - pc % PAGE, (halfword / 64) as usize, 1 << (halfword % 64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_merge_export() {
+        let mut coverage = Coverage::new();
+        for pc in [0x10000, 0x10004, 0x10006, 0x7fff_f000] {
+            coverage.record(pc);
+        }
+        coverage.record(0x10004);
+        assert_eq!(coverage.len(), 4);
+        assert!(coverage.contains(0x10006) && !coverage.contains(0x10002));
+        assert_eq!(coverage.pcs().collect::<Vec<_>>(), [0x10000, 0x10004, 0x10006, 0x7fff_f000]);
+
+        let mut other = Coverage::new();
+        other.record(0x10004);
+        other.record(0x10008);
+        assert_eq!(coverage.merge(&other), 1);
+        assert_eq!(coverage.merge(&other), 0);
+
+        let mut addresses = Vec::new();
+        other.write_addresses(&mut addresses).unwrap();
+        assert_eq!(String::from_utf8(addresses).unwrap(), "0x10004\n0x10008\n");
+        let mut lcov = Vec::new();
+        coverage
+            .write_lcov(&mut lcov, |pc| (pc < 0x20000).then(|| ("main.c".to_string(), (pc as u32 - 0x10000) / 4 + 1)))
+            .unwrap();
+        assert_eq!(String::from_utf8(lcov).unwrap(), "TN:\nSF:main.c\nDA:1,1\nDA:2,1\nDA:3,1\nLF:3\nLH:3\nend_of_record\n");
+    }
+}
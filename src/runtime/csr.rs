@@ -1 +1,817 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use crate::frontend::cache::CodeCache;
+use crate::middleend::address_map::{Access, GuestFault};
+use crate::runtime::clock::Clock;
+use crate::runtime::config::Config;
+
+/// Accrued exception flags of `fflags`, raised by floating point
+/// instructions and only cleared by the guest
+pub const FFLAG_NX: u32 = 1 << 0;
+pub const FFLAG_UF: u32 = 1 << 1;
+pub const FFLAG_OF: u32 = 1 << 2;
+pub const FFLAG_DZ: u32 = 1 << 3;
+pub const FFLAG_NV: u32 = 1 << 4;
+
+const FFLAGS_MASK: u32 = 0x1f;
+const FRM_SHIFT: u32 = 5;
+const FRM_MASK: u32 = 7;
+/// The `rm` field of an instruction asking for the rounding mode in `frm`
+const RM_DYNAMIC: u32 = 7;
+
+/// Bits of `mstatus`, of which `sstatus` shows the supervisor ones
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 3 << MSTATUS_MPP_SHIFT;
+/// Supervisor mode may load and store user pages
+pub const MSTATUS_SUM: u64 = 1 << 18;
+/// Loads may read pages that are only executable
+pub const MSTATUS_MXR: u64 = 1 << 19;
+const MSTATUS_MPP_SHIFT: u64 = 11;
+const MSTATUS_MASK: u64 =
+    MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP | MSTATUS_MPP | MSTATUS_SUM | MSTATUS_MXR;
+const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+/// Bits of `mip` and `mie`, for the machine software, timer and external
+/// interrupts and the supervisor external one
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_MEIP: u64 = 1 << 11;
+const MIE_MASK: u64 = MIP_MSIP | MIP_MTIP | MIP_MEIP | MIP_SEIP;
+/// The bit of `mcause` telling an interrupt
+const MCAUSE_INTERRUPT: u64 = 1 << 63;
+
+/// The hardware performance counters `3` to `31`
+pub const HPM_COUNTERS: usize = 29;
+const HPM_FIRST: usize = 3;
+
+/// Events `mhpmevent` selects for its counter, 0 counting nothing
+pub const HPM_EVENT_LOADS: u64 = 1;
+pub const HPM_EVENT_STORES: u64 = 2;
+/// Conditional branches, taken or not
+pub const HPM_EVENT_BRANCHES: u64 = 3;
+/// Lookups of decoded instructions the code cache did not have
+pub const HPM_EVENT_CODE_CACHE_MISSES: u64 = 4;
+
+/// The CSRs the VM implements
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CsrAddress {
+    Fflags,
+    Frm,
+    Fcsr,
+    Cycle,
+    Time,
+    InstRet,
+    /// `hpmcounter3` to `hpmcounter31`, the read only view of
+    /// `mhpmcounter` of the same number
+    HpmCounter(usize),
+    /// `mhpmcounter3` to `mhpmcounter31`
+    MhpmCounter(usize),
+    /// `mhpmevent3` to `mhpmevent31`, the event each counter counts
+    MhpmEvent(usize),
+    Sstatus,
+    Sscratch,
+    Sepc,
+    Satp,
+    Mstatus,
+    Mie,
+    Mtvec,
+    Mscratch,
+    Mepc,
+    Mcause,
+    Mtval,
+    Mip,
+}
+
+impl CsrAddress {
+    pub fn from_number(number: u32) -> Option<Self> {
+        let counter = (number & 0x1f) as usize;
+        Some(match number {
+            0x001 => CsrAddress::Fflags,
+            0x002 => CsrAddress::Frm,
+            0x003 => CsrAddress::Fcsr,
+            0xc00 => CsrAddress::Cycle,
+            0xc01 => CsrAddress::Time,
+            0xc02 => CsrAddress::InstRet,
+            0xc03..=0xc1f => CsrAddress::HpmCounter(counter),
+            0xb03..=0xb1f => CsrAddress::MhpmCounter(counter),
+            0x323..=0x33f => CsrAddress::MhpmEvent(counter),
+            0x100 => CsrAddress::Sstatus,
+            0x140 => CsrAddress::Sscratch,
+            0x141 => CsrAddress::Sepc,
+            0x180 => CsrAddress::Satp,
+            0x300 => CsrAddress::Mstatus,
+            0x304 => CsrAddress::Mie,
+            0x305 => CsrAddress::Mtvec,
+            0x340 => CsrAddress::Mscratch,
+            0x341 => CsrAddress::Mepc,
+            0x342 => CsrAddress::Mcause,
+            0x343 => CsrAddress::Mtval,
+            0x344 => CsrAddress::Mip,
+            _ => return None,
+        })
+    }
+
+    pub fn number(self) -> u32 {
+        match self {
+            CsrAddress::Fflags => 0x001,
+            CsrAddress::Frm => 0x002,
+            CsrAddress::Fcsr => 0x003,
+            CsrAddress::Cycle => 0xc00,
+            CsrAddress::Time => 0xc01,
+            CsrAddress::InstRet => 0xc02,
+            CsrAddress::HpmCounter(counter) => 0xc00 + counter as u32,
+            CsrAddress::MhpmCounter(counter) => 0xb00 + counter as u32,
+            CsrAddress::MhpmEvent(counter) => 0x320 + counter as u32,
+            CsrAddress::Sstatus => 0x100,
+            CsrAddress::Sscratch => 0x140,
+            CsrAddress::Sepc => 0x141,
+            CsrAddress::Satp => 0x180,
+            CsrAddress::Mstatus => 0x300,
+            CsrAddress::Mie => 0x304,
+            CsrAddress::Mtvec => 0x305,
+            CsrAddress::Mscratch => 0x340,
+            CsrAddress::Mepc => 0x341,
+            CsrAddress::Mcause => 0x342,
+            CsrAddress::Mtval => 0x343,
+            CsrAddress::Mip => 0x344,
+        }
+    }
+
+    /// The lowest privilege the CSR may be accessed at, from bits 8 and 9
+    /// of its number. The VM has no hypervisor mode, its CSRs are machine
+    /// mode ones.
+    pub fn privilege(self) -> Privilege {
+        Privilege::from_bits((self.number() >> 8) as u64).unwrap_or(Privilege::Machine)
+    }
+
+    /// Whether the CSR may only be read, as are all numbered `0xc00` and up
+    /// to `0xfff`
+    pub fn read_only(self) -> bool {
+        self.number() >> 10 == 3
+    }
+}
+
+/// Privilege level of a hart
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum Privilege {
+    /// Where a Linux program runs, and so where a hart starts
+    #[default]
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits & 3 {
+            0 => Some(Privilege::User),
+            1 => Some(Privilege::Supervisor),
+            3 => Some(Privilege::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// The synchronous exceptions the VM raises, by their `mcause`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreMisaligned = 6,
+    StoreAccessFault = 7,
+    EcallFromU = 8,
+    EcallFromS = 9,
+    EcallFromM = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    /// The exception of an ecall at `privilege`
+    pub fn ecall(privilege: Privilege) -> Self {
+        match privilege {
+            Privilege::User => Exception::EcallFromU,
+            Privilege::Supervisor => Exception::EcallFromS,
+            Privilege::Machine => Exception::EcallFromM,
+        }
+    }
+
+    /// The exception of a guest memory fault, with the address for `mtval`
+    pub fn from_fault(fault: &GuestFault) -> (Self, u64) {
+        // the exceptions of a fetch, a load and a store
+        let (vaddr, access, exceptions) = match *fault {
+            GuestFault::Unmapped { vaddr, access } | GuestFault::Protection { vaddr, access, .. } => (
+                vaddr,
+                access,
+                [Exception::InstructionAccessFault, Exception::LoadAccessFault, Exception::StoreAccessFault],
+            ),
+            GuestFault::PageFault { vaddr, access } => {
+                (vaddr, access, [Exception::InstructionPageFault, Exception::LoadPageFault, Exception::StorePageFault])
+            }
+            GuestFault::Misaligned { vaddr, access } => {
+                (vaddr, access, [Exception::InstructionMisaligned, Exception::LoadMisaligned, Exception::StoreMisaligned])
+            }
+        };
+        let exception = match access {
+            Access::Exec => exceptions[0],
+            Access::Read => exceptions[1],
+            Access::Write => exceptions[2],
+        };
+        (exception, vaddr)
+    }
+}
+
+/// The interrupts of the devices the VM models, by their `mcause` code and
+/// bit in `mip`, from the highest priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    MachineExternal = 11,
+    MachineSoftware = 3,
+    MachineTimer = 7,
+    /// Taken in machine mode as well, nothing is delegated
+    SupervisorExternal = 9,
+}
+
+/// A device raising interrupts
+pub trait InterruptSource: Debug + Send + Sync {
+    /// The interrupts it has pending, as bits of `mip`
+    fn pending(&self) -> u64;
+
+    /// When, on the guest's monotonic clock, it raises its next interrupt,
+    /// for sources that know ahead like timers
+    fn deadline(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Something the VM counts as it runs the guest, for the hardware
+/// performance counters to count
+pub trait EventSource: Debug + Send + Sync {
+    fn count(&self) -> u64;
+}
+
+impl EventSource for AtomicU64 {
+    fn count(&self) -> u64 {
+        self.load(Ordering::Relaxed)
+    }
+}
+
+impl EventSource for Mutex<CodeCache> {
+    fn count(&self) -> u64 {
+        self.lock().unwrap().misses()
+    }
+}
+
+/// Rounding mode of a floating point instruction, its own or `frm`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// To nearest, ties to even
+    Rne = 0,
+    /// Towards zero
+    Rtz = 1,
+    /// Down, towards -inf
+    Rdn = 2,
+    /// Up, towards +inf
+    Rup = 3,
+    /// To nearest, ties to max magnitude
+    Rmm = 4,
+}
+
+impl RoundingMode {
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        Some(match bits {
+            0 => RoundingMode::Rne,
+            1 => RoundingMode::Rtz,
+            2 => RoundingMode::Rdn,
+            3 => RoundingMode::Rup,
+            4 => RoundingMode::Rmm,
+            _ => return None,
+        })
+    }
+}
+
+/// A hardware performance counter, kept as the offset of its value from
+/// the count of its event so it runs without the VM touching it. In a state
+/// `CsrManager::save` made the offset is from no events, the value itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PerfCounter {
+    /// What `mhpmevent` selects
+    pub event: u64,
+    pub offset: u64,
+}
+
+/// The CSR state of a hart kept with its registers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CsrState {
+    /// `fflags` in the low 5 bits and `frm` in the 3 above, what the
+    /// `fcsr` global of translated code holds
+    pub fcsr: u32,
+    pub hpm: [PerfCounter; HPM_COUNTERS],
+    /// The privilege the hart runs at, user mode unless the guest is a
+    /// kernel started in machine mode
+    pub privilege: Privilege,
+    pub mstatus: u64,
+    pub mie: u64,
+    /// The interrupts the devices have pending, which the guest only reads
+    pub mip: u64,
+    /// Base of the trap handler in the upper bits, and whether interrupts
+    /// are vectored in bit 0
+    pub mtvec: u64,
+    pub mscratch: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+    pub sscratch: u64,
+    pub sepc: u64,
+    /// The translation mode and root page table of the MMU
+    pub satp: u64,
+}
+
+impl CsrState {
+    pub fn fflags(&self) -> u32 {
+        self.fcsr & FFLAGS_MASK
+    }
+
+    pub fn frm(&self) -> u32 {
+        (self.fcsr >> FRM_SHIFT) & FRM_MASK
+    }
+
+    pub fn set_fflags(&mut self, flags: u32) {
+        self.fcsr = (self.fcsr & !FFLAGS_MASK) | (flags & FFLAGS_MASK);
+    }
+
+    pub fn set_frm(&mut self, frm: u32) {
+        self.fcsr = (self.fcsr & !(FRM_MASK << FRM_SHIFT)) | ((frm & FRM_MASK) << FRM_SHIFT);
+    }
+
+    /// Raise the `FFLAG_*` exception flags an instruction ran into, on top
+    /// of those raised before
+    pub fn accrue(&mut self, flags: u32) {
+        self.fcsr |= flags & FFLAGS_MASK;
+    }
+
+    /// Whether the guest installed a trap handler at `mtvec`, to which its
+    /// exceptions go instead of ending it. A Linux program never does, the
+    /// VM handles its ecalls and faults.
+    pub fn handles_traps(&self) -> bool {
+        self.mtvec != 0
+    }
+
+    /// Take `exception` of the instruction at `pc` into machine mode:
+    /// save where and why in `mepc`, `mcause` and `mtval`, and the
+    /// privilege and interrupt enable in `mstatus`, and get the pc of the
+    /// handler. Exceptions go to the base of `mtvec` even when vectored.
+    pub fn trap(&mut self, exception: Exception, pc: u64, tval: u64) -> u64 {
+        self.enter_handler(exception as u64, pc, tval);
+        self.mtvec & !3
+    }
+
+    /// The interrupt to take before the instruction at hand: the most
+    /// important one pending and enabled in `mie`, if the hart is below
+    /// machine mode or has `mstatus.MIE` set
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        if self.privilege == Privilege::Machine && self.mstatus & MSTATUS_MIE == 0 {
+            return None;
+        }
+        let pending = self.mip & self.mie;
+        [Interrupt::MachineExternal, Interrupt::MachineSoftware, Interrupt::MachineTimer, Interrupt::SupervisorExternal]
+            .into_iter()
+            .find(|&interrupt| pending & 1 << interrupt as u64 != 0)
+    }
+
+    /// Take `interrupt` before the instruction at `pc` like `trap` does an
+    /// exception, to the handler of the interrupt when `mtvec` is vectored
+    pub fn interrupt(&mut self, interrupt: Interrupt, pc: u64) -> u64 {
+        self.enter_handler(MCAUSE_INTERRUPT | interrupt as u64, pc, 0);
+        match self.mtvec & 3 {
+            1 => (self.mtvec & !3) + 4 * interrupt as u64,
+            _ => self.mtvec & !3,
+        }
+    }
+
+    fn enter_handler(&mut self, cause: u64, pc: u64, tval: u64) {
+        self.mepc = pc;
+        self.mcause = cause;
+        self.mtval = tval;
+        let mpie = if self.mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+        let mpp = (self.privilege as u64) << MSTATUS_MPP_SHIFT;
+        self.mstatus = (self.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp;
+        self.privilege = Privilege::Machine;
+    }
+
+    /// mret: return from a machine mode trap handler to `mepc`, at the
+    /// privilege `mstatus.MPP` saved, with the interrupts enabled as they
+    /// were. `None` below machine mode, where mret is illegal.
+    pub fn mret(&mut self) -> Option<u64> {
+        if self.privilege < Privilege::Machine {
+            return None;
+        }
+        self.privilege = Privilege::from_bits(self.mstatus >> MSTATUS_MPP_SHIFT).unwrap_or_default();
+        let mie = if self.mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        self.mstatus = (self.mstatus & !(MSTATUS_MIE | MSTATUS_MPP)) | mie | MSTATUS_MPIE;
+        Some(self.mepc)
+    }
+
+    /// sret, the same from a supervisor mode trap handler to `sepc`
+    pub fn sret(&mut self) -> Option<u64> {
+        if self.privilege < Privilege::Supervisor {
+            return None;
+        }
+        self.privilege = if self.mstatus & MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User };
+        let sie = if self.mstatus & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+        self.mstatus = (self.mstatus & !(MSTATUS_SIE | MSTATUS_SPP)) | sie | MSTATUS_SPIE;
+        Some(self.sepc)
+    }
+
+    /// The rounding mode of an instruction with the `rm` field `rm`: its
+    /// own, or `frm` for the dynamic mode. `None` for the reserved modes,
+    /// with which the instruction is illegal.
+    pub fn rounding_mode(&self, rm: u32) -> Option<RoundingMode> {
+        match rm {
+            RM_DYNAMIC => RoundingMode::from_bits(self.frm()),
+            rm => RoundingMode::from_bits(rm),
+        }
+    }
+}
+
+/// What a CSR instruction does with its source, by `funct3 & 3`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrOp {
+    /// csrrw and csrrwi
+    Write,
+    /// csrrs and csrrsi, set the bits of the source
+    Set,
+    /// csrrc and csrrci, clear the bits of the source
+    Clear,
+}
+
+impl CsrOp {
+    pub fn from_funct3(funct3: u32) -> Option<Self> {
+        match funct3 & 3 {
+            1 => Some(CsrOp::Write),
+            2 => Some(CsrOp::Set),
+            3 => Some(CsrOp::Clear),
+            _ => None,
+        }
+    }
+}
+
+/// Runs the CSR instructions of a guest on its `CsrState`, reading the
+/// counters from its clock and its event sources
+#[derive(Debug, Clone, Default)]
+pub struct CsrManager {
+    /// The model of `cycle`: a fixed number of cycles per retired
+    /// instruction, or the clock's cycles
+    cpi: Option<f64>,
+    events: HashMap<u64, Arc<dyn EventSource>>,
+    /// Ignore accesses to unimplemented CSRs and writes to read only ones
+    permissive: bool,
+}
+
+impl CsrManager {
+    pub fn new(config: &Config) -> Self {
+        Self { cpi: config.cycles_per_instruction, events: HashMap::new(), permissive: config.permissive_csrs }
+    }
+
+    /// Count `event` with `source`, for the counters selecting it
+    pub fn event_source(mut self, event: u64, source: Arc<dyn EventSource>) -> Self {
+        self.events.insert(event, source);
+        self
+    }
+
+    /// How often `event` happened so far, 0 for events nothing counts
+    pub fn events(&self, event: u64) -> u64 {
+        self.events.get(&event).map_or(0, |source| source.count())
+    }
+
+    /// Value of `mhpmcounter<counter>`
+    pub fn hpm_counter(&self, state: &CsrState, counter: usize) -> u64 {
+        let hpm = state.hpm[counter - HPM_FIRST];
+        self.events(hpm.event).wrapping_add(hpm.offset)
+    }
+
+    /// Copy of `state` to keep, with the performance counters at their
+    /// values rather than relative to the events counted, so it loads into
+    /// a VM that counted other events so far
+    pub fn save(&self, state: &CsrState) -> CsrState {
+        let mut saved = *state;
+        for (counter, hpm) in saved.hpm.iter_mut().enumerate() {
+            hpm.offset = self.hpm_counter(state, counter + HPM_FIRST);
+        }
+        saved
+    }
+
+    /// Put a state `save` made back, the counters going on from their
+    /// saved values
+    pub fn load(&self, state: &mut CsrState, saved: &CsrState) {
+        *state = *saved;
+        for hpm in state.hpm.iter_mut() {
+            hpm.offset = hpm.offset.wrapping_sub(self.events(hpm.event));
+        }
+    }
+
+    /// Value of the `cycle` counter
+    pub fn cycles(&self, clock: &Clock) -> u64 {
+        match self.cpi {
+            Some(cpi) => (clock.rdinstret() as f64 * cpi) as u64,
+            None => clock.rdcycle(),
+        }
+    }
+
+    pub fn read(&self, state: &CsrState, clock: &Clock, csr: CsrAddress) -> u64 {
+        match csr {
+            CsrAddress::Fflags => state.fflags() as u64,
+            CsrAddress::Frm => state.frm() as u64,
+            CsrAddress::Fcsr => (state.fcsr & (FFLAGS_MASK | FRM_MASK << FRM_SHIFT)) as u64,
+            CsrAddress::Cycle => self.cycles(clock),
+            CsrAddress::Time => clock.rdtime(),
+            CsrAddress::InstRet => clock.rdinstret(),
+            CsrAddress::HpmCounter(counter) | CsrAddress::MhpmCounter(counter) => self.hpm_counter(state, counter),
+            CsrAddress::MhpmEvent(counter) => state.hpm[counter - HPM_FIRST].event,
+            CsrAddress::Sstatus => state.mstatus & SSTATUS_MASK,
+            CsrAddress::Sscratch => state.sscratch,
+            CsrAddress::Sepc => state.sepc,
+            CsrAddress::Satp => state.satp,
+            CsrAddress::Mstatus => state.mstatus,
+            CsrAddress::Mtvec => state.mtvec,
+            CsrAddress::Mscratch => state.mscratch,
+            CsrAddress::Mepc => state.mepc,
+            CsrAddress::Mcause => state.mcause,
+            CsrAddress::Mtval => state.mtval,
+            CsrAddress::Mie => state.mie,
+            CsrAddress::Mip => state.mip,
+        }
+    }
+
+    /// Write `value` to `csr`, false when it is read only
+    pub fn write(&mut self, state: &mut CsrState, csr: CsrAddress, value: u64) -> bool {
+        match csr {
+            CsrAddress::Fflags => state.set_fflags(value as u32),
+            CsrAddress::Frm => state.set_frm(value as u32),
+            CsrAddress::Fcsr => state.fcsr = value as u32 & (FFLAGS_MASK | FRM_MASK << FRM_SHIFT),
+            CsrAddress::MhpmCounter(counter) => {
+                let event = state.hpm[counter - HPM_FIRST].event;
+                state.hpm[counter - HPM_FIRST].offset = value.wrapping_sub(self.events(event));
+            }
+            // the counter goes on from its value, now counting the new event
+            CsrAddress::MhpmEvent(counter) => {
+                let current = self.hpm_counter(state, counter);
+                state.hpm[counter - HPM_FIRST] = PerfCounter { event: value, offset: current.wrapping_sub(self.events(value)) };
+            }
+            CsrAddress::Sstatus => state.mstatus = (state.mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK),
+            CsrAddress::Mstatus => {
+                // MPP keeps its value when written the reserved mode
+                let mask = match Privilege::from_bits(value >> MSTATUS_MPP_SHIFT) {
+                    Some(_) => MSTATUS_MASK,
+                    None => MSTATUS_MASK & !MSTATUS_MPP,
+                };
+                state.mstatus = (state.mstatus & !mask) | (value & mask);
+            }
+            CsrAddress::Sscratch => state.sscratch = value,
+            CsrAddress::Mscratch => state.mscratch = value,
+            // no instruction starts at an odd address
+            CsrAddress::Sepc => state.sepc = value & !1,
+            // a write of a mode the MMU lacks has no effect, Bare and Sv39
+            // are all there is
+            CsrAddress::Satp => {
+                if matches!(value >> 60, 0 | 8) {
+                    state.satp = value;
+                }
+            }
+            CsrAddress::Mepc => state.mepc = value & !1,
+            // only the direct and vectored modes exist
+            CsrAddress::Mtvec => state.mtvec = if value & 3 < 2 { value } else { value & !3 },
+            CsrAddress::Mcause => state.mcause = value,
+            CsrAddress::Mtval => state.mtval = value,
+            CsrAddress::Mie => state.mie = value & MIE_MASK,
+            // the bits of the machine interrupts are the devices' to clear
+            CsrAddress::Mip => {}
+            CsrAddress::Cycle | CsrAddress::Time | CsrAddress::InstRet | CsrAddress::HpmCounter(_) => return false,
+        }
+        true
+    }
+
+    /// The CSRs of a hart in `state` as the guest would read them now
+    pub fn view<'a>(&'a self, state: CsrState, clock: &'a Clock) -> CsrView<'a> {
+        CsrView { csrs: self, state, clock }
+    }
+
+    /// Run a CSR instruction on `csr` with the value of its source
+    /// register or immediate, and get the old value. `write` is false for
+    /// csrrs and csrrc from x0 or a zero immediate, which only read, and so
+    /// may read a read only CSR. `None` if the instruction is illegal, as
+    /// it is below the privilege of the CSR or writes a read only one.
+    pub fn access(
+        &mut self,
+        state: &mut CsrState,
+        clock: &Clock,
+        csr: CsrAddress,
+        op: CsrOp,
+        source: u64,
+        write: bool,
+    ) -> Option<u64> {
+        if state.privilege < csr.privilege() {
+            return None;
+        }
+        let old = self.read(state, clock, csr);
+        let new = match op {
+            CsrOp::Write => source,
+            CsrOp::Set => old | source,
+            CsrOp::Clear => old & !source,
+        };
+        if (op == CsrOp::Write || write) && !self.write(state, csr, new) && !self.permissive {
+            return None;
+        }
+        Some(old)
+    }
+
+    /// `access` by the number of the CSR the instruction names, `None` as
+    /// well for a number the VM does not implement
+    pub fn access_number(
+        &mut self,
+        state: &mut CsrState,
+        clock: &Clock,
+        number: u32,
+        op: CsrOp,
+        source: u64,
+        write: bool,
+    ) -> Option<u64> {
+        match CsrAddress::from_number(number) {
+            Some(csr) => self.access(state, clock, csr, op, source, write),
+            None if self.permissive => Some(0),
+            None => None,
+        }
+    }
+}
+
+/// The CSRs of a hart at one moment, for tools sampling a running guest
+/// without stopping it. The counters read as what the clock retired, to
+/// which compiled code adds its instructions when it returns to the host.
+#[derive(Debug, Clone, Copy)]
+pub struct CsrView<'a> {
+    csrs: &'a CsrManager,
+    state: CsrState,
+    clock: &'a Clock,
+}
+
+impl CsrView<'_> {
+    pub fn read(&self, csr: CsrAddress) -> u64 {
+        self.csrs.read(&self.state, self.clock, csr)
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        self.state.privilege
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::clock::ClockMode;
+
+    #[test]
+    fn test_fp_csrs() {
+        let clock = Clock::new(ClockMode::Virtual { instructions_per_sec: 1_000_000 });
+        let mut csrs = CsrManager::new(&Config::new());
+        let mut state = CsrState::default();
+        state.accrue(FFLAG_NX | FFLAG_OF);
+        assert_eq!(csrs.access(&mut state, &clock, CsrAddress::Frm, CsrOp::Write, RoundingMode::Rup as u64, true), Some(0));
+        assert_eq!(csrs.read(&state, &clock, CsrAddress::Fcsr), 0x65);
+        assert_eq!(state.rounding_mode(RM_DYNAMIC), Some(RoundingMode::Rup));
+        assert_eq!(state.rounding_mode(RoundingMode::Rtz as u32), Some(RoundingMode::Rtz));
+        assert_eq!(state.rounding_mode(5), None);
+        // frflags clears what it read
+        assert_eq!(csrs.access(&mut state, &clock, CsrAddress::Fflags, CsrOp::Clear, 0x1f, true), Some(0x5));
+        assert_eq!(state.fcsr, 0x60);
+        state.set_frm(RM_DYNAMIC);
+        assert_eq!(state.rounding_mode(RM_DYNAMIC), None);
+        // the counters only read
+        clock.retire(3);
+        assert_eq!(csrs.access(&mut state, &clock, CsrAddress::InstRet, CsrOp::Set, 0, false), Some(3));
+        assert_eq!(csrs.access(&mut state, &clock, CsrAddress::InstRet, CsrOp::Write, 0, true), None);
+    }
+
+    #[test]
+    fn test_counters() {
+        let clock = Clock::new(ClockMode::Virtual { instructions_per_sec: 1_000_000 });
+        let csrs = CsrManager::new(&Config::new().cycles_per_instruction(1.5));
+        let state = CsrState::default();
+        clock.retire(1000);
+        assert_eq!(csrs.read(&state, &clock, CsrAddress::InstRet), 1000);
+        assert_eq!(csrs.read(&state, &clock, CsrAddress::Cycle), 1500);
+        // 1 ms of virtual time at the 10 MHz timebase
+        assert_eq!(csrs.read(&state, &clock, CsrAddress::Time), 10_000);
+        assert_eq!(CsrManager::new(&Config::new()).read(&state, &clock, CsrAddress::Cycle), 1000);
+        let view = csrs.view(state, &clock);
+        clock.retire(1000);
+        assert_eq!((view.read(CsrAddress::InstRet), view.read(CsrAddress::Cycle)), (2000, 3000));
+    }
+
+    #[test]
+    fn test_hpm_counters() {
+        let clock = Clock::new(ClockMode::Virtual { instructions_per_sec: 1_000_000 });
+        let (loads, stores) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(7)));
+        let code = Arc::new(Mutex::new(CodeCache::new()));
+        let mut csrs = CsrManager::new(&Config::new())
+            .event_source(HPM_EVENT_LOADS, loads.clone())
+            .event_source(HPM_EVENT_STORES, stores.clone())
+            .event_source(HPM_EVENT_CODE_CACHE_MISSES, code.clone());
+        let mut state = CsrState::default();
+        let [counter, event] = [0xc04, 0x324].map(|number| CsrAddress::from_number(number).unwrap());
+        assert_eq!(counter, CsrAddress::HpmCounter(4));
+        assert_eq!(CsrAddress::from_number(0xc20), None);
+
+        // a counter selecting no event stands still
+        loads.fetch_add(5, Ordering::Relaxed);
+        assert_eq!(csrs.read(&state, &clock, counter), 0);
+        assert!(csrs.write(&mut state, event, HPM_EVENT_LOADS));
+        loads.fetch_add(5, Ordering::Relaxed);
+        assert_eq!(csrs.read(&state, &clock, counter), 5);
+        // it counts on from what is written, and from its value when it
+        // switches events
+        assert!(csrs.write(&mut state, CsrAddress::MhpmCounter(4), 100));
+        loads.fetch_add(2, Ordering::Relaxed);
+        assert!(csrs.write(&mut state, event, HPM_EVENT_STORES));
+        stores.fetch_add(3, Ordering::Relaxed);
+        assert_eq!(csrs.read(&state, &clock, counter), 105);
+        assert_eq!(csrs.access(&mut state, &clock, counter, CsrOp::Write, 0, true), None);
+
+        assert!(csrs.write(&mut state, CsrAddress::MhpmEvent(31), HPM_EVENT_CODE_CACHE_MISSES));
+        assert!(code.lock().unwrap().get(0x1000).is_none());
+        assert_eq!(csrs.read(&state, &clock, CsrAddress::HpmCounter(31)), 1);
+
+        // a saved state loads into a VM whose events started over
+        let saved = csrs.save(&state);
+        let fresh = Arc::new(AtomicU64::new(0));
+        let other = CsrManager::new(&Config::new()).event_source(HPM_EVENT_STORES, fresh.clone());
+        let mut loaded = CsrState::default();
+        other.load(&mut loaded, &saved);
+        fresh.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(other.read(&loaded, &clock, counter), 106);
+    }
+
+    #[test]
+    fn test_privilege() {
+        let clock = Clock::new(ClockMode::Virtual { instructions_per_sec: 1_000_000 });
+        let mut csrs = CsrManager::new(&Config::new());
+        let mut state = CsrState::default();
+        let mstatus = CsrAddress::from_number(0x300).unwrap();
+        assert_eq!(mstatus.privilege(), Privilege::Machine);
+        assert_eq!(csrs.access(&mut state, &clock, mstatus, CsrOp::Set, 0, false), None);
+        assert_eq!(state.mret(), None);
+
+        // a kernel in machine mode drops to supervisor mode
+        state.privilege = Privilege::Machine;
+        let mpp_s = (Privilege::Supervisor as u64) << MSTATUS_MPP_SHIFT;
+        assert_eq!(csrs.access(&mut state, &clock, mstatus, CsrOp::Write, mpp_s | MSTATUS_MPIE | MSTATUS_SPP, true), Some(0));
+        assert_eq!(csrs.access(&mut state, &clock, CsrAddress::Mepc, CsrOp::Write, 0x80001001, true), Some(0));
+        assert_eq!(state.mret(), Some(0x80001000));
+        assert_eq!(state.privilege, Privilege::Supervisor);
+        assert_eq!(state.mstatus, MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_SPP);
+        // which sees the supervisor bits only, and returns to itself
+        assert_eq!(csrs.access(&mut state, &clock, mstatus, CsrOp::Set, 0, false), None);
+        assert_eq!(csrs.access(&mut state, &clock, CsrAddress::Sstatus, CsrOp::Set, MSTATUS_SPIE, true), Some(MSTATUS_SPP));
+        assert_eq!(csrs.access(&mut state, &clock, CsrAddress::Satp, CsrOp::Write, 9 << 60, true), Some(0));
+        assert_eq!(state.satp, 0);
+        assert_eq!(state.sret(), Some(0));
+        assert_eq!(state.privilege, Privilege::Supervisor);
+        assert_eq!(state.mstatus & SSTATUS_MASK, MSTATUS_SIE | MSTATUS_SPIE);
+        assert_eq!(state.sret(), Some(0));
+        assert_eq!(state.privilege, Privilege::User);
+        assert_eq!(state.sret(), None);
+    }
+
+    #[test]
+    fn test_illegal_csrs() {
+        let clock = Clock::new(ClockMode::Virtual { instructions_per_sec: 1_000_000 });
+        let mut state = CsrState::default();
+        let mut csrs = CsrManager::new(&Config::new());
+        assert_eq!(csrs.access_number(&mut state, &clock, 0x7c0, CsrOp::Set, 0, false), None);
+        assert_eq!(csrs.access_number(&mut state, &clock, 0xc02, CsrOp::Write, 1, true), None);
+        assert_eq!(csrs.access_number(&mut state, &clock, 0xc02, CsrOp::Set, 0, false), Some(0));
+
+        // the legacy behavior reads zero and drops the writes
+        let mut csrs = CsrManager::new(&Config::new().permissive_csrs(true));
+        assert_eq!(csrs.access_number(&mut state, &clock, 0x7c0, CsrOp::Write, 1, true), Some(0));
+        assert_eq!(csrs.access_number(&mut state, &clock, 0xc02, CsrOp::Write, 1, true), Some(0));
+        // the privilege of a CSR still holds
+        assert_eq!(csrs.access_number(&mut state, &clock, 0x300, CsrOp::Set, 0, false), None);
+    }
+}
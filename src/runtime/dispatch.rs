@@ -0,0 +1,247 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::frontend::cache::CodeCache;
+use crate::runtime::config::OptLevel;
+
+/// Blocks compiled on their first execution, keyed by the pc they start
+/// at. The engine runs a block, which returns the pc execution continues
+/// at, and comes back here to find the next one, so only code that runs is
+/// ever compiled.
+#[derive(Debug)]
+pub struct Dispatcher<B> {
+    blocks: HashMap<u64, B>,
+    /// Times each loop header was reached through a back-edge
+    loops: HashMap<u64, u64>,
+    /// Loop headers whose block was replaced by the optimizing tier
+    optimized: HashSet<u64>,
+    /// Generation of the code cache the blocks were translated in
+    generation: u64,
+}
+
+impl<B: Clone> Dispatcher<B> {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            loops: HashMap::new(),
+            optimized: HashSet::new(),
+            generation: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Whether the guest rewrote its code since the last call, in which case
+    /// every compiled block was dropped and direct links between blocks
+    /// have to be undone as well
+    pub fn invalidated(&mut self, code: &CodeCache) -> bool {
+        if self.generation == code.generation() {
+            return false;
+        }
+        self.clear();
+        self.generation = code.generation();
+        true
+    }
+
+    /// Drop every block, e.g. after the memory the blocks were translated
+    /// for changed its layout
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.loops.clear();
+        self.optimized.clear();
+    }
+
+    /// The block at `pc`, compiled by `compile` when it runs for the first
+    /// time. After the guest rewrote its code every block is compiled anew.
+    pub fn get_or_compile<E>(
+        &mut self,
+        pc: u64,
+        code: &CodeCache,
+        compile: impl FnOnce(u64) -> Result<B, E>,
+    ) -> Result<B, E> {
+        self.invalidated(code);
+        if let Some(block) = self.blocks.get(&pc) {
+            return Ok(block.clone());
+        }
+        let block = compile(pc)?;
+        self.blocks.insert(pc, block.clone());
+        Ok(block)
+    }
+
+    /// The block at `from` continued at `to`. A jump back to or before the
+    /// block closes a loop headed by `to`, and once it went around often
+    /// enough for `opt_level` this returns true, for the engine to compile
+    /// the loop again with the optimizing tier and swap it in with `insert`.
+    /// Each loop is reported once.
+    pub fn back_edge(&mut self, from: u64, to: u64, opt_level: OptLevel) -> bool {
+        if to > from || self.optimized.contains(&to) {
+            return false;
+        }
+        let count = self.loops.entry(to).or_default();
+        *count += 1;
+        if !opt_level.is_hot(*count) {
+            return false;
+        }
+        self.loops.remove(&to);
+        self.optimized.insert(to);
+        true
+    }
+
+    pub fn is_optimized(&self, pc: u64) -> bool {
+        self.optimized.contains(&pc)
+    }
+
+    /// Drop the block at `pc`, for it to be compiled again when it runs next
+    pub fn remove(&mut self, pc: u64) {
+        self.blocks.remove(&pc);
+    }
+
+    /// Add a block compiled ahead of its execution, e.g. by the optimizing
+    /// tier in the background
+    pub fn insert(&mut self, pc: u64, block: B) {
+        self.blocks.insert(pc, block);
+    }
+}
+
+impl<B: Clone> Default for Dispatcher<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Indices of the blocks in the table compiled blocks are linked through.
+/// A block exit to a known pc names the slot of its target, so once the
+/// target is compiled and put in the slot execution goes straight there.
+#[derive(Debug, Default)]
+pub struct BlockSlots {
+    slots: HashMap<u64, u32>,
+}
+
+impl BlockSlots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The slot of the block at `pc`, assigned on first use
+    pub fn slot(&mut self, pc: u64) -> u32 {
+        let next = self.slots.len() as u32;
+        *self.slots.entry(pc).or_insert(next)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Every pc with a slot, and its slot
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u32)> + '_ {
+        self.slots.iter().map(|(&pc, &slot)| (pc, slot))
+    }
+}
+
+/// What a loop unwinds the guest with to be resumed at its header in the
+/// optimized code of the loop, on-stack replacement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("entering the optimized loop at {0:#x}")]
+pub struct OsrEntry(pub u64);
+
+/// Targets an inline cache checks for before the table lookup. A site that
+/// jumped to more is megamorphic and keeps the ones it has.
+pub const INLINE_CACHE_SIZE: usize = 4;
+
+/// The targets the computed jump ending each block went to, for its next
+/// compilation to compare against and exit to directly: returns and
+/// virtual calls mostly go to the same few places.
+#[derive(Debug, Default)]
+pub struct InlineCaches {
+    sites: HashMap<u64, Vec<u64>>,
+}
+
+impl InlineCaches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The computed jump of the block at `site` went to `target`. Returns
+    /// true when the target is new to the cache, and the block is worth
+    /// compiling again.
+    pub fn record(&mut self, site: u64, target: u64) -> bool {
+        let targets = self.sites.entry(site).or_default();
+        if targets.contains(&target) || targets.len() == INLINE_CACHE_SIZE {
+            return false;
+        }
+        targets.push(target);
+        true
+    }
+
+    /// The targets seen at `site`, in the order they first appeared
+    pub fn targets(&self, site: u64) -> &[u64] {
+        self.sites.get(&site).map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_on_first_execution() {
+        let mut code = CodeCache::new();
+        let mut dispatcher = Dispatcher::new();
+        let mut compiled = Vec::new();
+        let mut compile = |pc| {
+            compiled.push(pc);
+            Ok::<_, ()>(pc + 4)
+        };
+        assert_eq!(dispatcher.get_or_compile(0x10000, &code, &mut compile), Ok(0x10004));
+        assert_eq!(dispatcher.get_or_compile(0x10000, &code, &mut compile), Ok(0x10004));
+        assert_eq!(dispatcher.get_or_compile(0x10004, &code, &mut compile), Ok(0x10008));
+        assert_eq!(dispatcher.get_or_compile(0x10008, &code, |_| Err(())), Err(()));
+        assert_eq!(dispatcher.len(), 2);
+
+        code.invalidate(0x10000..0x10002);
+        assert_eq!(dispatcher.get_or_compile(0x10004, &code, &mut compile), Ok(0x10008));
+        assert_eq!(compiled, [0x10000, 0x10004, 0x10004]);
+    }
+
+    #[test]
+    fn test_hot_loops() {
+        let code = CodeCache::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.get_or_compile(0x10000, &code, |_| Ok::<_, ()>("baseline")).unwrap();
+        let tiered = OptLevel::Tiered { threshold: 3 };
+        assert!(!dispatcher.back_edge(0x10000, 0x10008, tiered));
+        assert!(!dispatcher.back_edge(0x10020, 0x10020, OptLevel::Optimized));
+        assert!(!dispatcher.back_edge(0x10010, 0x10000, tiered));
+        assert!(!dispatcher.back_edge(0x10010, 0x10000, tiered));
+        assert!(dispatcher.back_edge(0x10010, 0x10000, tiered));
+        assert!(!dispatcher.back_edge(0x10010, 0x10000, tiered));
+        assert!(dispatcher.is_optimized(0x10000));
+
+        let mut slots = BlockSlots::new();
+        assert_eq!((slots.slot(0x10000), slots.slot(0x10008), slots.slot(0x10000)), (0, 1, 0));
+
+        dispatcher.insert(0x10000, "optimized");
+        assert_eq!(dispatcher.get_or_compile(0x10000, &code, |_| Err(())), Ok("optimized"));
+    }
+
+    #[test]
+    fn test_inline_caches() {
+        let mut caches = InlineCaches::new();
+        assert!(caches.record(0x10000, 0x20000));
+        assert!(!caches.record(0x10000, 0x20000));
+        for target in 1..8 {
+            caches.record(0x10000, 0x20000 + target * 4);
+        }
+        assert_eq!(caches.targets(0x10000), [0x20000, 0x20004, 0x20008, 0x2000c]);
+        assert!(caches.targets(0x10004).is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+use crate::middleend::address_map::{GuestFault, MemoryError};
+use crate::runtime::syscall::Errno;
+
+/// Why running a guest failed, as opposed to the guest exiting on its own
+#[derive(Debug, thiserror::Error)]
+pub enum RuntimeError {
+    /// Setting up or resizing the guest address space failed
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+    /// The guest accessed memory it was not allowed to
+    #[error("guest fault: {0}")]
+    Fault(#[from] GuestFault),
+    /// A syscall failed in a way the guest cannot be told about
+    #[error("syscall {nr} failed with errno {}", .errno.0)]
+    Syscall { nr: u64, errno: Errno },
+    /// The compiled code trapped, e.g. on an `unreachable`
+    #[error("guest trapped: {0}")]
+    Trap(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Access;
+    use std::error::Error;
+
+    #[test]
+    fn test_conversions() {
+        let fault = GuestFault::Unmapped { vaddr: 0x1000, access: Access::Read };
+        let error = RuntimeError::from(fault);
+        assert_eq!(error.to_string(), "guest fault: Read of unmapped address 0x1000");
+        assert!(error.source().is_some());
+
+        let error = RuntimeError::from(MemoryError::Unmapped(0x2000));
+        assert_eq!(error.to_string(), "guest address 0x2000 is not mapped");
+        let error = RuntimeError::Syscall { nr: 63, errno: Errno(9) };
+        assert_eq!(error.to_string(), "syscall 63 failed with errno 9");
+    }
+}
@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// How a guest run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionResult {
+    /// exit_group, or exit of the last thread, with the exit status
+    Exited(i32),
+    /// Killed by a signal whose action is to terminate
+    Signaled(u32),
+}
+
+impl ExecutionResult {
+    /// The status a shell would report, 128 + signal for a killed guest
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ExecutionResult::Exited(code) => code,
+            ExecutionResult::Signaled(sig) => 128 + sig as i32,
+        }
+    }
+}
+
+/// The program execve replaces the current one with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecImage {
+    /// The path the guest passed, resolved against its working directory
+    pub path: PathBuf,
+    /// Contents of the ELF file
+    pub data: Vec<u8>,
+    pub args: Vec<String>,
+    pub envs: Vec<(String, String)>,
+}
+
+/// Raised by the syscall layer to stop running a guest thread. Host
+/// functions return it as a trap, which unwinds the guest code, and the
+/// runtime turns it back into an `ExecutionResult` instead of a panic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuestExit {
+    /// The calling thread ended through exit, the others keep running
+    Thread(i32),
+    /// The whole process ended
+    Process(ExecutionResult),
+    /// execve succeeded: the engine stops every thread, loads the image into
+    /// a fresh address space and runs it in the same process
+    Exec(Box<ExecImage>),
+}
+
+impl fmt::Display for GuestExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuestExit::Thread(code) => write!(f, "guest thread exited with status {code}"),
+            GuestExit::Process(ExecutionResult::Exited(code)) => {
+                write!(f, "guest exited with status {code}")
+            }
+            GuestExit::Process(ExecutionResult::Signaled(sig)) => {
+                write!(f, "guest killed by signal {sig}")
+            }
+            GuestExit::Exec(image) => write!(f, "guest executed {}", image.path.display()),
+        }
+    }
+}
+
+impl Error for GuestExit {}
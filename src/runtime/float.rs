@@ -0,0 +1,543 @@
+use std::num::FpCategory;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::runtime::csr::{RoundingMode, FFLAG_DZ, FFLAG_NV, FFLAG_NX, FFLAG_OF, FFLAG_UF};
+use crate::runtime::regs::Registers;
+
+/// The upper half of a single precision register, all ones when it holds a
+/// properly NaN boxed value
+const BOX: u64 = 0xffff_ffff_0000_0000;
+
+/// A format of the F or D extension, the operations of which are generic
+/// over both
+pub trait Float:
+    Copy + PartialOrd + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> + Neg<Output = Self>
+{
+    const ZERO: Self;
+    const MAX: Self;
+    const INFINITY: Self;
+    const MIN_POSITIVE: Self;
+    /// The sign bit of the raw bits
+    const SIGN: u64;
+    /// Bits of a register above the value, which NaN boxing sets
+    const BOX: u64;
+
+    fn to_raw(self) -> u64;
+    fn from_raw(raw: u64) -> Self;
+    fn is_nan(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+    fn classify(self) -> FpCategory;
+    fn mul_add(self, b: Self, c: Self) -> Self;
+    fn sqrt(self) -> Self;
+    /// The integral value `mode` rounds to
+    fn round_int(self, mode: RoundingMode) -> Self;
+    /// The value of an integral float, saturating as `as` does
+    fn to_i128(self) -> i128;
+    /// `value` rounded to nearest
+    fn from_i128(value: i128) -> Self;
+
+    /// The value of a register, the canonical NaN for a single precision
+    /// one not NaN boxed
+    fn unbox(reg: u64) -> Self {
+        match reg & Self::BOX == Self::BOX {
+            true => Self::from_raw(reg & !Self::BOX),
+            false => Self::nan(),
+        }
+    }
+
+    /// The value as a register holds it, a NaN as the canonical one
+    fn boxed(self) -> u64 {
+        match self.is_nan() {
+            true => Self::nan().to_raw() | Self::BOX,
+            false => self.to_raw() | Self::BOX,
+        }
+    }
+
+    /// The canonical NaN
+    fn nan() -> Self {
+        Self::from_raw(Self::INFINITY.to_raw() | quiet_bit::<Self>())
+    }
+
+    /// Whether the value is a signaling NaN, whose quiet bit is clear
+    fn is_signaling(self) -> bool {
+        self.is_nan() && self.to_raw() & quiet_bit::<Self>() == 0
+    }
+
+    /// The next representable value above, or below, a finite one
+    fn next(self, up: bool) -> Self {
+        let raw = self.to_raw() & !Self::SIGN;
+        if raw == 0 {
+            let min = Self::from_raw(1);
+            return if up { min } else { -min };
+        }
+        let raw = if self.is_sign_negative() != up { raw + 1 } else { raw - 1 };
+        Self::from_raw(raw | (self.to_raw() & Self::SIGN))
+    }
+}
+
+/// The most significant bit of the fraction, set in a quiet NaN
+fn quiet_bit<F: Float>() -> u64 {
+    let exponent = F::INFINITY.to_raw();
+    (exponent & exponent.wrapping_neg()) >> 1
+}
+
+macro_rules! float {
+    ($float:ty, $bits:ty, $box:expr) => {
+        impl Float for $float {
+            const ZERO: Self = 0.0;
+            const MAX: Self = <$float>::MAX;
+            const INFINITY: Self = <$float>::INFINITY;
+            const MIN_POSITIVE: Self = <$float>::MIN_POSITIVE;
+            const SIGN: u64 = 1 << (<$bits>::BITS - 1);
+            const BOX: u64 = $box;
+
+            fn to_raw(self) -> u64 {
+                self.to_bits() as u64
+            }
+
+            fn from_raw(raw: u64) -> Self {
+                <$float>::from_bits(raw as $bits)
+            }
+
+            fn is_nan(self) -> bool {
+                <$float>::is_nan(self)
+            }
+
+            fn is_infinite(self) -> bool {
+                <$float>::is_infinite(self)
+            }
+
+            fn is_sign_negative(self) -> bool {
+                <$float>::is_sign_negative(self)
+            }
+
+            fn classify(self) -> FpCategory {
+                <$float>::classify(self)
+            }
+
+            fn mul_add(self, b: Self, c: Self) -> Self {
+                <$float>::mul_add(self, b, c)
+            }
+
+            fn sqrt(self) -> Self {
+                <$float>::sqrt(self)
+            }
+
+            fn round_int(self, mode: RoundingMode) -> Self {
+                match mode {
+                    RoundingMode::Rne => self.round_ties_even(),
+                    RoundingMode::Rtz => self.trunc(),
+                    RoundingMode::Rdn => self.floor(),
+                    RoundingMode::Rup => self.ceil(),
+                    RoundingMode::Rmm => self.round(),
+                }
+            }
+
+            fn to_i128(self) -> i128 {
+                self as i128
+            }
+
+            fn from_i128(value: i128) -> Self {
+                value as $float
+            }
+        }
+    };
+}
+
+float!(f32, u32, BOX);
+float!(f64, u64, 0);
+
+/// Where the result of an OP-FP instruction goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatResult {
+    /// Into the floating point register rd, as the register holds it
+    F(u64),
+    /// Into the integer register rd
+    X(u64),
+}
+
+/// Which way the exact result of an inexact operation lies from the one
+/// rounded to nearest, and whether halfway to the next value
+#[derive(Debug, Clone, Copy)]
+struct Inexact {
+    above: bool,
+    tie: bool,
+}
+
+impl Inexact {
+    /// From the exact error `err` of the result `r`
+    fn of<F: Float>(r: F, err: F) -> Option<Self> {
+        if err.is_nan() || err == F::ZERO {
+            return None;
+        }
+        let above = err > F::ZERO;
+        Some(Self { above, tie: r.next(above) - r == err + err })
+    }
+}
+
+/// The result `r` an operation rounded to nearest, rounded as `mode` asks
+/// instead
+fn directed<F: Float>(r: F, inexact: Inexact, mode: RoundingMode) -> F {
+    let Inexact { above, tie } = inexact;
+    let away = r.next(above);
+    let zero = r == F::ZERO;
+    match mode {
+        RoundingMode::Rtz if !zero && above == r.is_sign_negative() => away,
+        RoundingMode::Rdn if !above => away,
+        RoundingMode::Rup if above => away,
+        RoundingMode::Rmm if tie && above != r.is_sign_negative() => away,
+        _ => r,
+    }
+}
+
+/// The result of an overflow to the infinity of `negative` as `mode`
+/// rounds it, which rounds towards zero to the largest finite value
+fn overflow<F: Float>(negative: bool, mode: RoundingMode) -> F {
+    let towards_zero = match mode {
+        RoundingMode::Rne | RoundingMode::Rmm => false,
+        RoundingMode::Rtz => true,
+        RoundingMode::Rdn => !negative,
+        RoundingMode::Rup => negative,
+    };
+    let magnitude = if towards_zero { F::MAX } else { F::INFINITY };
+    if negative {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Finish the result `r` an operation on `inputs` rounded to nearest:
+/// raise the flags it ran into and round it as `mode` asks
+fn finish<F: Float>(inputs: &[F], r: F, inexact: Option<Inexact>, mode: RoundingMode, flags: &mut u32) -> F {
+    if inputs.iter().any(|x| x.is_signaling()) || (r.is_nan() && !inputs.iter().any(|x| x.is_nan())) {
+        *flags |= FFLAG_NV;
+    }
+    if r.is_nan() {
+        return r;
+    }
+    if r.is_infinite() && !inputs.iter().any(|x| x.is_infinite()) {
+        *flags |= FFLAG_OF | FFLAG_NX;
+        return overflow(r.is_sign_negative(), mode);
+    }
+    let Some(inexact) = inexact.filter(|_| !r.is_infinite()) else {
+        return r;
+    };
+    *flags |= FFLAG_NX;
+    let result = directed(r, inexact, mode);
+    if result.is_infinite() {
+        *flags |= FFLAG_OF;
+    } else if result > -F::MIN_POSITIVE && result < F::MIN_POSITIVE {
+        *flags |= FFLAG_UF;
+    }
+    result
+}
+
+/// fadd, fsub, fmul, fdiv and fsqrt by `funct5`, computed to nearest on
+/// the host, with the error telling how to round otherwise
+fn arith<F: Float>(funct5: u32, a: F, b: F, mode: RoundingMode, flags: &mut u32) -> Option<F> {
+    Some(match funct5 {
+        0x00 | 0x01 => {
+            let b = if funct5 == 0x01 { -b } else { b };
+            // the exact error of the sum, by Knuth's TwoSum
+            let r = a + b;
+            let a_part = r - b;
+            let err = (a - a_part) + (b - (r - a_part));
+            finish(&[a, b], r, Inexact::of(r, err), mode, flags)
+        }
+        0x02 => {
+            let r = a * b;
+            finish(&[a, b], r, Inexact::of(r, a.mul_add(b, -r)), mode, flags)
+        }
+        0x03 => {
+            let r = a / b;
+            if b == F::ZERO && !a.is_nan() && !a.is_infinite() && a != F::ZERO {
+                *flags |= FFLAG_DZ;
+                return Some(r);
+            }
+            // the exact quotient is above r when the remainder has the sign
+            // of b, and never halfway
+            let rem = (-r).mul_add(b, a);
+            let inexact = Inexact::of(r, rem).map(|rem| Inexact { above: rem.above == (b > F::ZERO), tie: false });
+            finish(&[a, b], r, inexact, mode, flags)
+        }
+        0x0b => {
+            let r = a.sqrt();
+            let inexact = Inexact::of(r, (-r).mul_add(r, a)).map(|rem| Inexact { tie: false, ..rem });
+            finish(&[a], r, inexact, mode, flags)
+        }
+        _ => return None,
+    })
+}
+
+/// fmadd, fmsub, fnmsub and fnmadd by the major opcode. The host computes
+/// them fused and to nearest, so they round to nearest whatever the mode
+/// and do not raise NX.
+fn fused_fmt<F: Float>(opcode: u32, a: F, b: F, c: F, mode: RoundingMode, flags: &mut u32) -> F {
+    let (a, c) = match opcode {
+        0x43 => (a, c),
+        0x47 => (a, -c),
+        0x4b => (-a, c),
+        _ => (-a, -c),
+    };
+    // 0 times infinity is invalid even with a quiet NaN to add
+    let zero = |x: F| x == F::ZERO;
+    if (zero(a) && b.is_infinite()) || (a.is_infinite() && zero(b)) {
+        *flags |= FFLAG_NV;
+    }
+    finish(&[a, b, c], a.mul_add(b, c), None, mode, flags)
+}
+
+/// fmin and fmax, -0 being less than +0 and a NaN operand giving the
+/// other one
+fn min_max<F: Float>(a: F, b: F, max: bool, flags: &mut u32) -> F {
+    if a.is_signaling() || b.is_signaling() {
+        *flags |= FFLAG_NV;
+    }
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ if a < b => if max { b } else { a },
+        _ if b < a => if max { a } else { b },
+        _ => if a.is_sign_negative() != max { a } else { b },
+    }
+}
+
+/// feq, fle and flt by `funct3`, of which only feq is quiet about a quiet
+/// NaN
+fn compare<F: Float>(funct3: u32, a: F, b: F, flags: &mut u32) -> Option<u64> {
+    let invalid = match funct3 {
+        2 => a.is_signaling() || b.is_signaling(),
+        _ => a.is_nan() || b.is_nan(),
+    };
+    if invalid {
+        *flags |= FFLAG_NV;
+    }
+    Some(match funct3 {
+        0 => a <= b,
+        1 => a < b,
+        2 => a == b,
+        _ => return None,
+    } as u64)
+}
+
+/// The bit of fclass telling the class of `value`
+fn class<F: Float>(value: F) -> u64 {
+    let negative = value.is_sign_negative();
+    let bit = match value.classify() {
+        FpCategory::Nan if value.is_signaling() => 8,
+        FpCategory::Nan => 9,
+        FpCategory::Infinite => if negative { 0 } else { 7 },
+        FpCategory::Normal => if negative { 1 } else { 6 },
+        FpCategory::Subnormal => if negative { 2 } else { 5 },
+        FpCategory::Zero => if negative { 3 } else { 4 },
+    };
+    1 << bit
+}
+
+/// fcvt to w, wu, l or lu by `rs2`, saturating and raising NV when `value`
+/// is a NaN or out of range
+fn to_int<F: Float>(value: F, rs2: u32, mode: RoundingMode, flags: &mut u32) -> Option<u64> {
+    let (min, max): (i128, i128) = match rs2 {
+        0 => (i32::MIN.into(), i32::MAX.into()),
+        1 => (0, u32::MAX.into()),
+        2 => (i64::MIN.into(), i64::MAX.into()),
+        3 => (0, u64::MAX.into()),
+        _ => return None,
+    };
+    let rounded = value.round_int(mode);
+    let int = if value.is_nan() {
+        *flags |= FFLAG_NV;
+        max
+    } else if rounded.to_i128() < min || rounded.to_i128() > max {
+        *flags |= FFLAG_NV;
+        rounded.to_i128().clamp(min, max)
+    } else {
+        if rounded != value {
+            *flags |= FFLAG_NX;
+        }
+        rounded.to_i128()
+    };
+    // the 32 bit results are sign extended, unsigned ones too
+    Some(if rs2 < 2 { int as u32 as i32 as u64 } else { int as u64 })
+}
+
+/// fcvt from the integer `x` of w, wu, l or lu by `rs2`
+fn from_int<F: Float>(x: u64, rs2: u32, mode: RoundingMode, flags: &mut u32) -> Option<F> {
+    let value = match rs2 {
+        0 => x as i32 as i128,
+        1 => x as u32 as i128,
+        2 => x as i64 as i128,
+        3 => x as i128,
+        _ => return None,
+    };
+    let r = F::from_i128(value);
+    let err = value - r.to_i128();
+    if err == 0 {
+        return Some(r);
+    }
+    *flags |= FFLAG_NX;
+    let tie = 2 * err == r.next(err > 0).to_i128() - r.to_i128();
+    Some(directed(r, Inexact { above: err > 0, tie }, mode))
+}
+
+/// fcvt.s.d, rounding as `mode` asks
+fn narrow(value: f64, mode: RoundingMode, flags: &mut u32) -> f32 {
+    if value.is_signaling() {
+        *flags |= FFLAG_NV;
+    }
+    let r = value as f32;
+    if value.is_nan() || value.is_infinite() {
+        return r;
+    }
+    // the difference of the neighbours is exact
+    let err = value - r as f64;
+    let inexact = (!r.is_infinite() && err != 0.0).then(|| Inexact {
+        above: err > 0.0,
+        tie: r.next(err > 0.0) as f64 - r as f64 == err + err,
+    });
+    finish(&[], r, inexact, mode, flags)
+}
+
+/// Run the instruction `insn` of the OP-FP major opcode on `regs`, the
+/// flags it raises accrued into fcsr. `None` for an illegal one, a
+/// reserved rounding mode among them.
+pub fn op(insn: u32, regs: &mut Registers) -> Option<FloatResult> {
+    let mut flags = 0;
+    let result = match (insn >> 25) & 3 {
+        0 => op_fmt::<f32>(insn, regs, &mut flags),
+        1 => op_fmt::<f64>(insn, regs, &mut flags),
+        _ => None,
+    };
+    regs.csr.accrue(flags);
+    result
+}
+
+fn op_fmt<F: Float>(insn: u32, regs: &Registers, flags: &mut u32) -> Option<FloatResult> {
+    let (rs1, rs2, funct3, funct5) = (((insn >> 15) & 31) as usize, (insn >> 20) & 31, (insn >> 12) & 7, insn >> 27);
+    let (a, b) = (F::unbox(regs.f[rs1]), F::unbox(regs.f[rs2 as usize]));
+    let mode = || regs.csr.rounding_mode(funct3);
+    let double = F::BOX == 0;
+    Some(match funct5 {
+        0x00..=0x03 => FloatResult::F(arith(funct5, a, b, mode()?, flags)?.boxed()),
+        0x0b if rs2 == 0 => FloatResult::F(arith(funct5, a, b, mode()?, flags)?.boxed()),
+        // the sign injections keep the payload of a NaN
+        0x04 => {
+            let (a, b) = (a.to_raw(), b.to_raw());
+            let sign = match funct3 {
+                0 => b,
+                1 => !b,
+                2 => a ^ b,
+                _ => return None,
+            };
+            FloatResult::F((a & !F::SIGN) | (sign & F::SIGN) | F::BOX)
+        }
+        0x05 if funct3 < 2 => FloatResult::F(min_max(a, b, funct3 == 1, flags).boxed()),
+        // fcvt.d.s, exact
+        0x08 if double && rs2 == 0 => {
+            let value = f32::unbox(regs.f[rs1]);
+            if value.is_signaling() {
+                *flags |= FFLAG_NV;
+            }
+            FloatResult::F((value as f64).boxed())
+        }
+        0x08 if !double && rs2 == 1 => FloatResult::F(narrow(f64::unbox(regs.f[rs1]), mode()?, flags).boxed()),
+        0x14 => FloatResult::X(compare(funct3, a, b, flags)?),
+        0x18 => FloatResult::X(to_int(a, rs2, mode()?, flags)?),
+        0x1a => FloatResult::F(from_int::<F>(regs.x[rs1], rs2, mode()?, flags)?.boxed()),
+        // fmv.x.w sign extends the raw bits, boxed or not
+        0x1c if rs2 == 0 && funct3 == 0 => FloatResult::X(match double {
+            true => regs.f[rs1],
+            false => regs.f[rs1] as u32 as i32 as u64,
+        }),
+        0x1c if rs2 == 0 && funct3 == 1 => FloatResult::X(class(a)),
+        0x1e if rs2 == 0 && funct3 == 0 => FloatResult::F(regs.x[rs1] | F::BOX),
+        _ => return None,
+    })
+}
+
+/// Run the fused multiply-add `insn` on `regs`, giving the value of rd.
+/// `None` for an illegal one.
+pub fn fused(insn: u32, regs: &mut Registers) -> Option<u64> {
+    let (rs1, rs2, rs3) = (((insn >> 15) & 31) as usize, ((insn >> 20) & 31) as usize, (insn >> 27) as usize);
+    let mode = regs.csr.rounding_mode((insn >> 12) & 7)?;
+    let mut flags = 0;
+    let value = match (insn >> 25) & 3 {
+        0 => {
+            let [a, b, c] = [rs1, rs2, rs3].map(|reg| f32::unbox(regs.f[reg]));
+            fused_fmt(insn & 0x7f, a, b, c, mode, &mut flags).boxed()
+        }
+        1 => {
+            let [a, b, c] = [rs1, rs2, rs3].map(|reg| f64::unbox(regs.f[reg]));
+            fused_fmt(insn & 0x7f, a, b, c, mode, &mut flags).boxed()
+        }
+        _ => return None,
+    };
+    regs.csr.accrue(flags);
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An OP-FP instruction of the format `fmt` on f1 and f2, or x1, into
+    /// register 3
+    fn run(regs: &mut Registers, funct5: u32, fmt: u32, rm: u32, rs2: u32) -> Option<FloatResult> {
+        regs.csr.fcsr = 0;
+        op((funct5 << 27) | (fmt << 25) | (rs2 << 20) | (1 << 15) | (rm << 12) | (3 << 7) | 0x53, regs)
+    }
+
+    #[test]
+    fn test_rounding_and_flags() {
+        let mut regs = Registers::default();
+        let f = |value: f64| FloatResult::F(value.to_bits());
+        (regs.f[1], regs.f[2]) = (0.1f64.to_bits(), 0.2f64.to_bits());
+        assert_eq!(run(&mut regs, 0x00, 1, 0, 2), Some(f(0.1 + 0.2)));
+        assert_eq!(regs.csr.fflags(), FFLAG_NX);
+        // the exact sum is below the one rounded to nearest
+        assert_eq!(run(&mut regs, 0x00, 1, 1, 2), Some(f(0.3)));
+        assert_eq!(run(&mut regs, 0x00, 1, 3, 2), Some(f(0.1 + 0.2)));
+        assert_eq!(run(&mut regs, 0x00, 1, 5, 2), None);
+        // a dynamic rounding mode is the one of frm
+        regs.csr.set_frm(2);
+        assert_eq!(op(0x0220_f1d3, &mut regs), Some(f(0.3)));
+
+        (regs.f[1], regs.f[2]) = ((-1.0f64).to_bits(), 0);
+        assert_eq!(run(&mut regs, 0x0b, 1, 0, 0), Some(FloatResult::F(0x7ff8_0000_0000_0000)));
+        assert_eq!(regs.csr.fflags(), FFLAG_NV);
+        assert_eq!(run(&mut regs, 0x03, 1, 0, 2), Some(f(f64::NEG_INFINITY)));
+        assert_eq!(regs.csr.fflags(), FFLAG_DZ);
+        assert_eq!(run(&mut regs, 0x05, 1, 0, 2), Some(f(-1.0)));
+        assert_eq!(run(&mut regs, 0x1c, 1, 1, 0), Some(FloatResult::X(1 << 1)));
+
+        // conversions saturate and round as asked
+        regs.f[1] = 1e10f64.to_bits();
+        assert_eq!(run(&mut regs, 0x18, 1, 0, 0), Some(FloatResult::X(i32::MAX as u64)));
+        assert_eq!(regs.csr.fflags(), FFLAG_NV);
+        regs.f[1] = (-2.5f64).to_bits();
+        assert_eq!(run(&mut regs, 0x18, 1, 4, 2), Some(FloatResult::X(-3i64 as u64)));
+        assert_eq!(run(&mut regs, 0x18, 1, 0, 2), Some(FloatResult::X(-2i64 as u64)));
+        assert_eq!(regs.csr.fflags(), FFLAG_NX);
+        assert_eq!(run(&mut regs, 0x18, 1, 0, 1), Some(FloatResult::X(0)));
+        regs.x[1] = (1 << 24) + 1;
+        let single = |value: f32| FloatResult::F(BOX | value.to_bits() as u64);
+        assert_eq!(run(&mut regs, 0x1a, 0, 3, 2), Some(single(16777218.0)));
+        assert_eq!(run(&mut regs, 0x1a, 0, 0, 2), Some(single(16777216.0)));
+        regs.f[1] = (1.0 + 2f64.powi(-30)).to_bits();
+        assert_eq!(run(&mut regs, 0x08, 0, 3, 1), Some(single(1.0f32.next(true))));
+        assert_eq!(run(&mut regs, 0x08, 0, 0, 1), Some(single(1.0)));
+        assert_eq!(regs.csr.fflags(), FFLAG_NX);
+
+        // a single not NaN boxed reads as the canonical NaN
+        (regs.f[1], regs.f[2]) = (1.0f32.to_bits() as u64, BOX | 1.0f32.to_bits() as u64);
+        assert_eq!(run(&mut regs, 0x00, 0, 0, 2), Some(FloatResult::F(BOX | 0x7fc0_0000)));
+        assert_eq!(run(&mut regs, 0x1c, 0, 0, 0), Some(FloatResult::X(1.0f32.to_bits() as u64)));
+
+        // fmadd.d f3, f1, f2, f4
+        (regs.f[1], regs.f[2], regs.f[4]) = (2.0f64.to_bits(), 3.0f64.to_bits(), 1.0f64.to_bits());
+        assert_eq!(fused(0x2220_81c3, &mut regs), Some(7.0f64.to_bits()));
+    }
+}
@@ -0,0 +1,1529 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::middleend::address_map::{Access, GuestFault, GuestMemory, LinearMemory};
+use crate::runtime::clock::Clock;
+use crate::runtime::config::{Config, OptLevel};
+use crate::runtime::coverage::Coverage;
+use crate::runtime::csr::{
+    CsrAddress, CsrManager, CsrOp, CsrView, EventSource, Exception, InterruptSource, Privilege, HPM_EVENT_BRANCHES, HPM_EVENT_LOADS, HPM_EVENT_STORES,
+};
+use crate::runtime::execution::GuestExit;
+use crate::runtime::float::{self, FloatResult};
+use crate::runtime::mmu::Mmu;
+use crate::runtime::regs::Registers;
+use crate::runtime::syscall::signal::{self, Delivery, SigInfo};
+use crate::runtime::syscall::{ecall, log_backtrace, mm, SyscallEnv};
+use crate::tools::perf::{RUNTIME_EXECUTE, RUNTIME_INSTRUCTIONS};
+use crate::tools::trace::TraceEvent;
+
+/// Longest a wfi sleeps, as interrupts of device threads and signals of
+/// the host do not announce themselves ahead like timers
+const WFI_SLICE: Duration = Duration::from_millis(10);
+
+/// Why the interpreter handed control back to the engine
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    /// The instruction budget ran out at a block boundary. The engine
+    /// delivers pending signals and carries on.
+    Yield,
+    /// The block at `pc` just ran for the threshold'th time. The engine
+    /// compiles it in the background while the interpreter goes on.
+    Hot(u64),
+    /// The block at `pc` has been compiled, execution switches over to it
+    Compiled(u64),
+    /// The interpreter does not implement the system instruction at `pc`
+    Unsupported(u64),
+    /// The instruction at `pc` is an ebreak
+    Breakpoint(u64),
+    /// The instruction at `pc` is not a valid one
+    Illegal(u64),
+    /// The guest accessed memory it may not, `regs.pc` is at the access
+    Fault(GuestFault),
+    /// The guest exited or replaced its image
+    Exit(GuestExit),
+    /// An access hit a watchpoint that stops, `regs.pc` is at the access,
+    /// which happens when the guest resumes
+    Watchpoint(WatchHit),
+}
+
+/// The accesses a watchpoint watches for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        matches!((self, access), (WatchKind::Read, Access::Read) | (WatchKind::Write, Access::Write) | (WatchKind::Access, _))
+    }
+}
+
+/// An access of the guest hitting a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// The instruction making the access
+    pub pc: u64,
+    pub vaddr: u64,
+    pub len: u64,
+    pub access: Access,
+    /// The kind of the watchpoint hit
+    pub kind: WatchKind,
+    /// The value being written, for writes
+    pub value: Option<u64>,
+}
+
+/// What a hit of a watchpoint does
+#[derive(Clone)]
+pub enum WatchAction {
+    /// Stop the interpreter before the access
+    Stop,
+    /// Tell the callback and go on with the access
+    Call(Arc<dyn Fn(&WatchHit) + Send + Sync>),
+}
+
+impl fmt::Debug for WatchAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchAction::Stop => f.write_str("Stop"),
+            WatchAction::Call(_) => f.write_str("Call(..)"),
+        }
+    }
+}
+
+/// Guest virtual addresses whose accesses are reported
+#[derive(Debug, Clone)]
+struct Watchpoint {
+    range: Range<u64>,
+    kind: WatchKind,
+    action: WatchAction,
+}
+
+/// Tier-0 of the engine: runs guest code straight from memory, so the first
+/// instruction runs before anything is compiled, and counts the executions
+/// of each block to find the ones worth compiling
+#[derive(Debug)]
+pub struct Interpreter {
+    /// Executions after which a block is hot
+    threshold: u64,
+    /// Check page permissions like the strict memory helpers do
+    strict: bool,
+    counts: HashMap<u64, u64>,
+    compiled: HashSet<u64>,
+    /// Address reserved by the last lr, with the value it loaded
+    reservation: Option<(u64, u64)>,
+    csrs: CsrManager,
+    /// Events of the guest for the performance counters
+    loads: Arc<AtomicU64>,
+    stores: Arc<AtomicU64>,
+    branches: Arc<AtomicU64>,
+    /// The devices whose interrupts are checked for at block boundaries
+    interrupts: Vec<Arc<dyn InterruptSource>>,
+    /// Translation of the guest's own page table, once it sets `satp`
+    mmu: Mmu,
+    /// Whether the instruction at hand accesses memory through `mmu`
+    paging: bool,
+    /// The instructions that ran, when recording coverage
+    coverage: Option<Coverage>,
+    watchpoints: Vec<Watchpoint>,
+    /// The instruction at hand
+    pc: u64,
+    /// The instruction a watchpoint stopped before, whose accesses are not
+    /// reported again when it runs next
+    watch_stopped: Option<u64>,
+    /// Whether the instruction at hand is that one
+    watch_skip: bool,
+    /// Trace every instruction that runs, from `Config::instrumented`
+    debug: bool,
+}
+
+impl Interpreter {
+    /// Without tiering every block is hot on its first execution, so the
+    /// interpreter only bridges the time until its code is compiled
+    pub fn new(config: &Config) -> Self {
+        let threshold = match config.opt_level {
+            OptLevel::Tiered { threshold } => threshold.max(1),
+            OptLevel::Baseline | OptLevel::Optimized => 1,
+        };
+        let (loads, stores, branches) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let csrs = CsrManager::new(config)
+            .event_source(HPM_EVENT_LOADS, loads.clone())
+            .event_source(HPM_EVENT_STORES, stores.clone())
+            .event_source(HPM_EVENT_BRANCHES, branches.clone());
+        Self {
+            threshold,
+            strict: config.strict_memory,
+            counts: HashMap::new(),
+            compiled: HashSet::new(),
+            reservation: None,
+            csrs,
+            loads,
+            stores,
+            branches,
+            interrupts: Vec::new(),
+            mmu: Mmu::new(),
+            paging: false,
+            coverage: None,
+            watchpoints: Vec::new(),
+            pc: 0,
+            watch_stopped: None,
+            watch_skip: false,
+            debug: config.instrumented(),
+        }
+    }
+
+    /// Take the interrupts of `source`, like the timer of a `Clint`
+    pub fn interrupt_source(mut self, source: Arc<dyn InterruptSource>) -> Self {
+        self.interrupts.push(source);
+        self
+    }
+
+    /// Count `event` with `source` as well, like the misses of the code
+    /// cache
+    pub fn event_source(mut self, event: u64, source: Arc<dyn EventSource>) -> Self {
+        self.csrs = self.csrs.event_source(event, source);
+        self
+    }
+
+    /// Switch over to compiled code whenever the block at `pc` is reached
+    pub fn mark_compiled(&mut self, pc: u64) {
+        self.compiled.insert(pc);
+    }
+
+    /// Interpret the block at `pc` again, which compiled code left to the
+    /// interpreter, e.g. after the guest rewrote it
+    pub fn unmark_compiled(&mut self, pc: u64) {
+        self.compiled.remove(&pc);
+    }
+
+    /// The CSRs of the hart whose registers are `regs`, read as the guest
+    /// would now
+    pub fn csr<'a>(&'a self, regs: &Registers, clock: &'a Clock) -> CsrView<'a> {
+        self.csrs.view(regs.csr, clock)
+    }
+
+    /// Record every instruction that runs from now on into a coverage map
+    pub fn record_coverage(&mut self) {
+        self.coverage.get_or_insert_with(Coverage::new);
+    }
+
+    /// The coverage recorded so far, which stops recording
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Report the accesses of the guest to `range` of the `kind`, e.g. to
+    /// find what corrupts a variable. Only the code the interpreter runs is
+    /// watched, not compiled code nor syscalls writing guest memory.
+    pub fn watch(&mut self, range: Range<u64>, kind: WatchKind, action: WatchAction) {
+        self.watchpoints.push(Watchpoint { range, kind, action });
+    }
+
+    /// Remove the watchpoints on `range` of the `kind`, telling whether
+    /// there were any
+    pub fn unwatch(&mut self, range: &Range<u64>, kind: WatchKind) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| watchpoint.range != *range || watchpoint.kind != kind);
+        self.watchpoints.len() != before
+    }
+
+    /// How often the block at `pc` started running in the interpreter
+    pub fn count(&self, pc: u64) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or(0)
+    }
+
+    /// The blocks that started running in the interpreter and how often, in
+    /// no particular order
+    pub fn blocks(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.counts.iter().map(|(&pc, &count)| (pc, count))
+    }
+
+    /// Interpret from `regs.pc` until a block boundary past `budget`
+    /// instructions, or until something needs the engine. The time and the
+    /// instructions retired go to `env.profiler`.
+    pub fn run<M: LinearMemory + ?Sized>(
+        &mut self,
+        env: &mut SyscallEnv,
+        memory: &mut GuestMemory<M>,
+        regs: &mut Registers,
+        budget: u64,
+    ) -> Stop {
+        let Some(profiler) = env.profiler.clone() else {
+            return self.run_blocks(env, memory, regs, budget);
+        };
+        let (start, retired) = (Instant::now(), env.clock.instret());
+        let stop = self.run_blocks(env, memory, regs, budget);
+        profiler.record(RUNTIME_EXECUTE, start.elapsed());
+        profiler.count(RUNTIME_INSTRUCTIONS, env.clock.instret().saturating_sub(retired));
+        stop
+    }
+
+    fn run_blocks<M: LinearMemory + ?Sized>(
+        &mut self,
+        env: &mut SyscallEnv,
+        memory: &mut GuestMemory<M>,
+        regs: &mut Registers,
+        budget: u64,
+    ) -> Stop {
+        let mut executed = 0;
+        self.mmu.set_satp(regs.csr.satp);
+        loop {
+            if executed >= budget {
+                // the engine gets control back with pending signals delivered
+                return match signal::deliver(env, memory, regs) {
+                    Delivery::Terminate(sig) => Stop::Exit(signal::terminate(env, sig)),
+                    Delivery::None | Delivery::Handler(_) => Stop::Yield,
+                };
+            }
+            if !self.interrupts.is_empty() {
+                let pending = self.interrupts.iter().fold(0, |pending, source| pending | source.pending());
+                regs.csr.mip = match &env.replay {
+                    Some(replay) => replay.interrupts(env.clock.instret(), pending),
+                    None => pending,
+                };
+                if let Some(interrupt) = regs.csr.pending_interrupt() {
+                    regs.pc = regs.csr.interrupt(interrupt, regs.pc);
+                }
+            }
+            let start = regs.pc;
+            if self.compiled.contains(&start) {
+                return Stop::Compiled(start);
+            }
+            if let Some(tracer) = &env.tracer {
+                tracer.record(env.tid, TraceEvent::Block { pc: start });
+            }
+            let count = self.counts.entry(start).or_default();
+            *count += 1;
+            let hot = *count == self.threshold;
+            let mut block = 0;
+            let result = loop {
+                if self.debug {
+                    tracing::trace!(target: "doublejit::interp", tid = env.tid, pc = format_args!("{:#x}", regs.pc));
+                }
+                match self.step(env, memory, regs) {
+                    Ok(false) => block += 1,
+                    Ok(true) => break Ok(()),
+                    Err(stop) => break Err(stop),
+                }
+            };
+            // the ending instruction retires too, unless it faulted
+            block += matches!(result, Ok(()) | Err(Stop::Exit(_))) as u64;
+            env.clock.retire(block);
+            // a trap retires nothing but counts against the budget, so a
+            // guest trapping over and over still yields
+            executed += block.max(1);
+            // another thread may have mapped the page since this one last
+            // took over the permissions, the access then goes again
+            if matches!(result, Err(Stop::Fault(_))) && mm::sync(env, memory) {
+                continue;
+            }
+            if let Err(stop) = result.or_else(|stop| trap(regs, stop)).or_else(|stop| raise(env, memory, regs, stop)) {
+                unhandled(env, memory, regs, &stop);
+                return stop;
+            }
+            if hot {
+                return Stop::Hot(start);
+            }
+        }
+    }
+
+    /// Run only the instruction at `regs.pc`, as a debugger steps, with a
+    /// trap going to the guest's handler like in `run`
+    pub fn step_instruction<M: LinearMemory + ?Sized>(
+        &mut self,
+        env: &mut SyscallEnv,
+        memory: &mut GuestMemory<M>,
+        regs: &mut Registers,
+    ) -> Result<(), Stop> {
+        self.mmu.set_satp(regs.csr.satp);
+        let result = self.step(env, memory, regs).map(|_| ());
+        if matches!(result, Ok(()) | Err(Stop::Exit(_))) {
+            env.clock.retire(1);
+        }
+        let result = result.or_else(|stop| trap(regs, stop)).or_else(|stop| raise(env, memory, regs, stop));
+        if let Err(stop) = &result {
+            unhandled(env, memory, regs, stop);
+        }
+        result
+    }
+
+    /// Sleep through a wfi until an interrupt the guest enabled may be
+    /// pending, the earliest deadline of the sources
+    fn wait_for_interrupt(&self, env: &SyscallEnv, regs: &Registers) {
+        let pending = self.interrupts.iter().fold(0, |pending, source| pending | source.pending());
+        if pending & regs.csr.mie != 0 {
+            return;
+        }
+        let deadline = self
+            .interrupts
+            .iter()
+            .filter_map(|source| source.deadline())
+            .fold(env.clock.monotonic() + WFI_SLICE, Duration::min);
+        env.clock.sleep_until(deadline);
+    }
+
+    fn check<M: LinearMemory + ?Sized>(
+        &self,
+        memory: &GuestMemory<M>,
+        vaddr: u64,
+        len: u64,
+        access: Access,
+    ) -> Result<(), GuestFault> {
+        // accesses are at most 8 bytes, so they touch at most two pages
+        for addr in [vaddr, vaddr.wrapping_add(len - 1)] {
+            if self.strict {
+                memory.map().check(addr, access)?;
+            } else if memory.map().vaddr_to_offset(addr).is_none() {
+                return Err(GuestFault::Unmapped { vaddr: addr, access });
+            }
+        }
+        Ok(())
+    }
+
+    /// The guest physical address of `vaddr`, through the page table of the
+    /// guest when it turned paging on and runs below machine mode
+    fn translate<M: LinearMemory + ?Sized>(
+        &mut self,
+        memory: &mut GuestMemory<M>,
+        vaddr: u64,
+        access: Access,
+    ) -> Result<u64, Stop> {
+        if !self.paging {
+            return Ok(vaddr);
+        }
+        memory.translate(&mut self.mmu, vaddr, access).map_err(Stop::Fault)
+    }
+
+    /// Report an access of `len` bytes at `vaddr` to the watchpoints it
+    /// hits, stopping before the access for one that stops
+    fn watch_access(&mut self, vaddr: u64, len: u64, access: Access, value: Option<u64>) -> Result<(), Stop> {
+        if self.watch_skip {
+            return Ok(());
+        }
+        let end = vaddr.saturating_add(len);
+        for watchpoint in &self.watchpoints {
+            if !watchpoint.kind.matches(access) || watchpoint.range.start >= end || vaddr >= watchpoint.range.end {
+                continue;
+            }
+            let hit = WatchHit { pc: self.pc, vaddr, len, access, kind: watchpoint.kind, value };
+            match &watchpoint.action {
+                WatchAction::Stop => {
+                    self.watch_stopped = Some(self.pc);
+                    return Err(Stop::Watchpoint(hit));
+                }
+                WatchAction::Call(callback) => callback(&hit),
+            }
+        }
+        Ok(())
+    }
+
+    fn load<M: LinearMemory + ?Sized>(
+        &mut self,
+        memory: &mut GuestMemory<M>,
+        vaddr: u64,
+        len: u64,
+    ) -> Result<u64, Stop> {
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        if !self.watchpoints.is_empty() {
+            self.watch_access(vaddr, len, Access::Read, None)?;
+        }
+        let vaddr = self.translate(memory, vaddr, Access::Read)?;
+        let fault = Stop::Fault(GuestFault::Unmapped {
+            vaddr,
+            access: Access::Read,
+        });
+        if memory.map().mmio_region(vaddr).is_some() {
+            return memory.map().mmio_read(vaddr, len as u8).map_err(|_| fault);
+        }
+        self.check(memory, vaddr, len, Access::Read).map_err(Stop::Fault)?;
+        let mut bytes = [0; 8];
+        memory.peek(vaddr, &mut bytes[..len as usize]).map_err(|_| fault)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn store<M: LinearMemory + ?Sized>(
+        &mut self,
+        memory: &mut GuestMemory<M>,
+        vaddr: u64,
+        len: u64,
+        value: u64,
+    ) -> Result<(), Stop> {
+        self.store_if(memory, vaddr, len, None, value).map(|_| ())
+    }
+
+    /// `store`, only if the memory still holds `current` when given, which
+    /// another thread may have changed since it was loaded. Tells whether
+    /// it stored.
+    fn store_if<M: LinearMemory + ?Sized>(
+        &mut self,
+        memory: &mut GuestMemory<M>,
+        vaddr: u64,
+        len: u64,
+        current: Option<u64>,
+        value: u64,
+    ) -> Result<bool, Stop> {
+        self.stores.fetch_add(1, Ordering::Relaxed);
+        if !self.watchpoints.is_empty() {
+            self.watch_access(vaddr, len, Access::Write, Some(value & (u64::MAX >> (64 - len * 8))))?;
+        }
+        // any store to the reserved address breaks the reservation
+        if self.reservation.is_some_and(|(reserved, _)| reserved == vaddr) {
+            self.reservation = None;
+        }
+        let vaddr = self.translate(memory, vaddr, Access::Write)?;
+        let fault = Stop::Fault(GuestFault::Unmapped {
+            vaddr,
+            access: Access::Write,
+        });
+        if memory.map().mmio_region(vaddr).is_some() {
+            return memory.map().mmio_write(vaddr, len as u8, value).map(|()| true).map_err(|_| fault);
+        }
+        self.check(memory, vaddr, len, Access::Write).map_err(Stop::Fault)?;
+        match current {
+            Some(current) => memory.compare_exchange(vaddr, len as usize, current, value).map_err(|_| fault),
+            None => memory
+                .init(vaddr, &value.to_le_bytes()[..len as usize])
+                .map(|()| true)
+                .map_err(|_| fault),
+        }
+    }
+
+    /// Fetch the instruction at `pc` and its length, with a compressed one
+    /// expanded to its 32 bit form
+    fn fetch<M: LinearMemory + ?Sized>(
+        &mut self,
+        memory: &mut GuestMemory<M>,
+        pc: u64,
+    ) -> Result<(u32, u64), Stop> {
+        let low = self.fetch_half(memory, pc)?;
+        if low & 0b11 != 0b11 {
+            let insn = expand_compressed(low as u16).ok_or(Stop::Illegal(pc))?;
+            return Ok((insn, 2));
+        }
+        Ok((low | self.fetch_half(memory, pc.wrapping_add(2))? << 16, 4))
+    }
+
+    fn fetch_half<M: LinearMemory + ?Sized>(&mut self, memory: &mut GuestMemory<M>, vaddr: u64) -> Result<u32, Stop> {
+        let vaddr = self.translate(memory, vaddr, Access::Exec)?;
+        self.check(memory, vaddr, 2, Access::Exec).map_err(Stop::Fault)?;
+        let mut half = [0; 2];
+        memory.peek(vaddr, &mut half).map_err(|_| {
+            Stop::Fault(GuestFault::Unmapped {
+                vaddr,
+                access: Access::Exec,
+            })
+        })?;
+        Ok(u16::from_le_bytes(half) as u32)
+    }
+
+    /// Execute one instruction, telling whether it ends the block
+    fn step<M: LinearMemory + ?Sized>(
+        &mut self,
+        env: &mut SyscallEnv,
+        memory: &mut GuestMemory<M>,
+        regs: &mut Registers,
+    ) -> Result<bool, Stop> {
+        let pc = regs.pc;
+        self.pc = pc;
+        self.watch_skip = self.watch_stopped.take() == Some(pc);
+        // machine mode bypasses the page table
+        self.paging = regs.csr.privilege < Privilege::Machine;
+        if self.paging {
+            self.mmu.set_privilege(regs.csr.privilege, regs.csr.mstatus);
+        }
+        let (insn, len) = self.fetch(memory, pc)?;
+        if let Some(tracer) = &env.tracer {
+            tracer.record(env.tid, TraceEvent::Instruction { pc, insn, len });
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc);
+        }
+        let next = pc.wrapping_add(len);
+        let rd = ((insn >> 7) & 31) as usize;
+        let funct3 = (insn >> 12) & 7;
+        let rs1 = regs.x[((insn >> 15) & 31) as usize];
+        let rs2 = regs.x[((insn >> 20) & 31) as usize];
+        let funct7 = insn >> 25;
+        let illegal = Stop::Illegal(pc);
+
+        let value = match insn & 0x7f {
+            // lui
+            0x37 => imm_u(insn),
+            // auipc
+            0x17 => pc.wrapping_add(imm_u(insn)),
+            // jal
+            0x6f => {
+                regs.set_x(rd, next);
+                regs.pc = pc.wrapping_add(imm_j(insn));
+                return Ok(true);
+            }
+            // jalr
+            0x67 if funct3 == 0 => {
+                let target = rs1.wrapping_add(imm_i(insn)) & !1;
+                regs.set_x(rd, next);
+                regs.pc = target;
+                return Ok(true);
+            }
+            0x63 => {
+                let taken = match funct3 {
+                    0 => rs1 == rs2,
+                    1 => rs1 != rs2,
+                    4 => (rs1 as i64) < rs2 as i64,
+                    5 => rs1 as i64 >= rs2 as i64,
+                    6 => rs1 < rs2,
+                    7 => rs1 >= rs2,
+                    _ => return Err(illegal),
+                };
+                self.branches.fetch_add(1, Ordering::Relaxed);
+                regs.pc = if taken { pc.wrapping_add(imm_b(insn)) } else { next };
+                return Ok(true);
+            }
+            0x03 => {
+                let addr = rs1.wrapping_add(imm_i(insn));
+                match funct3 {
+                    0 => self.load(memory, addr, 1).map(|v| v as i8 as u64),
+                    1 => self.load(memory, addr, 2).map(|v| v as i16 as u64),
+                    2 => self.load(memory, addr, 4).map(|v| v as i32 as u64),
+                    3 => self.load(memory, addr, 8),
+                    4 => self.load(memory, addr, 1),
+                    5 => self.load(memory, addr, 2),
+                    6 => self.load(memory, addr, 4),
+                    _ => return Err(illegal),
+                }?
+            }
+            0x23 => {
+                if funct3 > 3 {
+                    return Err(illegal);
+                }
+                let addr = rs1.wrapping_add(imm_s(insn));
+                self.store(memory, addr, 1 << funct3, rs2)?;
+                regs.pc = next;
+                return Ok(false);
+            }
+            0x13 => {
+                let imm = imm_i(insn);
+                let shamt = (insn >> 20) & 0x3f;
+                match funct3 {
+                    0 => rs1.wrapping_add(imm),
+                    1 if insn >> 26 == 0 => rs1 << shamt,
+                    2 => ((rs1 as i64) < imm as i64) as u64,
+                    3 => (rs1 < imm) as u64,
+                    4 => rs1 ^ imm,
+                    5 if insn >> 26 == 0 => rs1 >> shamt,
+                    5 if insn >> 26 == 0x10 => ((rs1 as i64) >> shamt) as u64,
+                    6 => rs1 | imm,
+                    7 => rs1 & imm,
+                    _ => return Err(illegal),
+                }
+            }
+            0x1b => {
+                let rs1 = rs1 as u32;
+                let shamt = (insn >> 20) & 0x1f;
+                let value = match (funct3, funct7) {
+                    (0, _) => rs1.wrapping_add(imm_i(insn) as u32),
+                    (1, 0) => rs1 << shamt,
+                    (5, 0) => rs1 >> shamt,
+                    (5, 0x20) => ((rs1 as i32) >> shamt) as u32,
+                    _ => return Err(illegal),
+                };
+                value as i32 as u64
+            }
+            0x33 => alu(funct3, funct7, rs1, rs2).ok_or(illegal)?,
+            0x3b => alu_w(funct3, funct7, rs1 as u32, rs2 as u32).ok_or(illegal)? as i32 as u64,
+            // fence and fence.i, the interpreter reads code from memory anew
+            // on every fetch, compiled code is dropped after a fence.i
+            0x0f => {
+                if funct3 == 1 {
+                    env.code.lock().unwrap().clear();
+                }
+                regs.pc = next;
+                return Ok(false);
+            }
+            0x2f => self.amo(memory, insn, rs1, rs2).ok_or(illegal)??,
+            0x73 => match (funct3, insn >> 7) {
+                // a kernel in the guest handles its own ecalls
+                (0, 0) if regs.csr.handles_traps() => {
+                    regs.pc = regs.csr.trap(Exception::ecall(regs.csr.privilege), pc, 0);
+                    return Ok(true);
+                }
+                (0, 0) => {
+                    regs.pc = next;
+                    return match ecall(env, memory, regs) {
+                        Ok(()) => Ok(true),
+                        Err(exit) => Err(Stop::Exit(exit)),
+                    };
+                }
+                (0, 0x2000) => return Err(Stop::Breakpoint(pc)),
+                // mret and sret
+                (0, 0x60_4000) => {
+                    regs.pc = regs.csr.mret().ok_or(illegal)?;
+                    return Ok(true);
+                }
+                (0, 0x20_4000) => {
+                    regs.pc = regs.csr.sret().ok_or(illegal)?;
+                    return Ok(true);
+                }
+                // wfi, which user mode may not use to stall the hart
+                (0, 0x20_a000) => {
+                    if regs.csr.privilege < Privilege::Supervisor {
+                        return Err(illegal);
+                    }
+                    self.wait_for_interrupt(env, regs);
+                    regs.pc = next;
+                    return Ok(true);
+                }
+                // sfence.vma, for all addresses or the one in rs1
+                (0, _) if funct7 == 0x09 && rd == 0 => {
+                    if regs.csr.privilege < Privilege::Supervisor {
+                        return Err(illegal);
+                    }
+                    match (insn >> 15) & 31 {
+                        0 => self.mmu.flush(),
+                        _ => self.mmu.flush_vaddr(rs1),
+                    }
+                    regs.pc = next;
+                    return Ok(false);
+                }
+                (1..=3 | 5..=7, _) => {
+                    let source = if funct3 >= 5 { ((insn >> 15) & 31) as u64 } else { rs1 };
+                    let op = CsrOp::from_funct3(funct3).ok_or(Stop::Illegal(pc))?;
+                    let write = (insn >> 15) & 31 != 0;
+                    let old = self.csrs.access_number(&mut regs.csr, &env.clock, insn >> 20, op, source, write).ok_or(illegal)?;
+                    if insn >> 20 == CsrAddress::Satp.number() {
+                        self.mmu.set_satp(regs.csr.satp);
+                    }
+                    old
+                }
+                _ => return Err(Stop::Unsupported(pc)),
+            },
+            // flw and fld, a single NaN boxed
+            0x07 => {
+                let addr = rs1.wrapping_add(imm_i(insn));
+                regs.f[rd] = match funct3 {
+                    2 => self.load(memory, addr, 4)? | 0xffff_ffff_0000_0000,
+                    3 => self.load(memory, addr, 8)?,
+                    _ => return Err(illegal),
+                };
+                regs.pc = next;
+                return Ok(false);
+            }
+            // fsw and fsd
+            0x27 => {
+                if !matches!(funct3, 2 | 3) {
+                    return Err(illegal);
+                }
+                let addr = rs1.wrapping_add(imm_s(insn));
+                self.store(memory, addr, 1 << funct3, regs.f[((insn >> 20) & 31) as usize])?;
+                regs.pc = next;
+                return Ok(false);
+            }
+            0x43 | 0x47 | 0x4b | 0x4f => {
+                regs.f[rd] = float::fused(insn, regs).ok_or(illegal)?;
+                regs.pc = next;
+                return Ok(false);
+            }
+            0x53 => match float::op(insn, regs).ok_or(illegal)? {
+                FloatResult::X(value) => value,
+                FloatResult::F(value) => {
+                    regs.f[rd] = value;
+                    regs.pc = next;
+                    return Ok(false);
+                }
+            },
+            _ => return Err(illegal),
+        };
+        regs.set_x(rd, value);
+        regs.pc = next;
+        Ok(false)
+    }
+
+    /// lr, sc and the AMOs, returning the value for rd or `None` for an
+    /// invalid encoding
+    fn amo<M: LinearMemory + ?Sized>(
+        &mut self,
+        memory: &mut GuestMemory<M>,
+        insn: u32,
+        addr: u64,
+        value: u64,
+    ) -> Option<Result<u64, Stop>> {
+        let len = match (insn >> 12) & 7 {
+            2 => 4,
+            3 => 8,
+            _ => return None,
+        };
+        self.rmw(memory, insn >> 27, len, addr, value)
+    }
+
+    fn rmw<M: LinearMemory + ?Sized>(
+        &mut self,
+        memory: &mut GuestMemory<M>,
+        op: u32,
+        len: u64,
+        addr: u64,
+        value: u64,
+    ) -> Option<Result<u64, Stop>> {
+        if !addr.is_multiple_of(len) {
+            let access = if op == 0x02 { Access::Read } else { Access::Write };
+            return Some(Err(Stop::Fault(GuestFault::Misaligned { vaddr: addr, access })));
+        }
+        let extend = |v: u64| if len == 4 { v as i32 as u64 } else { v };
+        let value = extend(value);
+        // other threads store meanwhile, so the new value only goes in if
+        // the old one is still there, and an amo tries again otherwise
+        loop {
+            let old = match self.load(memory, addr, len) {
+                Ok(old) => extend(old),
+                Err(stop) => return Some(Err(stop)),
+            };
+            let current = match op {
+                // lr
+                0x02 => {
+                    self.reservation = Some((addr, old));
+                    return Some(Ok(old));
+                }
+                // sc, which fails unless the reservation is still held and
+                // the reserved value still there
+                0x03 => match self.reservation.take() {
+                    Some((reserved, current)) if reserved == addr => current,
+                    _ => return Some(Ok(1)),
+                },
+                _ => old,
+            };
+            let new = amo(op, len, old, value)?;
+            match self.store_if(memory, addr, len, Some(current), new) {
+                Ok(true) => return Some(Ok(if op == 0x03 { 0 } else { old })),
+                Ok(false) if op == 0x03 => return Some(Ok(1)),
+                Ok(false) => continue,
+                Err(stop) => return Some(Err(stop)),
+            }
+        }
+    }
+}
+
+/// The value an amo or sc of `op` on `len` bytes stores, from the `old` one
+/// and the source register
+fn amo(op: u32, len: u64, old: u64, value: u64) -> Option<u64> {
+    Some(match op {
+        0x03 => value,
+        0x00 => old.wrapping_add(value),
+        0x01 => value,
+        0x04 => old ^ value,
+        0x08 => old | value,
+        0x0c => old & value,
+        0x10 => (old as i64).min(value as i64) as u64,
+        0x14 => (old as i64).max(value as i64) as u64,
+        0x18 if len == 4 => (old as u32).min(value as u32) as i32 as u64,
+        0x1c if len == 4 => (old as u32).max(value as u32) as i32 as u64,
+        0x18 => old.min(value),
+        0x1c => old.max(value),
+        _ => return None,
+    })
+}
+
+/// The starts of the basic blocks of `code` loaded at `base`, found by
+/// decoding it linearly: the first instruction, the targets of branches and
+/// jals inside it, and every instruction after one ending a block as
+/// `step` does. Data between functions decodes as instructions too, so the
+/// list is what the engine may compile, not what a run reaches.
+pub fn find_blocks(code: &[u8], base: u64) -> Vec<u64> {
+    let end = base.wrapping_add(code.len() as u64);
+    let mut blocks = HashSet::from([base]);
+    let mut offset = 0;
+    while let Some((insn, len)) = insn_at(code, offset) {
+        let pc = base.wrapping_add(offset as u64);
+        offset += len;
+        if !ends_block(insn) {
+            continue;
+        }
+        let target = match insn & 0x7f {
+            0x6f => Some(pc.wrapping_add(imm_j(insn))),
+            0x63 => Some(pc.wrapping_add(imm_b(insn))),
+            _ => None,
+        };
+        blocks.extend(target.filter(|target| (base..end).contains(target)));
+        if offset < code.len() {
+            blocks.insert(base.wrapping_add(offset as u64));
+        }
+    }
+    let mut blocks: Vec<_> = blocks.into_iter().collect();
+    blocks.sort_unstable();
+    blocks
+}
+
+/// Length of the block at the start of `code`, up to and including the
+/// instruction ending it as in `find_blocks`, all of `code` if none does
+pub fn block_len(code: &[u8]) -> usize {
+    let mut offset = 0;
+    while let Some((insn, len)) = insn_at(code, offset) {
+        offset += len;
+        if ends_block(insn) {
+            break;
+        }
+    }
+    offset
+}
+
+/// The instruction at `offset` into `code`, a compressed one expanded, and
+/// its length
+pub fn insn_at(code: &[u8], offset: usize) -> Option<(u32, usize)> {
+    let low = u16::from_le_bytes([*code.get(offset)?, *code.get(offset + 1)?]);
+    match code.get(offset + 2..offset + 4) {
+        _ if low & 0b11 != 0b11 => Some((expand_compressed(low).unwrap_or(0), 2)),
+        Some(high) => Some((low as u32 | (u16::from_le_bytes([high[0], high[1]]) as u32) << 16, 4)),
+        None => None,
+    }
+}
+
+/// Jumps, branches, ecall and ebreak end a block
+pub fn ends_block(insn: u32) -> bool {
+    match insn & 0x7f {
+        0x6f | 0x63 | 0x67 => true,
+        0x73 => (insn >> 12) & 7 == 0,
+        _ => false,
+    }
+}
+
+/// Deliver the exception `stop` stands for to the trap handler of the
+/// guest, if it has one, for execution to go on there
+fn trap(regs: &mut Registers, stop: Stop) -> Result<(), Stop> {
+    let (exception, pc, tval) = match stop {
+        Stop::Illegal(pc) => (Exception::IllegalInstruction, pc, 0),
+        Stop::Breakpoint(pc) => (Exception::Breakpoint, pc, pc),
+        Stop::Fault(fault) => {
+            let (exception, vaddr) = Exception::from_fault(&fault);
+            (exception, regs.pc, vaddr)
+        }
+        stop => return Err(stop),
+    };
+    if !regs.csr.handles_traps() {
+        return Err(stop);
+    }
+    regs.pc = regs.csr.trap(exception, pc, tval);
+    Ok(())
+}
+
+/// Enter the guest's handler of the signal a fault or an invalid instruction
+/// raises, when the guest has no trap handler of its own, as linux does for
+/// a user process. Without a signal handler the stop ends the guest.
+fn raise<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+    stop: Stop,
+) -> Result<(), Stop> {
+    let info = match &stop {
+        Stop::Fault(fault) => SigInfo::from_fault(fault),
+        Stop::Illegal(pc) => {
+            regs.pc = *pc;
+            SigInfo::illegal(*pc)
+        }
+        _ => return Err(stop),
+    };
+    match signal::force(env, memory, regs, info) {
+        Delivery::Handler(_) => Ok(()),
+        Delivery::None | Delivery::Terminate(_) => Err(stop),
+    }
+}
+
+/// Log the backtrace of a trap the guest has no handler for, which ends it
+fn unhandled<M: LinearMemory + ?Sized>(env: &SyscallEnv, memory: &GuestMemory<M>, regs: &Registers, stop: &Stop) {
+    let reason = match stop {
+        Stop::Illegal(_) => "illegal instruction".to_string(),
+        Stop::Fault(fault) => fault.to_string(),
+        _ => return,
+    };
+    log_backtrace(env, memory, regs, &reason);
+}
+
+/// The register-register operations of I and M
+fn alu(funct3: u32, funct7: u32, a: u64, b: u64) -> Option<u64> {
+    let shamt = b & 0x3f;
+    Some(match (funct7, funct3) {
+        (0, 0) => a.wrapping_add(b),
+        (0x20, 0) => a.wrapping_sub(b),
+        (0, 1) => a << shamt,
+        (0, 2) => ((a as i64) < b as i64) as u64,
+        (0, 3) => (a < b) as u64,
+        (0, 4) => a ^ b,
+        (0, 5) => a >> shamt,
+        (0x20, 5) => ((a as i64) >> shamt) as u64,
+        (0, 6) => a | b,
+        (0, 7) => a & b,
+        (1, 0) => a.wrapping_mul(b),
+        (1, 1) => ((a as i64 as i128 * b as i64 as i128) >> 64) as u64,
+        (1, 2) => ((a as i64 as i128 * b as i128) >> 64) as u64,
+        (1, 3) => ((a as u128 * b as u128) >> 64) as u64,
+        (1, 4) => match b {
+            0 => u64::MAX,
+            _ => (a as i64).wrapping_div(b as i64) as u64,
+        },
+        (1, 5) => a.checked_div(b).unwrap_or(u64::MAX),
+        (1, 6) => match b {
+            0 => a,
+            _ => (a as i64).wrapping_rem(b as i64) as u64,
+        },
+        (1, 7) => a.checked_rem(b).unwrap_or(a),
+        _ => return None,
+    })
+}
+
+/// The 32 bit register-register operations of RV64I and RV64M
+fn alu_w(funct3: u32, funct7: u32, a: u32, b: u32) -> Option<u32> {
+    let shamt = b & 0x1f;
+    Some(match (funct7, funct3) {
+        (0, 0) => a.wrapping_add(b),
+        (0x20, 0) => a.wrapping_sub(b),
+        (0, 1) => a << shamt,
+        (0, 5) => a >> shamt,
+        (0x20, 5) => ((a as i32) >> shamt) as u32,
+        (1, 0) => a.wrapping_mul(b),
+        (1, 4) => match b {
+            0 => u32::MAX,
+            _ => (a as i32).wrapping_div(b as i32) as u32,
+        },
+        (1, 5) => a.checked_div(b).unwrap_or(u32::MAX),
+        (1, 6) => match b {
+            0 => a,
+            _ => (a as i32).wrapping_rem(b as i32) as u32,
+        },
+        (1, 7) => a.checked_rem(b).unwrap_or(a),
+        _ => return None,
+    })
+}
+
+pub fn imm_i(insn: u32) -> u64 {
+    (insn as i32 >> 20) as u64
+}
+
+pub fn imm_s(insn: u32) -> u64 {
+    ((insn as i32 >> 25) << 5 | ((insn >> 7) & 0x1f) as i32) as u64
+}
+
+pub fn imm_b(insn: u32) -> u64 {
+    let imm = (insn as i32 >> 31) << 12
+        | (((insn >> 7) & 1) << 11) as i32
+        | (((insn >> 25) & 0x3f) << 5) as i32
+        | (((insn >> 8) & 0xf) << 1) as i32;
+    imm as u64
+}
+
+pub fn imm_u(insn: u32) -> u64 {
+    (insn & 0xffff_f000) as i32 as u64
+}
+
+pub fn imm_j(insn: u32) -> u64 {
+    let imm = (insn as i32 >> 31) << 20
+        | (insn & 0xff000) as i32
+        | (((insn >> 20) & 1) << 11) as i32
+        | (((insn >> 21) & 0x3ff) << 1) as i32;
+    imm as u64
+}
+
+fn itype(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: i32) -> u32 {
+    ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn stype(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 5) & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+}
+
+fn rtype(opcode: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn btype(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 12) & 1) << 31)
+        | (((imm >> 5) & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xf) << 8)
+        | (((imm >> 11) & 1) << 7)
+        | 0x63
+}
+
+fn jtype(rd: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 20) & 1) << 31)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 11) & 1) << 20)
+        | (((imm >> 12) & 0xff) << 12)
+        | (rd << 7)
+        | 0x6f
+}
+
+/// Sign extend the low `bits` bits of `value`
+fn sext(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+/// The 32 bit instruction a compressed one stands for, `None` for the
+/// reserved encodings
+fn expand_compressed(half: u16) -> Option<u32> {
+    let c = half as u32;
+    let bit = |n: u32| (c >> n) & 1;
+    // registers x8 to x15 of the 3 bit fields
+    let rd_ = 8 + ((c >> 2) & 7);
+    let rs1_ = 8 + ((c >> 7) & 7);
+    let rd = (c >> 7) & 31;
+    let rs2 = (c >> 2) & 31;
+    let imm6 = sext((bit(12) << 5) | ((c >> 2) & 0x1f), 6);
+    let shamt = (bit(12) << 5) | ((c >> 2) & 0x1f);
+    let ld_off = (((c >> 7) & 0x38) | ((c << 1) & 0xc0)) as i32;
+    let lw_off = (((c >> 7) & 0x38) | ((c >> 4) & 4) | ((c << 1) & 0x40)) as i32;
+    Some(match (c & 3, c >> 13) {
+        (0, 0) => {
+            let imm = ((c >> 7) & 0x30) | ((c >> 1) & 0x3c0) | ((c >> 4) & 4) | ((c >> 2) & 8);
+            if imm == 0 {
+                return None;
+            }
+            itype(0x13, rd_, 0, 2, imm as i32)
+        }
+        (0, 1) => itype(0x07, rd_, 3, rs1_, ld_off),
+        (0, 2) => itype(0x03, rd_, 2, rs1_, lw_off),
+        (0, 3) => itype(0x03, rd_, 3, rs1_, ld_off),
+        (0, 5) => stype(0x27, 3, rs1_, rd_, ld_off),
+        (0, 6) => stype(0x23, 2, rs1_, rd_, lw_off),
+        (0, 7) => stype(0x23, 3, rs1_, rd_, ld_off),
+        (1, 0) => itype(0x13, rd, 0, rd, imm6),
+        (1, 1) if rd != 0 => itype(0x1b, rd, 0, rd, imm6),
+        (1, 2) => itype(0x13, rd, 0, 0, imm6),
+        (1, 3) if rd == 2 => {
+            let imm = (bit(12) << 9) | (bit(6) << 4) | (bit(5) << 6) | (((c >> 3) & 3) << 7) | (bit(2) << 5);
+            if imm == 0 {
+                return None;
+            }
+            itype(0x13, 2, 0, 2, sext(imm, 10))
+        }
+        (1, 3) => {
+            if imm6 == 0 {
+                return None;
+            }
+            ((imm6 as u32) << 12) | (rd << 7) | 0x37
+        }
+        (1, 4) => match (c >> 10) & 3 {
+            0 => itype(0x13, rs1_, 5, rs1_, shamt as i32),
+            1 => itype(0x13, rs1_, 5, rs1_, (shamt | 0x400) as i32),
+            2 => itype(0x13, rs1_, 7, rs1_, imm6),
+            _ => {
+                let rs2_ = rd_;
+                match (bit(12), (c >> 5) & 3) {
+                    (0, 0) => rtype(0x33, rs1_, 0, rs1_, rs2_, 0x20),
+                    (0, 1) => rtype(0x33, rs1_, 4, rs1_, rs2_, 0),
+                    (0, 2) => rtype(0x33, rs1_, 6, rs1_, rs2_, 0),
+                    (0, 3) => rtype(0x33, rs1_, 7, rs1_, rs2_, 0),
+                    (1, 0) => rtype(0x3b, rs1_, 0, rs1_, rs2_, 0x20),
+                    (1, 1) => rtype(0x3b, rs1_, 0, rs1_, rs2_, 0),
+                    _ => return None,
+                }
+            }
+        },
+        (1, 5) => {
+            let imm = (bit(12) << 11)
+                | (bit(11) << 4)
+                | (((c >> 9) & 3) << 8)
+                | (bit(8) << 10)
+                | (bit(7) << 6)
+                | (bit(6) << 7)
+                | (((c >> 3) & 7) << 1)
+                | (bit(2) << 5);
+            jtype(0, sext(imm, 12))
+        }
+        (1, 6 | 7) => {
+            let imm = (bit(12) << 8) | (((c >> 10) & 3) << 3) | (((c >> 5) & 3) << 6) | (((c >> 3) & 3) << 1) | (bit(2) << 5);
+            btype((c >> 13) & 1, rs1_, 0, sext(imm, 9))
+        }
+        (2, 0) => itype(0x13, rd, 1, rd, shamt as i32),
+        (2, 1) => itype(0x07, rd, 3, 2, ((bit(12) << 5) | (((c >> 5) & 3) << 3) | (((c >> 2) & 7) << 6)) as i32),
+        (2, 2) if rd != 0 => itype(0x03, rd, 2, 2, ((bit(12) << 5) | (((c >> 4) & 7) << 2) | (((c >> 2) & 3) << 6)) as i32),
+        (2, 3) if rd != 0 => itype(0x03, rd, 3, 2, ((bit(12) << 5) | (((c >> 5) & 3) << 3) | (((c >> 2) & 7) << 6)) as i32),
+        (2, 4) => match (bit(12), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => itype(0x67, 0, 0, rd, 0),
+            (0, _, _) => rtype(0x33, rd, 0, 0, rs2, 0),
+            (1, 0, 0) => 0x0010_0073,
+            (1, _, 0) => itype(0x67, 1, 0, rd, 0),
+            _ => rtype(0x33, rd, 0, rd, rs2, 0),
+        },
+        (2, 5) => stype(0x27, 3, 2, rs2, ((((c >> 10) & 7) << 3) | (((c >> 7) & 7) << 6)) as i32),
+        (2, 6) => stype(0x23, 2, 2, rs2, ((((c >> 9) & 0xf) << 2) | (((c >> 7) & 3) << 6)) as i32),
+        (2, 7) => stype(0x23, 3, 2, rs2, ((((c >> 10) & 7) << 3) | (((c >> 7) & 7) << 6)) as i32),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::middleend::address_map::{MmioDevice, Perm};
+    use crate::runtime::clint::Clint;
+    use crate::runtime::clock::{ClockMode, TIMEBASE_FREQ};
+    use crate::runtime::csr::{CsrState, Interrupt, MIP_MEIP, MIP_MTIP, MSTATUS_MIE};
+    use crate::runtime::execution::ExecutionResult;
+    use crate::runtime::plic::{self, IrqLine, Plic, PLIC_BASE};
+    use crate::runtime::regs::{A0, T1};
+    use crate::runtime::syscall::testing::TestMemory;
+    use crate::tools::perf::Profiler;
+
+    #[test]
+    fn test_interpret_and_tier_up() {
+        let mut code = Vec::new();
+        for insn in [
+            itype(0x13, 10, 0, 0, 0),     // addi a0, zero, 0
+            itype(0x13, 5, 0, 0, 10),     // addi t0, zero, 10
+            rtype(0x33, 10, 0, 10, 5, 0), // loop: add a0, a0, t0
+            itype(0x13, 5, 0, 5, -1),     // addi t0, t0, -1
+            btype(1, 5, 0, -8),           // bnez t0, loop
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        code.extend(0x4589u16.to_le_bytes()); // c.li a1, 2
+        for insn in [
+            rtype(0x33, 10, 0, 10, 11, 1), // mul a0, a0, a1
+            itype(0x13, 17, 0, 0, 94),     // addi a7, zero, 94
+            0x73,                          // ecall
+            0x0000_0013,                   // nop, to realign
+            itype(0x03, 10, 3, 0, 8),      // 0x10026: ld a0, 8(zero)
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+
+        let profiler = Profiler::new();
+        let config = Config::new().opt_level(OptLevel::Tiered { threshold: 3 }).profiler(profiler.clone());
+        let mut env = SyscallEnv::new(&config);
+        let mut regs = Registers::new(0x10000, 0);
+        let mut interp = Interpreter::new(&config);
+        // the loop body runs for the third time in the second block
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, u64::MAX), Stop::Hot(0x10008));
+        assert_eq!((interp.count(0x10008), regs.x[A0]), (3, 34));
+
+        // execution continues in the interpreter until the block is compiled
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 3), Stop::Yield);
+        interp.mark_compiled(0x10008);
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, u64::MAX), Stop::Compiled(0x10008));
+
+        let mut interp = Interpreter::new(&Config::new().opt_level(OptLevel::Tiered { threshold: 100 }));
+        interp.record_coverage();
+        let stop = interp.run(&mut env, &mut memory, &mut regs, u64::MAX);
+        assert_eq!(stop, Stop::Exit(GuestExit::Process(ExecutionResult::Exited(110))));
+        assert_eq!(env.clock.instret(), 2 + 10 * 3 + 4);
+        assert_eq!((profiler.counter(RUNTIME_INSTRUCTIONS), profiler.timer(RUNTIME_EXECUTE).unwrap().count), (36, 4));
+        let coverage = interp.take_coverage().unwrap();
+        assert_eq!(coverage.pcs().collect::<Vec<_>>(), [0x10008, 0x1000c, 0x10010, 0x10014, 0x10016, 0x1001a, 0x1001e]);
+
+        regs.pc = 0x10026;
+        let stop = interp.run(&mut env, &mut memory, &mut regs, u64::MAX);
+        assert_eq!(stop, Stop::Fault(GuestFault::Unmapped { vaddr: 8, access: Access::Read }));
+        assert_eq!(regs.pc, 0x10026);
+        // the faulting load still counts
+        assert_eq!(interp.csrs.events(HPM_EVENT_LOADS), 1);
+    }
+
+    #[test]
+    fn test_mret() {
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &0x3020_0073u32.to_le_bytes()).unwrap();
+        let config = Config::new();
+        let mut env = SyscallEnv::new(&config);
+        let mut interp = Interpreter::new(&config);
+
+        // a user mode guest may not mret
+        let mut regs = Registers::new(0x10000, 0);
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, u64::MAX), Stop::Illegal(0x10000));
+        // machine mode returns to the user mode code at mepc
+        regs.csr.privilege = Privilege::Machine;
+        regs.csr.mepc = 0x10008;
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, u64::MAX), Stop::Illegal(0x10008));
+        assert_eq!(regs.csr.privilege, Privilege::User);
+    }
+
+    #[test]
+    fn test_trap() {
+        let mut code = Vec::new();
+        for insn in [
+            0x73,                        // ecall
+            rtype(0x2f, 5, 2, 6, 0, 0),  // amoadd.w t0, zero, (t1)
+            itype(0x73, 5, 2, 0, 0x7c0), // csrr t0, 0x7c0
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX), (0x20000..0x21000, Perm::RW)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+        let config = Config::new().opt_level(OptLevel::Tiered { threshold: 100 });
+        let mut env = SyscallEnv::new(&config);
+        let mut interp = Interpreter::new(&config);
+
+        // with a handler installed the ecall goes to it, not to the VM
+        let mut regs = Registers::new(0x10000, 0);
+        regs.csr.mtvec = 0x10100;
+        regs.x[T1] = 0x20002;
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 1), Stop::Yield);
+        assert_eq!((regs.pc, regs.csr.mepc, regs.csr.mcause), (0x10100, 0x10000, Exception::EcallFromU as u64));
+        assert_eq!(regs.csr.privilege, Privilege::Machine);
+        assert_eq!(regs.csr.mret(), Some(0x10000));
+
+        regs.pc = 0x10004;
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 1), Stop::Yield);
+        assert_eq!((regs.csr.mepc, regs.csr.mcause, regs.csr.mtval), (0x10004, Exception::StoreMisaligned as u64, 0x20002));
+        // so does a CSR the VM does not implement
+        regs.pc = 0x10008;
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 1), Stop::Yield);
+        assert_eq!((regs.csr.mepc, regs.csr.mcause), (0x10008, Exception::IllegalInstruction as u64));
+        // without one the VM gets the fault
+        regs.csr.mtvec = 0;
+        regs.pc = 0x10004;
+        let stop = interp.run(&mut env, &mut memory, &mut regs, 1);
+        assert_eq!(stop, Stop::Fault(GuestFault::Misaligned { vaddr: 0x20002, access: Access::Write }));
+    }
+
+    #[test]
+    fn test_paging() {
+        let mut guest = TestMemory::new(&[(0x8000_0000..0x8001_0000, Perm::RWX)]);
+        let mut memory = guest.memory();
+        // 0x40000000 maps to the code at 0x80003000, through a three level
+        // table at 0x80000000
+        let (root, mid, leaf, code) = (0x8000_0000u64, 0x8000_1000u64, 0x8000_2000u64, 0x8000_3000u64);
+        let pte = |paddr: u64, flags: u64| ((paddr >> 12) << 10 | flags | 1).to_le_bytes();
+        memory.init(root + 8, &pte(mid, 0)).unwrap();
+        memory.init(mid, &pte(leaf, 0)).unwrap();
+        memory.init(leaf, &pte(code, 0b1010)).unwrap();
+        for (offset, insn) in [itype(0x13, 10, 0, 0, 7), 0x1200_0073].into_iter().enumerate() {
+            memory.init(code + offset as u64 * 4, &insn.to_le_bytes()).unwrap();
+        }
+        let config = Config::new().opt_level(OptLevel::Tiered { threshold: 100 });
+        let mut env = SyscallEnv::new(&config);
+        let mut interp = Interpreter::new(&config);
+
+        let mut regs = Registers::new(0x4000_0000, 0);
+        regs.csr.privilege = Privilege::Supervisor;
+        regs.csr.satp = 8 << 60 | root >> 12;
+        // li a0, 7 / sfence.vma / and the zeros after, not an instruction
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, u64::MAX), Stop::Illegal(0x4000_0008));
+        assert_eq!(regs.x[A0], 7);
+        regs.pc = 0x4000_1000;
+        let stop = interp.run(&mut env, &mut memory, &mut regs, u64::MAX);
+        assert_eq!(stop, Stop::Fault(GuestFault::PageFault { vaddr: 0x4000_1000, access: Access::Exec }));
+        // machine mode sees the physical addresses
+        regs.csr.privilege = Privilege::Machine;
+        regs.pc = code;
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, u64::MAX), Stop::Illegal(code + 8));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut code = Vec::new();
+        for insn in [
+            0x0001_02b7,                    // lui t0, 0x10
+            itype(0x13, 10, 0, 0, 7),       // addi a0, zero, 7
+            stype(0x23, 3, 5, 10, 0x400),   // sd a0, 0x400(t0)
+            itype(0x03, 11, 3, 5, 0x404),   // ld a1, 0x404(t0)
+            itype(0x13, 17, 0, 0, 94),      // addi a7, zero, 94
+            0x73,                           // ecall
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX | Perm::RW)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+        let config = Config::new();
+        let mut env = SyscallEnv::new(&config);
+        let mut interp = Interpreter::new(&config);
+        let mut regs = Registers::new(0x10000, 0);
+
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let log = reads.clone();
+        interp.watch(0x10404..0x10408, WatchKind::Write, WatchAction::Stop);
+        interp.watch(0x10400..0x10408, WatchKind::Read, WatchAction::Call(Arc::new(move |hit| log.lock().unwrap().push(*hit))));
+        // the store stops before it happens, and happens on resuming
+        let hit = WatchHit { pc: 0x10008, vaddr: 0x10400, len: 8, access: Access::Write, kind: WatchKind::Write, value: Some(7) };
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, u64::MAX), Stop::Watchpoint(hit));
+        assert_eq!((regs.pc, memory.read_u64(0x10400).unwrap()), (0x10008, 0));
+        let stop = interp.run(&mut env, &mut memory, &mut regs, u64::MAX);
+        assert_eq!(stop, Stop::Exit(GuestExit::Process(ExecutionResult::Exited(7))));
+        assert_eq!(*reads.lock().unwrap(), [WatchHit { pc: 0x1000c, vaddr: 0x10404, access: Access::Read, kind: WatchKind::Read, value: None, ..hit }]);
+        assert!(interp.unwatch(&(0x10404..0x10408), WatchKind::Write));
+        assert!(!interp.unwatch(&(0x10404..0x10408), WatchKind::Write));
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut code = Vec::new();
+        for insn in [itype(0x13, 5, 0, 5, 1), jtype(0, -4)] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+        memory.init(0x10100, &jtype(0, 0).to_le_bytes()).unwrap();
+        let config = Config::new()
+            .opt_level(OptLevel::Tiered { threshold: 100 })
+            .clock(ClockMode::Virtual { instructions_per_sec: TIMEBASE_FREQ });
+        let mut env = SyscallEnv::new(&config);
+        let clint = Arc::new(Mutex::new(Clint::new(env.clock.clone())));
+        let mut interp = Interpreter::new(&config).interrupt_source(clint.clone());
+
+        // the loop is preempted at the first block boundary past mtimecmp,
+        // and the handler spins with interrupts off
+        clint.lock().unwrap().write(0x4000, 8, 10);
+        let mut regs = Registers::new(0x10000, 0);
+        regs.csr.mtvec = 0x10100;
+        regs.csr.mie = MIP_MTIP;
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 20), Stop::Yield);
+        assert_eq!((regs.pc, regs.csr.mepc, regs.x[5]), (0x10100, 0x10000, 5));
+        assert_eq!(regs.csr.mcause, 1 << 63 | Interrupt::MachineTimer as u64);
+        assert_eq!(regs.csr.mip, MIP_MTIP);
+    }
+
+    #[test]
+    fn test_external_interrupt() {
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let config = Config::new().opt_level(OptLevel::Tiered { threshold: 100 });
+        let mut env = SyscallEnv::new(&config);
+        let plic = Arc::new(Mutex::new(Plic::new()));
+        let mut interp = plic::attach(&plic, &mut guest.map, Interpreter::new(&config)).unwrap();
+        let mut memory = guest.memory();
+        let mut code = Vec::new();
+        for insn in [itype(0x13, 5, 0, 5, 1), jtype(0, -4)] {
+            code.extend(insn.to_le_bytes());
+        }
+        memory.init(0x10000, &code).unwrap();
+        // the handler claims the interrupt, completes it and spins
+        let mut handler = Vec::new();
+        for insn in [itype(0x03, 11, 2, 10, 4), stype(0x23, 2, 10, 11, 4), jtype(0, 0)] {
+            handler.extend(insn.to_le_bytes());
+        }
+        memory.init(0x10100, &handler).unwrap();
+
+        // the guest gives the uart's source a priority and enables it, all
+        // through the PLIC's registers
+        memory.map().mmio_write(PLIC_BASE + 4 * 10, 4, 1).unwrap();
+        memory.map().mmio_write(PLIC_BASE + 0x2000, 4, 1 << 10).unwrap();
+        let mut regs = Registers::new(0x10000, 0);
+        regs.x[10] = PLIC_BASE + 0x20_0000;
+        regs.csr.mtvec = 0x10100;
+        regs.csr.mie = MIP_MEIP;
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 10), Stop::Yield);
+        assert_eq!(regs.csr.mcause, 0);
+
+        IrqLine::new(plic.clone(), 10).raise();
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 20), Stop::Yield);
+        assert_eq!((regs.pc, regs.x[11]), (0x10108, 10));
+        assert_eq!(regs.csr.mcause, 1 << 63 | Interrupt::MachineExternal as u64);
+        assert!((0x10000..0x10008).contains(&regs.csr.mepc));
+        // the line is still raised, so the source is pending again
+        assert_eq!(regs.csr.mip, MIP_MEIP);
+    }
+
+    #[test]
+    fn test_wfi() {
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &0x1050_0073u32.to_le_bytes()).unwrap();
+        memory.init(0x10100, &jtype(0, 0).to_le_bytes()).unwrap();
+        let config = Config::new()
+            .opt_level(OptLevel::Tiered { threshold: 100 })
+            .clock(ClockMode::Virtual { instructions_per_sec: TIMEBASE_FREQ });
+        let mut env = SyscallEnv::new(&config);
+        let clint = Arc::new(Mutex::new(Clint::new(env.clock.clone())));
+        let mut interp = Interpreter::new(&config).interrupt_source(clint.clone());
+
+        // the hart sleeps through to the timer instead of spinning
+        clint.lock().unwrap().write(0x4000, 8, 50_000);
+        let mut regs = Registers::new(0x10000, 0);
+        regs.csr.privilege = Privilege::Machine;
+        regs.csr.mstatus = MSTATUS_MIE;
+        regs.csr.mtvec = 0x10100;
+        regs.csr.mie = MIP_MTIP;
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 10), Stop::Yield);
+        assert_eq!((regs.pc, regs.csr.mepc), (0x10100, 0x10004));
+        assert!(env.clock.rdtime() >= 50_000 && env.clock.instret() < 20);
+
+        // and user mode may not use it
+        regs.pc = 0x10000;
+        regs.csr = CsrState::default();
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 10), Stop::Illegal(0x10000));
+    }
+
+    #[test]
+    fn test_expand_compressed() {
+        // c.addi sp, -16 / c.sdsp ra, 8(sp) / c.j -4 / c.beqz a0, 6
+        assert_eq!(expand_compressed(0x1141), Some(itype(0x13, 2, 0, 2, -16)));
+        assert_eq!(expand_compressed(0xe406), Some(stype(0x23, 3, 2, 1, 8)));
+        assert_eq!(expand_compressed(0xbff5), Some(jtype(0, -4)));
+        assert_eq!(expand_compressed(0xc119), Some(btype(0, 10, 0, 6)));
+        assert_eq!(expand_compressed(0), None);
+    }
+
+    #[test]
+    fn test_find_blocks() {
+        let mut code = itype(0x13, 10, 0, 0, 0).to_le_bytes().to_vec();
+        code.extend(0xc119u16.to_le_bytes()); // c.beqz a0, 6
+        code.extend(itype(0x13, 10, 0, 10, 1).to_le_bytes());
+        code.extend(jtype(0, -10).to_le_bytes());
+        code.extend(0x73u32.to_le_bytes()); // ecall
+        code.extend(itype(0x13, 10, 0, 10, 1).to_le_bytes());
+        assert_eq!(find_blocks(&code, 0x10000), [0x10000, 0x10006, 0x1000a, 0x1000e, 0x10012]);
+    }
+
+    /// Collects what a subscriber writes
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debug_runtime() {
+        let mut code = Vec::new();
+        for insn in [
+            itype(0x13, 17, 0, 0, 172), // addi a7, zero, 172
+            0x73,                       // ecall, getpid
+            itype(0x13, 17, 0, 0, 94),  // addi a7, zero, 94
+            0x73,                       // ecall, exit_group
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+        let config = Config::new().opt_level(OptLevel::Tiered { threshold: 100 }).debug_runtime(true);
+        let mut env = SyscallEnv::new(&config);
+        let mut interp = Interpreter::new(&config);
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt().with_max_level(tracing::Level::TRACE).with_ansi(false).with_writer(move || writer.clone()).finish();
+
+        let mut regs = Registers::new(0x10000, 0);
+        let stop = tracing::subscriber::with_default(subscriber, || interp.run(&mut env, &mut memory, &mut regs, u64::MAX));
+        assert!(matches!(stop, Stop::Exit(_)));
+        // each instruction and the syscall are traced, only in builds with
+        // the instrumentation
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.contains("pc=0x10008"), cfg!(feature = "debug-runtime"));
+        assert_eq!(output.contains("nr=172"), cfg!(feature = "debug-runtime"));
+    }
+}
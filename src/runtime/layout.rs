@@ -0,0 +1,85 @@
+use crate::frontend::page::Page;
+use crate::runtime::config::Aslr;
+use crate::runtime::rng::Rng;
+
+/// Top of the user address space, the sv39 limit
+pub const TASK_SIZE: u64 = Page::ADDRESS_LIMIT;
+/// Where PIE executables are loaded, 2/3 of the address space as linux does
+pub const PIE_BASE: u64 = (TASK_SIZE / 3 * 2) & !(Page::SIZE as u64 - 1);
+pub const STACK_TOP: u64 = TASK_SIZE - Page::SIZE as u64;
+/// Gap reserved for stack growth between the stack and the mmap area
+pub const STACK_GAP: u64 = 128 << 20;
+
+/// Random page bits for every randomized region (sv39 values from linux riscv)
+const PIE_RND_BITS: u32 = 18;
+const STACK_RND_BITS: u32 = 16;
+const MMAP_RND_BITS: u32 = 18;
+
+/// Where the loader places the image, the initial stack and the mmap area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// Added to every virtual address of a PIE image, zero for fixed executables
+    pub load_bias: u64,
+    pub stack_top: u64,
+    /// Highest address handed out by mmap, which grows downward
+    pub mmap_base: u64,
+}
+
+impl MemoryLayout {
+    pub fn new(aslr: Aslr, is_pie: bool) -> Self {
+        let mut rng = match aslr {
+            Aslr::Disabled => None,
+            Aslr::Random => Some(Rng::from_entropy()),
+            Aslr::Seeded(seed) => Some(Rng::new(seed)),
+        };
+        let mut random_pages = |bits: u32| match rng.as_mut() {
+            Some(rng) => rng.next_bits(bits) * Page::SIZE as u64,
+            None => 0,
+        };
+        // draw in a fixed order so that a seed always yields the same layout
+        let pie_offset = random_pages(PIE_RND_BITS);
+        let stack_offset = random_pages(STACK_RND_BITS);
+        let mmap_offset = random_pages(MMAP_RND_BITS);
+        let stack_top = STACK_TOP - stack_offset;
+        Self {
+            load_bias: if is_pie { PIE_BASE + pie_offset } else { 0 },
+            stack_top,
+            mmap_base: stack_top - STACK_GAP - mmap_offset,
+        }
+    }
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self::new(Aslr::Disabled, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_layout_is_deterministic() {
+        let a = MemoryLayout::new(Aslr::Seeded(42), true);
+        let b = MemoryLayout::new(Aslr::Seeded(42), true);
+        let c = MemoryLayout::new(Aslr::Seeded(43), true);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_layout_bounds() {
+        for seed in 0..64 {
+            let layout = MemoryLayout::new(Aslr::Seeded(seed), true);
+            assert_eq!(layout.load_bias % Page::SIZE as u64, 0);
+            assert_eq!(layout.stack_top % Page::SIZE as u64, 0);
+            assert_eq!(layout.mmap_base % Page::SIZE as u64, 0);
+            assert!(layout.load_bias >= PIE_BASE);
+            assert!(layout.load_bias + (1 << 32) < layout.mmap_base);
+            assert!(layout.stack_top <= STACK_TOP);
+        }
+        assert_eq!(MemoryLayout::new(Aslr::Seeded(1), false).load_bias, 0);
+        assert_eq!(MemoryLayout::default().stack_top, STACK_TOP);
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+use crate::runtime::config::Config;
+
+/// Instructions the guest runs between two checks of the wall clock
+pub const CHECK_INTERVAL: u64 = 1 << 20;
+
+/// The execution limit a guest ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Limit {
+    #[error("budget of {0} instructions")]
+    Instructions(u64),
+    #[error("wall time of {0:?}")]
+    WallTime(Duration),
+}
+
+/// The limits of one run, checked by `process::run` before each slice the
+/// guest runs, which ends no later than where the next check is due
+#[derive(Debug, Clone)]
+pub struct Limits {
+    max_instructions: Option<u64>,
+    max_wall_time: Option<Duration>,
+    start: Instant,
+}
+
+impl Limits {
+    /// Start the wall clock of the run
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_instructions: config.max_instructions,
+            max_wall_time: config.max_wall_time,
+            start: Instant::now(),
+        }
+    }
+
+    /// Check the limits after `executed` instructions, and give the count
+    /// at which to check again. Without a wall time limit that is only when
+    /// the instruction budget runs out.
+    pub fn check(&self, executed: u64) -> Result<u64, Limit> {
+        if let Some(max) = self.max_instructions.filter(|&max| executed >= max) {
+            return Err(Limit::Instructions(max));
+        }
+        if let Some(max) = self.max_wall_time.filter(|&max| self.start.elapsed() >= max) {
+            return Err(Limit::WallTime(max));
+        }
+        let next = match self.max_wall_time {
+            Some(_) => executed.saturating_add(CHECK_INTERVAL),
+            None => u64::MAX,
+        };
+        Ok(next.min(self.max_instructions.unwrap_or(u64::MAX)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let unlimited = Limits::new(&Config::new());
+        assert_eq!(unlimited.check(0), Ok(u64::MAX));
+
+        let limits = Limits::new(&Config::new().max_instructions(1000));
+        assert_eq!(limits.check(10), Ok(1000));
+        assert_eq!(limits.check(1000), Err(Limit::Instructions(1000)));
+
+        let limits = Limits::new(&Config::new().max_wall_time(Duration::from_secs(3600)));
+        assert_eq!(limits.check(5), Ok(5 + CHECK_INTERVAL));
+        let limits = Limits::new(&Config::new().max_wall_time(Duration::ZERO));
+        assert_eq!(limits.check(5), Err(Limit::WallTime(Duration::ZERO)));
+    }
+}
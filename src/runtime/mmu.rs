@@ -0,0 +1,317 @@
+use crate::frontend::page::Page;
+use crate::middleend::address_map::{Access, AddressMap, GuestFault, LinearMemory};
+use crate::runtime::csr::{Privilege, MSTATUS_MXR, MSTATUS_SUM};
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_SIZE: u64 = 8;
+const PPN_MASK: u64 = (1 << 44) - 1;
+const LEVELS: usize = 3;
+const VPN_BITS: u32 = 9;
+const PAGE_SHIFT: u32 = 12;
+const VPN_MASK: u64 = (1 << (VPN_BITS * LEVELS as u32)) - 1;
+
+/// Entries of the direct mapped software TLB
+pub const TLB_SIZE: usize = 64;
+
+/// Address translation mode selected by `satp.MODE`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TranslationMode {
+    /// Guest addresses are used as they are
+    Bare,
+    Sv39,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct TlbEntry {
+    valid: bool,
+    asid: u16,
+    vpn: u64,
+    ppn: u64,
+    /// Leaf PTE flags, with D only set once a write went through the walker
+    flags: u64,
+}
+
+/// Guest MMU walking the Sv39 page table rooted at `satp`
+#[derive(Clone, Debug)]
+pub struct Mmu {
+    mode: TranslationMode,
+    asid: u16,
+    root_ppn: u64,
+    /// Whether the hart runs in user mode, which only reaches user pages
+    user: bool,
+    /// `mstatus.SUM` and `mstatus.MXR`
+    status: u64,
+    tlb: [TlbEntry; TLB_SIZE],
+}
+
+impl Mmu {
+    pub fn new() -> Self {
+        Self {
+            mode: TranslationMode::Bare,
+            asid: 0,
+            root_ppn: 0,
+            user: false,
+            status: 0,
+            tlb: [TlbEntry::default(); TLB_SIZE],
+        }
+    }
+
+    pub fn mode(&self) -> TranslationMode {
+        self.mode
+    }
+
+    pub fn satp(&self) -> u64 {
+        let mode = match self.mode {
+            TranslationMode::Bare => 0,
+            TranslationMode::Sv39 => 8,
+        };
+        (mode << 60) | ((self.asid as u64) << 44) | self.root_ppn
+    }
+
+    /// Apply a write of `satp`, unsupported modes leave it unchanged as the
+    /// privileged spec requires
+    pub fn set_satp(&mut self, satp: u64) {
+        self.mode = match satp >> 60 {
+            0 => TranslationMode::Bare,
+            8 => TranslationMode::Sv39,
+            _ => return,
+        };
+        self.asid = (satp >> 44) as u16;
+        self.root_ppn = satp & PPN_MASK;
+    }
+
+    /// Check the accesses from here on as made at `privilege` under
+    /// `mstatus`, below machine mode which does not translate
+    pub fn set_privilege(&mut self, privilege: Privilege, mstatus: u64) {
+        self.user = privilege == Privilege::User;
+        self.status = mstatus & (MSTATUS_SUM | MSTATUS_MXR);
+    }
+
+    pub fn flush(&mut self) {
+        self.tlb.iter_mut().for_each(|e| e.valid = false);
+    }
+
+    pub fn flush_vaddr(&mut self, vaddr: u64) {
+        let vpn = (vaddr >> PAGE_SHIFT) & VPN_MASK;
+        let entry = &mut self.tlb[vpn as usize % TLB_SIZE];
+        if entry.vpn == vpn {
+            entry.valid = false;
+        }
+    }
+
+    /// Translate a guest virtual address into a guest physical address, the
+    /// page table itself is read through the address map from `memory`
+    pub fn translate(
+        &mut self,
+        vaddr: u64,
+        access: Access,
+        map: &AddressMap,
+        memory: &mut (impl LinearMemory + ?Sized),
+    ) -> Result<u64, GuestFault> {
+        if self.mode == TranslationMode::Bare {
+            return Ok(vaddr);
+        }
+        let fault = GuestFault::PageFault { vaddr, access };
+        // bits 63..39 have to be copies of bit 38
+        if ((vaddr as i64) << 25 >> 25) as u64 != vaddr {
+            return Err(fault);
+        }
+        let vpn = (vaddr >> PAGE_SHIFT) & VPN_MASK;
+        let page_offset = vaddr & (Page::SIZE as u64 - 1);
+        let entry = self.tlb[vpn as usize % TLB_SIZE];
+        if entry.valid
+            && entry.vpn == vpn
+            && entry.asid == self.asid
+            && self.allows(entry.flags, access)
+            && (access != Access::Write || entry.flags & PTE_D != 0)
+        {
+            return Ok((entry.ppn << PAGE_SHIFT) | page_offset);
+        }
+
+        let (pte_offset, mut pte, ppn) = self.walk(vpn, map, memory).ok_or(fault)?;
+        if !self.allows(pte, access) {
+            return Err(fault);
+        }
+        let dirty = if access == Access::Write { PTE_D } else { 0 };
+        if pte & (PTE_A | dirty) != PTE_A | dirty {
+            pte |= PTE_A | dirty;
+            memory.write_u64(pte_offset, pte).map_err(|_| fault)?;
+        }
+        self.tlb[vpn as usize % TLB_SIZE] = TlbEntry {
+            valid: true,
+            asid: self.asid,
+            vpn,
+            ppn,
+            flags: pte,
+        };
+        Ok((ppn << PAGE_SHIFT) | page_offset)
+    }
+
+    /// The guest physical address of `vaddr` whatever the permissions of its
+    /// page, leaving the TLB and the accessed bits alone, for debuggers
+    /// looking at the memory of a guest
+    pub fn peek(&self, vaddr: u64, map: &AddressMap, memory: &(impl LinearMemory + ?Sized)) -> Option<u64> {
+        if self.mode == TranslationMode::Bare {
+            return Some(vaddr);
+        }
+        if ((vaddr as i64) << 25 >> 25) as u64 != vaddr {
+            return None;
+        }
+        let vpn = (vaddr >> PAGE_SHIFT) & VPN_MASK;
+        let (_, _, ppn) = self.walk(vpn, map, memory)?;
+        Some((ppn << PAGE_SHIFT) | (vaddr & (Page::SIZE as u64 - 1)))
+    }
+
+    /// Walk the page table to the leaf of `vpn`, giving the linear offset
+    /// of the leaf PTE, the PTE and the physical page
+    fn walk(&self, vpn: u64, map: &AddressMap, memory: &(impl LinearMemory + ?Sized)) -> Option<(usize, u64, u64)> {
+        let mut table = self.root_ppn << PAGE_SHIFT;
+        for level in (0..LEVELS).rev() {
+            let index = (vpn >> (VPN_BITS * level as u32)) & ((1 << VPN_BITS) - 1);
+            let pte_offset = map.vaddr_to_offset(table + index * PTE_SIZE)?;
+            let pte = memory.read_u64(pte_offset).ok()?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return None;
+            }
+            let ppn = (pte >> 10) & PPN_MASK;
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+            // a superpage has to be aligned to its size
+            let low_mask = (1 << (VPN_BITS * level as u32)) - 1;
+            if ppn & low_mask != 0 {
+                return None;
+            }
+            return Some((pte_offset, pte, ppn | (vpn & low_mask)));
+        }
+        None
+    }
+
+    /// Whether the leaf `flags` allow `access` at the privilege of the
+    /// hart: user mode reaches only user pages, supervisor mode never runs
+    /// them and loads and stores them only with SUM, and with MXR loads
+    /// read executable pages as well
+    fn allows(&self, flags: u64, access: Access) -> bool {
+        let user_page = flags & PTE_U != 0;
+        let reachable = match self.user {
+            true => user_page,
+            false => !user_page || (access != Access::Exec && self.status & MSTATUS_SUM != 0),
+        };
+        let needed = match access {
+            Access::Read if self.status & MSTATUS_MXR != 0 => PTE_R | PTE_X,
+            Access::Read => PTE_R,
+            Access::Write => PTE_W,
+            Access::Exec => PTE_X,
+        };
+        reachable && flags & needed != 0
+    }
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    fn pte(ppn: u64, flags: u64) -> u64 {
+        (ppn << 10) | flags | PTE_V
+    }
+
+    #[test]
+    fn test_sv39_walk() {
+        let mut guest = TestMemory::new(&[]);
+        // identity map 0x80000000.. as guest physical memory
+        let base = guest.map.map(0x8000_0000..0x8001_0000, Perm::RWX).unwrap();
+        let TestMemory { map, linear: mut memory } = guest;
+        let root = 0x8000_0000u64;
+        let mid = 0x8000_1000u64;
+        let leaf = 0x8000_2000u64;
+        let vaddr = 0x4020_3123u64;
+        let at = |paddr: u64| base + (paddr - 0x8000_0000) as usize;
+        memory
+            .write_u64(at(root + ((vaddr >> 30) & 0x1ff) * 8), pte(mid >> 12, 0))
+            .unwrap();
+        memory
+            .write_u64(at(mid + ((vaddr >> 21) & 0x1ff) * 8), pte(leaf >> 12, 0))
+            .unwrap();
+        let leaf_pte = at(leaf + ((vaddr >> 12) & 0x1ff) * 8);
+        memory
+            .write_u64(leaf_pte, pte(0x8000_5, PTE_R | PTE_W))
+            .unwrap();
+
+        let mut mmu = Mmu::new();
+        mmu.set_satp((8 << 60) | (root >> 12));
+        assert_eq!(mmu.mode(), TranslationMode::Sv39);
+        // looking leaves no trace
+        assert_eq!(mmu.peek(vaddr, &map, &memory), Some(0x8000_5123));
+        assert_eq!(memory.read_u64(leaf_pte).unwrap() & PTE_A, 0);
+        assert_eq!(
+            mmu.translate(vaddr, Access::Read, &map, &mut memory),
+            Ok(0x8000_5123)
+        );
+        assert_eq!(memory.read_u64(leaf_pte).unwrap() & PTE_A, PTE_A);
+        assert_eq!(memory.read_u64(leaf_pte).unwrap() & PTE_D, 0);
+        mmu.translate(vaddr, Access::Write, &map, &mut memory)
+            .unwrap();
+        assert_eq!(memory.read_u64(leaf_pte).unwrap() & PTE_D, PTE_D);
+        assert_eq!(
+            mmu.translate(vaddr, Access::Exec, &map, &mut memory),
+            Err(GuestFault::PageFault {
+                vaddr,
+                access: Access::Exec
+            })
+        );
+        assert!(mmu
+            .translate(0x1000, Access::Read, &map, &mut memory)
+            .is_err());
+
+        // a cached translation survives until the tlb is flushed
+        memory.write_u64(leaf_pte, 0).unwrap();
+        assert!(mmu.translate(vaddr, Access::Read, &map, &mut memory).is_ok());
+        mmu.flush_vaddr(vaddr);
+        assert!(mmu.translate(vaddr, Access::Read, &map, &mut memory).is_err());
+
+        // addresses above the hole flush the entry they translated to
+        let high = 0xffff_ffc0_0020_3123u64;
+        memory.write_u64(at(root + 0x100 * 8), pte(mid >> 12, 0)).unwrap();
+        memory.write_u64(leaf_pte, pte(0x8000_5, PTE_R | PTE_X)).unwrap();
+        assert_eq!(mmu.translate(high, Access::Read, &map, &mut memory), Ok(0x8000_5123));
+        memory.write_u64(leaf_pte, 0).unwrap();
+        assert!(mmu.translate(high, Access::Read, &map, &mut memory).is_ok());
+        mmu.flush_vaddr(high);
+        assert!(mmu.translate(high, Access::Read, &map, &mut memory).is_err());
+
+        // user pages are for user mode, and for supervisor loads and stores
+        // with SUM
+        let denied = |access| Err(GuestFault::PageFault { vaddr, access });
+        memory.write_u64(leaf_pte, pte(0x8000_5, PTE_R | PTE_X | PTE_U)).unwrap();
+        mmu.flush();
+        assert_eq!(mmu.translate(vaddr, Access::Read, &map, &mut memory), denied(Access::Read));
+        mmu.set_privilege(Privilege::Supervisor, MSTATUS_SUM);
+        assert_eq!(mmu.translate(vaddr, Access::Read, &map, &mut memory), Ok(0x8000_5123));
+        assert_eq!(mmu.translate(vaddr, Access::Exec, &map, &mut memory), denied(Access::Exec));
+        mmu.set_privilege(Privilege::User, 0);
+        assert_eq!(mmu.translate(vaddr, Access::Exec, &map, &mut memory), Ok(0x8000_5123));
+        memory.write_u64(leaf_pte, pte(0x8000_5, PTE_X)).unwrap();
+        mmu.flush();
+        assert_eq!(mmu.translate(vaddr, Access::Exec, &map, &mut memory), denied(Access::Exec));
+        // MXR reads pages that are only executable
+        mmu.set_privilege(Privilege::Supervisor, MSTATUS_MXR);
+        assert_eq!(mmu.translate(vaddr, Access::Read, &map, &mut memory), Ok(0x8000_5123));
+        mmu.set_privilege(Privilege::Supervisor, 0);
+        assert_eq!(mmu.translate(vaddr, Access::Read, &map, &mut memory), denied(Access::Read));
+    }
+}
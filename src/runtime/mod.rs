@@ -1 +1,23 @@
+pub mod clint;
+pub mod clock;
+pub mod config;
+pub mod coverage;
 pub mod csr;
+pub mod dispatch;
+pub mod error;
+pub mod execution;
+pub mod float;
+pub mod interp;
+pub mod layout;
+pub mod limits;
+pub mod mmu;
+pub mod pgo;
+pub mod plic;
+pub mod process;
+pub mod regs;
+pub mod replay;
+pub mod rng;
+pub mod snapshot;
+pub mod stack;
+pub mod syscall;
+pub mod tier;
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+
+/// Share of the runs of a block its successor needs for a trace to carry on
+/// into it
+pub const TRACE_BIAS: f64 = 0.9;
+/// Blocks a trace is made of at most
+pub const MAX_TRACE_BLOCKS: usize = 16;
+
+/// How often each block ran and which block followed it, recorded in one
+/// run to lay out the code of the next compilations: hot successors become
+/// the fallthrough and blocks that never ran go out of line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockProfile {
+    counts: HashMap<u64, u64>,
+    edges: HashMap<(u64, u64), u64>,
+}
+
+impl BlockProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The block at `pc` ran and continued at `next`
+    pub fn record(&mut self, pc: u64, next: u64) {
+        *self.counts.entry(pc).or_default() += 1;
+        *self.edges.entry((pc, next)).or_default() += 1;
+    }
+
+    pub fn count(&self, pc: u64) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or(0)
+    }
+
+    /// The blocks that ran and how often, in no particular order
+    pub fn blocks(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.counts.iter().map(|(&pc, &count)| (pc, count))
+    }
+
+    /// A block that never ran in the profiled run
+    pub fn is_cold(&self, pc: u64) -> bool {
+        self.count(pc) == 0
+    }
+
+    /// Times the block at `pc` continued at `next`
+    pub fn edge(&self, pc: u64, next: u64) -> u64 {
+        self.edges.get(&(pc, next)).copied().unwrap_or(0)
+    }
+
+    /// The successor the block at `pc` continued at most often
+    pub fn likely_successor(&self, pc: u64) -> Option<u64> {
+        self.edges
+            .iter()
+            .filter(|(&(from, _), _)| from == pc)
+            .max_by_key(|(&(_, to), &count)| (count, std::cmp::Reverse(to)))
+            .map(|(&(_, to), _)| to)
+    }
+
+    /// The superblock starting at `start`: the blocks reached by following
+    /// a successor as long as it is taken in `TRACE_BIAS` of the runs of the
+    /// block before it. A block seen before ends the trace, so a loop ends
+    /// it at its back-edge.
+    pub fn trace(&self, start: u64) -> Vec<u64> {
+        let mut trace = vec![start];
+        let mut pc = start;
+        while trace.len() < MAX_TRACE_BLOCKS {
+            let Some(next) = self.likely_successor(pc) else {
+                break;
+            };
+            if (self.edge(pc, next) as f64) < TRACE_BIAS * self.count(pc) as f64 || trace.contains(&next) {
+                break;
+            }
+            trace.push(next);
+            pc = next;
+        }
+        trace
+    }
+
+    /// Order `blocks` for emission: chains of likely successors starting
+    /// from the hottest blocks, then the cold blocks by address
+    pub fn layout(&self, blocks: &[u64]) -> Vec<u64> {
+        let wanted: HashSet<u64> = blocks.iter().copied().collect();
+        let (mut hot, mut cold): (Vec<u64>, Vec<u64>) = blocks.iter().partition(|&&pc| !self.is_cold(pc));
+        hot.sort_by_key(|&pc| (std::cmp::Reverse(self.count(pc)), pc));
+        cold.sort_unstable();
+        let mut placed = HashSet::new();
+        let mut order = Vec::with_capacity(blocks.len());
+        for start in hot {
+            let mut pc = start;
+            while wanted.contains(&pc) && !self.is_cold(pc) && placed.insert(pc) {
+                order.push(pc);
+                match self.likely_successor(pc) {
+                    Some(next) => pc = next,
+                    None => break,
+                }
+            }
+        }
+        order.extend(cold);
+        order
+    }
+
+    /// Write the profile out as text, one `pc next count` edge per line
+    pub fn save(&self, mut out: impl Write) -> io::Result<()> {
+        let mut edges: Vec<_> = self.edges.iter().collect();
+        edges.sort_unstable();
+        for (&(pc, next), count) in edges {
+            writeln!(out, "{:#x} {:#x} {}", pc, next, count)?;
+        }
+        Ok(())
+    }
+
+    /// Read a profile written by `save`
+    pub fn load(input: impl BufRead) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed block profile");
+        let hex = |field: Option<&str>| {
+            let field = field.and_then(|f| f.strip_prefix("0x")).ok_or_else(invalid)?;
+            u64::from_str_radix(field, 16).map_err(|_| invalid())
+        };
+        let mut profile = Self::new();
+        for line in input.lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            let (pc, next) = (hex(fields.next())?, hex(fields.next())?);
+            let count: u64 = fields.next().and_then(|f| f.parse().ok()).ok_or_else(invalid)?;
+            *profile.counts.entry(pc).or_default() += count;
+            *profile.edges.entry((pc, next)).or_default() += count;
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_and_save() {
+        let mut profile = BlockProfile::new();
+        // a loop 0x100 -> 0x180 -> 0x100 that leaves through 0x140 once
+        profile.record(0x80, 0x100);
+        for _ in 0..10 {
+            profile.record(0x100, 0x180);
+            profile.record(0x180, 0x100);
+        }
+        profile.record(0x100, 0x140);
+        profile.record(0x140, 0x200);
+        assert_eq!(profile.likely_successor(0x100), Some(0x180));
+        assert_eq!(
+            profile.layout(&[0x80, 0x100, 0x140, 0x180, 0x1c0, 0x200]),
+            [0x100, 0x180, 0x80, 0x140, 0x1c0, 0x200]
+        );
+
+        // 0x100 continues at 0x180 in 10 of its 11 runs, 0x140 always at 0x200
+        assert_eq!(profile.trace(0x100), [0x100, 0x180]);
+        assert_eq!(profile.trace(0x80), [0x80, 0x100, 0x180]);
+        assert_eq!(profile.trace(0x140), [0x140, 0x200]);
+
+        let mut text = Vec::new();
+        profile.save(&mut text).unwrap();
+        assert!(text.starts_with(b"0x80 0x100 1\n"));
+        assert_eq!(BlockProfile::load(&text[..]).unwrap(), profile);
+        assert!(BlockProfile::load(&b"0x80 x\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::{Arc, Mutex};
+
+use crate::middleend::address_map::{AddressMap, MemoryError, MmioDevice};
+use crate::runtime::csr::{InterruptSource, MIP_MEIP, MIP_SEIP};
+use crate::runtime::interp::Interpreter;
+
+/// Where the PLIC sits on the qemu virt board
+pub const PLIC_BASE: u64 = 0xc00_0000;
+pub const PLIC_SIZE: u64 = 0x40_0000;
+
+/// Interrupt sources, of which source 0 stands for none
+pub const PLIC_SOURCES: usize = 32;
+/// The contexts of the hart, which interrupt its machine and supervisor
+/// mode through `mip.MEIP` and `mip.SEIP`
+pub const MACHINE_CONTEXT: usize = 0;
+pub const SUPERVISOR_CONTEXT: usize = 1;
+const CONTEXTS: usize = 2;
+
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+/// Enable bits of a context, every `ENABLE_STRIDE` bytes
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+/// Threshold and claim register of a context, every `CONTEXT_STRIDE` bytes
+const THRESHOLD: u64 = 0x20_0000;
+const CLAIM: u64 = 0x20_0004;
+const CONTEXT_STRIDE: u64 = 0x1000;
+/// Priorities go from 0, never interrupting, to 7
+const PRIORITY_MASK: u32 = 7;
+
+/// The platform level interrupt controller, routing the interrupts of
+/// device models to a single hart. An interrupt is delivered to a context
+/// when its source is enabled there and has a priority above the context's
+/// threshold, and the hart claims it to handle it and completes it after.
+#[derive(Debug, Default)]
+pub struct Plic {
+    priority: [u32; PLIC_SOURCES],
+    /// Levels of the interrupt lines, by bit
+    level: u32,
+    pending: u32,
+    enabled: [u32; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+    /// Sources claimed and not completed yet, which are not pending again
+    /// until then
+    claimed: u32,
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Raise or lower the line of `source`
+    pub fn set_level(&mut self, source: usize, level: bool) {
+        if source == 0 || source >= PLIC_SOURCES {
+            return;
+        }
+        let bit = 1 << source;
+        if level {
+            self.level |= bit;
+            self.pending |= bit & !self.claimed;
+        } else {
+            self.level &= !bit;
+            self.pending &= !bit;
+        }
+    }
+
+    /// The source to deliver to `context`: the pending and enabled one of
+    /// the highest priority above the threshold, the lowest numbered among
+    /// equals
+    fn best(&self, context: usize) -> Option<usize> {
+        (1..PLIC_SOURCES)
+            .filter(|&source| self.pending & self.enabled[context] & 1 << source != 0)
+            .filter(|&source| self.priority[source] > self.threshold[context])
+            .min_by_key(|&source| (std::cmp::Reverse(self.priority[source]), source))
+    }
+
+    /// Take the interrupt to deliver to `context`, 0 for none
+    pub fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.best(context) else { return 0 };
+        self.pending &= !(1 << source);
+        self.claimed |= 1 << source;
+        source as u32
+    }
+
+    /// Finish handling `source`, which is pending again if its line is
+    /// still raised
+    pub fn complete(&mut self, source: u32) {
+        let Some(bit) = 1u32.checked_shl(source) else { return };
+        if self.claimed & bit != 0 {
+            self.claimed &= !bit;
+            self.pending |= bit & self.level;
+        }
+    }
+
+    /// `MIP_MEIP` and `MIP_SEIP` while an interrupt is waiting to be
+    /// claimed by the context
+    pub fn pending(&self) -> u64 {
+        let machine = if self.best(MACHINE_CONTEXT).is_some() { MIP_MEIP } else { 0 };
+        let supervisor = if self.best(SUPERVISOR_CONTEXT).is_some() { MIP_SEIP } else { 0 };
+        machine | supervisor
+    }
+}
+
+/// The register of a context at `offset`, as the context and the offset
+/// of the register from the context's first
+fn context_register(offset: u64, base: u64, stride: u64) -> Option<(usize, u64)> {
+    let context = ((offset - base) / stride) as usize;
+    (context < CONTEXTS).then_some((context, (offset - base) % stride))
+}
+
+impl MmioDevice for Plic {
+    fn read(&mut self, offset: u64, _width: u8) -> u64 {
+        let value = match offset {
+            PRIORITY..PENDING => self.priority.get(offset as usize / 4).copied().unwrap_or(0),
+            PENDING => self.pending,
+            ENABLE..THRESHOLD => match context_register(offset, ENABLE, ENABLE_STRIDE) {
+                Some((context, 0)) => self.enabled[context],
+                _ => 0,
+            },
+            THRESHOLD.. => match context_register(offset, THRESHOLD, CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context],
+                Some((context, register)) if register == CLAIM - THRESHOLD => self.claim(context),
+                _ => 0,
+            },
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, offset: u64, _width: u8, value: u64) {
+        let value = value as u32;
+        match offset {
+            PRIORITY..PENDING => {
+                if let Some(priority) = self.priority.get_mut(offset as usize / 4).filter(|_| offset >= 4) {
+                    *priority = value & PRIORITY_MASK;
+                }
+            }
+            ENABLE..THRESHOLD => {
+                if let Some((context, 0)) = context_register(offset, ENABLE, ENABLE_STRIDE) {
+                    // source 0 does not exist
+                    self.enabled[context] = value & !1;
+                }
+            }
+            THRESHOLD.. => match context_register(offset, THRESHOLD, CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context] = value & PRIORITY_MASK,
+                Some((_, register)) if register == CLAIM - THRESHOLD => self.complete(value),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Put `plic` on the board: route the guest's loads and stores at
+/// `PLIC_BASE` to it, and have `interp` take the interrupts it raises
+pub fn attach(plic: &Arc<Mutex<Plic>>, map: &mut AddressMap, interp: Interpreter) -> Result<Interpreter, MemoryError> {
+    map.map_mmio(PLIC_BASE..PLIC_BASE + PLIC_SIZE, plic.clone())?;
+    Ok(interp.interrupt_source(plic.clone()))
+}
+
+impl InterruptSource for Mutex<Plic> {
+    fn pending(&self) -> u64 {
+        self.lock().unwrap().pending()
+    }
+}
+
+/// The interrupt line of a device model to the PLIC
+#[derive(Debug, Clone)]
+pub struct IrqLine {
+    plic: Arc<Mutex<Plic>>,
+    source: usize,
+}
+
+impl IrqLine {
+    pub fn new(plic: Arc<Mutex<Plic>>, source: usize) -> Self {
+        Self { plic, source }
+    }
+
+    pub fn raise(&self) {
+        self.plic.lock().unwrap().set_level(self.source, true);
+    }
+
+    pub fn lower(&self) {
+        self.plic.lock().unwrap().set_level(self.source, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_complete() {
+        let plic = Arc::new(Mutex::new(Plic::new()));
+        let (uart, virtio) = (IrqLine::new(plic.clone(), 10), IrqLine::new(plic.clone(), 1));
+        let mut guard = plic.lock().unwrap();
+        guard.write(4 * 10, 4, 1);
+        guard.write(4, 4, 3);
+        guard.write(ENABLE, 4, 1 << 10 | 1 << 1);
+        drop(guard);
+
+        uart.raise();
+        virtio.raise();
+        let mut plic = plic.lock().unwrap();
+        assert_eq!(plic.read(PENDING, 4), 1 << 10 | 1 << 1);
+        assert_eq!(plic.pending(), MIP_MEIP);
+        // the higher priority goes first, and the threshold masks the other
+        plic.write(THRESHOLD, 4, 1);
+        assert_eq!(plic.read(CLAIM, 4), 1);
+        assert_eq!((plic.read(CLAIM, 4), plic.pending()), (0, 0));
+        plic.write(THRESHOLD, 4, 0);
+        assert_eq!(plic.read(CLAIM, 4), 10);
+        // a line still raised is pending again once completed
+        plic.write(CLAIM, 4, 10);
+        assert_eq!(plic.read(PENDING, 4), 1 << 10);
+        plic.set_level(10, false);
+        plic.set_level(1, false);
+        plic.write(CLAIM, 4, 1);
+        assert_eq!(plic.pending(), 0);
+
+        // the supervisor context has its own enables, threshold and claim,
+        // and a source claimed by one context is gone for the other
+        plic.write(ENABLE + ENABLE_STRIDE, 4, 1 << 10);
+        plic.set_level(10, true);
+        assert_eq!(plic.pending(), MIP_MEIP | MIP_SEIP);
+        plic.write(THRESHOLD + CONTEXT_STRIDE, 4, 1);
+        assert_eq!(plic.pending(), MIP_MEIP);
+        plic.write(THRESHOLD + CONTEXT_STRIDE, 4, 0);
+        assert_eq!(plic.read(CLAIM + CONTEXT_STRIDE, 4), 10);
+        assert_eq!((plic.read(CLAIM, 4), plic.pending()), (0, 0));
+    }
+}
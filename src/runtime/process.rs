@@ -0,0 +1,637 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::error::VmError;
+use crate::frontend::elf::{Class, Data, ElfError, ElfFile, Machine, ProgramHeaderType, Type};
+use crate::frontend::page::Page;
+use crate::middleend::address_map::{AddressMap, GuestMemory, LinearMemory, MemoryError, Perm, SharedMemory};
+use crate::runtime::config::Config;
+use crate::runtime::execution::{ExecutionResult, GuestExit};
+use crate::runtime::interp::{Interpreter, Stop};
+use crate::runtime::layout::MemoryLayout;
+use crate::runtime::regs::Registers;
+use crate::runtime::rng::Entropy;
+use crate::runtime::stack::{setup_stack, AT_BASE, AT_ENTRY, AT_PHDR, AT_PHENT, AT_PHNUM};
+use crate::runtime::syscall::mm::{self, Mappings};
+use crate::runtime::syscall::signal::{self, Delivery, SigInfo, SIGILL, SIGKILL, SIGRETURN_TRAMPOLINE, SIGTRAP};
+use crate::runtime::syscall::exec::NewProcess;
+use crate::runtime::syscall::thread::NewThread;
+use crate::runtime::syscall::{Errno, SyscallEnv, EAGAIN};
+use crate::runtime::tier::{Leave, Tier};
+use crate::tools::perf::FRONTEND_DECODE;
+use crate::wasm::block::BlockEngine;
+use crate::wasm::disk_cache::DiskCache;
+use crate::wasm::wasm_builder::WasmBuilder;
+
+const PAGE: u64 = Page::SIZE as u64;
+/// Bytes of stack mapped below the stack top, linux's default
+/// `RLIMIT_STACK`
+pub const STACK_SIZE: u64 = 8 << 20;
+/// Bytes of the mmap area, below `MemoryLayout::mmap_base`
+pub const MMAP_SIZE: u64 = 128 << 20;
+/// Bytes brk can grow the heap by, above the end of the image
+pub const BRK_SIZE: u64 = 16 << 20;
+/// Instructions a thread runs before the engine checks whether another
+/// thread ended the process
+const SLICE: u64 = 1 << 16;
+
+/// Why an executable cannot be loaded
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessError {
+    #[error(transparent)]
+    Elf(#[from] ElfError),
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+    /// The executable is dynamically linked and needs the dynamic linker
+    /// at the path, which the VM does not load
+    #[error("dynamically linked executables are not supported, {0} would load it")]
+    Interpreter(String),
+    #[error("the executable has no loadable segments")]
+    NoSegments,
+    /// The interpreter does not implement the instruction at the pc, a
+    /// system one it does not know
+    #[error("unsupported instruction at {0:#x}")]
+    Unsupported(u64),
+}
+
+/// A loadable segment of an executable, at its address after the load bias
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: Range<u64>,
+    /// The bytes of the file the segment starts with, the rest up to its
+    /// memory size being zero, as for `.bss`
+    pub file: Range<usize>,
+    pub perm: Perm,
+}
+
+/// Where the segments and the stack of an executable go and how it starts,
+/// as the linux ELF loader sets a process up: the segments copied in the
+/// order of their program headers, the initial stack with argv, envp and the
+/// auxiliary vector, and pc at the entry point. The thread pointer stays
+/// zero, the libc sets up TLS from the `tls` template it finds through
+/// `AT_PHDR`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessImage {
+    pub segments: Vec<Segment>,
+    /// Added to every address of a PIE executable
+    pub load_bias: u64,
+    pub entry: u64,
+    /// Where the program headers are in guest memory, `AT_PHDR`, zero when
+    /// no segment loads them
+    pub phdr: u64,
+    pub phent: u64,
+    pub phnum: u64,
+    /// The page after the highest segment, where the heap starts
+    pub brk: u64,
+    /// The initial TLS image of each thread, `PT_TLS`
+    pub tls: Option<Range<u64>>,
+    pub stack: Range<u64>,
+    /// The page above the stack holding `SIGRETURN_TRAMPOLINE`, which
+    /// signal handlers return to, in place of the vDSO of linux
+    pub trampoline: u64,
+}
+
+impl ProcessImage {
+    /// The image of the executable `elf`, placed where `layout` says
+    pub fn new(elf: &ElfFile, layout: &MemoryLayout) -> Result<Self, ProcessError> {
+        let header = &elf.header_part2;
+        if elf.header_part1.get_class() != Class::SixtyFour
+            || elf.header_part1.get_data() != Data::LittleEndian
+            || header.get_machine() != Machine::RISCV
+            || !matches!(header.get_type(), Type::Executable | Type::SharedObject)
+        {
+            return Err(ElfError::NotMeet("not a little endian RV64 executable".into()).into());
+        }
+        if let Some(interp) = elf.program_iter().find(|ph| ph.get_type() == ProgramHeaderType::Interp) {
+            let offset = interp.get_offset() as usize;
+            let path = elf.input.get(offset..offset + interp.get_file_size() as usize).unwrap_or_default();
+            return Err(ProcessError::Interpreter(String::from_utf8_lossy(path).trim_end_matches('\0').to_string()));
+        }
+        let bias = layout.load_bias;
+        let ph_offset = elf.header_part2.get_ph_offset();
+        let (mut segments, mut phdr, mut tls) = (Vec::new(), 0, None);
+        for ph in elf.program_iter() {
+            let start = bias.wrapping_add(ph.get_virtual_addr());
+            let end = start.checked_add(ph.get_mem_size());
+            let vaddr = start..end.ok_or_else(|| ElfError::Malformed(format!("segment at {:#x} wraps around", start)))?;
+            match ph.get_type() {
+                ProgramHeaderType::Load => {
+                    let end = ph.get_offset().saturating_add(ph.get_file_size());
+                    let file = ph.get_offset() as usize..end as usize;
+                    if ph.get_file_size() > ph.get_mem_size() || end > elf.input.len() as u64 {
+                        return Err(ElfError::Malformed(format!("segment at {:#x} past its end", vaddr.start)).into());
+                    }
+                    // without PT_PHDR, the program headers are where the
+                    // segment holding them loads them
+                    if phdr == 0 && (ph.get_offset()..ph.get_offset() + ph.get_file_size()).contains(&ph_offset) {
+                        phdr = vaddr.start + ph_offset - ph.get_offset();
+                    }
+                    segments.push(Segment { vaddr, file, perm: Perm::from_elf_flags(ph.get_flags()) });
+                }
+                ProgramHeaderType::Phdr => phdr = vaddr.start,
+                ProgramHeaderType::Tls => tls = Some(vaddr),
+                _ => {}
+            }
+        }
+        let brk = segments.iter().map(|segment| segment.vaddr.end).max().ok_or(ProcessError::NoSegments)?;
+        Ok(Self {
+            segments,
+            load_bias: bias,
+            entry: bias + elf.header_part2.get_entry_point(),
+            phdr,
+            phent: elf.header_part2.get_ph_entry_size() as u64,
+            phnum: elf.header_part2.get_ph_count() as u64,
+            brk: (brk + PAGE - 1) & !(PAGE - 1),
+            tls,
+            stack: layout.stack_top - STACK_SIZE..layout.stack_top,
+            trampoline: layout.stack_top,
+        })
+    }
+
+    /// The pages of the segments with their permissions, a page segments
+    /// share getting the permissions of all of them
+    pub fn pages(&self) -> BTreeMap<u64, Perm> {
+        let mut pages = BTreeMap::new();
+        for segment in &self.segments {
+            for vaddr in (segment.vaddr.start & !(PAGE - 1)..segment.vaddr.end).step_by(PAGE as usize) {
+                let perm = pages.entry(vaddr).or_insert(Perm::NONE);
+                *perm = *perm | segment.perm;
+            }
+        }
+        pages
+    }
+
+    /// Map the segments, the stack and the trampoline into `map`, runs of
+    /// pages with the same permissions as one region
+    pub fn map(&self, map: &mut AddressMap) -> Result<(), MemoryError> {
+        let mut run: Option<(Range<u64>, Perm)> = None;
+        for (vaddr, perm) in self.pages() {
+            match &mut run {
+                Some((pages, run_perm)) if pages.end == vaddr && *run_perm == perm => pages.end += PAGE,
+                _ => {
+                    if let Some((pages, perm)) = run.take() {
+                        map.map(pages, perm)?;
+                    }
+                    run = Some((vaddr..vaddr + PAGE, perm));
+                }
+            }
+        }
+        if let Some((pages, perm)) = run {
+            map.map(pages, perm)?;
+        }
+        map.map(self.stack.clone(), Perm::RW)?;
+        map.map(self.trampoline..self.trampoline + PAGE, Perm::RX)?;
+        Ok(())
+    }
+
+    /// Copy the segments from the executable `data` into memory mapped by
+    /// `map`, in the order of the program headers, and write the trampoline
+    pub fn load<M: LinearMemory + ?Sized>(&self, memory: &mut GuestMemory<M>, data: &[u8]) -> Result<(), MemoryError> {
+        for segment in &self.segments {
+            memory.init(segment.vaddr.start, &data[segment.file.clone()])?;
+        }
+        let trampoline: Vec<u8> = SIGRETURN_TRAMPOLINE.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        memory.init(self.trampoline, &trampoline)
+    }
+
+    /// The entries of the auxiliary vector describing the image, to which
+    /// `setup_stack` adds the rest
+    pub fn auxv(&self) -> Vec<(u64, u64)> {
+        vec![
+            (AT_PHDR, self.phdr),
+            (AT_PHENT, self.phent),
+            (AT_PHNUM, self.phnum),
+            // no dynamic linker
+            (AT_BASE, 0),
+            (AT_ENTRY, self.entry),
+        ]
+    }
+
+    /// Write the initial stack and give the registers the process starts
+    /// with
+    pub fn start<M: LinearMemory + ?Sized>(
+        &self,
+        memory: &mut GuestMemory<M>,
+        args: &[String],
+        envs: &[(String, String)],
+        entropy: &Entropy,
+    ) -> Result<Registers, MemoryError> {
+        let sp = setup_stack(memory, self.stack.end, args, envs, &self.auxv(), entropy)?;
+        Ok(Registers::new(self.entry, sp))
+    }
+}
+
+/// Load the executable `data` into `map` and `linear` as `config` asks,
+/// placed by its ASLR mode, for the process of `env`, whose mmap area it
+/// reserves, giving the image and the registers to run the process from
+pub fn load<M: LinearMemory + ?Sized>(
+    data: &[u8],
+    config: &Config,
+    env: &SyscallEnv,
+    map: &mut AddressMap,
+    linear: &mut M,
+) -> Result<(ProcessImage, Registers), ProcessError> {
+    let decode = || -> Result<_, ProcessError> {
+        let elf = ElfFile::new(data)?;
+        let layout = MemoryLayout::new(config.aslr, elf.header_part2.get_type() == Type::SharedObject);
+        Ok((ProcessImage::new(&elf, &layout)?, layout))
+    };
+    let (image, layout) = match &config.profiler {
+        Some(profiler) => profiler.time(FRONTEND_DECODE, decode)?,
+        None => decode()?,
+    };
+    image.map(map)?;
+    let mut mm = Mappings::reserve(map, layout.mmap_base, MMAP_SIZE)?;
+    mm.reserve_heap(map, image.brk, BRK_SIZE)?;
+    let mut memory = GuestMemory::new(map, linear);
+    image.load(&mut memory, data)?;
+    memory.write_perm_table()?;
+    *env.mm.lock().unwrap() = mm;
+    let regs = image.start(&mut memory, &config.args, &config.envs, &env.entropy)?;
+    env.signals.set_trampoline(image.trampoline);
+    Ok((image, regs))
+}
+
+/// Check that the executable `data` loads, without loading it, for execve
+/// to fail while the process it would replace is still there
+pub fn check(data: &[u8]) -> Result<(), ProcessError> {
+    let image = ProcessImage::new(&ElfFile::new(data)?, &MemoryLayout::default())?;
+    image.map(&mut AddressMap::new(AddressMap::DEFAULT_SIZE))?;
+    Ok(())
+}
+
+/// Run the thread of `env` from `regs` until it exits, the process ends or
+/// execve replaces the image: in the interpreter, and in the code of `tier`
+/// for the blocks the interpreter found hot. Without a tier hot blocks keep
+/// being interpreted. A fault, an illegal instruction or a breakpoint the
+/// guest has no handler for ends the process with the signal linux sends
+/// for it. The limits of `env` are checked between slices, which end where
+/// the instruction limit is, and a guest running into one fails with
+/// `VmError::LimitExceeded`.
+pub fn run<M: LinearMemory + ?Sized>(
+    interp: &mut Interpreter,
+    mut tier: Option<&mut dyn Tier<M>>,
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+) -> Result<GuestExit, VmError> {
+    loop {
+        if let Some(result) = env.threads.exit_status() {
+            return Ok(GuestExit::Process(result));
+        }
+        // execve in another thread ended this one
+        if !env.threads.is_live(env.tid) {
+            return Ok(GuestExit::Thread(0));
+        }
+        let executed = env.clock.instret();
+        let budget = (env.limits.check(executed)? - executed).min(SLICE);
+        let stop = match (interp.run(env, memory, regs, budget), &mut tier) {
+            (Stop::Hot(pc), Some(tier)) => {
+                if tier.compile(env, memory, pc)? {
+                    interp.mark_compiled(pc);
+                }
+                for pc in tier.take_compiled() {
+                    interp.mark_compiled(pc);
+                }
+                continue;
+            }
+            (Stop::Compiled(_), Some(tier)) => {
+                let stop = run_compiled(interp, &mut **tier, env, memory, regs, budget)?;
+                for pc in tier.take_compiled() {
+                    interp.mark_compiled(pc);
+                }
+                match stop {
+                    Some(stop) => stop,
+                    None => continue,
+                }
+            }
+            (stop, _) => stop,
+        };
+        let sig = match stop {
+            Stop::Exit(exit) => return Ok(exit),
+            Stop::Yield | Stop::Hot(_) | Stop::Compiled(_) | Stop::Watchpoint(_) => continue,
+            Stop::Unsupported(pc) => return Err(ProcessError::Unsupported(pc).into()),
+            Stop::Fault(fault) => SigInfo::from_fault(&fault).signo,
+            Stop::Illegal(_) => SIGILL,
+            Stop::Breakpoint(_) => SIGTRAP,
+        };
+        return Ok(signal::terminate(env, sig));
+    }
+}
+
+/// Run the compiled code of `tier` from `regs.pc` for about `budget`
+/// instructions, and hand the guest back to the interpreter however it
+/// leaves. A trap in compiled code is raised by interpreting the
+/// instruction again, the stop it ends in is the one the guest has no
+/// handler for.
+fn run_compiled<M: LinearMemory + ?Sized>(
+    interp: &mut Interpreter,
+    tier: &mut dyn Tier<M>,
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+    budget: u64,
+) -> Result<Option<Stop>, VmError> {
+    Ok(match tier.run(env, memory, regs, budget)? {
+        Leave::Uncompiled(pc) => {
+            interp.unmark_compiled(pc);
+            None
+        }
+        Leave::Yield => match signal::deliver(env, memory, regs) {
+            Delivery::Terminate(sig) => Some(Stop::Exit(signal::terminate(env, sig))),
+            Delivery::None | Delivery::Handler(_) => None,
+        },
+        Leave::Trap => {
+            // the access may be to a page another thread just mapped
+            mm::sync(env, memory);
+            interp.step_instruction(env, memory, regs).err()
+        }
+    })
+}
+
+/// Run the executable `data` as `config` asks until the process ends, with
+/// every thread the guest creates on a host thread of its own
+pub fn execute(data: &[u8], config: &Config) -> Result<ExecutionResult, VmError> {
+    let mut env = SyscallEnv::new(config);
+    let mut map = AddressMap::new(AddressMap::DEFAULT_SIZE);
+    let memory = SharedMemory::new(AddressMap::DEFAULT_SIZE);
+    let (image, regs) = load(data, config, &env, &mut map, &mut memory.clone())?;
+    let builder = Arc::new(WasmBuilder::with_opt_level(config.opt_level));
+    let cache = DiskCache::for_binary(config, data, image.load_bias);
+    set_spawners(config, &builder, &cache, &env, &memory);
+    // when the first thread exits, the others go on until the last one
+    // ends the process
+    let result = run_thread(config, &builder, &cache, &mut env, map, memory, regs)?;
+    Ok(result.unwrap_or_else(|| env.threads.wait_exit()))
+}
+
+/// Whether the blocks of a guest run as `config` asks may be compiled by
+/// the `BlockEngine`: not for a guest with paging of its own, whose blocks
+/// translate addresses through the MMU, nor when tracing every instruction
+fn compiles(config: &Config) -> bool {
+    !config.mmu && config.trace.is_none()
+}
+
+/// Run a thread of the process of `env` until it ends, going on with the
+/// program execve replaces the image with in a fresh address space. Tells
+/// how the process ended, if the thread ended it. The compiled blocks of
+/// the binary are kept in `cache`, if any.
+fn run_thread(
+    config: &Config,
+    builder: &Arc<WasmBuilder>,
+    cache: &Option<DiskCache>,
+    env: &mut SyscallEnv,
+    mut map: AddressMap,
+    mut memory: SharedMemory,
+    mut regs: Registers,
+) -> Result<Option<ExecutionResult>, VmError> {
+    let (mut config, mut cache) = (config.clone(), cache.clone());
+    loop {
+        let mut interp = Interpreter::new(&config);
+        let mut engine = match compiles(&config) {
+            true => {
+                let engine = BlockEngine::new(builder.clone(), &config, &map, &memory)?;
+                Some(match cache.clone() {
+                    Some(cache) => engine.disk_cache(cache),
+                    None => engine,
+                })
+            }
+            false => None,
+        };
+        let tier = engine.as_mut().map(|engine| engine as &mut dyn Tier<SharedMemory>);
+        let image = match run(&mut interp, tier, env, &mut GuestMemory::new(&mut map, &mut memory), &mut regs)? {
+            GuestExit::Thread(_) => return Ok(None),
+            GuestExit::Process(result) => return Ok(Some(result)),
+            GuestExit::Exec(image) => image,
+        };
+        config = config.args(image.args).envs(image.envs);
+        map = AddressMap::new(AddressMap::DEFAULT_SIZE);
+        memory = SharedMemory::new(AddressMap::DEFAULT_SIZE);
+        let (loaded, start) = load(&image.data, &config, env, &mut map, &mut memory.clone())?;
+        regs = start;
+        cache = DiskCache::for_binary(&config, &image.data, loaded.load_bias);
+        set_spawners(&config, builder, &cache, env, &memory);
+    }
+}
+
+/// Have clone start the new threads of the process of `env` on host
+/// threads, running over `memory` like the thread that created them, and
+/// fork the new processes over a copy of it. All of them compile with
+/// `builder`, into `cache`.
+fn set_spawners(config: &Config, builder: &Arc<WasmBuilder>, cache: &Option<DiskCache>, env: &SyscallEnv, memory: &SharedMemory) {
+    let (thread_config, thread_builder, thread_cache, thread_memory) = (config.clone(), builder.clone(), cache.clone(), memory.clone());
+    env.threads.set_spawner(Arc::new(move |thread: NewThread| {
+        let NewThread { env, map, regs, .. } = thread;
+        spawn(&thread_config, &thread_builder, &thread_cache, env, map, thread_memory.clone(), regs)
+    }));
+    let (config, builder, cache, memory) = (config.clone(), builder.clone(), cache.clone(), memory.clone());
+    env.threads.set_process_spawner(Arc::new(move |process: NewProcess| {
+        let memory = memory.copy();
+        let mut map = process.map.clone();
+        process.init(&mut GuestMemory::new(&mut map, &mut memory.clone()))?;
+        let NewProcess { env, regs, .. } = process;
+        // the spawners the child inherited run over the parent's memory
+        set_spawners(&config, &builder, &cache, &env, &memory);
+        spawn(&config, &builder, &cache, env, map, memory, regs)
+    }));
+}
+
+/// Run the thread of `env` on a host thread of its own
+fn spawn(
+    config: &Config,
+    builder: &Arc<WasmBuilder>,
+    cache: &Option<DiskCache>,
+    mut env: SyscallEnv,
+    map: AddressMap,
+    memory: SharedMemory,
+    regs: Registers,
+) -> Result<(), Errno> {
+    let (config, builder, cache) = (config.clone(), builder.clone(), cache.clone());
+    std::thread::Builder::new()
+        .name(format!("guest-{}", env.tid))
+        .spawn(move || {
+            if let Err(e) = run_thread(&config, &builder, &cache, &mut env, map, memory, regs) {
+                // nothing is left to report the error to but the log, the
+                // process ends as if the host killed it
+                tracing::error!(target: "doublejit::runtime", "thread {} stopped: {}", env.tid, e);
+                env.threads.terminate(ExecutionResult::Signaled(SIGKILL));
+            }
+        })
+        .map(|_| ())
+        .map_err(|_| EAGAIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::layout::STACK_TOP;
+    use crate::runtime::limits::Limit;
+    use crate::runtime::regs::SP;
+
+    fn read(path: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/test_binaries/{}", env!("CARGO_MANIFEST_DIR"), path)).unwrap()
+    }
+
+    #[test]
+    fn test_load() {
+        let data = read("add_test/add_test");
+        let config = Config::new().args(["add_test".to_string()]).seed(1);
+        let mut map = AddressMap::new(AddressMap::DEFAULT_SIZE);
+        let mut linear = vec![0u8; AddressMap::DEFAULT_SIZE];
+        let env = SyscallEnv::new(&config);
+        let (image, regs) = load(&data, &config, &env, &mut map, &mut linear).unwrap();
+        assert_eq!((image.load_bias, image.entry, regs.pc), (0, 0x105fc, 0x105fc));
+        // the headers are loaded with the text, the first segment starting
+        // at offset 0
+        assert_eq!((image.phdr, image.phent, image.phnum), (0x10040, 56, 6));
+        assert_eq!(image.tls, Some(0x7fb28..0x7fb88));
+        assert_eq!(image.brk, 0x84000);
+        let pages = image.pages();
+        assert_eq!((pages[&0x7e000], pages[&0x7f000], pages[&0x83000]), (Perm::RX, Perm::RW, Perm::RW));
+        assert_eq!(pages.len(), 0x74);
+
+        let memory = GuestMemory::new(&mut map, &mut linear);
+        let mut magic = [0; 4];
+        memory.peek(0x10000, &mut magic).unwrap();
+        assert_eq!(&magic, b"\x7fELF");
+        // the end of .bss is zero
+        let mut bss = [0xff; 8];
+        memory.peek(0x7fb28 + 0x35e0 - 8, &mut bss).unwrap();
+        assert_eq!(bss, [0; 8]);
+        // argc, then argv[0] and the NULL ending it
+        let sp = regs.x[SP];
+        assert!(sp % 16 == 0 && image.stack.contains(&sp) && image.stack.end == STACK_TOP);
+        assert_eq!((memory.read_u64(sp).unwrap(), memory.read_u64(sp + 16).unwrap()), (1, 0));
+        assert_eq!(memory.read_cstr(memory.read_u64(sp + 8).unwrap(), 64).unwrap(), b"add_test");
+        // handlers return to the trampoline above the stack
+        assert_eq!(image.trampoline, image.stack.end);
+        assert_eq!(memory.read_u32(image.trampoline).unwrap(), SIGRETURN_TRAMPOLINE[0]);
+
+        let dynamic = read("test1");
+        let error = ProcessImage::new(&ElfFile::new(&dynamic).unwrap(), &MemoryLayout::default()).unwrap_err();
+        assert!(matches!(error, ProcessError::Interpreter(path) if path == "/lib/ld-linux-riscv64-lp64d.so.1"));
+        assert!(check(&data).is_ok());
+        assert!(matches!(check(&data[..40]), Err(ProcessError::Elf(ElfError::Malformed(_)))));
+        assert!(matches!(check(b"#!/bin/sh\n echo hello world of scripts"), Err(ProcessError::Elf(ElfError::BadMagic(_)))));
+    }
+
+    #[test]
+    fn test_blocks_cached_across_runs() {
+        let data = read("add_test/add_test");
+        let dir = std::env::temp_dir().join(format!("doublejit-cache-{}", std::process::id()));
+        let config = Config::new().args(["add_test".to_string()]).seed(1).cache_dir(&dir);
+        let cache = DiskCache::for_binary(&config, &data, 0).unwrap();
+        let first = execute(&data, &config).unwrap();
+        assert!(std::fs::read_dir(cache.dir()).unwrap().count() > 0);
+        // the next run loads the blocks, and compiles those whose artifact
+        // does not check out again
+        assert_eq!(execute(&data, &config).unwrap(), first);
+        for artifact in std::fs::read_dir(cache.dir()).unwrap() {
+            std::fs::write(artifact.unwrap().path(), b"junk").unwrap();
+        }
+        assert_eq!(execute(&data, &config).unwrap(), first);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_thread_runs() {
+        // clone a thread that adds 5 to a counter, wait for it to show and
+        // exit with it
+        let mut code = Vec::new();
+        for insn in [
+            0x0001_1537u32, // lui a0, 0x11
+            0xf005_0513,    // addi a0, a0, -256, the thread flags
+            0x0001_25b7,    // lui a1, 0x12, the stack
+            0x0dc0_0893,    // addi a7, zero, 220
+            0x0000_0073,    // ecall, clone
+            0x0005_1c63,    // bnez a0, parent
+            0x0001_12b7,    // lui t0, 0x11
+            0x0050_0313,    // addi t1, zero, 5
+            0x0062_a02f,    // amoadd.w zero, t1, (t0)
+            0x05d0_0893,    // addi a7, zero, 93
+            0x0000_0073,    // ecall, exit
+            0x0001_12b7,    // parent: lui t0, 0x11
+            0x0002_a503,    // lw a0, 0(t0)
+            0xfe05_0ee3,    // beqz a0, -4
+            0x05e0_0893,    // addi a7, zero, 94
+            0x0000_0073,    // ecall, exit_group
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let config = Config::new();
+        let mut env = SyscallEnv::new(&config);
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        map.map(0x11000..0x13000, Perm::RW).unwrap();
+        let memory = SharedMemory::new(1 << 20);
+        let mut linear = memory.clone();
+        let mut guest = GuestMemory::new(&mut map, &mut linear);
+        guest.init(0x10000, &code).unwrap();
+        guest.write_perm_table().unwrap();
+
+        let builder = Arc::new(WasmBuilder::new());
+        set_spawners(&config, &builder, &None, &env, &memory);
+        let result = run_thread(&config, &builder, &None, &mut env, map, memory, Registers::new(0x10000, 0x12000)).unwrap();
+        assert_eq!(result, Some(ExecutionResult::Exited(5)));
+        assert_eq!(env.threads.wait_exit(), ExecutionResult::Exited(5));
+    }
+
+    #[test]
+    fn test_fork_runs() {
+        // fork a child exiting with 7 and exit with its exit status
+        let mut code = Vec::new();
+        for insn in [
+            0x0110_0513u32, // addi a0, zero, 17, SIGCHLD
+            0x0000_0593,    // addi a1, zero, 0, the stack of the parent
+            0x0dc0_0893,    // addi a7, zero, 220
+            0x0000_0073,    // ecall, clone
+            0x0005_1863,    // bnez a0, parent
+            0x0070_0513,    // addi a0, zero, 7
+            0x05d0_0893,    // addi a7, zero, 93
+            0x0000_0073,    // ecall, exit
+            0xfff0_0513,    // parent: addi a0, zero, -1
+            0x0001_15b7,    // lui a1, 0x11, the status, a2 and a3 left zero
+            0x1040_0893,    // addi a7, zero, 260
+            0x0000_0073,    // ecall, wait4
+            0x0005_a503,    // lw a0, 0(a1)
+            0x0085_5513,    // srli a0, a0, 8
+            0x05e0_0893,    // addi a7, zero, 94
+            0x0000_0073,    // ecall, exit_group
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let config = Config::new();
+        let mut env = SyscallEnv::new(&config);
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        map.map(0x11000..0x12000, Perm::RW).unwrap();
+        let memory = SharedMemory::new(1 << 20);
+        let mut linear = memory.clone();
+        let mut guest = GuestMemory::new(&mut map, &mut linear);
+        guest.init(0x10000, &code).unwrap();
+        guest.write_perm_table().unwrap();
+
+        let builder = Arc::new(WasmBuilder::new());
+        set_spawners(&config, &builder, &None, &env, &memory);
+        let result = run_thread(&config, &builder, &None, &mut env, map, memory, Registers::new(0x10000, 0x12000)).unwrap();
+        assert_eq!(result, Some(ExecutionResult::Exited(7)));
+    }
+
+    #[test]
+    fn test_spinning_guest_stops() {
+        let config = Config::new().max_instructions(100_000);
+        let mut env = SyscallEnv::new(&config);
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        let mut linear = vec![0u8; 1 << 20];
+        let mut guest = GuestMemory::new(&mut map, &mut linear);
+        // j .
+        guest.init(0x10000, &0x0000_006fu32.to_le_bytes()).unwrap();
+        let mut interp = Interpreter::new(&config);
+        let mut regs = Registers::new(0x10000, 0);
+        let error = run(&mut interp, None, &mut env, &mut guest, &mut regs).unwrap_err();
+        assert!(matches!(error, VmError::LimitExceeded(Limit::Instructions(100_000))));
+        assert_eq!(env.clock.instret(), 100_000);
+    }
+}
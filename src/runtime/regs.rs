@@ -0,0 +1,42 @@
+use crate::runtime::csr::CsrState;
+
+/// Indices of the integer registers with an ABI role the runtime relies on
+pub const RA: usize = 1;
+pub const SP: usize = 2;
+pub const GP: usize = 3;
+pub const TP: usize = 4;
+pub const T0: usize = 5;
+pub const T1: usize = 6;
+pub const A0: usize = 10;
+pub const A1: usize = 11;
+pub const A2: usize = 12;
+pub const A7: usize = 17;
+
+/// Architectural register state of one guest hart
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Registers {
+    pub pc: u64,
+    /// Integer registers, `x[0]` always reads as zero
+    pub x: [u64; 32],
+    /// Floating point registers as raw NaN boxed bits
+    pub f: [u64; 32],
+    pub csr: CsrState,
+}
+
+impl Registers {
+    pub fn new(pc: u64, sp: u64) -> Self {
+        let mut regs = Self {
+            pc,
+            ..Self::default()
+        };
+        regs.x[SP] = sp;
+        regs
+    }
+
+    pub fn set_x(&mut self, index: usize, value: u64) {
+        if index != 0 {
+            self.x[index] = value;
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::middleend::address_map::Writes;
+use crate::runtime::syscall::{
+    SYS_CLOCK_GETRES, SYS_CLOCK_GETTIME, SYS_FSTAT, SYS_GETRANDOM, SYS_GETRUSAGE, SYS_GETTIMEOFDAY, SYS_NEWFSTATAT,
+    SYS_PREAD64, SYS_PREADV, SYS_READ, SYS_READV, SYS_RECVFROM, SYS_STATX,
+};
+
+/// Start of a recording file, with the version of its format
+const MAGIC: &[u8; 8] = b"DJREPLY1";
+
+/// Syscalls that bring data in from outside the guest. A replay takes their
+/// results and the memory they wrote from the recording instead of making
+/// them, so it needs neither the input nor the files of the original run.
+/// The other syscalls act on the state of the VM and run again.
+const INPUTS: [u64; 13] = [
+    SYS_READ,
+    SYS_READV,
+    SYS_PREAD64,
+    SYS_PREADV,
+    SYS_RECVFROM,
+    SYS_CLOCK_GETTIME,
+    SYS_CLOCK_GETRES,
+    SYS_GETTIMEOFDAY,
+    SYS_GETRANDOM,
+    SYS_GETRUSAGE,
+    SYS_NEWFSTATAT,
+    SYS_FSTAT,
+    SYS_STATX,
+];
+
+/// Whether a replay takes the result of syscall `nr` from the recording
+pub fn is_input(nr: u64) -> bool {
+    INPUTS.contains(&nr)
+}
+
+/// Something nondeterministic the guest saw
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Syscall `nr` returned `result`, the value of `a0`, after writing
+    /// `writes` as linear offset and bytes. Only the writes of inputs are
+    /// kept.
+    Syscall { nr: u64, result: u64, writes: Writes },
+    /// A read of the host clock, in nanoseconds since the guest started
+    Time(u64),
+    /// The pending interrupts changed to `mip` at the block boundary after
+    /// `instret` instructions
+    Interrupt { instret: u64, mip: u64 },
+}
+
+/// The nondeterministic inputs of a run, in the order the guest saw them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    /// Host wall clock at the start, in nanoseconds since the unix epoch
+    pub wall_start: u64,
+    pub events: Vec<Event>,
+}
+
+impl Recording {
+    pub fn write_to(&self, mut out: impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        write_u64(&mut out, self.wall_start)?;
+        write_u64(&mut out, self.events.len() as u64)?;
+        for event in &self.events {
+            match event {
+                Event::Syscall { nr, result, writes } => {
+                    out.write_all(&[0])?;
+                    write_u64(&mut out, *nr)?;
+                    write_u64(&mut out, *result)?;
+                    write_u64(&mut out, writes.len() as u64)?;
+                    for (offset, data) in writes {
+                        write_u64(&mut out, *offset as u64)?;
+                        write_u64(&mut out, data.len() as u64)?;
+                        out.write_all(data)?;
+                    }
+                }
+                Event::Time(nanos) => {
+                    out.write_all(&[1])?;
+                    write_u64(&mut out, *nanos)?;
+                }
+                Event::Interrupt { instret, mip } => {
+                    out.write_all(&[2])?;
+                    write_u64(&mut out, *instret)?;
+                    write_u64(&mut out, *mip)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_from(mut input: impl Read) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a recording"));
+        }
+        let wall_start = read_u64(&mut input)?;
+        let mut events = Vec::new();
+        for _ in 0..read_u64(&mut input)? {
+            let mut tag = [0];
+            input.read_exact(&mut tag)?;
+            events.push(match tag[0] {
+                0 => {
+                    let (nr, result) = (read_u64(&mut input)?, read_u64(&mut input)?);
+                    let mut writes = Vec::new();
+                    for _ in 0..read_u64(&mut input)? {
+                        let offset = read_u64(&mut input)? as usize;
+                        let mut data = Vec::new();
+                        let len = read_u64(&mut input)?;
+                        if (&mut input).take(len).read_to_end(&mut data)? as u64 != len {
+                            return Err(io::ErrorKind::UnexpectedEof.into());
+                        }
+                        writes.push((offset, data));
+                    }
+                    Event::Syscall { nr, result, writes }
+                }
+                1 => Event::Time(read_u64(&mut input)?),
+                2 => Event::Interrupt { instret: read_u64(&mut input)?, mip: read_u64(&mut input)? },
+                _ => return Err(invalid("unknown event")),
+            });
+        }
+        Ok(Self { wall_start, events })
+    }
+}
+
+/// Where a replay stopped following its recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the event in the recording
+    pub index: usize,
+    /// The event recorded there, `None` past the end of the recording
+    pub recorded: Option<Event>,
+    /// What the replay did instead
+    pub replayed: Event,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Record,
+    Replay,
+    /// A replay past its divergence, which carries on live
+    Diverged,
+}
+
+#[derive(Debug)]
+struct State {
+    mode: Mode,
+    recording: Recording,
+    /// Index of the next event to replay
+    next: usize,
+    /// The pending interrupts as of the last `Event::Interrupt`
+    mip: u64,
+    divergence: Option<Divergence>,
+}
+
+/// Records the nondeterministic inputs of a run, the syscall results, host
+/// time and interrupts, or feeds a recording back to reproduce the run
+/// exactly, down to the instruction an interrupt arrives at. Together with
+/// a virtual clock and a seed, a replay repeats a failing run as often as
+/// bisecting it takes.
+///
+/// The guest threads share one log, so replays are exact for guests with
+/// one thread. Signals sent from the host are not recorded.
+#[derive(Debug)]
+pub struct Replay {
+    state: Mutex<State>,
+    /// Inside an input syscall, whose own clock reads a replay skips
+    muted: AtomicBool,
+}
+
+impl Replay {
+    /// Record a new run
+    pub fn record() -> Self {
+        Self::with(Mode::Record, Recording::default())
+    }
+
+    /// Feed `recording` back to the run
+    pub fn from_recording(recording: Recording) -> Self {
+        Self::with(Mode::Replay, recording)
+    }
+
+    fn with(mode: Mode, recording: Recording) -> Self {
+        Self {
+            state: Mutex::new(State { mode, recording, next: 0, mip: 0, divergence: None }),
+            muted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.state.lock().unwrap().mode == Mode::Record
+    }
+
+    /// What was recorded so far, or the recording being replayed
+    pub fn recording(&self) -> Recording {
+        self.state.lock().unwrap().recording.clone()
+    }
+
+    /// The first event a replay did not reproduce, after which it runs live
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.state.lock().unwrap().divergence.clone()
+    }
+
+    /// The wall clock time the guest starts at, `host` unless replaying
+    pub fn wall_start(&self, host: Duration) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        match state.mode {
+            Mode::Record => state.recording.wall_start = host.as_nanos() as u64,
+            Mode::Replay => return Duration::from_nanos(state.recording.wall_start),
+            Mode::Diverged => {}
+        }
+        host
+    }
+
+    /// Read the host clock, `host` giving its time since the guest started
+    pub fn time(&self, host: impl FnOnce() -> Duration) -> Duration {
+        if self.muted.load(Ordering::Relaxed) {
+            return host();
+        }
+        let mut state = self.state.lock().unwrap();
+        match state.mode {
+            Mode::Record => {
+                let time = host();
+                state.recording.events.push(Event::Time(time.as_nanos() as u64));
+                time
+            }
+            Mode::Replay => match state.take() {
+                Some(Event::Time(nanos)) => Duration::from_nanos(nanos),
+                _ => {
+                    let time = host();
+                    state.diverge(Event::Time(time.as_nanos() as u64));
+                    time
+                }
+            },
+            Mode::Diverged => host(),
+        }
+    }
+
+    /// The interrupts pending at a block boundary after `instret`
+    /// instructions, `host` being the ones the devices raise now
+    pub fn interrupts(&self, instret: u64, host: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        match state.mode {
+            Mode::Record => {
+                if host != state.mip {
+                    state.recording.events.push(Event::Interrupt { instret, mip: host });
+                    state.mip = host;
+                }
+                host
+            }
+            Mode::Replay => {
+                if let Some(&Event::Interrupt { instret: at, mip }) = state.recording.events.get(state.next) {
+                    if at == instret {
+                        state.next += 1;
+                        state.mip = mip;
+                    }
+                }
+                state.mip
+            }
+            Mode::Diverged => host,
+        }
+    }
+
+    /// Run the input syscall `f` without logging the clock reads it makes,
+    /// which its replay does not make
+    pub fn input<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.muted.store(true, Ordering::Relaxed);
+        let result = f();
+        self.muted.store(false, Ordering::Relaxed);
+        result
+    }
+
+    /// Log that syscall `nr` returned `result` after `writes`
+    pub fn record_syscall(&self, nr: u64, result: u64, writes: Writes) {
+        let mut state = self.state.lock().unwrap();
+        if state.mode == Mode::Record {
+            state.recording.events.push(Event::Syscall { nr, result, writes });
+        }
+    }
+
+    /// The recorded result and writes of syscall `nr`, which the guest makes
+    /// next. `None` when not replaying, or when the recording has another
+    /// event next, which ends the replay.
+    pub fn replay_syscall(&self, nr: u64) -> Option<(u64, Writes)> {
+        let mut state = self.state.lock().unwrap();
+        if state.mode != Mode::Replay {
+            return None;
+        }
+        match state.take() {
+            Some(Event::Syscall { nr: recorded, result, writes }) if recorded == nr => Some((result, writes)),
+            _ => {
+                state.diverge(Event::Syscall { nr, result: 0, writes: Vec::new() });
+                None
+            }
+        }
+    }
+
+    /// Check the result of a syscall that ran again against the `recorded`
+    /// one
+    pub fn check_syscall(&self, nr: u64, recorded: u64, result: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.mode == Mode::Replay && recorded != result {
+            state.diverge(Event::Syscall { nr, result, writes: Vec::new() });
+        }
+    }
+}
+
+impl State {
+    /// The next recorded event, consumed
+    fn take(&mut self) -> Option<Event> {
+        let event = self.recording.events.get(self.next).cloned();
+        self.next += 1;
+        event
+    }
+
+    /// The replay did `replayed` instead of the event before `next`
+    fn diverge(&mut self, replayed: Event) {
+        let index = self.next - 1;
+        tracing::warn!(target: "doublejit::replay", index, ?replayed, "replay diverged from the recording");
+        self.divergence = Some(Divergence { index, recorded: self.recording.events.get(index).cloned(), replayed });
+        self.mode = Mode::Diverged;
+    }
+}
+
+fn write_u64(out: &mut impl Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::testing::TestMemory;
+    use crate::runtime::syscall::{syscall_handler, SyscallEnv, SYS_GETPID};
+
+    /// getrandom, clock_gettime and getpid into a fresh guest, returning
+    /// the results and the memory they wrote
+    fn run(replay: Arc<Replay>, calls: &[u64]) -> (Vec<u64>, Vec<u8>) {
+        let mut env = SyscallEnv::new(&Config::new().replay(replay));
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+        let results = calls
+            .iter()
+            .map(|&nr| match nr {
+                SYS_GETRANDOM => syscall_handler(&mut env, &mut memory, nr, [0x10000, 32, 0, 0, 0, 0]),
+                SYS_CLOCK_GETTIME => syscall_handler(&mut env, &mut memory, nr, [1, 0x10020, 0, 0, 0, 0]),
+                _ => syscall_handler(&mut env, &mut memory, nr, [0; 6]),
+            })
+            .collect();
+        (results, memory.read_vec(0x10000, 0x30).unwrap())
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let calls = [SYS_GETRANDOM, SYS_CLOCK_GETTIME, SYS_GETPID];
+        let replay = Arc::new(Replay::record());
+        let recorded = run(replay.clone(), &calls);
+        let recording = replay.recording();
+        // one read of the clock sets the time of the VFS
+        assert!(matches!(recording.events[0], Event::Time(_)));
+        assert!(matches!(&recording.events[1], Event::Syscall { nr: SYS_GETRANDOM, result: 32, writes } if writes.len() == 1));
+        assert_eq!(recording.events.len(), 4);
+
+        let mut file = Vec::new();
+        recording.write_to(&mut file).unwrap();
+        let recording = Recording::read_from(&file[..]).unwrap();
+        assert_eq!(recording, replay.recording());
+
+        let replay = Arc::new(Replay::from_recording(recording.clone()));
+        assert_eq!(run(replay.clone(), &calls), recorded);
+        assert_eq!(replay.divergence(), None);
+
+        // a run making another syscall leaves the recording there
+        let replay = Arc::new(Replay::from_recording(recording));
+        run(replay.clone(), &[SYS_GETRANDOM, SYS_GETPID]);
+        let divergence = replay.divergence().unwrap();
+        assert_eq!(divergence.index, 2);
+        assert_eq!(divergence.replayed, Event::Syscall { nr: SYS_GETPID, result: 0, writes: Vec::new() });
+    }
+}
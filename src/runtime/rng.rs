@@ -0,0 +1,69 @@
+use std::sync::{Arc, Mutex};
+
+/// Small SplitMix64 generator used wherever the VM needs reproducible randomness.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Seed from the host entropy source.
+    pub fn from_entropy() -> Self {
+        let mut seed = [0u8; 8];
+        getrandom::getrandom(&mut seed).expect("host entropy source unavailable");
+        Self::new(u64::from_le_bytes(seed))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+
+    /// Uniformly pick a value with the lowest `bits` bits random.
+    pub fn next_bits(&mut self, bits: u32) -> u64 {
+        if bits == 0 {
+            0
+        } else {
+            self.next_u64() >> (64 - bits)
+        }
+    }
+}
+
+/// Source of the randomness guests see through getrandom and /dev/urandom
+#[derive(Debug, Clone, Default)]
+pub enum Entropy {
+    /// The host's cryptographic random source
+    #[default]
+    Host,
+    /// A PRNG shared by the whole guest, so a seed reproduces a run
+    Seeded(Arc<Mutex<Rng>>),
+}
+
+impl Entropy {
+    pub fn new(seed: Option<u64>) -> Self {
+        match seed {
+            Some(seed) => Entropy::Seeded(Arc::new(Mutex::new(Rng::new(seed)))),
+            None => Entropy::Host,
+        }
+    }
+
+    pub fn fill(&self, buf: &mut [u8]) {
+        match self {
+            Entropy::Host => getrandom::getrandom(buf).expect("host entropy source unavailable"),
+            Entropy::Seeded(rng) => rng.lock().unwrap().fill_bytes(buf),
+        }
+    }
+}
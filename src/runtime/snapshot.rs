@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+
+use crate::middleend::address_map::{AddressMap, LinearMemory, MemoryDelta, MemoryError, Snapshot};
+use crate::runtime::regs::Registers;
+use crate::runtime::syscall::fd::SavedFds;
+use crate::runtime::syscall::mm::Mappings;
+use crate::runtime::syscall::signal::{SignalTable, ThreadSignals};
+use crate::runtime::syscall::sys::Rlimits;
+use crate::runtime::syscall::time::SavedTimers;
+use crate::runtime::syscall::SyscallEnv;
+
+/// A guest paused at a block boundary, which resumes from it later
+#[derive(Debug, Clone)]
+pub struct VmSnapshot {
+    pub regs: Registers,
+    pub map: AddressMap,
+    /// The memory pages that differ from the base of the capture
+    pub memory: MemoryDelta,
+    /// The mmap area and the heap with its break
+    pub mm: Mappings,
+    /// The descriptors, which still refer to the open files of the guest
+    /// the snapshot was taken from, with the positions and pipe contents
+    /// those files are put back to
+    pub fds: SavedFds,
+    pub cwd: PathBuf,
+    pub rlimits: Rlimits,
+    /// Dispositions and the signals pending for the process
+    pub signals: SignalTable,
+    /// Mask, pending signals and alternate stack of the thread
+    pub sigstate: ThreadSignals,
+    pub timers: SavedTimers,
+}
+
+impl VmSnapshot {
+    /// Capture the state of the guest. Only memory changed since `base` is
+    /// copied, so against the memory as the loader left it a snapshot holds
+    /// little more than the pages the guest wrote.
+    pub fn capture<M: LinearMemory + ?Sized>(
+        env: &SyscallEnv,
+        regs: &Registers,
+        map: &AddressMap,
+        memory: &M,
+        base: &Snapshot,
+    ) -> Self {
+        Self {
+            regs: regs.clone(),
+            map: map.clone(),
+            memory: memory.capture(base),
+            mm: env.mm.lock().unwrap().clone(),
+            fds: env.fds.lock().unwrap().save(),
+            cwd: env.cwd.clone(),
+            rlimits: env.rlimits.lock().unwrap().clone(),
+            signals: (*env.signals).clone(),
+            sigstate: env.sigstate.clone(),
+            timers: env.timers.save(env.clock.monotonic()),
+        }
+    }
+
+    /// Put the guest back into the captured state and return its address
+    /// map. `memory` must be in the state of the base the snapshot was
+    /// captured against, and decoded code is dropped as it may be stale.
+    pub fn restore<M: LinearMemory + ?Sized>(
+        &self,
+        env: &mut SyscallEnv,
+        regs: &mut Registers,
+        memory: &mut M,
+    ) -> Result<AddressMap, MemoryError> {
+        memory.restore(&self.memory)?;
+        *regs = self.regs.clone();
+        *env.mm.lock().unwrap() = self.mm.clone();
+        *env.fds.lock().unwrap() = self.fds.restore();
+        env.cwd = self.cwd.clone();
+        *env.rlimits.lock().unwrap() = self.rlimits.clone();
+        env.signals.restore(&self.signals);
+        env.sigstate = self.sigstate.clone();
+        env.timers.restore(&self.timers, env.clock.monotonic());
+        env.code.lock().unwrap().clear();
+        Ok(self.map.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::{GuestMemory, Perm};
+    use crate::runtime::clock::ClockMode;
+    use crate::runtime::config::Config;
+    use crate::runtime::regs::A0;
+    use crate::runtime::syscall::fs::AT_FDCWD;
+    use crate::runtime::syscall::signal::SIGUSR1;
+    use crate::runtime::syscall::testing::{call, TestMemory, MEMORY_SIZE};
+    use crate::runtime::syscall::{SYS_BRK, SYS_GETITIMER, SYS_OPENAT, SYS_PIPE2, SYS_READ};
+    use crate::runtime::syscall::{SYS_RT_SIGACTION, SYS_SETITIMER, SYS_WRITE};
+
+    #[test]
+    fn test_capture_and_restore() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut guest = TestMemory::new(&[(0x10000..0x14000, Perm::RW)]);
+        let base = Snapshot::zeroed(MEMORY_SIZE);
+        guest.memory().write_u64(0x11008, 42).unwrap();
+        let mut regs = Registers::new(0x10000, 0x14000);
+        regs.x[A0] = 7;
+
+        let snapshot = VmSnapshot::capture(&env, &regs, &guest.map, &guest.linear, &base);
+        // only the page written to is kept
+        assert_eq!(snapshot.memory.len(), 0x1000);
+
+        guest.memory().write_u64(0x12000, 1).unwrap();
+        env.fds.lock().unwrap().remove(1).unwrap();
+        env.cwd = PathBuf::from("/tmp");
+        regs.x[A0] = 0;
+
+        let mut fresh = vec![0u8; MEMORY_SIZE];
+        let mut map = snapshot.restore(&mut env, &mut regs, &mut fresh).unwrap();
+        let memory = GuestMemory::new(&mut map, &mut fresh);
+        assert_eq!(memory.read_u64(0x11008).unwrap(), 42);
+        assert_eq!(memory.read_u64(0x12000).unwrap(), 0);
+        assert_eq!((regs.x[A0], env.cwd.to_str()), (7, Some("/")));
+        assert!(env.fds.lock().unwrap().get(1).is_ok());
+    }
+
+    #[test]
+    fn test_restore_process_state() {
+        let config = Config::new()
+            .clock(ClockMode::Virtual {
+                instructions_per_sec: 1000,
+            })
+            .file("/data", "0123456789");
+        let mut env = SyscallEnv::new(&config);
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut mm = Mappings::reserve(&mut guest.map, 0x100000, 0x10000).unwrap();
+        mm.reserve_heap(&mut guest.map, 0x20000, 0x4000).unwrap();
+        *env.mm.lock().unwrap() = mm;
+        let base = Snapshot::zeroed(MEMORY_SIZE);
+        let regs = Registers::new(0x10000, 0x11000);
+
+        let mut memory = guest.memory();
+        assert_eq!(call(&mut env, &mut memory, SYS_BRK, [0x21000, 0, 0, 0, 0, 0]), 0x21000);
+        // a pipe holding data and a file read partway
+        assert_eq!(call(&mut env, &mut memory, SYS_PIPE2, [0x10000, 0, 0, 0, 0, 0]), 0);
+        let (read_end, write_end) = (memory.read_u32(0x10000).unwrap(), memory.read_u32(0x10004).unwrap());
+        memory.write(0x10100, b"abc").unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_WRITE, [write_end as u64, 0x10100, 3, 0, 0, 0]), 3);
+        memory.write(0x10200, b"/data\0").unwrap();
+        let file = call(&mut env, &mut memory, SYS_OPENAT, [AT_FDCWD as u64, 0x10200, 0, 0, 0, 0]) as u64;
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [file, 0x10300, 4, 0, 0, 0]), 4);
+        // a handler, a blocked signal and an alarm due in 2s
+        memory.write_u64(0x10400, 0x10000).unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_RT_SIGACTION, [SIGUSR1 as u64, 0x10400, 0, 8, 0, 0]), 0);
+        env.sigstate.mask = 1 << (SIGUSR1 - 1);
+        memory.write(0x10500, &[0, 0, 2, 0].map(u64::to_le_bytes).concat()).unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_SETITIMER, [0, 0x10500, 0, 0, 0, 0]), 0);
+
+        let snapshot = VmSnapshot::capture(&env, &regs, &guest.map, &guest.linear, &base);
+
+        let mut memory = guest.memory();
+        assert_eq!(call(&mut env, &mut memory, SYS_BRK, [0x23000, 0, 0, 0, 0, 0]), 0x23000);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [read_end as u64, 0x10300, 3, 0, 0, 0]), 3);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [file, 0x10300, 4, 0, 0, 0]), 4);
+        memory.write_u64(0x10400, 1).unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_RT_SIGACTION, [SIGUSR1 as u64, 0x10400, 0, 8, 0, 0]), 0);
+        env.sigstate.mask = 0;
+        memory.write(0x10500, &[0u64; 4].map(u64::to_le_bytes).concat()).unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_SETITIMER, [0, 0x10500, 0, 0, 0, 0]), 0);
+        env.clock.retire(1000);
+
+        let mut regs = regs.clone();
+        let mut fresh = vec![0u8; MEMORY_SIZE];
+        let mut map = snapshot.restore(&mut env, &mut regs, &mut fresh).unwrap();
+        let mut memory = GuestMemory::new(&mut map, &mut fresh);
+        assert_eq!(call(&mut env, &mut memory, SYS_BRK, [0, 0, 0, 0, 0, 0]), 0x21000);
+        assert!(memory.read_u64(0x21000).is_err());
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [read_end as u64, 0x10300, 8, 0, 0, 0]), 3);
+        assert_eq!(memory.read_vec(0x10300, 3).unwrap(), b"abc");
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [file, 0x10300, 4, 0, 0, 0]), 4);
+        assert_eq!(memory.read_vec(0x10300, 4).unwrap(), b"4567");
+        assert_eq!(env.signals.action(SIGUSR1).handler, 0x10000);
+        assert_eq!(env.sigstate.mask, 1 << (SIGUSR1 - 1));
+        // the alarm is still 2s off, counted from the restore
+        assert_eq!(call(&mut env, &mut memory, SYS_GETITIMER, [0, 0x10500, 0, 0, 0, 0]), 0);
+        assert_eq!(memory.read_u64(0x10510).unwrap(), 2);
+    }
+}
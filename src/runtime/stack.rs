@@ -0,0 +1,124 @@
+use crate::frontend::page::Page;
+use crate::middleend::address_map::{GuestMemory, LinearMemory, MemoryError};
+use crate::runtime::rng::Entropy;
+
+/// Auxiliary vector keys, as in linux's `auxvec.h`
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_FLAGS: u64 = 8;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
+/// Write the initial process stack below `stack_top` the way linux's
+/// `create_elf_tables` does and return the initial stack pointer.
+///
+/// From the top down the stack holds the 16 `AT_RANDOM` bytes, the argument
+/// and environment strings, then, 16 byte aligned at the stack pointer, argc,
+/// the argv and envp arrays and the auxiliary vector. `auxv` carries the image
+/// entries (`AT_PHDR`, `AT_ENTRY`, ...), the page size, random bytes, exec
+/// name and the terminating `AT_NULL` are added here.
+pub fn setup_stack<M: LinearMemory + ?Sized>(
+    memory: &mut GuestMemory<M>,
+    stack_top: u64,
+    args: &[String],
+    envs: &[(String, String)],
+    auxv: &[(u64, u64)],
+    entropy: &Entropy,
+) -> Result<u64, MemoryError> {
+    let mut top = stack_top;
+    let mut push = |memory: &mut GuestMemory<M>, data: &[u8]| {
+        top -= data.len() as u64;
+        memory.write(top, data).map(|_| top)
+    };
+
+    let mut random = [0; 16];
+    entropy.fill(&mut random);
+    let random = push(memory, &random)?;
+    let mut push_str = |memory: &mut GuestMemory<M>, s: &[u8]| {
+        let mut bytes = s.to_vec();
+        bytes.push(0);
+        push(memory, &bytes)
+    };
+    // strings go in reverse so argv[0] ends up lowest, as on linux
+    let mut env_ptrs = envs
+        .iter()
+        .rev()
+        .map(|(key, value)| push_str(memory, format!("{key}={value}").as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    env_ptrs.reverse();
+    let mut arg_ptrs = args
+        .iter()
+        .rev()
+        .map(|arg| push_str(memory, arg.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    arg_ptrs.reverse();
+    let execfn = arg_ptrs.first().copied().unwrap_or(0);
+
+    let mut auxv = auxv.to_vec();
+    auxv.push((AT_PAGESZ, Page::SIZE as u64));
+    auxv.push((AT_RANDOM, random));
+    if execfn != 0 {
+        auxv.push((AT_EXECFN, execfn));
+    }
+    auxv.push((AT_NULL, 0));
+
+    let mut words = vec![args.len() as u64];
+    words.extend(&arg_ptrs);
+    words.push(0);
+    words.extend(&env_ptrs);
+    words.push(0);
+    words.extend(auxv.iter().flat_map(|&(key, value)| [key, value]));
+
+    let sp = (top - words.len() as u64 * 8) & !15;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    memory.write(sp, &bytes)?;
+    Ok(sp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    #[test]
+    fn test_initial_stack() {
+        let mut guest = TestMemory::new(&[(0x10000..0x20000, Perm::RW)]);
+        let mut memory = guest.memory();
+        let args = ["prog".to_string(), "-v".to_string()];
+        let envs = [("HOME".to_string(), "/".to_string())];
+        let sp = setup_stack(&mut memory, 0x20000, &args, &envs, &[(AT_ENTRY, 0x1234)], &Entropy::new(Some(1))).unwrap();
+        assert_eq!(sp % 16, 0);
+
+        let word = |index: u64| memory.read_u64(sp + index * 8).unwrap();
+        assert_eq!(word(0), 2);
+        assert_eq!(memory.read_cstr(word(1), 64).unwrap(), b"prog");
+        assert_eq!(memory.read_cstr(word(2), 64).unwrap(), b"-v");
+        assert_eq!(word(3), 0);
+        assert_eq!(memory.read_cstr(word(4), 64).unwrap(), b"HOME=/");
+        assert_eq!(word(5), 0);
+
+        let mut auxv = Vec::new();
+        for index in (6..).step_by(2) {
+            auxv.push((word(index), word(index + 1)));
+            if word(index) == AT_NULL {
+                break;
+            }
+        }
+        assert_eq!(auxv[0], (AT_ENTRY, 0x1234));
+        assert!(auxv.contains(&(AT_PAGESZ, Page::SIZE as u64)));
+        assert!(auxv.contains(&(AT_EXECFN, word(1))));
+        let random = auxv.iter().find(|(key, _)| *key == AT_RANDOM).unwrap().1;
+        assert_eq!(random, 0x20000 - 16);
+    }
+}
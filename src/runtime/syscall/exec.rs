@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use super::fs;
+use super::sys::RUSAGE_LEN;
+use super::mm::Mappings;
+use super::thread::{self, CLONE_CHILD_CLEARTID, CLONE_CHILD_SETTID, CLONE_PARENT_SETTID, CLONE_VFORK, CLONE_VM};
+use super::{Errno, SyscallEnv, SyscallResult, E2BIG, EINVAL, ENOEXEC, ENOSYS};
+use crate::middleend::address_map::{AddressMap, GuestMemory, LinearMemory, MemoryError};
+use crate::runtime::execution::{ExecImage, ExecutionResult};
+use crate::runtime::process;
+use crate::runtime::regs::Registers;
+
+pub const WNOHANG: u32 = 1;
+pub const WUNTRACED: u32 = 2;
+pub const WCONTINUED: u32 = 8;
+pub const WNOTHREAD: u32 = 0x20000000;
+pub const WALL: u32 = 0x40000000;
+pub const WCLONE: u32 = 0x80000000;
+
+/// Bytes the argument and environment strings of execve may take in total
+pub const ARG_MAX: usize = 2 << 20;
+
+/// A child process created by fork, for the engine to run on a copy of the
+/// parent's memory
+pub struct NewProcess {
+    pub env: SyscallEnv,
+    pub stack: u64,
+    /// The registers of the parent except `a0 = 0` and `sp = stack` when it
+    /// is non-zero
+    pub regs: Registers,
+    /// A copy of the parent's address map, for the copy of its memory
+    pub map: AddressMap,
+    child_tid: Option<u64>,
+}
+
+impl NewProcess {
+    /// Finish the child's setup in its copy of the memory, before it runs
+    pub fn init<M: LinearMemory + ?Sized>(&self, memory: &mut GuestMemory<M>) -> Result<(), Errno> {
+        if let Some(child_tid) = self.child_tid {
+            memory.write_u32(child_tid, self.env.tid as u32)?;
+        }
+        Ok(())
+    }
+}
+
+/// Callback through which fork hands new processes to the execution engine
+pub type ProcessSpawner = Arc<dyn Fn(NewProcess) -> Result<(), Errno> + Send + Sync>;
+
+/// clone without `CLONE_VM`, which creates a child process. vfork is a fork
+/// as well, the parent does not wait for the child to exec.
+pub fn fork<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &Registers,
+    flags: u64,
+    stack: u64,
+    parent_tid: u64,
+    child_tid: u64,
+) -> SyscallResult {
+    let known = CLONE_VFORK | CLONE_PARENT_SETTID | CLONE_CHILD_SETTID | CLONE_CHILD_CLEARTID;
+    // the low byte is the signal the parent gets, which is always SIGCHLD
+    if flags & !known & !0xff != 0 || flags & CLONE_VM != 0 {
+        return Err(EINVAL);
+    }
+    let spawner = env.threads.process_spawner().ok_or(ENOSYS)?;
+    let threads = env.threads.fork(env.signals.clone());
+    let pid = threads.pid();
+    if flags & CLONE_PARENT_SETTID != 0 {
+        if let Err(e) = memory.write_u32(parent_tid, pid as u32) {
+            env.threads.forget_child(pid);
+            return Err(e.into());
+        }
+    }
+    let mut child = env.fork(threads);
+    if flags & CLONE_CHILD_CLEARTID != 0 {
+        child.clear_child_tid = child_tid;
+    }
+    let spawned = spawner(NewProcess {
+        env: child,
+        stack,
+        regs: thread::child_regs(regs, stack, None),
+        map: memory.map().clone(),
+        child_tid: (flags & CLONE_CHILD_SETTID != 0).then_some(child_tid),
+    });
+    if let Err(e) = spawned {
+        env.threads.forget_child(pid);
+        return Err(e);
+    }
+    Ok(pid as u64)
+}
+
+/// Read a NULL terminated array of string pointers such as argv, counting
+/// the bytes against `budget`
+fn read_strings<M: LinearMemory + ?Sized>(
+    memory: &GuestMemory<M>,
+    array: u64,
+    budget: &mut usize,
+) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    // linux takes a NULL argv or envp as an empty one
+    if array == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let ptr = memory.read_u64(array + strings.len() as u64 * 8)?;
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        let bytes = memory.read_cstr(ptr, *budget).map_err(|e| match e {
+            MemoryError::OutOfBounds(_) => E2BIG,
+            e => e.into(),
+        })?;
+        *budget = budget.checked_sub(bytes.len() + 1).ok_or(E2BIG)?;
+        strings.push(String::from_utf8_lossy(&bytes).into_owned());
+    }
+}
+
+/// Replace the program of the process with the ELF file at `path`. On
+/// success the process state is reset for the new image, which the caller
+/// hands to the engine to load in place of the current one.
+pub fn execve<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    path: u64,
+    argv: u64,
+    envp: u64,
+) -> Result<ExecImage, Errno> {
+    let path = fs::read_path(memory, path)?;
+    let mut budget = ARG_MAX;
+    let args = read_strings(memory, argv, &mut budget)?;
+    let envs = read_strings(memory, envp, &mut budget)?
+        .into_iter()
+        .map(|var| match var.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (var, String::new()),
+        })
+        .collect();
+    let (path, data) = fs::read_executable(env, &path)?;
+    // the image must load before the process it replaces is torn down
+    if let Err(e) = process::check(&data) {
+        tracing::debug!(target: "doublejit::syscall", path = %path.display(), "execve: {}", e);
+        return Err(ENOEXEC);
+    }
+
+    tracing::debug!(target: "doublejit::syscall", path = %path.display(), ?args, "execve");
+    // past this point execve cannot fail any more
+    env.fds.lock().unwrap().close_on_exec();
+    env.signals.exec();
+    env.sigstate.altstack = None;
+    env.timers.exec();
+    env.threads.exec();
+    // the caller becomes the thread group leader if it was not
+    env.tid = env.threads.pid();
+    env.clear_child_tid = 0;
+    *env.mm.lock().unwrap() = Mappings::default();
+    env.code.lock().unwrap().clear();
+    Ok(ExecImage { path, data, args, envs })
+}
+
+/// The wait status of a child that ended with `result`
+fn wait_status(result: ExecutionResult) -> u32 {
+    match result {
+        ExecutionResult::Exited(code) => (code as u32 & 0xff) << 8,
+        ExecutionResult::Signaled(sig) => sig & 0x7f,
+    }
+}
+
+pub fn wait4<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    pid: i32,
+    wstatus: u64,
+    options: u32,
+    rusage: u64,
+) -> SyscallResult {
+    if options & !(WNOHANG | WUNTRACED | WCONTINUED | WNOTHREAD | WALL | WCLONE) != 0 {
+        return Err(EINVAL);
+    }
+    // the guest has a single process group, so waiting for a group is
+    // waiting for any child
+    let pid = (pid > 0).then_some(pid);
+    let Some((pid, result)) = env.threads.wait_child(pid, options & WNOHANG == 0)? else {
+        return Ok(0);
+    };
+    if wstatus != 0 {
+        memory.write_u32(wstatus, wait_status(result))?;
+    }
+    if rusage != 0 {
+        memory.write(rusage, &[0; RUSAGE_LEN])?;
+    }
+    Ok(pid as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::execution::GuestExit;
+    use crate::runtime::regs::{A0, A1, A2, A7, SP};
+    use crate::runtime::syscall::signal::SIGCHLD;
+    use crate::runtime::syscall::testing::{call, TestMemory};
+    use crate::runtime::syscall::{ecall, ECHILD, SYS_CLONE, SYS_EXECVE, SYS_WAIT4};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_fork_and_wait() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+        // clone needs the registers, which only come with an ecall
+        let fork = |env: &mut SyscallEnv, memory: &mut GuestMemory<Vec<u8>>| {
+            let mut regs = Registers::new(0x10200, 0x10f00);
+            (regs.x[A7], regs.x[A0]) = (SYS_CLONE, SIGCHLD as u64);
+            ecall(env, memory, &mut regs).unwrap();
+            regs.x[A0] as i64
+        };
+        assert_eq!(call(&mut env, &mut memory, SYS_WAIT4, [u64::MAX, 0x10000, 0, 0, 0, 0]), -ECHILD.0 as i64);
+
+        let spawned = Arc::new(Mutex::new(Vec::new()));
+        let sink = spawned.clone();
+        env.threads.set_process_spawner(Arc::new(move |process| {
+            sink.lock().unwrap().push(process);
+            Ok(())
+        }));
+        let pid = fork(&mut env, &mut memory);
+        assert_eq!(pid, 2);
+        let child = spawned.lock().unwrap().pop().unwrap();
+        assert_eq!((child.env.threads.pid(), child.env.tid), (2, 2));
+        assert_eq!((child.regs.pc, child.regs.x[SP], child.regs.x[A0]), (0x10200, 0x10f00, 0));
+        assert_eq!(call(&mut env, &mut memory, SYS_WAIT4, [u64::MAX, 0x10000, WNOHANG as u64, 0, 0, 0]), 0);
+
+        child.env.threads.terminate(ExecutionResult::Exited(7));
+        assert_ne!(env.signals.pending() & (1 << (SIGCHLD - 1)), 0);
+        assert_eq!(call(&mut env, &mut memory, SYS_WAIT4, [pid as u64, 0x10000, 0, 0x10100, 0, 0]), pid);
+        assert_eq!(memory.read_u32(0x10000).unwrap(), 7 << 8);
+        assert_eq!(call(&mut env, &mut memory, SYS_WAIT4, [u64::MAX, 0, 0, 0, 0, 0]), -ECHILD.0 as i64);
+    }
+
+    #[test]
+    fn test_execve() {
+        let config = Config::new()
+            .file("/bin/true", std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test_binaries/add_test/add_test")).unwrap())
+            .file("/bin/broken", b"\x7fELF rest of the image".to_vec())
+            .file("/bin/script", b"#!/bin/sh\n".to_vec());
+        let mut env = SyscallEnv::new(&config);
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+        memory.write(0x10000, b"/bin/true\0-v\0HOME=/\0/bin/script\0/bin/broken\0").unwrap();
+        for (index, ptr) in [0x10000u64, 0x1000a, 0, 0x1000d, 0].iter().enumerate() {
+            memory.write_u64(0x10100 + index as u64 * 8, *ptr).unwrap();
+        }
+        let mut execve = |env: &mut SyscallEnv, path| {
+            let mut regs = Registers::default();
+            regs.x[A7] = SYS_EXECVE;
+            (regs.x[A0], regs.x[A1], regs.x[A2]) = (path, 0x10100, 0x10118);
+            ecall(env, &mut memory, &mut regs).map(|()| regs.x[A0] as i64)
+        };
+
+        assert_eq!(execve(&mut env, 0x10014), Ok(-ENOEXEC.0 as i64));
+        env.fds.lock().unwrap().set_cloexec(1, true).unwrap();
+        // a malformed image fails with the process left as it was
+        assert_eq!(execve(&mut env, 0x10020), Ok(-ENOEXEC.0 as i64));
+        assert!(env.fds.lock().unwrap().get(1).is_ok());
+        let Err(GuestExit::Exec(image)) = execve(&mut env, 0x10000) else {
+            panic!("execve did not replace the image");
+        };
+        assert_eq!(image.path, std::path::Path::new("/bin/true"));
+        assert_eq!(image.args, ["/bin/true", "-v"]);
+        assert_eq!(image.envs, [("HOME".to_string(), "/".to_string())]);
+        assert!(env.fds.lock().unwrap().get(1).is_err());
+        assert!(env.fds.lock().unwrap().get(2).is_ok());
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use super::net::Socket;
+use super::pipe::{PipeReader, PipeWriter};
+use super::vfs::VfsFile;
+use super::{Errno, EBADF, EMFILE};
+
+/// Descriptors a guest may have open at once
+pub const MAX_FDS: usize = 1024;
+
+/// Source of the guest's standard input
+pub enum Input {
+    /// The host process' stdin
+    Host,
+    /// A reader supplied by the embedder
+    Reader(Box<dyn Read + Send>),
+}
+
+impl Input {
+    /// Read what is available, blocking until at least one byte or end of
+    /// file, the same short read semantics as read(2) on a pipe
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let result = match self {
+                Input::Host => io::stdin().lock().read(buf),
+                Input::Reader(reader) => reader.read(buf),
+            };
+            match result {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Host => write!(f, "Host"),
+            Input::Reader(_) => write!(f, "Reader"),
+        }
+    }
+}
+
+/// What an open file description refers to on the host side
+#[derive(Debug)]
+pub enum FileKind {
+    Stdin(Input),
+    Stdout,
+    Stderr,
+    File(File),
+    /// An open directory, usable as `dirfd` of the `*at` syscalls
+    Dir,
+    /// A node of the in-memory filesystem
+    Virtual(VfsFile),
+    Socket(Socket),
+    PipeRead(PipeReader),
+    PipeWrite(PipeWriter),
+}
+
+/// An open file description, shared by all descriptors duplicated from it
+#[derive(Debug)]
+pub struct OpenFile {
+    pub kind: FileKind,
+    /// Path as the guest sees it, to resolve paths relative to a `dirfd`
+    pub guest_path: PathBuf,
+    /// Host path, `None` for the standard streams and VFS nodes
+    pub host_path: Option<PathBuf>,
+    /// `O_*` status flags given to openat
+    pub flags: u32,
+}
+
+/// What an open file description holds that sharing it does not keep as
+/// it was: its file position, or the bytes buffered in its pipe
+#[derive(Debug, Clone)]
+enum FileState {
+    Position(u64),
+    Pipe(Vec<u8>),
+}
+
+impl OpenFile {
+    fn state(&mut self) -> Option<FileState> {
+        match &mut self.kind {
+            FileKind::File(file) => file.stream_position().ok().map(FileState::Position),
+            FileKind::Virtual(file) => file.position().map(|pos| FileState::Position(pos as u64)),
+            FileKind::PipeRead(pipe) => Some(FileState::Pipe(pipe.end().contents())),
+            FileKind::PipeWrite(pipe) => Some(FileState::Pipe(pipe.end().contents())),
+            _ => None,
+        }
+    }
+
+    fn set_state(&mut self, state: &FileState) {
+        match (&mut self.kind, state) {
+            (FileKind::File(file), FileState::Position(pos)) => {
+                // seeking a regular file from its start does not fail
+                let _ = file.seek(SeekFrom::Start(*pos));
+            }
+            (FileKind::Virtual(file), FileState::Position(pos)) => file.set_position(*pos as usize),
+            (FileKind::PipeRead(pipe), FileState::Pipe(data)) => pipe.end().set_contents(data),
+            (FileKind::PipeWrite(pipe), FileState::Pipe(data)) => pipe.end().set_contents(data),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Fd {
+    pub file: Arc<Mutex<OpenFile>>,
+    pub cloexec: bool,
+}
+
+/// The guest's file descriptor table
+#[derive(Debug, Clone)]
+pub struct FdTable {
+    fds: Vec<Option<Fd>>,
+    /// Descriptors below this are usable, `RLIMIT_NOFILE`
+    limit: usize,
+}
+
+impl FdTable {
+    /// A table with the standard streams open on descriptors 0, 1 and 2
+    pub fn new() -> Self {
+        let mut table = Self {
+            fds: Vec::new(),
+            limit: MAX_FDS,
+        };
+        for kind in [FileKind::Stdin(Input::Host), FileKind::Stdout, FileKind::Stderr] {
+            table
+                .insert(
+                    OpenFile {
+                        kind,
+                        guest_path: PathBuf::new(),
+                        host_path: None,
+                        flags: 0,
+                    },
+                    false,
+                )
+                .unwrap();
+        }
+        table
+    }
+
+    /// Open a new description on the lowest free descriptor
+    pub fn insert(&mut self, file: OpenFile, cloexec: bool) -> Result<i32, Errno> {
+        self.insert_fd(Fd {
+            file: Arc::new(Mutex::new(file)),
+            cloexec,
+        })
+    }
+
+    pub fn insert_fd(&mut self, fd: Fd) -> Result<i32, Errno> {
+        self.insert_fd_from(0, fd)
+    }
+
+    /// Place `fd` on the lowest free descriptor not below `min`
+    pub fn insert_fd_from(&mut self, min: usize, fd: Fd) -> Result<i32, Errno> {
+        let index = (min..self.limit)
+            .find(|&index| self.fds.get(index).is_none_or(Option::is_none))
+            .ok_or(EMFILE)?;
+        if index >= self.fds.len() {
+            self.fds.resize(index + 1, None);
+        }
+        self.fds[index] = Some(fd);
+        Ok(index as i32)
+    }
+
+    /// Place `fd` on the descriptor `index`, returning what was open there
+    pub fn set(&mut self, index: i32, fd: Fd) -> Result<Option<Fd>, Errno> {
+        let index = usize::try_from(index).ok().filter(|&index| index < self.limit).ok_or(EBADF)?;
+        if index >= self.fds.len() {
+            self.fds.resize(index + 1, None);
+        }
+        Ok(self.fds[index].replace(fd))
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Limit new descriptors to below `limit`, at most `MAX_FDS`. Those
+    /// already open above it stay open, as on linux.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(MAX_FDS);
+    }
+
+    pub fn set_cloexec(&mut self, fd: i32, cloexec: bool) -> Result<(), Errno> {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.fds.get_mut(fd))
+            .and_then(Option::as_mut)
+            .ok_or(EBADF)?
+            .cloexec = cloexec;
+        Ok(())
+    }
+
+    pub fn get(&self, fd: i32) -> Result<&Fd, Errno> {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.fds.get(fd))
+            .and_then(Option::as_ref)
+            .ok_or(EBADF)
+    }
+
+    pub fn file(&self, fd: i32) -> Result<Arc<Mutex<OpenFile>>, Errno> {
+        self.get(fd).map(|fd| fd.file.clone())
+    }
+
+    /// Close the descriptors marked close-on-exec
+    pub fn close_on_exec(&mut self) {
+        for fd in &mut self.fds {
+            if fd.as_ref().is_some_and(|fd| fd.cloexec) {
+                *fd = None;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, fd: i32) -> Result<Fd, Errno> {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.fds.get_mut(fd))
+            .and_then(Option::take)
+            .ok_or(EBADF)
+    }
+}
+
+/// A descriptor table as a snapshot keeps it, with the positions and pipe
+/// contents of its open files, which go on changing in the guest the table
+/// was saved from
+#[derive(Debug, Clone)]
+pub struct SavedFds {
+    table: FdTable,
+    states: Vec<(Arc<Mutex<OpenFile>>, FileState)>,
+}
+
+impl FdTable {
+    pub fn save(&self) -> SavedFds {
+        let mut states: Vec<(Arc<Mutex<OpenFile>>, FileState)> = Vec::new();
+        for fd in self.fds.iter().flatten() {
+            // descriptors duplicated from one another share the state
+            if states.iter().any(|(file, _)| Arc::ptr_eq(file, &fd.file)) {
+                continue;
+            }
+            if let Some(state) = fd.file.lock().unwrap().state() {
+                states.push((fd.file.clone(), state));
+            }
+        }
+        SavedFds {
+            table: self.clone(),
+            states,
+        }
+    }
+}
+
+impl SavedFds {
+    /// The saved table, its open files put back into their saved state
+    pub fn restore(&self) -> FdTable {
+        for (file, state) in &self.states {
+            file.lock().unwrap().set_state(state);
+        }
+        self.table.clone()
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
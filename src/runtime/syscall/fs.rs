@@ -0,0 +1,722 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use super::fd::{Fd, FileKind, Input, OpenFile};
+use super::signal::SIGPIPE;
+use super::stat::{Stat, S_IFIFO, S_IFSOCK};
+use super::vfs::VfsNode;
+use super::{Errno, SyscallEnv, SyscallResult};
+use super::{EACCES, EBADF, EINVAL, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, EPIPE, ESPIPE};
+use crate::middleend::address_map::{AddressMap, GuestMemory, LinearMemory, MemoryError};
+
+pub const AT_FDCWD: i32 = -100;
+pub const AT_SYMLINK_NOFOLLOW: u32 = 0x100;
+pub const AT_REMOVEDIR: u32 = 0x200;
+pub const AT_NO_AUTOMOUNT: u32 = 0x800;
+pub const AT_EMPTY_PATH: u32 = 0x1000;
+pub const AT_STATX_SYNC_TYPE: u32 = 0x6000;
+
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+pub const O_NONBLOCK: u32 = 0o4000;
+pub const O_DIRECTORY: u32 = 0o200000;
+pub const O_CLOEXEC: u32 = 0o2000000;
+
+pub const F_DUPFD: u32 = 0;
+pub const F_GETFD: u32 = 1;
+pub const F_SETFD: u32 = 2;
+pub const F_GETFL: u32 = 3;
+pub const F_SETFL: u32 = 4;
+pub const F_DUPFD_CLOEXEC: u32 = 1030;
+pub const FD_CLOEXEC: u64 = 1;
+
+pub const PATH_MAX: usize = 4096;
+/// Largest transfer of a single read or write, larger requests complete short
+const MAX_IO: usize = 1 << 20;
+/// Most buffers a readv or writev takes
+pub const IOV_MAX: u64 = 1024;
+
+/// Lexically normalize `path` against `base`, never climbing above `/`
+fn normalize(base: &Path, path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in base.join(path).components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+/// Resolve a guest path relative to `dirfd` into an absolute guest path
+fn guest_path(env: &SyscallEnv, dirfd: i32, path: &[u8]) -> Result<PathBuf, Errno> {
+    if path.is_empty() {
+        return Err(ENOENT);
+    }
+    if path.len() >= PATH_MAX {
+        return Err(ENAMETOOLONG);
+    }
+    let path = Path::new(std::ffi::OsStr::from_bytes(path));
+    let base = if path.is_absolute() {
+        PathBuf::from("/")
+    } else if dirfd == AT_FDCWD {
+        env.cwd.clone()
+    } else {
+        let file = env.fds.lock().unwrap().file(dirfd)?;
+        let file = file.lock().unwrap();
+        match file.kind {
+            FileKind::Dir => file.guest_path.clone(),
+            _ => return Err(ENOTDIR),
+        }
+    };
+    Ok(normalize(&base, path))
+}
+
+/// Map a guest path onto the host. Only paths below a preopened directory
+/// resolve, and a host symlink pointing outside of its preopen is refused.
+fn host_path(env: &SyscallEnv, guest: &Path) -> Result<PathBuf, Errno> {
+    let preopen = env
+        .preopens
+        .iter()
+        .filter(|p| guest.starts_with(&p.guest))
+        .max_by_key(|p| p.guest.components().count())
+        .ok_or(EACCES)?;
+    let host = preopen
+        .host
+        .join(guest.strip_prefix(&preopen.guest).unwrap());
+
+    // the deepest existing ancestor decides where the path really points to
+    let root = preopen.host.canonicalize()?;
+    let mut existing = host.as_path();
+    loop {
+        match existing.canonicalize() {
+            Ok(real) if real.starts_with(&root) => break,
+            Ok(_) => return Err(EACCES),
+            Err(_) => existing = existing.parent().ok_or(EACCES)?,
+        }
+    }
+    Ok(host)
+}
+
+/// Resolve a guest path relative to `dirfd` into (guest path, host path)
+pub fn resolve_path(env: &SyscallEnv, dirfd: i32, path: &[u8]) -> Result<(PathBuf, PathBuf), Errno> {
+    let guest = guest_path(env, dirfd, path)?;
+    let host = host_path(env, &guest)?;
+    Ok((guest, host))
+}
+
+/// Whether `guest` is served by the VFS, which shadows the host
+fn is_virtual(env: &SyscallEnv, guest: &Path) -> bool {
+    let vfs = env.vfs.lock().unwrap();
+    vfs.get(guest).is_some() || vfs.owns_parent(guest)
+}
+
+pub fn read_path<M: LinearMemory + ?Sized>(memory: &GuestMemory<M>, ptr: u64) -> Result<Vec<u8>, Errno> {
+    memory.read_cstr(ptr, PATH_MAX).map_err(|e| match e {
+        MemoryError::OutOfBounds(_) => ENAMETOOLONG,
+        e => e.into(),
+    })
+}
+
+/// Read the whole program file at the guest `path` for execve, returning
+/// its absolute guest path. VFS files are all executable, host files need
+/// an execute bit.
+pub fn read_executable(env: &SyscallEnv, path: &[u8]) -> Result<(PathBuf, Vec<u8>), Errno> {
+    let guest = guest_path(env, AT_FDCWD, path)?;
+    if is_virtual(env, &guest) {
+        return match env.vfs.lock().unwrap().get(&guest) {
+            Some(VfsNode::File(data)) => Ok((guest, data.lock().unwrap().clone())),
+            Some(_) => Err(EACCES),
+            None => Err(ENOENT),
+        };
+    }
+    let host = host_path(env, &guest)?;
+    let meta = fs::metadata(&host)?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Err(EACCES);
+    }
+    Ok((guest, fs::read(&host)?))
+}
+
+pub fn openat<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    dirfd: i32,
+    path: u64,
+    flags: u32,
+    mode: u32,
+) -> SyscallResult {
+    let path = read_path(memory, path)?;
+    let guest_path = guest_path(env, dirfd, &path)?;
+    if is_virtual(env, &guest_path) {
+        return vfs_openat(env, memory.map(), guest_path, flags);
+    }
+    let host_path = host_path(env, &guest_path)?;
+    let access = flags & O_ACCMODE;
+    let kind = if host_path.is_dir() {
+        if access != O_RDONLY || flags & O_CREAT != 0 {
+            return Err(EISDIR);
+        }
+        FileKind::Dir
+    } else {
+        if flags & O_DIRECTORY != 0 {
+            return Err(if host_path.exists() { ENOTDIR } else { ENOENT });
+        }
+        let file = OpenOptions::new()
+            .read(access != O_WRONLY)
+            .write(access != O_RDONLY)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL)
+            .mode(mode & 0o7777)
+            .open(&host_path)?;
+        FileKind::File(file)
+    };
+    let fd = env.fds.lock().unwrap().insert(
+        OpenFile {
+            kind,
+            guest_path,
+            host_path: Some(host_path),
+            flags,
+        },
+        flags & O_CLOEXEC != 0,
+    )?;
+    Ok(fd as u64)
+}
+
+fn vfs_openat(env: &mut SyscallEnv, map: &AddressMap, guest_path: PathBuf, flags: u32) -> SyscallResult {
+    let access = flags & O_ACCMODE;
+    let mut vfs = env.vfs.lock().unwrap();
+    if flags & O_CREAT != 0 {
+        vfs.create(&guest_path, flags & O_EXCL != 0)?;
+    }
+    let file = vfs.open(&guest_path, map)?;
+    drop(vfs);
+    let kind = match file {
+        Some(_) if flags & O_DIRECTORY != 0 => return Err(ENOTDIR),
+        Some(mut file) => {
+            if flags & O_TRUNC != 0 && access != O_RDONLY {
+                file.truncate();
+            }
+            FileKind::Virtual(file)
+        }
+        None if access != O_RDONLY => return Err(EISDIR),
+        None => FileKind::Dir,
+    };
+    let fd = env.fds.lock().unwrap().insert(
+        OpenFile {
+            kind,
+            guest_path,
+            host_path: None,
+            flags,
+        },
+        flags & O_CLOEXEC != 0,
+    )?;
+    Ok(fd as u64)
+}
+
+pub fn close(env: &mut SyscallEnv, fd: i32) -> SyscallResult {
+    env.fds.lock().unwrap().remove(fd)?;
+    Ok(0)
+}
+
+/// Duplicate `fd` onto the lowest free descriptor, sharing the open file
+/// description but never the close-on-exec flag
+pub fn dup(env: &mut SyscallEnv, fd: i32) -> SyscallResult {
+    let mut fds = env.fds.lock().unwrap();
+    let file = fds.file(fd)?;
+    Ok(fds.insert_fd(Fd { file, cloexec: false })? as u64)
+}
+
+pub fn dup3(env: &mut SyscallEnv, oldfd: i32, newfd: i32, flags: u32) -> SyscallResult {
+    if oldfd == newfd || flags & !O_CLOEXEC != 0 {
+        return Err(EINVAL);
+    }
+    let mut fds = env.fds.lock().unwrap();
+    let file = fds.file(oldfd)?;
+    let replaced = fds.set(
+        newfd,
+        Fd {
+            file,
+            cloexec: flags & O_CLOEXEC != 0,
+        },
+    )?;
+    // closing the replaced description may wake pipe peers, do it unlocked
+    drop(fds);
+    drop(replaced);
+    Ok(newfd as u64)
+}
+
+pub fn fcntl(env: &mut SyscallEnv, fd: i32, cmd: u32, arg: u64) -> SyscallResult {
+    let mut fds = env.fds.lock().unwrap();
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            if arg >= fds.limit() as u64 {
+                return Err(EINVAL);
+            }
+            let file = fds.file(fd)?;
+            let cloexec = cmd == F_DUPFD_CLOEXEC;
+            Ok(fds.insert_fd_from(arg as usize, Fd { file, cloexec })? as u64)
+        }
+        F_GETFD => Ok(if fds.get(fd)?.cloexec { FD_CLOEXEC } else { 0 }),
+        F_SETFD => fds.set_cloexec(fd, arg & FD_CLOEXEC != 0).map(|()| 0),
+        F_GETFL => Ok(fds.file(fd)?.lock().unwrap().flags as u64),
+        F_SETFL => {
+            let file = fds.file(fd)?;
+            drop(fds);
+            let mut file = file.lock().unwrap();
+            // only these status flags can change after open
+            let settable = O_APPEND | O_NONBLOCK;
+            file.flags = (file.flags & !settable) | (arg as u32 & settable);
+            if let FileKind::Socket(socket) = &mut file.kind {
+                socket.set_nonblocking(arg as u32 & O_NONBLOCK != 0)?;
+            }
+            Ok(0)
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+/// Read from the file open on `fd` at its position, or at `offset` without
+/// moving it
+fn read_fd(env: &SyscallEnv, fd: i32, data: &mut [u8], offset: Option<u64>) -> Result<usize, Errno> {
+    let file = env.fds.lock().unwrap().file(fd)?;
+    let mut file = file.lock().unwrap();
+    let access = file.flags & O_ACCMODE;
+    let nonblocking = file.flags & O_NONBLOCK != 0;
+    // pipes block with the file unlocked, for other threads to use it
+    if let (FileKind::PipeRead(pipe), None) = (&file.kind, offset) {
+        let pipe = pipe.end();
+        drop(file);
+        return pipe.read_bytes(data, nonblocking);
+    }
+    Ok(match (&mut file.kind, offset) {
+        (FileKind::File(file), None) => file.read(data)?,
+        (FileKind::File(file), Some(offset)) => file.read_at(data, offset)?,
+        (FileKind::Virtual(_), _) if access == O_WRONLY => return Err(EBADF),
+        (FileKind::Virtual(file), None) => file.read(data),
+        (FileKind::Virtual(file), Some(offset)) => file.read_at(data, offset as usize),
+        (FileKind::Dir, _) => return Err(EISDIR),
+        (FileKind::Stdout | FileKind::Stderr | FileKind::PipeWrite(_), _) => return Err(EBADF),
+        // streams have no position to read at
+        (_, Some(_)) => return Err(ESPIPE),
+        (FileKind::Stdin(input), None) => input.read(data)?,
+        (FileKind::Socket(socket), None) => socket.read(data)?,
+        (FileKind::PipeRead(_), None) => unreachable!(),
+    })
+}
+
+/// Write to the file open on `fd` at its position, or at `offset` without
+/// moving it
+fn write_fd(env: &mut SyscallEnv, fd: i32, data: &[u8], offset: Option<u64>) -> Result<usize, Errno> {
+    let file = env.fds.lock().unwrap().file(fd)?;
+    let mut file = file.lock().unwrap();
+    let flags = file.flags;
+    if let (FileKind::PipeWrite(pipe), None) = (&file.kind, offset) {
+        let pipe = pipe.end();
+        drop(file);
+        return match pipe.write_bytes(data, flags & O_NONBLOCK != 0) {
+            Err(EPIPE) => {
+                // writing to a pipe nobody reads raises SIGPIPE as well
+                env.sigstate.post(SIGPIPE);
+                Err(EPIPE)
+            }
+            result => result,
+        };
+    }
+    Ok(match (&mut file.kind, offset) {
+        (FileKind::File(file), None) => file.write(data)?,
+        (FileKind::File(file), Some(offset)) => file.write_at(data, offset)?,
+        (FileKind::Virtual(_), _) if flags & O_ACCMODE == O_RDONLY => return Err(EBADF),
+        (FileKind::Virtual(file), None) => file.write(data, flags & O_APPEND != 0)?,
+        (FileKind::Virtual(file), Some(offset)) => file.write_at(data, offset as usize)?,
+        (FileKind::Dir, _) => return Err(EISDIR),
+        (FileKind::Stdin(_) | FileKind::PipeRead(_), _) => return Err(EBADF),
+        (_, Some(_)) => return Err(ESPIPE),
+        (FileKind::Stdout, None) => io::stdout().write(data)?,
+        (FileKind::Stderr, None) => io::stderr().write(data)?,
+        (FileKind::Socket(socket), None) => socket.write(data)?,
+        (FileKind::PipeWrite(_), None) => unreachable!(),
+    })
+}
+
+fn file_offset(offset: i64) -> Result<u64, Errno> {
+    u64::try_from(offset).map_err(|_| EINVAL)
+}
+
+/// Decode `iovcnt` guest `struct iovec`s into (base, len) pairs, shortened
+/// so that together they stay within a single transfer
+fn read_iovecs<M: LinearMemory + ?Sized>(
+    memory: &GuestMemory<M>,
+    iov: u64,
+    iovcnt: u64,
+) -> Result<Vec<(u64, usize)>, Errno> {
+    if iovcnt > IOV_MAX {
+        return Err(EINVAL);
+    }
+    let mut total = 0usize;
+    let mut iovecs = Vec::new();
+    for index in 0..iovcnt {
+        let base = memory.read_u64(iov + index * 16)?;
+        let len = memory.read_u64(iov + index * 16 + 8)?;
+        if len > i64::MAX as u64 {
+            return Err(EINVAL);
+        }
+        let len = (len as usize).min(MAX_IO - total);
+        total += len;
+        iovecs.push((base, len));
+    }
+    Ok(iovecs)
+}
+
+pub fn read<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    buf: u64,
+    count: u64,
+) -> SyscallResult {
+    let mut data = vec![0; (count as usize).min(MAX_IO)];
+    let len = read_fd(env, fd, &mut data, None)?;
+    memory.write(buf, &data[..len])?;
+    Ok(len as u64)
+}
+
+pub fn write<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    buf: u64,
+    count: u64,
+) -> SyscallResult {
+    let data = memory.read_vec(buf, (count as usize).min(MAX_IO))?;
+    Ok(write_fd(env, fd, &data, None)? as u64)
+}
+
+pub fn pread64<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    buf: u64,
+    count: u64,
+    offset: i64,
+) -> SyscallResult {
+    let mut data = vec![0; (count as usize).min(MAX_IO)];
+    let len = read_fd(env, fd, &mut data, Some(file_offset(offset)?))?;
+    memory.write(buf, &data[..len])?;
+    Ok(len as u64)
+}
+
+pub fn pwrite64<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    buf: u64,
+    count: u64,
+    offset: i64,
+) -> SyscallResult {
+    let data = memory.read_vec(buf, (count as usize).min(MAX_IO))?;
+    Ok(write_fd(env, fd, &data, Some(file_offset(offset)?))? as u64)
+}
+
+/// readv, and preadv with an `offset`. The buffers are filled by a single
+/// read, so a short read leaves the later ones untouched.
+pub fn readv<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    iov: u64,
+    iovcnt: u64,
+    offset: Option<i64>,
+) -> SyscallResult {
+    let iovecs = read_iovecs(memory, iov, iovcnt)?;
+    let offset = offset.map(file_offset).transpose()?;
+    let mut data = vec![0; iovecs.iter().map(|(_, len)| len).sum()];
+    let len = read_fd(env, fd, &mut data, offset)?;
+    let mut chunks = &data[..len];
+    for (base, iov_len) in iovecs {
+        let (chunk, rest) = chunks.split_at(iov_len.min(chunks.len()));
+        memory.write(base, chunk)?;
+        chunks = rest;
+    }
+    Ok(len as u64)
+}
+
+/// writev, and pwritev with an `offset`, gathering the buffers into a
+/// single write
+pub fn writev<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    iov: u64,
+    iovcnt: u64,
+    offset: Option<i64>,
+) -> SyscallResult {
+    let iovecs = read_iovecs(memory, iov, iovcnt)?;
+    let offset = offset.map(file_offset).transpose()?;
+    let mut data = Vec::new();
+    for (base, len) in iovecs {
+        data.extend(memory.read_vec(base, len)?);
+    }
+    Ok(write_fd(env, fd, &data, offset)? as u64)
+}
+
+pub fn unlinkat<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    dirfd: i32,
+    path: u64,
+    flags: u32,
+) -> SyscallResult {
+    if flags & !AT_REMOVEDIR != 0 {
+        return Err(EINVAL);
+    }
+    let path = read_path(memory, path)?;
+    let guest_path = guest_path(env, dirfd, &path)?;
+    if is_virtual(env, &guest_path) {
+        env.vfs.lock().unwrap().remove(&guest_path, flags & AT_REMOVEDIR != 0)?;
+        return Ok(0);
+    }
+    let host_path = host_path(env, &guest_path)?;
+    if flags & AT_REMOVEDIR != 0 {
+        fs::remove_dir(host_path)?;
+    } else {
+        fs::remove_file(host_path)?;
+    }
+    Ok(0)
+}
+
+/// Attributes of the file open on `fd`
+fn fd_stat(env: &SyscallEnv, fd: i32) -> Result<Stat, Errno> {
+    let file = env.fds.lock().unwrap().file(fd)?;
+    let file = file.lock().unwrap();
+    Ok(match (&file.kind, &file.host_path) {
+        (FileKind::File(file), _) => Stat::from_metadata(&file.metadata()?),
+        (FileKind::Dir, Some(path)) => Stat::from_metadata(&fs::metadata(path)?),
+        (FileKind::Dir, None) => env.vfs.lock().unwrap().stat(&file.guest_path)?,
+        (FileKind::Virtual(file), _) => env.vfs.lock().unwrap().file_stat(file),
+        (FileKind::Socket(_), _) => Stat {
+            mode: S_IFSOCK | 0o777,
+            nlink: 1,
+            blksize: 4096,
+            ..Stat::default()
+        },
+        // the standard streams are whatever the host has on them
+        (FileKind::Stdin(Input::Host), _) => Stat::from_metadata(&fs::metadata("/dev/stdin")?),
+        // an embedder supplied reader looks like a pipe
+        (FileKind::Stdin(Input::Reader(_)) | FileKind::PipeRead(_) | FileKind::PipeWrite(_), _) => Stat {
+            mode: S_IFIFO | 0o600,
+            nlink: 1,
+            blksize: 4096,
+            ..Stat::default()
+        },
+        (FileKind::Stdout, _) => Stat::from_metadata(&fs::metadata("/dev/stdout")?),
+        (FileKind::Stderr, _) => Stat::from_metadata(&fs::metadata("/dev/stderr")?),
+    })
+}
+
+/// Attributes of `path` relative to `dirfd`, or of `dirfd` itself for an
+/// empty path with `AT_EMPTY_PATH`
+fn path_stat<M: LinearMemory + ?Sized>(
+    env: &SyscallEnv,
+    memory: &GuestMemory<M>,
+    dirfd: i32,
+    path: u64,
+    flags: u32,
+) -> Result<Stat, Errno> {
+    let path = read_path(memory, path)?;
+    if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        return fd_stat(env, dirfd);
+    }
+    let guest_path = guest_path(env, dirfd, &path)?;
+    if is_virtual(env, &guest_path) {
+        return env.vfs.lock().unwrap().stat(&guest_path);
+    }
+    let host_path = host_path(env, &guest_path)?;
+    Ok(if flags & AT_SYMLINK_NOFOLLOW != 0 {
+        Stat::from_metadata(&fs::symlink_metadata(host_path)?)
+    } else {
+        Stat::from_metadata(&fs::metadata(host_path)?)
+    })
+}
+
+pub fn fstat<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    statbuf: u64,
+) -> SyscallResult {
+    memory.write(statbuf, &fd_stat(env, fd)?.to_bytes())?;
+    Ok(0)
+}
+
+pub fn newfstatat<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    dirfd: i32,
+    path: u64,
+    statbuf: u64,
+    flags: u32,
+) -> SyscallResult {
+    if flags & !(AT_SYMLINK_NOFOLLOW | AT_EMPTY_PATH) != 0 {
+        return Err(EINVAL);
+    }
+    let stat = path_stat(env, memory, dirfd, path, flags)?;
+    memory.write(statbuf, &stat.to_bytes())?;
+    Ok(0)
+}
+
+/// statx, answering every request with the basic stats whatever `mask` asks
+/// for, which the interface allows
+pub fn statx<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    dirfd: i32,
+    path: u64,
+    flags: u32,
+    _mask: u32,
+    statxbuf: u64,
+) -> SyscallResult {
+    if flags & !(AT_SYMLINK_NOFOLLOW | AT_NO_AUTOMOUNT | AT_EMPTY_PATH | AT_STATX_SYNC_TYPE) != 0 {
+        return Err(EINVAL);
+    }
+    let stat = path_stat(env, memory, dirfd, path, flags)?;
+    memory.write(statxbuf, &stat.to_statx())?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::stat::S_IFCHR;
+    use crate::runtime::syscall::EBADF;
+    use crate::runtime::syscall::{SYS_CLOSE, SYS_FSTAT, SYS_NEWFSTATAT, SYS_OPENAT};
+    use crate::runtime::syscall::{SYS_PREAD64, SYS_PWRITE64, SYS_READ, SYS_READV, SYS_UNLINKAT, SYS_WRITE, SYS_WRITEV};
+    use crate::runtime::syscall::ESPIPE;
+    use crate::runtime::syscall::testing::{call, TestMemory};
+
+    #[test]
+    fn test_file_roundtrip_in_sandbox() {
+        let dir = std::env::temp_dir().join(format!("doublejit-fs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut env = SyscallEnv::new(&Config::new().preopen("/data", &dir));
+        let base = 0x10000;
+        let mut guest = TestMemory::new(&[(base..base + 0x1000, Perm::RW)]);
+        let mut memory = guest.memory();
+
+        memory.write(base, b"/data/sub/../f.txt\0").unwrap();
+        memory.write(base + 0x100, b"hello").unwrap();
+        let flags = (O_CREAT | O_WRONLY | O_TRUNC) as u64;
+        let fd = call(&mut env, &mut memory, SYS_OPENAT, [AT_FDCWD as u64, base, flags, 0o644, 0, 0]);
+        assert!(fd >= 3);
+        assert_eq!(call(&mut env, &mut memory, SYS_WRITE, [fd as u64, base + 0x100, 5, 0, 0, 0]), 5);
+        assert_eq!(call(&mut env, &mut memory, SYS_CLOSE, [fd as u64, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(fs::read(dir.join("f.txt")).unwrap(), b"hello");
+
+        let fd = call(&mut env, &mut memory, SYS_OPENAT, [AT_FDCWD as u64, base, 0, 0, 0, 0]);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [fd as u64, base + 0x200, 64, 0, 0, 0]), 5);
+        assert_eq!(memory.read_vec(base + 0x200, 5).unwrap(), b"hello");
+        assert_eq!(call(&mut env, &mut memory, SYS_NEWFSTATAT, [AT_FDCWD as u64, base, base + 0x300, 0, 0, 0]), 0);
+        assert_eq!(memory.read_u64(base + 0x300 + 48).unwrap(), 5);
+        assert_eq!(call(&mut env, &mut memory, SYS_UNLINKAT, [AT_FDCWD as u64, base, 0, 0, 0, 0]), 0);
+        assert!(!dir.join("f.txt").exists());
+
+        // nothing outside of the preopen is reachable
+        memory.write(base, b"/data/../etc/passwd\0").unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_OPENAT, [AT_FDCWD as u64, base, 0, 0, 0, 0]), -EACCES.0 as i64);
+        assert_eq!(call(&mut env, &mut memory, SYS_CLOSE, [77, 0, 0, 0, 0, 0]), -EBADF.0 as i64);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_vfs_without_host_access() {
+        let mut env = SyscallEnv::new(&Config::new().file("/etc/hostname", "riscv\n"));
+        let base = 0x10000;
+        let mut guest = TestMemory::new(&[(base..base + 0x1000, Perm::RW)]);
+        let mut memory = guest.memory();
+
+        memory.write(base, b"/dev/null\0").unwrap();
+        let fd = call(&mut env, &mut memory, SYS_OPENAT, [AT_FDCWD as u64, base, O_WRONLY as u64, 0, 0, 0]);
+        assert!(fd >= 3);
+        assert_eq!(call(&mut env, &mut memory, SYS_WRITE, [fd as u64, base, 9, 0, 0, 0]), 9);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [fd as u64, base + 0x100, 9, 0, 0, 0]), -EBADF.0 as i64);
+        assert_eq!(call(&mut env, &mut memory, SYS_FSTAT, [fd as u64, base + 0x300, 0, 0, 0, 0]), 0);
+        assert_eq!(memory.read_u32(base + 0x300 + 16).unwrap() & 0o170000, S_IFCHR);
+
+        memory.write(base, b"/etc/hostname\0").unwrap();
+        let fd = call(&mut env, &mut memory, SYS_OPENAT, [AT_FDCWD as u64, base, 0, 0, 0, 0]);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [fd as u64, base + 0x100, 64, 0, 0, 0]), 6);
+        assert_eq!(memory.read_vec(base + 0x100, 6).unwrap(), b"riscv\n");
+
+        // the host stays out of reach without preopens
+        memory.write(base, b"/etc/passwd\0").unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_OPENAT, [AT_FDCWD as u64, base, 0, 0, 0, 0]), -ENOENT.0 as i64);
+        memory.write(base, b"/home\0").unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_NEWFSTATAT, [AT_FDCWD as u64, base, base + 0x300, 0, 0, 0]), -EACCES.0 as i64);
+    }
+
+    #[test]
+    fn test_stdin_reader() {
+        let input = io::Cursor::new(b"hello\nworld".to_vec());
+        let mut env = SyscallEnv::new(&Config::new()).stdin(input);
+        let base = 0x10000;
+        let mut guest = TestMemory::new(&[(base..base + 0x1000, Perm::RW)]);
+        let mut memory = guest.memory();
+
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [0, base, 3, 0, 0, 0]), 3);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [0, base + 3, 64, 0, 0, 0]), 8);
+        assert_eq!(memory.read_vec(base, 11).unwrap(), b"hello\nworld");
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [0, base, 64, 0, 0, 0]), 0);
+        assert_eq!(call(&mut env, &mut memory, SYS_WRITE, [0, base, 1, 0, 0, 0]), -EBADF.0 as i64);
+        assert_eq!(call(&mut env, &mut memory, SYS_FSTAT, [0, base + 0x100, 0, 0, 0, 0]), 0);
+        assert_eq!(memory.read_u32(base + 0x100 + 16).unwrap() & 0o170000, S_IFIFO);
+    }
+
+    #[test]
+    fn test_vectored_and_positional_io() {
+        let mut env = SyscallEnv::new(&Config::new().file("/tmp/data", "hello world"));
+        let base = 0x10000;
+        let mut guest = TestMemory::new(&[(base..base + 0x1000, Perm::RW)]);
+        let mut memory = guest.memory();
+
+        memory.write(base, b"/tmp/data\0").unwrap();
+        let fd = call(&mut env, &mut memory, SYS_OPENAT, [AT_FDCWD as u64, base, O_RDWR as u64, 0, 0, 0]) as u64;
+        assert_eq!(call(&mut env, &mut memory, SYS_PREAD64, [fd, base + 0x100, 5, 6, 0, 0]), 5);
+        assert_eq!(memory.read_vec(base + 0x100, 5).unwrap(), b"world");
+        memory.write(base + 0x100, b"HELLO").unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_PWRITE64, [fd, base + 0x100, 5, 0, 0, 0]), 5);
+
+        // the positional calls left the position at the start
+        let iovecs = [base + 0x200, 3, base + 0x300, 100].map(u64::to_le_bytes).concat();
+        memory.write(base + 0x80, &iovecs).unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_READV, [fd, base + 0x80, 2, 0, 0, 0]), 11);
+        assert_eq!(memory.read_vec(base + 0x200, 3).unwrap(), b"HEL");
+        assert_eq!(memory.read_vec(base + 0x300, 8).unwrap(), b"LO world");
+
+        memory.write(base + 0x200, b"!").unwrap();
+        memory.write(base + 0x300, b"?").unwrap();
+        let iovecs = [base + 0x200, 1, base + 0x300, 1].map(u64::to_le_bytes).concat();
+        memory.write(base + 0x80, &iovecs).unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_WRITEV, [fd, base + 0x80, 2, 0, 0, 0]), 2);
+        assert_eq!(call(&mut env, &mut memory, SYS_PREAD64, [fd, base + 0x100, 64, 0, 0, 0]), 13);
+        assert_eq!(memory.read_vec(base + 0x100, 13).unwrap(), b"HELLO world!?");
+
+        assert_eq!(call(&mut env, &mut memory, SYS_PREAD64, [0, base + 0x100, 1, 0, 0, 0]), -ESPIPE.0 as i64);
+        assert_eq!(call(&mut env, &mut memory, SYS_PREAD64, [fd, base + 0x100, 1, -1i64 as u64, 0, 0]), -EINVAL.0 as i64);
+    }
+}
@@ -0,0 +1,138 @@
+use super::fd::{FileKind, Input};
+use super::fs::O_NONBLOCK;
+use super::{SyscallEnv, SyscallResult, EINVAL, ENOTTY};
+use crate::middleend::address_map::{GuestMemory, LinearMemory};
+
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+pub const TCSETSW: u32 = 0x5403;
+pub const TCSETSF: u32 = 0x5404;
+pub const TIOCGPGRP: u32 = 0x540f;
+pub const TIOCGWINSZ: u32 = 0x5413;
+pub const TIOCSWINSZ: u32 = 0x5414;
+pub const FIONREAD: u32 = 0x541b;
+pub const FIONBIO: u32 = 0x5421;
+
+/// Size of the terminal the guest sees when its stdio is a tty
+pub const TTY_ROWS: u16 = 24;
+pub const TTY_COLS: u16 = 80;
+
+/// The kernel's `struct termios` of a freshly opened terminal, as `stty
+/// sane` leaves it: canonical mode with echo, CR to NL on input and NL to
+/// CR NL on output, 8 bit characters at 38400 baud
+fn default_termios() -> [u8; 36] {
+    const ICRNL: u32 = 0o400;
+    const IXON: u32 = 0o2000;
+    const OPOST: u32 = 0o1;
+    const ONLCR: u32 = 0o4;
+    const B38400: u32 = 0o17;
+    const CS8: u32 = 0o60;
+    const CREAD: u32 = 0o200;
+    const ISIG: u32 = 0o1;
+    const ICANON: u32 = 0o2;
+    const ECHO: u32 = 0o10;
+    const ECHOE: u32 = 0o20;
+    const ECHOK: u32 = 0o40;
+    const ECHOCTL: u32 = 0o1000;
+    const ECHOKE: u32 = 0o4000;
+    const IEXTEN: u32 = 0o100000;
+    // intr quit erase kill eof time min swtc start stop susp eol reprint
+    // discard werase lnext eol2
+    const CC: [u8; 17] = [3, 28, 127, 21, 4, 0, 1, 0, 17, 19, 26, 0, 18, 15, 23, 22, 0];
+
+    let flags = [
+        ICRNL | IXON,
+        OPOST | ONLCR,
+        B38400 | CS8 | CREAD,
+        ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN,
+    ];
+    let mut termios = [0; 36];
+    for (bytes, flag) in termios.chunks_mut(4).zip(flags) {
+        bytes.copy_from_slice(&flag.to_le_bytes());
+    }
+    // c_line stays 0, c_cc follows it
+    termios[17..17 + CC.len()].copy_from_slice(&CC);
+    termios
+}
+
+/// Whether `kind` is one of the host's standard streams
+fn is_host_stdio(kind: &FileKind) -> bool {
+    matches!(kind, FileKind::Stdin(Input::Host) | FileKind::Stdout | FileKind::Stderr)
+}
+
+pub fn ioctl<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    request: u32,
+    arg: u64,
+) -> SyscallResult {
+    let file = env.fds.lock().unwrap().file(fd)?;
+    let mut file = file.lock().unwrap();
+    let tty = env.tty && is_host_stdio(&file.kind);
+    match request {
+        FIONBIO => {
+            let nonblocking = memory.read_u32(arg)? != 0;
+            file.flags = (file.flags & !O_NONBLOCK) | if nonblocking { O_NONBLOCK } else { 0 };
+            if let FileKind::Socket(socket) = &mut file.kind {
+                socket.set_nonblocking(nonblocking)?;
+            }
+            Ok(0)
+        }
+        FIONREAD => {
+            let available = match &file.kind {
+                FileKind::PipeRead(pipe) => pipe.available(),
+                _ if tty => 0,
+                _ => return Err(ENOTTY),
+            };
+            memory.write_u32(arg, available as u32)?;
+            Ok(0)
+        }
+        _ if !tty => Err(ENOTTY),
+        TCGETS => {
+            memory.write(arg, &default_termios())?;
+            Ok(0)
+        }
+        // the host terminal is left alone, so mode changes are accepted and
+        // forgotten
+        TCSETS | TCSETSW | TCSETSF => Ok(0),
+        TIOCGWINSZ => {
+            let mut winsize = [0; 8];
+            winsize[..2].copy_from_slice(&TTY_ROWS.to_le_bytes());
+            winsize[2..4].copy_from_slice(&TTY_COLS.to_le_bytes());
+            memory.write(arg, &winsize)?;
+            Ok(0)
+        }
+        TIOCSWINSZ => Ok(0),
+        TIOCGPGRP => {
+            memory.write_u32(arg, env.threads.pid() as u32)?;
+            Ok(0)
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    #[test]
+    fn test_tty_detection() {
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+
+        let mut env = SyscallEnv::new(&Config::new());
+        assert_eq!(ioctl(&mut env, &mut memory, 1, TCGETS, 0x10000), Err(ENOTTY));
+
+        let mut env = SyscallEnv::new(&Config::new().tty(true));
+        assert_eq!(ioctl(&mut env, &mut memory, 1, TIOCGWINSZ, 0x10000), Ok(0));
+        assert_eq!(memory.read_u32(0x10000).unwrap(), (80 << 16) | 24);
+        assert_eq!(ioctl(&mut env, &mut memory, 0, TCGETS, 0x10000), Ok(0));
+        // a redirected stream is no terminal any more
+        let _stdout = env.capture(1).unwrap();
+        assert_eq!(ioctl(&mut env, &mut memory, 1, TCGETS, 0x10000), Err(ENOTTY));
+    }
+}
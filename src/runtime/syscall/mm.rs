@@ -0,0 +1,695 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+use super::fd::{FileKind, OpenFile};
+use super::fs::{O_ACCMODE, O_WRONLY};
+use super::{Errno, SyscallEnv, SyscallResult};
+use super::{EACCES, EEXIST, EFAULT, EINVAL, ENODEV, ENOMEM};
+use crate::frontend::page::Page;
+use crate::middleend::address_map::{AddressMap, GuestMemory, LinearMemory, MemoryError, Perm};
+
+pub const PROT_READ: u32 = 0x1;
+pub const PROT_WRITE: u32 = 0x2;
+pub const PROT_EXEC: u32 = 0x4;
+
+pub const MAP_SHARED: u32 = 0x01;
+pub const MAP_PRIVATE: u32 = 0x02;
+pub const MAP_FIXED: u32 = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x20;
+pub const MAP_FIXED_NOREPLACE: u32 = 0x100000;
+
+pub const MREMAP_MAYMOVE: u32 = 1;
+pub const MREMAP_FIXED: u32 = 2;
+pub const MREMAP_DONTUNMAP: u32 = 4;
+
+pub const MADV_NORMAL: u32 = 0;
+pub const MADV_RANDOM: u32 = 1;
+pub const MADV_SEQUENTIAL: u32 = 2;
+pub const MADV_WILLNEED: u32 = 3;
+pub const MADV_DONTNEED: u32 = 4;
+pub const MADV_FREE: u32 = 8;
+pub const MADV_REMOVE: u32 = 9;
+pub const MADV_DONTFORK: u32 = 10;
+pub const MADV_DOFORK: u32 = 11;
+pub const MADV_MERGEABLE: u32 = 12;
+pub const MADV_UNMERGEABLE: u32 = 13;
+pub const MADV_HUGEPAGE: u32 = 14;
+pub const MADV_NOHUGEPAGE: u32 = 15;
+pub const MADV_DONTDUMP: u32 = 16;
+pub const MADV_DODUMP: u32 = 17;
+
+const PAGE: u64 = Page::SIZE as u64;
+
+/// What a page of the area in use holds other than private anonymous
+/// memory, which `MADV_DONTNEED` zeroes
+#[derive(Debug, Clone)]
+enum Backing {
+    /// A shared mapping, whose page keeps its contents
+    Shared,
+    /// A private mapping of the file at `offset`, which the page is read
+    /// from again
+    File { file: Arc<Mutex<OpenFile>>, offset: u64 },
+}
+
+impl Backing {
+    /// The backing of the page `pages` pages further on, as a mapping
+    /// grows
+    fn advance(&self, pages: u64) -> Self {
+        match self {
+            Backing::Shared => Backing::Shared,
+            Backing::File { file, offset } => Backing::File {
+                file: file.clone(),
+                offset: offset + pages * PAGE,
+            },
+        }
+    }
+}
+
+/// Page allocator of the mmap area. The area is mapped into the address map
+/// up front, inaccessible, since the translation compiled into the guest
+/// module cannot learn about regions added later; mmap only hands out its
+/// pages and sets their permissions.
+#[derive(Debug, Clone, Default)]
+pub struct Mappings {
+    area: Range<u64>,
+    /// Whether each page of the area is in use
+    used: Vec<bool>,
+    /// The pages in use that are not private anonymous memory, by address
+    backing: BTreeMap<u64, Backing>,
+    /// Pages in use now and at most so far
+    in_use: usize,
+    peak: usize,
+    /// The heap, from the end of the image up to the current break
+    heap: Range<u64>,
+    /// End of the region reserved for the heap, which brk stays below
+    heap_limit: u64,
+    /// Bumped whenever permissions change, for the other threads of the
+    /// process to apply the change to their own address maps
+    generation: u64,
+}
+
+impl Mappings {
+    /// Reserve `len` bytes below `top` in `map` as the mmap area
+    pub fn reserve(map: &mut AddressMap, top: u64, len: u64) -> Result<Self, MemoryError> {
+        let area = (top - len) & !(PAGE - 1)..top & !(PAGE - 1);
+        map.map(area.clone(), Perm::NONE)?;
+        Ok(Self {
+            used: vec![false; ((area.end - area.start) / PAGE) as usize],
+            backing: BTreeMap::new(),
+            area,
+            in_use: 0,
+            peak: 0,
+            heap: 0..0,
+            heap_limit: 0,
+            generation: 0,
+        })
+    }
+
+    /// Reserve `len` bytes from `start` in `map` for brk to grow the heap
+    /// into, inaccessible like the mmap area
+    pub fn reserve_heap(&mut self, map: &mut AddressMap, start: u64, len: u64) -> Result<(), MemoryError> {
+        map.map(start..start + len, Perm::NONE)?;
+        self.heap = start..start;
+        self.heap_limit = start + len;
+        Ok(())
+    }
+
+    /// The current break
+    pub fn brk(&self) -> u64 {
+        self.heap.end
+    }
+
+    pub fn area(&self) -> Range<u64> {
+        self.area.clone()
+    }
+
+    fn pages(&self, vaddr: &Range<u64>) -> Range<usize> {
+        let start = vaddr.start.clamp(self.area.start, self.area.end);
+        let end = vaddr.end.clamp(self.area.start, self.area.end);
+        ((start - self.area.start) / PAGE) as usize..((end - self.area.start) / PAGE) as usize
+    }
+
+    /// Bytes of the area that were in use at the same time at most
+    pub fn peak(&self) -> u64 {
+        self.peak as u64 * PAGE
+    }
+
+    /// Mark the range in use as fresh private anonymous memory, or free
+    fn set_used(&mut self, vaddr: &Range<u64>, used: bool) {
+        self.generation += 1;
+        self.backing.retain(|page, _| !vaddr.contains(page));
+        let pages = self.pages(vaddr);
+        let changed = self.used[pages.clone()].iter().filter(|&&page| page != used).count();
+        self.used[pages].fill(used);
+        if used {
+            self.in_use += changed;
+            self.peak = self.peak.max(self.in_use);
+        } else {
+            self.in_use -= changed;
+        }
+    }
+
+    /// Back the pages of the range in use, the first one by `backing`
+    fn set_backing(&mut self, vaddr: &Range<u64>, backing: Backing) {
+        for (index, page) in (vaddr.start..vaddr.end).step_by(Page::SIZE).enumerate() {
+            self.backing.insert(page, backing.advance(index as u64));
+        }
+    }
+
+    /// Whether the range lies within the area
+    fn is_within(&self, vaddr: &Range<u64>) -> bool {
+        self.area.start <= vaddr.start && vaddr.end <= self.area.end
+    }
+
+    /// Whether the range lies within the area and none of it is in use
+    fn is_available(&self, vaddr: &Range<u64>) -> bool {
+        self.is_within(vaddr) && self.used[self.pages(vaddr)].iter().all(|used| !used)
+    }
+
+    /// Whether the range lies within the area and all of it is in use
+    fn is_used(&self, vaddr: &Range<u64>) -> bool {
+        self.is_within(vaddr) && self.used[self.pages(vaddr)].iter().all(|&used| used)
+    }
+
+    /// Highest free run of `len` bytes, handing out addresses top down
+    fn find_free(&self, len: u64) -> Option<u64> {
+        let pages = (len / PAGE) as usize;
+        let mut run = 0;
+        for index in (0..self.used.len()).rev() {
+            run = if self.used[index] { 0 } else { run + 1 };
+            if run == pages {
+                return Some(self.area.start + index as u64 * PAGE);
+            }
+        }
+        None
+    }
+
+    /// Whether every page of the range is mapped, inside the area it must be
+    /// in use and outside of it backed by a region of the address map
+    fn is_mapped(&self, map: &AddressMap, vaddr: &Range<u64>) -> bool {
+        (vaddr.start..vaddr.end).step_by(Page::SIZE).all(|page| {
+            if self.area.contains(&page) {
+                self.used[((page - self.area.start) / PAGE) as usize]
+            } else {
+                map.region(page).is_some()
+            }
+        })
+    }
+}
+
+/// Pick up the permission changes the other threads of the process made
+/// since the last call, telling whether there were any
+pub fn sync<M: LinearMemory + ?Sized>(env: &mut SyscallEnv, memory: &mut GuestMemory<M>) -> bool {
+    let generation = env.mm.lock().unwrap().generation;
+    if generation == env.mm_synced {
+        return false;
+    }
+    env.mm_synced = generation;
+    memory.read_perm_table().is_ok()
+}
+
+fn page_range(addr: u64, len: u64) -> Result<Range<u64>, Errno> {
+    if !addr.is_multiple_of(PAGE) || len == 0 {
+        return Err(EINVAL);
+    }
+    let end = addr.checked_add(len).ok_or(ENOMEM)?;
+    Ok(addr..(end + PAGE - 1) & !(PAGE - 1))
+}
+
+/// Round a mapping length up to whole pages
+fn page_len(len: u64) -> Result<u64, Errno> {
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    Ok(len.checked_add(PAGE - 1).ok_or(ENOMEM)? & !(PAGE - 1))
+}
+
+fn prot_perm(prot: u32) -> Result<Perm, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EINVAL);
+    }
+    Ok(Perm(prot as u8))
+}
+
+/// Fill `data` from the file at `offset`, giving the bytes read, fewer at
+/// its end
+fn read_file(file: &mut OpenFile, data: &mut [u8], offset: u64) -> Result<usize, Errno> {
+    match &mut file.kind {
+        FileKind::File(file) => {
+            let mut read = 0;
+            while read < data.len() {
+                match file.read_at(&mut data[read..], offset + read as u64)? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            Ok(read)
+        }
+        FileKind::Virtual(file) => Ok(file.read_at(data, offset as usize)),
+        _ => Err(ENODEV),
+    }
+}
+
+/// Copy `file` from `offset` into the freshly zeroed mapping
+fn map_file<M: LinearMemory + ?Sized>(
+    memory: &mut GuestMemory<M>,
+    file: &Mutex<OpenFile>,
+    vaddr: &Range<u64>,
+    offset: u64,
+    writable_shared: bool,
+) -> Result<(), Errno> {
+    let mut file = file.lock().unwrap();
+    if file.flags & O_ACCMODE == O_WRONLY {
+        return Err(EACCES);
+    }
+    // changes are never written back, so a writable shared mapping of a
+    // file would silently lose data
+    if writable_shared && matches!(file.kind, FileKind::File(_) | FileKind::Virtual(_)) {
+        return Err(ENODEV);
+    }
+    let mut data = vec![0; (vaddr.end - vaddr.start) as usize];
+    let read = read_file(&mut file, &mut data, offset)?;
+    memory.init(vaddr.start, &data[..read])?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn mmap<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    len: u64,
+    prot: u32,
+    flags: u32,
+    fd: i32,
+    offset: u64,
+) -> SyscallResult {
+    let perm = prot_perm(prot)?;
+    if len == 0 || !offset.is_multiple_of(PAGE) {
+        return Err(EINVAL);
+    }
+    match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED | MAP_PRIVATE => {}
+        _ => return Err(EINVAL),
+    }
+    let len = len.checked_add(PAGE - 1).ok_or(ENOMEM)? & !(PAGE - 1);
+    let hint = addr & !(PAGE - 1);
+    let mut mm = env.mm.lock().unwrap();
+    let start = if flags & MAP_FIXED_NOREPLACE != 0 {
+        if !mm.is_available(&page_range(addr, len)?) {
+            return Err(EEXIST);
+        }
+        addr
+    } else if flags & MAP_FIXED != 0 {
+        // fixed mappings may replace anything of the area, but not the image
+        // or the stack, and the threads of the process only share the
+        // permissions of the area
+        if !mm.is_within(&page_range(addr, len)?) {
+            return Err(ENOMEM);
+        }
+        addr
+    } else if hint != 0 && mm.is_available(&(hint..hint.saturating_add(len))) {
+        // like linux, a free hint is taken as is
+        hint
+    } else {
+        mm.find_free(len).ok_or(ENOMEM)?
+    };
+    let vaddr = start..start + len;
+
+    memory.init(vaddr.start, &vec![0; len as usize])?;
+    let mut backing = (flags & MAP_SHARED != 0).then_some(Backing::Shared);
+    if flags & MAP_ANONYMOUS == 0 {
+        let file = env.fds.lock().unwrap().file(fd)?;
+        let writable_shared = flags & MAP_SHARED != 0 && prot & PROT_WRITE != 0;
+        map_file(memory, &file, &vaddr, offset, writable_shared)?;
+        backing = backing.or(Some(Backing::File { file, offset }));
+    }
+    memory.protect(vaddr.clone(), perm)?;
+    mm.set_used(&vaddr, true);
+    if let Some(backing) = backing {
+        mm.set_backing(&vaddr, backing);
+    }
+    Ok(vaddr.start)
+}
+
+/// brk, moving the break within the reserved heap. An address outside of
+/// it, like the 0 libcs start with, leaves the break where it is, which is
+/// also what a brk that cannot be satisfied returns.
+pub fn brk<M: LinearMemory + ?Sized>(env: &mut SyscallEnv, memory: &mut GuestMemory<M>, addr: u64) -> SyscallResult {
+    let mut mm = env.mm.lock().unwrap();
+    if addr < mm.heap.start || addr > mm.heap_limit {
+        return Ok(mm.heap.end);
+    }
+    let old = mm.heap.end.next_multiple_of(PAGE);
+    let new = addr.next_multiple_of(PAGE);
+    if new > old {
+        memory.init(old, &vec![0; (new - old) as usize])?;
+        memory.protect(old..new, Perm::RW)?;
+    } else if new < old {
+        memory.protect(new..old, Perm::NONE)?;
+    }
+    if new != old {
+        mm.generation += 1;
+    }
+    mm.heap.end = addr;
+    Ok(addr)
+}
+
+pub fn munmap<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    len: u64,
+) -> SyscallResult {
+    let vaddr = page_range(addr, len)?;
+    // unmapping pages that are not mapped is fine, only those that are lose
+    // their permissions
+    let mut page = vaddr.start;
+    while page < vaddr.end {
+        if memory.map().region(page).is_some() {
+            memory.protect(page..page + PAGE, Perm::NONE)?;
+        }
+        page += PAGE;
+    }
+    env.mm.lock().unwrap().set_used(&vaddr, false);
+    Ok(0)
+}
+
+/// Hand pages of the area back to the allocator, inaccessible
+fn release<M: LinearMemory + ?Sized>(
+    memory: &mut GuestMemory<M>,
+    mm: &mut Mappings,
+    vaddr: Range<u64>,
+) -> Result<(), Errno> {
+    if !vaddr.is_empty() {
+        memory.protect(vaddr.clone(), Perm::NONE)?;
+        mm.set_used(&vaddr, false);
+    }
+    Ok(())
+}
+
+/// Resize or move a mapping of the mmap area. Growing stays in place when
+/// the pages behind the mapping are free, otherwise with `MREMAP_MAYMOVE`
+/// the pages are copied to a new place along with their permissions.
+pub fn mremap<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    old_addr: u64,
+    old_len: u64,
+    new_len: u64,
+    flags: u32,
+    new_addr: u64,
+) -> SyscallResult {
+    if flags & !(MREMAP_MAYMOVE | MREMAP_FIXED | MREMAP_DONTUNMAP) != 0
+        || (flags & (MREMAP_FIXED | MREMAP_DONTUNMAP) != 0 && flags & MREMAP_MAYMOVE == 0)
+    {
+        return Err(EINVAL);
+    }
+    // an old_len of 0 duplicates a shared mapping, which the area never has
+    let old = page_range(old_addr, old_len)?;
+    let old_len = old.end - old.start;
+    let new_len = page_len(new_len)?;
+    if flags & MREMAP_DONTUNMAP != 0 && new_len != old_len {
+        return Err(EINVAL);
+    }
+    let mut mm = env.mm.lock().unwrap();
+    if !mm.is_used(&old) {
+        return Err(EFAULT);
+    }
+    // the tail takes the permission of the last page, as the kernel extends
+    // the last vma
+    let tail_perm = memory.map().permission(old.end - PAGE).unwrap_or(Perm::NONE);
+    let tail_backing = mm.backing.get(&(old.end - PAGE)).cloned();
+
+    if flags & MREMAP_FIXED == 0 {
+        if new_len <= old_len {
+            release(memory, &mut mm, old.start + new_len..old.end)?;
+            return Ok(old.start);
+        }
+        let tail = old.end..old.start.checked_add(new_len).ok_or(ENOMEM)?;
+        if flags & MREMAP_DONTUNMAP == 0 && mm.is_available(&tail) {
+            memory.init(tail.start, &vec![0; (tail.end - tail.start) as usize])?;
+            memory.protect(tail.clone(), tail_perm)?;
+            mm.set_used(&tail, true);
+            if let Some(backing) = &tail_backing {
+                mm.set_backing(&tail, backing.advance(1));
+            }
+            return Ok(old.start);
+        }
+        if flags & MREMAP_MAYMOVE == 0 {
+            return Err(ENOMEM);
+        }
+    }
+
+    let start = if flags & MREMAP_FIXED != 0 {
+        let target = page_range(new_addr, new_len)?;
+        if target.start < old.end && old.start < target.end {
+            return Err(EINVAL);
+        }
+        // only the area can take moved pages
+        if !mm.is_within(&target) {
+            return Err(ENOMEM);
+        }
+        // whatever was mapped at the target goes, as with MAP_FIXED
+        release(memory, &mut mm, target.clone())?;
+        target.start
+    } else {
+        mm.find_free(new_len).ok_or(ENOMEM)?
+    };
+    // the moved pages keep what backs them
+    let moved: Vec<_> = (0..new_len)
+        .step_by(Page::SIZE)
+        .filter_map(|offset| match offset < old_len {
+            true => mm.backing.get(&(old.start + offset)).cloned(),
+            false => tail_backing.as_ref().map(|backing| backing.advance((offset - old_len) / PAGE + 1)),
+        }
+        .map(|backing| (start + offset, backing)))
+        .collect();
+    let mut page = vec![0; Page::SIZE];
+    for offset in (0..new_len).step_by(Page::SIZE) {
+        let perm = if offset < old_len {
+            memory.peek(old.start + offset, &mut page)?;
+            memory.map().permission(old.start + offset).unwrap_or(Perm::NONE)
+        } else {
+            page.fill(0);
+            tail_perm
+        };
+        memory.init(start + offset, &page)?;
+        memory.protect(start + offset..start + offset + PAGE, perm)?;
+    }
+    mm.set_used(&(start..start + new_len), true);
+    mm.backing.extend(moved);
+
+    if flags & MREMAP_DONTUNMAP != 0 {
+        // the old range stays mapped, and reads as fresh anonymous memory
+        memory.init(old.start, &vec![0; old_len as usize])?;
+        mm.set_used(&old, true);
+    } else {
+        release(memory, &mut mm, old)?;
+    }
+    Ok(start)
+}
+
+/// Advice on how memory is used. Only the advice that changes what the
+/// guest sees has an effect: `MADV_DONTNEED` and its kin zero the private
+/// anonymous pages of the mmap area and read private file pages from the
+/// file again, as linux does. Shared pages and those of the image and the
+/// stack keep their contents. The pages stay in linear memory, which cannot
+/// shrink.
+pub fn madvise<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    len: u64,
+    advice: u32,
+) -> SyscallResult {
+    if !addr.is_multiple_of(PAGE) {
+        return Err(EINVAL);
+    }
+    let discard = match advice {
+        MADV_DONTNEED | MADV_FREE | MADV_REMOVE => true,
+        MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL | MADV_WILLNEED | MADV_DONTFORK | MADV_DOFORK
+        | MADV_MERGEABLE | MADV_UNMERGEABLE | MADV_HUGEPAGE | MADV_NOHUGEPAGE | MADV_DONTDUMP
+        | MADV_DODUMP => false,
+        _ => return Err(EINVAL),
+    };
+    if len == 0 {
+        return Ok(0);
+    }
+    let vaddr = page_range(addr, len)?;
+    let mm = env.mm.lock().unwrap();
+    if !mm.is_mapped(memory.map(), &vaddr) {
+        return Err(ENOMEM);
+    }
+    if !discard {
+        return Ok(0);
+    }
+    let mut data = vec![0; Page::SIZE];
+    for page in (vaddr.start..vaddr.end).step_by(Page::SIZE).filter(|page| mm.area.contains(page)) {
+        data.fill(0);
+        match mm.backing.get(&page) {
+            Some(Backing::Shared) => continue,
+            Some(Backing::File { file, offset }) => {
+                read_file(&mut file.lock().unwrap(), &mut data, *offset)?;
+            }
+            None => {}
+        }
+        memory.init(page, &data)?;
+    }
+    Ok(0)
+}
+
+pub fn mprotect<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    len: u64,
+    prot: u32,
+) -> SyscallResult {
+    let perm = prot_perm(prot)?;
+    let vaddr = page_range(addr, len)?;
+    let mut mm = env.mm.lock().unwrap();
+    if !mm.is_mapped(memory.map(), &vaddr) {
+        return Err(ENOMEM);
+    }
+    memory.protect(vaddr, perm)?;
+    mm.generation += 1;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::testing::{call, TestMemory};
+    use crate::runtime::syscall::{fs, EBADF, SYS_MMAP, SYS_MPROTECT, SYS_MUNMAP};
+    use crate::runtime::syscall::{SYS_BRK, SYS_MADVISE, SYS_MREMAP};
+
+    #[test]
+    fn test_anonymous_mappings() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        *env.mm.lock().unwrap() = Mappings::reserve(&mut guest.map, 0x100000, 0x10000).unwrap();
+        let mut memory = guest.memory();
+        let anon = (MAP_PRIVATE | MAP_ANONYMOUS) as u64;
+        let rw = (PROT_READ | PROT_WRITE) as u64;
+
+        // top down allocation of fresh zeroed pages
+        let a = call(&mut env, &mut memory, SYS_MMAP, [0, 0x2000, rw, anon, u64::MAX, 0]) as u64;
+        assert_eq!(a, 0xfe000);
+        memory.write(a, &[0xaa; 16]).unwrap();
+        let b = call(&mut env, &mut memory, SYS_MMAP, [0, 100, rw, anon, u64::MAX, 0]) as u64;
+        assert_eq!(b, 0xfd000);
+
+        assert_eq!(call(&mut env, &mut memory, SYS_MPROTECT, [a, 0x1000, PROT_READ as u64, 0, 0, 0]), 0);
+        assert!(memory.write(a, &[1]).is_err());
+        assert_eq!(memory.map().permission(a + 0x1000), Some(Perm::RW));
+
+        // freed pages come back zeroed and fixed mappings land where asked
+        assert_eq!(call(&mut env, &mut memory, SYS_MUNMAP, [a, 0x2000, 0, 0, 0, 0]), 0);
+        assert!(memory.read_vec(a, 1).is_err());
+        assert_eq!(call(&mut env, &mut memory, SYS_MPROTECT, [a, 0x1000, rw, 0, 0, 0]), -ENOMEM.0 as i64);
+        let fixed = anon | MAP_FIXED as u64;
+        assert_eq!(call(&mut env, &mut memory, SYS_MMAP, [a, 0x1000, rw, fixed, u64::MAX, 0]) as u64, a);
+        assert_eq!(memory.read_vec(a, 16).unwrap(), [0; 16]);
+        assert_eq!(call(&mut env, &mut memory, SYS_MMAP, [0x1000, 0x1000, rw, fixed, u64::MAX, 0]), -ENOMEM.0 as i64);
+        // nor over mapped memory outside the area, or across its end
+        assert_eq!(call(&mut env, &mut memory, SYS_MMAP, [0x10000, 0x1000, rw, fixed, u64::MAX, 0]), -ENOMEM.0 as i64);
+        assert_eq!(memory.map().permission(0x10000), Some(Perm::RX));
+        assert_eq!(call(&mut env, &mut memory, SYS_MMAP, [0xff000, 0x2000, rw, fixed, u64::MAX, 0]), -ENOMEM.0 as i64);
+        assert_eq!(call(&mut env, &mut memory, SYS_MMAP, [0, 0x100000, rw, anon, u64::MAX, 0]), -ENOMEM.0 as i64);
+    }
+
+    #[test]
+    fn test_file_mapping() {
+        let mut env = SyscallEnv::new(&Config::new().file("/data/blob", vec![7; 0x1800]));
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        *env.mm.lock().unwrap() = Mappings::reserve(&mut guest.map, 0x100000, 0x10000).unwrap();
+        let mut memory = guest.memory();
+        memory.write(0x10000, b"/data/blob\0").unwrap();
+        let fd = fs::openat(&mut env, &mut memory, fs::AT_FDCWD, 0x10000, 0, 0).unwrap() as i32;
+
+        let addr = mmap(&mut env, &mut memory, 0, 0x2000, PROT_READ, MAP_PRIVATE, fd, 0x1000).unwrap();
+        assert_eq!(memory.read_vec(addr, 0x800).unwrap(), vec![7; 0x800]);
+        assert_eq!(memory.read_vec(addr + 0x800, 0x800).unwrap(), vec![0; 0x800]);
+        let writable = PROT_READ | PROT_WRITE;
+        assert_eq!(mmap(&mut env, &mut memory, 0, 0x1000, writable, MAP_SHARED, fd, 0), Err(ENODEV));
+        assert_eq!(mmap(&mut env, &mut memory, 0, 0x1000, PROT_READ, MAP_PRIVATE, 99, 0), Err(EBADF));
+
+        // dropping private file pages reads them from the file again, while
+        // shared pages keep what was written
+        let private = mmap(&mut env, &mut memory, 0, 0x2000, writable, MAP_PRIVATE, fd, 0x1000).unwrap();
+        memory.write(private, &[1; 0x1000]).unwrap();
+        memory.write(private + 0x1000, &[1; 0x1000]).unwrap();
+        let shared = mmap(&mut env, &mut memory, 0, 0x1000, writable, MAP_SHARED | MAP_ANONYMOUS, -1, 0).unwrap();
+        memory.write(shared, &[2; 16]).unwrap();
+        madvise(&mut env, &mut memory, private, 0x2000, MADV_DONTNEED).unwrap();
+        madvise(&mut env, &mut memory, shared, 0x1000, MADV_DONTNEED).unwrap();
+        assert_eq!(memory.read_vec(private, 0x1000).unwrap(), [vec![7; 0x800], vec![0; 0x800]].concat());
+        assert_eq!(memory.read_vec(private + 0x1000, 16).unwrap(), [0; 16]);
+        assert_eq!(memory.read_vec(shared, 16).unwrap(), [2; 16]);
+        // the file page the mapping held moves along with it
+        let moved = mremap(&mut env, &mut memory, private, 0x2000, 0x4000, MREMAP_MAYMOVE, 0).unwrap();
+        assert_ne!(moved, private);
+        memory.write(moved, &[1; 16]).unwrap();
+        madvise(&mut env, &mut memory, moved, 0x1000, MADV_DONTNEED).unwrap();
+        assert_eq!(memory.read_vec(moved, 16).unwrap(), [7; 16]);
+    }
+
+    #[test]
+    fn test_mremap_and_madvise() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut guest = TestMemory::new(&[]);
+        *env.mm.lock().unwrap() = Mappings::reserve(&mut guest.map, 0x100000, 0x10000).unwrap();
+        let mut memory = guest.memory();
+        let anon = (MAP_PRIVATE | MAP_ANONYMOUS) as u64;
+        let rw = (PROT_READ | PROT_WRITE) as u64;
+        let maymove = MREMAP_MAYMOVE as u64;
+
+        let a = call(&mut env, &mut memory, SYS_MMAP, [0, 0x1000, rw, anon, u64::MAX, 0]) as u64;
+        let b = call(&mut env, &mut memory, SYS_MMAP, [0, 0x1000, rw, anon, u64::MAX, 0]) as u64;
+        memory.write(b, b"data").unwrap();
+        // b is boxed in by a, so growing it needs a move that keeps the data
+        assert_eq!(call(&mut env, &mut memory, SYS_MREMAP, [b, 0x1000, 0x2000, 0, 0, 0]), -ENOMEM.0 as i64);
+        let moved = call(&mut env, &mut memory, SYS_MREMAP, [b, 0x1000, 0x3000, maymove, 0, 0]) as u64;
+        assert_ne!(moved, b);
+        assert_eq!(memory.read_vec(moved, 4).unwrap(), b"data");
+        assert_eq!(memory.map().permission(moved + 0x2000), Some(Perm::RW));
+        assert!(memory.read_vec(b, 1).is_err());
+
+        // shrinking and then growing again stays in place
+        assert_eq!(call(&mut env, &mut memory, SYS_MREMAP, [moved, 0x3000, 0x1000, 0, 0, 0]) as u64, moved);
+        assert!(memory.read_vec(moved + 0x1000, 1).is_err());
+        assert_eq!(call(&mut env, &mut memory, SYS_MREMAP, [moved, 0x1000, 0x2000, 0, 0, 0]) as u64, moved);
+        assert_eq!(call(&mut env, &mut memory, SYS_MREMAP, [b, 0x1000, 0x1000, 0, 0, 0]), -EFAULT.0 as i64);
+
+        memory.write(a, &[0xaa; 16]).unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_MADVISE, [a, 0x1000, MADV_WILLNEED as u64, 0, 0, 0]), 0);
+        assert_eq!(memory.read_vec(a, 16).unwrap(), [0xaa; 16]);
+        assert_eq!(call(&mut env, &mut memory, SYS_MADVISE, [a, 0x1000, MADV_DONTNEED as u64, 0, 0, 0]), 0);
+        assert_eq!(memory.read_vec(a, 16).unwrap(), [0; 16]);
+        assert_eq!(call(&mut env, &mut memory, SYS_MADVISE, [b, 0x1000, MADV_DONTNEED as u64, 0, 0, 0]), -ENOMEM.0 as i64);
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut mm = Mappings::reserve(&mut guest.map, 0x100000, 0x10000).unwrap();
+        mm.reserve_heap(&mut guest.map, 0x20000, 0x4000).unwrap();
+        *env.mm.lock().unwrap() = mm;
+        let mut memory = guest.memory();
+
+        assert_eq!(call(&mut env, &mut memory, SYS_BRK, [0; 6]), 0x20000);
+        assert_eq!(call(&mut env, &mut memory, SYS_BRK, [0x21800, 0, 0, 0, 0, 0]), 0x21800);
+        memory.write(0x21ff8, &[1; 8]).unwrap();
+        // growing past the reservation fails, leaving the break as it was
+        assert_eq!(call(&mut env, &mut memory, SYS_BRK, [0x30000, 0, 0, 0, 0, 0]), 0x21800);
+        assert_eq!(call(&mut env, &mut memory, SYS_BRK, [0x20800, 0, 0, 0, 0, 0]), 0x20800);
+        assert!(memory.write(0x21000, &[1]).is_err());
+        assert_eq!(call(&mut env, &mut memory, SYS_BRK, [0x22000, 0, 0, 0, 0, 0]), 0x22000);
+        assert_eq!(memory.read_vec(0x21ff8, 8).unwrap(), [0; 8]);
+    }
+}
@@ -0,0 +1,783 @@
+pub mod exec;
+pub mod fd;
+pub mod fs;
+pub mod ioctl;
+pub mod mm;
+pub mod net;
+pub mod pipe;
+pub mod policy;
+pub mod signal;
+pub mod stat;
+pub mod stats;
+pub mod sync;
+pub mod sys;
+#[cfg(test)]
+pub mod testing;
+pub mod thread;
+pub mod time;
+pub mod vfs;
+
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::frontend::cache::CodeCache;
+use crate::middleend::address_map::{GuestMemory, LinearMemory, MemoryError};
+use crate::runtime::clock::Clock;
+use crate::runtime::config::Config;
+use crate::runtime::execution::GuestExit;
+use crate::runtime::limits::Limits;
+use crate::runtime::regs::{Registers, A0, A7, SP};
+use crate::runtime::replay::{self, Replay};
+use crate::runtime::rng::Entropy;
+use crate::tools::backtrace::Unwinder;
+use crate::tools::perf::{Profiler, RUNTIME_SYSCALL};
+use crate::tools::trace::{TraceEvent, Tracer};
+use fd::{Fd, FdTable, FileKind, Input, OpenFile};
+use fs::{O_RDONLY, O_WRONLY};
+use mm::Mappings;
+use net::NetPolicy;
+use pipe::{PipeReader, PipeWriter};
+use policy::SyscallPolicy;
+use sys::Rlimits;
+use signal::{Delivery, SignalTable, ThreadSignals};
+use stats::SyscallStats;
+use thread::Threads;
+use time::Timers;
+use vfs::Vfs;
+
+pub const SYS_DUP: u64 = 23;
+pub const SYS_DUP3: u64 = 24;
+pub const SYS_FCNTL: u64 = 25;
+pub const SYS_IOCTL: u64 = 29;
+pub const SYS_UNLINKAT: u64 = 35;
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_PIPE2: u64 = 59;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_READV: u64 = 65;
+pub const SYS_WRITEV: u64 = 66;
+pub const SYS_PREAD64: u64 = 67;
+pub const SYS_PWRITE64: u64 = 68;
+pub const SYS_PREADV: u64 = 69;
+pub const SYS_PWRITEV: u64 = 70;
+pub const SYS_NEWFSTATAT: u64 = 79;
+pub const SYS_FSTAT: u64 = 80;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_SET_TID_ADDRESS: u64 = 96;
+pub const SYS_FUTEX: u64 = 98;
+pub const SYS_NANOSLEEP: u64 = 101;
+pub const SYS_GETITIMER: u64 = 102;
+pub const SYS_SETITIMER: u64 = 103;
+pub const SYS_TIMER_CREATE: u64 = 107;
+pub const SYS_TIMER_GETTIME: u64 = 108;
+pub const SYS_TIMER_GETOVERRUN: u64 = 109;
+pub const SYS_TIMER_SETTIME: u64 = 110;
+pub const SYS_TIMER_DELETE: u64 = 111;
+pub const SYS_CLOCK_GETTIME: u64 = 113;
+pub const SYS_CLOCK_GETRES: u64 = 114;
+pub const SYS_CLOCK_NANOSLEEP: u64 = 115;
+pub const SYS_SCHED_YIELD: u64 = 124;
+pub const SYS_KILL: u64 = 129;
+pub const SYS_TKILL: u64 = 130;
+pub const SYS_TGKILL: u64 = 131;
+pub const SYS_SIGALTSTACK: u64 = 132;
+pub const SYS_RT_SIGACTION: u64 = 134;
+pub const SYS_RT_SIGPROCMASK: u64 = 135;
+pub const SYS_RT_SIGPENDING: u64 = 136;
+pub const SYS_RT_SIGRETURN: u64 = 139;
+pub const SYS_GETRLIMIT: u64 = 163;
+pub const SYS_SETRLIMIT: u64 = 164;
+pub const SYS_GETRUSAGE: u64 = 165;
+pub const SYS_GETTIMEOFDAY: u64 = 169;
+pub const SYS_GETPID: u64 = 172;
+pub const SYS_GETTID: u64 = 178;
+pub const SYS_SOCKET: u64 = 198;
+pub const SYS_BIND: u64 = 200;
+pub const SYS_LISTEN: u64 = 201;
+pub const SYS_ACCEPT: u64 = 202;
+pub const SYS_CONNECT: u64 = 203;
+pub const SYS_GETSOCKNAME: u64 = 204;
+pub const SYS_GETPEERNAME: u64 = 205;
+pub const SYS_SENDTO: u64 = 206;
+pub const SYS_RECVFROM: u64 = 207;
+pub const SYS_SETSOCKOPT: u64 = 208;
+pub const SYS_SHUTDOWN: u64 = 210;
+pub const SYS_BRK: u64 = 214;
+pub const SYS_MUNMAP: u64 = 215;
+pub const SYS_MREMAP: u64 = 216;
+pub const SYS_CLONE: u64 = 220;
+pub const SYS_EXECVE: u64 = 221;
+pub const SYS_MMAP: u64 = 222;
+pub const SYS_MPROTECT: u64 = 226;
+pub const SYS_MADVISE: u64 = 233;
+pub const SYS_ACCEPT4: u64 = 242;
+pub const SYS_RISCV_FLUSH_ICACHE: u64 = 259;
+pub const SYS_WAIT4: u64 = 260;
+pub const SYS_PRLIMIT64: u64 = 261;
+pub const SYS_GETRANDOM: u64 = 278;
+pub const SYS_MEMBARRIER: u64 = 283;
+pub const SYS_STATX: u64 = 291;
+
+/// The name of syscall `nr` as strace and `qemu -strace` print it, for the
+/// syscalls the VM implements
+pub fn syscall_name(nr: u64) -> Option<&'static str> {
+    let name = match nr {
+        SYS_DUP => "dup",
+        SYS_DUP3 => "dup3",
+        SYS_FCNTL => "fcntl",
+        SYS_IOCTL => "ioctl",
+        SYS_UNLINKAT => "unlinkat",
+        SYS_OPENAT => "openat",
+        SYS_CLOSE => "close",
+        SYS_PIPE2 => "pipe2",
+        SYS_READ => "read",
+        SYS_WRITE => "write",
+        SYS_READV => "readv",
+        SYS_WRITEV => "writev",
+        SYS_PREAD64 => "pread64",
+        SYS_PWRITE64 => "pwrite64",
+        SYS_PREADV => "preadv",
+        SYS_PWRITEV => "pwritev",
+        SYS_NEWFSTATAT => "newfstatat",
+        SYS_FSTAT => "fstat",
+        SYS_EXIT => "exit",
+        SYS_EXIT_GROUP => "exit_group",
+        SYS_SET_TID_ADDRESS => "set_tid_address",
+        SYS_FUTEX => "futex",
+        SYS_NANOSLEEP => "nanosleep",
+        SYS_GETITIMER => "getitimer",
+        SYS_SETITIMER => "setitimer",
+        SYS_TIMER_CREATE => "timer_create",
+        SYS_TIMER_GETTIME => "timer_gettime",
+        SYS_TIMER_GETOVERRUN => "timer_getoverrun",
+        SYS_TIMER_SETTIME => "timer_settime",
+        SYS_TIMER_DELETE => "timer_delete",
+        SYS_CLOCK_GETTIME => "clock_gettime",
+        SYS_CLOCK_GETRES => "clock_getres",
+        SYS_CLOCK_NANOSLEEP => "clock_nanosleep",
+        SYS_SCHED_YIELD => "sched_yield",
+        SYS_KILL => "kill",
+        SYS_TKILL => "tkill",
+        SYS_TGKILL => "tgkill",
+        SYS_SIGALTSTACK => "sigaltstack",
+        SYS_RT_SIGACTION => "rt_sigaction",
+        SYS_RT_SIGPROCMASK => "rt_sigprocmask",
+        SYS_RT_SIGPENDING => "rt_sigpending",
+        SYS_RT_SIGRETURN => "rt_sigreturn",
+        SYS_GETRLIMIT => "getrlimit",
+        SYS_SETRLIMIT => "setrlimit",
+        SYS_GETRUSAGE => "getrusage",
+        SYS_GETTIMEOFDAY => "gettimeofday",
+        SYS_GETPID => "getpid",
+        SYS_GETTID => "gettid",
+        SYS_SOCKET => "socket",
+        SYS_BIND => "bind",
+        SYS_LISTEN => "listen",
+        SYS_ACCEPT => "accept",
+        SYS_CONNECT => "connect",
+        SYS_GETSOCKNAME => "getsockname",
+        SYS_GETPEERNAME => "getpeername",
+        SYS_SENDTO => "sendto",
+        SYS_RECVFROM => "recvfrom",
+        SYS_SETSOCKOPT => "setsockopt",
+        SYS_SHUTDOWN => "shutdown",
+        SYS_BRK => "brk",
+        SYS_MUNMAP => "munmap",
+        SYS_MREMAP => "mremap",
+        SYS_CLONE => "clone",
+        SYS_EXECVE => "execve",
+        SYS_MMAP => "mmap",
+        SYS_MPROTECT => "mprotect",
+        SYS_MADVISE => "madvise",
+        SYS_ACCEPT4 => "accept4",
+        SYS_RISCV_FLUSH_ICACHE => "riscv_flush_icache",
+        SYS_WAIT4 => "wait4",
+        SYS_PRLIMIT64 => "prlimit64",
+        SYS_GETRANDOM => "getrandom",
+        SYS_MEMBARRIER => "membarrier",
+        SYS_STATX => "statx",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// A linux errno value
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+pub const EPERM: Errno = Errno(1);
+pub const ENOENT: Errno = Errno(2);
+pub const ESRCH: Errno = Errno(3);
+pub const EIO: Errno = Errno(5);
+pub const E2BIG: Errno = Errno(7);
+pub const ENOEXEC: Errno = Errno(8);
+pub const EBADF: Errno = Errno(9);
+pub const ECHILD: Errno = Errno(10);
+pub const EAGAIN: Errno = Errno(11);
+pub const EACCES: Errno = Errno(13);
+pub const ENOMEM: Errno = Errno(12);
+pub const EFAULT: Errno = Errno(14);
+pub const EEXIST: Errno = Errno(17);
+pub const ENODEV: Errno = Errno(19);
+pub const ENOTDIR: Errno = Errno(20);
+pub const EISDIR: Errno = Errno(21);
+pub const EINVAL: Errno = Errno(22);
+pub const EMFILE: Errno = Errno(24);
+pub const ENOTTY: Errno = Errno(25);
+pub const ESPIPE: Errno = Errno(29);
+pub const EPIPE: Errno = Errno(32);
+pub const ENAMETOOLONG: Errno = Errno(36);
+pub const ENOSYS: Errno = Errno(38);
+pub const ENOTEMPTY: Errno = Errno(39);
+pub const ENOTSOCK: Errno = Errno(88);
+pub const EDESTADDRREQ: Errno = Errno(89);
+pub const EPROTONOSUPPORT: Errno = Errno(93);
+pub const EOPNOTSUPP: Errno = Errno(95);
+pub const EAFNOSUPPORT: Errno = Errno(97);
+pub const EISCONN: Errno = Errno(106);
+pub const ENOTCONN: Errno = Errno(107);
+pub const ETIMEDOUT: Errno = Errno(110);
+
+impl From<io::Error> for Errno {
+    fn from(e: io::Error) -> Self {
+        // the host is linux as well, so its errno values carry over as is
+        if let Some(errno) = e.raw_os_error() {
+            return Errno(errno);
+        }
+        match e.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            io::ErrorKind::PermissionDenied => EACCES,
+            io::ErrorKind::AlreadyExists => EEXIST,
+            io::ErrorKind::InvalidInput => EINVAL,
+            io::ErrorKind::WouldBlock => EAGAIN,
+            _ => EIO,
+        }
+    }
+}
+
+impl From<MemoryError> for Errno {
+    fn from(_: MemoryError) -> Self {
+        EFAULT
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Host directory made visible to the guest under `guest`
+#[derive(Debug, Clone)]
+pub struct Preopen {
+    pub guest: PathBuf,
+    pub host: PathBuf,
+}
+
+/// Kernel side state of one guest thread. What the threads of a process
+/// share is behind an `Arc`, so each host thread running a guest thread owns
+/// its env and can block in a syscall without holding up the others.
+#[derive(Debug)]
+pub struct SyscallEnv {
+    pub fds: Arc<Mutex<FdTable>>,
+    pub preopens: Vec<Preopen>,
+    /// In-memory filesystem, consulted before the preopens
+    pub vfs: Arc<Mutex<Vfs>>,
+    /// Allowlist of host addresses sockets may bind or connect to
+    pub net: NetPolicy,
+    /// Syscalls the guest may make
+    pub policy: Arc<SyscallPolicy>,
+    /// Whether the host's standard streams look like a terminal
+    pub tty: bool,
+    /// Pages of the mmap area in use, empty until the loader reserves it
+    pub mm: Arc<Mutex<Mappings>>,
+    /// The generation of `mm` whose permissions the address map of the
+    /// thread has
+    pub mm_synced: u64,
+    /// Instructions decoded from guest memory, which the guest invalidates
+    /// by flushing its icache
+    pub code: Arc<Mutex<CodeCache>>,
+    /// Current working directory as the guest sees it
+    pub cwd: PathBuf,
+    pub threads: Arc<Threads>,
+    pub tid: i32,
+    /// Futex word cleared when the thread exits, from set_tid_address
+    pub clear_child_tid: u64,
+    /// Signal dispositions and process directed pending signals
+    pub signals: Arc<SignalTable>,
+    /// Signal mask, thread directed pending signals and alternate stack
+    pub sigstate: ThreadSignals,
+    /// Randomness for getrandom, shared with the VFS's /dev/urandom
+    pub entropy: Entropy,
+    /// Guest time, shared with the counter CSRs
+    pub clock: Arc<Clock>,
+    /// Interval and POSIX timers of the process
+    pub timers: Arc<Timers>,
+    /// The limits of the run, whose wall clock started with the first
+    /// process
+    pub limits: Limits,
+    /// Resource limits, from setrlimit and prlimit64
+    pub rlimits: Arc<Mutex<Rlimits>>,
+    /// The numbers of the syscalls the process made, in order, when
+    /// `Config::record_syscalls` asks for them
+    pub syscall_log: Option<Arc<Mutex<Vec<u64>>>>,
+    /// Calls and time per syscall of the process, when
+    /// `Config::syscall_stats` asks for them
+    pub syscall_stats: Option<Arc<Mutex<SyscallStats>>>,
+    /// Where the results of syscalls are recorded or replayed from
+    pub replay: Option<Arc<Replay>>,
+    /// Unwinds the guest stack for `log_backtrace`, from
+    /// `Config::backtrace`
+    pub unwinder: Option<Arc<Unwinder>>,
+    /// Where the time of the syscalls and of running the guest goes, from
+    /// `Config::profiler`
+    pub profiler: Option<Profiler>,
+    /// Where the events of the guest are traced to, from `Config::trace`
+    pub tracer: Option<Tracer>,
+    /// Trace every syscall with its arguments and result, from
+    /// `Config::instrumented`
+    pub debug: bool,
+}
+
+impl SyscallEnv {
+    pub fn new(config: &Config) -> Self {
+        let entropy = Entropy::new(config.seed);
+        let clock = match &config.replay {
+            Some(replay) => Clock::new(config.clock).replay(replay.clone()),
+            None => Clock::new(config.clock),
+        };
+        let mut vfs = Vfs::new();
+        vfs.set_entropy(entropy.clone());
+        vfs.set_time(clock.realtime());
+        for (path, data) in &config.files {
+            vfs.insert_file(path, data.clone());
+        }
+        // the guest is alone in its pid namespace
+        let threads = Threads::new(1);
+        Self {
+            fds: Arc::new(Mutex::new(FdTable::new())),
+            preopens: config.preopens.clone(),
+            vfs: Arc::new(Mutex::new(vfs)),
+            net: config.net.clone(),
+            policy: Arc::new(config.syscalls.clone()),
+            tty: config.tty,
+            mm: Arc::new(Mutex::new(Mappings::default())),
+            mm_synced: 0,
+            code: Arc::new(Mutex::new(CodeCache::new())),
+            cwd: PathBuf::from("/"),
+            tid: threads.pid(),
+            threads: Arc::new(threads),
+            clear_child_tid: 0,
+            signals: Arc::new(SignalTable::new()),
+            sigstate: ThreadSignals::default(),
+            entropy,
+            clock: Arc::new(clock),
+            timers: Arc::new(Timers::new()),
+            limits: Limits::new(config),
+            rlimits: Arc::new(Mutex::new(Rlimits::new())),
+            syscall_log: config.record_syscalls.then(Default::default),
+            syscall_stats: config.syscall_stats.then(Default::default),
+            replay: config.replay.clone(),
+            unwinder: config.backtrace.clone(),
+            profiler: config.profiler.clone(),
+            tracer: config.trace.clone(),
+            debug: config.instrumented(),
+        }
+    }
+
+    /// Env of a new thread `tid` in the same process
+    pub fn thread(&self, tid: i32) -> Self {
+        Self {
+            fds: self.fds.clone(),
+            preopens: self.preopens.clone(),
+            vfs: self.vfs.clone(),
+            net: self.net.clone(),
+            policy: self.policy.clone(),
+            tty: self.tty,
+            mm: self.mm.clone(),
+            // the thread starts from a copy of the caller's map
+            mm_synced: self.mm_synced,
+            code: self.code.clone(),
+            cwd: self.cwd.clone(),
+            threads: self.threads.clone(),
+            tid,
+            clear_child_tid: 0,
+            signals: self.signals.clone(),
+            // a new thread inherits the mask, nothing else
+            sigstate: ThreadSignals {
+                mask: self.sigstate.mask,
+                ..ThreadSignals::default()
+            },
+            entropy: self.entropy.clone(),
+            clock: self.clock.clone(),
+            timers: self.timers.clone(),
+            limits: self.limits.clone(),
+            rlimits: self.rlimits.clone(),
+            syscall_log: self.syscall_log.clone(),
+            syscall_stats: self.syscall_stats.clone(),
+            replay: self.replay.clone(),
+            unwinder: self.unwinder.clone(),
+            profiler: self.profiler.clone(),
+            tracer: self.tracer.clone(),
+            debug: self.debug,
+        }
+    }
+
+    /// Env of the first thread of a child process forked from this thread,
+    /// with copies of the descriptor table, mappings and signal dispositions
+    pub fn fork(&self, threads: Threads) -> Self {
+        Self {
+            fds: Arc::new(Mutex::new(self.fds.lock().unwrap().clone())),
+            preopens: self.preopens.clone(),
+            vfs: self.vfs.clone(),
+            net: self.net.clone(),
+            policy: self.policy.clone(),
+            tty: self.tty,
+            mm: Arc::new(Mutex::new(self.mm.lock().unwrap().clone())),
+            mm_synced: self.mm_synced,
+            code: Arc::new(Mutex::new(CodeCache::new())),
+            cwd: self.cwd.clone(),
+            tid: threads.pid(),
+            threads: Arc::new(threads),
+            clear_child_tid: 0,
+            signals: Arc::new(self.signals.fork()),
+            // the mask and alternate stack carry over, pending signals do not
+            sigstate: ThreadSignals {
+                pending: 0,
+                ..self.sigstate.clone()
+            },
+            entropy: self.entropy.clone(),
+            clock: self.clock.clone(),
+            timers: Arc::new(Timers::new()),
+            limits: self.limits.clone(),
+            rlimits: Arc::new(Mutex::new(self.rlimits.lock().unwrap().clone())),
+            // the child's syscalls go into the same log, as with strace -f
+            syscall_log: self.syscall_log.clone(),
+            syscall_stats: self.syscall_stats.clone(),
+            replay: self.replay.clone(),
+            unwinder: self.unwinder.clone(),
+            profiler: self.profiler.clone(),
+            tracer: self.tracer.clone(),
+            debug: self.debug,
+        }
+    }
+
+    /// Feed the guest's stdin from `reader` instead of the host stdin
+    pub fn stdin(self, reader: impl Read + Send + 'static) -> Self {
+        let file = self.fds.lock().unwrap().file(0).expect("stdin is open on a fresh table");
+        file.lock().unwrap().kind = FileKind::Stdin(Input::Reader(Box::new(reader)));
+        self
+    }
+
+    /// Put the write end of a new pipe on the guest's descriptor `fd` and
+    /// return the read end, through which the host sees what the guest writes
+    pub fn capture(&self, fd: i32) -> Result<PipeReader, Errno> {
+        let (reader, writer) = pipe::pipe();
+        self.set_fd(fd, pipe::open_file(FileKind::PipeWrite(writer), O_WRONLY))?;
+        Ok(reader)
+    }
+
+    /// Put the read end of a new pipe on the guest's descriptor `fd` and
+    /// return the write end, through which the host feeds the guest
+    pub fn feed(&self, fd: i32) -> Result<PipeWriter, Errno> {
+        let (reader, writer) = pipe::pipe();
+        self.set_fd(fd, pipe::open_file(FileKind::PipeRead(reader), O_RDONLY))?;
+        Ok(writer)
+    }
+
+    fn set_fd(&self, fd: i32, file: OpenFile) -> Result<(), Errno> {
+        let file = Arc::new(Mutex::new(file));
+        let replaced = self.fds.lock().unwrap().set(fd, Fd { file, cloexec: false })?;
+        drop(replaced);
+        Ok(())
+    }
+}
+
+/// Handle an `ecall` of the guest thread with the registers `regs`, setting
+/// `a0` to the result. This covers the syscalls that need the whole register
+/// file or end the thread, and defers the others to `syscall_handler`.
+/// A pending signal is delivered as the syscall returns. An exit, or the end
+/// of the process by a policy kill, a signal or another thread, comes back
+/// as the error, for the engine to stop the thread.
+pub fn ecall<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+) -> Result<(), GuestExit> {
+    let nr = regs.x[A7];
+    let args: [u64; 6] = regs.x[A0..A0 + 6].try_into().unwrap();
+    mm::sync(env, memory);
+    // the pc already moved past the ecall
+    let start = log_syscall(env, Some(regs.pc.wrapping_sub(4)), nr, args);
+    let _span = env.debug.then(|| tracing::trace_span!(target: "doublejit::syscall", "ecall", tid = env.tid, nr).entered());
+    let result = match policy::filter(env, nr) {
+        Err(e) => Err(e),
+        Ok(()) => match nr {
+            SYS_EXIT => return Err(thread::exit(env, memory, args[0] as i32)),
+            SYS_EXIT_GROUP => return Err(thread::exit_group(env, args[0] as i32)),
+            SYS_RT_SIGRETURN => match signal::sigreturn(env, memory, regs) {
+                // every register including a0 comes back from the frame
+                Ok(()) => return syscall_return(env, memory, regs),
+                Err(e) => Err(e),
+            },
+            SYS_SIGALTSTACK => signal::sigaltstack(env, memory, args[0], args[1], regs.x[SP]),
+            // the child starts from the registers of the caller
+            SYS_CLONE => thread::clone(env, memory, regs, args[0], args[1], args[2], args[3], args[4]),
+            SYS_EXECVE => match exec::execve(env, memory, args[0], args[1], args[2]) {
+                Ok(image) => return Err(GuestExit::Exec(Box::new(image))),
+                Err(e) => Err(e),
+            },
+            _ => dispatch_replayed(env, memory, nr, args),
+        },
+    };
+    if env.debug {
+        tracing::trace!(target: "doublejit::syscall", ?args, ?result);
+    }
+    syscall_returned(env, nr, start, &result);
+    if env.unwinder.is_some() && syscall_name(nr).is_none() {
+        // the frame of the ecall, pc already moved past it
+        let ecall = Registers { pc: regs.pc - 4, ..regs.clone() };
+        log_backtrace(env, memory, &ecall, &format!("unknown syscall {}", nr));
+    }
+    regs.x[A0] = errno_to_a0(result);
+    syscall_return(env, memory, regs)
+}
+
+/// Return to the guest from a syscall, into the handler of a signal that
+/// became deliverable meanwhile
+fn syscall_return<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+) -> Result<(), GuestExit> {
+    if let Some(result) = env.threads.exit_status() {
+        return Err(GuestExit::Process(result));
+    }
+    match signal::deliver(env, memory, regs) {
+        Delivery::Terminate(sig) => Err(signal::terminate(env, sig)),
+        Delivery::None | Delivery::Handler(_) => Ok(()),
+    }
+}
+
+/// Handle the `ecall` with number `nr` if the syscall policy allows it,
+/// returning the value for `a0`, which is `-errno` on failure as the linux
+/// ABI has it
+pub fn syscall_handler<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    nr: u64,
+    args: [u64; 6],
+) -> u64 {
+    let _span = env.debug.then(|| tracing::trace_span!(target: "doublejit::syscall", "syscall", tid = env.tid, nr).entered());
+    let start = log_syscall(env, None, nr, args);
+    let result = policy::filter(env, nr).and_then(|()| dispatch_replayed(env, memory, nr, args));
+    if env.debug {
+        tracing::trace!(target: "doublejit::syscall", ?args, ?result);
+    }
+    syscall_returned(env, nr, start, &result);
+    errno_to_a0(result)
+}
+
+/// Log `reason` with the backtrace of the guest at `regs`, when
+/// `Config::backtrace` asks for them
+pub fn log_backtrace<M: LinearMemory + ?Sized>(env: &SyscallEnv, memory: &GuestMemory<M>, regs: &Registers, reason: &str) {
+    if let Some(unwinder) = &env.unwinder {
+        let backtrace = unwinder.report(memory, regs);
+        tracing::error!(target: "doublejit::backtrace", "{} in thread {}\n{}", reason, env.tid, backtrace.trim_end());
+    }
+}
+
+/// Log the syscall `nr` the guest makes at `pc`, returning when it started
+/// if the time it takes is wanted
+fn log_syscall(env: &SyscallEnv, pc: Option<u64>, nr: u64, args: [u64; 6]) -> Option<Instant> {
+    if let Some(tracer) = &env.tracer {
+        tracer.record(env.tid, TraceEvent::Syscall { pc, nr, args });
+    }
+    if let Some(log) = &env.syscall_log {
+        log.lock().unwrap().push(nr);
+    }
+    if let Some(stats) = &env.syscall_stats {
+        stats.lock().unwrap().call(nr);
+    }
+    (env.syscall_stats.is_some() || env.profiler.is_some()).then(Instant::now)
+}
+
+fn syscall_returned(env: &SyscallEnv, nr: u64, start: Option<Instant>, result: &Result<u64, Errno>) {
+    if let Some(tracer) = &env.tracer {
+        tracer.record(env.tid, TraceEvent::Sysret { nr, ret: errno_to_a0(*result) });
+    }
+    let Some(start) = start else { return };
+    let elapsed = start.elapsed();
+    if let Some(stats) = &env.syscall_stats {
+        stats.lock().unwrap().returned(nr, elapsed, result.is_err());
+    }
+    if let Some(profiler) = &env.profiler {
+        let name = stats::timer_name(nr);
+        profiler.record(RUNTIME_SYSCALL, elapsed);
+        profiler.record(&name, elapsed);
+        if result.is_err() {
+            profiler.count(&format!("{}.errors", name), 1);
+        }
+    }
+}
+
+fn errno_to_a0(result: Result<u64, Errno>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(Errno(errno)) => (-(errno as i64)) as u64,
+    }
+}
+
+/// `a0` back as the result it stands for
+fn a0_to_result(a0: u64) -> Result<u64, Errno> {
+    match a0 as i64 {
+        -4095..=-1 => Err(Errno(-(a0 as i64) as i32)),
+        _ => Ok(a0),
+    }
+}
+
+/// `dispatch`, recording the result when recording, and when replaying
+/// taking the result of an input from the recording or checking the result
+/// of the syscall against it
+fn dispatch_replayed<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    nr: u64,
+    args: [u64; 6],
+) -> Result<u64, Errno> {
+    let Some(replay) = env.replay.clone() else {
+        return dispatch(env, memory, nr, args);
+    };
+    if replay.is_recording() {
+        let (result, writes) = match replay::is_input(nr) {
+            true => replay.input(|| memory.journaled(|memory| dispatch(env, memory, nr, args))),
+            false => (dispatch(env, memory, nr, args), Vec::new()),
+        };
+        replay.record_syscall(nr, errno_to_a0(result), writes);
+        return result;
+    }
+    match replay.replay_syscall(nr) {
+        Some((result, writes)) if replay::is_input(nr) => {
+            memory.replay_writes(&writes)?;
+            a0_to_result(result)
+        }
+        Some((recorded, _)) => {
+            let result = dispatch(env, memory, nr, args);
+            replay.check_syscall(nr, recorded, errno_to_a0(result));
+            result
+        }
+        None => dispatch(env, memory, nr, args),
+    }
+}
+
+fn dispatch<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    nr: u64,
+    args: [u64; 6],
+) -> Result<u64, Errno> {
+    match nr {
+        SYS_OPENAT => fs::openat(env, memory, args[0] as i32, args[1], args[2] as u32, args[3] as u32),
+        SYS_CLOSE => fs::close(env, args[0] as i32),
+        SYS_DUP => fs::dup(env, args[0] as i32),
+        SYS_DUP3 => fs::dup3(env, args[0] as i32, args[1] as i32, args[2] as u32),
+        SYS_FCNTL => fs::fcntl(env, args[0] as i32, args[1] as u32, args[2]),
+        SYS_IOCTL => ioctl::ioctl(env, memory, args[0] as i32, args[1] as u32, args[2]),
+        SYS_PIPE2 => pipe::pipe2(env, memory, args[0], args[1] as u32),
+        SYS_READ => fs::read(env, memory, args[0] as i32, args[1], args[2]),
+        SYS_WRITE => fs::write(env, memory, args[0] as i32, args[1], args[2]),
+        SYS_READV => fs::readv(env, memory, args[0] as i32, args[1], args[2], None),
+        SYS_WRITEV => fs::writev(env, memory, args[0] as i32, args[1], args[2], None),
+        SYS_PREAD64 => fs::pread64(env, memory, args[0] as i32, args[1], args[2], args[3] as i64),
+        SYS_PWRITE64 => fs::pwrite64(env, memory, args[0] as i32, args[1], args[2], args[3] as i64),
+        SYS_PREADV => fs::readv(env, memory, args[0] as i32, args[1], args[2], Some(args[3] as i64)),
+        SYS_PWRITEV => fs::writev(env, memory, args[0] as i32, args[1], args[2], Some(args[3] as i64)),
+        SYS_UNLINKAT => fs::unlinkat(env, memory, args[0] as i32, args[1], args[2] as u32),
+        SYS_NEWFSTATAT => {
+            fs::newfstatat(env, memory, args[0] as i32, args[1], args[2], args[3] as u32)
+        }
+        SYS_FSTAT => fs::fstat(env, memory, args[0] as i32, args[1]),
+        SYS_STATX => {
+            let (dirfd, flags, mask) = (args[0] as i32, args[2] as u32, args[3] as u32);
+            fs::statx(env, memory, dirfd, args[1], flags, mask, args[4])
+        }
+        SYS_SET_TID_ADDRESS => thread::set_tid_address(env, args[0]),
+        SYS_WAIT4 => exec::wait4(env, memory, args[0] as i32, args[1], args[2] as u32, args[3]),
+        SYS_FUTEX => {
+            let (op, val, val3) = (args[1] as u32, args[2] as u32, args[5] as u32);
+            thread::futex(env, memory, args[0], op, val, args[3], args[4], val3)
+        }
+        SYS_RT_SIGACTION => {
+            signal::rt_sigaction(env, memory, args[0] as u32, args[1], args[2], args[3])
+        }
+        SYS_RT_SIGPROCMASK => {
+            signal::rt_sigprocmask(env, memory, args[0] as u32, args[1], args[2], args[3])
+        }
+        SYS_RT_SIGPENDING => signal::rt_sigpending(env, memory, args[0], args[1]),
+        SYS_KILL => signal::kill(env, args[0] as i32, args[1] as u32),
+        SYS_TKILL => signal::tgkill(env, None, args[0] as i32, args[1] as u32),
+        SYS_TGKILL => signal::tgkill(env, Some(args[0] as i32), args[1] as i32, args[2] as u32),
+        SYS_GETPID => Ok(env.threads.pid() as u64),
+        SYS_GETTID => Ok(env.tid as u64),
+        SYS_MMAP => {
+            let (prot, flags, fd) = (args[2] as u32, args[3] as u32, args[4] as i32);
+            mm::mmap(env, memory, args[0], args[1], prot, flags, fd, args[5])
+        }
+        SYS_BRK => mm::brk(env, memory, args[0]),
+        SYS_MUNMAP => mm::munmap(env, memory, args[0], args[1]),
+        SYS_MPROTECT => mm::mprotect(env, memory, args[0], args[1], args[2] as u32),
+        SYS_MREMAP => mm::mremap(env, memory, args[0], args[1], args[2], args[3] as u32, args[4]),
+        SYS_MADVISE => mm::madvise(env, memory, args[0], args[1], args[2] as u32),
+        SYS_CLOCK_GETTIME => time::clock_gettime(env, memory, args[0] as u32, args[1]),
+        SYS_CLOCK_GETRES => time::clock_getres(env, memory, args[0] as u32, args[1]),
+        SYS_NANOSLEEP => time::nanosleep(env, memory, args[0], args[1]),
+        SYS_CLOCK_NANOSLEEP => {
+            let (id, flags) = (args[0] as u32, args[1] as u32);
+            time::clock_nanosleep(env, memory, id, flags, args[2], args[3])
+        }
+        SYS_SCHED_YIELD => time::sched_yield(env),
+        SYS_GETITIMER => time::getitimer(env, memory, args[0] as u32, args[1]),
+        SYS_SETITIMER => time::setitimer(env, memory, args[0] as u32, args[1], args[2]),
+        SYS_TIMER_CREATE => time::timer_create(env, memory, args[0] as u32, args[1], args[2]),
+        SYS_TIMER_SETTIME => {
+            let (id, flags) = (args[0] as i32, args[1] as u32);
+            time::timer_settime(env, memory, id, flags, args[2], args[3])
+        }
+        SYS_TIMER_GETTIME => time::timer_gettime(env, memory, args[0] as i32, args[1]),
+        SYS_TIMER_GETOVERRUN => time::timer_getoverrun(env, args[0] as i32),
+        SYS_TIMER_DELETE => time::timer_delete(env, args[0] as i32),
+        SYS_GETTIMEOFDAY => time::gettimeofday(env, memory, args[0], args[1]),
+        SYS_GETRANDOM => sys::getrandom(env, memory, args[0], args[1], args[2] as u32),
+        SYS_GETRUSAGE => sys::getrusage(env, memory, args[0] as i32, args[1]),
+        SYS_GETRLIMIT => sys::getrlimit(env, memory, args[0] as u32, args[1]),
+        SYS_SETRLIMIT => sys::setrlimit(env, memory, args[0] as u32, args[1]),
+        SYS_PRLIMIT64 => {
+            let (pid, resource) = (args[0] as i32, args[1] as u32);
+            sys::prlimit64(env, memory, pid, resource, args[2], args[3])
+        }
+        SYS_RISCV_FLUSH_ICACHE => sync::riscv_flush_icache(env, args[0], args[1], args[2]),
+        SYS_MEMBARRIER => sync::membarrier(env, args[0] as u32, args[1] as u32),
+        SYS_SOCKET => net::socket(env, args[0] as u32, args[1] as u32, args[2] as u32),
+        SYS_BIND => net::bind(env, memory, args[0] as i32, args[1], args[2] as u32),
+        SYS_LISTEN => net::listen(env, args[0] as i32, args[1] as i32),
+        SYS_ACCEPT => net::accept4(env, memory, args[0] as i32, args[1], args[2], 0),
+        SYS_ACCEPT4 => net::accept4(env, memory, args[0] as i32, args[1], args[2], args[3] as u32),
+        SYS_CONNECT => net::connect(env, memory, args[0] as i32, args[1], args[2] as u32),
+        SYS_GETSOCKNAME => net::getsockname(env, memory, args[0] as i32, args[1], args[2]),
+        SYS_GETPEERNAME => net::getpeername(env, memory, args[0] as i32, args[1], args[2]),
+        SYS_SENDTO => {
+            let (fd, flags, len) = (args[0] as i32, args[3] as u32, args[5] as u32);
+            net::sendto(env, memory, fd, args[1], args[2], flags, args[4], len)
+        }
+        SYS_RECVFROM => {
+            let (fd, flags) = (args[0] as i32, args[3] as u32);
+            net::recvfrom(env, memory, fd, args[1], args[2], flags, args[4], args[5])
+        }
+        SYS_SETSOCKOPT => net::setsockopt(env, args[0] as i32),
+        SYS_SHUTDOWN => net::shutdown(env, args[0] as i32, args[1] as u32),
+        _ => {
+            tracing::debug!(target: "doublejit::syscall", nr, "unimplemented syscall");
+            Err(ENOSYS)
+        }
+    }
+}
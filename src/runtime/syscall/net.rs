@@ -0,0 +1,561 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use super::fd::{FileKind, OpenFile};
+use super::{Errno, SyscallEnv, SyscallResult};
+use super::{EACCES, EAFNOSUPPORT, EDESTADDRREQ, EINVAL, EISCONN, ENOTCONN, ENOTSOCK, EOPNOTSUPP};
+use super::EPROTONOSUPPORT;
+use crate::middleend::address_map::{GuestMemory, LinearMemory};
+
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+
+pub const SOCK_STREAM: u32 = 1;
+pub const SOCK_DGRAM: u32 = 2;
+pub const SOCK_NONBLOCK: u32 = 0o4000;
+pub const SOCK_CLOEXEC: u32 = 0o2000000;
+
+pub const IPPROTO_TCP: u32 = 6;
+pub const IPPROTO_UDP: u32 = 17;
+
+pub const SHUT_RD: u32 = 0;
+pub const SHUT_WR: u32 = 1;
+pub const SHUT_RDWR: u32 = 2;
+
+/// Largest transfer of a single send or receive
+const MAX_IO: usize = 1 << 20;
+
+/// An address pattern of the socket allowlist, `None` matches any value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetRule {
+    pub ip: Option<IpAddr>,
+    pub port: Option<u16>,
+}
+
+impl NetRule {
+    pub fn new(ip: Option<IpAddr>, port: Option<u16>) -> Self {
+        Self { ip, port }
+    }
+
+    pub fn any() -> Self {
+        Self::new(None, None)
+    }
+
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        self.ip.is_none_or(|ip| ip == addr.ip()) && self.port.is_none_or(|port| port == addr.port())
+    }
+}
+
+impl From<SocketAddr> for NetRule {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(Some(addr.ip()), Some(addr.port()))
+    }
+}
+
+/// Parses `ip:port` where either side may be `*`, ipv6 addresses are
+/// bracketed as in `[::1]:8080`, and a lone `*` matches everything
+impl FromStr for NetRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(Self::any());
+        }
+        let (ip, port) = s.rsplit_once(':').ok_or_else(|| format!("missing port in {s}"))?;
+        let ip = match ip.trim_start_matches('[').trim_end_matches(']') {
+            "*" => None,
+            ip => Some(ip.parse().map_err(|_| format!("invalid address {ip}"))?),
+        };
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| format!("invalid port {port}"))?),
+        };
+        Ok(Self::new(ip, port))
+    }
+}
+
+/// Which host addresses guests may reach, by default none
+#[derive(Debug, Clone, Default)]
+pub struct NetPolicy {
+    pub connect: Vec<NetRule>,
+    pub bind: Vec<NetRule>,
+}
+
+impl NetPolicy {
+    pub fn allows_connect(&self, addr: &SocketAddr) -> bool {
+        self.connect.iter().any(|rule| rule.matches(addr))
+    }
+
+    pub fn allows_bind(&self, addr: &SocketAddr) -> bool {
+        self.bind.iter().any(|rule| rule.matches(addr))
+    }
+}
+
+/// The host object behind a socket, which std only creates once it is
+/// bound or connected
+#[derive(Debug)]
+pub enum SocketState {
+    /// Stream socket before listen or connect, with the address given to bind
+    Stream(Option<SocketAddr>),
+    Listener(TcpListener),
+    Connected(TcpStream),
+    /// Datagram socket, bound by bind, connect or the first send
+    Datagram(Option<UdpSocket>),
+}
+
+#[derive(Debug)]
+pub struct Socket {
+    pub family: u16,
+    pub nonblocking: bool,
+    pub state: SocketState,
+}
+
+impl Socket {
+    fn unspecified(&self) -> SocketAddr {
+        match self.family {
+            AF_INET6 => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            _ => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        }
+    }
+
+    /// The datagram socket, bound to `addr` or an ephemeral port if needed
+    fn udp(&mut self, addr: Option<SocketAddr>) -> Result<&UdpSocket, Errno> {
+        let unspecified = self.unspecified();
+        let nonblocking = self.nonblocking;
+        let SocketState::Datagram(socket) = &mut self.state else {
+            return Err(EOPNOTSUPP);
+        };
+        if socket.is_none() {
+            let udp = UdpSocket::bind(addr.unwrap_or(unspecified))?;
+            udp.set_nonblocking(nonblocking)?;
+            *socket = Some(udp);
+        } else if addr.is_some() {
+            return Err(EINVAL);
+        }
+        Ok(socket.as_ref().unwrap())
+    }
+
+    /// The datagram socket, bound first if it is not, which sending to or
+    /// connecting to `peer` does implicitly, to an ephemeral port the bind
+    /// policy has to allow like an explicit bind
+    fn udp_towards(&mut self, peer: SocketAddr, policy: &NetPolicy) -> Result<&UdpSocket, Errno> {
+        if !matches!(self.state, SocketState::Datagram(None)) {
+            return self.udp(None);
+        }
+        // the host picks the loopback address for a loopback peer
+        let local = match peer.ip().is_loopback() {
+            true => SocketAddr::new(peer.ip(), 0),
+            false => self.unspecified(),
+        };
+        if !policy.allows_bind(&local) {
+            return Err(EACCES);
+        }
+        self.udp(Some(local))
+    }
+
+    /// Switch the socket and its host object between blocking and not
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Errno> {
+        self.nonblocking = nonblocking;
+        match &self.state {
+            SocketState::Listener(listener) => listener.set_nonblocking(nonblocking)?,
+            SocketState::Connected(stream) => stream.set_nonblocking(nonblocking)?,
+            SocketState::Datagram(Some(socket)) => socket.set_nonblocking(nonblocking)?,
+            SocketState::Stream(_) | SocketState::Datagram(None) => {}
+        }
+        Ok(())
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        match &mut self.state {
+            SocketState::Connected(stream) => Ok(stream.read(buf)?),
+            SocketState::Datagram(Some(socket)) => Ok(socket.recv(buf)?),
+            _ => Err(ENOTCONN),
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, Errno> {
+        match &mut self.state {
+            SocketState::Connected(stream) => Ok(stream.write(buf)?),
+            SocketState::Datagram(Some(socket)) => Ok(socket.send(buf)?),
+            SocketState::Datagram(None) => Err(EDESTADDRREQ),
+            _ => Err(ENOTCONN),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Option<SocketAddr>> {
+        Ok(match &self.state {
+            SocketState::Stream(addr) => *addr,
+            SocketState::Listener(listener) => Some(listener.local_addr()?),
+            SocketState::Connected(stream) => Some(stream.local_addr()?),
+            SocketState::Datagram(socket) => socket.as_ref().map(UdpSocket::local_addr).transpose()?,
+        })
+    }
+}
+
+/// Run `f` on the socket behind `fd`
+fn with_socket<R>(env: &SyscallEnv, fd: i32, f: impl FnOnce(&mut Socket) -> Result<R, Errno>) -> Result<R, Errno> {
+    let file = env.fds.lock().unwrap().file(fd)?;
+    let mut file = file.lock().unwrap();
+    match &mut file.kind {
+        FileKind::Socket(socket) => f(socket),
+        _ => Err(ENOTSOCK),
+    }
+}
+
+/// Decode a `struct sockaddr_in` or `struct sockaddr_in6`
+fn read_sockaddr<M: LinearMemory + ?Sized>(
+    memory: &GuestMemory<M>,
+    addr: u64,
+    len: u32,
+    family: u16,
+) -> Result<SocketAddr, Errno> {
+    let data = memory.read_vec(addr, (len as usize).min(28))?;
+    if data.len() < 4 {
+        return Err(EINVAL);
+    }
+    let port = u16::from_be_bytes([data[2], data[3]]);
+    match u16::from_le_bytes([data[0], data[1]]) {
+        f if f != family => Err(EAFNOSUPPORT),
+        AF_INET if data.len() >= 16 => {
+            let ip = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+            Ok((ip, port).into())
+        }
+        AF_INET6 if data.len() >= 28 => {
+            let ip: [u8; 16] = data[8..24].try_into().unwrap();
+            Ok((Ipv6Addr::from(ip), port).into())
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+/// Store `sa` to a guest `struct sockaddr` of `*len` bytes, truncating like
+/// the kernel does and updating `*len` to the full size
+fn write_sockaddr<M: LinearMemory + ?Sized>(
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    len: u64,
+    sa: SocketAddr,
+) -> Result<(), Errno> {
+    if addr == 0 {
+        return Ok(());
+    }
+    let mut data = Vec::with_capacity(28);
+    match sa {
+        SocketAddr::V4(v4) => {
+            data.extend_from_slice(&AF_INET.to_le_bytes());
+            data.extend_from_slice(&v4.port().to_be_bytes());
+            data.extend_from_slice(&v4.ip().octets());
+            data.extend_from_slice(&[0; 8]);
+        }
+        SocketAddr::V6(v6) => {
+            data.extend_from_slice(&AF_INET6.to_le_bytes());
+            data.extend_from_slice(&v6.port().to_be_bytes());
+            data.extend_from_slice(&v6.flowinfo().to_be_bytes());
+            data.extend_from_slice(&v6.ip().octets());
+            data.extend_from_slice(&v6.scope_id().to_le_bytes());
+        }
+    }
+    let room = memory.read_u32(len)? as usize;
+    memory.write(addr, &data[..room.min(data.len())])?;
+    memory.write_u32(len, data.len() as u32)?;
+    Ok(())
+}
+
+pub fn socket(env: &mut SyscallEnv, domain: u32, ty: u32, protocol: u32) -> SyscallResult {
+    let family = match domain as u16 {
+        family @ (AF_INET | AF_INET6) => family,
+        _ => return Err(EAFNOSUPPORT),
+    };
+    let state = match (ty & !(SOCK_NONBLOCK | SOCK_CLOEXEC), protocol) {
+        (SOCK_STREAM, 0 | IPPROTO_TCP) => SocketState::Stream(None),
+        (SOCK_DGRAM, 0 | IPPROTO_UDP) => SocketState::Datagram(None),
+        _ => return Err(EPROTONOSUPPORT),
+    };
+    let socket = Socket {
+        family,
+        nonblocking: ty & SOCK_NONBLOCK != 0,
+        state,
+    };
+    let fd = env.fds.lock().unwrap().insert(
+        OpenFile {
+            kind: FileKind::Socket(socket),
+            guest_path: PathBuf::new(),
+            host_path: None,
+            flags: 0o2,
+        },
+        ty & SOCK_CLOEXEC != 0,
+    )?;
+    Ok(fd as u64)
+}
+
+pub fn bind<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    addr: u64,
+    len: u32,
+) -> SyscallResult {
+    let policy = env.net.clone();
+    with_socket(env, fd, |socket| {
+        let addr = read_sockaddr(memory, addr, len, socket.family)?;
+        if !policy.allows_bind(&addr) {
+            return Err(EACCES);
+        }
+        match &mut socket.state {
+            SocketState::Stream(bound @ None) => *bound = Some(addr),
+            SocketState::Datagram(None) => {
+                socket.udp(Some(addr))?;
+            }
+            _ => return Err(EINVAL),
+        }
+        Ok(0)
+    })
+}
+
+pub fn listen(env: &mut SyscallEnv, fd: i32, _backlog: i32) -> SyscallResult {
+    with_socket(env, fd, |socket| {
+        let addr = match socket.state {
+            SocketState::Stream(Some(addr)) => addr,
+            SocketState::Listener(_) => return Ok(0),
+            // the guest has to bind first, an implicit ephemeral port would
+            // dodge the allowlist
+            SocketState::Stream(None) => return Err(EDESTADDRREQ),
+            _ => return Err(EOPNOTSUPP),
+        };
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(socket.nonblocking)?;
+        socket.state = SocketState::Listener(listener);
+        Ok(0)
+    })
+}
+
+pub fn accept4<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    addr: u64,
+    len: u64,
+    flags: u32,
+) -> SyscallResult {
+    if flags & !(SOCK_NONBLOCK | SOCK_CLOEXEC) != 0 {
+        return Err(EINVAL);
+    }
+    let (listener, family) = with_socket(env, fd, |socket| match &socket.state {
+        SocketState::Listener(listener) => Ok((listener.try_clone()?, socket.family)),
+        _ => Err(EINVAL),
+    })?;
+    // wait for the connection without holding the file, which the other
+    // threads of the guest may use meanwhile
+    let (stream, peer) = listener.accept()?;
+    let nonblocking = flags & SOCK_NONBLOCK != 0;
+    stream.set_nonblocking(nonblocking)?;
+    write_sockaddr(memory, addr, len, peer)?;
+    let socket = Socket {
+        family,
+        nonblocking,
+        state: SocketState::Connected(stream),
+    };
+    let fd = env.fds.lock().unwrap().insert(
+        OpenFile {
+            kind: FileKind::Socket(socket),
+            guest_path: PathBuf::new(),
+            host_path: None,
+            flags: 0o2,
+        },
+        flags & SOCK_CLOEXEC != 0,
+    )?;
+    Ok(fd as u64)
+}
+
+pub fn connect<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    addr: u64,
+    len: u32,
+) -> SyscallResult {
+    let policy = env.net.clone();
+    with_socket(env, fd, |socket| {
+        let addr = read_sockaddr(memory, addr, len, socket.family)?;
+        if !policy.allows_connect(&addr) {
+            return Err(EACCES);
+        }
+        match &socket.state {
+            SocketState::Stream(_) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nonblocking(socket.nonblocking)?;
+                socket.state = SocketState::Connected(stream);
+            }
+            SocketState::Datagram(_) => socket.udp_towards(addr, &policy)?.connect(addr)?,
+            SocketState::Connected(_) => return Err(EISCONN),
+            SocketState::Listener(_) => return Err(EINVAL),
+        }
+        Ok(0)
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn sendto<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    buf: u64,
+    count: u64,
+    _flags: u32,
+    addr: u64,
+    len: u32,
+) -> SyscallResult {
+    let data = memory.read_vec(buf, (count as usize).min(MAX_IO))?;
+    let policy = env.net.clone();
+    with_socket(env, fd, |socket| {
+        if addr == 0 || matches!(socket.state, SocketState::Connected(_)) {
+            return Ok(socket.write(&data)? as u64);
+        }
+        let addr = read_sockaddr(memory, addr, len, socket.family)?;
+        if !policy.allows_connect(&addr) {
+            return Err(EACCES);
+        }
+        Ok(socket.udp_towards(addr, &policy)?.send_to(&data, addr)? as u64)
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn recvfrom<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    buf: u64,
+    count: u64,
+    _flags: u32,
+    addr: u64,
+    len: u64,
+) -> SyscallResult {
+    let mut data = vec![0; (count as usize).min(MAX_IO)];
+    let (received, peer) = with_socket(env, fd, |socket| match &socket.state {
+        SocketState::Datagram(Some(udp)) => {
+            let (received, peer) = udp.recv_from(&mut data)?;
+            Ok((received, Some(peer)))
+        }
+        _ => Ok((socket.read(&mut data)?, None)),
+    })?;
+    memory.write(buf, &data[..received])?;
+    if let Some(peer) = peer {
+        write_sockaddr(memory, addr, len, peer)?;
+    }
+    Ok(received as u64)
+}
+
+pub fn shutdown(env: &mut SyscallEnv, fd: i32, how: u32) -> SyscallResult {
+    let how = match how {
+        SHUT_RD => Shutdown::Read,
+        SHUT_WR => Shutdown::Write,
+        SHUT_RDWR => Shutdown::Both,
+        _ => return Err(EINVAL),
+    };
+    with_socket(env, fd, |socket| match &socket.state {
+        SocketState::Connected(stream) => Ok(stream.shutdown(how).map(|_| 0)?),
+        _ => Err(ENOTCONN),
+    })
+}
+
+pub fn getsockname<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    addr: u64,
+    len: u64,
+) -> SyscallResult {
+    let local = with_socket(env, fd, |socket| {
+        Ok(socket.local_addr()?.unwrap_or_else(|| socket.unspecified()))
+    })?;
+    write_sockaddr(memory, addr, len, local)?;
+    Ok(0)
+}
+
+pub fn getpeername<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    fd: i32,
+    addr: u64,
+    len: u64,
+) -> SyscallResult {
+    let peer = with_socket(env, fd, |socket| match &socket.state {
+        SocketState::Connected(stream) => Ok(stream.peer_addr()?),
+        SocketState::Datagram(Some(udp)) => Ok(udp.peer_addr()?),
+        _ => Err(ENOTCONN),
+    })?;
+    write_sockaddr(memory, addr, len, peer)?;
+    Ok(0)
+}
+
+/// Options are accepted and ignored, the host sockets already come with
+/// sensible defaults such as `SO_REUSEADDR` on listeners
+pub fn setsockopt(env: &mut SyscallEnv, fd: i32) -> SyscallResult {
+    with_socket(env, fd, |_| Ok(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::testing::{call, TestMemory};
+    use crate::runtime::syscall::{SYS_ACCEPT, SYS_BIND, SYS_CONNECT};
+    use crate::runtime::syscall::{SYS_GETSOCKNAME, SYS_LISTEN, SYS_READ, SYS_SENDTO, SYS_SOCKET};
+
+    #[test]
+    fn test_rule_parse() {
+        let rule: NetRule = "127.0.0.1:*".parse().unwrap();
+        assert!(rule.matches(&"127.0.0.1:80".parse().unwrap()));
+        assert!(!rule.matches(&"10.0.0.1:80".parse().unwrap()));
+        assert_eq!("[::1]:53".parse::<NetRule>().unwrap(), NetRule::from("[::1]:53".parse::<SocketAddr>().unwrap()));
+        assert_eq!("*".parse::<NetRule>().unwrap(), NetRule::any());
+        assert!("localhost".parse::<NetRule>().is_err());
+    }
+
+    #[test]
+    fn test_loopback_tcp() {
+        let localhost: NetRule = "127.0.0.1:*".parse().unwrap();
+        let mut env = SyscallEnv::new(&Config::new().allow_bind(localhost).allow_connect(localhost));
+        let base = 0x10000;
+        let mut guest = TestMemory::new(&[(base..base + 0x1000, Perm::RW)]);
+        let mut memory = guest.memory();
+
+        // sockaddr_in for 127.0.0.1 on an ephemeral port
+        let sa = base;
+        memory.write(sa, &[2, 0, 0, 0, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        let server = call(&mut env, &mut memory, SYS_SOCKET, [AF_INET as u64, SOCK_STREAM as u64, 0, 0, 0, 0]);
+        assert!(server >= 3);
+        assert_eq!(call(&mut env, &mut memory, SYS_BIND, [server as u64, sa, 16, 0, 0, 0]), 0);
+        assert_eq!(call(&mut env, &mut memory, SYS_LISTEN, [server as u64, 16, 0, 0, 0, 0]), 0);
+        memory.write_u32(base + 0x40, 16).unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_GETSOCKNAME, [server as u64, sa, base + 0x40, 0, 0, 0]), 0);
+        assert_ne!(memory.read_vec(sa + 2, 2).unwrap(), [0, 0]);
+
+        let client = call(&mut env, &mut memory, SYS_SOCKET, [AF_INET as u64, SOCK_STREAM as u64, 0, 0, 0, 0]);
+        assert_eq!(call(&mut env, &mut memory, SYS_CONNECT, [client as u64, sa, 16, 0, 0, 0]), 0);
+        let conn = call(&mut env, &mut memory, SYS_ACCEPT, [server as u64, 0, 0, 0, 0, 0]);
+        assert!(conn > client);
+        memory.write(base + 0x100, b"ping").unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_SENDTO, [client as u64, base + 0x100, 4, 0, 0, 0]), 4);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [conn as u64, base + 0x200, 4, 0, 0, 0]), 4);
+        assert_eq!(memory.read_vec(base + 0x200, 4).unwrap(), b"ping");
+
+        // anything outside of the allowlist is refused
+        memory.write(sa, &[2, 0, 0, 80, 10, 0, 0, 1]).unwrap();
+        let other = call(&mut env, &mut memory, SYS_SOCKET, [AF_INET as u64, SOCK_STREAM as u64, 0, 0, 0, 0]);
+        assert_eq!(call(&mut env, &mut memory, SYS_CONNECT, [other as u64, sa, 16, 0, 0, 0]), -EACCES.0 as i64);
+        assert_eq!(call(&mut env, &mut memory, SYS_LISTEN, [0, 1, 0, 0, 0, 0]), -ENOTSOCK.0 as i64);
+
+        // the first send of a datagram socket binds it, which the bind
+        // policy has to allow too
+        memory.write(sa, &[2, 0, 0, 9, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        let mut connect_only = SyscallEnv::new(&Config::new().allow_connect(localhost));
+        let udp = socket(&mut connect_only, AF_INET as u32, SOCK_DGRAM, 0).unwrap() as i32;
+        assert_eq!(sendto(&mut connect_only, &mut memory, udp, base + 0x100, 4, 0, sa, 16), Err(EACCES));
+        let udp = socket(&mut env, AF_INET as u32, SOCK_DGRAM, 0).unwrap() as i32;
+        assert_eq!(sendto(&mut env, &mut memory, udp, base + 0x100, 4, 0, sa, 16), Ok(4));
+    }
+}
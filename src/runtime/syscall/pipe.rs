@@ -0,0 +1,305 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::fd::{FileKind, OpenFile};
+use super::fs::{O_CLOEXEC, O_NONBLOCK, O_RDONLY, O_WRONLY};
+use super::{Errno, SyscallEnv, SyscallResult, EAGAIN, EINVAL, EPIPE};
+use crate::middleend::address_map::{GuestMemory, LinearMemory};
+
+/// Bytes a pipe buffers before writers block, linux's default pipe size
+pub const PIPE_CAPACITY: usize = 65536;
+/// Writes up to this size land in the pipe as a whole, never interleaved
+pub const PIPE_BUF: usize = 4096;
+
+#[derive(Debug, Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    buffer: Mutex<Buffer>,
+    /// Signalled whenever data moves or an end is closed
+    changed: Condvar,
+}
+
+/// Read end of a pipe, which the host can hold as well as the guest
+#[derive(Debug)]
+pub struct PipeReader {
+    shared: Arc<Shared>,
+}
+
+/// Write end of a pipe, which the host can hold as well as the guest
+#[derive(Debug)]
+pub struct PipeWriter {
+    shared: Arc<Shared>,
+}
+
+/// A pipe end taken out of its open file, to block on without holding the
+/// file locked. It does not keep the pipe open by itself.
+#[derive(Debug, Clone)]
+pub struct PipeEnd {
+    shared: Arc<Shared>,
+}
+
+/// Create a connected pair of pipe ends
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let shared = Arc::new(Shared::default());
+    {
+        let mut buffer = shared.buffer.lock().unwrap();
+        buffer.readers = 1;
+        buffer.writers = 1;
+    }
+    (
+        PipeReader {
+            shared: shared.clone(),
+        },
+        PipeWriter { shared },
+    )
+}
+
+impl PipeEnd {
+    /// Read what is buffered, blocking until at least one byte is or every
+    /// write end is closed, which reads as end of file
+    pub fn read_bytes(&self, buf: &mut [u8], nonblocking: bool) -> Result<usize, Errno> {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        while buffer.data.is_empty() && buffer.writers > 0 && !buf.is_empty() {
+            if nonblocking {
+                return Err(EAGAIN);
+            }
+            buffer = self.shared.changed.wait(buffer).unwrap();
+        }
+        let len = buf.len().min(buffer.data.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.data.drain(..len)) {
+            *dst = src;
+        }
+        self.shared.changed.notify_all();
+        Ok(len)
+    }
+
+    /// Append `data`, blocking while the pipe is full until all of it is
+    /// written. Writes of at most `PIPE_BUF` bytes wait for room for all
+    /// of them, larger ones are written as room frees up and complete
+    /// short only when nonblocking. Fails with EPIPE once every read end is
+    /// closed, after a partial write returning what was written.
+    pub fn write_bytes(&self, data: &[u8], nonblocking: bool) -> Result<usize, Errno> {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        let mut written = 0;
+        loop {
+            if buffer.readers == 0 {
+                return if written > 0 { Ok(written) } else { Err(EPIPE) };
+            }
+            let room = PIPE_CAPACITY - buffer.data.len();
+            let remaining = data.len() - written;
+            if room >= remaining || (data.len() > PIPE_BUF && room > 0) {
+                let len = remaining.min(room);
+                buffer.data.extend(&data[written..written + len]);
+                written += len;
+                self.shared.changed.notify_all();
+                if written == data.len() {
+                    return Ok(written);
+                }
+            } else if nonblocking {
+                return if written > 0 { Ok(written) } else { Err(EAGAIN) };
+            } else {
+                buffer = self.shared.changed.wait(buffer).unwrap();
+            }
+        }
+    }
+
+    /// The bytes the pipe buffers
+    pub fn contents(&self) -> Vec<u8> {
+        self.shared.buffer.lock().unwrap().data.iter().copied().collect()
+    }
+
+    /// Replace what the pipe buffers by `data`
+    pub fn set_contents(&self, data: &[u8]) {
+        self.shared.buffer.lock().unwrap().data = data.iter().copied().collect();
+        self.shared.changed.notify_all();
+    }
+}
+
+impl PipeReader {
+    pub fn end(&self) -> PipeEnd {
+        PipeEnd {
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn read_bytes(&self, buf: &mut [u8], nonblocking: bool) -> Result<usize, Errno> {
+        self.end().read_bytes(buf, nonblocking)
+    }
+
+    /// Bytes that can be read without blocking
+    pub fn available(&self) -> usize {
+        self.shared.buffer.lock().unwrap().data.len()
+    }
+}
+
+impl PipeWriter {
+    pub fn end(&self) -> PipeEnd {
+        PipeEnd {
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn write_bytes(&self, data: &[u8], nonblocking: bool) -> Result<usize, Errno> {
+        self.end().write_bytes(data, nonblocking)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.shared.buffer.lock().unwrap().readers -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.shared.buffer.lock().unwrap().writers -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl io::Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_bytes(buf, false)
+            .map_err(|Errno(errno)| io::Error::from_raw_os_error(errno))
+    }
+}
+
+impl io::Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_bytes(buf, false)
+            .map_err(|Errno(errno)| io::Error::from_raw_os_error(errno))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An open file description for a pipe end
+pub fn open_file(kind: FileKind, flags: u32) -> OpenFile {
+    OpenFile {
+        kind,
+        guest_path: PathBuf::new(),
+        host_path: None,
+        flags,
+    }
+}
+
+pub fn pipe2<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    pipefd: u64,
+    flags: u32,
+) -> SyscallResult {
+    if flags & !(O_CLOEXEC | O_NONBLOCK) != 0 {
+        return Err(EINVAL);
+    }
+    let (reader, writer) = pipe();
+    let cloexec = flags & O_CLOEXEC != 0;
+    let status = flags & O_NONBLOCK;
+    let mut fds = env.fds.lock().unwrap();
+    let read_fd = fds.insert(open_file(FileKind::PipeRead(reader), O_RDONLY | status), cloexec)?;
+    let write_fd = match fds.insert(open_file(FileKind::PipeWrite(writer), O_WRONLY | status), cloexec) {
+        Ok(fd) => fd,
+        Err(e) => {
+            fds.remove(read_fd)?;
+            return Err(e);
+        }
+    };
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&read_fd.to_le_bytes());
+    bytes[4..].copy_from_slice(&write_fd.to_le_bytes());
+    if let Err(e) = memory.write(pipefd, &bytes) {
+        fds.remove(read_fd)?;
+        fds.remove(write_fd)?;
+        return Err(e.into());
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::fs::{F_GETFD, FD_CLOEXEC};
+    use crate::runtime::syscall::testing::{call, TestMemory};
+    use crate::runtime::syscall::{SYS_CLOSE, SYS_DUP3, SYS_FCNTL, SYS_PIPE2, SYS_READ, SYS_WRITE};
+    use std::io::{Read, Write};
+    use std::thread;
+
+    #[test]
+    fn test_pipe_ends() {
+        let (mut reader, mut writer) = pipe();
+        let mut buf = [0; 8];
+        assert_eq!(reader.read_bytes(&mut buf, true), Err(EAGAIN));
+
+        // a full pipe blocks the writer until the reader drains it
+        let big = vec![7; PIPE_CAPACITY + 100];
+        let producer = thread::spawn(move || {
+            writer.write_all(&big).unwrap();
+            writer
+        });
+        let mut received = 0;
+        while received < PIPE_CAPACITY + 100 {
+            received += reader.read(&mut buf).unwrap();
+        }
+        drop(producer.join().unwrap());
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        let (reader, writer) = pipe();
+        drop(reader);
+        assert_eq!(writer.write_bytes(b"x", false), Err(EPIPE));
+
+        // a single blocking write hands over all of a large buffer
+        let (mut reader, writer) = pipe();
+        let producer = thread::spawn(move || writer.write_bytes(&vec![7; 3 * PIPE_CAPACITY], false));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(producer.join().unwrap(), Ok(3 * PIPE_CAPACITY));
+        assert_eq!(data.len(), 3 * PIPE_CAPACITY);
+        // while a nonblocking one fills the pipe and stops
+        let (_reader, writer) = pipe();
+        assert_eq!(writer.write_bytes(&vec![7; 2 * PIPE_CAPACITY], true), Ok(PIPE_CAPACITY));
+        assert_eq!(writer.write_bytes(b"x", true), Err(EAGAIN));
+    }
+
+    #[test]
+    fn test_redirect_through_pipes() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut stdout = env.capture(1).unwrap();
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+        let base = 0x10000;
+
+        assert_eq!(call(&mut env, &mut memory, SYS_PIPE2, [base, O_CLOEXEC as u64, 0, 0, 0, 0]), 0);
+        let (read_fd, write_fd) = (memory.read_u32(base).unwrap() as u64, memory.read_u32(base + 4).unwrap() as u64);
+        assert_eq!(call(&mut env, &mut memory, SYS_FCNTL, [read_fd, F_GETFD as u64, 0, 0, 0, 0]), FD_CLOEXEC as i64);
+
+        // the guest moves the write end over a descriptor of its own
+        assert_eq!(call(&mut env, &mut memory, SYS_DUP3, [write_fd, 9, 0, 0, 0, 0]), 9);
+        assert_eq!(call(&mut env, &mut memory, SYS_FCNTL, [9, F_GETFD as u64, 0, 0, 0, 0]), 0);
+        assert_eq!(call(&mut env, &mut memory, SYS_CLOSE, [write_fd, 0, 0, 0, 0, 0]), 0);
+        memory.write(base + 16, b"hello").unwrap();
+        assert_eq!(call(&mut env, &mut memory, SYS_WRITE, [9, base + 16, 5, 0, 0, 0]), 5);
+        assert_eq!(call(&mut env, &mut memory, SYS_CLOSE, [9, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [read_fd, base + 32, 16, 0, 0, 0]), 5);
+        assert_eq!(call(&mut env, &mut memory, SYS_READ, [read_fd, base + 32, 16, 0, 0, 0]), 0);
+        assert_eq!(memory.read_vec(base + 32, 5).unwrap(), b"hello");
+
+        // and the host reads what it writes to its stdout
+        assert_eq!(call(&mut env, &mut memory, SYS_WRITE, [1, base + 16, 5, 0, 0, 0]), 5);
+        let mut buf = [0; 5];
+        stdout.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
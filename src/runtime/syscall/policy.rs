@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use crate::runtime::execution::ExecutionResult;
+use crate::runtime::syscall::signal::SIGSYS;
+use crate::runtime::syscall::{Errno, SyscallEnv, ENOSYS};
+
+/// What happens to a syscall the policy matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    /// Fail the syscall with the errno without running it
+    Errno(Errno),
+    /// End the process with SIGSYS, as seccomp's `SECCOMP_RET_KILL_PROCESS`
+    Kill,
+}
+
+/// Seccomp-like filter deciding per syscall number whether the guest may
+/// make the call, checked before any syscall is dispatched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallPolicy {
+    default: Action,
+    rules: HashMap<u64, Action>,
+}
+
+impl SyscallPolicy {
+    /// A policy applying `default` to every syscall without a rule
+    pub fn new(default: Action) -> Self {
+        Self {
+            default,
+            rules: HashMap::new(),
+        }
+    }
+
+    /// A policy allowing only the syscalls `nrs` and killing on any other
+    pub fn allowlist(nrs: impl IntoIterator<Item = u64>) -> Self {
+        nrs.into_iter().fold(Self::new(Action::Kill), |policy, nr| policy.allow(nr))
+    }
+
+    pub fn rule(mut self, nr: u64, action: Action) -> Self {
+        self.rules.insert(nr, action);
+        self
+    }
+
+    pub fn allow(self, nr: u64) -> Self {
+        self.rule(nr, Action::Allow)
+    }
+
+    pub fn deny(self, nr: u64, errno: Errno) -> Self {
+        self.rule(nr, Action::Errno(errno))
+    }
+
+    pub fn kill(self, nr: u64) -> Self {
+        self.rule(nr, Action::Kill)
+    }
+
+    pub fn action(&self, nr: u64) -> Action {
+        self.rules.get(&nr).copied().unwrap_or(self.default)
+    }
+}
+
+impl Default for SyscallPolicy {
+    fn default() -> Self {
+        Self::new(Action::Allow)
+    }
+}
+
+/// Apply the policy to the syscall `nr`. A killed process reports ENOSYS to
+/// the calling thread, which the engine never resumes since the process
+/// has ended.
+pub fn filter(env: &SyscallEnv, nr: u64) -> Result<(), Errno> {
+    match env.policy.action(nr) {
+        Action::Allow => Ok(()),
+        Action::Errno(errno) => Err(errno),
+        Action::Kill => {
+            tracing::warn!(target: "doublejit::syscall", nr, "syscall not allowed, killing the guest");
+            env.threads.terminate(ExecutionResult::Signaled(SIGSYS));
+            Err(ENOSYS)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::execution::GuestExit;
+    use crate::runtime::regs::{Registers, A0, A7};
+    use crate::runtime::syscall::testing::TestMemory;
+    use crate::runtime::syscall::{ecall, EPERM, SYS_GETPID, SYS_GETTID, SYS_WRITE};
+
+    #[test]
+    fn test_policy_actions() {
+        let policy = SyscallPolicy::allowlist([SYS_GETPID]).deny(SYS_WRITE, EPERM);
+        let mut env = SyscallEnv::new(&Config::new().syscall_policy(policy));
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+        let mut call = |nr: u64| {
+            let mut regs = Registers::default();
+            regs.x[A7] = nr;
+            ecall(&mut env, &mut memory, &mut regs).map(|()| regs.x[A0] as i64)
+        };
+
+        assert_eq!(call(SYS_GETPID), Ok(1));
+        assert_eq!(call(SYS_WRITE), Ok(-EPERM.0 as i64));
+        assert_eq!(call(SYS_GETTID), Err(GuestExit::Process(ExecutionResult::Signaled(SIGSYS))));
+    }
+}
@@ -0,0 +1,698 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::time;
+use super::{Errno, SyscallEnv, SyscallResult};
+use super::{EINVAL, ENOMEM, EPERM, ESRCH};
+use crate::middleend::address_map::{GuestFault, GuestMemory, LinearMemory};
+use crate::runtime::execution::{ExecutionResult, GuestExit};
+use crate::runtime::regs::{Registers, A0, A1, A2, RA, SP};
+
+pub const NSIG: usize = 64;
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGVTALRM: u32 = 26;
+pub const SIGPROF: u32 = 27;
+pub const SIGWINCH: u32 = 28;
+pub const SIGSYS: u32 = 31;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const SA_SIGINFO: u64 = 0x4;
+pub const SA_ONSTACK: u64 = 0x0800_0000;
+pub const SA_RESTART: u64 = 0x1000_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+pub const SIG_BLOCK: u32 = 0;
+pub const SIG_UNBLOCK: u32 = 1;
+pub const SIG_SETMASK: u32 = 2;
+
+pub const SS_ONSTACK: u32 = 1;
+pub const SS_DISABLE: u32 = 2;
+const MINSIGSTKSZ: u64 = 2048;
+
+pub const SI_USER: i32 = 0;
+pub const SI_KERNEL: i32 = 0x80;
+pub const SI_TKILL: i32 = -6;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+pub const BUS_ADRALN: i32 = 1;
+pub const ILL_ILLOPC: i32 = 1;
+
+/// `li a7, 139; ecall`, the rt_sigreturn trampoline the loader places in
+/// guest memory, the counterpart of the linux vDSO's `__vdso_rt_sigreturn`
+pub const SIGRETURN_TRAMPOLINE: [u32; 2] = [0x08b0_0893, 0x0000_0073];
+
+/// `struct rt_sigframe` offsets: siginfo, then the ucontext
+const FRAME_SIZE: u64 = 128 + UC_SIZE;
+const UC_OFFSET: u64 = 128;
+const UC_STACK: u64 = 16;
+const UC_SIGMASK: u64 = 40;
+const UC_MCONTEXT: u64 = 176;
+/// `sc_regs` of 32 registers plus the 528 byte `__riscv_fp_state`
+const UC_SIZE: u64 = UC_MCONTEXT + 32 * 8 + 528;
+
+fn bit(sig: u32) -> u64 {
+    1 << (sig - 1)
+}
+
+/// Signals that can be neither caught, blocked nor ignored
+const UNBLOCKABLE: u64 = (1 << (SIGKILL - 1)) | (1 << (SIGSTOP - 1));
+
+fn check_signal(sig: u32) -> Result<(), Errno> {
+    if sig == 0 || sig as usize > NSIG {
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+/// The linux `struct sigaction` of riscv, which has no `sa_restorer`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub mask: u64,
+}
+
+/// What becomes of a signal with the default disposition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DefaultAction {
+    Ignore,
+    Terminate,
+}
+
+fn default_action(sig: u32) -> DefaultAction {
+    match sig {
+        // without job control stopping and continuing are no-ops
+        SIGCHLD | SIGURG | SIGWINCH | SIGCONT | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => {
+            DefaultAction::Ignore
+        }
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// The `siginfo_t` fields the runtime fills in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigInfo {
+    pub signo: u32,
+    pub code: i32,
+    /// `si_addr` for faults, `si_pid` in the low half for kill
+    pub value: u64,
+}
+
+impl SigInfo {
+    /// SIGSEGV for a guest memory fault, including instruction fetches, or
+    /// SIGBUS for a misaligned atomic
+    pub fn from_fault(fault: &GuestFault) -> Self {
+        let (signo, code, vaddr) = match *fault {
+            GuestFault::Unmapped { vaddr, .. } | GuestFault::PageFault { vaddr, .. } => (SIGSEGV, SEGV_MAPERR, vaddr),
+            GuestFault::Protection { vaddr, .. } => (SIGSEGV, SEGV_ACCERR, vaddr),
+            GuestFault::Misaligned { vaddr, .. } => (SIGBUS, BUS_ADRALN, vaddr),
+        };
+        Self {
+            signo,
+            code,
+            value: vaddr,
+        }
+    }
+
+    /// SIGILL for the invalid instruction at `pc`
+    pub fn illegal(pc: u64) -> Self {
+        Self {
+            signo: SIGILL,
+            code: ILL_ILLOPC,
+            value: pc,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 128] {
+        let mut info = [0; 128];
+        info[0..4].copy_from_slice(&self.signo.to_le_bytes());
+        info[8..12].copy_from_slice(&self.code.to_le_bytes());
+        info[16..24].copy_from_slice(&self.value.to_le_bytes());
+        info
+    }
+}
+
+/// Dispositions and process directed pending signals, shared by all threads
+/// and postable from any host thread
+#[derive(Debug)]
+pub struct SignalTable {
+    actions: Mutex<[SigAction; NSIG]>,
+    pending: AtomicU64,
+    trampoline: AtomicU64,
+}
+
+impl SignalTable {
+    pub fn new() -> Self {
+        Self {
+            actions: Mutex::new([SigAction::default(); NSIG]),
+            pending: AtomicU64::new(0),
+            trampoline: AtomicU64::new(0),
+        }
+    }
+
+    pub fn action(&self, sig: u32) -> SigAction {
+        self.actions.lock().unwrap()[sig as usize - 1]
+    }
+
+    /// Queue `sig` for the process, e.g. a SIGINT the host received
+    pub fn post(&self, sig: u32) {
+        self.pending.fetch_or(bit(sig), Ordering::SeqCst);
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// The table of a forked child: the same dispositions, nothing pending
+    pub fn fork(&self) -> Self {
+        Self {
+            actions: Mutex::new(*self.actions.lock().unwrap()),
+            pending: AtomicU64::new(0),
+            trampoline: AtomicU64::new(self.trampoline.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Reset caught signals to their default on execve, as the handlers are
+    /// gone with the old image. Ignored signals stay ignored.
+    pub fn exec(&self) {
+        for action in self.actions.lock().unwrap().iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+        self.trampoline.store(0, Ordering::Relaxed);
+    }
+
+    /// Where the loader put `SIGRETURN_TRAMPOLINE`, handlers return there
+    pub fn set_trampoline(&self, addr: u64) {
+        self.trampoline.store(addr, Ordering::Relaxed);
+    }
+
+    /// Take over the dispositions and pending signals of `saved`, a copy
+    /// of the table a snapshot kept
+    pub fn restore(&self, saved: &SignalTable) {
+        *self.actions.lock().unwrap() = *saved.actions.lock().unwrap();
+        self.pending.store(saved.pending(), Ordering::SeqCst);
+        self.trampoline.store(saved.trampoline.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+impl Clone for SignalTable {
+    fn clone(&self) -> Self {
+        Self {
+            pending: AtomicU64::new(self.pending()),
+            ..self.fork()
+        }
+    }
+}
+
+impl Default for SignalTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Signal state private to one thread
+#[derive(Debug, Clone, Default)]
+pub struct ThreadSignals {
+    pub mask: u64,
+    /// Signals directed at this thread by tkill and tgkill
+    pub pending: u64,
+    /// Alternate signal stack as (base, size), from sigaltstack
+    pub altstack: Option<(u64, u64)>,
+}
+
+impl ThreadSignals {
+    /// Make `sig` pending on this thread
+    pub fn post(&mut self, sig: u32) {
+        self.pending |= bit(sig);
+    }
+}
+
+/// Outcome of a signal delivery check at a block boundary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Nothing deliverable is pending
+    None,
+    /// The registers now enter the handler of the signal
+    Handler(u32),
+    /// The signal's action is to terminate the process
+    Terminate(u32),
+}
+
+/// Deliver the lowest pending unblocked signal, if any. Called as each
+/// syscall returns and whenever the interpreter yields, with the registers
+/// of the interrupted guest.
+pub fn deliver<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+) -> Delivery {
+    time::fire_timers(env);
+    loop {
+        let deliverable = (env.signals.pending() | env.sigstate.pending) & !(env.sigstate.mask & !UNBLOCKABLE);
+        if deliverable == 0 {
+            return Delivery::None;
+        }
+        let sig = deliverable.trailing_zeros() + 1;
+        if env.sigstate.pending & bit(sig) != 0 {
+            env.sigstate.pending &= !bit(sig);
+        } else if env.signals.pending.fetch_and(!bit(sig), Ordering::SeqCst) & bit(sig) == 0 {
+            // another thread took it meanwhile
+            continue;
+        }
+        let info = SigInfo {
+            signo: sig,
+            code: SI_USER,
+            value: env.threads.pid() as u64,
+        };
+        match run_handler(env, memory, regs, info, false) {
+            Delivery::None => continue,
+            delivery => return delivery,
+        }
+    }
+}
+
+/// Deliver a synchronous signal raised by the instruction at `regs.pc`,
+/// which cannot be blocked or ignored without killing the process
+pub fn force<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+    info: SigInfo,
+) -> Delivery {
+    run_handler(env, memory, regs, info, true)
+}
+
+/// End the process by `sig`, for a signal whose action is to terminate
+pub fn terminate(env: &SyscallEnv, sig: u32) -> GuestExit {
+    GuestExit::Process(env.threads.terminate(ExecutionResult::Signaled(sig)))
+}
+
+fn run_handler<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+    info: SigInfo,
+    forced: bool,
+) -> Delivery {
+    let sig = info.signo;
+    let action = env.signals.action(sig);
+    let blocked = env.sigstate.mask & bit(sig) != 0;
+    match action.handler {
+        _ if sig == SIGKILL => return Delivery::Terminate(sig),
+        SIG_IGN | SIG_DFL if forced => return Delivery::Terminate(sig),
+        _ if forced && blocked => return Delivery::Terminate(sig),
+        SIG_IGN => return Delivery::None,
+        SIG_DFL => {
+            return match default_action(sig) {
+                DefaultAction::Ignore => Delivery::None,
+                DefaultAction::Terminate => Delivery::Terminate(sig),
+            }
+        }
+        _ => {}
+    }
+
+    let mut sp = regs.x[SP];
+    let on_altstack = env.sigstate.altstack.is_some_and(|(base, size)| (base..base + size).contains(&sp));
+    if let (Some((base, size)), true, false) = (env.sigstate.altstack, action.flags & SA_ONSTACK != 0, on_altstack) {
+        sp = base + size;
+    }
+    // like force_sigsegv, a frame that does not fit or cannot be written
+    // kills the process
+    let Some(frame) = sp.checked_sub(FRAME_SIZE).map(|frame| frame & !15) else {
+        return Delivery::Terminate(SIGSEGV);
+    };
+    if write_frame(env, memory, regs, info, frame).is_err() {
+        return Delivery::Terminate(SIGSEGV);
+    }
+
+    env.sigstate.mask |= action.mask & !UNBLOCKABLE;
+    if action.flags & SA_NODEFER == 0 {
+        env.sigstate.mask |= bit(sig);
+    }
+    if action.flags & SA_RESETHAND != 0 {
+        env.signals.actions.lock().unwrap()[sig as usize - 1] = SigAction::default();
+    }
+    regs.pc = action.handler;
+    regs.x[RA] = env.signals.trampoline.load(Ordering::Relaxed);
+    regs.x[SP] = frame;
+    regs.x[A0] = sig as u64;
+    regs.x[A1] = frame;
+    regs.x[A2] = frame + UC_OFFSET;
+    Delivery::Handler(sig)
+}
+
+fn write_frame<M: LinearMemory + ?Sized>(
+    env: &SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &Registers,
+    info: SigInfo,
+    frame: u64,
+) -> Result<(), Errno> {
+    let mut data = vec![0u8; FRAME_SIZE as usize];
+    data[..128].copy_from_slice(&info.to_bytes());
+    let uc = &mut data[UC_OFFSET as usize..];
+    if let Some((base, size)) = env.sigstate.altstack {
+        uc[UC_STACK as usize..][..8].copy_from_slice(&base.to_le_bytes());
+        uc[UC_STACK as usize + 16..][..8].copy_from_slice(&size.to_le_bytes());
+    } else {
+        uc[UC_STACK as usize + 8..][..4].copy_from_slice(&SS_DISABLE.to_le_bytes());
+    }
+    uc[UC_SIGMASK as usize..][..8].copy_from_slice(&env.sigstate.mask.to_le_bytes());
+    let mcontext = &mut uc[UC_MCONTEXT as usize..];
+    // sc_regs has the pc in the slot of x0
+    for (i, value) in regs.x.iter().enumerate() {
+        let value = if i == 0 { regs.pc } else { *value };
+        mcontext[i * 8..][..8].copy_from_slice(&value.to_le_bytes());
+    }
+    for (i, value) in regs.f.iter().enumerate() {
+        mcontext[256 + i * 8..][..8].copy_from_slice(&value.to_le_bytes());
+    }
+    mcontext[512..516].copy_from_slice(&regs.csr.fcsr.to_le_bytes());
+    memory.write(frame, &data)?;
+    Ok(())
+}
+
+/// Return from a handler, restoring what the signal frame at `sp` saved.
+/// The engine calls this for the rt_sigreturn syscall, which unlike the
+/// others rewrites every register.
+pub fn sigreturn<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &mut Registers,
+) -> Result<(), Errno> {
+    let uc = regs.x[SP] + UC_OFFSET;
+    let mcontext = memory.read_vec(uc + UC_MCONTEXT, 32 * 8 + 516)?;
+    let word = |offset: usize| u64::from_le_bytes(mcontext[offset..offset + 8].try_into().unwrap());
+    regs.pc = word(0);
+    for i in 1..32 {
+        regs.x[i] = word(i * 8);
+    }
+    for i in 0..32 {
+        regs.f[i] = word(256 + i * 8);
+    }
+    regs.csr.fcsr = u32::from_le_bytes(mcontext[512..516].try_into().unwrap());
+    env.sigstate.mask = memory.read_u64(uc + UC_SIGMASK)? & !UNBLOCKABLE;
+    Ok(())
+}
+
+pub fn rt_sigaction<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    sig: u32,
+    act: u64,
+    oldact: u64,
+    sigsetsize: u64,
+) -> SyscallResult {
+    check_signal(sig)?;
+    if sigsetsize != 8 {
+        return Err(EINVAL);
+    }
+    let old = env.signals.action(sig);
+    if act != 0 {
+        if bit(sig) & UNBLOCKABLE != 0 {
+            return Err(EINVAL);
+        }
+        let action = SigAction {
+            handler: memory.read_u64(act)?,
+            flags: memory.read_u64(act + 8)?,
+            mask: memory.read_u64(act + 16)?,
+        };
+        env.signals.actions.lock().unwrap()[sig as usize - 1] = action;
+    }
+    if oldact != 0 {
+        memory.write_u64(oldact, old.handler)?;
+        memory.write_u64(oldact + 8, old.flags)?;
+        memory.write_u64(oldact + 16, old.mask)?;
+    }
+    Ok(0)
+}
+
+pub fn rt_sigprocmask<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    how: u32,
+    set: u64,
+    oldset: u64,
+    sigsetsize: u64,
+) -> SyscallResult {
+    if sigsetsize != 8 {
+        return Err(EINVAL);
+    }
+    let old = env.sigstate.mask;
+    if set != 0 {
+        let set = memory.read_u64(set)?;
+        env.sigstate.mask = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(EINVAL),
+        } & !UNBLOCKABLE;
+    }
+    if oldset != 0 {
+        memory.write_u64(oldset, old)?;
+    }
+    Ok(0)
+}
+
+pub fn rt_sigpending<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    set: u64,
+    sigsetsize: u64,
+) -> SyscallResult {
+    if sigsetsize != 8 {
+        return Err(EINVAL);
+    }
+    let pending = (env.signals.pending() | env.sigstate.pending) & env.sigstate.mask;
+    memory.write_u64(set, pending)?;
+    Ok(0)
+}
+
+pub fn sigaltstack<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    ss: u64,
+    old_ss: u64,
+    sp: u64,
+) -> SyscallResult {
+    let old = env.sigstate.altstack;
+    let on_stack = old.is_some_and(|(base, size)| (base..base + size).contains(&sp));
+    if ss != 0 {
+        if on_stack {
+            return Err(EPERM);
+        }
+        let base = memory.read_u64(ss)?;
+        let flags = memory.read_u32(ss + 8)?;
+        let size = memory.read_u64(ss + 16)?;
+        env.sigstate.altstack = match flags {
+            SS_DISABLE => None,
+            0 | SS_ONSTACK if size < MINSIGSTKSZ => return Err(ENOMEM),
+            0 | SS_ONSTACK => Some((base, size)),
+            _ => return Err(EINVAL),
+        };
+    }
+    if old_ss != 0 {
+        let (base, size) = old.unwrap_or_default();
+        let flags = match old {
+            None => SS_DISABLE,
+            Some(_) if on_stack => SS_ONSTACK,
+            Some(_) => 0,
+        };
+        memory.write_u64(old_ss, base)?;
+        memory.write_u32(old_ss + 8, flags)?;
+        memory.write_u64(old_ss + 16, size)?;
+    }
+    Ok(0)
+}
+
+pub fn kill(env: &mut SyscallEnv, pid: i32, sig: u32) -> SyscallResult {
+    // 0 and -1 reach the process group and everyone, which is just us
+    if pid != env.threads.pid() && pid != 0 && pid != -1 {
+        return Err(ESRCH);
+    }
+    if sig != 0 {
+        check_signal(sig)?;
+        env.signals.post(sig);
+    }
+    Ok(0)
+}
+
+/// tkill and tgkill. Signals for other threads of the process go to the
+/// whole process, since the env only knows its own thread.
+pub fn tgkill(env: &mut SyscallEnv, tgid: Option<i32>, tid: i32, sig: u32) -> SyscallResult {
+    if tid <= 0 || tgid.is_some_and(|tgid| tgid <= 0) {
+        return Err(EINVAL);
+    }
+    if tgid.is_some_and(|tgid| tgid != env.threads.pid()) || !env.threads.is_live(tid) {
+        return Err(ESRCH);
+    }
+    if sig != 0 {
+        check_signal(sig)?;
+        if tid == env.tid {
+            env.sigstate.post(sig);
+        } else {
+            env.signals.post(sig);
+        }
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::{Access, Perm};
+    use crate::runtime::config::{Config, OptLevel};
+    use crate::runtime::interp::{Interpreter, Stop};
+    use crate::runtime::syscall::testing::TestMemory;
+
+    #[test]
+    fn test_handler_roundtrip() {
+        let mut env = SyscallEnv::new(&Config::new());
+        env.signals.set_trampoline(0x1000);
+        let mut guest = TestMemory::new(&[(0x10000..0x20000, Perm::RW)]);
+        let mut memory = guest.memory();
+        let mut regs = Registers::new(0x4000, 0x20000);
+        regs.x[A0] = 7;
+        regs.f[3] = 0x3ff0_0000_0000_0000;
+
+        // sa_handler, SA_SIGINFO, sa_mask of SIGUSR2
+        memory.write_u64(0x10000, 0x5000).unwrap();
+        memory.write_u64(0x10008, SA_SIGINFO).unwrap();
+        memory.write_u64(0x10010, bit(SIGUSR2)).unwrap();
+        assert_eq!(rt_sigaction(&mut env, &mut memory, SIGUSR1, 0x10000, 0, 8), Ok(0));
+        assert_eq!(rt_sigaction(&mut env, &mut memory, SIGKILL, 0x10000, 0, 8), Err(EINVAL));
+
+        assert_eq!(deliver(&mut env, &mut memory, &mut regs), Delivery::None);
+        kill(&mut env, 1, SIGUSR1).unwrap();
+        let saved = regs.clone();
+        assert_eq!(deliver(&mut env, &mut memory, &mut regs), Delivery::Handler(SIGUSR1));
+        assert_eq!((regs.pc, regs.x[RA], regs.x[A0]), (0x5000, 0x1000, SIGUSR1 as u64));
+        assert_eq!(regs.x[SP] % 16, 0);
+        assert_eq!(memory.read_u32(regs.x[A1]).unwrap(), SIGUSR1);
+        assert_eq!(env.sigstate.mask, bit(SIGUSR1) | bit(SIGUSR2));
+
+        // a second SIGUSR1 waits until the handler returns
+        kill(&mut env, 0, SIGUSR1).unwrap();
+        assert_eq!(deliver(&mut env, &mut memory, &mut regs), Delivery::None);
+        regs.x[5] = 99;
+        sigreturn(&mut env, &mut memory, &mut regs).unwrap();
+        assert_eq!(regs, saved);
+        assert_eq!(env.sigstate.mask, 0);
+        assert_eq!(deliver(&mut env, &mut memory, &mut regs), Delivery::Handler(SIGUSR1));
+    }
+
+    #[test]
+    fn test_handler_runs() {
+        let mut code = Vec::new();
+        for insn in [
+            0x0ac0_0893u32, // addi a7, zero, 172
+            0x0000_0073,    // ecall, getpid
+            0x00a0_0593,    // addi a1, zero, SIGUSR1
+            0x0810_0893,    // addi a7, zero, 129
+            0x0000_0073,    // ecall, kill, returning into the handler
+            0x0001_12b7,    // lui t0, 0x11
+            0xf002_a503,    // lw a0, -256(t0)
+            0x05d0_0893,    // addi a7, zero, 93
+            0x0000_0073,    // ecall, exit with what the handler stored
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut handler = Vec::new();
+        for insn in [
+            0x0001_12b7u32, // lui t0, 0x11
+            0xf0a2_a023,    // sw a0, -256(t0)
+            0x0000_8067,    // ret, to the trampoline
+        ] {
+            handler.extend(insn.to_le_bytes());
+        }
+        let trampoline: Vec<u8> = SIGRETURN_TRAMPOLINE.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RWX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+        memory.init(0x10100, &handler).unwrap();
+        memory.init(0x10200, &trampoline).unwrap();
+
+        let config = Config::new().opt_level(OptLevel::Tiered { threshold: 100 });
+        let mut env = SyscallEnv::new(&config);
+        env.signals.set_trampoline(0x10200);
+        memory.write_u64(0x10800, 0x10100).unwrap();
+        rt_sigaction(&mut env, &mut memory, SIGUSR1, 0x10800, 0, 8).unwrap();
+        let mut regs = Registers::new(0x10000, 0x10e00);
+        let stop = Interpreter::new(&config).run(&mut env, &mut memory, &mut regs, u64::MAX);
+        assert_eq!(stop, Stop::Exit(GuestExit::Process(ExecutionResult::Exited(SIGUSR1 as i32))));
+        assert_eq!(env.sigstate.mask, 0);
+
+        // a fault without a handler of its own ends the process
+        let mut regs = Registers::new(0x20000, 0x10e00);
+        let mut env = SyscallEnv::new(&config);
+        let stop = Interpreter::new(&config).run(&mut env, &mut memory, &mut regs, u64::MAX);
+        assert!(matches!(stop, Stop::Fault(_)));
+        memory.write_u64(0x10800, 0x10100).unwrap();
+        rt_sigaction(&mut env, &mut memory, SIGSEGV, 0x10800, 0, 8).unwrap();
+        let mut interp = Interpreter::new(&config);
+        assert_eq!(interp.run(&mut env, &mut memory, &mut regs, 1), Stop::Yield);
+        assert_eq!((regs.pc, regs.x[A0]), (0x10100, SIGSEGV as u64));
+    }
+
+    #[test]
+    fn test_default_actions() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut guest = TestMemory::new(&[(0x10000..0x20000, Perm::RW)]);
+        let mut memory = guest.memory();
+        let mut regs = Registers::new(0x4000, 0x20000);
+
+        let tid = env.tid;
+        tgkill(&mut env, Some(1), tid, SIGCHLD).unwrap();
+        assert_eq!(deliver(&mut env, &mut memory, &mut regs), Delivery::None);
+        kill(&mut env, 1, SIGTERM).unwrap();
+        assert_eq!(deliver(&mut env, &mut memory, &mut regs), Delivery::Terminate(SIGTERM));
+
+        // a fault cannot be ignored
+        memory.write_u64(0x10000, SIG_IGN).unwrap();
+        rt_sigaction(&mut env, &mut memory, SIGSEGV, 0x10000, 0, 8).unwrap();
+        let fault = GuestFault::Unmapped {
+            vaddr: 0x30000,
+            access: Access::Read,
+        };
+        let info = SigInfo::from_fault(&fault);
+        assert_eq!((info.code, info.value), (SEGV_MAPERR, 0x30000));
+        assert_eq!(force(&mut env, &mut memory, &mut regs, info), Delivery::Terminate(SIGSEGV));
+        assert_eq!(kill(&mut env, 42, SIGTERM), Err(ESRCH));
+        assert_eq!(tgkill(&mut env, Some(1), 42, SIGTERM), Err(ESRCH));
+        assert_eq!(tgkill(&mut env, None, 42, 0), Err(ESRCH));
+
+        // a frame below address zero kills the process instead of wrapping
+        memory.write_u64(0x10000, 0x5000).unwrap();
+        rt_sigaction(&mut env, &mut memory, SIGUSR1, 0x10000, 0, 8).unwrap();
+        regs.x[SP] = 0x100;
+        kill(&mut env, 1, SIGUSR1).unwrap();
+        assert_eq!(deliver(&mut env, &mut memory, &mut regs), Delivery::Terminate(SIGSEGV));
+    }
+}
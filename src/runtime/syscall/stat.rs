@@ -0,0 +1,162 @@
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFIFO: u32 = 0o010000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFSOCK: u32 = 0o140000;
+
+pub const STATX_TYPE: u32 = 0x1;
+pub const STATX_MODE: u32 = 0x2;
+pub const STATX_NLINK: u32 = 0x4;
+pub const STATX_UID: u32 = 0x8;
+pub const STATX_GID: u32 = 0x10;
+pub const STATX_ATIME: u32 = 0x20;
+pub const STATX_MTIME: u32 = 0x40;
+pub const STATX_CTIME: u32 = 0x80;
+pub const STATX_INO: u32 = 0x100;
+pub const STATX_SIZE: u32 = 0x200;
+pub const STATX_BLOCKS: u32 = 0x400;
+/// Everything `struct stat` has, which is what statx always fills in
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+
+/// Size of `struct stat` on riscv64
+pub const STAT_LEN: usize = 128;
+/// Size of `struct statx`
+pub const STATX_LEN: usize = 256;
+
+/// Major number of a `dev_t`, glibc's `gnu_dev_major`
+pub fn major(dev: u64) -> u32 {
+    (((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)) as u32
+}
+
+/// Minor number of a `dev_t`, glibc's `gnu_dev_minor`
+pub fn minor(dev: u64) -> u32 {
+    ((dev & 0xff) | ((dev >> 12) & !0xff)) as u32
+}
+
+/// File attributes as both `struct stat` and `struct statx` report them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    pub size: i64,
+    pub blksize: i32,
+    pub blocks: i64,
+    pub atime: i64,
+    pub atime_nsec: i64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+}
+
+impl Stat {
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            mode: meta.mode(),
+            nlink: meta.nlink() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            rdev: meta.rdev(),
+            size: meta.size() as i64,
+            blksize: meta.blksize() as i32,
+            blocks: meta.blocks() as i64,
+            atime: meta.atime(),
+            atime_nsec: meta.atime_nsec(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+        }
+    }
+
+    /// riscv64 `struct stat`, as laid out by asm-generic/stat.h
+    pub fn to_bytes(&self) -> [u8; STAT_LEN] {
+        let mut stat = [0u8; STAT_LEN];
+        let mut put = |offset: usize, bytes: &[u8]| stat[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0, &self.dev.to_le_bytes());
+        put(8, &self.ino.to_le_bytes());
+        put(16, &self.mode.to_le_bytes());
+        put(20, &self.nlink.to_le_bytes());
+        put(24, &self.uid.to_le_bytes());
+        put(28, &self.gid.to_le_bytes());
+        put(32, &self.rdev.to_le_bytes());
+        // 40 is __pad1
+        put(48, &self.size.to_le_bytes());
+        put(56, &self.blksize.to_le_bytes());
+        // 60 is __pad2
+        put(64, &self.blocks.to_le_bytes());
+        put(72, &self.atime.to_le_bytes());
+        put(80, &self.atime_nsec.to_le_bytes());
+        put(88, &self.mtime.to_le_bytes());
+        put(96, &self.mtime_nsec.to_le_bytes());
+        put(104, &self.ctime.to_le_bytes());
+        put(112, &self.ctime_nsec.to_le_bytes());
+        stat
+    }
+
+    /// `struct statx` from linux/stat.h, with the basic stats filled in and
+    /// no birth time
+    pub fn to_statx(&self) -> [u8; STATX_LEN] {
+        let mut statx = [0u8; STATX_LEN];
+        let mut put = |offset: usize, bytes: &[u8]| statx[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0, &STATX_BASIC_STATS.to_le_bytes());
+        put(4, &(self.blksize as u32).to_le_bytes());
+        put(16, &self.nlink.to_le_bytes());
+        put(20, &self.uid.to_le_bytes());
+        put(24, &self.gid.to_le_bytes());
+        put(28, &(self.mode as u16).to_le_bytes());
+        put(32, &self.ino.to_le_bytes());
+        put(40, &self.size.to_le_bytes());
+        put(48, &self.blocks.to_le_bytes());
+        // struct statx_timestamp is { i64 tv_sec, u32 tv_nsec, i32 pad }
+        let mut timestamp = |offset: usize, sec: i64, nsec: i64| {
+            put(offset, &sec.to_le_bytes());
+            put(offset + 8, &(nsec as u32).to_le_bytes());
+        };
+        timestamp(64, self.atime, self.atime_nsec);
+        // 80 is the birth time
+        timestamp(96, self.ctime, self.ctime_nsec);
+        timestamp(112, self.mtime, self.mtime_nsec);
+        put(128, &major(self.rdev).to_le_bytes());
+        put(132, &minor(self.rdev).to_le_bytes());
+        put(136, &major(self.dev).to_le_bytes());
+        put(140, &minor(self.dev).to_le_bytes());
+        statx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layouts_agree() {
+        let meta = std::fs::metadata(std::env::temp_dir()).unwrap();
+        let stat = Stat::from_metadata(&meta);
+        let (bytes, statx) = (stat.to_bytes(), stat.to_statx());
+        let u32_at = |bytes: &[u8], offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at = |bytes: &[u8], offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+        assert_eq!(u32_at(&bytes, 16) & S_IFMT, S_IFDIR);
+        assert_eq!(u32_at(&statx, 28) & 0xffff, u32_at(&bytes, 16) & 0xffff);
+        assert_eq!(u64_at(&statx, 32), u64_at(&bytes, 8));
+        assert_eq!(u64_at(&statx, 40), u64_at(&bytes, 48));
+        assert_eq!(u64_at(&statx, 112), u64_at(&bytes, 88));
+        // makedev of the split device number gives the original back
+        let (major, minor) = (u32_at(&statx, 136) as u64, u32_at(&statx, 140) as u64);
+        let dev = ((major & 0xfff) << 8) | ((major & !0xfff) << 32) | (minor & 0xff) | ((minor & !0xff) << 12);
+        assert_eq!(dev, meta.dev());
+    }
+}
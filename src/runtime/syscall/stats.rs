@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::runtime::syscall::syscall_name;
+use crate::tools::perf::{Profiler, Timer, RUNTIME_SYSCALL};
+
+/// Calls of one syscall
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallStat {
+    pub calls: u64,
+    pub errors: u64,
+    /// Host time spent in the calls that returned
+    pub time: Duration,
+}
+
+/// Calls, failures and host time per syscall number, for seeing where the
+/// time of a guest goes in the kernel, e.g. futex spinning
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyscallStats {
+    stats: BTreeMap<u64, SyscallStat>,
+}
+
+impl SyscallStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The guest made syscall `nr`
+    pub fn call(&mut self, nr: u64) {
+        self.stats.entry(nr).or_default().calls += 1;
+    }
+
+    /// Syscall `nr` returned after `time`, failing or not. Calls ending the
+    /// thread do not return.
+    pub fn returned(&mut self, nr: u64, time: Duration, failed: bool) {
+        let stat = self.stats.entry(nr).or_default();
+        stat.time += time;
+        stat.errors += failed as u64;
+    }
+
+    pub fn get(&self, nr: u64) -> SyscallStat {
+        self.stats.get(&nr).copied().unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, SyscallStat)> + '_ {
+        self.stats.iter().map(|(&nr, &stat)| (nr, stat))
+    }
+
+    /// A table like `strace -c` prints, the syscalls taking the most time
+    /// first
+    pub fn summary(&self) -> String {
+        let total = self.stats.values().map(|stat| stat.time).sum::<Duration>();
+        let mut stats: Vec<_> = self.iter().collect();
+        stats.sort_by(|(a_nr, a), (b_nr, b)| b.time.cmp(&a.time).then(b.calls.cmp(&a.calls)).then(a_nr.cmp(b_nr)));
+        let mut out = String::new();
+        writeln!(out, "% time     seconds  usecs/call     calls    errors syscall").unwrap();
+        writeln!(out, "------ ----------- ----------- --------- --------- ----------------").unwrap();
+        for (nr, stat) in &stats {
+            let share = match total.is_zero() {
+                true => 0.0,
+                false => stat.time.as_secs_f64() * 100.0 / total.as_secs_f64(),
+            };
+            let per_call = stat.time.as_micros() as u64 / stat.calls.max(1);
+            write!(out, "{:>6.2} {:>11.6} {:>11} {:>9} ", share, stat.time.as_secs_f64(), per_call, stat.calls).unwrap();
+            match stat.errors {
+                0 => out.push_str("          "),
+                errors => write!(out, "{:>9} ", errors).unwrap(),
+            }
+            match syscall_name(*nr) {
+                Some(name) => writeln!(out, "{}", name).unwrap(),
+                None => writeln!(out, "syscall_{}", nr).unwrap(),
+            }
+        }
+        let (calls, errors) = stats.iter().fold((0, 0), |(calls, errors), (_, stat)| (calls + stat.calls, errors + stat.errors));
+        writeln!(out, "------ ----------- ----------- --------- --------- ----------------").unwrap();
+        writeln!(out, "100.00 {:>11.6} {:>11} {:>9} {:>9} total", total.as_secs_f64(), "", calls, errors).unwrap();
+        out
+    }
+
+    /// Add the calls to `profiler`, as the timers `runtime.syscall.<name>`
+    /// and the counters `runtime.syscall.<name>.errors` of the syscalls that
+    /// failed
+    pub fn report(&self, profiler: &Profiler) {
+        for (nr, stat) in self.iter() {
+            let name = timer_name(nr);
+            profiler.add(&name, Timer { total: stat.time, count: stat.calls });
+            if stat.errors > 0 {
+                profiler.count(&format!("{}.errors", name), stat.errors);
+            }
+        }
+    }
+}
+
+/// The timer of syscall `nr` in a profiler, `runtime.syscall.<name>`
+pub fn timer_name(nr: u64) -> String {
+    match syscall_name(nr) {
+        Some(name) => format!("{}.{}", RUNTIME_SYSCALL, name),
+        None => format!("{}.syscall_{}", RUNTIME_SYSCALL, nr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::testing::TestMemory;
+    use crate::runtime::syscall::{syscall_handler, SyscallEnv, SYS_CLOSE, SYS_FUTEX, SYS_GETPID};
+
+    #[test]
+    fn test_stats() {
+        let mut env = SyscallEnv::new(&Config::new().syscall_stats(true));
+        let mut guest = TestMemory::new(&[]);
+        let mut memory = guest.memory();
+        for nr in [SYS_GETPID, SYS_GETPID, SYS_CLOSE] {
+            syscall_handler(&mut env, &mut memory, nr, [99, 0, 0, 0, 0, 0]);
+        }
+        let measured = env.syscall_stats.as_ref().unwrap().lock().unwrap().clone();
+        assert_eq!((measured.get(SYS_GETPID).calls, measured.get(SYS_GETPID).errors), (2, 0));
+        assert_eq!((measured.get(SYS_CLOSE).calls, measured.get(SYS_CLOSE).errors), (1, 1));
+
+        // the same calls with fixed times, the host's ones vary
+        let mut stats = SyscallStats::new();
+        for (nr, time, failed) in [
+            (SYS_GETPID, 0, false),
+            (SYS_GETPID, 0, false),
+            (SYS_CLOSE, 0, true),
+            (SYS_FUTEX, 30, false),
+        ] {
+            stats.call(nr);
+            stats.returned(nr, Duration::from_millis(time), failed);
+        }
+        let summary = stats.summary();
+        let lines: Vec<_> = summary.lines().collect();
+        assert_eq!(lines.len(), 7);
+        // futex takes all of the time and goes first
+        assert!(lines[2].starts_with("100.00    0.030000       30000") && lines[2].ends_with("         1           futex"));
+        assert!(summary.contains("        1         1 close\n"));
+        assert!(lines[6].ends_with("        4         1 total"));
+
+        let profiler = Profiler::new();
+        stats.report(&profiler);
+        assert_eq!(profiler.timer("runtime.syscall.futex").unwrap().total, Duration::from_millis(30));
+        assert_eq!(profiler.timer("runtime.syscall.getpid").unwrap().count, 2);
+        assert_eq!(profiler.counter("runtime.syscall.close.errors"), 1);
+    }
+
+    #[test]
+    fn test_profiler() {
+        let profiler = Profiler::new();
+        let mut env = SyscallEnv::new(&Config::new().profiler(profiler.clone()));
+        let mut guest = TestMemory::new(&[]);
+        let mut memory = guest.memory();
+        for nr in [SYS_GETPID, SYS_CLOSE, SYS_CLOSE] {
+            syscall_handler(&mut env, &mut memory, nr, [99, 0, 0, 0, 0, 0]);
+        }
+        // without syscall_stats the calls go straight into the profiler
+        assert!(env.syscall_stats.is_none());
+        assert_eq!(profiler.timer(RUNTIME_SYSCALL).unwrap().count, 3);
+        assert_eq!(profiler.timer("runtime.syscall.close").unwrap().count, 2);
+        assert_eq!(profiler.counter("runtime.syscall.close.errors"), 2);
+        assert_eq!(profiler.counter("runtime.syscall.getpid.errors"), 0);
+    }
+}
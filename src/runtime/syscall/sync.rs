@@ -0,0 +1,101 @@
+use std::sync::atomic::{fence, Ordering};
+
+use super::{SyscallEnv, SyscallResult, EINVAL, EPERM};
+
+/// Flush only the caller's hart, which the engine does not distinguish
+pub const SYS_RISCV_FLUSH_ICACHE_LOCAL: u64 = 1;
+
+pub const MEMBARRIER_CMD_QUERY: u32 = 0;
+pub const MEMBARRIER_CMD_GLOBAL: u32 = 1 << 0;
+pub const MEMBARRIER_CMD_GLOBAL_EXPEDITED: u32 = 1 << 1;
+pub const MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED: u32 = 1 << 2;
+pub const MEMBARRIER_CMD_PRIVATE_EXPEDITED: u32 = 1 << 3;
+pub const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED: u32 = 1 << 4;
+pub const MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE: u32 = 1 << 5;
+pub const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE: u32 = 1 << 6;
+
+/// The commands membarrier answers, what `MEMBARRIER_CMD_QUERY` reports
+const MEMBARRIER_SUPPORTED: u32 = MEMBARRIER_CMD_GLOBAL
+    | MEMBARRIER_CMD_GLOBAL_EXPEDITED
+    | MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED
+    | MEMBARRIER_CMD_PRIVATE_EXPEDITED
+    | MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED
+    | MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE
+    | MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE;
+
+/// Make code the guest wrote between `start` and `end` visible to its
+/// instruction fetch, dropping what was decoded from there
+pub fn riscv_flush_icache(env: &mut SyscallEnv, start: u64, end: u64, flags: u64) -> SyscallResult {
+    if flags & !SYS_RISCV_FLUSH_ICACHE_LOCAL != 0 {
+        return Err(EINVAL);
+    }
+    env.code.lock().unwrap().invalidate(start..end.max(start));
+    Ok(0)
+}
+
+/// Barriers across the threads of the process. Every guest thread runs on a
+/// host thread that synchronizes with the others through the syscall
+/// layer's locks, so a full fence on the caller is all that is left to do.
+pub fn membarrier(env: &mut SyscallEnv, cmd: u32, flags: u32) -> SyscallResult {
+    if flags != 0 {
+        return Err(EINVAL);
+    }
+    let threads = &env.threads;
+    match cmd {
+        MEMBARRIER_CMD_QUERY => return Ok(MEMBARRIER_SUPPORTED as u64),
+        MEMBARRIER_CMD_REGISTER_GLOBAL_EXPEDITED
+        | MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED
+        | MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE => {
+            threads.register_membarrier(cmd);
+            return Ok(0);
+        }
+        MEMBARRIER_CMD_GLOBAL | MEMBARRIER_CMD_GLOBAL_EXPEDITED => {}
+        MEMBARRIER_CMD_PRIVATE_EXPEDITED | MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE => {
+            // the private commands need the matching registration first
+            if threads.membarrier_registered() & (cmd << 1) == 0 {
+                return Err(EPERM);
+            }
+            if cmd == MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE {
+                // other threads may run code written before the barrier
+                env.code.lock().unwrap().clear();
+            }
+        }
+        _ => return Err(EINVAL),
+    }
+    fence(Ordering::SeqCst);
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::instruction::{Instr, Instruction};
+    use crate::runtime::config::Config;
+
+    #[test]
+    fn test_icache_and_barriers() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let instruction = Instruction { instr: Instr::NOP };
+        {
+            let mut code = env.code.lock().unwrap();
+            code.set(0x1000, instruction.clone());
+            code.set(0x1ffe, instruction.clone());
+            code.set(0x3000, instruction);
+        }
+        assert_eq!(riscv_flush_icache(&mut env, 0x2000, 0x3000, 2), Err(EINVAL));
+        assert_eq!(riscv_flush_icache(&mut env, 0x2000, 0x3000, 0), Ok(0));
+        let mut code = env.code.lock().unwrap();
+        assert_eq!(code.generation(), 1);
+        assert!(code.get(0x1000).is_some());
+        assert!(code.get(0x1ffe).is_none());
+        assert!(code.get(0x3000).is_some());
+        drop(code);
+
+        assert_eq!(membarrier(&mut env, MEMBARRIER_CMD_QUERY, 0), Ok(MEMBARRIER_SUPPORTED as u64));
+        assert_eq!(membarrier(&mut env, MEMBARRIER_CMD_PRIVATE_EXPEDITED, 0), Err(EPERM));
+        assert_eq!(membarrier(&mut env, MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED, 0), Ok(0));
+        assert_eq!(membarrier(&mut env, MEMBARRIER_CMD_PRIVATE_EXPEDITED, 0), Ok(0));
+        assert_eq!(membarrier(&mut env, MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE, 0), Err(EPERM));
+        assert_eq!(membarrier(&mut env, MEMBARRIER_CMD_GLOBAL, 1), Err(EINVAL));
+    }
+}
@@ -0,0 +1,263 @@
+use super::fd::MAX_FDS;
+use super::{SyscallEnv, SyscallResult, EINVAL, EPERM, ESRCH};
+use crate::middleend::address_map::{AddressMap, GuestMemory, LinearMemory};
+
+pub const GRND_NONBLOCK: u32 = 0x1;
+pub const GRND_RANDOM: u32 = 0x2;
+pub const GRND_INSECURE: u32 = 0x4;
+
+pub const RUSAGE_SELF: i32 = 0;
+pub const RUSAGE_CHILDREN: i32 = -1;
+pub const RUSAGE_THREAD: i32 = 1;
+/// Size of `struct rusage`
+pub const RUSAGE_LEN: usize = 144;
+
+pub const RLIMIT_CPU: u32 = 0;
+pub const RLIMIT_FSIZE: u32 = 1;
+pub const RLIMIT_DATA: u32 = 2;
+pub const RLIMIT_STACK: u32 = 3;
+pub const RLIMIT_CORE: u32 = 4;
+pub const RLIMIT_RSS: u32 = 5;
+pub const RLIMIT_NPROC: u32 = 6;
+pub const RLIMIT_NOFILE: u32 = 7;
+pub const RLIMIT_MEMLOCK: u32 = 8;
+pub const RLIMIT_AS: u32 = 9;
+pub const RLIMIT_LOCKS: u32 = 10;
+pub const RLIMIT_SIGPENDING: u32 = 11;
+pub const RLIMIT_MSGQUEUE: u32 = 12;
+pub const RLIMIT_NICE: u32 = 13;
+pub const RLIMIT_RTPRIO: u32 = 14;
+pub const RLIMIT_RTTIME: u32 = 15;
+pub const RLIM_NLIMITS: usize = 16;
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Largest request served at once, larger ones complete short as on linux
+const MAX_GETRANDOM: usize = 1 << 20;
+
+/// Soft and hard limit of a resource, `struct rlimit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rlimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+/// Resource limits of the process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rlimits {
+    limits: [Rlimit; RLIM_NLIMITS],
+}
+
+impl Rlimits {
+    /// The limits a linux login shell starts with, the descriptor limit
+    /// being what the descriptor table holds
+    pub fn new() -> Self {
+        let unlimited = Rlimit {
+            cur: RLIM_INFINITY,
+            max: RLIM_INFINITY,
+        };
+        let mut limits = [unlimited; RLIM_NLIMITS];
+        let mut set = |resource: u32, cur: u64, max: u64| limits[resource as usize] = Rlimit { cur, max };
+        set(RLIMIT_STACK, 8 << 20, RLIM_INFINITY);
+        set(RLIMIT_CORE, 0, RLIM_INFINITY);
+        set(RLIMIT_NOFILE, MAX_FDS as u64, MAX_FDS as u64);
+        set(RLIMIT_MEMLOCK, 8 << 20, 8 << 20);
+        set(RLIMIT_MSGQUEUE, 819200, 819200);
+        set(RLIMIT_NICE, 0, 0);
+        set(RLIMIT_RTPRIO, 0, 0);
+        Self { limits }
+    }
+
+    pub fn get(&self, resource: u32) -> Option<Rlimit> {
+        self.limits.get(resource as usize).copied()
+    }
+}
+
+impl Default for Rlimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn getrandom<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    buf: u64,
+    count: u64,
+    flags: u32,
+) -> SyscallResult {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0
+        || flags & (GRND_RANDOM | GRND_INSECURE) == GRND_RANDOM | GRND_INSECURE
+    {
+        return Err(EINVAL);
+    }
+    let mut data = vec![0; (count as usize).min(MAX_GETRANDOM)];
+    env.entropy.fill(&mut data);
+    memory.write(buf, &data)?;
+    Ok(data.len() as u64)
+}
+
+/// `struct rusage` of the process. The guest clock's processor time counts
+/// as user time, and the max RSS is the memory mapped outside the mmap area
+/// plus the most of the area ever in use.
+fn rusage(env: &SyscallEnv, map: &AddressMap) -> [u8; RUSAGE_LEN] {
+    let cpu = env.clock.cpu_time();
+    let mm = env.mm.lock().unwrap();
+    let area = mm.area();
+    let outside: u64 = map
+        .regions()
+        .iter()
+        .filter(|region| !(area.start <= region.vaddr.start && region.vaddr.end <= area.end))
+        .map(|region| region.vaddr.end - region.vaddr.start)
+        .sum();
+    let mut usage = [0; RUSAGE_LEN];
+    usage[..8].copy_from_slice(&cpu.as_secs().to_le_bytes());
+    usage[8..16].copy_from_slice(&(cpu.subsec_micros() as u64).to_le_bytes());
+    // 16 is the system time, which the guest never spends
+    usage[32..40].copy_from_slice(&((outside + mm.peak()) / 1024).to_le_bytes());
+    usage
+}
+
+pub fn getrusage<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    who: i32,
+    usage: u64,
+) -> SyscallResult {
+    let bytes = match who {
+        // threads are not accounted for separately
+        RUSAGE_SELF | RUSAGE_THREAD => rusage(env, memory.map()),
+        // the usage of children is not collected
+        RUSAGE_CHILDREN => [0; RUSAGE_LEN],
+        _ => return Err(EINVAL),
+    };
+    memory.write(usage, &bytes)?;
+    Ok(0)
+}
+
+pub fn prlimit64<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    pid: i32,
+    resource: u32,
+    new: u64,
+    old: u64,
+) -> SyscallResult {
+    // the limits of other processes are out of reach
+    if pid != 0 && pid != env.threads.pid() {
+        return Err(ESRCH);
+    }
+    let new = match new {
+        0 => None,
+        _ => Some(Rlimit {
+            cur: memory.read_u64(new)?,
+            max: memory.read_u64(new + 8)?,
+        }),
+    };
+    let mut rlimits = env.rlimits.lock().unwrap();
+    let current = rlimits.get(resource).ok_or(EINVAL)?;
+    if let Some(new) = new {
+        if new.cur > new.max {
+            return Err(EINVAL);
+        }
+        // without CAP_SYS_RESOURCE hard limits only come down, which keeps
+        // the descriptor table within MAX_FDS like linux's nr_open
+        if new.max > current.max {
+            return Err(EPERM);
+        }
+    }
+    if old != 0 {
+        memory.write_u64(old, current.cur)?;
+        memory.write_u64(old + 8, current.max)?;
+    }
+    if let Some(new) = new {
+        rlimits.limits[resource as usize] = new;
+        if resource == RLIMIT_NOFILE {
+            env.fds.lock().unwrap().set_limit(new.cur as usize);
+        }
+    }
+    Ok(0)
+}
+
+pub fn getrlimit<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    resource: u32,
+    rlim: u64,
+) -> SyscallResult {
+    prlimit64(env, memory, 0, resource, 0, rlim)
+}
+
+pub fn setrlimit<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    resource: u32,
+    rlim: u64,
+) -> SyscallResult {
+    prlimit64(env, memory, 0, resource, rlim, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::clock::ClockMode;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::mm::{mmap, Mappings, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ};
+    use crate::runtime::syscall::testing::TestMemory;
+
+    fn random_bytes(config: &Config) -> Vec<u8> {
+        let mut env = SyscallEnv::new(config);
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+        assert_eq!(getrandom(&mut env, &mut memory, 0x10000, 64, 0), Ok(64));
+        assert_eq!(getrandom(&mut env, &mut memory, 0x10000, 64, 6), Err(EINVAL));
+        memory.read_vec(0x10000, 64).unwrap()
+    }
+
+    #[test]
+    fn test_seeded_getrandom() {
+        let seeded = Config::new().seed(7);
+        assert_eq!(random_bytes(&seeded), random_bytes(&seeded));
+        assert_ne!(random_bytes(&seeded), random_bytes(&Config::new().seed(8)));
+        assert_ne!(random_bytes(&Config::new()), vec![0; 64]);
+    }
+
+    #[test]
+    fn test_rusage_and_limits() {
+        let config = Config::new().clock(ClockMode::Virtual {
+            instructions_per_sec: 1000,
+        });
+        let mut env = SyscallEnv::new(&config);
+        let mut guest = TestMemory::new(&[(0x10000..0x12000, Perm::RW)]);
+        *env.mm.lock().unwrap() = Mappings::reserve(&mut guest.map, 0x100000, 0x10000).unwrap();
+        let mut memory = guest.memory();
+
+        env.clock.retire(2500);
+        mmap(&mut env, &mut memory, 0, 0x3000, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0).unwrap();
+        assert_eq!(getrusage(&mut env, &mut memory, RUSAGE_SELF, 0x10000), Ok(0));
+        assert_eq!(memory.read_u64(0x10000).unwrap(), 2);
+        assert_eq!(memory.read_u64(0x10008).unwrap(), 500_000);
+        // 8 KiB of image plus the 12 KiB mapping
+        assert_eq!(memory.read_u64(0x10020).unwrap(), 20);
+
+        assert_eq!(getrlimit(&mut env, &mut memory, RLIMIT_NOFILE, 0x10100), Ok(0));
+        assert_eq!(memory.read_u64(0x10100).unwrap(), MAX_FDS as u64);
+        memory.write_u64(0x10100, 4).unwrap();
+        assert_eq!(setrlimit(&mut env, &mut memory, RLIMIT_NOFILE, 0x10100), Ok(0));
+        assert_eq!(env.fds.lock().unwrap().limit(), 4);
+        memory.write_u64(0x10108, RLIM_INFINITY).unwrap();
+        assert_eq!(setrlimit(&mut env, &mut memory, RLIMIT_NOFILE, 0x10100), Err(EPERM));
+        // a lowered hard limit stays down, and soft limits stay below it
+        memory.write_u64(0x10100, 1 << 20).unwrap();
+        memory.write_u64(0x10108, 1 << 20).unwrap();
+        assert_eq!(setrlimit(&mut env, &mut memory, RLIMIT_STACK, 0x10100), Ok(0));
+        memory.write_u64(0x10108, 2 << 20).unwrap();
+        assert_eq!(setrlimit(&mut env, &mut memory, RLIMIT_STACK, 0x10100), Err(EPERM));
+        memory.write_u64(0x10100, 2 << 20).unwrap();
+        memory.write_u64(0x10108, 1 << 20).unwrap();
+        assert_eq!(setrlimit(&mut env, &mut memory, RLIMIT_STACK, 0x10100), Err(EINVAL));
+        assert_eq!(env.rlimits.lock().unwrap().get(RLIMIT_STACK).unwrap().max, 1 << 20);
+        assert_eq!(prlimit64(&mut env, &mut memory, 0, RLIM_NLIMITS as u32, 0, 0x10100), Err(EINVAL));
+        assert_eq!(prlimit64(&mut env, &mut memory, 42, RLIMIT_CPU, 0, 0x10100), Err(ESRCH));
+    }
+}
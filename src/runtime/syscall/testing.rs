@@ -0,0 +1,38 @@
+use std::ops::Range;
+
+use super::{syscall_handler, SyscallEnv};
+use crate::middleend::address_map::{AddressMap, GuestMemory, Perm};
+
+/// Size of the linear memory behind a test guest
+pub const MEMORY_SIZE: usize = 1 << 20;
+
+/// The address map and linear memory of a test guest
+pub struct TestMemory {
+    pub map: AddressMap,
+    pub linear: Vec<u8>,
+}
+
+impl TestMemory {
+    /// A zeroed memory of `MEMORY_SIZE` bytes with `regions` mapped
+    pub fn new(regions: &[(Range<u64>, Perm)]) -> Self {
+        let mut map = AddressMap::new(MEMORY_SIZE);
+        for (range, perm) in regions {
+            map.map(range.clone(), *perm).unwrap();
+        }
+        Self {
+            map,
+            linear: vec![0; MEMORY_SIZE],
+        }
+    }
+
+    /// The memory as syscalls and the interpreter access it
+    pub fn memory(&mut self) -> GuestMemory<'_, Vec<u8>> {
+        GuestMemory::new(&mut self.map, &mut self.linear)
+    }
+}
+
+/// Make syscall `nr`, returning what the guest finds in a0, a negated
+/// errno when it failed
+pub fn call(env: &mut SyscallEnv, memory: &mut GuestMemory<Vec<u8>>, nr: u64, args: [u64; 6]) -> i64 {
+    syscall_handler(env, memory, nr, args) as i64
+}
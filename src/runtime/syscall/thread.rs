@@ -0,0 +1,596 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+use super::exec::{self, ProcessSpawner};
+use super::signal::{SignalTable, SIGCHLD};
+use super::time::read_timespec;
+use super::{Errno, SyscallEnv, SyscallResult};
+use super::{EAGAIN, ECHILD, EINVAL, ENOSYS, ETIMEDOUT};
+use crate::middleend::address_map::{AddressMap, GuestMemory, LinearMemory};
+use crate::runtime::execution::{ExecutionResult, GuestExit};
+use crate::runtime::regs::{Registers, A0, SP, TP};
+
+pub const CLONE_VM: u64 = 0x100;
+pub const CLONE_FS: u64 = 0x200;
+pub const CLONE_FILES: u64 = 0x400;
+pub const CLONE_SIGHAND: u64 = 0x800;
+pub const CLONE_VFORK: u64 = 0x4000;
+pub const CLONE_THREAD: u64 = 0x10000;
+pub const CLONE_SYSVSEM: u64 = 0x40000;
+pub const CLONE_SETTLS: u64 = 0x80000;
+pub const CLONE_PARENT_SETTID: u64 = 0x100000;
+pub const CLONE_CHILD_CLEARTID: u64 = 0x200000;
+pub const CLONE_DETACHED: u64 = 0x400000;
+pub const CLONE_CHILD_SETTID: u64 = 0x1000000;
+
+/// What pthread_create passes, the flags a guest thread must share
+const CLONE_THREAD_FLAGS: u64 = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+
+pub const FUTEX_WAIT: u32 = 0;
+pub const FUTEX_WAKE: u32 = 1;
+pub const FUTEX_REQUEUE: u32 = 3;
+pub const FUTEX_CMP_REQUEUE: u32 = 4;
+pub const FUTEX_WAIT_BITSET: u32 = 9;
+pub const FUTEX_WAKE_BITSET: u32 = 10;
+pub const FUTEX_PRIVATE_FLAG: u32 = 128;
+pub const FUTEX_CLOCK_REALTIME: u32 = 256;
+pub const FUTEX_BITSET_MATCH_ANY: u32 = u32::MAX;
+
+/// A guest thread created by clone, for the engine to run on a host thread
+/// over the memory of the process, with a copy of the parent's address map
+pub struct NewThread {
+    pub env: SyscallEnv,
+    pub stack: u64,
+    pub tls: Option<u64>,
+    /// The registers of the parent except `a0 = 0`, `sp = stack` when it is
+    /// non-zero and `tp = tls` when given
+    pub regs: Registers,
+    pub map: AddressMap,
+}
+
+/// Callback through which clone hands new threads to the execution engine
+pub type ThreadSpawner = Arc<dyn Fn(NewThread) -> Result<(), Errno> + Send + Sync>;
+
+struct Waiter {
+    bitset: u32,
+    woken: Mutex<bool>,
+    condvar: Condvar,
+}
+
+/// The process a forked child reports its end to
+struct Parent {
+    threads: Arc<Threads>,
+    signals: Arc<SignalTable>,
+}
+
+/// State of the thread group, shared by every thread of the guest
+pub struct Threads {
+    pid: i32,
+    /// Allocator of thread and process ids, shared by every process forked
+    /// from the first guest, which has its own pid namespace
+    ids: Arc<AtomicI32>,
+    parent: Option<Parent>,
+    /// Forked children that have not been waited for, with how they ended
+    /// once they did
+    children: Mutex<HashMap<i32, Option<ExecutionResult>>>,
+    /// Signalled whenever a child ends
+    child_changed: Condvar,
+    /// Threads that have not exited yet
+    live: AtomicUsize,
+    /// Their thread ids
+    tids: Mutex<HashSet<i32>>,
+    /// How the process ended, once one of its threads ended it
+    exit: Mutex<Option<ExecutionResult>>,
+    /// Signalled when it does
+    exited: Condvar,
+    /// Waiters by futex address, in the order they started waiting
+    futexes: Mutex<HashMap<u64, VecDeque<Arc<Waiter>>>>,
+    spawner: Mutex<Option<ThreadSpawner>>,
+    process_spawner: Mutex<Option<ProcessSpawner>>,
+    /// membarrier commands the process registered for
+    membarrier: AtomicU32,
+}
+
+impl Threads {
+    /// A thread group whose leader has `pid` as thread id
+    pub fn new(pid: i32) -> Self {
+        Self::with_ids(pid, Arc::new(AtomicI32::new(pid + 1)), None)
+    }
+
+    fn with_ids(pid: i32, ids: Arc<AtomicI32>, parent: Option<Parent>) -> Self {
+        Self {
+            pid,
+            ids,
+            parent,
+            children: Mutex::new(HashMap::new()),
+            child_changed: Condvar::new(),
+            live: AtomicUsize::new(1),
+            tids: Mutex::new(HashSet::from([pid])),
+            exit: Mutex::new(None),
+            exited: Condvar::new(),
+            futexes: Mutex::new(HashMap::new()),
+            spawner: Mutex::new(None),
+            process_spawner: Mutex::new(None),
+            membarrier: AtomicU32::new(0),
+        }
+    }
+
+    /// The thread group of a new child process, whose end is reported to
+    /// this one and `signals` gets SIGCHLD for. The spawners carry over.
+    pub fn fork(self: &Arc<Self>, signals: Arc<SignalTable>) -> Self {
+        let pid = self.next_id();
+        let child = Self::with_ids(
+            pid,
+            self.ids.clone(),
+            Some(Parent {
+                threads: self.clone(),
+                signals,
+            }),
+        );
+        *child.spawner.lock().unwrap() = self.spawner.lock().unwrap().clone();
+        *child.process_spawner.lock().unwrap() = self.process_spawner.lock().unwrap().clone();
+        self.children.lock().unwrap().insert(pid, None);
+        child
+    }
+
+    /// Drop the child `pid` again, when it never got to run
+    pub fn forget_child(&self, pid: i32) {
+        self.children.lock().unwrap().remove(&pid);
+    }
+
+    /// Reap a child that ended, any child or only `pid`, and return its pid
+    /// and how it ended. With `block` wait until one does, otherwise `None`
+    /// tells that none has yet.
+    pub fn wait_child(&self, pid: Option<i32>, block: bool) -> Result<Option<(i32, ExecutionResult)>, Errno> {
+        let matches = |child: i32| pid.is_none_or(|pid| pid == child);
+        let mut children = self.children.lock().unwrap();
+        loop {
+            if !children.keys().any(|&child| matches(child)) {
+                return Err(ECHILD);
+            }
+            let ended = children
+                .iter()
+                .find_map(|(&child, &result)| result.filter(|_| matches(child)).map(|result| (child, result)));
+            if let Some((child, result)) = ended {
+                children.remove(&child);
+                return Ok(Some((child, result)));
+            }
+            if !block {
+                return Ok(None);
+            }
+            children = self.child_changed.wait(children).unwrap();
+        }
+    }
+
+    fn next_id(&self) -> i32 {
+        self.ids.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// Whether `tid` is a thread of the process that has not exited
+    pub fn is_live(&self, tid: i32) -> bool {
+        self.tids.lock().unwrap().contains(&tid)
+    }
+
+    pub fn set_spawner(&self, spawner: ThreadSpawner) {
+        *self.spawner.lock().unwrap() = Some(spawner);
+    }
+
+    pub fn set_process_spawner(&self, spawner: ProcessSpawner) {
+        *self.process_spawner.lock().unwrap() = Some(spawner);
+    }
+
+    pub fn process_spawner(&self) -> Option<ProcessSpawner> {
+        self.process_spawner.lock().unwrap().clone()
+    }
+
+    /// Forget the other threads, which execve ends, leaving the caller alone
+    pub fn exec(&self) {
+        self.live.store(1, Ordering::SeqCst);
+        *self.tids.lock().unwrap() = HashSet::from([self.pid]);
+        self.membarrier.store(0, Ordering::SeqCst);
+    }
+
+    pub fn register_membarrier(&self, cmds: u32) {
+        self.membarrier.fetch_or(cmds, Ordering::SeqCst);
+    }
+
+    pub fn membarrier_registered(&self) -> u32 {
+        self.membarrier.load(Ordering::SeqCst)
+    }
+
+    /// How the process ended, which every other thread of the engine checks
+    /// between blocks to stop once one thread ended the process
+    pub fn exit_status(&self) -> Option<ExecutionResult> {
+        *self.exit.lock().unwrap()
+    }
+
+    /// Block until one of the threads ends the process, and tell how
+    pub fn wait_exit(&self) -> ExecutionResult {
+        let exit = self.exit.lock().unwrap();
+        self.exited.wait_while(exit, |exit| exit.is_none()).unwrap().unwrap()
+    }
+
+    /// End the process with `result`, unless another thread ended it first.
+    /// The first end is reported to the parent process, if there is one.
+    pub fn terminate(&self, result: ExecutionResult) -> ExecutionResult {
+        let mut exit = self.exit.lock().unwrap();
+        if exit.is_none() {
+            *exit = Some(result);
+            self.exited.notify_all();
+            if let Some(parent) = &self.parent {
+                parent.threads.children.lock().unwrap().insert(self.pid, Some(result));
+                parent.threads.child_changed.notify_all();
+                parent.signals.post(SIGCHLD);
+            }
+        }
+        exit.unwrap()
+    }
+
+    /// Block while the futex word at `addr` holds `expected`, until woken or
+    /// until `deadline` passes
+    pub fn wait<M: LinearMemory + ?Sized>(
+        &self,
+        memory: &GuestMemory<M>,
+        addr: u64,
+        expected: u32,
+        bitset: u32,
+        deadline: Option<Instant>,
+    ) -> Result<(), Errno> {
+        let waiter = Arc::new(Waiter {
+            bitset,
+            woken: Mutex::new(false),
+            condvar: Condvar::new(),
+        });
+        {
+            // compare and enqueue under the table lock, so a waker that
+            // changed the word first cannot slip in between
+            let mut futexes = self.futexes.lock().unwrap();
+            if memory.read_u32(addr)? != expected {
+                return Err(EAGAIN);
+            }
+            futexes.entry(addr).or_default().push_back(waiter.clone());
+        }
+        let mut woken = waiter.woken.lock().unwrap();
+        while !*woken {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    woken = waiter.condvar.wait_timeout(woken, deadline - now).unwrap().0;
+                }
+                None => woken = waiter.condvar.wait(woken).unwrap(),
+            }
+        }
+        if *woken {
+            return Ok(());
+        }
+        drop(woken);
+        // timed out, unless a wake raced with the timeout
+        let mut futexes = self.futexes.lock().unwrap();
+        let queue = futexes.get_mut(&addr);
+        match queue.and_then(|q| q.iter().position(|w| Arc::ptr_eq(w, &waiter)).map(|i| (q, i))) {
+            Some((queue, index)) => {
+                queue.remove(index);
+                Err(ETIMEDOUT)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Wake up to `count` waiters on `addr` whose bitset intersects `bitset`
+    pub fn wake(&self, addr: u64, count: u32, bitset: u32) -> u32 {
+        wake_waiters(&mut self.futexes.lock().unwrap(), addr, count, bitset)
+    }
+
+    /// Wake `count` waiters on `from` and move up to `requeue` of the rest
+    /// over to wait on `to`, under one lock so no waiter comes or goes in
+    /// between
+    pub fn requeue(&self, from: u64, to: u64, count: u32, requeue: u32) -> u32 {
+        let mut futexes = self.futexes.lock().unwrap();
+        let woken = wake_waiters(&mut futexes, from, count, FUTEX_BITSET_MATCH_ANY);
+        let moved: Vec<_> = match futexes.get_mut(&from) {
+            Some(queue) => {
+                let n = (requeue as usize).min(queue.len());
+                queue.drain(..n).collect()
+            }
+            None => Vec::new(),
+        };
+        if futexes.get(&from).is_some_and(VecDeque::is_empty) {
+            futexes.remove(&from);
+        }
+        let moved_count = moved.len() as u32;
+        if !moved.is_empty() {
+            futexes.entry(to).or_default().extend(moved);
+        }
+        woken + moved_count
+    }
+}
+
+/// Wake up to `count` of the `futexes` waiting on `addr` whose bitset
+/// intersects `bitset`
+fn wake_waiters(futexes: &mut HashMap<u64, VecDeque<Arc<Waiter>>>, addr: u64, count: u32, bitset: u32) -> u32 {
+    let Some(queue) = futexes.get_mut(&addr) else {
+        return 0;
+    };
+    let mut woken = 0;
+    let mut index = 0;
+    while woken < count && index < queue.len() {
+        if queue[index].bitset & bitset == 0 {
+            index += 1;
+            continue;
+        }
+        let waiter = queue.remove(index).unwrap();
+        *waiter.woken.lock().unwrap() = true;
+        waiter.condvar.notify_one();
+        woken += 1;
+    }
+    if queue.is_empty() {
+        futexes.remove(&addr);
+    }
+    woken
+}
+
+impl fmt::Debug for Threads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Threads")
+            .field("pid", &self.pid)
+            .field("parent", &self.parent.as_ref().map(|parent| parent.threads.pid))
+            .finish_non_exhaustive()
+    }
+}
+
+/// The registers a child made by clone starts with, those of the parent
+/// that made the call except for the return value, the stack and the TLS
+pub fn child_regs(parent: &Registers, stack: u64, tls: Option<u64>) -> Registers {
+    let mut regs = parent.clone();
+    regs.x[A0] = 0;
+    if stack != 0 {
+        regs.x[SP] = stack;
+    }
+    if let Some(tls) = tls {
+        regs.x[TP] = tls;
+    }
+    regs
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn clone<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    regs: &Registers,
+    flags: u64,
+    stack: u64,
+    parent_tid: u64,
+    tls: u64,
+    child_tid: u64,
+) -> SyscallResult {
+    if flags & (CLONE_VM | CLONE_THREAD) == 0 {
+        return exec::fork(env, memory, regs, flags, stack, parent_tid, child_tid);
+    }
+    // a thread must share everything, a process sharing the address space
+    // is not supported
+    if flags & CLONE_THREAD_FLAGS != CLONE_THREAD_FLAGS {
+        return Err(ENOSYS);
+    }
+    let known = CLONE_THREAD_FLAGS
+        | CLONE_SYSVSEM
+        | CLONE_SETTLS
+        | CLONE_PARENT_SETTID
+        | CLONE_CHILD_CLEARTID
+        | CLONE_DETACHED
+        | CLONE_CHILD_SETTID;
+    // the low byte is the exit signal, which threads do not have
+    if flags & !known & !0xff != 0 {
+        return Err(EINVAL);
+    }
+    let spawner = env.threads.spawner.lock().unwrap().clone().ok_or(ENOSYS)?;
+    let tid = env.threads.next_id();
+    if flags & CLONE_PARENT_SETTID != 0 {
+        memory.write_u32(parent_tid, tid as u32)?;
+    }
+    if flags & CLONE_CHILD_SETTID != 0 {
+        memory.write_u32(child_tid, tid as u32)?;
+    }
+    let mut child = env.thread(tid);
+    if flags & CLONE_CHILD_CLEARTID != 0 {
+        child.clear_child_tid = child_tid;
+    }
+    env.threads.live.fetch_add(1, Ordering::SeqCst);
+    env.threads.tids.lock().unwrap().insert(tid);
+    let tls = (flags & CLONE_SETTLS != 0).then_some(tls);
+    let spawned = spawner(NewThread {
+        env: child,
+        stack,
+        tls,
+        regs: child_regs(regs, stack, tls),
+        map: memory.map().clone(),
+    });
+    if let Err(e) = spawned {
+        env.threads.live.fetch_sub(1, Ordering::SeqCst);
+        env.threads.tids.lock().unwrap().remove(&tid);
+        return Err(e);
+    }
+    Ok(tid as u64)
+}
+
+pub fn set_tid_address(env: &mut SyscallEnv, tidptr: u64) -> SyscallResult {
+    env.clear_child_tid = tidptr;
+    Ok(env.tid as u64)
+}
+
+/// Tear down the calling thread: clear `clear_child_tid` and wake whoever
+/// joins on it, as the kernel does when a thread exits
+pub fn exit_thread<M: LinearMemory + ?Sized>(env: &mut SyscallEnv, memory: &mut GuestMemory<M>) {
+    if env.clear_child_tid != 0 {
+        // a bad pointer only means nobody can join, the exit goes on
+        if memory.write_u32(env.clear_child_tid, 0).is_ok() {
+            env.threads.wake(env.clear_child_tid, 1, FUTEX_BITSET_MATCH_ANY);
+        }
+        env.clear_child_tid = 0;
+    }
+}
+
+/// The exit syscall, which ends the calling thread and with the last
+/// thread the process
+pub fn exit<M: LinearMemory + ?Sized>(env: &mut SyscallEnv, memory: &mut GuestMemory<M>, status: i32) -> GuestExit {
+    exit_thread(env, memory);
+    env.threads.tids.lock().unwrap().remove(&env.tid);
+    if env.threads.live.fetch_sub(1, Ordering::SeqCst) == 1 {
+        GuestExit::Process(env.threads.terminate(ExecutionResult::Exited(status & 0xff)))
+    } else {
+        GuestExit::Thread(status & 0xff)
+    }
+}
+
+/// exit_group, which ends every thread
+pub fn exit_group(env: &mut SyscallEnv, status: i32) -> GuestExit {
+    GuestExit::Process(env.threads.terminate(ExecutionResult::Exited(status & 0xff)))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn futex<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    op: u32,
+    val: u32,
+    timeout: u64,
+    addr2: u64,
+    val3: u32,
+) -> SyscallResult {
+    if !addr.is_multiple_of(4) {
+        return Err(EINVAL);
+    }
+    let threads = env.threads.clone();
+    let cmd = op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+    match cmd {
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+            let bitset = if cmd == FUTEX_WAIT { FUTEX_BITSET_MATCH_ANY } else { val3 };
+            if bitset == 0 {
+                return Err(EINVAL);
+            }
+            let deadline = match timeout {
+                0 => None,
+                // FUTEX_WAIT takes a relative timeout
+                _ if cmd == FUTEX_WAIT => Some(Instant::now() + read_timespec(memory, timeout)?),
+                // and FUTEX_WAIT_BITSET an absolute one on the selected clock
+                _ => {
+                    let abs = read_timespec(memory, timeout)?;
+                    let now = if op & FUTEX_CLOCK_REALTIME != 0 {
+                        env.clock.realtime()
+                    } else {
+                        env.clock.monotonic()
+                    };
+                    Some(Instant::now() + abs.saturating_sub(now))
+                }
+            };
+            threads.wait(memory, addr, val, bitset, deadline)?;
+            Ok(0)
+        }
+        FUTEX_WAKE => Ok(threads.wake(addr, val, FUTEX_BITSET_MATCH_ANY) as u64),
+        FUTEX_WAKE_BITSET if val3 == 0 => Err(EINVAL),
+        FUTEX_WAKE_BITSET => Ok(threads.wake(addr, val, val3) as u64),
+        FUTEX_REQUEUE => Ok(threads.requeue(addr, addr2, val, timeout as u32) as u64),
+        FUTEX_CMP_REQUEUE => {
+            if memory.read_u32(addr)? != val3 {
+                return Err(EAGAIN);
+            }
+            Ok(threads.requeue(addr, addr2, val, timeout as u32) as u64)
+        }
+        _ => Err(ENOSYS),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::regs::A7;
+    use crate::runtime::syscall::testing::TestMemory;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_futex_wait_wake() {
+        let threads = Arc::new(Threads::new(1));
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let memory = guest.memory();
+
+        // a changed word fails right away and a deadline expires
+        assert_eq!(threads.wait(&memory, 0x10000, 1, FUTEX_BITSET_MATCH_ANY, None), Err(EAGAIN));
+        let deadline = Some(Instant::now() + Duration::from_millis(10));
+        assert_eq!(threads.wait(&memory, 0x10000, 0, FUTEX_BITSET_MATCH_ANY, deadline), Err(ETIMEDOUT));
+
+        let waker = threads.clone();
+        let handle = thread::spawn(move || {
+            // retry until the waiter is queued
+            while waker.wake(0x10000, 1, FUTEX_BITSET_MATCH_ANY) == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        assert_eq!(threads.wait(&memory, 0x10000, 0, FUTEX_BITSET_MATCH_ANY, None), Ok(()));
+        handle.join().unwrap();
+        assert_eq!(threads.wake(0x10000, 1, FUTEX_BITSET_MATCH_ANY), 0);
+    }
+
+    #[test]
+    fn test_clone_thread() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+        let regs = Registers::new(0x10100, 0x10f00);
+        let flags = CLONE_THREAD_FLAGS | CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID;
+        assert_eq!(clone(&mut env, &mut memory, &regs, flags, 0x10800, 0x10000, 0x7000, 0x10004), Err(ENOSYS));
+
+        let spawned = Arc::new(Mutex::new(Vec::new()));
+        let sink = spawned.clone();
+        env.threads.set_spawner(Arc::new(move |thread| {
+            sink.lock().unwrap().push(thread);
+            Ok(())
+        }));
+        let tid = clone(&mut env, &mut memory, &regs, flags, 0x10800, 0x10000, 0x7000, 0x10004).unwrap();
+        assert_eq!(tid, env.tid as u64 + 1);
+        assert_eq!(memory.read_u32(0x10000).unwrap() as u64, tid);
+        let mut child = spawned.lock().unwrap().pop().unwrap();
+        assert_eq!((child.stack, child.tls), (0x10800, Some(0x7000)));
+        assert_eq!((child.regs.pc, child.regs.x[SP], child.regs.x[TP], child.regs.x[A0]), (0x10100, 0x10800, 0x7000, 0));
+        assert_eq!(set_tid_address(&mut child.env, 0x10004), Ok(tid));
+
+        // the child's exit clears the tid word for pthread_join
+        memory.write_u32(0x10004, tid as u32).unwrap();
+        exit_thread(&mut child.env, &mut memory);
+        assert_eq!(memory.read_u32(0x10004).unwrap(), 0);
+        assert_eq!(clone(&mut env, &mut memory, &regs, 0x11, 0, 0, 0, 0), Err(ENOSYS));
+    }
+
+    #[test]
+    fn test_exit_paths() {
+        let mut env = SyscallEnv::new(&Config::new());
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+        env.threads.set_spawner(Arc::new(|_| Ok(())));
+        clone(&mut env, &mut memory, &Registers::default(), CLONE_THREAD_FLAGS, 0, 0, 0, 0).unwrap();
+
+        // the first of two threads leaves the process running
+        let mut regs = Registers::default();
+        regs.x[A7] = crate::runtime::syscall::SYS_EXIT;
+        regs.x[A0] = 3;
+        assert_eq!(crate::runtime::syscall::ecall(&mut env, &mut memory, &mut regs), Err(GuestExit::Thread(3)));
+        assert_eq!(env.threads.exit_status(), None);
+        assert_eq!(exit(&mut env, &mut memory, 0x105), GuestExit::Process(ExecutionResult::Exited(5)));
+
+        // and whoever ends the process first decides the status
+        assert_eq!(exit_group(&mut env, 1), GuestExit::Process(ExecutionResult::Exited(5)));
+        assert_eq!(env.threads.exit_status(), Some(ExecutionResult::Exited(5)));
+    }
+}
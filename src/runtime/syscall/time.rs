@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use super::signal::{SIGALRM, SIGPROF, SIGVTALRM};
+use super::{Errno, SyscallEnv, SyscallResult, EAGAIN, EINVAL};
+use crate::middleend::address_map::{GuestMemory, LinearMemory};
+
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+pub const CLOCK_PROCESS_CPUTIME_ID: u32 = 2;
+pub const CLOCK_THREAD_CPUTIME_ID: u32 = 3;
+pub const CLOCK_MONOTONIC_RAW: u32 = 4;
+pub const CLOCK_REALTIME_COARSE: u32 = 5;
+pub const CLOCK_MONOTONIC_COARSE: u32 = 6;
+pub const CLOCK_BOOTTIME: u32 = 7;
+
+pub const TIMER_ABSTIME: u32 = 1;
+
+pub const ITIMER_REAL: u32 = 0;
+pub const ITIMER_VIRTUAL: u32 = 1;
+pub const ITIMER_PROF: u32 = 2;
+
+pub const SIGEV_SIGNAL: u32 = 0;
+pub const SIGEV_NONE: u32 = 1;
+pub const SIGEV_THREAD_ID: u32 = 4;
+
+/// POSIX timers a process may have at once
+pub const MAX_TIMERS: usize = 32;
+
+/// Read the guest clock `id`. The cpu time clocks count from guest start as
+/// well, the runtime does not account host time per guest thread.
+pub fn clock_time(env: &SyscallEnv, id: u32) -> Result<Duration, Errno> {
+    match id {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => Ok(env.clock.realtime()),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+            Ok(env.clock.monotonic())
+        }
+        CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => Ok(env.clock.cpu_time()),
+        _ => Err(EINVAL),
+    }
+}
+
+/// Load a `struct timespec`, rejecting what the kernel rejects
+pub fn read_timespec<M: LinearMemory + ?Sized>(memory: &GuestMemory<M>, addr: u64) -> Result<Duration, Errno> {
+    let sec = memory.read_u64(addr)? as i64;
+    let nsec = memory.read_u64(addr + 8)? as i64;
+    if sec < 0 || !(0..1_000_000_000).contains(&nsec) {
+        return Err(EINVAL);
+    }
+    Ok(Duration::new(sec as u64, nsec as u32))
+}
+
+/// Store a `struct timespec`
+pub fn write_timespec<M: LinearMemory + ?Sized>(
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    time: Duration,
+) -> Result<(), Errno> {
+    memory.write_u64(addr, time.as_secs())?;
+    memory.write_u64(addr + 8, time.subsec_nanos() as u64)?;
+    Ok(())
+}
+
+pub fn clock_gettime<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    id: u32,
+    tp: u64,
+) -> SyscallResult {
+    let time = clock_time(env, id)?;
+    write_timespec(memory, tp, time)?;
+    Ok(0)
+}
+
+pub fn clock_getres<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    id: u32,
+    res: u64,
+) -> SyscallResult {
+    clock_time(env, id)?;
+    if res != 0 {
+        write_timespec(memory, res, Duration::from_nanos(1))?;
+    }
+    Ok(0)
+}
+
+pub fn gettimeofday<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    tv: u64,
+    tz: u64,
+) -> SyscallResult {
+    if tv != 0 {
+        let time = env.clock.realtime();
+        memory.write_u64(tv, time.as_secs())?;
+        memory.write_u64(tv + 8, time.subsec_micros() as u64)?;
+    }
+    if tz != 0 {
+        // struct timezone, always UTC
+        memory.write_u64(tz, 0)?;
+    }
+    Ok(0)
+}
+
+/// Monotonic deadline of a sleep or timer on `clock` given `time`, which
+/// is absolute with `TIMER_ABSTIME` and relative to now otherwise
+fn deadline(env: &SyscallEnv, clock: u32, time: Duration, absolute: bool) -> Result<Duration, Errno> {
+    let now = env.clock.monotonic();
+    if !absolute {
+        return Ok(now + time);
+    }
+    // an absolute time on another clock is as far off as it is on that one
+    Ok(now + time.saturating_sub(clock_time(env, clock)?))
+}
+
+pub fn nanosleep<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    req: u64,
+    _rem: u64,
+) -> SyscallResult {
+    let time = read_timespec(memory, req)?;
+    env.clock.sleep_until(env.clock.monotonic() + time);
+    // sleeps are never interrupted, so the remaining time is never reported
+    Ok(0)
+}
+
+pub fn clock_nanosleep<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    id: u32,
+    flags: u32,
+    req: u64,
+    _rem: u64,
+) -> SyscallResult {
+    if id == CLOCK_THREAD_CPUTIME_ID {
+        return Err(EINVAL);
+    }
+    let time = read_timespec(memory, req)?;
+    let deadline = deadline(env, id, time, flags & TIMER_ABSTIME != 0)?;
+    env.clock.sleep_until(deadline);
+    Ok(0)
+}
+
+pub fn sched_yield(_env: &mut SyscallEnv) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// Which timer of the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimerId {
+    /// One of the three setitimer timers
+    Interval(u32),
+    /// A timer from timer_create
+    Posix(i32),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Timer {
+    /// Clock the timer was created on, for absolute times and timer_gettime
+    clock: u32,
+    /// Signal raised on expiry, 0 for none
+    signal: u32,
+    /// Monotonic time of the next expiry, `None` while disarmed
+    deadline: Option<Duration>,
+    interval: Duration,
+}
+
+/// The timers of a process as a snapshot keeps them, each deadline as the
+/// time left until it, so they expire as far into the restored run as they
+/// would have into the captured one
+#[derive(Debug, Clone, Default)]
+pub struct SavedTimers(HashMap<TimerId, Timer>);
+
+/// The process' timers, checked against the guest clock whenever the engine
+/// looks for signals to deliver
+#[derive(Debug)]
+pub struct Timers {
+    timers: Mutex<HashMap<TimerId, Timer>>,
+    /// Earliest deadline in nanoseconds, so the check between blocks is a
+    /// single load while nothing is due
+    next: AtomicU64,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            timers: Mutex::new(HashMap::new()),
+            next: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    fn update_next(&self, timers: &HashMap<TimerId, Timer>) {
+        let next = timers.values().filter_map(|timer| timer.deadline).min();
+        self.next.store(next.map_or(u64::MAX, |next| next.as_nanos() as u64), Ordering::SeqCst);
+    }
+
+    /// Delete the POSIX timers, which execve does not preserve unlike the
+    /// interval timers
+    pub fn exec(&self) {
+        let mut timers = self.timers.lock().unwrap();
+        timers.retain(|id, _| matches!(id, TimerId::Interval(_)));
+        self.update_next(&timers);
+    }
+
+    /// The timers as of `now`, for a snapshot
+    pub fn save(&self, now: Duration) -> SavedTimers {
+        let timers = self.timers.lock().unwrap();
+        let relative = |timer: &Timer| Timer {
+            deadline: timer.deadline.map(|deadline| deadline.saturating_sub(now)),
+            ..*timer
+        };
+        SavedTimers(timers.iter().map(|(&id, timer)| (id, relative(timer))).collect())
+    }
+
+    /// Replace the timers by `saved`, counting the time left on them from `now`
+    pub fn restore(&self, saved: &SavedTimers, now: Duration) {
+        let mut timers = self.timers.lock().unwrap();
+        *timers = saved
+            .0
+            .iter()
+            .map(|(&id, timer)| (id, Timer { deadline: timer.deadline.map(|left| now + left), ..*timer }))
+            .collect();
+        self.update_next(&timers);
+    }
+
+    /// Signals of the timers that expired by `now`, rearming the periodic ones
+    pub fn expire(&self, now: Duration) -> Vec<u32> {
+        if (now.as_nanos() as u64) < self.next.load(Ordering::SeqCst) {
+            return Vec::new();
+        }
+        let mut timers = self.timers.lock().unwrap();
+        let mut signals = Vec::new();
+        for timer in timers.values_mut() {
+            let Some(deadline) = timer.deadline.filter(|&deadline| deadline <= now) else {
+                continue;
+            };
+            if timer.signal != 0 {
+                signals.push(timer.signal);
+            }
+            // overruns collapse into one signal as linux does
+            timer.deadline = (!timer.interval.is_zero()).then(|| {
+                // in u128 nanoseconds, as a short interval misses more
+                // periods than a u32 counts
+                let interval = timer.interval.as_nanos();
+                let missed = (now - deadline).as_nanos() / interval;
+                Duration::from_nanos_u128(deadline.as_nanos() + interval * (missed + 1))
+            });
+        }
+        self.update_next(&timers);
+        signals
+    }
+
+    /// Arm or with a zero `value` disarm the timer, returning the previous
+    /// (interval, remaining time)
+    fn set(
+        &self,
+        id: TimerId,
+        now: Duration,
+        deadline: Option<Duration>,
+        interval: Duration,
+    ) -> Result<(Duration, Duration), Errno> {
+        let mut timers = self.timers.lock().unwrap();
+        let timer = match id {
+            TimerId::Interval(which) => timers.entry(id).or_insert(Timer {
+                clock: CLOCK_MONOTONIC,
+                signal: [SIGALRM, SIGVTALRM, SIGPROF][which as usize],
+                ..Timer::default()
+            }),
+            TimerId::Posix(_) => timers.get_mut(&id).ok_or(EINVAL)?,
+        };
+        let old = (timer.interval, timer.deadline.map_or(Duration::ZERO, |d| d.saturating_sub(now)));
+        timer.deadline = deadline;
+        timer.interval = interval;
+        self.update_next(&timers);
+        Ok(old)
+    }
+
+    fn get(&self, id: TimerId, now: Duration) -> Result<(Duration, Duration), Errno> {
+        let timers = self.timers.lock().unwrap();
+        Ok(match (timers.get(&id), id) {
+            (Some(timer), _) => (timer.interval, timer.deadline.map_or(Duration::ZERO, |d| d.saturating_sub(now))),
+            (None, TimerId::Interval(_)) => (Duration::ZERO, Duration::ZERO),
+            (None, TimerId::Posix(_)) => return Err(EINVAL),
+        })
+    }
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Post the signals of expired timers, called before signal delivery
+pub fn fire_timers(env: &SyscallEnv) {
+    for signal in env.timers.expire(env.clock.monotonic()) {
+        env.signals.post(signal);
+    }
+}
+
+/// Load a `struct timeval` of an itimerval
+fn read_timeval<M: LinearMemory + ?Sized>(memory: &GuestMemory<M>, addr: u64) -> Result<Duration, Errno> {
+    let sec = memory.read_u64(addr)? as i64;
+    let usec = memory.read_u64(addr + 8)? as i64;
+    if sec < 0 || !(0..1_000_000).contains(&usec) {
+        return Err(EINVAL);
+    }
+    Ok(Duration::new(sec as u64, usec as u32 * 1000))
+}
+
+fn write_itimerval<M: LinearMemory + ?Sized>(
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    (interval, value): (Duration, Duration),
+) -> Result<(), Errno> {
+    for (offset, time) in [(0, interval), (16, value)] {
+        memory.write_u64(addr + offset, time.as_secs())?;
+        memory.write_u64(addr + offset + 8, time.subsec_micros() as u64)?;
+    }
+    Ok(())
+}
+
+fn write_itimerspec<M: LinearMemory + ?Sized>(
+    memory: &mut GuestMemory<M>,
+    addr: u64,
+    (interval, value): (Duration, Duration),
+) -> Result<(), Errno> {
+    write_timespec(memory, addr, interval)?;
+    write_timespec(memory, addr + 16, value)
+}
+
+pub fn getitimer<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    which: u32,
+    value: u64,
+) -> SyscallResult {
+    if which > ITIMER_PROF {
+        return Err(EINVAL);
+    }
+    let current = env.timers.get(TimerId::Interval(which), env.clock.monotonic())?;
+    write_itimerval(memory, value, current)?;
+    Ok(0)
+}
+
+/// setitimer, where the virtual and profiling timers run on guest time
+/// like the real one, as the runtime has no separate cpu time
+pub fn setitimer<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    which: u32,
+    new: u64,
+    old: u64,
+) -> SyscallResult {
+    if which > ITIMER_PROF {
+        return Err(EINVAL);
+    }
+    let interval = read_timeval(memory, new)?;
+    let value = read_timeval(memory, new + 16)?;
+    let now = env.clock.monotonic();
+    let deadline = (!value.is_zero()).then_some(now + value);
+    let previous = env.timers.set(TimerId::Interval(which), now, deadline, interval)?;
+    if old != 0 {
+        write_itimerval(memory, old, previous)?;
+    }
+    Ok(0)
+}
+
+pub fn timer_create<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    clock: u32,
+    sevp: u64,
+    timerid: u64,
+) -> SyscallResult {
+    clock_time(env, clock)?;
+    // struct sigevent: sigev_value, then sigev_signo and sigev_notify
+    let signal = if sevp == 0 {
+        SIGALRM
+    } else {
+        match memory.read_u32(sevp + 12)? {
+            SIGEV_NONE => 0,
+            // a thread directed signal goes to the process, whose threads
+            // all take signals alike
+            SIGEV_SIGNAL | SIGEV_THREAD_ID => match memory.read_u32(sevp + 8)? {
+                signal @ 1..=64 => signal,
+                _ => return Err(EINVAL),
+            },
+            _ => return Err(EINVAL),
+        }
+    };
+    let mut timers = env.timers.timers.lock().unwrap();
+    let id = (0..MAX_TIMERS as i32)
+        .find(|&id| !timers.contains_key(&TimerId::Posix(id)))
+        .ok_or(EAGAIN)?;
+    memory.write_u32(timerid, id as u32)?;
+    timers.insert(
+        TimerId::Posix(id),
+        Timer {
+            clock,
+            signal,
+            ..Timer::default()
+        },
+    );
+    Ok(0)
+}
+
+pub fn timer_settime<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    id: i32,
+    flags: u32,
+    new: u64,
+    old: u64,
+) -> SyscallResult {
+    let interval = read_timespec(memory, new)?;
+    let value = read_timespec(memory, new + 16)?;
+    let clock = env.timers.timers.lock().unwrap().get(&TimerId::Posix(id)).ok_or(EINVAL)?.clock;
+    let deadline = if value.is_zero() {
+        None
+    } else {
+        Some(deadline(env, clock, value, flags & TIMER_ABSTIME != 0)?)
+    };
+    let previous = env.timers.set(TimerId::Posix(id), env.clock.monotonic(), deadline, interval)?;
+    if old != 0 {
+        write_itimerspec(memory, old, previous)?;
+    }
+    Ok(0)
+}
+
+pub fn timer_gettime<M: LinearMemory + ?Sized>(
+    env: &mut SyscallEnv,
+    memory: &mut GuestMemory<M>,
+    id: i32,
+    value: u64,
+) -> SyscallResult {
+    let current = env.timers.get(TimerId::Posix(id), env.clock.monotonic())?;
+    write_itimerspec(memory, value, current)?;
+    Ok(0)
+}
+
+pub fn timer_getoverrun(env: &mut SyscallEnv, id: i32) -> SyscallResult {
+    env.timers.get(TimerId::Posix(id), env.clock.monotonic())?;
+    Ok(0)
+}
+
+pub fn timer_delete(env: &mut SyscallEnv, id: i32) -> SyscallResult {
+    let mut timers = env.timers.timers.lock().unwrap();
+    timers.remove(&TimerId::Posix(id)).ok_or(EINVAL)?;
+    env.timers.update_next(&timers);
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::clock::{ClockMode, VIRTUAL_EPOCH};
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    #[test]
+    fn test_virtual_clock_syscalls() {
+        let config = Config::new().clock(ClockMode::Virtual {
+            instructions_per_sec: 1000,
+        });
+        let mut env = SyscallEnv::new(&config);
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+
+        env.clock.retire(1500);
+        clock_gettime(&mut env, &mut memory, CLOCK_MONOTONIC, 0x10000).unwrap();
+        assert_eq!(memory.read_u64(0x10000).unwrap(), 1);
+        assert_eq!(memory.read_u64(0x10008).unwrap(), 500_000_000);
+        gettimeofday(&mut env, &mut memory, 0x10000, 0).unwrap();
+        assert_eq!(memory.read_u64(0x10000).unwrap(), VIRTUAL_EPOCH.as_secs() + 1);
+        assert_eq!(memory.read_u64(0x10008).unwrap(), 500_000);
+        assert_eq!(clock_gettime(&mut env, &mut memory, 99, 0x10000), Err(EINVAL));
+    }
+
+    #[test]
+    fn test_sleep_and_timers() {
+        let config = Config::new().clock(ClockMode::Virtual {
+            instructions_per_sec: 1000,
+        });
+        let mut env = SyscallEnv::new(&config);
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RW)]);
+        let mut memory = guest.memory();
+
+        // a virtual sleep returns at once with the clock moved ahead
+        write_timespec(&mut memory, 0x10000, Duration::from_secs(5)).unwrap();
+        nanosleep(&mut env, &mut memory, 0x10000, 0).unwrap();
+        assert_eq!(env.clock.monotonic(), Duration::from_secs(5));
+        write_timespec(&mut memory, 0x10000, VIRTUAL_EPOCH + Duration::from_secs(7)).unwrap();
+        clock_nanosleep(&mut env, &mut memory, CLOCK_REALTIME, TIMER_ABSTIME, 0x10000, 0).unwrap();
+        assert_eq!(env.clock.monotonic(), Duration::from_secs(7));
+
+        // a one shot alarm fires once
+        let alarm = [0, 0, 1, 0].map(u64::to_le_bytes).concat();
+        memory.write(0x10100, &alarm).unwrap();
+        setitimer(&mut env, &mut memory, ITIMER_REAL, 0x10100, 0).unwrap();
+        fire_timers(&env);
+        assert_eq!(env.signals.pending(), 0);
+        env.clock.retire(1000);
+        fire_timers(&env);
+        assert_eq!(env.signals.pending(), 1 << (SIGALRM - 1));
+        assert_eq!(env.timers.expire(Duration::from_secs(100)), Vec::<u32>::new());
+
+        // a periodic timer rearms, collapsing missed periods
+        timer_create(&mut env, &mut memory, CLOCK_MONOTONIC, 0, 0x10200).unwrap();
+        let id = memory.read_u32(0x10200).unwrap() as i32;
+        write_timespec(&mut memory, 0x10300, Duration::from_secs(1)).unwrap();
+        write_timespec(&mut memory, 0x10310, Duration::from_secs(1)).unwrap();
+        timer_settime(&mut env, &mut memory, id, 0, 0x10300, 0).unwrap();
+        let now = env.clock.monotonic();
+        assert_eq!(env.timers.expire(now + Duration::from_millis(3500)), vec![SIGALRM]);
+        // the periods at 1s, 2s and 3s are behind it, the next is at 4s
+        timer_gettime(&mut env, &mut memory, id, 0x10400).unwrap();
+        assert_eq!(memory.read_u64(0x10400).unwrap(), 1);
+        assert_eq!(memory.read_u64(0x10410).unwrap(), 4);
+        // billions of 1ns periods missed, the next is still 1ns ahead
+        write_timespec(&mut memory, 0x10300, Duration::from_nanos(1)).unwrap();
+        write_timespec(&mut memory, 0x10310, Duration::from_nanos(1)).unwrap();
+        timer_settime(&mut env, &mut memory, id, 0, 0x10300, 0).unwrap();
+        let now = env.clock.monotonic();
+        assert_eq!(env.timers.expire(now + Duration::from_secs(10)), vec![SIGALRM]);
+        assert_eq!(env.timers.expire(now + Duration::from_secs(10)), Vec::<u32>::new());
+        assert_eq!(env.timers.expire(now + Duration::from_secs(10) + Duration::from_nanos(1)), vec![SIGALRM]);
+        timer_delete(&mut env, id).unwrap();
+        assert_eq!(timer_delete(&mut env, id), Err(EINVAL));
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::stat::{Stat, S_IFCHR, S_IFDIR, S_IFREG};
+use super::{Errno, EEXIST, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use crate::frontend::page::Page;
+use crate::middleend::address_map::{AddressMap, Perm};
+use crate::runtime::rng::Entropy;
+
+/// Device number of every VFS node, an anonymous device like tmpfs gets
+pub const VFS_DEV: u64 = 0x16;
+
+/// A node of the in-memory filesystem
+#[derive(Debug, Clone)]
+pub enum VfsNode {
+    Dir,
+    File(Arc<Mutex<Vec<u8>>>),
+    Null,
+    Zero,
+    Urandom,
+    /// `/proc/self/maps`, rendered from the address map when opened
+    ProcMaps,
+}
+
+/// In-memory filesystem the syscalls consult before the host, so a guest
+/// always finds `/dev/null` and friends even without any preopened directory
+#[derive(Debug, Clone)]
+pub struct Vfs {
+    nodes: BTreeMap<PathBuf, VfsNode>,
+    /// What `/dev/urandom` reads from
+    entropy: Entropy,
+    /// Timestamp every node reports, since the epoch
+    time: Duration,
+}
+
+/// An open VFS node with its own file position
+#[derive(Debug)]
+pub enum VfsFile {
+    Memory { data: Arc<Mutex<Vec<u8>>>, pos: usize },
+    /// Content generated once at open time
+    Generated { data: Vec<u8>, pos: usize },
+    Null,
+    Zero,
+    Urandom(Entropy),
+}
+
+impl Vfs {
+    pub fn empty() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::from("/"), VfsNode::Dir);
+        Self {
+            nodes,
+            entropy: Entropy::Host,
+            time: Duration::ZERO,
+        }
+    }
+
+    /// The minimal tree every guest sees
+    pub fn new() -> Self {
+        let mut vfs = Self::empty();
+        vfs.insert("/dev/null", VfsNode::Null);
+        vfs.insert("/dev/zero", VfsNode::Zero);
+        vfs.insert("/dev/urandom", VfsNode::Urandom);
+        vfs.insert("/dev/random", VfsNode::Urandom);
+        vfs.insert("/proc/self/maps", VfsNode::ProcMaps);
+        vfs
+    }
+
+    pub fn set_entropy(&mut self, entropy: Entropy) {
+        self.entropy = entropy;
+    }
+
+    /// Date the nodes with `time`, the guest's boot time
+    pub fn set_time(&mut self, time: Duration) {
+        self.time = time;
+    }
+
+    /// Insert a node, creating its parent directories
+    pub fn insert(&mut self, path: impl AsRef<Path>, node: VfsNode) {
+        let path = path.as_ref();
+        for dir in path.ancestors().skip(1) {
+            self.nodes.entry(dir.to_path_buf()).or_insert(VfsNode::Dir);
+        }
+        self.nodes.insert(path.to_path_buf(), node);
+    }
+
+    pub fn insert_file(&mut self, path: impl AsRef<Path>, data: Vec<u8>) {
+        self.insert(path, VfsNode::File(Arc::new(Mutex::new(data))));
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&VfsNode> {
+        self.nodes.get(path)
+    }
+
+    /// Whether a missing `path` would be created in the VFS rather than on
+    /// the host, that is whether its parent is a VFS directory other than `/`
+    pub fn owns_parent(&self, path: &Path) -> bool {
+        match path.parent() {
+            Some(parent) if parent != Path::new("/") => {
+                matches!(self.nodes.get(parent), Some(VfsNode::Dir))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn create(&mut self, path: &Path, exclusive: bool) -> Result<VfsNode, Errno> {
+        match self.nodes.get(path) {
+            Some(VfsNode::Dir) => return Err(EISDIR),
+            Some(_) if exclusive => return Err(EEXIST),
+            Some(node) => return Ok(node.clone()),
+            None => {}
+        }
+        if !self.owns_parent(path) {
+            return Err(ENOENT);
+        }
+        let node = VfsNode::File(Arc::new(Mutex::new(Vec::new())));
+        self.nodes.insert(path.to_path_buf(), node.clone());
+        Ok(node)
+    }
+
+    pub fn remove(&mut self, path: &Path, dir: bool) -> Result<(), Errno> {
+        match (self.nodes.get(path), dir) {
+            (None, _) => return Err(ENOENT),
+            (Some(VfsNode::Dir), false) => return Err(EISDIR),
+            (Some(VfsNode::Dir), true) => {
+                if self.nodes.keys().any(|p| p.parent() == Some(path)) {
+                    return Err(ENOTEMPTY);
+                }
+            }
+            (Some(VfsNode::File(_)), false) => {}
+            (Some(VfsNode::File(_)), true) => return Err(ENOTDIR),
+            // the device nodes are part of the fixed tree
+            (Some(_), _) => return Err(EPERM),
+        }
+        self.nodes.remove(path);
+        Ok(())
+    }
+
+    pub fn open(&self, path: &Path, map: &AddressMap) -> Result<Option<VfsFile>, Errno> {
+        Ok(Some(match self.nodes.get(path).ok_or(ENOENT)? {
+            VfsNode::Dir => return Ok(None),
+            VfsNode::File(data) => VfsFile::Memory {
+                data: data.clone(),
+                pos: 0,
+            },
+            VfsNode::Null => VfsFile::Null,
+            VfsNode::Zero => VfsFile::Zero,
+            VfsNode::Urandom => VfsFile::Urandom(self.entropy.clone()),
+            VfsNode::ProcMaps => VfsFile::Generated {
+                data: proc_maps(map),
+                pos: 0,
+            },
+        }))
+    }
+
+    pub fn stat(&self, path: &Path) -> Result<Stat, Errno> {
+        let node = self.nodes.get(path).ok_or(ENOENT)?;
+        // inode numbers only have to be stable and distinct within the VFS
+        let ino = self.nodes.keys().position(|p| p == path).unwrap() as u64 + 1;
+        Ok(self.stamp(node_stat(node, ino)))
+    }
+
+    /// Attributes of an open VFS file
+    pub fn file_stat(&self, file: &VfsFile) -> Stat {
+        self.stamp(file.stat())
+    }
+
+    fn stamp(&self, stat: Stat) -> Stat {
+        let (sec, nsec) = (self.time.as_secs() as i64, self.time.subsec_nanos() as i64);
+        Stat {
+            dev: VFS_DEV,
+            atime: sec,
+            atime_nsec: nsec,
+            mtime: sec,
+            mtime_nsec: nsec,
+            ctime: sec,
+            ctime_nsec: nsec,
+            ..stat
+        }
+    }
+}
+
+impl Default for Vfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn node_stat(node: &VfsNode, ino: u64) -> Stat {
+    // major 1 is the linux memory devices, as in makedev(1, minor)
+    let (mode, rdev, size) = match node {
+        VfsNode::Dir => (S_IFDIR | 0o755, 0, 0),
+        VfsNode::File(data) => (S_IFREG | 0o644, 0, data.lock().unwrap().len() as i64),
+        VfsNode::Null => (S_IFCHR | 0o666, (1 << 8) | 3, 0),
+        VfsNode::Zero => (S_IFCHR | 0o666, (1 << 8) | 5, 0),
+        VfsNode::Urandom => (S_IFCHR | 0o666, (1 << 8) | 9, 0),
+        VfsNode::ProcMaps => (S_IFREG | 0o444, 0, 0),
+    };
+    Stat {
+        ino,
+        mode,
+        nlink: 1,
+        rdev,
+        size,
+        blksize: Page::SIZE as i32,
+        blocks: (size + 511) / 512,
+        ..Stat::default()
+    }
+}
+
+/// Render the address map in the `/proc/self/maps` format
+fn proc_maps(map: &AddressMap) -> Vec<u8> {
+    let mut maps = String::new();
+    for region in map.regions() {
+        let perm = map.permission(region.vaddr.start).unwrap_or(Perm::NONE);
+        maps.push_str(&format!(
+            "{:08x}-{:08x} {:?}p 00000000 00:00 0\n",
+            region.vaddr.start, region.vaddr.end, perm
+        ));
+    }
+    maps.into_bytes()
+}
+
+impl VfsFile {
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        match self {
+            VfsFile::Memory { data, pos } => {
+                let data = data.lock().unwrap();
+                let len = data.len().saturating_sub(*pos).min(buf.len());
+                buf[..len].copy_from_slice(&data[*pos..*pos + len]);
+                *pos += len;
+                len
+            }
+            VfsFile::Generated { data, pos } => {
+                let len = data.len().saturating_sub(*pos).min(buf.len());
+                buf[..len].copy_from_slice(&data[*pos..*pos + len]);
+                *pos += len;
+                len
+            }
+            VfsFile::Null => 0,
+            VfsFile::Zero => {
+                buf.fill(0);
+                buf.len()
+            }
+            VfsFile::Urandom(entropy) => {
+                entropy.fill(buf);
+                buf.len()
+            }
+        }
+    }
+
+    /// Read at `offset` without moving the file position
+    pub fn read_at(&mut self, buf: &mut [u8], offset: usize) -> usize {
+        let copy = |data: &[u8], buf: &mut [u8]| {
+            let start = offset.min(data.len());
+            let len = (data.len() - start).min(buf.len());
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            len
+        };
+        match self {
+            VfsFile::Memory { data, .. } => copy(&data.lock().unwrap(), buf),
+            VfsFile::Generated { data, .. } => copy(data, buf),
+            // the devices have no position to begin with
+            _ => self.read(buf),
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8], append: bool) -> Result<usize, Errno> {
+        match self {
+            VfsFile::Memory { data, pos } => {
+                let mut data = data.lock().unwrap();
+                if append {
+                    *pos = data.len();
+                }
+                let end = *pos + buf.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[*pos..end].copy_from_slice(buf);
+                *pos = end;
+                Ok(buf.len())
+            }
+            VfsFile::Generated { .. } => Err(EPERM),
+            VfsFile::Null | VfsFile::Zero | VfsFile::Urandom(_) => Ok(buf.len()),
+        }
+    }
+
+    /// Write at `offset` without moving the file position
+    pub fn write_at(&mut self, buf: &[u8], offset: usize) -> Result<usize, Errno> {
+        match self {
+            VfsFile::Memory { data, .. } => {
+                let mut data = data.lock().unwrap();
+                let end = offset + buf.len();
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[offset..end].copy_from_slice(buf);
+                Ok(buf.len())
+            }
+            _ => self.write(buf, false),
+        }
+    }
+
+    /// The file position, `None` for the devices, which have none
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            VfsFile::Memory { pos, .. } | VfsFile::Generated { pos, .. } => Some(*pos),
+            _ => None,
+        }
+    }
+
+    pub fn set_position(&mut self, position: usize) {
+        if let VfsFile::Memory { pos, .. } | VfsFile::Generated { pos, .. } = self {
+            *pos = position;
+        }
+    }
+
+    pub fn truncate(&mut self) {
+        if let VfsFile::Memory { data, .. } = self {
+            data.lock().unwrap().clear();
+        }
+    }
+
+    pub fn stat(&self) -> Stat {
+        let node = match self {
+            VfsFile::Memory { data, .. } => VfsNode::File(data.clone()),
+            VfsFile::Generated { data, .. } => {
+                let mut stat = node_stat(&VfsNode::ProcMaps, 0);
+                stat.size = data.len() as i64;
+                return stat;
+            }
+            VfsFile::Null => VfsNode::Null,
+            VfsFile::Zero => VfsNode::Zero,
+            VfsFile::Urandom(_) => VfsNode::Urandom,
+        };
+        node_stat(&node, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_tree() {
+        let mut vfs = Vfs::new();
+        let map = AddressMap::new(1 << 20);
+        assert!(matches!(vfs.get(Path::new("/dev")), Some(VfsNode::Dir)));
+        let mut null = vfs.open(Path::new("/dev/null"), &map).unwrap().unwrap();
+        assert_eq!(null.read(&mut [0; 16]), 0);
+        assert_eq!(vfs.stat(Path::new("/dev/null")).unwrap().rdev, 0x103);
+        assert_eq!(vfs.remove(Path::new("/dev/null"), false), Err(EPERM));
+        assert_eq!(vfs.remove(Path::new("/dev"), true), Err(ENOTEMPTY));
+
+        // files can only be created inside vfs directories
+        assert!(vfs.create(Path::new("/dev/log"), true).is_ok());
+        assert_eq!(vfs.create(Path::new("/dev/log"), true).err(), Some(EEXIST));
+        assert_eq!(vfs.create(Path::new("/etc/hosts"), false).err(), Some(ENOENT));
+    }
+
+    #[test]
+    fn test_memory_file_and_maps() {
+        let mut vfs = Vfs::empty();
+        vfs.insert_file("/etc/hostname", b"riscv\n".to_vec());
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x12000, Perm::RX).unwrap();
+        vfs.insert("/proc/self/maps", VfsNode::ProcMaps);
+
+        let mut file = vfs.open(Path::new("/etc/hostname"), &map).unwrap().unwrap();
+        file.write(b"!", true).unwrap();
+        let mut buf = [0; 16];
+        let mut again = vfs.open(Path::new("/etc/hostname"), &map).unwrap().unwrap();
+        assert_eq!(again.read(&mut buf), 7);
+        assert_eq!(&buf[..7], b"riscv\n!");
+
+        let mut maps = vfs.open(Path::new("/proc/self/maps"), &map).unwrap().unwrap();
+        let len = maps.read(&mut [0; 256]);
+        assert_eq!(maps.stat().size as usize, len);
+        let mut maps = vfs.open(Path::new("/proc/self/maps"), &map).unwrap().unwrap();
+        let mut text = vec![0; len];
+        maps.read(&mut text);
+        assert_eq!(text, b"00010000-00012000 r-xp 00000000 00:00 0\n");
+    }
+}
@@ -0,0 +1,37 @@
+use crate::error::VmError;
+use crate::middleend::address_map::{GuestMemory, LinearMemory};
+use crate::runtime::regs::Registers;
+use crate::runtime::syscall::SyscallEnv;
+
+/// Why compiled code handed the guest back to the interpreter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Leave {
+    /// Execution reached `pc`, which has no compiled code, e.g. an ecall
+    Uncompiled(u64),
+    /// The instruction budget ran out
+    Yield,
+    /// The instruction at `regs.pc` trapped, e.g. on a fault, and the
+    /// interpreter runs it again to raise the trap the way it does
+    Trap,
+}
+
+/// A tier compiling the blocks the interpreter found hot, which the guest
+/// runs in from then on
+pub trait Tier<M: LinearMemory + ?Sized> {
+    /// Compile the block at `pc`, telling whether there is code for it now
+    fn compile(&mut self, env: &SyscallEnv, memory: &GuestMemory<M>, pc: u64) -> Result<bool, VmError>;
+
+    /// Run compiled code from `regs.pc` over about `budget` instructions,
+    /// with the registers in `regs` before and after
+    fn run(
+        &mut self,
+        env: &mut SyscallEnv,
+        memory: &mut GuestMemory<M>,
+        regs: &mut Registers,
+        budget: u64,
+    ) -> Result<Leave, VmError>;
+
+    /// The blocks compiled since the last call, for the interpreter to
+    /// switch over at
+    fn take_compiled(&mut self) -> Vec<u64>;
+}
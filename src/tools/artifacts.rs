@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::codegen::optimizer::Optimizer;
+use crate::codegen::regmap::RegMap;
+use crate::error::VmError;
+use crate::frontend::binary::{get_memory_initializers, MemoryInitializer};
+use crate::frontend::elf::{ElfFile, ProgramHeaderType};
+use crate::middleend::address_map::{AddressMap, GuestMemory, Perm};
+use crate::middleend::emit_wasm::emit_blocks_module;
+use crate::middleend::standalone::{ecall_block, emit_guest, emit_standalone};
+use crate::middleend::translate::{translate_block, GuestBlock};
+use crate::runtime::config::{Config, OptLevel};
+use crate::runtime::dispatch::{BlockSlots, InlineCaches};
+use crate::runtime::interp::find_blocks;
+use crate::runtime::layout::MemoryLayout;
+use crate::runtime::process::{ProcessError, ProcessImage};
+use crate::runtime::rng::Entropy;
+use crate::wasm::error::BackendError;
+use crate::wasm::wasm_builder::WasmBuilder;
+
+/// Every stage of translating the executable segments of a binary into one
+/// module ahead of a run, for the runner to write out and the pipeline to
+/// be looked at one stage at a time
+#[derive(Debug, Clone)]
+pub struct Artifacts {
+    /// The starts of the blocks translated, see `find_blocks`
+    pub blocks: Vec<u64>,
+    /// The module of the middle end, see `emit_blocks_module`
+    pub wat: String,
+    /// The module after the optimizer
+    pub optimized: String,
+    /// The optimized module assembled
+    pub wasm: Vec<u8>,
+}
+
+impl Artifacts {
+    /// Translate the blocks of the executable `data` as `config` asks, for
+    /// the addresses it loads at without ASLR
+    pub fn translate(data: &[u8], config: &Config) -> Result<Self, VmError> {
+        let elf = ElfFile::new(data)?;
+        let image = ProcessImage::new(&elf, &MemoryLayout::default())?;
+        let mut map = AddressMap::new(AddressMap::DEFAULT_SIZE);
+        image.map(&mut map).map_err(ProcessError::from)?;
+        let (mut slots, caches) = (BlockSlots::new(), InlineCaches::new());
+        let mut bodies = Vec::new();
+        for (base, code) in exec_segments(&elf, data, image.load_bias) {
+            for pc in block_starts(base, code, image.entry) {
+                // a block starting at an ecall is the interpreter's
+                if let Some(block) = GuestBlock::decode(&code[(pc - base) as usize..], pc) {
+                    bodies.push((pc, translate_block(&block, &map, config, &RegMap::new(&[]), &mut slots, &caches, None)));
+                }
+            }
+        }
+        let mut wat = String::new();
+        emit_blocks_module(&mut wat, &map, config, &bodies, None);
+        let optimized = Optimizer::new(OptLevel::Optimized).run(&wat, config.profiler.as_ref()).map_err(BackendError::Optimize)?;
+        let wasm = WasmBuilder::assemble(&optimized)?;
+        let blocks = bodies.into_iter().map(|(pc, _)| pc).collect();
+        Ok(Self { blocks, wat, optimized, wasm })
+    }
+
+    /// Translate the executable `data` into a standalone module, see
+    /// `emit_standalone`, started with `args` as a run would. Every block
+    /// reachable from the ones `find_blocks` finds is translated, with its
+    /// ecalls going to WASI, and the module holds the initial stack. Code
+    /// the compiled tiers leave to the interpreter, e.g. floating point or
+    /// atomics, has no block and faults the guest.
+    pub fn standalone(data: &[u8], config: &Config, args: &[String]) -> Result<String, VmError> {
+        let elf = ElfFile::new(data)?;
+        let image = ProcessImage::new(&elf, &MemoryLayout::default())?;
+        let mut map = AddressMap::new(AddressMap::DEFAULT_SIZE);
+        image.map(&mut map).map_err(ProcessError::from)?;
+        let mut linear = vec![0u8; map.size()];
+        let mut memory = GuestMemory::new(&mut map, &mut linear);
+        let regs = image.start(&mut memory, args, &[], &Entropy::new(config.seed)).map_err(ProcessError::from)?;
+        let sp = regs.x[2];
+        let mut stack = vec![0; (image.stack.end - sp) as usize];
+        memory.peek(sp, &mut stack).map_err(ProcessError::from)?;
+
+        let segments = exec_segments(&elf, data, image.load_bias);
+        let code_at = |pc: u64| {
+            segments.iter().find(|(base, code)| (*base..base + code.len() as u64).contains(&pc)).map(|(base, code)| &code[(pc - base) as usize..])
+        };
+        let (mut slots, caches) = (BlockSlots::new(), InlineCaches::new());
+        let mut pending: Vec<u64> = segments.iter().flat_map(|&(base, code)| block_starts(base, code, image.entry)).collect();
+        let mut seen: HashSet<u64> = pending.iter().copied().collect();
+        let mut bodies = BTreeMap::new();
+        // each round translates the targets the blocks of the last named
+        while !pending.is_empty() {
+            for pc in pending.drain(..) {
+                let Some(code) = code_at(pc) else {
+                    continue;
+                };
+                if let Some(block) = GuestBlock::decode(code, pc) {
+                    bodies.insert(pc, translate_block(&block, memory.map(), config, &RegMap::new(&[]), &mut slots, &caches, None));
+                    // where a call returns to
+                    slots.slot(block.end());
+                } else if code.get(..4) == Some(&ECALL.to_le_bytes()) {
+                    bodies.insert(pc, ecall_block(pc, &mut slots));
+                }
+            }
+            pending.extend(slots.iter().map(|(pc, _)| pc).filter(|&pc| seen.insert(pc)));
+        }
+        let bodies: Vec<(u64, String)> = bodies.into_iter().collect();
+        let mut code = String::new();
+        emit_guest(&mut code, &bodies, &mut slots, &regs);
+
+        let mut image_data = get_memory_initializers(&elf, image.load_bias)?;
+        image_data.push(MemoryInitializer { vaddr: sp, perm: Perm::RW, data: stack });
+        Ok(emit_standalone(&map, config, &image_data, &code)?)
+    }
+
+    /// Write every stage into `dir`: `module.wat`, `module.opt.wat`,
+    /// `module.wasm` and `blocks.txt`, one pc in hex per line
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("module.wat"), &self.wat)?;
+        fs::write(dir.join("module.opt.wat"), &self.optimized)?;
+        fs::write(dir.join("module.wasm"), &self.wasm)?;
+        let mut list = String::new();
+        for pc in &self.blocks {
+            writeln!(list, "{:#x}", pc).unwrap();
+        }
+        fs::write(dir.join("blocks.txt"), list)
+    }
+}
+
+const ECALL: u32 = 0x0000_0073;
+
+/// The executable segments of `elf` in the file `data`, by the address
+/// they load at
+fn exec_segments<'a>(elf: &ElfFile, data: &'a [u8], load_bias: u64) -> Vec<(u64, &'a [u8])> {
+    let mut segments = Vec::new();
+    for ph in elf.program_iter() {
+        if ph.get_type() != ProgramHeaderType::Load || ph.get_flags() & 1 == 0 {
+            continue;
+        }
+        let start = ph.get_offset() as usize;
+        if let Some(code) = data.get(start..start + ph.get_file_size() as usize) {
+            segments.push((ph.get_virtual_addr().wrapping_add(load_bias), code));
+        }
+    }
+    segments
+}
+
+/// The blocks `find_blocks` finds in the segment `code` loaded at `base`.
+/// The segment may start with the headers, decoding out of step with the
+/// code up to the entry point, so the blocks from `entry` are added.
+fn block_starts(base: u64, code: &[u8], entry: u64) -> BTreeSet<u64> {
+    let mut pcs: BTreeSet<u64> = find_blocks(code, base).into_iter().collect();
+    if (base..base + code.len() as u64).contains(&entry) {
+        pcs.extend(find_blocks(&code[(entry - base) as usize..], entry));
+    }
+    pcs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::emit_wasm::block_export;
+
+    #[test]
+    fn test_translate_binary() {
+        let data = fs::read(format!("{}/test_binaries/archive/assembly_controlflow", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let artifacts = Artifacts::translate(&data, &Config::new()).unwrap();
+        // the entry point is a block of its own
+        let entry = ProcessImage::new(&ElfFile::new(&data).unwrap(), &MemoryLayout::default()).unwrap().entry;
+        assert!(artifacts.blocks.contains(&entry));
+        assert!(artifacts.wat.contains(&format!("(export \"{}\"", block_export(entry))));
+        assert!(artifacts.blocks.len() > 1);
+
+        let dir = std::env::temp_dir().join(format!("doublejit-artifacts-{}", std::process::id()));
+        artifacts.write(&dir).unwrap();
+        let blocks = fs::read_to_string(dir.join("blocks.txt")).unwrap();
+        assert_eq!(blocks.lines().next(), Some(format!("{:#x}", artifacts.blocks[0]).as_str()));
+        assert_eq!(blocks.lines().count(), artifacts.blocks.len());
+        assert_eq!(fs::read_to_string(dir.join("module.wat")).unwrap(), artifacts.wat);
+        assert_eq!(fs::read_to_string(dir.join("module.opt.wat")).unwrap(), artifacts.optimized);
+        assert_eq!(fs::read(dir.join("module.wasm")).unwrap(), artifacts.wasm);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_standalone_binary() {
+        let data = fs::read(format!("{}/test_binaries/archive/assembly_controlflow", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let wat = Artifacts::standalone(&data, &Config::new(), &["controlflow".to_string()]).unwrap();
+        let entry = ProcessImage::new(&ElfFile::new(&data).unwrap(), &MemoryLayout::default()).unwrap().entry;
+        assert!(wat.contains("(func $guest_entry"));
+        assert!(wat.contains(&format!("(local.set $pc (i64.const {:#x}))", entry)));
+        assert!(wat.contains(&format!("(if (i64.eq (local.get $pc) (i64.const {:#x}))", entry)));
+        // the exit goes through WASI
+        assert!(wat.contains("(call $ecall (global.get $x17)"));
+        assert_eq!(wat.matches('(').count(), wat.matches(')').count());
+        WasmBuilder::assemble(&wat).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::frontend::elf::{ElfFile, ParseResult, ProgramHeaderType};
+use crate::middleend::address_map::{GuestMemory, LinearMemory};
+use crate::runtime::regs::{Registers, RA, SP};
+use crate::tools::flamegraph::Symbols;
+
+/// The frame pointer, s0
+const FP: usize = 8;
+/// Frames a backtrace holds at most, in case the chain loops
+const MAX_FRAMES: usize = 64;
+/// Bytes of stack above sp searched for return addresses when there is no
+/// frame pointer chain
+const SCAN_BYTES: u64 = 4096;
+
+/// How a frame was found, which says how far to trust it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// The pc the guest stopped at
+    Pc,
+    /// The return address register of a leaf, which saved no frame
+    Ra,
+    /// The return address saved along the frame pointer chain
+    FramePointer,
+    /// A word on the stack that points just past a call. It may be a stale
+    /// one a returned call left behind.
+    Scan,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub pc: u64,
+    pub kind: FrameKind,
+}
+
+/// Walks the guest stack from the registers to the calls that led there,
+/// along the frame pointers, or by scanning the stack for words pointing
+/// into the code when the binary keeps none
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Unwinder {
+    /// The executable ranges a return address must lie in, any address
+    /// when empty
+    pub text: Vec<Range<u64>>,
+    pub symbols: Symbols,
+}
+
+impl Unwinder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The executable segments and function symbols of the ELF `data`
+    pub fn from_elf(data: &[u8]) -> ParseResult<Self> {
+        let elf = ElfFile::new(data)?;
+        let mut unwinder = Self::new().symbols(Symbols::from_elf(data)?);
+        for ph in elf.program_iter() {
+            if ph.get_type() == ProgramHeaderType::Load && ph.get_flags() & 1 != 0 {
+                unwinder = unwinder.text(ph.get_virtual_addr()..ph.get_virtual_addr() + ph.get_mem_size());
+            }
+        }
+        Ok(unwinder)
+    }
+
+    pub fn text(mut self, text: Range<u64>) -> Self {
+        self.text.push(text);
+        self
+    }
+
+    pub fn symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    fn is_text(&self, pc: u64) -> bool {
+        pc != 0 && (self.text.is_empty() || self.text.iter().any(|text| text.contains(&pc)))
+    }
+
+    /// The frames from the pc in `regs` out to the first call
+    pub fn backtrace<M: LinearMemory + ?Sized>(&self, memory: &GuestMemory<M>, regs: &Registers) -> Vec<Frame> {
+        let mut frames = vec![Frame { pc: regs.pc, kind: FrameKind::Pc }];
+        // a leaf keeps its return address in ra, stale once it returned to
+        // the function it is in
+        let ra = regs.x[RA];
+        let leaf = self.is_text(ra) && follows_call(memory, ra) && !self.same_function(regs.pc, ra);
+        if leaf {
+            frames.push(Frame { pc: ra, kind: FrameKind::Ra });
+        }
+        // each frame keeps the return address at fp-8 and the caller's fp
+        // at fp-16, the callers' frames lying further up the stack
+        let mut fp = regs.x[FP];
+        let mut chain = false;
+        while frames.len() < MAX_FRAMES && fp > regs.x[SP] && fp.is_multiple_of(8) {
+            let (Some(pc), Some(caller)) = (read_u64(memory, fp - 8), read_u64(memory, fp - 16)) else { break };
+            if !self.is_text(pc) {
+                break;
+            }
+            // the leaf's return address again, when the leaf did save a frame
+            if chain || !leaf || pc != ra {
+                frames.push(Frame { pc, kind: FrameKind::FramePointer });
+            }
+            chain = true;
+            if caller <= fp {
+                break;
+            }
+            fp = caller;
+        }
+        if !chain {
+            let sp = regs.x[SP];
+            for addr in (sp..sp.saturating_add(SCAN_BYTES)).step_by(8) {
+                if frames.len() >= MAX_FRAMES {
+                    break;
+                }
+                let Some(pc) = read_u64(memory, addr) else { break };
+                if self.is_text(pc) && follows_call(memory, pc) && frames.last().map(|frame| frame.pc) != Some(pc) {
+                    frames.push(Frame { pc, kind: FrameKind::Scan });
+                }
+            }
+        }
+        frames
+    }
+
+    /// The backtrace as one line per frame, `#1  0x10234 in main+0x18`,
+    /// with the frames not found along the frame pointers marked
+    pub fn report<M: LinearMemory + ?Sized>(&self, memory: &GuestMemory<M>, regs: &Registers) -> String {
+        let mut out = String::new();
+        for (index, frame) in self.backtrace(memory, regs).iter().enumerate() {
+            write!(out, "#{:<2} {:#x} in ", index, frame.pc).unwrap();
+            match self.symbols.resolve(frame.pc) {
+                Some((name, 0)) => out.push_str(name),
+                Some((name, offset)) => write!(out, "{}+{:#x}", name, offset).unwrap(),
+                None => out.push_str("??"),
+            }
+            match frame.kind {
+                FrameKind::Ra => out.push_str(" (ra)"),
+                FrameKind::Scan => out.push_str(" (scanned)"),
+                FrameKind::Pc | FrameKind::FramePointer => {}
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn same_function(&self, a: u64, b: u64) -> bool {
+        match (self.symbols.resolve(a), self.symbols.resolve(b)) {
+            (Some((a, _)), Some((b, _))) => a == b,
+            _ => false,
+        }
+    }
+}
+
+fn read_u64<M: LinearMemory + ?Sized>(memory: &GuestMemory<M>, vaddr: u64) -> Option<u64> {
+    let mut bytes = [0; 8];
+    memory.peek(vaddr, &mut bytes).ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Whether the instruction before `pc` is a call, a jal or jalr linking ra
+/// or a c.jalr, so `pc` is a return address
+fn follows_call<M: LinearMemory + ?Sized>(memory: &GuestMemory<M>, pc: u64) -> bool {
+    let mut insn = [0; 4];
+    if memory.peek(pc.wrapping_sub(4), &mut insn).is_ok() {
+        let insn = u32::from_le_bytes(insn);
+        let links_ra = (insn >> 7) & 0x1f == RA as u32;
+        if links_ra && (insn & 0x7f == 0x6f || insn & 0x707f == 0x67) {
+            return true;
+        }
+    }
+    let mut insn = [0; 2];
+    if memory.peek(pc.wrapping_sub(2), &mut insn).is_ok() {
+        let insn = u16::from_le_bytes(insn);
+        return insn & 0xf07f == 0x9002 && (insn >> 7) & 0x1f != 0;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    #[test]
+    fn test_backtrace() {
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX), (0x7000..0x8000, Perm::RW)]);
+        let mut memory = guest.memory();
+        // main calls f at 0x10008 with jal, f calls g at 0x10108 with jalr
+        memory.init(0x10004, &0x0fc0_00efu32.to_le_bytes()).unwrap();
+        memory.init(0x10104, &0x0000_00e7u32.to_le_bytes()).unwrap();
+        let mut symbols = Symbols::new();
+        symbols.add("main", 0x10000, 0x100);
+        symbols.add("f", 0x10100, 0x100);
+        symbols.add("g", 0x10200, 0x100);
+        let unwinder = Unwinder::new().text(0x10000..0x11000).symbols(symbols);
+
+        // f's frame at 0x7f00 returns to main, g is a leaf faulting at 0x10210
+        memory.init(0x7f00 - 8, &0x10008u64.to_le_bytes()).unwrap();
+        memory.init(0x7f00 - 16, &0u64.to_le_bytes()).unwrap();
+        let mut regs = Registers::new(0x10210, 0x7e00);
+        regs.x[RA] = 0x10108;
+        regs.x[FP] = 0x7f00;
+        assert_eq!(
+            unwinder.report(&memory, &regs),
+            "#0  0x10210 in g+0x10\n#1  0x10108 in f+0x8 (ra)\n#2  0x10008 in main+0x8\n"
+        );
+
+        // without frame pointers the return addresses on the stack are found,
+        // and a stale ra into the function at pc is not a frame
+        regs.x[FP] = 0;
+        regs.x[RA] = 0x10218;
+        regs.pc = 0x10210;
+        memory.init(0x7e10, &0x10108u64.to_le_bytes()).unwrap();
+        memory.init(0x7e18, &0x10050u64.to_le_bytes()).unwrap();
+        memory.init(0x7e40, &0x10008u64.to_le_bytes()).unwrap();
+        let frames = unwinder.backtrace(&memory, &regs);
+        let pcs: Vec<_> = frames.iter().map(|frame| (frame.pc, frame.kind)).collect();
+        assert_eq!(pcs, [(0x10210, FrameKind::Pc), (0x10108, FrameKind::Scan), (0x10008, FrameKind::Scan)]);
+    }
+}
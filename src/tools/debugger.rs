@@ -0,0 +1,329 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use crate::frontend::instruction::{Instruction, X_NAMES};
+use crate::middleend::address_map::{GuestMemory, LinearMemory};
+use crate::runtime::execution::GuestExit;
+use crate::runtime::interp::{Interpreter, Stop};
+use crate::runtime::regs::Registers;
+use crate::runtime::syscall::SyscallEnv;
+use crate::tools::backtrace::Unwinder;
+use crate::tools::disasm::annotate;
+use crate::tools::gdb::Breakpoints;
+use crate::tools::inspect::Inspector;
+use crate::tools::opcodes::OpcodeHistogram;
+
+/// Instructions the guest runs between checks for a breakpoint hit while
+/// continuing
+const CONTINUE_BUDGET: u64 = 100_000;
+
+const HELP: &str = "\
+break|b <addr|symbol[+off]>  stop when the guest reaches the address, list them without one
+delete|d [addr|symbol]       remove a breakpoint, all of them without one
+step|s [n]                   run n instructions, 1 by default
+continue|c                   run until a breakpoint, a watchpoint, a fault or the exit
+regs|r                       the integer registers and pc
+mem|x <addr|symbol> [len]    len bytes of guest memory, 64 by default
+bt                           the calls that led to pc
+opcodes                      the instructions run so far by extension and mnemonic
+quit|q                       leave the guest where it is
+an empty line repeats the last command";
+
+/// What the debugger does after a command
+enum Next {
+    Prompt,
+    Quit,
+    Exit(GuestExit),
+}
+
+/// A debugger reading commands from a terminal, for quick triage without
+/// gdb. It runs the guest in the interpreter as `GdbStub` does, putting the
+/// breakpoints into guest memory only while the guest runs, so memory and
+/// the instruction at pc read as the program has them.
+pub struct Debugger<'a, 'm, M: LinearMemory + ?Sized> {
+    interp: &'a mut Interpreter,
+    env: &'a mut SyscallEnv,
+    memory: &'a mut GuestMemory<'m, M>,
+    regs: &'a mut Registers,
+    /// Walks the stack for `bt` and holds the symbols of the guest
+    unwinder: Unwinder,
+    inspector: Inspector,
+    /// The breakpoints set, in memory or not
+    breaks: BTreeSet<u64>,
+    inserted: Breakpoints,
+    last: String,
+}
+
+impl<'a, 'm, M: LinearMemory + ?Sized> Debugger<'a, 'm, M> {
+    pub fn new(
+        interp: &'a mut Interpreter,
+        env: &'a mut SyscallEnv,
+        memory: &'a mut GuestMemory<'m, M>,
+        regs: &'a mut Registers,
+    ) -> Self {
+        Self {
+            interp,
+            env,
+            memory,
+            regs,
+            unwinder: Unwinder::new(),
+            inspector: Inspector::new(),
+            breaks: BTreeSet::new(),
+            inserted: Breakpoints::new(),
+            last: String::new(),
+        }
+    }
+
+    /// The unwinder whose symbols addresses are named and looked up by
+    pub fn unwinder(mut self, unwinder: Unwinder) -> Self {
+        self.unwinder = unwinder;
+        self
+    }
+
+    /// The segments `mem` names
+    pub fn inspector(mut self, inspector: Inspector) -> Self {
+        self.inspector = inspector;
+        self
+    }
+
+    /// Read commands from `input` until it ends, `quit` or the guest exits,
+    /// which is returned
+    pub fn run(&mut self, mut input: impl BufRead, mut out: impl Write) -> io::Result<Option<GuestExit>> {
+        self.location(&mut out)?;
+        loop {
+            write!(out, "(djdb) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = match line.trim() {
+                "" => self.last.clone(),
+                line => {
+                    self.last = line.to_string();
+                    self.last.clone()
+                }
+            };
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else { continue };
+            let args: Vec<_> = words.collect();
+            match self.command(command, &args, &mut out)? {
+                Next::Prompt => {}
+                Next::Quit => return Ok(None),
+                Next::Exit(exit) => return Ok(Some(exit)),
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str, args: &[&str], out: &mut impl Write) -> io::Result<Next> {
+        match (command, args) {
+            ("break" | "b", []) => {
+                for &addr in &self.breaks {
+                    writeln!(out, "{}", self.describe(addr))?;
+                }
+            }
+            ("break" | "b", [at]) => match self.address(at) {
+                Some(addr) => {
+                    self.breaks.insert(addr);
+                    writeln!(out, "Breakpoint at {}", self.describe(addr))?;
+                }
+                None => writeln!(out, "no symbol {}", at)?,
+            },
+            ("delete" | "d", []) => self.breaks.clear(),
+            ("delete" | "d", [at]) => match self.address(at).filter(|addr| self.breaks.remove(addr)) {
+                Some(addr) => writeln!(out, "Deleted the breakpoint at {}", self.describe(addr))?,
+                None => writeln!(out, "no breakpoint at {}", at)?,
+            },
+            ("step" | "s", []) => return self.step(1, out),
+            ("step" | "s", [count]) => match count.parse() {
+                Ok(count) => return self.step(count, out),
+                Err(_) => writeln!(out, "not a count: {}", count)?,
+            },
+            ("continue" | "c", []) => return self.resume(out),
+            ("regs" | "r", []) => self.registers(out)?,
+            ("mem" | "x", [at, rest @ ..]) if rest.len() <= 1 => {
+                let len = match rest.first().map(|len| parse_number(len)) {
+                    None => Some(64),
+                    Some(len) => len,
+                };
+                match (self.address(at), len) {
+                    (Some(addr), Some(len)) => {
+                        write!(out, "{}", self.inspector.hexdump(self.memory, None, addr, len as usize))?
+                    }
+                    (None, _) => writeln!(out, "no symbol {}", at)?,
+                    (_, None) => writeln!(out, "not a length: {}", rest[0])?,
+                }
+            }
+            ("opcodes", []) => write!(out, "{}", OpcodeHistogram::from_blocks(self.memory, self.interp.blocks()).report())?,
+            ("bt", []) => write!(out, "{}", self.unwinder.report(self.memory, self.regs))?,
+            ("help" | "h", _) => writeln!(out, "{}", HELP)?,
+            ("quit" | "q", []) => return Ok(Next::Quit),
+            _ => writeln!(out, "unknown command {}, try help", [command].iter().chain(args).copied().collect::<Vec<_>>().join(" "))?,
+        }
+        Ok(Next::Prompt)
+    }
+
+    fn step(&mut self, count: u64, out: &mut impl Write) -> io::Result<Next> {
+        for _ in 0..count {
+            if let Err(stop) = self.interp.step_instruction(self.env, self.memory, self.regs) {
+                return self.stopped(stop, out);
+            }
+        }
+        self.location(out)?;
+        Ok(Next::Prompt)
+    }
+
+    /// Run until something stops the guest. The instruction at pc runs
+    /// before the breakpoints go in, so one the guest stopped at does not
+    /// stop it again.
+    fn resume(&mut self, out: &mut impl Write) -> io::Result<Next> {
+        if let Err(stop) = self.interp.step_instruction(self.env, self.memory, self.regs) {
+            return self.stopped(stop, out);
+        }
+        for &addr in &self.breaks {
+            let mut first = [0];
+            let compressed = self.memory.peek(addr, &mut first).is_ok() && first[0] & 0b11 != 0b11;
+            if !self.inserted.insert(self.memory, addr, compressed) {
+                writeln!(out, "cannot put a breakpoint at {:#x}", addr)?;
+            }
+        }
+        let stop = loop {
+            match self.interp.run(self.env, self.memory, self.regs, CONTINUE_BUDGET) {
+                Stop::Yield | Stop::Hot(_) => {}
+                stop => break stop,
+            }
+        };
+        self.inserted.clear(self.memory);
+        self.stopped(stop, out)
+    }
+
+    /// Say why the guest stopped and where
+    fn stopped(&mut self, stop: Stop, out: &mut impl Write) -> io::Result<Next> {
+        match stop {
+            Stop::Exit(exit) => {
+                writeln!(out, "{}", exit)?;
+                return Ok(Next::Exit(exit));
+            }
+            Stop::Breakpoint(pc) if self.breaks.contains(&pc) => writeln!(out, "Breakpoint at {}", self.describe(pc))?,
+            Stop::Breakpoint(_) => writeln!(out, "The guest ran an ebreak")?,
+            Stop::Watchpoint(hit) => writeln!(out, "Watchpoint: {:?} of {} bytes at {:#x}", hit.access, hit.len, hit.vaddr)?,
+            Stop::Fault(fault) => writeln!(out, "Fault: {}", fault)?,
+            Stop::Illegal(_) => writeln!(out, "Illegal instruction")?,
+            Stop::Unsupported(_) => writeln!(out, "The interpreter does not implement this instruction")?,
+            Stop::Yield | Stop::Hot(_) | Stop::Compiled(_) => {}
+        }
+        self.location(out)?;
+        Ok(Next::Prompt)
+    }
+
+    /// pc, its symbol and the instruction there
+    fn location(&self, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.regs.pc;
+        let mut code = [0; 4];
+        // a compressed instruction may end the mapping
+        let asm = match self.memory.peek(pc, &mut code[..2]) {
+            Err(_) => "<not mapped>".to_string(),
+            Ok(()) => {
+                if code[0] & 0b11 == 0b11 {
+                    let _ = self.memory.peek(pc + 2, &mut code[2..]);
+                }
+                match Instruction::decode(&code) {
+                    Some((instruction, _)) => annotate(&instruction.instr, pc, &self.unwinder.symbols),
+                    None => "<unknown>".to_string(),
+                }
+            }
+        };
+        writeln!(out, "{}: {}", self.describe(pc), asm)
+    }
+
+    fn registers(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "pc   {:#018x}", self.regs.pc)?;
+        for (index, name) in X_NAMES.iter().enumerate().skip(1) {
+            write!(out, "{:<4} {:#018x}", name, self.regs.x[index])?;
+            if index % 4 == 3 || index == 31 {
+                writeln!(out)?;
+            } else {
+                write!(out, "  ")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A number in hex or decimal, or a function with an optional offset
+    fn address(&self, text: &str) -> Option<u64> {
+        if let Some(number) = parse_number(text) {
+            return Some(number);
+        }
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, parse_number(offset)?),
+            None => (text, 0),
+        };
+        Some(self.unwinder.symbols.function(name)?.start + offset)
+    }
+
+    fn describe(&self, addr: u64) -> String {
+        match self.unwinder.symbols.resolve(addr) {
+            Some((name, 0)) => format!("{:#x} <{}>", addr, name),
+            Some((name, offset)) => format!("{:#x} <{}+{:#x}>", addr, name, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::execution::ExecutionResult;
+    use crate::runtime::syscall::testing::TestMemory;
+    use crate::tools::flamegraph::Symbols;
+
+    #[test]
+    fn test_break_step_continue() {
+        let mut code = Vec::new();
+        for insn in [
+            0x0010_0513u32, // addi a0, zero, 1
+            0x0015_0513,    // addi a0, a0, 1
+            0x0015_0513,    // addi a0, a0, 1
+            0x05e0_0893,    // addi a7, zero, 94
+            0x0000_0073,    // ecall
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+        let config = Config::new();
+        let mut env = SyscallEnv::new(&config);
+        let mut interp = Interpreter::new(&config);
+        let mut regs = Registers::new(0x10000, 0);
+        let mut symbols = Symbols::new();
+        symbols.add("main", 0x10000, 20);
+
+        let input = "b main+4\nc\nr\ns\n\nx main 8\nbt\nc\n";
+        let mut out = Vec::new();
+        let exit = Debugger::new(&mut interp, &mut env, &mut memory, &mut regs)
+            .unwinder(Unwinder::new().symbols(symbols))
+            .run(input.as_bytes(), &mut out)
+            .unwrap();
+        assert_eq!(exit, Some(GuestExit::Process(ExecutionResult::Exited(3))));
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("0x10000 <main>: addi a0,zero,1\n"), "{}", out);
+        assert!(out.contains("Breakpoint at 0x10004 <main+0x4>\n(djdb) Breakpoint at 0x10004 <main+0x4>\n0x10004 <main+0x4>: "));
+        assert!(out.contains("a0   0x0000000000000001"));
+        // the empty line stepped again
+        assert!(out.contains("0x10008 <main+0x8>: ") && out.contains("0x1000c <main+0xc>: "));
+        // the breakpoint is out of memory while the guest is stopped
+        assert!(out.contains("0000000000010000  13 05 10 00 13 05 15 00"));
+        assert!(out.contains("#0  0x1000c in main+0xc\n"));
+    }
+}
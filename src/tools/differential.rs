@@ -0,0 +1,209 @@
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+use crate::runtime::execution::ExecutionResult;
+use crate::runtime::syscall::syscall_name;
+
+/// An emulator the VM is checked against, run as a subprocess on the same
+/// ELF
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reference {
+    /// `qemu-riscv64` at the path, whose `-strace` log gives the syscalls
+    QemuUser(PathBuf),
+    /// `spike` with the proxy kernel `pk`, which logs no syscalls
+    Spike { spike: PathBuf, pk: PathBuf },
+}
+
+impl Reference {
+    /// Run `elf` with `args`, its argv without the program name
+    pub fn run(&self, elf: &Path, args: &[String]) -> io::Result<Run> {
+        let mut command = match self {
+            Reference::QemuUser(qemu) => {
+                let mut command = Command::new(qemu);
+                command.arg("-strace");
+                command
+            }
+            Reference::Spike { spike, pk } => {
+                let mut command = Command::new(spike);
+                command.arg(pk);
+                command
+            }
+        };
+        let output = command.arg(elf).args(args).output()?;
+        let syscalls = match self {
+            Reference::QemuUser(_) => Some(parse_strace(&String::from_utf8_lossy(&output.stderr))),
+            Reference::Spike { .. } => None,
+        };
+        Ok(Run { stdout: output.stdout, result: result(output.status), syscalls })
+    }
+}
+
+/// What a run of a guest did, as far as runs on different emulators are
+/// expected to agree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub stdout: Vec<u8>,
+    pub result: ExecutionResult,
+    /// The names of the syscalls made, in order, when the emulator logs
+    /// them
+    pub syscalls: Option<Vec<String>>,
+}
+
+impl Run {
+    pub fn new(stdout: Vec<u8>, result: ExecutionResult) -> Self {
+        Self { stdout, result, syscalls: None }
+    }
+
+    /// Add the syscalls of `SyscallEnv::syscall_log`
+    pub fn syscalls(mut self, log: &[u64]) -> Self {
+        let names = log.iter().map(|&nr| syscall_name(nr).map_or_else(|| format!("syscall_{}", nr), str::to_string));
+        self.syscalls = Some(names.collect());
+        self
+    }
+}
+
+/// Where a run of the VM departs from the reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// The output differs from this byte on
+    Stdout { offset: usize },
+    Result { vm: ExecutionResult, reference: ExecutionResult },
+    /// The syscall at `index` differs, `None` past the end of a sequence
+    Syscall { index: usize, vm: Option<String>, reference: Option<String> },
+}
+
+/// Compare a run of the VM with one of the reference. Only the first
+/// difference of the output and of the syscalls is reported, everything
+/// after it usually follows from it.
+pub fn compare(vm: &Run, reference: &Run) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    if let (Some(vm), Some(reference)) = (&vm.syscalls, &reference.syscalls) {
+        let index = (0..vm.len().max(reference.len())).find(|&i| vm.get(i) != reference.get(i));
+        if let Some(index) = index {
+            differences.push(Difference::Syscall {
+                index,
+                vm: vm.get(index).cloned(),
+                reference: reference.get(index).cloned(),
+            });
+        }
+    }
+    if vm.stdout != reference.stdout {
+        let offset = vm.stdout.iter().zip(&reference.stdout).take_while(|(a, b)| a == b).count();
+        differences.push(Difference::Stdout { offset });
+    }
+    if vm.result != reference.result {
+        differences.push(Difference::Result { vm: vm.result, reference: reference.result });
+    }
+    differences
+}
+
+fn result(status: ExitStatus) -> ExecutionResult {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => ExecutionResult::Exited(code),
+        (None, Some(signal)) => ExecutionResult::Signaled(signal as u32),
+        (None, None) => ExecutionResult::Exited(-1),
+    }
+}
+
+/// The syscall names of a `qemu -strace` log, whose lines go
+/// `pid name(args) = result`
+fn parse_strace(log: &str) -> Vec<String> {
+    log.lines()
+        .filter_map(|line| {
+            let (pid, call) = line.split_once(' ')?;
+            if pid.is_empty() || !pid.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let name = call.strip_prefix("Unknown syscall ").map_or_else(
+                || call.split_once('(').map(|(name, _)| name.to_string()),
+                |nr| Some(format!("syscall_{}", nr.split_whitespace().next()?)),
+            )?;
+            Some(name).filter(|name| name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_'))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::middleend::address_map::{AddressMap, GuestMemory, SharedMemory};
+    use crate::runtime::config::Config;
+    use crate::runtime::execution::GuestExit;
+    use crate::runtime::interp::Interpreter;
+    use crate::runtime::process;
+    use crate::runtime::syscall::fd::{Fd, FileKind};
+    use crate::runtime::syscall::fs::O_WRONLY;
+    use crate::runtime::syscall::pipe;
+    use crate::runtime::syscall::{SyscallEnv, SYS_EXIT_GROUP, SYS_WRITE};
+    use crate::runtime::tier::Tier;
+    use crate::wasm::block::BlockEngine;
+    use crate::wasm::wasm_builder::WasmBuilder;
+
+    /// Run `data` in the interpreter alone, or with its hot blocks handed to
+    /// the block engine, with stdout going into a pipe
+    fn vm_run(data: &[u8], compiled: bool) -> Run {
+        let config = Config::new().args(["guest".to_string()]).seed(1).record_syscalls(true);
+        let mut env = SyscallEnv::new(&config);
+        let (mut reader, writer) = pipe::pipe();
+        let stdout = pipe::open_file(FileKind::PipeWrite(writer), O_WRONLY);
+        let stdout = Fd { file: Arc::new(Mutex::new(stdout)), cloexec: false };
+        env.fds.lock().unwrap().set(1, stdout).unwrap();
+
+        let mut map = AddressMap::new(AddressMap::DEFAULT_SIZE);
+        let memory = SharedMemory::new(AddressMap::DEFAULT_SIZE);
+        let (_, mut regs) = process::load(data, &config, &env, &mut map, &mut memory.clone()).unwrap();
+        let mut engine = BlockEngine::new(Arc::new(WasmBuilder::new()), &config, &map, &memory).unwrap();
+        let tier = compiled.then_some(&mut engine as &mut dyn Tier<SharedMemory>);
+        let mut interp = Interpreter::new(&config);
+        let mut linear = memory.clone();
+        let mut guest = GuestMemory::new(&mut map, &mut linear);
+        let exit = process::run(&mut interp, tier, &mut env, &mut guest, &mut regs).unwrap();
+        let GuestExit::Process(result) = exit else { panic!("the guest did not end: {:?}", exit) };
+
+        env.fds.lock().unwrap().remove(1).unwrap();
+        let mut stdout = Vec::new();
+        io::Read::read_to_end(&mut reader, &mut stdout).unwrap();
+        let log = env.syscall_log.as_ref().unwrap().lock().unwrap().clone();
+        Run::new(stdout, result).syscalls(&log)
+    }
+
+    #[test]
+    fn test_interpreter_matches_compiled() {
+        let path = format!("{}/test_binaries/arithmetic_test/arithm", env!("CARGO_MANIFEST_DIR"));
+        let data = std::fs::read(path).unwrap();
+        let interpreted = vm_run(&data, false);
+        let stdout = String::from_utf8_lossy(&interpreted.stdout);
+        assert_eq!(stdout.lines().filter(|line| line.ends_with("PASSED")).count(), 32);
+        assert_eq!(compare(&vm_run(&data, true), &interpreted), []);
+    }
+
+    #[test]
+    fn test_compare() {
+        let log = "4242 write(1,0x4000800a70,6) = 6\n\
+                   hello\n\
+                   4242 Unknown syscall 500\n\
+                   4242 exit_group(1)\n";
+        let reference = Run {
+            stdout: b"hello\n".to_vec(),
+            result: ExecutionResult::Exited(1),
+            syscalls: Some(parse_strace(log)),
+        };
+        assert_eq!(reference.syscalls.as_ref().unwrap(), &["write", "syscall_500", "exit_group"]);
+
+        let vm = Run::new(b"hello\n".to_vec(), ExecutionResult::Exited(1)).syscalls(&[SYS_WRITE, 500, SYS_EXIT_GROUP]);
+        assert_eq!(compare(&vm, &reference), []);
+        let vm = Run::new(b"help\n".to_vec(), ExecutionResult::Exited(0)).syscalls(&[SYS_WRITE, SYS_EXIT_GROUP]);
+        assert_eq!(
+            compare(&vm, &reference),
+            [
+                Difference::Syscall { index: 1, vm: Some("exit_group".to_string()), reference: Some("syscall_500".to_string()) },
+                Difference::Stdout { offset: 3 },
+                Difference::Result { vm: ExecutionResult::Exited(0), reference: ExecutionResult::Exited(1) },
+            ]
+        );
+    }
+}
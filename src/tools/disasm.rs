@@ -0,0 +1,134 @@
+use std::io::{self, Write};
+
+use crate::frontend::elf::{ElfError, ElfFile, ProgramHeaderType};
+use crate::frontend::set_class;
+use crate::frontend::instruction::{Instr, Instruction, RV32Instr, RV32I};
+use crate::tools::flamegraph::Symbols;
+
+/// Why a section or function could not be disassembled
+#[derive(Debug, thiserror::Error)]
+pub enum DisasmError {
+    #[error(transparent)]
+    Elf(#[from] ElfError),
+    #[error("no section named {0}")]
+    NoSection(String),
+    #[error("no function named {0}")]
+    NoFunction(String),
+    #[error("{0:#x} is not in a loaded segment")]
+    NotLoaded(u64),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Disassemble `code` loaded at `base`, for the base ISA `set_class`
+/// picked, the way `objdump -d` lays it out:
+/// a header for each function starting in it, then one line per
+/// instruction with its address, its encoding and what the frontend
+/// decodes it to. Jumps and branches show their target and its symbol,
+/// and encodings the decoder does not know show as data.
+pub fn disassemble(code: &[u8], base: u64, symbols: &Symbols, mut out: impl Write) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < code.len() {
+        let pc = base + offset as u64;
+        if let Some((name, 0)) = symbols.resolve(pc) {
+            writeln!(out, "\n{:016x} <{}>:", pc, name)?;
+        }
+        let (asm, len) = match Instruction::decode(&code[offset..]) {
+            Some((instruction, len)) => (annotate(&instruction.instr, pc, symbols), len),
+            None => {
+                let len = if code[offset] & 0b11 == 0b11 { 4 } else { 2 }.min(code.len() - offset);
+                (format!(".{}byte {:#x}", len, word(&code[offset..offset + len])), len)
+            }
+        };
+        let raw = format!("{:0width$x}", word(&code[offset..offset + len]), width = len * 2);
+        writeln!(out, "{:>8x}:\t{:<8}\t{}", pc, raw, asm.replacen(' ', "\t", 1))?;
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Disassemble the section `name` of the ELF `data`, e.g. `.text`
+pub fn disassemble_section(data: &[u8], name: &str, out: impl Write) -> Result<(), DisasmError> {
+    let elf = ElfFile::new(data)?;
+    set_class(elf.header_part1.get_class());
+    let symbols = Symbols::from_elf(data)?;
+    let section = elf
+        .section_iter()
+        .find(|section| section.get_name(&elf).is_ok_and(|other| other == name))
+        .ok_or_else(|| DisasmError::NoSection(name.to_string()))?;
+    disassemble(section.raw_data(&elf), section.get_address(), &symbols, out)?;
+    Ok(())
+}
+
+/// Disassemble the function `name` of the ELF `data`
+pub fn disassemble_function(data: &[u8], name: &str, out: impl Write) -> Result<(), DisasmError> {
+    let elf = ElfFile::new(data)?;
+    set_class(elf.header_part1.get_class());
+    let symbols = Symbols::from_elf(data)?;
+    let range = symbols.function(name).ok_or_else(|| DisasmError::NoFunction(name.to_string()))?;
+    // a function without a size runs up to the end of its segment
+    let code = elf
+        .program_iter()
+        .filter(|ph| ph.get_type() == ProgramHeaderType::Load)
+        .find_map(|ph| {
+            let start = range.start.checked_sub(ph.get_virtual_addr()).filter(|&start| start < ph.get_file_size())?;
+            let end = (range.end - ph.get_virtual_addr()).min(ph.get_file_size());
+            elf.input.get((ph.get_offset() + start) as usize..(ph.get_offset() + end) as usize)
+        })
+        .ok_or(DisasmError::NotLoaded(range.start))?;
+    disassemble(code, range.start, &symbols, out)?;
+    Ok(())
+}
+
+fn word(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |word, &byte| word << 8 | byte as u32)
+}
+
+/// The instruction with the offset of a jump or branch replaced by its
+/// target
+pub fn annotate(instr: &Instr, pc: u64, symbols: &Symbols) -> String {
+    let asm = instr.to_string();
+    let offset = match instr {
+        Instr::RV32(RV32Instr::RV32I(RV32I::JAL(_, imm))) => imm.0,
+        Instr::RV32(RV32Instr::RV32I(
+            RV32I::BEQ(_, _, imm)
+            | RV32I::BNE(_, _, imm)
+            | RV32I::BLT(_, _, imm)
+            | RV32I::BGE(_, _, imm)
+            | RV32I::BLTU(_, _, imm)
+            | RV32I::BGEU(_, _, imm),
+        )) => imm.0,
+        _ => return asm,
+    };
+    let target = pc.wrapping_add(offset as i32 as i64 as u64);
+    let symbol = match symbols.resolve(target) {
+        Some((name, 0)) => format!(" <{}>", name),
+        Some((name, offset)) => format!(" <{}+{:#x}>", name, offset),
+        None => String::new(),
+    };
+    let operands = asm.rsplit_once(',').map_or(asm.as_str(), |(operands, _)| operands);
+    format!("{},{:#x}{}", operands, target, symbol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_function() {
+        let data = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test_binaries/test1")).unwrap();
+        let mut out = Vec::new();
+        disassemble_function(&data, "main", &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[..2], ["", "00000000000103a0 <main>:"]);
+        assert_eq!(lines[3], "   103a4:\t1141    \taddi\tsp,sp,-16");
+        assert_eq!(lines[6], "   103ac:\tfe5ff0ef\tjal\tra,0x10390 <puts@@GLIBC_2.27>");
+        assert_eq!(lines.last(), Some(&"   103b6:\t8082    \tjalr\tzero,0(ra)"));
+
+        let mut out = Vec::new();
+        disassemble_section(&data, ".text", &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("\n00000000000103b8 <_start>:\n"));
+        assert!(matches!(disassemble_function(&data, "missing", io::sink()), Err(DisasmError::NoFunction(_))));
+    }
+}
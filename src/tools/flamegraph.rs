@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::frontend::elf::{ElfFile, ParseResult};
+use crate::runtime::pgo::BlockProfile;
+
+/// Frame the samples of pcs outside any known function go to
+const UNKNOWN: &str = "[unknown]";
+
+/// The functions of a guest binary, sorted by address, to map pcs back to
+/// their names
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    /// Start, end and name of each function
+    functions: Vec<(u64, u64, String)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The function symbols of an ELF, none for a stripped one. A function
+    /// the toolchain left without a size runs up to the next one.
+    pub fn from_elf(data: &[u8]) -> ParseResult<Self> {
+        let elf = ElfFile::new(data)?;
+        let mut symbols = Self::new();
+        for (name, start, size) in elf.functions() {
+            symbols.add(name, start, size);
+        }
+        Ok(symbols)
+    }
+
+    /// Add the function `name` of `size` bytes at `start`
+    pub fn add(&mut self, name: &str, start: u64, size: u64) {
+        let index = self.functions.partition_point(|&(other, _, _)| other < start);
+        // aliases of a function share its address, keep the first
+        if self.functions.get(index).is_some_and(|&(other, _, _)| other == start) {
+            return;
+        }
+        let end = if size == 0 { u64::MAX } else { start.saturating_add(size) };
+        self.functions.insert(index, (start, end, name.to_string()));
+    }
+
+    /// The function `pc` is in
+    pub fn lookup(&self, pc: u64) -> Option<&str> {
+        self.resolve(pc).map(|(name, _)| name)
+    }
+
+    /// The function `pc` is in and the offset of `pc` into it
+    pub fn resolve(&self, pc: u64) -> Option<(&str, u64)> {
+        let index = self.functions.partition_point(|&(start, _, _)| start <= pc).checked_sub(1)?;
+        let range = self.range(index);
+        range.contains(&pc).then(|| (self.functions[index].2.as_str(), pc - range.start))
+    }
+
+    /// The addresses of the function `name`
+    pub fn function(&self, name: &str) -> Option<Range<u64>> {
+        self.functions.iter().position(|(_, _, other)| other == name).map(|index| self.range(index))
+    }
+
+    fn range(&self, index: usize) -> Range<u64> {
+        let (start, end, _) = self.functions[index];
+        let next = self.functions.get(index + 1).map_or(u64::MAX, |&(start, _, _)| start);
+        start..end.min(next)
+    }
+}
+
+/// Samples of guest pcs, taken by reading the live pc periodically (the
+/// pc global of `RegisterView`, or `Registers::pc` in the interpreter) or
+/// weighted by how often each block ran, folded by function into the stack
+/// format `inferno-flamegraph` and `flamegraph.pl` draw. Only the pc is
+/// sampled, so each stack is a single guest function under the root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuestProfile {
+    samples: BTreeMap<u64, u64>,
+}
+
+impl GuestProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The profile of the blocks of `profile`, each weighted by its runs
+    pub fn from_blocks(profile: &BlockProfile) -> Self {
+        let mut guest = Self::new();
+        for (pc, count) in profile.blocks() {
+            guest.add(pc, count);
+        }
+        guest
+    }
+
+    /// The guest was at `pc` when sampled
+    pub fn sample(&mut self, pc: u64) {
+        self.add(pc, 1);
+    }
+
+    pub fn add(&mut self, pc: u64, weight: u64) {
+        *self.samples.entry(pc).or_default() += weight;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.samples.values().sum()
+    }
+
+    /// The samples summed up by function, `[unknown]` for pcs no symbol
+    /// covers
+    pub fn functions(&self, symbols: &Symbols) -> BTreeMap<String, u64> {
+        let mut functions = BTreeMap::new();
+        for (&pc, &count) in &self.samples {
+            *functions.entry(symbols.lookup(pc).unwrap_or(UNKNOWN).to_string()).or_default() += count;
+        }
+        functions
+    }
+
+    /// One `root;function count` line per function, `root` naming the
+    /// guest program
+    pub fn write_folded(&self, symbols: &Symbols, root: &str, mut out: impl Write) -> io::Result<()> {
+        for (function, count) in self.functions(symbols) {
+            writeln!(out, "{};{} {}", root, function, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbolize_and_fold() {
+        let data = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test_binaries/test1")).unwrap();
+        let symbols = Symbols::from_elf(&data).unwrap();
+        assert_eq!(symbols.lookup(0x103a4), Some("main"));
+        assert_eq!(symbols.lookup(0x103b8), Some("_start"));
+        // frame_dummy has no size and runs up to __libc_csu_init
+        assert_eq!(symbols.lookup(0x10462), Some("frame_dummy"));
+        assert_eq!(symbols.lookup(0x10), None);
+
+        let mut blocks = BlockProfile::new();
+        for _ in 0..3 {
+            blocks.record(0x103a0, 0x10390);
+        }
+        blocks.record(0x10464, 0x10470);
+        let mut profile = GuestProfile::from_blocks(&blocks);
+        profile.sample(0x103b0);
+        profile.sample(0x10);
+        assert_eq!(profile.total(), 6);
+        let mut folded = Vec::new();
+        profile.write_folded(&symbols, "test1", &mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "test1;[unknown] 1\ntest1;__libc_csu_init 1\ntest1;main 4\n");
+    }
+}
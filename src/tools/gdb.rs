@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+use crate::middleend::address_map::{GuestFault, GuestMemory, LinearMemory};
+use crate::runtime::execution::{ExecutionResult, GuestExit};
+use crate::runtime::interp::{Interpreter, Stop, WatchAction, WatchKind};
+use crate::runtime::regs::Registers;
+use crate::runtime::syscall::signal::{SIGBUS, SIGILL, SIGSEGV, SIGTRAP};
+use crate::runtime::syscall::SyscallEnv;
+
+/// Instructions the guest runs between checks for a breakpoint hit while
+/// continuing
+const CONTINUE_BUDGET: u64 = 100_000;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+
+/// The registers of the `g` packet: x0 to x31, pc, f0 to f31 and fcsr
+const REGISTERS: usize = 66;
+const PC: usize = 32;
+const FCSR: usize = 65;
+
+/// What the stub does after a packet
+enum Next {
+    Reply(String),
+    /// The guest exited, which ends the session after the reply
+    Exit(String, GuestExit),
+    /// gdb detached, with the reply to send first, or killed the guest
+    Close(Option<&'static str>),
+}
+
+/// A gdb remote serial protocol server debugging a guest in the
+/// interpreter, so riscv64 gdb attaches to the VM over a socket. It reads
+/// and writes registers and memory, single-steps, continues, and puts
+/// software breakpoints into guest memory as ebreaks. Watchpoints stop the
+/// interpreter before the access. The guest only runs
+/// when gdb resumes it, and gdb cannot interrupt it while it does.
+pub struct GdbStub<'a, 'm, M: LinearMemory + ?Sized> {
+    interp: &'a mut Interpreter,
+    env: &'a mut SyscallEnv,
+    memory: &'a mut GuestMemory<'m, M>,
+    regs: &'a mut Registers,
+    breakpoints: Breakpoints,
+    /// The watchpoints gdb set on the interpreter
+    watchpoints: Vec<(Range<u64>, WatchKind)>,
+}
+
+impl<'a, 'm, M: LinearMemory + ?Sized> GdbStub<'a, 'm, M> {
+    pub fn new(
+        interp: &'a mut Interpreter,
+        env: &'a mut SyscallEnv,
+        memory: &'a mut GuestMemory<'m, M>,
+        regs: &'a mut Registers,
+    ) -> Self {
+        Self { interp, env, memory, regs, breakpoints: Breakpoints::new(), watchpoints: Vec::new() }
+    }
+
+    /// Serve gdb on `stream`, a connection it opened, until it detaches,
+    /// kills the guest or closes the connection, or the guest exits, which
+    /// is returned
+    pub fn serve(&mut self, mut stream: impl Read + Write) -> io::Result<Option<GuestExit>> {
+        while let Some(packet) = read_packet(&mut stream)? {
+            match self.handle(&packet) {
+                Next::Reply(reply) => write_packet(&mut stream, &reply)?,
+                Next::Exit(reply, exit) => {
+                    write_packet(&mut stream, &reply)?;
+                    return Ok(Some(exit));
+                }
+                Next::Close(reply) => {
+                    if let Some(reply) = reply {
+                        write_packet(&mut stream, reply)?;
+                    }
+                    break;
+                }
+            }
+        }
+        self.breakpoints.clear(self.memory);
+        for (range, kind) in self.watchpoints.drain(..) {
+            self.interp.unwatch(&range, kind);
+        }
+        Ok(None)
+    }
+
+    fn handle(&mut self, packet: &str) -> Next {
+        let reply = |reply: &str| Next::Reply(reply.to_string());
+        let (command, args) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" => reply("S05"),
+            "g" => Next::Reply((0..REGISTERS).map(|index| self.register(index)).collect()),
+            "G" => {
+                let Some(bytes) = decode_hex(args) else { return reply("E01") };
+                for (index, value) in bytes.chunks(8).take(REGISTERS).enumerate() {
+                    self.set_register(index, value);
+                }
+                reply("OK")
+            }
+            "p" => match parse_hex(args).filter(|&index| (index as usize) < REGISTERS) {
+                Some(index) => Next::Reply(self.register(index as usize)),
+                None => reply("E01"),
+            },
+            "P" => {
+                let Some((index, value)) = args.split_once('=') else { return reply("E01") };
+                match (parse_hex(index), decode_hex(value)) {
+                    (Some(index), Some(value)) if (index as usize) < REGISTERS => {
+                        self.set_register(index as usize, &value);
+                        reply("OK")
+                    }
+                    _ => reply("E01"),
+                }
+            }
+            "m" => {
+                let Some((addr, len)) = parse_range(args) else { return reply("E01") };
+                match self.read_memory(addr, len as usize) {
+                    Some(bytes) => Next::Reply(encode_hex(&bytes)),
+                    None => reply("E14"),
+                }
+            }
+            "M" => {
+                let Some((range, data)) = args.split_once(':') else { return reply("E01") };
+                let (Some(addr), Some(data)) = (range.split_once(',').and_then(|(addr, _)| parse_hex(addr)), decode_hex(data)) else {
+                    return reply("E01");
+                };
+                match self.memory.init(addr, &data) {
+                    Ok(()) => reply("OK"),
+                    Err(_) => reply("E14"),
+                }
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next().and_then(parse_hex), fields.next()) else {
+                    return reply("E01");
+                };
+                let watch = match kind {
+                    "0" => {
+                        let done = match command {
+                            "Z" => self.breakpoints.insert(self.memory, addr, len == "2"),
+                            _ => self.breakpoints.remove(self.memory, addr),
+                        };
+                        return reply(if done { "OK" } else { "E14" });
+                    }
+                    "2" => WatchKind::Write,
+                    "3" => WatchKind::Read,
+                    "4" => WatchKind::Access,
+                    // no hardware breakpoints
+                    _ => return reply(""),
+                };
+                let Some(range) = parse_hex(len).map(|len| addr..addr.saturating_add(len)) else { return reply("E01") };
+                if command == "Z" {
+                    self.interp.watch(range.clone(), watch, WatchAction::Stop);
+                    self.watchpoints.push((range, watch));
+                } else {
+                    self.interp.unwatch(&range, watch);
+                    self.watchpoints.retain(|watchpoint| *watchpoint != (range.clone(), watch));
+                }
+                reply("OK")
+            }
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.regs.pc = addr;
+                }
+                let stop = self.resume(command == "s");
+                stop_reply(stop, &self.watchpoints)
+            }
+            "H" | "T" => reply("OK"),
+            "D" => Next::Close(Some("OK")),
+            "k" => Next::Close(None),
+            "q" => self.query(packet),
+            _ => reply(""),
+        }
+    }
+
+    fn query(&self, packet: &str) -> Next {
+        let reply = match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ if packet.starts_with("qSupported") => "PacketSize=4000;qXfer:features:read+",
+            _ => {
+                let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") else { return Next::Reply(String::new()) };
+                let xml = target_xml();
+                let Some((offset, len)) = parse_range(range) else { return Next::Reply("E01".to_string()) };
+                let (offset, len) = (offset as usize, len as usize);
+                let chunk = xml.get(offset.min(xml.len())..(offset + len).min(xml.len())).unwrap_or_default();
+                let more = if offset + len < xml.len() { "m" } else { "l" };
+                return Next::Reply(format!("{}{}", more, chunk));
+            }
+        };
+        Next::Reply(reply.to_string())
+    }
+
+    /// Run the guest until it stops for the debugger, only the instruction
+    /// at pc when stepping. `None` for a step that completed.
+    fn resume(&mut self, step: bool) -> Option<Stop> {
+        if step {
+            return self.interp.step_instruction(self.env, self.memory, self.regs).err();
+        }
+        loop {
+            match self.interp.run(self.env, self.memory, self.regs, CONTINUE_BUDGET) {
+                Stop::Yield | Stop::Hot(_) => {}
+                stop => return Some(stop),
+            }
+        }
+    }
+
+    /// The register `index` of the `g` packet, as its target bytes in hex
+    fn register(&self, index: usize) -> String {
+        match index {
+            0..32 => encode_hex(&self.regs.x[index].to_le_bytes()),
+            PC => encode_hex(&self.regs.pc.to_le_bytes()),
+            FCSR => encode_hex(&self.regs.csr.fcsr.to_le_bytes()),
+            _ => encode_hex(&self.regs.f[index - PC - 1].to_le_bytes()),
+        }
+    }
+
+    fn set_register(&mut self, index: usize, bytes: &[u8]) {
+        let mut value = [0; 8];
+        let len = bytes.len().min(8);
+        value[..len].copy_from_slice(&bytes[..len]);
+        let value = u64::from_le_bytes(value);
+        match index {
+            0..32 => self.regs.set_x(index, value),
+            PC => self.regs.pc = value,
+            FCSR => self.regs.csr.fcsr = value as u32,
+            _ => self.regs.f[index - PC - 1] = value,
+        }
+    }
+
+    /// Guest memory as gdb sees it, with the code breakpoints replaced
+    fn read_memory(&self, addr: u64, len: usize) -> Option<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.memory.peek(addr, &mut bytes).ok()?;
+        self.breakpoints.hide(addr, &mut bytes);
+        Some(bytes)
+    }
+}
+
+/// Software breakpoints, ebreaks put into guest memory over the code
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    /// The code each breakpoint replaced, by address
+    code: HashMap<u64, Vec<u8>>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.code.contains_key(&addr)
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.code.keys().copied()
+    }
+
+    /// Put an ebreak at `addr`, a compressed one over a compressed
+    /// instruction
+    pub fn insert<M: LinearMemory + ?Sized>(&mut self, memory: &mut GuestMemory<M>, addr: u64, compressed: bool) -> bool {
+        if self.code.contains_key(&addr) {
+            return true;
+        }
+        let ebreak = if compressed { C_EBREAK.to_le_bytes().to_vec() } else { EBREAK.to_le_bytes().to_vec() };
+        let mut code = vec![0; ebreak.len()];
+        if memory.peek(addr, &mut code).is_err() || memory.init(addr, &ebreak).is_err() {
+            return false;
+        }
+        self.code.insert(addr, code);
+        true
+    }
+
+    pub fn remove<M: LinearMemory + ?Sized>(&mut self, memory: &mut GuestMemory<M>, addr: u64) -> bool {
+        match self.code.remove(&addr) {
+            Some(code) => memory.init(addr, &code).is_ok(),
+            None => true,
+        }
+    }
+
+    /// Put the code under every breakpoint back, so the guest runs on
+    /// without the debugger
+    pub fn clear<M: LinearMemory + ?Sized>(&mut self, memory: &mut GuestMemory<M>) {
+        for (addr, code) in self.code.drain() {
+            let _ = memory.init(addr, &code);
+        }
+    }
+
+    /// Replace the ebreaks in `bytes`, read from `addr`, by the code under
+    /// them
+    pub fn hide(&self, addr: u64, bytes: &mut [u8]) {
+        for (&at, code) in &self.code {
+            for (i, &byte) in code.iter().enumerate() {
+                if let Some(offset) = (at + i as u64).checked_sub(addr).filter(|&offset| offset < bytes.len() as u64) {
+                    bytes[offset as usize] = byte;
+                }
+            }
+        }
+    }
+}
+
+/// The reply telling gdb why the guest stopped, `None` for a step. A
+/// watchpoint hit names the first watched address accessed, as gdb looks
+/// for a watchpoint containing it.
+fn stop_reply(stop: Option<Stop>, watchpoints: &[(Range<u64>, WatchKind)]) -> Next {
+    let signal = match stop {
+        Some(Stop::Watchpoint(hit)) => {
+            let end = hit.vaddr.saturating_add(hit.len);
+            let addr = watchpoints
+                .iter()
+                .filter(|(range, kind)| *kind == hit.kind && range.start < end && hit.vaddr < range.end)
+                .map(|(range, _)| range.start.max(hit.vaddr))
+                .min()
+                .unwrap_or(hit.vaddr);
+            let reason = match hit.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            return Next::Reply(format!("T{:02x}{}:{:x};", SIGTRAP, reason, addr));
+        }
+        None | Some(Stop::Breakpoint(_)) => SIGTRAP,
+        Some(Stop::Illegal(_) | Stop::Unsupported(_)) => SIGILL,
+        Some(Stop::Fault(GuestFault::Misaligned { .. })) => SIGBUS,
+        Some(Stop::Fault(_)) => SIGSEGV,
+        Some(Stop::Exit(exit)) => {
+            let reply = match exit {
+                GuestExit::Process(ExecutionResult::Signaled(signal)) => format!("X{:02x}", signal),
+                GuestExit::Process(ExecutionResult::Exited(code)) | GuestExit::Thread(code) => format!("W{:02x}", code as u8),
+                // the new image is not the program gdb debugs
+                GuestExit::Exec(_) => "W00".to_string(),
+            };
+            return Next::Exit(reply, exit);
+        }
+        // the interpreter is never told blocks are compiled here
+        Some(Stop::Yield | Stop::Hot(_) | Stop::Compiled(_)) => SIGTRAP,
+    };
+    Next::Reply(format!("S{:02x}", signal))
+}
+
+/// The target description naming the registers of the `g` packet
+fn target_xml() -> String {
+    let mut xml = String::from(r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0">"#);
+    xml.push_str(r#"<architecture>riscv:rv64</architecture><feature name="org.gnu.gdb.riscv.cpu">"#);
+    for index in 0..32 {
+        write!(xml, r#"<reg name="x{}" bitsize="64" type="int" regnum="{}"/>"#, index, index).unwrap();
+    }
+    write!(xml, r#"<reg name="pc" bitsize="64" type="code_ptr" regnum="{}"/></feature>"#, PC).unwrap();
+    xml.push_str(r#"<feature name="org.gnu.gdb.riscv.fpu">"#);
+    for index in 0..32 {
+        write!(xml, r#"<reg name="f{}" bitsize="64" type="ieee_double" regnum="{}"/>"#, index, PC + 1 + index).unwrap();
+    }
+    write!(xml, r#"<reg name="fcsr" bitsize="32" type="int" regnum="{}"/></feature></target>"#, FCSR).unwrap();
+    xml
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Read the next packet and acknowledge it, skipping the acknowledgements
+/// of gdb and asking again for packets that arrived damaged. `None` once
+/// gdb closed the connection.
+fn read_packet(stream: &mut (impl Read + Write)) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum)?;
+        let valid = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok()) == Some(checksum(&data));
+        stream.write_all(if valid { b"+" } else { b"-" })?;
+        if valid {
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+}
+
+fn write_packet(stream: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data.as_bytes()))?;
+    stream.flush()
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+/// The `addr,len` of memory and transfer packets
+fn parse_range(text: &str) -> Option<(u64, u64)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::Config;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    /// A connection replaying what gdb sends and keeping what the stub
+    /// answers
+    struct Session {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Session {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Session {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut self.output, buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_breakpoint_step_continue() {
+        let mut code = Vec::new();
+        for insn in [
+            0x0010_0513u32, // addi a0, zero, 1
+            0x0015_0513,    // addi a0, a0, 1
+            0x05e0_0893,    // addi a7, zero, 94
+            0x0000_0073,    // ecall
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+        let config = Config::new();
+        let mut env = SyscallEnv::new(&config);
+        let mut interp = Interpreter::new(&config);
+        let mut regs = Registers::new(0x10000, 0);
+
+        let packets = ["?", "Z0,10008,4", "c", "p20", "pa", "m10008,4", "z0,10008,4", "s", "c"];
+        let mut input = Vec::new();
+        for packet in packets {
+            write_packet(&mut input, packet).unwrap();
+            input.push(b'+');
+        }
+        let mut session = Session { input: Cursor::new(input), output: Vec::new() };
+        let exit = GdbStub::new(&mut interp, &mut env, &mut memory, &mut regs).serve(&mut session).unwrap();
+        assert_eq!(exit, Some(GuestExit::Process(ExecutionResult::Exited(2))));
+
+        let output = String::from_utf8(session.output).unwrap();
+        let replies: Vec<_> = output.split('$').skip(1).map(|reply| reply.split('#').next().unwrap()).collect();
+        assert_eq!(
+            replies,
+            ["S05", "OK", "S05", "0800010000000000", "0200000000000000", "9308e005", "OK", "S05", "W02"]
+        );
+        assert_eq!(regs.x[17], 94);
+    }
+}
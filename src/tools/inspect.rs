@@ -0,0 +1,240 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::frontend::elf::{ElfFile, ParseResult};
+use crate::middleend::address_map::{GuestMemory, LinearMemory, Perm};
+use crate::runtime::mmu::Mmu;
+
+/// Bytes of a hexdump line
+const LINE: u64 = 16;
+/// Guest pages, which translations cover
+const PAGE: u64 = 4096;
+/// `sh_flags` of the sections a loader maps
+const SHF_ALLOC: u64 = 2;
+
+/// A named range of the guest's address space
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: Range<u64>,
+    pub name: String,
+}
+
+/// Where a guest address lives: what it translates to through the page
+/// table, where that is in linear memory, and the segment it belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub vaddr: u64,
+    /// The guest physical address, the same as `vaddr` without paging, and
+    /// `None` where the page table maps nothing
+    pub paddr: Option<u64>,
+    /// Offset into the linear memory of the module, `None` where the
+    /// address map has no region
+    pub offset: Option<usize>,
+    pub perm: Option<Perm>,
+    pub segment: Option<String>,
+}
+
+/// Reads guest memory for a debugging embedder the way the guest sees it,
+/// through the page table when the guest turned paging on, and tells which
+/// segment the bytes belong to, instead of working out linear memory
+/// offsets by hand. Reads go around the permission checks and leave no
+/// trace in the guest.
+#[derive(Debug, Clone, Default)]
+pub struct Inspector {
+    /// Sorted by start, the first of overlapping ones naming an address
+    segments: Vec<Segment>,
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name the sections the loader maps of the ELF `data`, loaded at
+    /// `load_bias`
+    pub fn from_elf(data: &[u8], load_bias: u64) -> ParseResult<Self> {
+        let elf = ElfFile::new(data)?;
+        let mut inspector = Self::new();
+        for section in elf.section_iter() {
+            if section.get_flags() & SHF_ALLOC == 0 || section.get_size() == 0 {
+                continue;
+            }
+            // get_name refuses note sections, which keep no interesting data
+            let Ok(name) = section.get_name(&elf) else { continue };
+            let start = section.get_address().wrapping_add(load_bias);
+            inspector = inspector.segment(start..start + section.get_size(), name);
+        }
+        Ok(inspector)
+    }
+
+    /// Name the addresses of `vaddr`, e.g. `[stack]` or `[mmap]`
+    pub fn segment(mut self, vaddr: Range<u64>, name: &str) -> Self {
+        let index = self.segments.partition_point(|segment| segment.vaddr.start <= vaddr.start);
+        self.segments.insert(index, Segment { vaddr, name: name.to_string() });
+        self
+    }
+
+    pub fn segment_of(&self, vaddr: u64) -> Option<&Segment> {
+        self.segments.iter().find(|segment| segment.vaddr.contains(&vaddr))
+    }
+
+    /// Where `vaddr` lives, under the page table of `mmu` if the guest pages
+    pub fn inspect<M: LinearMemory + ?Sized>(&self, memory: &GuestMemory<M>, mmu: Option<&Mmu>, vaddr: u64) -> Location {
+        let paddr = match mmu {
+            Some(mmu) => memory.lookup(mmu, vaddr),
+            None => Some(vaddr),
+        };
+        Location {
+            vaddr,
+            paddr,
+            offset: paddr.and_then(|paddr| memory.map().vaddr_to_offset(paddr)),
+            perm: paddr.and_then(|paddr| memory.map().permission(paddr)),
+            segment: self.segment_of(vaddr).map(|segment| segment.name.clone()),
+        }
+    }
+
+    /// The `len` bytes at `vaddr`, `None` for the ones not mapped
+    pub fn read<M: LinearMemory + ?Sized>(
+        &self,
+        memory: &GuestMemory<M>,
+        mmu: Option<&Mmu>,
+        vaddr: u64,
+        len: usize,
+    ) -> Vec<Option<u8>> {
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let addr = vaddr.wrapping_add(bytes.len() as u64);
+            // translations hold for the rest of the page
+            let n = (PAGE - addr % PAGE).min((len - bytes.len()) as u64) as usize;
+            let paddr = match mmu {
+                Some(mmu) => memory.lookup(mmu, addr),
+                None => Some(addr),
+            };
+            let mut page = vec![0; n];
+            match paddr.map(|paddr| memory.peek(paddr, &mut page)) {
+                Some(Ok(())) => bytes.extend(page.into_iter().map(Some)),
+                _ => bytes.extend(std::iter::repeat_n(None, n)),
+            }
+        }
+        bytes
+    }
+
+    /// The `len` bytes at `vaddr` as lines of an address, 16 bytes in hex
+    /// and as text, `??` for the bytes not mapped. A line with the name and
+    /// range of the segment goes in front of the lines in it.
+    pub fn hexdump<M: LinearMemory + ?Sized>(
+        &self,
+        memory: &GuestMemory<M>,
+        mmu: Option<&Mmu>,
+        vaddr: u64,
+        len: usize,
+    ) -> String {
+        let bytes = self.read(memory, mmu, vaddr, len);
+        let mut out = String::new();
+        let mut current = None;
+        for (index, line) in bytes.chunks(LINE as usize).enumerate() {
+            let addr = vaddr.wrapping_add(index as u64 * LINE);
+            let segment = self.segment_of(addr);
+            if segment != current {
+                match segment {
+                    Some(segment) => {
+                        writeln!(out, "{} {:#x}..{:#x}", segment.name, segment.vaddr.start, segment.vaddr.end).unwrap()
+                    }
+                    None => out.push_str("[unnamed]\n"),
+                }
+                current = segment;
+            }
+            write!(out, "{:016x} ", addr).unwrap();
+            for column in 0..LINE as usize {
+                match line.get(column) {
+                    Some(Some(byte)) => write!(out, " {:02x}", byte).unwrap(),
+                    Some(None) => out.push_str(" ??"),
+                    None => out.push_str("   "),
+                }
+                if column == 7 {
+                    out.push(' ');
+                }
+            }
+            let text: String = line
+                .iter()
+                .map(|byte| byte.filter(|byte| byte.is_ascii_graphic() || *byte == b' ').map_or('.', char::from))
+                .collect();
+            writeln!(out, "  |{}|", text).unwrap();
+        }
+        out
+    }
+}
+
+/// A range of guest memory to check for changes, such as a variable being
+/// corrupted, between runs of the guest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    vaddr: u64,
+    bytes: Vec<Option<u8>>,
+}
+
+impl Watch {
+    /// Watch the `len` bytes at `vaddr` from their contents now
+    pub fn new<M: LinearMemory + ?Sized>(
+        inspector: &Inspector,
+        memory: &GuestMemory<M>,
+        mmu: Option<&Mmu>,
+        vaddr: u64,
+        len: usize,
+    ) -> Self {
+        Self { vaddr, bytes: inspector.read(memory, mmu, vaddr, len) }
+    }
+
+    /// The addresses from the first to the last byte changed since the
+    /// last poll, if any did
+    pub fn poll<M: LinearMemory + ?Sized>(
+        &mut self,
+        inspector: &Inspector,
+        memory: &GuestMemory<M>,
+        mmu: Option<&Mmu>,
+    ) -> Option<Range<u64>> {
+        let bytes = inspector.read(memory, mmu, self.vaddr, self.bytes.len());
+        let changed = |(_, (old, new)): &(usize, (&Option<u8>, &Option<u8>))| old != new;
+        let first = self.bytes.iter().zip(&bytes).enumerate().find(changed)?.0;
+        let last = self.bytes.iter().zip(&bytes).enumerate().rfind(changed)?.0;
+        self.bytes = bytes;
+        Some(self.vaddr + first as u64..self.vaddr + last as u64 + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    #[test]
+    fn test_hexdump_and_watch() {
+        let mut guest = TestMemory::new(&[]);
+        let offset = guest.map.map(0x10000..0x12000, Perm::RW).unwrap();
+        let mut memory = guest.memory();
+        memory.init(0x10ff8, b"Hello, world!\n").unwrap();
+        let inspector = Inspector::new().segment(0x11000..0x12000, ".bss").segment(0x10000..0x11000, ".data");
+
+        let location = inspector.inspect(&memory, None, 0x11004);
+        assert_eq!((location.paddr, location.offset), (Some(0x11004), Some(offset + 0x1004)));
+        assert_eq!((location.perm, location.segment.as_deref()), (Some(Perm::RW), Some(".bss")));
+        assert_eq!(inspector.inspect(&memory, None, 0x20000).offset, None);
+
+        let dump = inspector.hexdump(&memory, None, 0x10ff8, 24);
+        assert_eq!(
+            dump,
+            ".data 0x10000..0x11000\n\
+             0000000000010ff8  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 00  |Hello, world!...|\n\
+             .bss 0x11000..0x12000\n\
+             0000000000011008  00 00 00 00 00 00 00 00                           |........|\n"
+        );
+        let dump = inspector.hexdump(&memory, None, 0x11ffc, 8);
+        assert!(dump.ends_with(" 00 00 00 00 ?? ?? ?? ??                           |........|\n"));
+
+        let mut watch = Watch::new(&inspector, &memory, None, 0x10ff8, 16);
+        assert_eq!(watch.poll(&inspector, &memory, None), None);
+        memory.write(0x10ffa, b"LL").unwrap();
+        assert_eq!(watch.poll(&inspector, &memory, None), Some(0x10ffa..0x10ffc));
+        assert_eq!(watch.poll(&inspector, &memory, None), None);
+    }
+}
@@ -1 +1,11 @@
+pub mod artifacts;
+pub mod backtrace;
+pub mod debugger;
+pub mod differential;
+pub mod disasm;
+pub mod flamegraph;
+pub mod gdb;
+pub mod inspect;
+pub mod opcodes;
 pub mod perf;
+pub mod trace;
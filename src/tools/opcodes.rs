@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::frontend::instruction::{Instr, Instruction, RV32Instr, RV64Instr};
+use crate::middleend::address_map::{GuestMemory, LinearMemory};
+use crate::runtime::interp::block_len;
+
+/// Bytes of a block decoded at most, for one running on into data
+const MAX_BLOCK: usize = 4096;
+/// Bytes read from guest memory at a time while looking for a block's end
+const CHUNK: usize = 64;
+
+/// The instructions a run retired, by mnemonic and by extension, to see
+/// which missing extensions and which optimizations a workload needs. The
+/// counts are the instructions of each block times the runs of the block,
+/// so a block left early, e.g. by a fault, still counts whole.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpcodeHistogram {
+    opcodes: BTreeMap<String, u64>,
+    extensions: BTreeMap<&'static str, u64>,
+    compressed: u64,
+    total: u64,
+}
+
+impl OpcodeHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The histogram of `blocks`, block starts in `memory` with how often
+    /// each ran, as `Interpreter::blocks` and `BlockProfile::blocks` give
+    /// them
+    pub fn from_blocks<M: LinearMemory + ?Sized>(
+        memory: &GuestMemory<M>,
+        blocks: impl IntoIterator<Item = (u64, u64)>,
+    ) -> Self {
+        let mut histogram = Self::new();
+        for (pc, count) in blocks {
+            histogram.add_block(&read_block(memory, pc), count);
+        }
+        histogram
+    }
+
+    /// Count the instructions of `code`, the code of a block, `count` times.
+    /// Encodings the decoder does not know count under their major opcode.
+    pub fn add_block(&mut self, code: &[u8], count: u64) {
+        let mut offset = 0;
+        while offset < code.len() {
+            let (opcode, extension, len) = match Instruction::decode(&code[offset..]) {
+                Some((instruction, len)) => {
+                    let asm = instruction.instr.to_string();
+                    let opcode = asm.split_whitespace().next().unwrap_or_default().to_string();
+                    (opcode, extension(&instruction.instr), len)
+                }
+                None if code[offset] & 0b11 == 0b11 => (format!("unknown {:#04x}", code[offset] & 0x7f), "unknown", 4),
+                None => ("unknown compressed".to_string(), "unknown", 2),
+            };
+            *self.opcodes.entry(opcode).or_default() += count;
+            *self.extensions.entry(extension).or_default() += count;
+            self.compressed += (len == 2) as u64 * count;
+            self.total += count;
+            offset += len;
+        }
+    }
+
+    /// Instructions retired with the mnemonic `opcode`, e.g. `addi`, a
+    /// compressed one counting as the instruction it expands to
+    pub fn opcode(&self, opcode: &str) -> u64 {
+        self.opcodes.get(opcode).copied().unwrap_or(0)
+    }
+
+    /// Instructions retired of the extension `extension`, e.g. `M` or `Zicsr`
+    pub fn extension(&self, extension: &str) -> u64 {
+        self.extensions.get(extension).copied().unwrap_or(0)
+    }
+
+    /// Instructions retired in their compressed encoding
+    pub fn compressed(&self) -> u64 {
+        self.compressed
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// The extensions, then the mnemonics, each with its count and share,
+    /// the most common first
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{:<20} {:>14} {:>7}", "extension", "instructions", "share").unwrap();
+        let extensions = self.extensions.iter().map(|(&name, &count)| (name, count));
+        self.write_rows(&mut out, extensions.chain([("(compressed)", self.compressed)]));
+        writeln!(out, "\n{:<20} {:>14} {:>7}", "opcode", "instructions", "share").unwrap();
+        self.write_rows(&mut out, self.opcodes.iter().map(|(name, &count)| (name.as_str(), count)));
+        writeln!(out, "{:<20} {:>14}", "total", self.total).unwrap();
+        out
+    }
+
+    fn write_rows<'a>(&self, out: &mut String, rows: impl Iterator<Item = (&'a str, u64)>) {
+        let mut rows: Vec<_> = rows.collect();
+        rows.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then(a_name.cmp(b_name)));
+        for (name, count) in rows {
+            let share = count as f64 * 100.0 / self.total.max(1) as f64;
+            writeln!(out, "{:<20} {:>14} {:>6.2}%", name, count, share).unwrap();
+        }
+    }
+}
+
+/// The extension of the base ISA `instr` belongs to
+fn extension(instr: &Instr) -> &'static str {
+    match instr {
+        Instr::RV32(RV32Instr::RV32I(_)) | Instr::RV64(RV64Instr::RV64I(_)) | Instr::NOP => "I",
+        Instr::RV32(RV32Instr::RV32E(_)) | Instr::RV64(RV64Instr::RV64E(_)) => "E",
+        Instr::RV32(RV32Instr::RV32M(_)) | Instr::RV64(RV64Instr::RV64M(_)) => "M",
+        Instr::RV32(RV32Instr::RV32A(_)) | Instr::RV64(RV64Instr::RV64A(_)) => "A",
+        Instr::RV32(RV32Instr::RV32F(_)) | Instr::RV64(RV64Instr::RV64F(_)) => "F",
+        Instr::RV32(RV32Instr::RV32D(_)) | Instr::RV64(RV64Instr::RV64D(_)) => "D",
+        Instr::RV32(RV32Instr::RVB(_)) | Instr::RV64(RV64Instr::RVB(_)) => "B",
+        Instr::RV32(RV32Instr::RVV(_)) | Instr::RV64(RV64Instr::RV64V(_)) => "V",
+        Instr::RV32(RV32Instr::RVZifencei(_)) | Instr::RV64(RV64Instr::RVZifencei(_)) => "Zifencei",
+        Instr::RV32(RV32Instr::RVZcsr(_)) | Instr::RV64(RV64Instr::RVZcsr(_)) => "Zicsr",
+        Instr::RV64(RV64Instr::RVPreviledge(_)) => "privileged",
+        Instr::RV128(_) => "RV128",
+    }
+}
+
+/// The code of the block at `pc`, as far as it is mapped
+fn read_block<M: LinearMemory + ?Sized>(memory: &GuestMemory<M>, pc: u64) -> Vec<u8> {
+    let mut code = Vec::new();
+    // read on until the block ends before the last instruction read, which
+    // may be cut off
+    while code.len() < MAX_BLOCK && block_len(&code) + 4 > code.len() {
+        let mut chunk = [0; CHUNK];
+        let at = pc + code.len() as u64;
+        if memory.peek(at, &mut chunk).is_ok() {
+            code.extend(chunk);
+            continue;
+        }
+        // close to the end of the mapping
+        let mut half = [0; 2];
+        if memory.peek(at, &mut half).is_err() {
+            break;
+        }
+        code.extend(half);
+    }
+    code.truncate(block_len(&code));
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    #[test]
+    fn test_histogram() {
+        let mut code = vec![0x05, 0x05, 0x01, 0x00]; // c.addi a0, 1; c.nop
+        for insn in [
+            0x02b5_0533u32, // mul a0, a0, a1
+            0x0000_0057,    // a vector instruction the decoder does not know
+            0xfe05_1ae3,    // bne a0, zero, -12
+            0x0000_0073,    // ecall, never reached
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        // the block runs up to the end of the mapping
+        memory.init(0x10000, &code).unwrap();
+        memory.init(0x10ffe, &0x0505u16.to_le_bytes()).unwrap();
+
+        let histogram = OpcodeHistogram::from_blocks(&memory, [(0x10000, 10), (0x10ffe, 1)]);
+        assert_eq!(histogram.total(), 51);
+        assert_eq!((histogram.opcode("addi"), histogram.opcode("nop"), histogram.compressed()), (11, 10, 21));
+        assert_eq!((histogram.extension("M"), histogram.opcode("bne"), histogram.opcode("ecall")), (10, 10, 0));
+        assert_eq!((histogram.opcode("unknown 0x57"), histogram.extension("unknown")), (10, 10));
+        let report = histogram.report();
+        assert!(report.contains("\nI                                31  60.78%\n"), "{}", report);
+        assert!(report.ends_with("total                            51\n"));
+    }
+}
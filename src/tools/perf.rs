@@ -1 +1,289 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+#[cfg(feature = "metrics-http")]
+use std::io::{self, BufRead, BufReader};
+#[cfg(feature = "metrics-http")]
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+/// Timers the stages of the pipeline report into
+pub const FRONTEND_DECODE: &str = "frontend.decode";
+pub const MIDDLEEND_EMIT: &str = "middleend.emit";
+/// Time spent emitting each block summed up, which over `middleend.emit`
+/// is the speedup of emitting blocks in parallel
+pub const MIDDLEEND_EMIT_BLOCKS: &str = "middleend.emit.blocks";
+pub const MIDDLEEND_OPTIMIZE: &str = "middleend.optimize";
+pub const BACKEND_COMPILE: &str = "backend.compile";
+pub const RUNTIME_EXECUTE: &str = "runtime.execute";
+/// Counter of the guest instructions retired under `runtime.execute`
+pub const RUNTIME_INSTRUCTIONS: &str = "runtime.execute.instructions";
+pub const RUNTIME_SYSCALL: &str = "runtime.syscall";
+
+/// Shards of the timers, each thread recording into one of them
+const SHARDS: usize = 16;
+
+/// Total time and number of measurements of one timer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timer {
+    pub total: Duration,
+    pub count: u64,
+}
+
+/// Named timers and counters of the pipeline. Clones share them, so the
+/// stages and threads of a run all report into one profiler: counters are
+/// atomics and each thread times into a shard of its own, the shards summed
+/// up when read.
+#[derive(Debug, Default, Clone)]
+pub struct Profiler {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    timers: [Mutex<BTreeMap<String, Timer>>; SHARDS],
+    counters: RwLock<BTreeMap<String, AtomicU64>>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a measurement of `elapsed` to the timer `name`
+    pub fn record(&self, name: &str, elapsed: Duration) {
+        self.add(name, Timer { total: elapsed, count: 1 });
+    }
+
+    /// Run `f`, timing it with the timer `name`
+    pub fn time<R>(&self, name: &str, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = f();
+        self.record(name, start.elapsed());
+        result
+    }
+
+    /// Add the measurements of `timer` to the timer `name`
+    pub fn add(&self, name: &str, timer: Timer) {
+        let mut timers = self.shared.timers[shard()].lock().unwrap();
+        let total = match timers.get_mut(name) {
+            Some(total) => total,
+            None => timers.entry(name.to_string()).or_default(),
+        };
+        total.total += timer.total;
+        total.count += timer.count;
+    }
+
+    pub fn count(&self, name: &str, n: u64) {
+        if let Some(counter) = self.shared.counters.read().unwrap().get(name) {
+            counter.fetch_add(n, Ordering::Relaxed);
+            return;
+        }
+        let mut counters = self.shared.counters.write().unwrap();
+        counters.entry(name.to_string()).or_default().fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn timer(&self, name: &str) -> Option<Timer> {
+        self.timers().remove(name)
+    }
+
+    pub fn counter(&self, name: &str) -> u64 {
+        self.shared.counters.read().unwrap().get(name).map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// The timers of every shard summed up, by name
+    fn timers(&self) -> BTreeMap<String, Timer> {
+        let mut timers = BTreeMap::<String, Timer>::new();
+        for shard in &self.shared.timers {
+            for (name, timer) in shard.lock().unwrap().iter() {
+                let total = timers.entry(name.clone()).or_default();
+                total.total += timer.total;
+                total.count += timer.count;
+            }
+        }
+        timers
+    }
+
+    fn counters(&self) -> BTreeMap<String, u64> {
+        let counters = self.shared.counters.read().unwrap();
+        counters.iter().map(|(name, counter)| (name.clone(), counter.load(Ordering::Relaxed))).collect()
+    }
+
+    /// One line per timer and counter, sorted by name
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        for (name, timer) in &self.timers() {
+            writeln!(out, "{:<24} {:>12.3?} {:>10}x", name, timer.total, timer.count).unwrap();
+        }
+        for (name, count) in &self.counters() {
+            writeln!(out, "{:<24} {:>12}", name, count).unwrap();
+        }
+        out
+    }
+
+    /// The timers and counters as a JSON object, the timers with their total
+    /// in nanoseconds: `{"timers":{"name":{"total_ns":5000000,"count":2}},
+    /// "counters":{"name":7}}`
+    pub fn export_json(&self) -> String {
+        let (timers, counters) = (self.timers(), self.counters());
+        let timers = timers.iter().map(|(name, timer)| {
+            format!("{}:{{\"total_ns\":{},\"count\":{}}}", json_string(name), timer.total.as_nanos(), timer.count)
+        });
+        let counters = counters.iter().map(|(name, count)| format!("{}:{}", json_string(name), count));
+        format!(
+            "{{\"timers\":{{{}}},\"counters\":{{{}}}}}",
+            timers.collect::<Vec<_>>().join(","),
+            counters.collect::<Vec<_>>().join(",")
+        )
+    }
+
+    /// The timers and counters in the Prometheus text format, the name of
+    /// each as the `name` label of the metric families
+    /// `doublejit_timer_seconds_total`, `doublejit_timer_count_total` and
+    /// `doublejit_counter_total`
+    pub fn export_prometheus(&self) -> String {
+        let (timers, counters) = (self.timers(), self.counters());
+        let mut out = String::new();
+        if !timers.is_empty() {
+            out.push_str("# HELP doublejit_timer_seconds_total Time spent in a stage of the pipeline.\n");
+            out.push_str("# TYPE doublejit_timer_seconds_total counter\n");
+            for (name, timer) in &timers {
+                let seconds = timer.total.as_secs_f64();
+                writeln!(out, "doublejit_timer_seconds_total{{name=\"{}\"}} {}", prometheus_label(name), seconds).unwrap();
+            }
+            out.push_str("# HELP doublejit_timer_count_total Measurements of a stage of the pipeline.\n");
+            out.push_str("# TYPE doublejit_timer_count_total counter\n");
+            for (name, timer) in &timers {
+                writeln!(out, "doublejit_timer_count_total{{name=\"{}\"}} {}", prometheus_label(name), timer.count).unwrap();
+            }
+        }
+        if !counters.is_empty() {
+            out.push_str("# HELP doublejit_counter_total Events counted by the VM.\n");
+            out.push_str("# TYPE doublejit_counter_total counter\n");
+            for (name, count) in &counters {
+                writeln!(out, "doublejit_counter_total{{name=\"{}\"}} {}", prometheus_label(name), count).unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// Serve the metrics of `profiler` in the Prometheus text format at
+/// `/metrics` to every client of `listener` until accepting fails. Run it
+/// on a thread of its own with a clone of the profiler the embedder keeps
+/// recording into meanwhile.
+#[cfg(feature = "metrics-http")]
+pub fn serve_metrics(listener: TcpListener, profiler: Profiler) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        let mut request = String::new();
+        if BufReader::new(&stream).read_line(&mut request).is_err() {
+            continue;
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+        let response = match (request.starts_with("GET "), path) {
+            (true, "/metrics") => {
+                let body = profiler.export_prometheus();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        };
+        // a client gone away does not stop the endpoint
+        let _ = io::Write::write_all(&mut stream, response.as_bytes());
+    }
+    Ok(())
+}
+
+/// The timer shard of the calling thread, the threads taking turns
+fn shard() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: usize = NEXT.fetch_add(1, Ordering::Relaxed) % SHARDS;
+    }
+    SHARD.with(|shard| *shard)
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn prometheus_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timers_and_counters() {
+        let profiler = Profiler::new();
+        assert_eq!(profiler.time(MIDDLEEND_EMIT, || 42), 42);
+        profiler.record(MIDDLEEND_EMIT, Duration::from_millis(5));
+        profiler.count("blocks", 3);
+        profiler.count("blocks", 4);
+
+        let timer = profiler.timer(MIDDLEEND_EMIT).unwrap();
+        assert_eq!(timer.count, 2);
+        assert!(timer.total >= Duration::from_millis(5));
+        assert_eq!(profiler.counter("blocks"), 7);
+        assert_eq!(profiler.counter("syscalls"), 0);
+        assert!(profiler.report().starts_with("middleend.emit "));
+    }
+
+    #[test]
+    fn test_export() {
+        let profiler = Profiler::new();
+        assert_eq!(profiler.export_json(), r#"{"timers":{},"counters":{}}"#);
+        assert_eq!(profiler.export_prometheus(), "");
+        profiler.record(RUNTIME_SYSCALL, Duration::from_millis(1500));
+        profiler.count("syscalls \"write\"", 3);
+
+        assert_eq!(
+            profiler.export_json(),
+            r#"{"timers":{"runtime.syscall":{"total_ns":1500000000,"count":1}},"counters":{"syscalls \"write\"":3}}"#
+        );
+        let text = profiler.export_prometheus();
+        assert!(text.contains("# TYPE doublejit_timer_seconds_total counter\n"));
+        assert!(text.contains("doublejit_timer_seconds_total{name=\"runtime.syscall\"} 1.5\n"));
+        assert!(text.contains("doublejit_timer_count_total{name=\"runtime.syscall\"} 1\n"));
+        assert!(text.ends_with("doublejit_counter_total{name=\"syscalls \\\"write\\\"\"} 3\n"));
+    }
+
+    #[test]
+    fn test_shared_across_threads() {
+        let profiler = Profiler::new();
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let profiler = profiler.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        profiler.record(RUNTIME_SYSCALL, Duration::from_micros(1));
+                        profiler.count("syscalls", 1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(profiler.timer(RUNTIME_SYSCALL), Some(Timer { total: Duration::from_millis(8), count: 8000 }));
+        assert_eq!(profiler.counter("syscalls"), 8000);
+        assert_eq!(profiler.report().lines().count(), 2);
+    }
+}
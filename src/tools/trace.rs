@@ -0,0 +1,194 @@
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::runtime::syscall::syscall_name;
+
+/// One thing the guest did, as a line of a trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// A block starting to run in the interpreter
+    Block { pc: u64 },
+    /// An instruction about to run in the interpreter, a compressed one
+    /// expanded, `len` telling it was
+    Instruction { pc: u64, insn: u32, len: u64 },
+    /// A syscall the guest makes. Calls from compiled code come without
+    /// the pc.
+    Syscall { pc: Option<u64>, nr: u64, args: [u64; 6] },
+    /// A syscall returning `ret`, a negated errno when it failed. Calls
+    /// ending the thread do not return.
+    Sysret { nr: u64, ret: u64 },
+}
+
+/// Writes what the guest does as JSON Lines, one object per event with the
+/// nanoseconds since the trace started, the thread and the pc, e.g.
+/// `{"ts":1532,"tid":1,"event":"syscall","pc":65560,"nr":64,"name":"write","args":[1,66000,6,0,0,0]}`,
+/// for tools and CI checks to read instead of the logs. Clones write into
+/// the same trace, so threads and forks of the guest share it.
+#[derive(Clone)]
+pub struct Tracer {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    start: Instant,
+    pub instructions: bool,
+    pub blocks: bool,
+    pub syscalls: bool,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("instructions", &self.instructions)
+            .field("blocks", &self.blocks)
+            .field("syscalls", &self.syscalls)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    /// A trace into `out` recording nothing yet, the kinds of events to
+    /// record are turned on with `instructions`, `blocks` and `syscalls`
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(Box::new(out))),
+            start: Instant::now(),
+            instructions: false,
+            blocks: false,
+            syscalls: false,
+        }
+    }
+
+    /// A trace into the file at `path`
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Record every instruction the interpreter runs, which makes for a
+    /// large trace
+    pub fn instructions(mut self, instructions: bool) -> Self {
+        self.instructions = instructions;
+        self
+    }
+
+    pub fn blocks(mut self, blocks: bool) -> Self {
+        self.blocks = blocks;
+        self
+    }
+
+    pub fn syscalls(mut self, syscalls: bool) -> Self {
+        self.syscalls = syscalls;
+        self
+    }
+
+    /// Whether events of the kind of `event` are recorded
+    pub fn wants(&self, event: &TraceEvent) -> bool {
+        match event {
+            TraceEvent::Instruction { .. } => self.instructions,
+            TraceEvent::Block { .. } => self.blocks,
+            TraceEvent::Syscall { .. } | TraceEvent::Sysret { .. } => self.syscalls,
+        }
+    }
+
+    /// Write `event` of thread `tid`, if its kind is recorded. A failing
+    /// write loses the event, the guest runs on.
+    pub fn record(&self, tid: i32, event: TraceEvent) {
+        if !self.wants(&event) {
+            return;
+        }
+        let mut line = format!("{{\"ts\":{},\"tid\":{},", self.start.elapsed().as_nanos(), tid);
+        match event {
+            TraceEvent::Block { pc } => write!(line, "\"event\":\"block\",\"pc\":{}", pc),
+            TraceEvent::Instruction { pc, insn, len } => {
+                write!(line, "\"event\":\"instruction\",\"pc\":{},\"insn\":{},\"len\":{}", pc, insn, len)
+            }
+            TraceEvent::Syscall { pc, nr, args } => {
+                line.push_str("\"event\":\"syscall\",");
+                if let Some(pc) = pc {
+                    write!(line, "\"pc\":{},", pc).unwrap();
+                }
+                let args = args.iter().map(u64::to_string).collect::<Vec<_>>().join(",");
+                write!(line, "\"nr\":{},\"name\":\"{}\",\"args\":[{}]", nr, syscall_name(nr).unwrap_or("unknown"), args)
+            }
+            TraceEvent::Sysret { nr, ret } => write!(line, "\"event\":\"sysret\",\"nr\":{},\"ret\":{}", nr, ret as i64),
+        }
+        .unwrap();
+        line.push_str("}\n");
+        let _ = self.out.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    /// Write out the events still buffered
+    pub fn flush(&self) -> io::Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::config::{Config, OptLevel};
+    use crate::runtime::interp::{Interpreter, Stop};
+    use crate::runtime::regs::Registers;
+    use crate::runtime::syscall::SyscallEnv;
+    use crate::runtime::syscall::testing::TestMemory;
+
+    /// A buffer the test keeps a handle on while the tracer writes into it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut code = Vec::new();
+        for insn in [
+            0x0ac0_0893u32, // addi a7, zero, 172
+            0x0000_0073,    // ecall, getpid
+            0x05d0_0893,    // addi a7, zero, 93
+            0x0000_0073,    // ecall, exit with the pid left in a0
+        ] {
+            code.extend(insn.to_le_bytes());
+        }
+        let mut guest = TestMemory::new(&[(0x10000..0x11000, Perm::RX)]);
+        let mut memory = guest.memory();
+        memory.init(0x10000, &code).unwrap();
+
+        let out = Shared::default();
+        let tracer = Tracer::new(out.clone()).blocks(true).syscalls(true);
+        let config = Config::new().opt_level(OptLevel::Tiered { threshold: 100 }).trace(tracer);
+        let mut env = SyscallEnv::new(&config);
+        let mut regs = Registers::new(0x10000, 0);
+        let stop = Interpreter::new(&config).run(&mut env, &mut memory, &mut regs, u64::MAX);
+        assert!(matches!(stop, Stop::Exit(_)));
+
+        let trace = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        // the timestamps vary, the rest of each line does not
+        let events: Vec<_> = trace.lines().map(|line| line.split_once(",\"tid\":").unwrap().1).collect();
+        let tid = env.tid;
+        assert_eq!(
+            events,
+            [
+                format!("{},\"event\":\"block\",\"pc\":65536}}", tid),
+                format!("{},\"event\":\"syscall\",\"pc\":65540,\"nr\":172,\"name\":\"getpid\",\"args\":[0,0,0,0,0,0]}}", tid),
+                format!("{},\"event\":\"sysret\",\"nr\":172,\"ret\":{}}}", tid, tid),
+                format!("{},\"event\":\"block\",\"pc\":65544}}", tid),
+                format!("{},\"event\":\"syscall\",\"pc\":65548,\"nr\":93,\"name\":\"exit\",\"args\":[{},0,0,0,0,0]}}", tid, tid),
+            ]
+        );
+        assert!(trace.lines().all(|line| line.starts_with("{\"ts\":")));
+
+        let tracer = Tracer::new(io::sink()).instructions(true);
+        assert!(tracer.wants(&TraceEvent::Instruction { pc: 0, insn: 0x13, len: 4 }));
+        assert!(!tracer.wants(&TraceEvent::Sysret { nr: 172, ret: 0 }));
+    }
+}
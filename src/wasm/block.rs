@@ -0,0 +1,808 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use wasmer::{
+    Function, FunctionEnv, FunctionEnvMut, Global, Imports, Instance, Module, Store, Table, TableType, Type, TypedFunction, Value,
+};
+
+use crate::codegen::optimizer::Optimizer;
+use crate::codegen::regmap::{count_uses, RegMap};
+use crate::error::VmError;
+use crate::frontend::cache::CodeCache;
+use crate::middleend::address_map::{AddressMap, GuestMemory, LinearMemory, Region, SharedMemory};
+use crate::middleend::emit_wasm::{
+    block_export, content_hash, emit_block_module, emit_chain_module, emit_blocks_module, BLOCK_EXPORT, BLOCK_TABLE, CHAIN_EXPORT, EXIT_SITE_GLOBAL, INSTR_BUDGET_GLOBAL,
+    INSTR_COUNT_GLOBAL, NEXT_SLOT_GLOBAL, OSR_GLOBAL,
+};
+use crate::middleend::translate::{is_loop, read_trace, translate_block, translate_loop, translate_trace, GuestBlock};
+use crate::runtime::config::{Config, OptLevel};
+use crate::runtime::dispatch::{BlockSlots, Dispatcher, InlineCaches, OsrEntry};
+use crate::runtime::pgo::BlockProfile;
+use crate::runtime::regs::Registers;
+use crate::runtime::syscall::SyscallEnv;
+use crate::runtime::tier::{Leave, Tier};
+use crate::tools::perf::{Profiler, BACKEND_COMPILE, MIDDLEEND_EMIT, RUNTIME_EXECUTE};
+use crate::wasm::disk_cache::DiskCache;
+use crate::wasm::error::BackendError;
+use crate::wasm::memory::alias_memory;
+use crate::wasm::state::{osr_exit, RegisterView};
+use crate::wasm::trap::{yield_trap, BudgetExhausted};
+use crate::wasm::wasm_builder::WasmBuilder;
+
+/// Instructions between two looks of the first tier at the block the guest
+/// is in when tiering, a loop it is found at getting optimized
+pub const SAMPLE_INTERVAL: u64 = 1 << 12;
+
+/// A compiled block, to call from the host or to link into the table
+#[derive(Clone)]
+struct Block {
+    func: Function,
+    run: TypedFunction<(), i64>,
+}
+
+/// A store compiled code runs in, with the guest memory, the register
+/// globals and the host functions its modules are instantiated with, but
+/// `budget_exhausted`, which differs between the tiers
+struct Lane {
+    store: Store,
+    imports: Imports,
+    registers: RegisterView,
+    /// `$instr_count` and `$instr_budget` of the code
+    count: Global,
+    budget: Global,
+    /// Compiled blocks by slot, through which block exits go straight to
+    /// the next block without returning to the dispatcher
+    table: Table,
+    next_slot: Global,
+    exit_site: Global,
+}
+
+impl Lane {
+    /// The lane of `store`, over the guest memory of the interpreter
+    fn new(mut store: Store, map: &AddressMap, memory: &SharedMemory) -> Result<Self, BackendError> {
+        let mut imports = Imports::new();
+        let memory = alias_memory(&mut store, map, memory)?;
+        imports.define("env", "memory", memory);
+        let registers = RegisterView::define(&mut store, &mut imports);
+        let count = Global::new_mut(&mut store, Value::I64(0));
+        let budget = Global::new_mut(&mut store, Value::I64(0));
+        imports.define("env", INSTR_COUNT_GLOBAL, count.clone());
+        imports.define("env", INSTR_BUDGET_GLOBAL, budget.clone());
+        // faults and devices are left to the interpreter, which runs the
+        // access again from the state the helpers left
+        let fault = Function::new_typed(&mut store, |_: i32, _: i64| -> Result<(), wasmer::RuntimeError> {
+            Err(wasmer::RuntimeError::new("guest fault"))
+        });
+        let mmio_read = Function::new_typed(&mut store, |_: i64, _: i32| -> Result<i64, wasmer::RuntimeError> {
+            Err(wasmer::RuntimeError::new("device access"))
+        });
+        let mmio_write = Function::new_typed(&mut store, |_: i64, _: i32, _: i64| -> Result<(), wasmer::RuntimeError> {
+            Err(wasmer::RuntimeError::new("device access"))
+        });
+        imports.define("env", "guest_fault", fault);
+        imports.define("env", "mmio_read", mmio_read);
+        imports.define("env", "mmio_write", mmio_write);
+
+        let table = Table::new(&mut store, TableType::new(Type::FuncRef, 0, None), Value::FuncRef(None))?;
+        let next_slot = Global::new_mut(&mut store, Value::I32(-1));
+        let exit_site = Global::new_mut(&mut store, Value::I64(0));
+        imports.define("env", BLOCK_TABLE, table.clone());
+        imports.define("env", NEXT_SLOT_GLOBAL, next_slot.clone());
+        imports.define("env", EXIT_SITE_GLOBAL, exit_site.clone());
+        Ok(Self { store, imports, registers, count, budget, table, next_slot, exit_site })
+    }
+
+    /// Hand the registers and the instruction count over to `other`, for
+    /// the guest to go on in code of its store
+    fn hand_over(&self, other: &mut Lane) -> Result<(), BackendError> {
+        let mut regs = Registers::new(0, 0);
+        self.registers.save(&self.store, &mut regs);
+        other.registers.load(&mut other.store, &regs)?;
+        Ok(other.count.set(&mut other.store, self.count.get(&self.store))?)
+    }
+}
+
+/// What the `budget_exhausted` host function of the first tier decides
+/// on: whether the slice is over, or else whether the guest is at a loop to
+/// compile with the optimizing tier, which the loop leaves for at its next
+/// back-edge
+struct Sampler {
+    builder: Arc<WasmBuilder>,
+    registers: RegisterView,
+    count: Global,
+    budget: Global,
+    osr_target: Global,
+    /// The instruction count the slice of the current run ends at
+    end: u64,
+    /// The optimized modules of the loops of the first tier, by header
+    pending: HashMap<u64, String>,
+    /// Those compiled, for the engine to instantiate when the guest leaves
+    /// for them
+    ready: HashMap<u64, Result<Module, BackendError>>,
+}
+
+/// The `budget_exhausted` host function of the first tier when tiering
+fn sample(mut env: FunctionEnvMut<Sampler>) -> Result<(), wasmer::RuntimeError> {
+    let (sampler, mut store) = env.data_and_store_mut();
+    let count = sampler.count.get(&store).i64().unwrap_or_default() as u64;
+    if count >= sampler.end {
+        return Err(yield_trap());
+    }
+    let pc = sampler.registers.pc(&store);
+    if let Some(wat) = sampler.pending.remove(&pc) {
+        // a failed compilation is reported once the loop leaves for it
+        sampler.ready.insert(pc, sampler.builder.compile_hot(wat.as_bytes()));
+        sampler.osr_target.set(&mut store, Value::I64(pc as i64))?;
+    }
+    let next = count.saturating_add(SAMPLE_INTERVAL).min(sampler.end);
+    sampler.budget.set(&mut store, Value::I64(next as i64))
+}
+
+/// The compiled tier of `process::run`: translates the blocks the
+/// interpreter found hot into one module each and runs the guest in them,
+/// over the memory of the interpreter and with the registers in globals,
+/// until it reaches code left to the interpreter. When tiering, a block
+/// looping on itself is compiled again as a loop with the optimizing tier
+/// once the guest is found in it, and the block leaves at its back-edge for
+/// the loop, on-stack replacement. The loops run in a store of the
+/// optimizing engine, `loop_lane`, over the same memory.
+pub struct BlockEngine {
+    builder: Arc<WasmBuilder>,
+    /// What the blocks are translated for
+    config: Config,
+    /// Where the blocks of the first tier run
+    lane: Lane,
+    loop_lane: Lane,
+    sampler: FunctionEnv<Sampler>,
+    /// The block at each pc reached, `None` where the interpreter runs the
+    /// first instruction
+    dispatcher: Dispatcher<Option<Block>>,
+    /// Blocks by the hash of their module, so blocks translating to the
+    /// same code share one compilation. They stay valid when the guest
+    /// rewrites its code, the hash changes with the code.
+    modules: HashMap<[u8; 32], Block>,
+    /// The loops compiled with the optimizing tier, by their header
+    loops: HashMap<u64, Block>,
+    optimizer: Optimizer,
+    slots: BlockSlots,
+    caches: InlineCaches,
+    chain: TypedFunction<i64, i64>,
+    /// The regions of the address map the blocks were translated for,
+    /// whose address translation they have built in
+    regions: Vec<Region>,
+    /// Blocks the interpreter found hot, the only ones compiled when
+    /// tiering
+    hot: HashSet<u64>,
+    /// Blocks compiled since `take_compiled`
+    compiled: Vec<u64>,
+    /// The profile the blocks run are recorded into, see
+    /// `Config::record_blocks`
+    profile: Option<Arc<Mutex<BlockProfile>>>,
+    /// Whether the blocks of the block profile were compiled for the code
+    /// the guest has now
+    prewarmed: bool,
+    /// Where the modules of the blocks are kept across runs of the binary
+    cache: Option<DiskCache>,
+    profiler: Option<Profiler>,
+}
+
+impl BlockEngine {
+    /// The tier of a guest running over `memory` as `map` lays it out,
+    /// compiling with `builder` as `config` asks
+    pub fn new(builder: Arc<WasmBuilder>, config: &Config, map: &AddressMap, memory: &SharedMemory) -> Result<Self, BackendError> {
+        let mut lane = Lane::new(builder.store(), map, memory)?;
+        let mut loop_lane = Lane::new(builder.hot_store(), map, memory)?;
+        let exhausted = Function::new_typed(&mut loop_lane.store, || -> Result<(), wasmer::RuntimeError> { Err(yield_trap()) });
+        loop_lane.imports.define("env", "budget_exhausted", exhausted);
+
+        let osr_target = Global::new_mut(&mut lane.store, Value::I64(0));
+        lane.imports.define("env", OSR_GLOBAL, osr_target.clone());
+        let leave = Function::new_typed(&mut lane.store, osr_exit);
+        lane.imports.define("env", "osr_exit", leave);
+        let sampler = Sampler {
+            builder: builder.clone(),
+            registers: lane.registers.clone(),
+            count: lane.count.clone(),
+            budget: lane.budget.clone(),
+            osr_target,
+            end: 0,
+            pending: HashMap::new(),
+            ready: HashMap::new(),
+        };
+        let sampler = FunctionEnv::new(&mut lane.store, sampler);
+        let exhausted = Function::new_typed_with_env(&mut lane.store, &sampler, sample);
+        lane.imports.define("env", "budget_exhausted", exhausted);
+
+        let mut wat = String::new();
+        emit_chain_module(&mut wat);
+        let instance = Instance::new(&mut lane.store, &builder.compile(wat.as_bytes())?, &lane.imports)?;
+        let chain = instance.exports.get_typed_function(&lane.store, CHAIN_EXPORT)?;
+        let mut optimizer = Optimizer::new(OptLevel::Optimized).verify(config.verify_optimizer);
+        if let Some(fuel) = config.optimizer_fuel {
+            optimizer = optimizer.fuel(fuel);
+        }
+        Ok(Self {
+            builder,
+            config: config.clone(),
+            lane,
+            loop_lane,
+            sampler,
+            dispatcher: Dispatcher::new(),
+            modules: HashMap::new(),
+            loops: HashMap::new(),
+            optimizer,
+            slots: BlockSlots::new(),
+            caches: InlineCaches::new(),
+            chain,
+            regions: map.regions().to_vec(),
+            hot: HashSet::new(),
+            compiled: Vec::new(),
+            profile: config.record_blocks.clone(),
+            prewarmed: false,
+            cache: None,
+            profiler: config.profiler.clone(),
+        })
+    }
+
+    /// Keep the compiled modules of the blocks in `cache`, and load them
+    /// from there in later runs instead of compiling them again
+    pub fn disk_cache(mut self, cache: DiskCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// The instructions compiled code ran so far
+    pub fn instructions(&self) -> u64 {
+        self.lane.count.get(&self.lane.store).i64().unwrap_or_default() as u64
+    }
+
+    /// The loops compiled with the optimizing tier so far
+    pub fn loops(&self) -> usize {
+        self.loops.len()
+    }
+
+    fn tiered(&self) -> bool {
+        matches!(self.builder.opt_level(), OptLevel::Tiered { .. })
+    }
+
+    /// Drop every block when the guest rewrote its code or changed its
+    /// mappings, which the address translation of the blocks has built in
+    fn sync_code(&mut self, code: &CodeCache, map: &AddressMap) -> Result<(), BackendError> {
+        let remapped = self.regions != map.regions();
+        if remapped {
+            self.regions = map.regions().to_vec();
+            self.dispatcher.clear();
+            self.modules.clear();
+        }
+        if self.dispatcher.invalidated(code) || remapped {
+            self.prewarmed = false;
+            self.loops.clear();
+            let sampler = self.sampler.as_mut(&mut self.lane.store);
+            sampler.pending.clear();
+            sampler.ready.clear();
+            let osr_target = sampler.osr_target.clone();
+            osr_target.set(&mut self.lane.store, Value::I64(0))?;
+            unlink_all(&mut self.lane.store, &self.lane.table)?;
+        }
+        Ok(())
+    }
+
+    /// The block at `pc`, translated from `memory` and compiled when it is
+    /// reached for the first time, with the blocks following it on its
+    /// trace when there is a block profile. When tiering, the module of
+    /// the loop a block makes of its own is kept for the sampler to compile.
+    fn block<M: LinearMemory + ?Sized>(&mut self, code: &CodeCache, memory: &GuestMemory<M>, pc: u64) -> Result<Option<Block>, BackendError> {
+        let tiered = self.tiered();
+        let Self { builder, config, lane, sampler, dispatcher, modules, optimizer, slots, caches, compiled, cache, profiler, .. } = self;
+        dispatcher.get_or_compile(pc, code, |pc| {
+            let start = profiler.is_some().then(Instant::now);
+            let Some((block, body)) = translate_at(memory, config, slots, caches, pc) else {
+                return Ok(None);
+            };
+            let mut wat = String::new();
+            emit_block_module(&mut wat, memory.map(), config, &body);
+            if tiered && is_loop(&block) {
+                let looped = loop_module(&block, memory, config, optimizer, slots, caches, profiler.as_ref())?;
+                sampler.as_mut(&mut lane.store).pending.insert(pc, looped);
+            }
+            record(profiler, MIDDLEEND_EMIT, start);
+            compiled.push(pc);
+            let hash = content_hash(&wat);
+            if let Some(block) = modules.get(&hash) {
+                return Ok(Some(block.clone()));
+            }
+            let start = profiler.is_some().then(Instant::now);
+            let module = compile(builder, cache.as_ref(), &wat)?;
+            record(profiler, BACKEND_COMPILE, start);
+            let block = instantiate(&mut lane.store, &lane.imports, &module)?;
+            modules.insert(hash, block.clone());
+            Ok(Some(block))
+        })
+    }
+
+    /// Compile the blocks of the block profile together, see
+    /// `emit_blocks_module`, before the guest reaches them. When tiering
+    /// only the blocks that got hot in the profile are.
+    fn prewarm<M: LinearMemory + ?Sized>(&mut self, memory: &GuestMemory<M>) -> Result<(), BackendError> {
+        let Some(guide) = self.config.block_profile.clone() else {
+            return Ok(());
+        };
+        if std::mem::replace(&mut self.prewarmed, true) {
+            return Ok(());
+        }
+        let tiered = self.tiered();
+        let start = self.profiler.is_some().then(Instant::now);
+        let mut blocks = Vec::new();
+        for (pc, count) in guide.blocks() {
+            if tiered && !self.builder.opt_level().is_hot(count) {
+                continue;
+            }
+            let Some((block, body)) = translate_at(memory, &self.config, &mut self.slots, &self.caches, pc) else {
+                continue;
+            };
+            if tiered && is_loop(&block) {
+                let Self { config, optimizer, slots, caches, profiler, .. } = self;
+                let looped = loop_module(&block, memory, config, optimizer, slots, caches, profiler.as_ref())?;
+                self.sampler.as_mut(&mut self.lane.store).pending.insert(pc, looped);
+            }
+            blocks.push((pc, body));
+        }
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let mut wat = String::new();
+        emit_blocks_module(&mut wat, memory.map(), &self.config, &blocks, Some(&guide));
+        record(&self.profiler, MIDDLEEND_EMIT, start);
+        let start = self.profiler.is_some().then(Instant::now);
+        let module = compile(&self.builder, self.cache.as_ref(), &wat)?;
+        record(&self.profiler, BACKEND_COMPILE, start);
+        let instance = Instance::new(&mut self.lane.store, &module, &self.lane.imports)?;
+        for (pc, _) in blocks {
+            let func = instance.exports.get_function(&block_export(pc))?.clone();
+            let run = func.typed(&self.lane.store)?;
+            self.dispatcher.insert(pc, Some(Block { func, run }));
+            self.hot.insert(pc);
+            self.compiled.push(pc);
+        }
+        Ok(())
+    }
+
+    /// Instantiate in the loop lane the loops the sampler compiled, and
+    /// unlink their blocks, so the dispatcher gets the guest to the loops
+    fn take_ready(&mut self) -> Result<(), BackendError> {
+        let ready = std::mem::take(&mut self.sampler.as_mut(&mut self.lane.store).ready);
+        for (header, module) in ready {
+            let looped = instantiate(&mut self.loop_lane.store, &self.loop_lane.imports, &module?)?;
+            self.loops.insert(header, looped);
+            unlink(&mut self.lane.store, &self.lane.table, self.slots.slot(header))?;
+        }
+        Ok(())
+    }
+
+    /// Run a loop of the optimizing tier in the loop lane, with the
+    /// registers and instruction count handed over there and back
+    fn enter_loop(&mut self, run: &TypedFunction<(), i64>) -> Result<Result<i64, wasmer::RuntimeError>, BackendError> {
+        self.lane.hand_over(&mut self.loop_lane)?;
+        let end = self.sampler.as_ref(&self.lane.store).end;
+        self.loop_lane.budget.set(&mut self.loop_lane.store, Value::I64(end as i64))?;
+        let result = run.call(&mut self.loop_lane.store);
+        self.loop_lane.hand_over(&mut self.lane)?;
+        Ok(result)
+    }
+
+    /// Run blocks from `pc` until one leaves for the interpreter. Blocks
+    /// are linked: the dispatcher only sees exits to blocks not compiled
+    /// yet and computed jumps the inline cache missed. A block whose cache
+    /// misses a target is compiled again with the target added.
+    fn run_blocks<M: LinearMemory + ?Sized>(
+        &mut self,
+        env: &SyscallEnv,
+        memory: &GuestMemory<M>,
+        mut pc: u64,
+    ) -> Result<Leave, BackendError> {
+        let tiered = self.tiered();
+        loop {
+            if tiered && !self.hot.contains(&pc) {
+                return Ok(Leave::Uncompiled(pc));
+            }
+            self.take_ready()?;
+            if let Some(looped) = self.loops.get(&pc).cloned() {
+                let start = self.profiler.is_some().then(Instant::now);
+                let result = self.enter_loop(&looped.run)?;
+                record(&self.profiler, RUNTIME_EXECUTE, start);
+                pc = match next(result) {
+                    Ok(next) => next,
+                    Err(leave) => return Ok(leave),
+                };
+                continue;
+            }
+            let block = self.block(&env.code.lock().unwrap(), memory, pc)?;
+            let Some(block) = block else {
+                return Ok(Leave::Uncompiled(pc));
+            };
+            let Self { lane: Lane { store, table, next_slot, exit_site, .. }, slots, caches, dispatcher, chain, profile, profiler, .. } = self;
+            // recording and debugging see every block, which linked blocks
+            // would run past
+            let linked = profile.is_none() && !env.debug;
+            if env.debug {
+                tracing::trace!(target: "doublejit::block", tid = env.tid, pc = format_args!("{:#x}", pc));
+            }
+            let start = profiler.is_some().then(Instant::now);
+            let result = if linked {
+                let slot = slots.slot(pc);
+                link(store, table, slot, block.func)?;
+                next_slot.set(store, Value::I32(slot as i32))?;
+                chain.call(store, pc as i64)
+            } else {
+                block.run.call(store)
+            };
+            record(profiler, RUNTIME_EXECUTE, start);
+            let next = match next(result) {
+                Ok(next) => next,
+                Err(leave) => return Ok(leave),
+            };
+            if next_slot.get(store).i32() == Some(-1) {
+                let site = exit_site.get(store).i64().unwrap_or_default() as u64;
+                if caches.record(site, next) {
+                    dispatcher.remove(site);
+                    unlink(store, table, slots.slot(site))?;
+                }
+            }
+            if let Some(profile) = profile {
+                profile.lock().unwrap().record(pc, next);
+            }
+            pc = next;
+        }
+    }
+}
+
+impl Tier<SharedMemory> for BlockEngine {
+    fn compile(&mut self, env: &SyscallEnv, memory: &GuestMemory<SharedMemory>, pc: u64) -> Result<bool, VmError> {
+        self.sync_code(&env.code.lock().unwrap(), memory.map())?;
+        self.prewarm(memory)?;
+        self.hot.insert(pc);
+        let block = self.block(&env.code.lock().unwrap(), memory, pc)?;
+        Ok(block.is_some())
+    }
+
+    fn run(
+        &mut self,
+        env: &mut SyscallEnv,
+        memory: &mut GuestMemory<SharedMemory>,
+        regs: &mut Registers,
+        budget: u64,
+    ) -> Result<Leave, VmError> {
+        self.sync_code(&env.code.lock().unwrap(), memory.map())?;
+        self.prewarm(&*memory)?;
+        let start = self.instructions();
+        let end = start.saturating_add(budget);
+        self.sampler.as_mut(&mut self.lane.store).end = end;
+        // without tiering there is nothing to sample for
+        let first = match self.tiered() {
+            true => end.min(start.saturating_add(SAMPLE_INTERVAL)),
+            false => end,
+        };
+        let lane = &mut self.lane;
+        lane.registers.load(&mut lane.store, regs)?;
+        lane.budget.set(&mut lane.store, Value::I64(first as i64)).map_err(BackendError::from)?;
+        let leave = self.run_blocks(env, &*memory, regs.pc)?;
+        self.lane.registers.save(&self.lane.store, regs);
+        if let Leave::Uncompiled(pc) = leave {
+            regs.pc = pc;
+        }
+        env.clock.retire(self.instructions() - start);
+        Ok(leave)
+    }
+
+    fn take_compiled(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.compiled)
+    }
+}
+
+/// The module of the loop `block` makes of its own, see `translate_loop`,
+/// optimized, with the registers it uses most in locals
+fn loop_module<M: LinearMemory + ?Sized>(
+    block: &GuestBlock,
+    memory: &GuestMemory<M>,
+    config: &Config,
+    optimizer: &mut Optimizer,
+    slots: &mut BlockSlots,
+    caches: &InlineCaches,
+    profiler: Option<&Profiler>,
+) -> Result<String, BackendError> {
+    let body = translate_loop(block, memory.map(), config, &trace_regs(std::slice::from_ref(block)), slots, caches);
+    let mut wat = String::new();
+    emit_block_module(&mut wat, memory.map(), config, &body);
+    Ok(optimizer.run(&wat, profiler)?)
+}
+
+/// Compile the module `wat`, or load it from `cache` where an earlier run
+/// compiled it
+fn compile(builder: &WasmBuilder, cache: Option<&DiskCache>, wat: &str) -> Result<Module, BackendError> {
+    match cache {
+        Some(cache) => cache.get_or_compile(builder, &DiskCache::module_key(wat), || builder.compile(wat.as_bytes())),
+        None => builder.compile(wat.as_bytes()),
+    }
+}
+
+/// The code of the block at `pc` and the block, with the blocks following
+/// it on its trace when there is a block profile, see `translate_trace`,
+/// and the registers they use most in locals.
+/// `None` where the interpreter runs the first instruction.
+fn translate_at<M: LinearMemory + ?Sized>(
+    memory: &GuestMemory<M>,
+    config: &Config,
+    slots: &mut BlockSlots,
+    caches: &InlineCaches,
+    pc: u64,
+) -> Option<(GuestBlock, String)> {
+    let guide = config.block_profile.as_deref();
+    let blocks = match guide {
+        Some(guide) => read_trace(memory, config.strict_memory, guide, pc),
+        None => GuestBlock::read(memory, config.strict_memory, pc).into_iter().collect(),
+    };
+    let regs = trace_regs(&blocks);
+    let body = match blocks.len() {
+        0 => return None,
+        1 => translate_block(&blocks[0], memory.map(), config, &regs, slots, caches, guide),
+        _ => translate_trace(&blocks, config, &regs, slots, caches, guide),
+    };
+    blocks.into_iter().next().map(|block| (block, body))
+}
+
+/// The registers `blocks` use most, kept in locals in their code
+fn trace_regs(blocks: &[GuestBlock]) -> RegMap {
+    let mut uses = [0; 32];
+    for insn in blocks.iter().flat_map(GuestBlock::words) {
+        count_uses(&mut uses, insn);
+    }
+    RegMap::from_uses(&uses)
+}
+
+/// Add the time since `start` to the timer `name`, when profiling
+fn record(profiler: &Option<Profiler>, name: &str, start: Option<Instant>) {
+    if let (Some(profiler), Some(start)) = (profiler, start) {
+        profiler.record(name, start.elapsed());
+    }
+}
+
+/// The pc compiled code continues at, or why it leaves for the interpreter.
+/// A block leaving for the optimized code of its loop continues at the
+/// header, with every register in its global.
+fn next(result: Result<i64, wasmer::RuntimeError>) -> Result<u64, Leave> {
+    match result {
+        Ok(next) => Ok(next as u64),
+        Err(trap) if trap.is::<BudgetExhausted>() => Err(Leave::Yield),
+        Err(trap) => match trap.downcast::<OsrEntry>() {
+            Ok(OsrEntry(header)) => {
+                tracing::debug!(target: "doublejit::backend", pc = header, "on-stack replacement");
+                Ok(header)
+            }
+            Err(trap) => {
+                tracing::debug!(target: "doublejit::backend", "compiled code trapped: {}", trap.message());
+                Err(Leave::Trap)
+            }
+        },
+    }
+}
+
+/// Instantiate a block module and get the function running the block
+fn instantiate(store: &mut Store, imports: &Imports, module: &Module) -> Result<Block, BackendError> {
+    let instance = Instance::new(store, module, imports)?;
+    let func = instance.exports.get_function(BLOCK_EXPORT)?.clone();
+    let run = func.typed(store)?;
+    Ok(Block { func, run })
+}
+
+/// Put a compiled block in its slot, so exits naming the slot call it
+fn link(store: &mut Store, table: &Table, slot: u32, func: Function) -> Result<(), BackendError> {
+    let size = table.size(store);
+    if slot >= size {
+        table.grow(store, slot + 1 - size, Value::FuncRef(None))?;
+    }
+    Ok(table.set(store, slot, Value::FuncRef(Some(func)))?)
+}
+
+/// Empty the slot of a block about to be compiled again
+fn unlink(store: &mut Store, table: &Table, slot: u32) -> Result<(), BackendError> {
+    if slot < table.size(store) {
+        table.set(store, slot, Value::FuncRef(None))?;
+    }
+    Ok(())
+}
+
+/// Empty every slot after the guest rewrote its code, so no exit goes to a
+/// stale block
+fn unlink_all(store: &mut Store, table: &Table) -> Result<(), BackendError> {
+    for slot in 0..table.size(store) {
+        table.set(store, slot, Value::FuncRef(None))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::address_map::Perm;
+    use crate::runtime::execution::{ExecutionResult, GuestExit};
+    use crate::runtime::interp::Interpreter;
+    use crate::runtime::limits::Limit;
+    use crate::runtime::process;
+
+    /// How a guest ran: the exit, the instructions retired in all, the
+    /// engine, the word at 0x11000 and the registers it ended with
+    struct Outcome {
+        exit: Result<GuestExit, VmError>,
+        instret: u64,
+        engine: BlockEngine,
+        stored: u64,
+        regs: Registers,
+    }
+
+    /// Run the guest `code` at 0x10000, with data at 0x11000, through
+    /// `process::run` with a `BlockEngine`
+    fn run(code: &[u32], config: &Config) -> Outcome {
+        let code: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut env = SyscallEnv::new(config);
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RX).unwrap();
+        map.map(0x11000..0x12000, Perm::RW).unwrap();
+        let memory = SharedMemory::new(1 << 20);
+        let mut engine = BlockEngine::new(Arc::new(WasmBuilder::with_opt_level(config.opt_level)), config, &map, &memory).unwrap();
+        let mut linear = memory.clone();
+        let mut guest = GuestMemory::new(&mut map, &mut linear);
+        guest.init(0x10000, &code).unwrap();
+        guest.write_perm_table().unwrap();
+
+        let mut interp = Interpreter::new(config);
+        let mut regs = Registers::new(0x10000, 0x12000);
+        let exit = process::run(&mut interp, Some(&mut engine), &mut env, &mut guest, &mut regs);
+        let mut stored = [0; 8];
+        guest.peek(0x11000, &mut stored).unwrap();
+        Outcome { exit, instret: env.clock.instret(), engine, stored: u64::from_le_bytes(stored), regs }
+    }
+
+    /// Run a guest adding 3 to a0 a thousand times, storing it and exiting
+    /// with it. Gives the instructions retired in all and the engine.
+    fn run_loop(config: &Config) -> (u64, BlockEngine) {
+        let code = [
+            0x0000_0513u32, // addi a0, zero, 0
+            0x3e80_0293,    // addi t0, zero, 1000
+            0x0035_0513,    // loop: addi a0, a0, 3
+            0xfff2_8293,    // addi t0, t0, -1
+            0xfe02_9ce3,    // bnez t0, loop
+            0x0001_1337,    // lui t1, 0x11
+            0x00a3_3023,    // sd a0, 0(t1)
+            0x07f5_7513,    // andi a0, a0, 127
+            0x05e0_0893,    // addi a7, zero, 94
+            0x0000_0073,    // ecall, exit_group
+        ];
+        let outcome = run(&code, config);
+        assert_eq!(outcome.exit.unwrap(), GuestExit::Process(ExecutionResult::Exited(3000 & 127)));
+        assert_eq!(outcome.stored, 3000);
+        (outcome.instret, outcome.engine)
+    }
+
+    #[test]
+    fn test_loop_runs_compiled() {
+        // every block is compiled once it ran, the loop after its first
+        // two iterations
+        let (instret, engine) = run_loop(&Config::new());
+        assert_eq!(instret, 2 + 3 * 1000 + 5);
+        assert_eq!(engine.instructions(), 3 * 998 + 4);
+        assert_eq!(engine.loops(), 0);
+    }
+
+    #[test]
+    fn test_trace_leaves_at_side_exit() {
+        // the profile saw the loop left every time, so its trace goes on
+        // into the block after it; each iteration but the last leaves the
+        // trace through the side exit, back to the header
+        let mut profile = BlockProfile::new();
+        profile.record(0x10008, 0x10014);
+        let (instret, engine) = run_loop(&Config::new().block_profile(profile));
+        assert_eq!(instret, 2 + 3 * 1000 + 5);
+        assert_eq!(engine.instructions(), 3 * 999 + 4);
+        // the block after the loop only ran on the trace
+        let (_, unguided) = run_loop(&Config::new());
+        assert_eq!(engine.dispatcher.len(), unguided.dispatcher.len() - 1);
+    }
+
+    #[test]
+    fn test_profiled_blocks_compile_ahead() {
+        // a first run records the blocks compiled code ran
+        let recorded = Arc::new(Mutex::new(BlockProfile::new()));
+        let (_, engine) = run_loop(&Config::new().record_blocks(recorded.clone()));
+        assert_eq!(engine.instructions(), 3 * 998 + 4);
+        let profile = recorded.lock().unwrap().clone();
+        assert_eq!((profile.edge(0x10008, 0x10008), profile.edge(0x10008, 0x10014)), (997, 1));
+
+        // the next one compiles them in one module once the first block
+        // got hot, so the loop runs compiled from its second iteration
+        let (instret, engine) = run_loop(&Config::new().block_profile(profile));
+        assert_eq!(instret, 2 + 3 * 1000 + 5);
+        assert_eq!(engine.instructions(), 3 * 999 + 4);
+        assert_eq!(engine.modules.len(), 1);
+    }
+
+    #[test]
+    fn test_hot_loop_tiers_up() {
+        // only the loop gets hot, after the interpreter ran it 101 times
+        let (instret, engine) = run_loop(&Config::new().opt_level(OptLevel::Tiered { threshold: 100 }));
+        assert_eq!(instret, 2 + 3 * 1000 + 5);
+        assert_eq!(engine.instructions(), 3 * 899);
+    }
+
+    #[test]
+    fn test_loop_leaves_for_optimized_code() {
+        // the interpreter leaves the loop at its header once it is hot, the
+        // first tier runs it until the sampler finds the guest there, then
+        // its back-edge leaves for the loop cranelift compiled, which ends
+        // in the loop lane with the registers the interpreter would have
+        let code = [
+            0x0000_0513u32, // addi a0, zero, 0
+            0x0000_42b7,    // lui t0, 4, 16384 iterations
+            0x0035_0513,    // loop: addi a0, a0, 3
+            0xfff2_8293,    // addi t0, t0, -1
+            0xfe02_9ce3,    // bnez t0, loop
+            0x0001_1337,    // lui t1, 0x11
+            0x00a3_3023,    // sd a0, 0(t1)
+            0x07f5_7513,    // andi a0, a0, 127
+            0x05e0_0893,    // addi a7, zero, 94
+            0x0000_0073,    // ecall, exit_group
+        ];
+        let config = Config::new().opt_level(OptLevel::Tiered { threshold: 100 }).verify_optimizer(true);
+        let outcome = run(&code, &config);
+        assert_eq!(outcome.exit.unwrap(), GuestExit::Process(ExecutionResult::Exited(0)));
+        assert_eq!(outcome.stored, 3 * 16384);
+        assert_eq!(outcome.instret, 2 + 3 * 16384 + 5);
+        let mut expected = Registers::new(0x10024, 0x12000);
+        expected.x[6] = 0x11000;
+        expected.x[17] = 94;
+        assert_eq!(outcome.regs.x, expected.x);
+
+        let engine = outcome.engine;
+        assert_eq!(engine.loops(), 1);
+        assert_eq!(engine.loop_lane.count.get(&engine.loop_lane.store).i64(), Some(engine.instructions() as i64));
+    }
+
+    #[test]
+    fn test_mapped_registers_survive_exits() {
+        // every block keeps the registers it uses in locals, which have to
+        // be back in their globals at the call, the return and the branch
+        let code = [
+            0x0000_0513u32, // addi a0, zero, 0
+            0x0070_0593,    // addi a1, zero, 7
+            0x0640_0293,    // addi t0, zero, 100
+            0x0140_00ef,    // loop: jal ra, add
+            0xfff2_8293,    // addi t0, t0, -1
+            0xfe02_9ce3,    // bnez t0, loop
+            0x05e0_0893,    // addi a7, zero, 94
+            0x0000_0073,    // ecall, exit_group
+            0x00b5_0533,    // add: add a0, a0, a1
+            0x0015_8593,    // addi a1, a1, 1
+            0x0000_8067,    // ret
+        ];
+        let outcome = run(&code, &Config::new());
+        assert!(matches!(outcome.exit, Ok(GuestExit::Process(ExecutionResult::Exited(_)))));
+        assert_eq!(outcome.instret, 3 + 6 * 100 + 2);
+        assert!(outcome.engine.instructions() > 0);
+        let mut expected = Registers::new(0x1001c, 0x12000);
+        expected.x[1] = 0x10010;
+        expected.x[10] = (0..100).map(|n| 7 + n).sum();
+        expected.x[11] = 107;
+        expected.x[17] = 94;
+        assert_eq!(outcome.regs.x, expected.x);
+    }
+
+    #[test]
+    fn test_spinning_guest_stops() {
+        // j . runs compiled from its second iteration, until the budget of
+        // the last slice ends exactly at the limit
+        let outcome = run(&[0x0000_006f], &Config::new().max_instructions(100_000));
+        assert!(matches!(outcome.exit, Err(VmError::LimitExceeded(Limit::Instructions(100_000)))));
+        assert_eq!(outcome.instret, 100_000);
+        assert_eq!(outcome.engine.instructions(), 100_000 - 1);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use wasmer::Module;
+
+use crate::error::VmError;
+use crate::runtime::config::Config;
+use crate::wasm::wasm_builder::WasmBuilder;
+
+/// Version of the translation, bumped whenever the emitted code changes so
+/// modules cached by an older build are not picked up
+pub const EMITTER_VERSION: u32 = 2;
+
+/// The start of every cached artifact, followed by `EMITTER_VERSION` and
+/// the checksum of the key and the artifact
+const MAGIC: &[u8; 8] = b"djitmod\0";
+const HEADER_LEN: usize = MAGIC.len() + 4 + 32;
+
+/// Compiled modules of guest binaries kept across runs, so running the
+/// same binary again skips compiling its blocks
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The cache of the modules of the binary `elf` loaded at `load_bias`,
+    /// in the directory `config` asks for, if any. Each binary has a
+    /// directory of its own, named by its `key`.
+    pub fn for_binary(config: &Config, elf: &[u8], load_bias: u64) -> Option<Self> {
+        let dir = config.cache_dir.as_ref()?;
+        Some(Self::new(dir.join(Self::key(elf, config, load_bias))))
+    }
+
+    /// The cache of the user, `$XDG_CACHE_HOME/doublejit` or
+    /// `~/.cache/doublejit`
+    pub fn user() -> Option<Self> {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(Self::new(base.join("doublejit")))
+    }
+
+    /// The key of the module translated from `elf` loaded at `load_bias`
+    /// with `config`: the hash of the binary, the version of the
+    /// translation and every setting that changes the emitted or compiled
+    /// code
+    pub fn key(elf: &[u8], config: &Config, load_bias: u64) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(elf);
+        hasher.update(format!(
+            "{} {} {:?} {} {} {} {} {} {:?} {:?} {} {:#x}",
+            env!("CARGO_PKG_VERSION"),
+            EMITTER_VERSION,
+            config.opt_level,
+            config.strict_memory,
+            config.mmu,
+            config.max_instructions.is_some() || config.max_wall_time.is_some(),
+            config.instrumented(),
+            config.verify_optimizer,
+            config.optimizer_fuel,
+            config.cycles_per_instruction.map(f64::to_bits),
+            config.permissive_csrs,
+            load_bias,
+        ));
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// The key of the module compiled from `wat` in the cache of a binary,
+    /// whose blocks translate to modules of their own
+    pub fn module_key(wat: &str) -> String {
+        Sha256::digest(wat.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.wasmu", key))
+    }
+
+    /// The module cached under `key`. An artifact another wasmer version or
+    /// target wrote is treated as missing, and so is one whose header does
+    /// not match its key and contents, or that others than the user could
+    /// have written.
+    pub fn load(&self, builder: &WasmBuilder, key: &str) -> Option<Module> {
+        let path = self.path(key);
+        if !private(&self.dir) || !private(&path) {
+            tracing::warn!(target: "doublejit::backend", path = %path.display(), "cached module writable by others");
+            return None;
+        }
+        let file = fs::read(&path).ok()?;
+        let Some(artifact) = unseal(key, &file) else {
+            tracing::warn!(target: "doublejit::backend", key, "corrupt cached module");
+            return None;
+        };
+        // SAFETY: the artifact was written by `store`, as its checksum
+        // shows, into a directory only the user running the guest can write
+        match unsafe { builder.deserialize_module(artifact) } {
+            Ok(module) => Some(module),
+            Err(e) => {
+                tracing::debug!(target: "doublejit::backend", key, error = %e, "stale cached module");
+                None
+            }
+        }
+    }
+
+    /// Cache `module` under `key`. The artifact is written next to its
+    /// place and renamed into it, so a concurrent run never loads half of it.
+    pub fn store(&self, key: &str, module: &Module) -> Result<(), VmError> {
+        fs::create_dir_all(&self.dir)?;
+        let artifact = WasmBuilder::serialize_module(module)?;
+        let temp = self.dir.join(format!("{}.{}.tmp", key, std::process::id()));
+        fs::write(&temp, seal(key, &artifact))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700))?;
+            fs::set_permissions(&temp, fs::Permissions::from_mode(0o600))?;
+        }
+        fs::rename(&temp, self.path(key))?;
+        Ok(())
+    }
+
+    /// The module cached under `key`, or the one `compile` translates and
+    /// compiles, which is then cached. Failing to cache it is not an error.
+    pub fn get_or_compile<E>(
+        &self,
+        builder: &WasmBuilder,
+        key: &str,
+        compile: impl FnOnce() -> Result<Module, E>,
+    ) -> Result<Module, E> {
+        if let Some(module) = self.load(builder, key) {
+            tracing::debug!(target: "doublejit::backend", key, "cached module");
+            return Ok(module);
+        }
+        let module = compile()?;
+        if let Err(e) = self.store(key, &module) {
+            tracing::warn!(target: "doublejit::backend", key, error = %e, "cannot cache the module");
+        }
+        Ok(module)
+    }
+}
+
+/// The checksum of `artifact` cached under `key`
+fn checksum(key: &str, artifact: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_bytes());
+    hasher.update(artifact);
+    hasher.finalize().into()
+}
+
+/// `artifact` with the header `load` checks before deserializing it
+fn seal(key: &str, artifact: &[u8]) -> Vec<u8> {
+    let mut file = Vec::with_capacity(HEADER_LEN + artifact.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&EMITTER_VERSION.to_le_bytes());
+    file.extend_from_slice(&checksum(key, artifact));
+    file.extend_from_slice(artifact);
+    file
+}
+
+/// The artifact in `file` cached under `key`, if its header matches
+fn unseal<'a>(key: &str, file: &'a [u8]) -> Option<&'a [u8]> {
+    if file.len() < HEADER_LEN || &file[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let (header, artifact) = file.split_at(HEADER_LEN);
+    let version = u32::from_le_bytes(header[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+    (version == EMITTER_VERSION && header[MAGIC.len() + 4..] == checksum(key, artifact)).then_some(artifact)
+}
+
+/// Whether only its owner may write `path`
+#[cfg(unix)]
+fn private(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o022 == 0)
+}
+
+#[cfg(not(unix))]
+fn private(_: &Path) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::config::OptLevel;
+
+    #[test]
+    fn test_cache_key() {
+        let elf = b"\x7fELF image";
+        let key = DiskCache::key(elf, &Config::new(), 0);
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, DiskCache::key(elf, &Config::new().seed(7), 0));
+        assert_ne!(key, DiskCache::key(b"\x7fELF other", &Config::new(), 0));
+        assert_ne!(key, DiskCache::key(elf, &Config::new().opt_level(OptLevel::Baseline), 0));
+        assert_ne!(key, DiskCache::key(elf, &Config::new().strict_memory(true), 0));
+        assert_ne!(key, DiskCache::key(elf, &Config::new().verify_optimizer(true), 0));
+        assert_ne!(key, DiskCache::key(elf, &Config::new().optimizer_fuel(10), 0));
+        assert_ne!(key, DiskCache::key(elf, &Config::new().cycles_per_instruction(1.5), 0));
+        assert_ne!(key, DiskCache::key(elf, &Config::new(), 0x5555_5555_4000));
+    }
+
+    #[test]
+    fn test_artifact_header() {
+        let file = seal("key", b"artifact");
+        assert_eq!(unseal("key", &file), Some(&b"artifact"[..]));
+        // another key, a changed byte or a cut file are not loaded
+        assert_eq!(unseal("other", &file), None);
+        let mut changed = file.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        assert_eq!(unseal("key", &changed), None);
+        assert_eq!(unseal("key", &file[..HEADER_LEN - 1]), None);
+        assert_eq!(unseal("key", b"artifact"), None);
+    }
+}
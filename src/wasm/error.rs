@@ -0,0 +1,34 @@
+use wasmer::{CompileError, DeserializeError, ExportError, InstantiationError, MemoryError, SerializeError, WasmError};
+
+use crate::codegen::ir::IrError;
+use crate::runtime::error::RuntimeError;
+
+/// A failure of the wasm backend to build or instantiate a guest module
+#[derive(Debug, thiserror::Error)]
+pub enum BackendError {
+    #[error("assembling the guest module failed: {0}")]
+    Assemble(#[from] WasmError),
+    #[error("optimizing the guest module failed: {0}")]
+    Optimize(#[from] IrError),
+    #[error("compiling the guest module failed: {0}")]
+    Compile(#[from] CompileError),
+    #[error("instantiating the guest module failed: {0}")]
+    Instantiate(#[from] InstantiationError),
+    #[error("the guest module lacks an export: {0}")]
+    Export(#[from] ExportError),
+    /// Setting a global failed, its type does not match the value
+    #[error("accessing the guest state failed: {0}")]
+    State(#[from] wasmer::RuntimeError),
+    #[error("creating the linear memory failed: {0}")]
+    Memory(#[from] MemoryError),
+    #[error("serializing the compiled module failed: {0}")]
+    Serialize(#[from] SerializeError),
+    #[error("loading the compiled module failed: {0}")]
+    Deserialize(#[from] DeserializeError),
+}
+
+impl From<wasmer::RuntimeError> for RuntimeError {
+    fn from(error: wasmer::RuntimeError) -> Self {
+        RuntimeError::Trap(error.message())
+    }
+}
@@ -0,0 +1,129 @@
+use wasmer::{Imports, Instance, Module, Store};
+
+use crate::error::VmError;
+use crate::runtime::execution::GuestExit;
+use crate::runtime::regs::Registers;
+use crate::runtime::syscall::SyscallEnv;
+use crate::wasm::error::BackendError;
+use crate::wasm::state;
+use crate::wasm::wasm_builder::WasmBuilder;
+
+/// One guest instance with a store of its own, so guests of modules
+/// compiled by the same engine run on different threads without sharing
+/// any mutable state
+pub struct Guest {
+    pub store: Store,
+    pub instance: Instance,
+    pub regs: Registers,
+    pub env: SyscallEnv,
+    /// The instructions of compiled code already retired into the clock
+    retired: u64,
+}
+
+// a server shares one builder between its workers and moves guests to them
+const _: fn() = || {
+    fn send<T: Send>() {}
+    fn sync<T: Send + Sync>() {}
+    send::<Guest>();
+    sync::<WasmBuilder>();
+    sync::<Module>();
+};
+
+impl Guest {
+    /// Run the guest from `entry` until it exits, traps or `entry` returns
+    pub fn run(&mut self, entry: &str) -> Result<Option<GuestExit>, VmError> {
+        let exit = state::run(&mut self.store, &self.instance, entry, &mut self.regs);
+        self.retired = state::retire_instructions(&mut self.store, &self.instance, &self.env.clock, self.retired);
+        exit
+    }
+}
+
+impl WasmBuilder {
+    /// Instantiate a module compiled by this builder in a fresh store.
+    /// `imports` builds the host functions, and the memory, in that store.
+    pub fn instantiate(
+        &self,
+        module: &Module,
+        env: SyscallEnv,
+        regs: Registers,
+        imports: impl FnOnce(&mut Store) -> Result<Imports, BackendError>,
+    ) -> Result<Guest, BackendError> {
+        let mut store = self.store();
+        let imports = imports(&mut store)?;
+        let instance = Instance::new(&mut store, module, &imports)?;
+        Ok(Guest {
+            store,
+            instance,
+            regs,
+            env,
+            retired: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::Function;
+
+    use super::*;
+    use crate::middleend::address_map::{AddressMap, GuestMemory, Perm, SharedMemory};
+    use crate::middleend::emit_wasm::{emit_register_globals, INSTR_COUNT_GLOBAL};
+    use crate::runtime::config::Config;
+    use crate::runtime::execution::ExecutionResult;
+    use crate::wasm::memory::alias_memory;
+    use crate::wasm::trap::exit_trap;
+
+    #[test]
+    fn test_guest_runs_on_its_memory() {
+        let mut map = AddressMap::new(1 << 20);
+        map.map(0x10000..0x11000, Perm::RW).unwrap();
+        let pages = map.size() >> 16;
+        let mut wat = format!(
+            "(module\n(import \"env\" \"memory\" (memory {0} {0}))\n(import \"env\" \"exit\" (func $exit (param i64)))\n",
+            pages
+        );
+        emit_register_globals(&mut wat);
+        wat.push_str(&format!("(global ${0} (export \"{0}\") (mut i64) (i64.const 0))\n", INSTR_COUNT_GLOBAL));
+        wat.push_str(&format!(
+            concat!(
+                "(func (export \"store\")\n",
+                "  (i64.store (i32.const {}) (global.get $x10))\n",
+                "  (global.set $instr_count (i64.add (global.get $instr_count) (i64.const 4))))\n",
+                "(func (export \"exit\") (call $exit (global.get $x10))))\n",
+            ),
+            map.vaddr_to_offset(0x10000).unwrap()
+        ));
+        let builder = WasmBuilder::new();
+        let module = builder.compile(wat.as_bytes()).unwrap();
+        let memory = SharedMemory::new(map.size());
+        let mut regs = Registers::new(0x10000, 0x11000);
+        regs.x[10] = 0x1234;
+        let env = SyscallEnv::new(&Config::new());
+        let mut guest = builder
+            .instantiate(&module, env, regs, |store| {
+                let mut imports = Imports::new();
+                imports.define("env", "memory", alias_memory(store, &map, &memory)?);
+                let exit = Function::new_typed(store, |status: i64| -> Result<(), wasmer::RuntimeError> {
+                    Err(exit_trap(GuestExit::Process(ExecutionResult::Exited(status as i32))))
+                });
+                imports.define("env", "exit", exit);
+                Ok(imports)
+            })
+            .unwrap();
+
+        // the guest stores into the memory the interpreter works on, and
+        // each run retires the instructions it counted into the clock once
+        assert_eq!(guest.run("store").unwrap(), None);
+        assert_eq!(guest.run("store").unwrap(), None);
+        assert_eq!(guest.env.clock.instret(), 8);
+        let mut linear = memory.clone();
+        let mut stored = [0; 8];
+        GuestMemory::new(&mut map, &mut linear).peek(0x10000, &mut stored).unwrap();
+        assert_eq!(u64::from_le_bytes(stored), 0x1234);
+
+        // a host function ends the guest with its exit
+        guest.regs.x[10] = 3;
+        assert_eq!(guest.run("exit").unwrap(), Some(GuestExit::Process(ExecutionResult::Exited(3))));
+        assert_eq!(guest.env.clock.instret(), 8);
+    }
+}
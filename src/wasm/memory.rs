@@ -0,0 +1,88 @@
+use std::cell::UnsafeCell;
+use std::ptr::NonNull;
+
+use wasmer::{AsStoreMut, Memory, MemoryError, MemoryType, Pages, WASM_MAX_PAGES};
+use wasmer_vm::{LinearMemory as VmLinearMemory, MemoryStyle, VMMemory, VMMemoryDefinition};
+
+use crate::middleend::address_map::{AddressMap, LinearMemory, SharedMemory};
+use crate::wasm::error::BackendError;
+
+const WASM_PAGE_SIZE: usize = 1 << 16;
+
+/// The memory of the interpreter as the linear memory of compiled code, so
+/// both work on the same bytes and a guest goes back and forth between them
+/// without copying anything. Compiled code checks every access against its
+/// size, as the tunables of `WasmBuilder` ask for.
+#[derive(Debug)]
+struct AliasedMemory {
+    memory: SharedMemory,
+    ty: MemoryType,
+    /// Where compiled code finds the memory, at an address that stays put
+    definition: Box<UnsafeCell<VMMemoryDefinition>>,
+}
+
+// SAFETY: the definition only points into `memory`, which threads share
+unsafe impl Send for AliasedMemory {}
+
+impl AliasedMemory {
+    fn new(memory: SharedMemory, ty: MemoryType) -> Self {
+        let definition = VMMemoryDefinition {
+            base: memory.as_ptr(),
+            current_length: ty.minimum.0 as usize * WASM_PAGE_SIZE,
+        };
+        Self {
+            memory,
+            ty,
+            definition: Box::new(UnsafeCell::new(definition)),
+        }
+    }
+}
+
+impl VmLinearMemory for AliasedMemory {
+    fn ty(&self) -> MemoryType {
+        self.ty
+    }
+
+    fn size(&self) -> Pages {
+        self.ty.minimum
+    }
+
+    fn style(&self) -> MemoryStyle {
+        MemoryStyle::Dynamic { offset_guard_size: 0 }
+    }
+
+    fn grow(&mut self, delta: Pages) -> Result<Pages, MemoryError> {
+        // the address map sizes the memory once and for all
+        match delta.0 {
+            0 => Ok(self.size()),
+            _ => Err(MemoryError::CouldNotGrow { current: self.size(), attempted_delta: delta }),
+        }
+    }
+
+    fn vmmemory(&self) -> NonNull<VMMemoryDefinition> {
+        NonNull::new(self.definition.get()).unwrap()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn VmLinearMemory + 'static>, MemoryError> {
+        Ok(Box::new(Self::new(self.memory.clone(), self.ty)))
+    }
+
+    fn copy(&mut self) -> Result<Box<dyn VmLinearMemory + 'static>, MemoryError> {
+        Ok(Box::new(Self::new(self.memory.copy(), self.ty)))
+    }
+}
+
+/// The linear memory the modules of `map` import, made of `memory` in
+/// place. Every store gets one of its own over the same bytes.
+pub fn alias_memory(store: &mut impl AsStoreMut, map: &AddressMap, memory: &SharedMemory) -> Result<Memory, BackendError> {
+    let pages = Pages((map.size() / WASM_PAGE_SIZE) as u32);
+    if pages.0 > WASM_MAX_PAGES || memory.size() < map.size() {
+        return Err(MemoryError::MinimumMemoryTooLarge {
+            min_requested: pages,
+            max_allowed: Pages((memory.size() / WASM_PAGE_SIZE).min(WASM_MAX_PAGES as usize) as u32),
+        }
+        .into());
+    }
+    let ty = MemoryType::new(pages, Some(pages), map.is_shared());
+    Ok(Memory::new_from_existing(store, VMMemory(Box::new(AliasedMemory::new(memory.clone(), ty)))))
+}
@@ -1 +1,8 @@
+pub mod block;
+pub mod disk_cache;
+pub mod error;
+pub mod guest;
+pub mod memory;
+pub mod state;
+pub mod trap;
 pub mod wasm_builder;
@@ -0,0 +1,335 @@
+use std::sync::Arc;
+
+use wasmer::{AsStoreRef, Function, FunctionEnv, FunctionEnvMut, Global, Imports, Instance, Store, TypedFunction, Value};
+
+use crate::error::VmError;
+use crate::middleend::emit_wasm::{f_global, x_global, FCSR_GLOBAL, INSTR_COUNT_GLOBAL, OSR_GLOBAL, PC_GLOBAL};
+use crate::middleend::standalone::IMAGE_LOADER_EXPORT;
+use crate::runtime::clock::Clock;
+use crate::runtime::csr::{CsrManager, CsrOp, CsrState, CsrView};
+use crate::runtime::dispatch::OsrEntry;
+use crate::runtime::error::RuntimeError;
+use crate::runtime::execution::{ExecutionResult, GuestExit};
+use crate::runtime::regs::Registers;
+use crate::runtime::syscall::signal::SIGILL;
+use crate::wasm::error::BackendError;
+use crate::wasm::trap::{exit_trap, guest_exit, osr_trap};
+
+fn set(store: &mut Store, instance: &Instance, name: &str, value: Value) -> Result<(), BackendError> {
+    Ok(instance.exports.get_global(name)?.set(store, value)?)
+}
+
+fn get(store: &Store, instance: &Instance, name: &str) -> Result<Value, BackendError> {
+    Ok(instance.exports.get_global(name)?.get(store))
+}
+
+/// Load the registers into the globals of the instance before it runs
+pub fn init_registers(store: &mut Store, instance: &Instance, regs: &Registers) -> Result<(), BackendError> {
+    set(store, instance, PC_GLOBAL, Value::I64(regs.pc as i64))?;
+    for index in 1..32 {
+        set(store, instance, &x_global(index), Value::I64(regs.x[index] as i64))?;
+    }
+    for index in 0..32 {
+        set(store, instance, &f_global(index), Value::I64(regs.f[index] as i64))?;
+    }
+    set(store, instance, FCSR_GLOBAL, Value::I32(regs.csr.fcsr as i32))
+}
+
+/// Copy the guest image into linear memory with the `load_image` export
+/// `emit_image_loader` adds to the module
+pub fn load_image(store: &mut Store, instance: &Instance) -> Result<(), BackendError> {
+    let load: TypedFunction<(), ()> = instance.exports.get_typed_function(store, IMAGE_LOADER_EXPORT)?;
+    Ok(load.call(store)?)
+}
+
+/// Copy the globals of the instance back into the registers, so the state
+/// the guest stopped in can be inspected or resumed from
+pub fn sync_state_from_globals(
+    store: &mut Store,
+    instance: &Instance,
+    regs: &mut Registers,
+) -> Result<(), BackendError> {
+    let bits = |value: Value| value.i64().unwrap_or_default() as u64;
+    regs.pc = bits(get(store, instance, PC_GLOBAL)?);
+    regs.x[0] = 0;
+    for index in 1..32 {
+        regs.x[index] = bits(get(store, instance, &x_global(index))?);
+    }
+    for index in 0..32 {
+        regs.f[index] = bits(get(store, instance, &f_global(index))?);
+    }
+    regs.csr.fcsr = get(store, instance, FCSR_GLOBAL)?.i32().unwrap_or_default() as u32;
+    Ok(())
+}
+
+/// The register globals of an instance, which read the registers as the
+/// guest has them while it runs rather than as `sync_state_from_globals`
+/// last copied them. Host functions the guest calls hold the store, and
+/// sample a running guest through it.
+#[derive(Clone)]
+pub struct RegisterView {
+    pc: Global,
+    /// `x1` to `x31`
+    x: Vec<Global>,
+    f: Vec<Global>,
+    fcsr: Global,
+}
+
+impl RegisterView {
+    pub fn new(instance: &Instance) -> Result<Self, BackendError> {
+        let global = |name: &str| Ok::<_, BackendError>(instance.exports.get_global(name)?.clone());
+        Ok(Self {
+            pc: global(PC_GLOBAL)?,
+            x: (1..32).map(|index| global(&x_global(index))).collect::<Result<_, _>>()?,
+            f: (0..32).map(|index| global(&f_global(index))).collect::<Result<_, _>>()?,
+            fcsr: global(FCSR_GLOBAL)?,
+        })
+    }
+
+    /// Globals of their own in `store`, defined in `imports` for the block
+    /// modules of one guest, which import them
+    pub fn define(store: &mut Store, imports: &mut Imports) -> Self {
+        let mut global = |name: &str, value: Value| {
+            let global = Global::new_mut(&mut *store, value);
+            imports.define("env", name, global.clone());
+            global
+        };
+        Self {
+            pc: global(PC_GLOBAL, Value::I64(0)),
+            x: (1..32).map(|index| global(&x_global(index), Value::I64(0))).collect(),
+            f: (0..32).map(|index| global(&f_global(index), Value::I64(0))).collect(),
+            fcsr: global(FCSR_GLOBAL, Value::I32(0)),
+        }
+    }
+
+    /// Set the globals to `regs`, before compiled code runs
+    pub fn load(&self, store: &mut Store, regs: &Registers) -> Result<(), BackendError> {
+        self.pc.set(store, Value::I64(regs.pc as i64))?;
+        for (global, &value) in self.x.iter().zip(&regs.x[1..]) {
+            global.set(store, Value::I64(value as i64))?;
+        }
+        for (global, &value) in self.f.iter().zip(&regs.f) {
+            global.set(store, Value::I64(value as i64))?;
+        }
+        Ok(self.fcsr.set(store, Value::I32(regs.csr.fcsr as i32))?)
+    }
+
+    /// Copy the globals back into `regs`, after compiled code ran
+    pub fn save(&self, store: &impl AsStoreRef, regs: &mut Registers) {
+        regs.pc = self.pc(store);
+        for index in 1..32 {
+            regs.x[index] = self.x(store, index);
+        }
+        for index in 0..32 {
+            regs.f[index] = self.f(store, index);
+        }
+        regs.csr.fcsr = self.fcsr(store);
+    }
+
+    pub fn pc(&self, store: &impl AsStoreRef) -> u64 {
+        self.pc.get(store).i64().unwrap_or_default() as u64
+    }
+
+    pub fn x(&self, store: &impl AsStoreRef, index: usize) -> u64 {
+        match index {
+            0 => 0,
+            index => self.x[index - 1].get(store).i64().unwrap_or_default() as u64,
+        }
+    }
+
+    pub fn f(&self, store: &impl AsStoreRef, index: usize) -> u64 {
+        self.f[index].get(store).i64().unwrap_or_default() as u64
+    }
+
+    pub fn fcsr(&self, store: &impl AsStoreRef) -> u32 {
+        self.fcsr.get(store).i32().unwrap_or_default() as u32
+    }
+}
+
+/// Retire into `clock` the instructions `$instr_count` counted since it
+/// last read `retired`, so the counters and a virtual clock include the
+/// instructions of compiled code, and get the count to pass next time
+pub fn retire_instructions(store: &mut Store, instance: &Instance, clock: &Clock, retired: u64) -> u64 {
+    let Ok(global) = instance.exports.get_global(INSTR_COUNT_GLOBAL) else { return retired };
+    let count = global.get(store).i64().unwrap_or_default() as u64;
+    clock.retire(count.saturating_sub(retired));
+    count
+}
+
+/// The `osr_exit` host function
+pub fn osr_exit(header: i64) -> Result<(), wasmer::RuntimeError> {
+    Err(osr_trap(header as u64))
+}
+
+/// What the CSR host functions run the CSR instructions of translated code
+/// on
+pub struct CsrEnv {
+    pub csrs: CsrManager,
+    /// The CSRs other than `fcsr`, which lives in its register global
+    pub state: CsrState,
+    pub clock: Arc<Clock>,
+    /// The `fcsr` global the block modules import
+    pub fcsr: Global,
+}
+
+impl CsrEnv {
+    /// The CSRs as the guest has them now, with `fcsr` from its global
+    pub fn csr(&self, store: &impl AsStoreRef) -> CsrView<'_> {
+        let state = CsrState { fcsr: self.fcsr.get(store).i32().unwrap_or_default() as u32, ..self.state };
+        self.csrs.view(state, &self.clock)
+    }
+}
+
+fn csr_access(mut env: FunctionEnvMut<CsrEnv>, csr: i32, op: CsrOp, source: i64, write: bool) -> Result<i64, wasmer::RuntimeError> {
+    let (env, mut store) = env.data_and_store_mut();
+    // like the kernel, kill the guest with SIGILL for a CSR it may not use
+    let illegal = || exit_trap(GuestExit::Process(ExecutionResult::Signaled(SIGILL)));
+    env.state.fcsr = env.fcsr.get(&store).i32().unwrap_or_default() as u32;
+    let old = env.csrs.access_number(&mut env.state, &env.clock, csr as u32, op, source as u64, write).ok_or_else(illegal)?;
+    env.fcsr.set(&mut store, Value::I32(env.state.fcsr as i32))?;
+    Ok(old as i64)
+}
+
+/// Define the `csr_read_*` host functions of `CSR_IMPORTS` in `imports`,
+/// working on `env`, which stays reachable through the returned handle
+pub fn define_csr_imports(store: &mut Store, imports: &mut Imports, env: CsrEnv) -> FunctionEnv<CsrEnv> {
+    let env = FunctionEnv::new(store, env);
+    let write = Function::new_typed_with_env(store, &env, |env: FunctionEnvMut<CsrEnv>, csr: i32, source: i64| {
+        csr_access(env, csr, CsrOp::Write, source, true)
+    });
+    let set = Function::new_typed_with_env(store, &env, |env: FunctionEnvMut<CsrEnv>, csr: i32, source: i64, write: i32| {
+        csr_access(env, csr, CsrOp::Set, source, write != 0)
+    });
+    let clear = Function::new_typed_with_env(store, &env, |env: FunctionEnvMut<CsrEnv>, csr: i32, source: i64, write: i32| {
+        csr_access(env, csr, CsrOp::Clear, source, write != 0)
+    });
+    imports.define("env", "csr_read_write", write);
+    imports.define("env", "csr_read_set", set);
+    imports.define("env", "csr_read_clear", clear);
+    env
+}
+
+/// Run the guest from the exported function `entry` with `regs`, and copy
+/// the registers back however it stops. `None` means `entry` returned, e.g.
+/// to the dispatcher, or a loop left for its optimized code, at `osr_entry`
+/// of `regs.pc`, and the guest can be resumed from `regs`.
+pub fn run(
+    store: &mut Store,
+    instance: &Instance,
+    entry: &str,
+    regs: &mut Registers,
+) -> Result<Option<GuestExit>, VmError> {
+    let entry: TypedFunction<(), ()> = instance
+        .exports
+        .get_typed_function(store, entry)
+        .map_err(BackendError::from)?;
+    init_registers(store, instance, regs)?;
+    let result = entry.call(store);
+    sync_state_from_globals(store, instance, regs)?;
+    match result {
+        Ok(()) => Ok(None),
+        Err(trap) => match trap.downcast::<OsrEntry>() {
+            Ok(OsrEntry(header)) => {
+                tracing::debug!(target: "doublejit::backend", pc = header, "on-stack replacement");
+                set(store, instance, OSR_GLOBAL, Value::I64(0))?;
+                Ok(None)
+            }
+            Err(trap) => Ok(Some(guest_exit(trap).map_err(RuntimeError::from)?)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleend::emit_wasm::{emit_register_globals, emit_register_imports, CSR_IMPORTS};
+    use crate::runtime::config::Config;
+    use crate::wasm::wasm_builder::WasmBuilder;
+
+    #[test]
+    fn test_run_syncs_registers() {
+        let mut wat = String::from("(module\n(import \"env\" \"osr_exit\" (func $osr_exit (param i64)))\n");
+        emit_register_globals(&mut wat);
+        wat.push_str(&format!("(global ${0} (export \"{0}\") (mut i64) (i64.const 0))\n", OSR_GLOBAL));
+        wat.push_str(concat!(
+            "(func (export \"step\")\n",
+            "  (global.set $x10 (i64.add (global.get $x10) (global.get $x11)))\n",
+            "  (global.set $f1 (global.get $x10))\n",
+            "  (global.set $fcsr (i32.or (global.get $fcsr) (i32.const 1)))\n",
+            "  (global.set $pc (i64.add (global.get $pc) (i64.const 4))))\n",
+            "(func (export \"leave\")\n",
+            "  (global.set $osr_target (i64.const 0x10000))\n",
+            "  (global.set $pc (i64.const 0x10000))\n",
+            "  (call $osr_exit (i64.const 0x10000)))\n",
+            "(func (export \"fault\") unreachable))\n",
+        ));
+        let builder = WasmBuilder::new();
+        let module = builder.compile(wat.as_bytes()).unwrap();
+        let mut store = builder.store();
+        let mut imports = Imports::new();
+        imports.define("env", "osr_exit", Function::new_typed(&mut store, osr_exit));
+        let instance = Instance::new(&mut store, &module, &imports).unwrap();
+
+        let mut regs = Registers::new(0x10000, 0x20000);
+        regs.x[10] = 5;
+        regs.x[11] = 7;
+        regs.csr.fcsr = 0x20;
+        assert_eq!(run(&mut store, &instance, "step", &mut regs).unwrap(), None);
+        assert_eq!((regs.pc, regs.x[2], regs.x[10], regs.f[1], regs.csr.fcsr), (0x10004, 0x20000, 12, 12, 0x21));
+
+        // leaving for the optimized loop resumes at its header, which is no
+        // longer asked to be left
+        assert_eq!(run(&mut store, &instance, "leave", &mut regs).unwrap(), None);
+        assert_eq!(regs.pc, 0x10000);
+        assert_eq!(get(&store, &instance, OSR_GLOBAL).unwrap().i64(), Some(0));
+        // a genuine trap fails the run, as does a missing entry
+        assert!(run(&mut store, &instance, "fault", &mut regs).is_err());
+        assert!(run(&mut store, &instance, "missing", &mut regs).is_err());
+    }
+
+    #[test]
+    fn test_csr_imports() {
+        let mut wat = String::from("(module\n");
+        wat.push_str(CSR_IMPORTS);
+        emit_register_imports(&mut wat);
+        wat.push_str(concat!(
+            "(func (export \"csrs\")\n",
+            "  (global.set $x10 (call $csr_read_set (i32.const 0x1) (global.get $x11) (i32.const 1)))\n",
+            "  (global.set $x12 (call $csr_read_set (i32.const 0xc02) (i64.const 0) (i32.const 0))))\n",
+            "(func (export \"illegal\") (drop (call $csr_read_write (i32.const 0xc00) (i64.const 1)))))\n",
+        ));
+        let builder = WasmBuilder::new();
+        let module = builder.compile(wat.as_bytes()).unwrap();
+        let mut store = builder.store();
+        let mut imports = Imports::new();
+        let view = RegisterView::define(&mut store, &mut imports);
+        let clock = Arc::new(Clock::default());
+        let csrs = CsrEnv {
+            csrs: CsrManager::new(&Config::new()),
+            state: CsrState::default(),
+            clock: clock.clone(),
+            fcsr: view.fcsr.clone(),
+        };
+        let env = define_csr_imports(&mut store, &mut imports, csrs);
+        let instance = Instance::new(&mut store, &module, &imports).unwrap();
+        let call = |store: &mut Store, name: &str| {
+            let func: TypedFunction<(), ()> = instance.exports.get_typed_function(&*store, name).unwrap();
+            func.call(store)
+        };
+
+        let mut regs = Registers::new(0x10000, 0x20000);
+        regs.x[11] = 5;
+        regs.csr.fcsr = 0x20;
+        view.load(&mut store, &regs).unwrap();
+        clock.retire(7);
+        call(&mut store, "csrs").unwrap();
+        view.save(&store, &mut regs);
+        // the flags are raised in the fcsr global, the rounding mode kept
+        assert_eq!((regs.x[10], regs.x[12], regs.csr.fcsr), (0, 7, 0x25));
+        assert_eq!(env.as_ref(&store).state.fcsr, 0x25);
+
+        // writing a counter kills the guest with SIGILL
+        let trap = call(&mut store, "illegal").unwrap_err();
+        assert_eq!(guest_exit(trap).unwrap(), GuestExit::Process(ExecutionResult::Signaled(SIGILL)));
+    }
+}
@@ -0,0 +1,32 @@
+use wasmer::RuntimeError;
+
+use crate::runtime::dispatch::OsrEntry;
+use crate::runtime::execution::GuestExit;
+
+/// The trap a host function returns to unwind the guest when it exits
+pub fn exit_trap(exit: GuestExit) -> RuntimeError {
+    RuntimeError::user(Box::new(exit))
+}
+
+/// Recover the exit from the trap that ended a guest call. A genuine trap,
+/// such as an `unreachable` after a guest fault, comes back as the error.
+pub fn guest_exit(error: RuntimeError) -> Result<GuestExit, RuntimeError> {
+    error.downcast::<GuestExit>()
+}
+
+/// The trap `osr_exit` unwinds the guest with to enter the optimized loop
+pub fn osr_trap(header: u64) -> RuntimeError {
+    RuntimeError::user(Box::new(OsrEntry(header)))
+}
+
+/// What the `budget_exhausted` host function of the block engine unwinds
+/// the guest with once its slice is used up, for the engine to hand it
+/// back to the interpreter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("instruction budget exhausted")]
+pub struct BudgetExhausted;
+
+/// The trap of a guest whose slice is used up
+pub fn yield_trap() -> RuntimeError {
+    RuntimeError::user(Box::new(BudgetExhausted))
+}
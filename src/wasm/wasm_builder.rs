@@ -1,3 +1,198 @@
-use wasmer::{Memory, MemoryType, Pages, Store, Type, Value, WASM_MAX_PAGES};
-use wasmer::Engine;
-use wasmer_compiler_cranelift::Cranelift;
\ No newline at end of file
+use wasmer::{BaseTunables, CompilerConfig, Engine, EngineBuilder, Features, MemoryError, Module, NativeEngineExt};
+use wasmer::{Memory, MemoryType, Pages, Store, WASM_MAX_PAGES};
+use wasmer_compiler_cranelift::{Cranelift, CraneliftOptLevel};
+use wasmer_compiler_singlepass::Singlepass;
+
+use crate::middleend::address_map::AddressMap;
+use crate::runtime::config::OptLevel;
+use crate::wasm::error::BackendError;
+
+const WASM_PAGE_SIZE: usize = 1 << 16;
+
+/// Owns the engines every guest module is compiled with
+pub struct WasmBuilder {
+    engine: Engine,
+    /// The Cranelift engine hot functions are recompiled with when tiering
+    optimizing: Option<Engine>,
+    opt_level: OptLevel,
+}
+
+fn engine(compiler: impl CompilerConfig + 'static) -> Engine {
+    let mut features = Features::new();
+    // shared memories and the atomic AMO helpers need the threads proposal
+    features.threads(true);
+    let mut engine = EngineBuilder::new(compiler).set_features(Some(features)).engine();
+    // compiled code checks accesses against the size of the memory rather
+    // than relying on guard pages, which the memory `alias_memory` makes of
+    // the interpreter's does not have
+    engine.set_tunables(BaseTunables {
+        static_memory_bound: Pages(0),
+        static_memory_offset_guard_size: 0,
+        dynamic_memory_offset_guard_size: 0,
+    });
+    engine
+}
+
+fn cranelift() -> Engine {
+    let mut cranelift = Cranelift::new();
+    cranelift.opt_level(CraneliftOptLevel::Speed);
+    engine(cranelift)
+}
+
+impl WasmBuilder {
+    pub fn new() -> Self {
+        Self::with_opt_level(OptLevel::default())
+    }
+
+    pub fn with_opt_level(opt_level: OptLevel) -> Self {
+        let (engine, optimizing) = match opt_level {
+            OptLevel::Baseline => (engine(Singlepass::new()), None),
+            OptLevel::Optimized => (cranelift(), None),
+            OptLevel::Tiered { .. } => (engine(Singlepass::new()), Some(cranelift())),
+        };
+        Self {
+            engine,
+            optimizing,
+            opt_level,
+        }
+    }
+
+    pub fn opt_level(&self) -> OptLevel {
+        self.opt_level
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Compile the translated module with the first tier
+    pub fn compile(&self, wasm: &[u8]) -> Result<Module, BackendError> {
+        Ok(Module::new(&self.engine, wasm)?)
+    }
+
+    /// Recompile the module of a function the profiler found hot with the
+    /// optimizing tier. Without tiering the first tier is all there is.
+    pub fn compile_hot(&self, wasm: &[u8]) -> Result<Module, BackendError> {
+        Ok(Module::new(self.optimizing.as_ref().unwrap_or(&self.engine), wasm)?)
+    }
+
+    /// The store instances of `compile_hot` modules live in. A block engine
+    /// gives it the interpreter's memory through `alias_memory`, a guest
+    /// module's shared memory goes there through `share_memory`.
+    pub fn hot_store(&self) -> Store {
+        Store::new(self.optimizing.as_ref().unwrap_or(&self.engine).clone())
+    }
+
+    /// Assemble the text of a module, e.g. one of `emit_standalone`, into the
+    /// binary format to write out as a .wasm file
+    pub fn assemble(wat: &str) -> Result<Vec<u8>, BackendError> {
+        Ok(wasmer::wat2wasm(wat.as_bytes())?.into_owned())
+    }
+
+    /// The native artifact of a compiled module, for an embedder to ship
+    /// and load with `deserialize_module` instead of translating and
+    /// compiling the guest again
+    pub fn serialize_module(module: &Module) -> Result<Vec<u8>, BackendError> {
+        Ok(module.serialize()?.to_vec())
+    }
+
+    /// Load an artifact of `serialize_module` into the first tier engine.
+    /// An artifact of another wasmer version or target is rejected.
+    ///
+    /// # Safety
+    ///
+    /// The artifact is native code run as is, it must come from
+    /// `serialize_module` and not have been tampered with.
+    pub unsafe fn deserialize_module(&self, artifact: &[u8]) -> Result<Module, BackendError> {
+        // SAFETY: the caller vouches for the artifact
+        Ok(unsafe { Module::deserialize(&self.engine, artifact) }?)
+    }
+
+    pub fn store(&self) -> Store {
+        Store::new(self.engine.clone())
+    }
+
+    /// Create the linear memory described by the address map. A shared map
+    /// gets a shared memory, which the module imports as `env.memory`.
+    pub fn create_memory(&self, store: &mut Store, map: &AddressMap) -> Result<Memory, BackendError> {
+        let pages = Pages((map.size() / WASM_PAGE_SIZE) as u32);
+        if pages.0 > WASM_MAX_PAGES {
+            return Err(MemoryError::MinimumMemoryTooLarge {
+                min_requested: pages,
+                max_allowed: Pages(WASM_MAX_PAGES),
+            }
+            .into());
+        }
+        tracing::debug!(target: "doublejit::backend", pages = pages.0, shared = map.is_shared(), "linear memory");
+        Ok(Memory::new(store, MemoryType::new(pages, Some(pages), map.is_shared()))?)
+    }
+
+    /// Hand a shared memory to another store, so a second guest instance
+    /// observes the same bytes as the first one
+    pub fn share_memory(
+        memory: &Memory,
+        store: &Store,
+        new_store: &mut Store,
+    ) -> Result<Memory, BackendError> {
+        Ok(memory.share_in_store(store, new_store)?)
+    }
+}
+
+impl Default for WasmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmer::{Imports, Instance, TypedFunction};
+
+    use super::*;
+
+    /// A module storing the byte it is called with at address 8 of the
+    /// memory it imports
+    const STORE_BYTE: &str = "(module\n(import \"env\" \"memory\" (memory 1 1))\n(func (export \"store\") (param $byte i32) (i32.store8 (i32.const 8) (local.get $byte))))";
+
+    /// Instantiate `module` in `store` against a memory of `map`, call it
+    /// with `byte` and read what it stored
+    fn store_byte(builder: &WasmBuilder, store: &mut Store, module: &Module, map: &AddressMap, byte: i32) -> u8 {
+        let memory = builder.create_memory(store, map).unwrap();
+        let mut imports = Imports::new();
+        imports.define("env", "memory", memory.clone());
+        let instance = Instance::new(store, module, &imports).unwrap();
+        let store_fn: TypedFunction<i32, ()> = instance.exports.get_typed_function(store, "store").unwrap();
+        store_fn.call(store, byte).unwrap();
+        let mut stored = [0];
+        memory.view(store).read(8, &mut stored).unwrap();
+        stored[0]
+    }
+
+    #[test]
+    fn test_compile_per_opt_level() {
+        let map = AddressMap::new(WASM_PAGE_SIZE);
+        let wasm = WasmBuilder::assemble(STORE_BYTE).unwrap();
+        for opt_level in [OptLevel::Baseline, OptLevel::Optimized, OptLevel::Tiered { threshold: 10 }] {
+            let builder = WasmBuilder::with_opt_level(opt_level);
+            assert_eq!(builder.opt_level(), opt_level);
+            let module = builder.compile(&wasm).unwrap();
+            assert_eq!(store_byte(&builder, &mut builder.store(), &module, &map, 42), 42);
+            let hot = builder.compile_hot(&wasm).unwrap();
+            assert_eq!(store_byte(&builder, &mut builder.hot_store(), &hot, &map, 43), 43);
+
+            // the artifact of a module loads back as the same module
+            let artifact = WasmBuilder::serialize_module(&module).unwrap();
+            // SAFETY: the artifact was just serialized by this builder
+            let loaded = unsafe { builder.deserialize_module(&artifact) }.unwrap();
+            assert_eq!(store_byte(&builder, &mut builder.store(), &loaded, &map, 44), 44);
+        }
+        // a memory the map does not describe does not satisfy the import
+        let builder = WasmBuilder::new();
+        let module = builder.compile(&wasm).unwrap();
+        let mut store = builder.store();
+        let memory = builder.create_memory(&mut store, &AddressMap::new(2 * WASM_PAGE_SIZE)).unwrap();
+        let mut imports = Imports::new();
+        imports.define("env", "memory", memory);
+        assert!(Instance::new(&mut store, &module, &imports).is_err());
+    }
+}